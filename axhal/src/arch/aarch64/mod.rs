@@ -1,19 +1,28 @@
 mod context;
 pub(crate) mod trap;
+mod uaccess;
 
 use core::arch::asm;
 
-use aarch64_cpu::registers::{DAIF, TPIDR_EL0, TTBR0_EL1, TTBR1_EL1, VBAR_EL1};
+use aarch64_cpu::registers::{DAIF, SPSR_EL1, TPIDR_EL0, TTBR0_EL1, TTBR1_EL1, VBAR_EL1};
 use memory_addr::{PhysAddr, VirtAddr};
 use tock_registers::interfaces::{Readable, Writeable};
 
 pub use self::context::{start_thread, FpState, TaskContext, TrapFrame};
 use crate::mem::PAGE_SIZE_4K;
-pub use trap::syscall;
+pub use trap::{syscall, SyscallArgs};
+pub use self::uaccess::{__get_user_asm, __put_user_asm, access_ok, fixup_exception};
+pub use self::uaccess::{fault_in_readable, fault_in_readable_checked, fault_in_writeable};
 
 pub const TASK_SIZE: usize = 0x40_0000_0000;
 pub const STACK_SIZE: usize = 32 * PAGE_SIZE_4K;
 
+/// Size of the area below the user stack pointer that the kernel must not
+/// touch when it pushes data onto a user stack.
+///
+/// AAPCS64 does not define a redzone.
+pub const USER_REDZONE: usize = 0;
+
 /*
  * This is the location that an ET_DYN program is loaded if exec'ed.
  * Typical use of this is to invoke "./ld.so someprog" to test out
@@ -34,6 +43,21 @@ pub const SR_SPIE: usize = 0x00000020; /* Previous Supervisor IE */
 pub const SR_FS_INITIAL: usize = 0x00002000;
 pub const SR_UXL_64: usize = 0x200000000; /* XLEN = 64 for U-mode */
 
+/// Returns whether the current exception was taken from EL0.
+#[inline]
+pub fn user_mode() -> bool {
+    SPSR_EL1.matches_all(SPSR_EL1::M::EL0t)
+}
+
+/// Returns to user space with the trap frame at `kstack_sp`, for a newly
+/// forked task.
+pub fn ret_from_fork(kstack_sp: usize) {
+    extern "C" {
+        fn __ret_from_fork(kstack_sp: usize) -> !;
+    }
+    unsafe { __ret_from_fork(kstack_sp) }
+}
+
 /// Allows the current CPU to respond to interrupts.
 #[inline]
 pub fn enable_irqs() {
@@ -164,3 +188,7 @@ pub unsafe fn write_thread_pointer(tpidr_el0: usize) {
 }
 
 pub fn sync_kernel_mappings(_src: PhysAddr, _dst: PhysAddr) {}
+
+/// Does the early architecture setup on the boot CPU, called by
+/// [`arch_init_early`](crate::arch_init_early). Nothing is needed yet.
+pub fn early_init() {}
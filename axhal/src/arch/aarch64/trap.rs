@@ -2,12 +2,14 @@ use core::arch::global_asm;
 
 use aarch64_cpu::registers::{ESR_EL1, FAR_EL1};
 use tock_registers::interfaces::Readable;
-use crate::trap::SyscallArgs;
 
 use super::TrapFrame;
 
 global_asm!(include_str!("trap.S"));
 
+/// The arguments of a syscall, `x0` to `x5`.
+pub type SyscallArgs = [usize; 6];
+
 #[repr(u8)]
 #[derive(Debug)]
 #[allow(dead_code)]
@@ -68,7 +70,9 @@ fn handle_sync_exception(tf: &mut TrapFrame) {
                 iss,
                 tf,
             );
-            crate::trap::handle_page_fault(FAR_EL1.get() as usize, 0);
+            if !super::fixup_exception(tf) {
+                panic!("Unhandled EL1 Page Fault @ {:#x}", tf.elr);
+            }
         }
         _ => {
             panic!(
@@ -84,7 +88,8 @@ fn handle_sync_exception(tf: &mut TrapFrame) {
 
 #[no_mangle]
 fn handle_irq_exception(_tf: &TrapFrame) {
-    crate::trap::handle_irq_extern(0)
+    #[cfg(feature = "irq")]
+    crate::platform::irq::dispatch_irq(0);
 }
 
 pub fn syscall_args(tf: &TrapFrame) -> SyscallArgs {
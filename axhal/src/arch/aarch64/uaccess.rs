@@ -0,0 +1,151 @@
+//! Accesses to user memory, with fault fixup.
+//!
+//! The accesses use the unprivileged `LDTR*`/`STTR*` instructions, so they
+//! are checked against the EL0 permissions. Each of them has an
+//! `__ex_table` entry (the address of the access and of its fixup code), and
+//! [`fixup_exception`] resumes a faulting one at its fixup code.
+
+use axerrno::{linux_err, LinuxError};
+
+use super::{TrapFrame, TASK_SIZE};
+
+#[inline]
+pub fn __get_user_asm(ptr: usize) -> (u8, usize) {
+    let mut x: u32;
+    let mut err: usize = 0;
+    unsafe {
+        core::arch::asm!(
+            "1:",
+            "   ldtrb {x:w}, [{ptr}]",
+            "2:",
+            "   .section .fixup,\"ax\"",
+            "   .balign 4",
+            "3:",
+            "   mov {err}, #{err_val}",
+            "   mov {x:w}, wzr",
+            "   b 2b",
+            "   .previous",
+            "   .section __ex_table,\"a\"",
+            "   .balign 8",
+            "   .quad 1b, 3b",
+            "   .previous",
+            err = inout(reg) err,
+            x = out(reg) x,
+            ptr = in(reg) ptr,
+            err_val = const (-(LinuxError::EFAULT as isize)),
+        )
+    }
+    (x as u8, err)
+}
+
+#[inline]
+pub fn __put_user_asm(x: u8, ptr: usize) -> usize {
+    let mut err: usize = 0;
+    unsafe {
+        core::arch::asm!(
+            "1:",
+            "   sttrb {x:w}, [{ptr}]",
+            "2:",
+            "   .section .fixup,\"ax\"",
+            "   .balign 4",
+            "3:",
+            "   mov {err}, #{err_val}",
+            "   b 2b",
+            "   .previous",
+            "   .section __ex_table,\"a\"",
+            "   .balign 8",
+            "   .quad 1b, 3b",
+            "   .previous",
+            err = inout(reg) err,
+            x = in(reg) x as u32,
+            ptr = in(reg) ptr,
+            err_val = const (-(LinuxError::EFAULT as isize)),
+        )
+    }
+    err
+}
+
+/// Checks if a user space pointer is valid, i.e. `[addr, addr + size)` is
+/// in the user space range.
+///
+/// The memory access functions may still return `EFAULT` after it.
+#[inline]
+pub fn access_ok(addr: usize, size: usize) -> bool {
+    size <= TASK_SIZE && addr <= TASK_SIZE - size
+}
+
+/// Faults in the user page at `addr` for reading.
+///
+/// Returns 0 on success, or the negated `EFAULT` as `usize`. New code should
+/// use [`fault_in_readable_checked`] instead.
+#[inline]
+pub fn fault_in_readable(addr: usize, size: usize) -> usize {
+    match fault_in_readable_checked(addr, size) {
+        Ok(()) => 0,
+        Err(_) => linux_err!(EFAULT),
+    }
+}
+
+/// Faults in the user page at `addr` for reading.
+///
+/// Returns [`LinuxError::EFAULT`] if the range is not in user space or the
+/// page cannot be read.
+#[inline]
+pub fn fault_in_readable_checked(addr: usize, size: usize) -> Result<(), LinuxError> {
+    if !access_ok(addr, size) {
+        return Err(LinuxError::EFAULT);
+    }
+
+    let (_, err) = __get_user_asm(addr);
+    if err != 0 {
+        error!("__get_user_asm: err = {:#x}", err);
+        return Err(LinuxError::EFAULT);
+    }
+    Ok(())
+}
+
+#[inline]
+pub fn fault_in_writeable(addr: usize, size: usize) -> usize {
+    if !access_ok(addr, size) {
+        return linux_err!(EFAULT);
+    }
+
+    let err = __put_user_asm(0u8, addr);
+    if err != 0 {
+        error!("__put_user_asm: err = {:#x}", err);
+        return err;
+    }
+    0
+}
+
+/// An entry of `__ex_table`.
+#[repr(C)]
+struct ExceptionTableEntry {
+    insn: usize,
+    fixup: usize,
+}
+
+#[allow(non_upper_case_globals)]
+extern "C" {
+    static __start___ex_table: ExceptionTableEntry;
+    static __stop___ex_table: ExceptionTableEntry;
+}
+
+/// Resumes a faulting user access at its fixup code.
+///
+/// Returns `false` if the faulting instruction (`tf.elr`) is not a user
+/// access with an `__ex_table` entry.
+pub fn fixup_exception(tf: &mut TrapFrame) -> bool {
+    let table = unsafe {
+        let start = core::ptr::addr_of!(__start___ex_table);
+        let end = core::ptr::addr_of!(__stop___ex_table);
+        core::slice::from_raw_parts(start, end.offset_from(start) as usize)
+    };
+    match table.iter().find(|entry| entry.insn == tf.elr as usize) {
+        Some(entry) => {
+            tf.elr = entry.fixup as u64;
+            true
+        }
+        None => false,
+    }
+}
@@ -0,0 +1,117 @@
+use core::arch::asm;
+use memory_addr::VirtAddr;
+
+use super::csr::{PRMD_PIE, PRMD_PPLV};
+
+/// Saved registers when a trap (exception) occurs.
+#[repr(C)]
+#[derive(Debug, Default, Clone, Copy)]
+pub struct TrapFrame {
+    /// General-purpose registers (R0..R31).
+    pub regs: [usize; 32],
+    /// Pre-exception Mode Information (PRMD).
+    pub prmd: usize,
+    /// Exception Return Address (ERA).
+    pub era: usize,
+}
+
+impl TrapFrame {
+    /// Returns whether the trap was taken from user mode (PLV3).
+    #[inline]
+    pub const fn from_user(&self) -> bool {
+        self.prmd & PRMD_PPLV != 0
+    }
+}
+
+/// Saved hardware states of a task.
+///
+/// The context usually includes:
+///
+/// - Callee-saved registers
+/// - Stack pointer register
+/// - Thread pointer register (for thread-local storage)
+///
+/// On context switch, current task saves its context from CPU to memory,
+/// and the next task restores its context from memory to CPU.
+#[allow(missing_docs)]
+#[repr(C)]
+#[derive(Debug)]
+pub struct TaskContext {
+    pub ra: usize,     // return address
+    pub sp: usize,     // stack pointer
+    pub s: [usize; 9], // s0..s8
+    pub fp: usize,     // r22
+    pub tp: usize,     // thread pointer
+}
+
+impl TaskContext {
+    /// Creates a new default context for a new task.
+    pub const fn new() -> Self {
+        unsafe { core::mem::MaybeUninit::zeroed().assume_init() }
+    }
+
+    /// Initializes the context for a new task, with the given entry point and
+    /// kernel stack.
+    pub fn init(&mut self, entry: usize, kstack_top: VirtAddr, tls_area: VirtAddr) {
+        self.sp = kstack_top.as_usize();
+        self.ra = entry;
+        self.tp = tls_area.as_usize();
+    }
+
+    /// Switches to another task.
+    ///
+    /// It first saves the current task's context from CPU to this place, and then
+    /// restores the next task's context from `next_ctx` to CPU.
+    pub fn switch_to(&mut self, next_ctx: &Self) {
+        unsafe { context_switch(self, next_ctx) }
+    }
+}
+
+#[naked]
+unsafe extern "C" fn context_switch(_current_task: &mut TaskContext, _next_task: &TaskContext) {
+    asm!(
+        "
+        // save old context (callee-saved registers)
+        st.d    $ra, $a0, 0 * 8
+        st.d    $sp, $a0, 1 * 8
+        st.d    $s0, $a0, 2 * 8
+        st.d    $s1, $a0, 3 * 8
+        st.d    $s2, $a0, 4 * 8
+        st.d    $s3, $a0, 5 * 8
+        st.d    $s4, $a0, 6 * 8
+        st.d    $s5, $a0, 7 * 8
+        st.d    $s6, $a0, 8 * 8
+        st.d    $s7, $a0, 9 * 8
+        st.d    $s8, $a0, 10 * 8
+        st.d    $fp, $a0, 11 * 8
+        st.d    $tp, $a0, 12 * 8
+
+        // restore new context
+        ld.d    $tp, $a1, 12 * 8
+        ld.d    $fp, $a1, 11 * 8
+        ld.d    $s8, $a1, 10 * 8
+        ld.d    $s7, $a1, 9 * 8
+        ld.d    $s6, $a1, 8 * 8
+        ld.d    $s5, $a1, 7 * 8
+        ld.d    $s4, $a1, 6 * 8
+        ld.d    $s3, $a1, 5 * 8
+        ld.d    $s2, $a1, 4 * 8
+        ld.d    $s1, $a1, 3 * 8
+        ld.d    $s0, $a1, 2 * 8
+        ld.d    $sp, $a1, 1 * 8
+        ld.d    $ra, $a1, 0 * 8
+
+        jr      $ra",
+        options(noreturn),
+    )
+}
+
+/// Sets up the trap frame at `regs` to enter user mode at `pc`, with the
+/// user stack pointer `sp`.
+pub fn start_thread(regs: usize, pc: usize, sp: usize) {
+    let regs = unsafe { &mut *(regs as *mut TrapFrame) };
+    regs.era = pc;
+    // PLV3 with interrupts enabled after `ertn`.
+    regs.prmd = PRMD_PPLV | PRMD_PIE;
+    regs.regs[3] = sp;
+}
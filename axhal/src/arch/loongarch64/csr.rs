@@ -0,0 +1,87 @@
+//! Control and status registers (CSRs).
+
+#![allow(dead_code)]
+
+/// Current mode information.
+pub const CRMD: usize = 0x0;
+/// Pre-exception mode information.
+pub const PRMD: usize = 0x1;
+/// Extended component unit enable.
+pub const EUEN: usize = 0x2;
+/// Exception configuration.
+pub const ECFG: usize = 0x4;
+/// Exception status.
+pub const ESTAT: usize = 0x5;
+/// Exception return address.
+pub const ERA: usize = 0x6;
+/// Bad virtual address.
+pub const BADV: usize = 0x7;
+/// Exception entry base address.
+pub const EENTRY: usize = 0xc;
+/// Address space identifier.
+pub const ASID: usize = 0x18;
+/// Page table base of the lower half of the address space.
+pub const PGDL: usize = 0x19;
+/// Page table base of the higher half of the address space.
+pub const PGDH: usize = 0x1a;
+/// Page table base of the faulting address (read-only).
+pub const PGD: usize = 0x1b;
+/// Page walk controller for the lower page table levels.
+pub const PWCL: usize = 0x1c;
+/// Page walk controller for the higher page table levels.
+pub const PWCH: usize = 0x1d;
+/// STLB page size.
+pub const STLBPS: usize = 0x1e;
+/// Kernel scratch registers, used by the trap entry.
+pub const KSAVE_KSP: usize = 0x30;
+pub const KSAVE_TEMP: usize = 0x31;
+pub const KSAVE_R21: usize = 0x32;
+pub const KSAVE_TP: usize = 0x33;
+/// TLB refill exception entry base address.
+pub const TLBRENTRY: usize = 0x88;
+/// TLB refill exception scratch register.
+pub const TLBRSAVE: usize = 0x8b;
+/// TLB refill exception entry high part.
+pub const TLBREHI: usize = 0x8e;
+
+/// Global interrupt enable bit in `CRMD`.
+pub const CRMD_IE: usize = 1 << 2;
+/// Previous privilege level field in `PRMD`.
+pub const PRMD_PPLV: usize = 0b11;
+/// Previous global interrupt enable bit in `PRMD`.
+pub const PRMD_PIE: usize = 1 << 2;
+
+/// Reads the CSR `csr`.
+macro_rules! csr_read {
+    ($csr:expr) => {{
+        let value: usize;
+        unsafe { core::arch::asm!("csrrd {}, {}", out(reg) value, const $csr) };
+        value
+    }};
+}
+
+/// Writes `value` to the CSR `csr`.
+macro_rules! csr_write {
+    ($csr:expr, $value:expr) => {{
+        let value: usize = $value;
+        unsafe { core::arch::asm!("csrwr {}, {}", inout(reg) value => _, const $csr) };
+    }};
+}
+
+/// Sets the bits of `mask` in the CSR `csr` to the ones in `value`.
+macro_rules! csr_xchg {
+    ($csr:expr, $value:expr, $mask:expr) => {{
+        let value: usize = $value;
+        let mask: usize = $mask;
+        unsafe {
+            core::arch::asm!(
+                "csrxchg {}, {}, {}",
+                inout(reg) value => _,
+                in(reg) mask,
+                const $csr,
+            )
+        };
+    }};
+}
+
+pub(super) use {csr_read, csr_write, csr_xchg};
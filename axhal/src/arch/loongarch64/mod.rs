@@ -0,0 +1,215 @@
+mod context;
+mod csr;
+pub(crate) mod trap;
+
+use core::arch::asm;
+
+use memory_addr::{PhysAddr, VirtAddr};
+
+pub use self::context::{start_thread, TaskContext, TrapFrame};
+use self::csr::*;
+use crate::mem::PAGE_SIZE_4K;
+pub use trap::{syscall, SyscallArgs};
+
+pub const TASK_SIZE: usize = 0x40_0000_0000;
+pub const STACK_SIZE: usize = 32 * PAGE_SIZE_4K;
+
+/// Size of the area below the user stack pointer that the kernel must not
+/// touch when it pushes data onto a user stack.
+///
+/// The LoongArch psABI does not define a redzone.
+pub const USER_REDZONE: usize = 0;
+
+/*
+ * This is the location that an ET_DYN program is loaded if exec'ed.
+ * Typical use of this is to invoke "./ld.so someprog" to test out
+ * a new version of the loader.
+ * We need to make sure that it is out of the way of the program
+ * that it will "exec", and that there is sufficient room for the brk.
+ */
+pub const ELF_ET_DYN_BASE: usize = (TASK_SIZE / 3) * 2;
+
+/*
+ * This decides where the kernel will search for a free chunk of vm
+ * space during mmap's.
+ */
+pub const TASK_UNMAPPED_BASE: usize = (TASK_SIZE / 3) & !(PAGE_SIZE_4K - 1);
+
+/// Returns to user space with the trap frame at `kstack_sp`, for a newly
+/// forked task.
+pub fn ret_from_fork(kstack_sp: usize) {
+    extern "C" {
+        fn __ret_from_fork(kstack_sp: usize) -> !;
+    }
+    unsafe { __ret_from_fork(kstack_sp) }
+}
+
+/// Allows the current CPU to respond to interrupts.
+#[inline]
+pub fn enable_irqs() {
+    csr_xchg!(CRMD, CRMD_IE, CRMD_IE);
+}
+
+/// Makes the current CPU to ignore interrupts.
+#[inline]
+pub fn disable_irqs() {
+    csr_xchg!(CRMD, 0, CRMD_IE);
+}
+
+/// Returns whether the current CPU is allowed to respond to interrupts.
+#[inline]
+pub fn irqs_enabled() -> bool {
+    csr_read!(CRMD) & CRMD_IE != 0
+}
+
+/// Relaxes the current CPU and waits for interrupts.
+///
+/// It must be called with interrupts enabled, otherwise it will never return.
+#[inline]
+pub fn wait_for_irqs() {
+    unsafe { asm!("idle 0") };
+}
+
+/// Halt the current CPU.
+#[inline]
+pub fn halt() {
+    disable_irqs();
+    unsafe { asm!("idle 0") }; // should never return
+}
+
+/// Reads the register that stores the current page table root.
+///
+/// Returns the physical address of the page table root.
+#[inline]
+pub fn read_page_table_root() -> PhysAddr {
+    PhysAddr::from(csr_read!(PGDL))
+}
+
+/// Reads the `PGDL` register, the root of the lower half (user space).
+pub fn read_page_table_root0() -> PhysAddr {
+    PhysAddr::from(csr_read!(PGDL))
+}
+
+/// Writes the register to update the current page table root.
+///
+/// Only `PGDL` (the lower half, user space) is switched. The upper half is
+/// translated through the kernel root in `PGDH`, which is set once by
+/// [`write_kernel_page_table_root`].
+///
+/// # Safety
+///
+/// This function is unsafe as it changes the virtual memory address space.
+pub unsafe fn write_page_table_root(root_paddr: PhysAddr) {
+    let old_root = read_page_table_root();
+    trace!("set page table root: {:#x} => {:#x}", old_root, root_paddr);
+    if old_root != root_paddr {
+        csr_write!(PGDL, root_paddr.as_usize());
+        flush_tlb(None);
+    }
+}
+
+/// Writes the `PGDH` register, the root of the upper half (the kernel).
+///
+/// It is called once on each CPU with the kernel page table, before the
+/// kernel uses a mapped (non-DMW) address of the upper half.
+///
+/// # Safety
+///
+/// This function is unsafe as it changes the virtual memory address space.
+pub unsafe fn write_kernel_page_table_root(root_paddr: PhysAddr) {
+    csr_write!(PGDH, root_paddr.as_usize());
+    flush_tlb(None);
+}
+
+/// Writes the `PGDL` register, the root of the lower half (user space).
+///
+/// # Safety
+///
+/// This function is unsafe as it changes the virtual memory address space.
+pub unsafe fn write_page_table_root0(root_paddr: PhysAddr) {
+    csr_write!(PGDL, root_paddr.as_usize());
+    flush_tlb(None);
+}
+
+/// Flushes the TLB.
+///
+/// If `vaddr` is [`None`], flushes the entire TLB. Otherwise, flushes the TLB
+/// entry that maps the given virtual address.
+#[inline]
+pub fn flush_tlb(vaddr: Option<VirtAddr>) {
+    unsafe {
+        if let Some(vaddr) = vaddr {
+            // The global entries and the ones of ASID 0 that map `vaddr`.
+            asm!("dbar 0; invtlb 0x6, $zero, {}", in(reg) vaddr.as_usize());
+        } else {
+            // flush the entire TLB
+            asm!("dbar 0; invtlb 0x0, $zero, $zero");
+        }
+    }
+}
+
+/// Flushes the entire instruction cache.
+#[inline]
+pub fn flush_icache_all() {
+    unsafe { asm!("ibar 0") };
+}
+
+/// Sets the base address of the trap vector (writes `EENTRY`).
+///
+/// All exceptions and interrupts use the same entry (`ECFG.VS` = 0).
+#[inline]
+pub fn set_trap_vector_base(eentry: usize) {
+    csr_xchg!(ECFG, 0, 0x7 << 16);
+    csr_write!(EENTRY, eentry);
+}
+
+/// Sets up the hardware page walker for the 4-level page table with 4K
+/// pages, and installs the TLB refill handler.
+///
+/// # Safety
+///
+/// This function is unsafe as it changes the address translation.
+pub unsafe fn init_tlb_refill() {
+    extern "C" {
+        fn handle_tlb_refill();
+    }
+    // PTbase = 12, PTwidth = 9, Dir1 = 21/9, Dir2 = 30/9, PTEwidth = 8 bytes
+    let pwcl = 12 | (9 << 5) | (21 << 10) | (9 << 15) | (30 << 20) | (9 << 25);
+    // Dir3 = 39/9, no Dir4
+    let pwch = 39 | (9 << 6);
+    csr_write!(PWCL, pwcl);
+    csr_write!(PWCH, pwch);
+    csr_write!(STLBPS, 12);
+    csr_write!(TLBREHI, 12);
+    // The refill handler runs in direct address mode.
+    let entry = crate::mem::virt_to_phys(VirtAddr::from(handle_tlb_refill as usize));
+    csr_write!(TLBRENTRY, entry.as_usize());
+}
+
+/// Reads the thread pointer of the current CPU.
+///
+/// It is used to implement TLS (Thread Local Storage).
+#[inline]
+pub fn read_thread_pointer() -> usize {
+    let tp;
+    unsafe { asm!("move {}, $tp", out(reg) tp) };
+    tp
+}
+
+/// Writes the thread pointer of the current CPU.
+///
+/// It is used to implement TLS (Thread Local Storage).
+///
+/// # Safety
+///
+/// This function is unsafe as it changes the CPU states.
+#[inline]
+pub unsafe fn write_thread_pointer(tp: usize) {
+    asm!("move $tp, {}", in(reg) tp)
+}
+
+pub fn sync_kernel_mappings(_src: PhysAddr, _dst: PhysAddr) {}
+
+/// Does the early architecture setup on the boot CPU, called by
+/// [`arch_init_early`](crate::arch_init_early). Nothing is needed yet.
+pub fn early_init() {}
@@ -0,0 +1,105 @@
+use core::arch::global_asm;
+
+use memory_addr::VirtAddr;
+
+use super::csr::{csr_read, BADV, ESTAT};
+use super::TrapFrame;
+use crate::trap::{PageFaultAccess, PageFaultInfo, TrapCause};
+
+global_asm!(include_str!("trap.S"));
+
+/// The arguments of a syscall, `a0` to `a5`.
+pub type SyscallArgs = [usize; 6];
+
+const ESTAT_ECODE_SHIFT: usize = 16;
+const ESTAT_ECODE_MASK: usize = 0x3f;
+#[cfg(feature = "irq")]
+const ESTAT_IS_MASK: usize = 0x1fff;
+
+/// Exception codes (`ESTAT.Ecode`).
+const ECODE_INT: usize = 0x0;
+const ECODE_PIL: usize = 0x1;
+const ECODE_PIS: usize = 0x2;
+const ECODE_PIF: usize = 0x3;
+const ECODE_PME: usize = 0x4;
+const ECODE_PPI: usize = 0x7;
+const ECODE_SYS: usize = 0xb;
+const ECODE_BRK: usize = 0xc;
+
+#[no_mangle]
+fn loongarch64_trap_handler(tf: &mut TrapFrame, from_user: bool) {
+    let estat = csr_read!(ESTAT);
+    match (estat >> ESTAT_ECODE_SHIFT) & ESTAT_ECODE_MASK {
+        ECODE_INT => {
+            // Each set bit of `ESTAT.IS` is a pending interrupt.
+            #[cfg(feature = "irq")]
+            {
+                let mut pending = estat & ESTAT_IS_MASK;
+                while pending != 0 {
+                    crate::platform::irq::dispatch_irq(pending.trailing_zeros() as usize);
+                    pending &= pending - 1;
+                }
+            }
+        }
+        ECODE_BRK => {
+            debug!("BREAK @ {:#x} ", tf.era);
+            tf.era += 4;
+        }
+        ECODE_SYS => {
+            // `syscall` does not advance ERA: return after it.
+            tf.era += 4;
+            if !crate::trap::dispatch_trap(TrapCause::UserEcall, tf) {
+                warn!("No syscall handler @ {:#x}", tf.era - 4);
+            }
+        }
+        ecode @ (ECODE_PIL | ECODE_PIS | ECODE_PIF | ECODE_PME | ECODE_PPI) => {
+            let badv = csr_read!(BADV);
+            let access = match ecode {
+                ECODE_PIS | ECODE_PME => PageFaultAccess::Write,
+                ECODE_PIF => PageFaultAccess::Execute,
+                _ => PageFaultAccess::Read,
+            };
+            let info = PageFaultInfo {
+                vaddr: VirtAddr::from(badv),
+                ip: tf.era,
+                access,
+                user: from_user,
+            };
+            // An unresolved fault would be taken again on return.
+            if !crate::trap::handle_page_fault(&info, tf) {
+                panic!(
+                    "Unhandled {} Page Fault @ {:#x}, BADV={:#x}:\n{:#x?}",
+                    if from_user { "User" } else { "Kernel" },
+                    tf.era,
+                    badv,
+                    tf
+                );
+            }
+        }
+        ecode => {
+            panic!(
+                "Unhandled exception @ {:#x}: ESTAT={:#x} (Ecode {:#x}), from_user={}",
+                tf.era, estat, ecode, from_user
+            );
+        }
+    }
+}
+
+pub fn syscall_args(tf: &TrapFrame) -> SyscallArgs {
+    [
+        tf.regs[4], tf.regs[5], tf.regs[6], tf.regs[7], tf.regs[8], tf.regs[9],
+    ]
+}
+
+/// Handles a syscall from user space, the number is in `a7` and the result
+/// goes to `a0`.
+///
+/// It is called by the handler of [`TrapCause::UserEcall`], with ERA
+/// already past the `syscall` instruction.
+pub fn syscall<F>(tf: &mut TrapFrame, do_syscall: F)
+where
+    F: FnOnce(SyscallArgs, usize) -> usize,
+{
+    let args = syscall_args(tf);
+    tf.regs[4] = do_syscall(args, tf.regs[11]);
+}
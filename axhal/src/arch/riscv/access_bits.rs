@@ -0,0 +1,129 @@
+//! Harvesting the accessed and dirty bits of the page table entries.
+//!
+//! The memory manager ages the pages by clearing their `A` bits and checking
+//! later whether they have been set again, and finds the pages to write back
+//! by their `D` bits. Whether the bits are set by the hardware (Svadu) or by
+//! the page fault handler (Svade), a cleared bit is only set again by an
+//! access that misses the TLB, so the entries are flushed after clearing.
+//!
+//! The bits are cleared atomically, so that an update by the hardware walker
+//! on another CPU is not lost. The 16 entries of a NAPOT mapping share their
+//! bits.
+
+use axerrno::LinuxError;
+use core::ops::Range;
+use core::sync::atomic::{AtomicUsize, Ordering};
+use memory_addr::{PhysAddr, VirtAddr};
+
+use super::huge_page::leaf_entry;
+use super::napot::NAPOT_64K_SIZE;
+use super::page_walk::{PTE_A, PTE_D, PTE_N};
+use super::tlb::TlbBatch;
+use crate::mem::PAGE_SIZE_4K;
+
+bitflags::bitflags! {
+    /// The accessed and dirty bits of a page.
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub struct AccessBits: usize {
+        /// Accessed (`A`), the page has been read, written or executed.
+        const ACCESSED = PTE_A;
+        /// Dirty (`D`), the page has been written.
+        const DIRTY = PTE_D;
+    }
+}
+
+/// Clears `clear` in the leaf entry (or entries, for NAPOT) that maps
+/// `vaddr`, without flushing the TLB. Returns the bits that were set, and
+/// the address and the size of the page.
+unsafe fn take_bits(
+    root: PhysAddr,
+    vaddr: VirtAddr,
+    clear: AccessBits,
+) -> Result<(AccessBits, VirtAddr, usize), LinuxError> {
+    let (pte, page_size) = leaf_entry(root, vaddr)?;
+    let (mut first, mut count, mut size) = (pte as *mut usize, 1, page_size.size());
+    if *first & PTE_N != 0 {
+        count = NAPOT_64K_SIZE / PAGE_SIZE_4K;
+        first = first.sub((vaddr.as_usize() / PAGE_SIZE_4K) % count);
+        size = NAPOT_64K_SIZE;
+    }
+    let mut found = 0;
+    for i in 0..count {
+        let pte = &*(first.add(i) as *const AtomicUsize);
+        found |= pte.fetch_and(!clear.bits(), Ordering::AcqRel);
+    }
+    let base = VirtAddr::from(vaddr.as_usize() & !(size - 1));
+    Ok((AccessBits::from_bits_truncate(found), base, size))
+}
+
+unsafe fn test_and_clear(
+    root: PhysAddr,
+    vaddr: VirtAddr,
+    bit: AccessBits,
+) -> Result<bool, LinuxError> {
+    let (found, base, _) = take_bits(root, vaddr, bit)?;
+    if found.contains(bit) {
+        let mut batch = TlbBatch::new();
+        batch.queue(base);
+        batch.flush();
+    }
+    Ok(found.contains(bit))
+}
+
+/// Returns whether the page that contains `vaddr` has been accessed, and
+/// clears its accessed bit.
+///
+/// The TLB entry of the page is flushed (on all CPUs with `smp`). Returns
+/// [`LinuxError::EFAULT`] if the page is not mapped.
+///
+/// # Safety
+///
+/// `root` must be a valid page table, and nobody else may remove the
+/// mapping concurrently.
+pub unsafe fn test_and_clear_accessed(root: PhysAddr, vaddr: VirtAddr) -> Result<bool, LinuxError> {
+    test_and_clear(root, vaddr, AccessBits::ACCESSED)
+}
+
+/// Returns whether the page that contains `vaddr` has been written, and
+/// clears its dirty bit, e.g. before writing it back.
+///
+/// See [`test_and_clear_accessed`].
+///
+/// # Safety
+///
+/// See [`test_and_clear_accessed`].
+pub unsafe fn test_and_clear_dirty(root: PhysAddr, vaddr: VirtAddr) -> Result<bool, LinuxError> {
+    test_and_clear(root, vaddr, AccessBits::DIRTY)
+}
+
+/// Clears `clear` in the pages mapped in `range`, and calls `f` with the
+/// address, the size and the accessed and dirty bits (before clearing) of
+/// each of them. Returns the number of pages.
+///
+/// The pages not mapped are skipped. The TLB entries of the pages whose bits
+/// have been cleared are flushed at the end, by a single batch.
+///
+/// # Safety
+///
+/// See [`test_and_clear_accessed`].
+pub unsafe fn harvest_access_bits(
+    root: PhysAddr,
+    range: Range<VirtAddr>,
+    clear: AccessBits,
+    mut f: impl FnMut(VirtAddr, usize, AccessBits),
+) -> usize {
+    let mut batch = TlbBatch::new();
+    let mut pages = 0;
+    for mapping in super::page_table_mappings(root, range) {
+        let Ok((found, base, size)) = take_bits(root, mapping.vaddr, clear) else {
+            continue;
+        };
+        if found.intersects(clear) {
+            batch.queue(base);
+        }
+        f(base, size, found);
+        pages += 1;
+    }
+    batch.flush();
+    pages
+}
@@ -0,0 +1,152 @@
+//! Alternative instructions, patched at boot.
+//!
+//! `asm_alternative!` emits an instruction sequence and a replacement of
+//! the same length (checked by the assembler), in `.altinstr_replacement`,
+//! with an entry in `.alternative` that keys it on an ISA extension
+//! ([`alt_key`]) or on an erratum of the cores (`ALT_ERRATA_*`, see
+//! [`errata`](super::errata)), and `asm_alternative_2!` emits two
+//! replacements with their keys, the second one winning if both are set. On the
+//! primary CPU, [`apply_alternatives`] copies the replacements whose key is
+//! set over the original sequences, before the secondary CPUs start. A hot
+//! path thus runs the best instructions for the CPU without a branch.
+//!
+//! The sequences are assembled without compressed instructions and linker
+//! relaxation, so that their lengths are known. A replacement runs at
+//! another address than where it is assembled, so it must not be
+//! PC-relative (no `auipc`, branch or jump out of it).
+
+use super::cpufeature::{cpu_has, Feature};
+use super::errata::has_erratum;
+
+/// The first key of the errata.
+pub(crate) const ALT_ERRATA_BASE: u32 = 0x100;
+
+/// Returns the key of the alternatives for the ISA extension `feature`.
+pub(crate) const fn alt_key(feature: Feature) -> u32 {
+    feature as u32
+}
+
+/// Emits `asm!` with `$old` as the template, and `$new` as its replacement
+/// when the key `$key` (a `u32` constant) is set at boot. The operands
+/// follow, without `options`.
+macro_rules! asm_alternative {
+    ($old:literal, $new:literal, $key:expr $(, $($operands:tt)*)?) => {
+        core::arch::asm!(
+            concat!(
+                ".option push\n",
+                ".option norvc\n",
+                ".option norelax\n",
+                "886:\n",
+                $old,
+                "\n887:\n",
+                ".pushsection .altinstr_replacement, \"a\"\n",
+                "888:\n",
+                $new,
+                "\n889:\n",
+                ".org . - (889b - 888b) + (887b - 886b)\n",
+                ".org . - (887b - 886b) + (889b - 888b)\n",
+                ".popsection\n",
+                ".pushsection .alternative, \"a\"\n",
+                ".balign 8\n",
+                ".dword 886b, 888b\n",
+                ".word 887b - 886b, {alt_key}\n",
+                ".popsection\n",
+                ".option pop",
+            )
+            $(, $($operands)*)?,
+            alt_key = const $key,
+        )
+    };
+}
+
+/// Emits `asm!` with `$old` as the template, `$new1` as its replacement
+/// when the key `$key1` is set, and `$new2` when `$key2` is set.
+macro_rules! asm_alternative_2 {
+    ($old:literal, $new1:literal, $key1:expr, $new2:literal, $key2:expr
+        $(, $($operands:tt)*)?) => {
+        core::arch::asm!(
+            concat!(
+                ".option push\n",
+                ".option norvc\n",
+                ".option norelax\n",
+                "882:\n",
+                $old,
+                "\n883:\n",
+                ".pushsection .altinstr_replacement, \"a\"\n",
+                "884:\n",
+                $new1,
+                "\n885:\n",
+                ".org . - (885b - 884b) + (883b - 882b)\n",
+                ".org . - (883b - 882b) + (885b - 884b)\n",
+                "886:\n",
+                $new2,
+                "\n887:\n",
+                ".org . - (887b - 886b) + (883b - 882b)\n",
+                ".org . - (883b - 882b) + (887b - 886b)\n",
+                ".popsection\n",
+                ".pushsection .alternative, \"a\"\n",
+                ".balign 8\n",
+                ".dword 882b, 884b\n",
+                ".word 883b - 882b, {alt_key1}\n",
+                ".dword 882b, 886b\n",
+                ".word 883b - 882b, {alt_key2}\n",
+                ".popsection\n",
+                ".option pop",
+            )
+            $(, $($operands)*)?,
+            alt_key1 = const $key1,
+            alt_key2 = const $key2,
+        )
+    };
+}
+
+/// An entry of `.alternative`.
+#[repr(C)]
+struct AltEntry {
+    /// The address of the original instructions.
+    old: usize,
+    /// The address of the replacement.
+    new: usize,
+    len: u32,
+    key: u32,
+}
+
+extern "C" {
+    static __alt_instructions: AltEntry;
+    static __alt_instructions_end: AltEntry;
+}
+
+fn alt_entries() -> &'static [AltEntry] {
+    unsafe {
+        let start = core::ptr::addr_of!(__alt_instructions);
+        let end = core::ptr::addr_of!(__alt_instructions_end);
+        core::slice::from_raw_parts(start, end.offset_from(start) as usize)
+    }
+}
+
+fn key_enabled(key: u32) -> bool {
+    match key.checked_sub(ALT_ERRATA_BASE) {
+        Some(_) => has_erratum(key),
+        None => Feature::ALL
+            .get(key as usize)
+            .is_some_and(|&feature| cpu_has(feature)),
+    }
+}
+
+/// Patches the alternatives whose key is set. Called by
+/// [`early_init`](super::early_init) on the primary CPU, after the CPU
+/// features are probed and before the secondary CPUs start.
+pub(super) fn apply_alternatives() {
+    let entries = alt_entries();
+    let mut patched = 0;
+    for entry in entries.iter().filter(|entry| key_enabled(entry.key)) {
+        let new =
+            unsafe { core::slice::from_raw_parts(entry.new as *const u8, entry.len as usize) };
+        match super::text_patch::poke_text(entry.old, new) {
+            Ok(()) => patched += 1,
+            Err(err) => warn!("Alternative at {:#x} not patched: {:?}", entry.old, err),
+        }
+    }
+    super::local_flush_icache_all();
+    info!("Alternatives: {} of {} patched", patched, entries.len());
+}
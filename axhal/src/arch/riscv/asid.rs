@@ -0,0 +1,180 @@
+//! Address space identifiers (ASIDs).
+//!
+//! Each address space can get its own ASID, which tags its TLB entries, so
+//! that switching to it needs no `sfence.vma`. ASID 0 is reserved for the
+//! address spaces without one, which are fenced on every switch as before.
+//!
+//! An ASID may have been used by another address space when it is allocated:
+//! each CPU then flushes its entries the first time it switches to it.
+
+use core::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use memory_addr::{PhysAddr, VirtAddr};
+use riscv::register::satp;
+
+#[cfg(feature = "smp")]
+use crate::mem::PAGE_SIZE_4K;
+
+/// The maximum ASID width of `satp` (RV64).
+pub const MAX_ASID_BITS: usize = 16;
+
+const SATP_ASID_SHIFT: usize = 44;
+const SATP_ASID_MASK: usize = (1 << MAX_ASID_BITS) - 1;
+
+/// The number of implemented ASID bits, probed by [`probe_asid_bits`].
+static ASID_BITS: AtomicUsize = AtomicUsize::new(0);
+
+const ASID_MAP_WORDS: usize = (1 << MAX_ASID_BITS) / 64;
+#[allow(clippy::declare_interior_mutable_const)]
+const ASID_MAP_INIT: AtomicU64 = AtomicU64::new(0);
+
+/// The allocated ASIDs, one bit each.
+static ASID_MAP: [AtomicU64; ASID_MAP_WORDS] = [ASID_MAP_INIT; ASID_MAP_WORDS];
+
+#[allow(clippy::declare_interior_mutable_const)]
+const ASID_STALE_INIT: [AtomicU64; ASID_MAP_WORDS] = [ASID_MAP_INIT; ASID_MAP_WORDS];
+
+/// The ASIDs allocated since each CPU last switched to them, whose TLB
+/// entries on that CPU may be left from a previous address space.
+static ASID_STALE: [[AtomicU64; ASID_MAP_WORDS]; axconfig::SMP] = [ASID_STALE_INIT; axconfig::SMP];
+
+/// Where the next search for a free ASID starts, so that a freed ASID is
+/// not reused right away.
+static ASID_HINT: AtomicUsize = AtomicUsize::new(1);
+
+/// Probes the number of implemented ASID bits, by writing all ones to the
+/// ASID field of `satp` and reading it back.
+///
+/// The root is left unchanged, so it is safe with paging enabled. If paging
+/// is not enabled, no ASIDs are used.
+pub(super) fn probe_asid_bits() {
+    if satp::read().mode() == satp::Mode::Bare {
+        return;
+    }
+    let old_satp = satp::read().bits();
+    let asid = unsafe {
+        let _guard = kernel_guard_base::IrqSave::new();
+        satp::write(old_satp | (SATP_ASID_MASK << SATP_ASID_SHIFT));
+        let asid = (satp::read().bits() >> SATP_ASID_SHIFT) & SATP_ASID_MASK;
+        satp::write(old_satp);
+        riscv::asm::sfence_vma_all();
+        asid
+    };
+    let bits = asid.trailing_ones() as usize;
+    info!("ASID bits: {}", bits);
+    ASID_BITS.store(bits, Ordering::Relaxed);
+}
+
+/// Returns the number of implemented ASID bits, 0 if ASIDs are not
+/// supported.
+#[inline]
+pub fn asid_bits() -> usize {
+    ASID_BITS.load(Ordering::Relaxed)
+}
+
+/// Allocates an ASID for a new address space.
+///
+/// Returns [`None`] if the CPU has no ASIDs or all of them are in use, then
+/// the address space should use ASID 0.
+pub fn alloc_asid() -> Option<usize> {
+    let bits = asid_bits();
+    if bits == 0 {
+        return None;
+    }
+    let count = 1 << bits;
+    let hint = ASID_HINT.load(Ordering::Relaxed) % count;
+    for i in 0..count {
+        let asid = (hint + i) % count;
+        if asid == 0 {
+            continue;
+        }
+        let bit = 1 << (asid % 64);
+        if ASID_MAP[asid / 64].fetch_or(bit, Ordering::Acquire) & bit == 0 {
+            for stale in ASID_STALE.iter() {
+                stale[asid / 64].fetch_or(bit, Ordering::Relaxed);
+            }
+            ASID_HINT.store(asid + 1, Ordering::Relaxed);
+            return Some(asid);
+        }
+    }
+    None
+}
+
+/// Frees an ASID allocated by [`alloc_asid`], when its address space is
+/// destroyed.
+///
+/// Its TLB entries are flushed on all CPUs first, so the next address space
+/// that gets it does not see them.
+pub fn free_asid(asid: usize) {
+    if asid == 0 || asid > SATP_ASID_MASK {
+        return;
+    }
+    flush_tlb_asid(asid);
+    #[cfg(feature = "smp")]
+    {
+        let mut others = crate::cpu::online_cpus();
+        others.remove(crate::cpu::_this_cpu_id());
+        if super::remote_sfence_vma_asid(others, VirtAddr::from(0), usize::MAX, asid).is_err() {
+            super::flush_tlb_all_cpus(None, Some(asid));
+        }
+    }
+    ASID_MAP[asid / 64].fetch_and(!(1 << (asid % 64)), Ordering::Release);
+}
+
+/// Flushes the (non-global) TLB entries of `asid` on the current CPU.
+#[inline]
+pub fn flush_tlb_asid(asid: usize) {
+    unsafe { core::arch::asm!("sfence.vma zero, {}", in(reg) asid) }
+}
+
+/// Flushes the TLB entry of `asid` that maps `vaddr` on all CPUs, after a
+/// change of the page table.
+pub fn flush_tlb_page_asid(vaddr: VirtAddr, asid: usize) {
+    #[cfg(feature = "smp")]
+    super::flush_tlb_all_cpus(Some(vaddr..vaddr + PAGE_SIZE_4K), Some(asid));
+    #[cfg(not(feature = "smp"))]
+    local_flush_tlb_page_asid(vaddr, asid);
+}
+
+/// Flushes the TLB entry of `asid` that maps `vaddr` on the current CPU.
+#[inline]
+pub(super) fn local_flush_tlb_page_asid(vaddr: VirtAddr, asid: usize) {
+    unsafe {
+        asm_alternative!(
+            "sfence.vma {}, {}",
+            "sfence.vma zero, {1}",
+            super::errata::ALT_ERRATA_SIFIVE_CIP_1200,
+            in(reg) vaddr.as_usize(),
+            in(reg) asid
+        )
+    }
+}
+
+/// Switches to the page table at `root` with the ASID `asid`.
+///
+/// With a nonzero ASID, the TLB is not fenced: the entries of other ASIDs do
+/// not match, and the ones of `asid` are still valid, provided that every
+/// change to the page table has been followed by [`flush_tlb_page_asid`].
+/// Only the first switch of the CPU to `asid` after [`alloc_asid`] flushes
+/// its entries, that may be left from the previous address space with it.
+/// With ASID 0, it is the same as
+/// [`write_page_table_root`](super::write_page_table_root).
+///
+/// # Safety
+///
+/// This function is unsafe as it changes the virtual memory address space.
+pub unsafe fn write_page_table_root_asid(root_paddr: PhysAddr, asid: usize) {
+    if asid == 0 {
+        super::write_page_table_root(root_paddr);
+        return;
+    }
+    let new_satp = super::make_satp(super::paging_mode().satp_mode(), asid, root_paddr);
+    trace!("set satp: {:#x} => {:#x}", satp::read().bits(), new_satp);
+    if satp::read().bits() != new_satp {
+        satp::write(new_satp);
+    }
+    let bit = 1 << (asid % 64);
+    let stale = &ASID_STALE[crate::cpu::_this_cpu_id()][asid / 64];
+    if stale.fetch_and(!bit, Ordering::Relaxed) & bit != 0 {
+        flush_tlb_asid(asid);
+    }
+}
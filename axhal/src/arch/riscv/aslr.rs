@@ -0,0 +1,226 @@
+//! Randomization of the user address space layout (ASLR).
+//!
+//! [`stack_top`], [`elf_et_dyn_base`] and [`task_unmapped_base`] are the
+//! fixed bases of the selected paging mode. At each exec, the OS draws a
+//! [`UserLayout`], that moves them down (the stack) or up (the `mmap` area,
+//! the `ET_DYN` programs and the `brk` heap) by random numbers of pages, as
+//! Linux does. The random offsets come from [`random_bytes`], so that the
+//! execs do not use up the seed sources.
+//!
+//! A compat (RV32) task gets a [`UserLayout::new_compat`], with the bases
+//! of [`COMPAT_TASK_SIZE`] and fewer random bits.
+//!
+//! [`set_randomize_va_space`] selects what is randomized (all but the `brk`
+//! by default, none with the `norandmaps` boot parameter), and
+//! [`set_mmap_rnd_bits`] the entropy of the `mmap` and `ET_DYN` offsets.
+
+use axerrno::LinuxError;
+use core::sync::atomic::{AtomicU32, AtomicU8, Ordering};
+
+use super::{
+    elf_et_dyn_base, random_bytes, stack_top, task_unmapped_base, COMPAT_ELF_ET_DYN_BASE,
+    COMPAT_STACK_TOP, COMPAT_TASK_SIZE, COMPAT_TASK_UNMAPPED_BASE,
+};
+use crate::mem::PAGE_SIZE_4K;
+
+/// The minimum number of random bits of the `mmap` offset, in pages.
+pub const MMAP_RND_BITS_MIN: u32 = 18;
+/// The maximum number of random bits of the `mmap` offset, in pages: 64G
+/// of the 256G of Sv39.
+pub const MMAP_RND_BITS_MAX: u32 = 24;
+/// The number of random bits of the stack offset, in pages (1G).
+pub const STACK_RND_BITS: u32 = 18;
+/// The number of random bits of the `mmap` and `ET_DYN` offsets of a compat
+/// task, in pages (1M).
+pub const MMAP_RND_COMPAT_BITS: u32 = 8;
+/// The number of random bits of the stack offset of a compat task, in pages
+/// (8M).
+pub const STACK_RND_COMPAT_BITS: u32 = 11;
+/// The range of the `brk` offset.
+pub const BRK_RND_SIZE: usize = 32 * 1024 * 1024;
+
+/// What is randomized, as the Linux `randomize_va_space`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+#[repr(u8)]
+pub enum RandomizeVaSpace {
+    /// Nothing, the fixed layout.
+    Off = 0,
+    /// The stack, the `mmap` area and the `ET_DYN` programs.
+    Conservative = 1,
+    /// Also the `brk` heap.
+    Full = 2,
+}
+
+static RANDOMIZE_VA_SPACE: AtomicU8 = AtomicU8::new(RandomizeVaSpace::Conservative as u8);
+static MMAP_RND_BITS: AtomicU32 = AtomicU32::new(MMAP_RND_BITS_MIN);
+
+/// Selects what the next [`UserLayout`]s randomize.
+pub fn set_randomize_va_space(level: RandomizeVaSpace) {
+    RANDOMIZE_VA_SPACE.store(level as u8, Ordering::Relaxed);
+}
+
+/// Returns what the [`UserLayout`]s randomize.
+pub fn randomize_va_space() -> RandomizeVaSpace {
+    match RANDOMIZE_VA_SPACE.load(Ordering::Relaxed) {
+        0 => RandomizeVaSpace::Off,
+        1 => RandomizeVaSpace::Conservative,
+        _ => RandomizeVaSpace::Full,
+    }
+}
+
+/// Sets the number of random bits of the `mmap` and `ET_DYN` offsets, in
+/// pages.
+///
+/// Returns [`LinuxError::EINVAL`] if `bits` is not in
+/// [`MMAP_RND_BITS_MIN`]..=[`MMAP_RND_BITS_MAX`].
+pub fn set_mmap_rnd_bits(bits: u32) -> Result<(), LinuxError> {
+    if !(MMAP_RND_BITS_MIN..=MMAP_RND_BITS_MAX).contains(&bits) {
+        return Err(LinuxError::EINVAL);
+    }
+    MMAP_RND_BITS.store(bits, Ordering::Relaxed);
+    Ok(())
+}
+
+/// Returns the number of random bits of the `mmap` and `ET_DYN` offsets.
+pub fn mmap_rnd_bits() -> u32 {
+    MMAP_RND_BITS.load(Ordering::Relaxed)
+}
+
+/// Registers the `norandmaps` boot parameter, that turns the randomization
+/// off. The platform initialization calls it.
+pub fn init_aslr() {
+    crate::platform::cmdline::register_early_param("norandmaps", |_| {
+        set_randomize_va_space(RandomizeVaSpace::Off);
+        Ok(())
+    });
+}
+
+/// Returns a random number of pages below `1 << bits`, in bytes.
+fn random_pages(bits: u32) -> usize {
+    let mut buf = [0; core::mem::size_of::<usize>()];
+    random_bytes(&mut buf);
+    let pages = usize::from_ne_bytes(buf) & ((1 << bits) - 1);
+    pages * PAGE_SIZE_4K
+}
+
+/// The layout of the address space of a new program, drawn at each exec.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct UserLayout {
+    stack_offset: usize,
+    mmap_offset: usize,
+    et_dyn_offset: usize,
+    brk_offset: usize,
+    compat: bool,
+}
+
+impl UserLayout {
+    /// Draws the random offsets of a new program, as selected by
+    /// [`set_randomize_va_space`].
+    pub fn new() -> Self {
+        Self::draw(false)
+    }
+
+    /// Draws the random offsets of a new compat program, below
+    /// [`COMPAT_TASK_SIZE`].
+    pub fn new_compat() -> Self {
+        Self::draw(true)
+    }
+
+    fn draw(compat: bool) -> Self {
+        let level = randomize_va_space();
+        if level == RandomizeVaSpace::Off {
+            return Self {
+                compat,
+                ..Self::fixed()
+            };
+        }
+        let (stack_bits, bits) = if compat {
+            (STACK_RND_COMPAT_BITS, MMAP_RND_COMPAT_BITS)
+        } else {
+            (STACK_RND_BITS, mmap_rnd_bits())
+        };
+        let brk_pages = (BRK_RND_SIZE / PAGE_SIZE_4K).trailing_zeros();
+        Self {
+            stack_offset: random_pages(stack_bits),
+            mmap_offset: random_pages(bits),
+            et_dyn_offset: random_pages(bits),
+            brk_offset: if level == RandomizeVaSpace::Full {
+                random_pages(brk_pages)
+            } else {
+                0
+            },
+            compat,
+        }
+    }
+
+    /// Returns the layout without randomization.
+    pub const fn fixed() -> Self {
+        Self {
+            stack_offset: 0,
+            mmap_offset: 0,
+            et_dyn_offset: 0,
+            brk_offset: 0,
+            compat: false,
+        }
+    }
+
+    /// Returns whether it is the layout of a compat program.
+    pub const fn is_compat(&self) -> bool {
+        self.compat
+    }
+
+    /// Returns the size of the address space.
+    pub fn task_size(&self) -> usize {
+        if self.compat {
+            COMPAT_TASK_SIZE
+        } else {
+            super::task_size()
+        }
+    }
+
+    /// Returns the top of the user stack, below [`stack_top`] (or
+    /// [`COMPAT_STACK_TOP`]).
+    pub fn stack_top(&self) -> usize {
+        let top = if self.compat {
+            COMPAT_STACK_TOP
+        } else {
+            stack_top()
+        };
+        top - self.stack_offset
+    }
+
+    /// Returns the base of the `mmap` area, above [`task_unmapped_base`] (or
+    /// [`COMPAT_TASK_UNMAPPED_BASE`]).
+    pub fn mmap_base(&self) -> usize {
+        let base = if self.compat {
+            COMPAT_TASK_UNMAPPED_BASE
+        } else {
+            task_unmapped_base()
+        };
+        base + self.mmap_offset
+    }
+
+    /// Returns the load address of an `ET_DYN` program, above
+    /// [`elf_et_dyn_base`] (or [`COMPAT_ELF_ET_DYN_BASE`]).
+    pub fn elf_et_dyn_base(&self) -> usize {
+        let base = if self.compat {
+            COMPAT_ELF_ET_DYN_BASE
+        } else {
+            elf_et_dyn_base()
+        };
+        base + self.et_dyn_offset
+    }
+
+    /// Returns the start of the `brk` heap of a program whose image ends at
+    /// `end`: the page after it, moved up with [`RandomizeVaSpace::Full`].
+    pub fn brk_base(&self, end: usize) -> usize {
+        let end = (end + PAGE_SIZE_4K - 1) & !(PAGE_SIZE_4K - 1);
+        end + self.brk_offset
+    }
+}
+
+impl Default for UserLayout {
+    fn default() -> Self {
+        Self::new()
+    }
+}
@@ -0,0 +1,312 @@
+//! Emulation of the atomic instructions for the user binaries, on cores
+//! without the A extension.
+//!
+//! Their `lr`, `sc` and AMOs raise illegal instruction exceptions, which
+//! [`handle_illegal_instruction`](super::handle_illegal_instruction) passes
+//! here. Each emulated access runs under a global lock with the interrupts
+//! disabled, so they are atomic with respect to each other (not to the plain
+//! stores of another CPU). The word is faulted in before the lock is taken,
+//! by passing the fault the user access would raise to the page fault
+//! handler, so it can sleep there. If the page is unmapped again before the
+//! access, the fault under the lock must be failed by the handler (see
+//! [`PageFaultHandler`](crate::trap::PageFaultHandler)), and the access is
+//! retried from the start a few times, unless the word was mapped already
+//! (e.g. the fault comes from the PMP).
+//!
+//! An `lr` takes a reservation on its CPU, that an `sc` to the same address
+//! only honours if no emulated store happened since and the task was not
+//! switched out: an interrupted LR/SC sequence fails its `sc` and restarts,
+//! as on hardware. The user space built for such cores may also call the
+//! `sysriscv` cmpxchg ([`sys_riscv_atomic`]) instead.
+
+use axerrno::LinuxError;
+use memory_addr::VirtAddr;
+use spinbase::SpinNoIrq;
+
+use super::misaligned::{read_gpr, write_gpr};
+use super::{__get_user_u32, __get_user_u64, __put_user_u32, __put_user_u64, access_ok, TrapFrame};
+use super::{read_page_table_root, walk, PteFlags};
+use crate::trap::{PageFaultAccess, PageFaultInfo};
+
+/// `sysriscv` command: 32-bit compare-and-exchange.
+pub const RISCV_ATOMIC_CMPXCHG: usize = 1;
+/// `sysriscv` command: 64-bit compare-and-exchange.
+pub const RISCV_ATOMIC_CMPXCHG64: usize = 2;
+
+/// The major opcode of the atomic instructions.
+const OPCODE_AMO: u32 = 0x2f;
+const OPCODE_MASK: u32 = 0x7f;
+
+const AMOADD: u32 = 0b00000;
+const AMOSWAP: u32 = 0b00001;
+const LR: u32 = 0b00010;
+const SC: u32 = 0b00011;
+const AMOXOR: u32 = 0b00100;
+const AMOOR: u32 = 0b01000;
+const AMOAND: u32 = 0b01100;
+const AMOMIN: u32 = 0b10000;
+const AMOMAX: u32 = 0b10100;
+const AMOMINU: u32 = 0b11000;
+const AMOMAXU: u32 = 0b11100;
+
+/// The number of emulated stores so far, under the lock of all the emulated
+/// accesses.
+static STORE_GENERATION: SpinNoIrq<u64> = SpinNoIrq::new(0);
+
+/// No reservation.
+const NO_RESERVATION: usize = usize::MAX;
+
+/// The address reserved by the last `lr` on this CPU, or [`NO_RESERVATION`].
+#[percpu2::def_percpu]
+static RESERVATION_ADDR: usize = NO_RESERVATION;
+
+/// The store generation at the last `lr` on this CPU.
+#[percpu2::def_percpu]
+static RESERVATION_GENERATION: u64 = 0;
+
+/// Drops the reservation of this CPU, on context switch.
+pub(super) fn clear_reservation() {
+    unsafe { RESERVATION_ADDR.write_current_raw(NO_RESERVATION) };
+}
+
+/// A user word of 4 or 8 bytes.
+#[derive(Clone, Copy)]
+enum Width {
+    W,
+    D,
+}
+
+impl Width {
+    const fn size(self) -> usize {
+        match self {
+            Width::W => 4,
+            Width::D => 8,
+        }
+    }
+
+    /// Returns the word at `addr`, sign-extended for `W`.
+    fn load(self, addr: usize) -> Option<usize> {
+        match self {
+            Width::W => match __get_user_u32(addr) {
+                (value, 0) => Some(value as i32 as usize),
+                _ => None,
+            },
+            Width::D => match __get_user_u64(addr) {
+                (value, 0) => Some(value as usize),
+                _ => None,
+            },
+        }
+    }
+
+    fn store(self, addr: usize, value: usize) -> Option<()> {
+        let err = match self {
+            Width::W => __put_user_u32(value as u32, addr),
+            Width::D => __put_user_u64(value as u64, addr),
+        };
+        (err == 0).then_some(())
+    }
+
+    /// Returns `value` as a signed word, sign-extended for `W`.
+    const fn signed(self, value: usize) -> isize {
+        match self {
+            Width::W => value as i32 as isize,
+            Width::D => value as isize,
+        }
+    }
+
+    /// Returns `value` as an unsigned word.
+    const fn unsigned(self, value: usize) -> usize {
+        match self {
+            Width::W => value as u32 as usize,
+            Width::D => value,
+        }
+    }
+}
+
+/// Returns the new value of an AMO `op` on `old` with `src`.
+fn amo_result(op: u32, width: Width, old: usize, src: usize) -> Option<usize> {
+    let (s_old, s_src) = (width.signed(old), width.signed(src));
+    let (u_old, u_src) = (width.unsigned(old), width.unsigned(src));
+    Some(match op {
+        AMOADD => old.wrapping_add(src),
+        AMOSWAP => src,
+        AMOXOR => old ^ src,
+        AMOOR => old | src,
+        AMOAND => old & src,
+        AMOMIN => s_old.min(s_src) as usize,
+        AMOMAX => s_old.max(s_src) as usize,
+        AMOMINU => u_old.min(u_src),
+        AMOMAXU => u_old.max(u_src),
+        _ => return None,
+    })
+}
+
+/// The number of times an emulated access that faults under the lock is
+/// retried.
+const MAX_FAULT_RETRIES: usize = 3;
+
+/// Faults in the user word at `addr` for reading, or writing if `write`,
+/// before the lock is taken. Unless it is already mapped so, the fault the
+/// user access would raise is passed to the page fault handler, with the
+/// interrupts as in the trap of `tf`.
+///
+/// Returns whether the word was mapped already, or [`None`] if the fault is
+/// not resolved.
+fn fault_in_word(tf: &mut TrapFrame, addr: usize, write: bool) -> Option<bool> {
+    let (access, needed) = match write {
+        true => (PageFaultAccess::Write, PteFlags::W | PteFlags::D),
+        false => (PageFaultAccess::Read, PteFlags::R),
+    };
+    let vaddr = VirtAddr::from(addr);
+    let mapped = walk(read_page_table_root(), vaddr)
+        .is_some_and(|(_, flags, _)| flags.contains(needed | PteFlags::U | PteFlags::A));
+    if mapped {
+        return Some(true);
+    }
+    let info = PageFaultInfo {
+        vaddr,
+        ip: tf.sepc,
+        access,
+        user: true,
+    };
+    crate::trap::handle_page_fault(&info, tf).then_some(false)
+}
+
+/// Runs `access` of the user word at `addr` (under the lock), once it is
+/// faulted in with [`fault_in_word`]. If it faults, it is retried up to
+/// [`MAX_FAULT_RETRIES`] times, and not at all if the word was mapped
+/// already. Returns [`None`] if the word cannot be accessed.
+fn access_user_word<T>(
+    tf: &mut TrapFrame,
+    addr: usize,
+    write: bool,
+    mut access: impl FnMut() -> Option<T>,
+) -> Option<T> {
+    for _ in 0..=MAX_FAULT_RETRIES {
+        let mapped = fault_in_word(tf, addr, write)?;
+        if let Some(value) = access() {
+            return Some(value);
+        }
+        if mapped {
+            break;
+        }
+    }
+    None
+}
+
+/// Emulates the atomic instruction `insn` of the user trap `tf`. Returns
+/// `false` if it is not one, or if its access faults or is misaligned (the
+/// task then gets `SIGILL`).
+pub(super) fn emulate_atomic(tf: &mut TrapFrame, insn: u32) -> bool {
+    if !tf.from_user() || insn & OPCODE_MASK != OPCODE_AMO {
+        return false;
+    }
+    let width = match (insn >> 12) & 0b111 {
+        0b010 => Width::W,
+        0b011 => Width::D,
+        _ => return false,
+    };
+    let op = insn >> 27;
+    let rd = ((insn >> 7) & 0x1f) as usize;
+    let addr = read_gpr(tf, ((insn >> 15) & 0x1f) as usize);
+    let src = read_gpr(tf, ((insn >> 20) & 0x1f) as usize);
+    if addr % width.size() != 0 || !access_ok(addr, width.size()) {
+        return false;
+    }
+
+    let result = access_user_word(tf, addr, op != LR, || atomic_op(width, op, addr, src));
+    match result.flatten() {
+        Some(value) => {
+            write_gpr(tf, rd, value);
+            true
+        }
+        None => false,
+    }
+}
+
+/// Runs the atomic instruction `op` at `addr` under the lock. Returns
+/// [`None`] if its access faults, or else the value of `rd`, [`None`] if
+/// `op` is not an atomic instruction.
+fn atomic_op(width: Width, op: u32, addr: usize, src: usize) -> Option<Option<usize>> {
+    let mut generation = STORE_GENERATION.lock();
+    Some(match op {
+        LR => {
+            let value = width.load(addr)?;
+            unsafe {
+                RESERVATION_ADDR.write_current_raw(addr);
+                RESERVATION_GENERATION.write_current_raw(*generation);
+            }
+            Some(value)
+        }
+        SC => {
+            let reserved = unsafe {
+                RESERVATION_ADDR.read_current_raw() == addr
+                    && RESERVATION_GENERATION.read_current_raw() == *generation
+            };
+            if reserved {
+                width.store(addr, src)?;
+                *generation += 1;
+            }
+            clear_reservation();
+            Some(if reserved { 0 } else { 1 })
+        }
+        op => {
+            let old = width.load(addr)?;
+            match amo_result(op, width, old, src) {
+                Some(new) => {
+                    width.store(addr, new)?;
+                    *generation += 1;
+                    Some(old)
+                }
+                None => None,
+            }
+        }
+    })
+}
+
+/// Compares the word of `width` at the user address `addr` with `old`, and
+/// writes `new` there if they are equal, atomically with the emulated
+/// atomic instructions. Returns the previous value.
+fn cmpxchg_user(
+    tf: &mut TrapFrame,
+    width: Width,
+    addr: usize,
+    old: usize,
+    new: usize,
+) -> Result<usize, LinuxError> {
+    if addr % width.size() != 0 || !access_ok(addr, width.size()) {
+        return Err(LinuxError::EFAULT);
+    }
+    access_user_word(tf, addr, true, || {
+        let mut generation = STORE_GENERATION.lock();
+        let value = width.load(addr)?;
+        if width.unsigned(value) == width.unsigned(old) {
+            width.store(addr, new)?;
+            *generation += 1;
+        }
+        Some(width.unsigned(value))
+    })
+    .ok_or(LinuxError::EFAULT)
+}
+
+/// The `sysriscv` syscall (`__NR_arch_specific_syscall`), for the user
+/// space built without the A extension: `cmd` is [`RISCV_ATOMIC_CMPXCHG`]
+/// or [`RISCV_ATOMIC_CMPXCHG64`], and the compare-and-exchange of `old` by
+/// `new` at `addr` returns the previous value. `tf` is the trap frame of the
+/// syscall, passed to the page fault handler if `addr` must be faulted in.
+///
+/// Returns [`LinuxError::EINVAL`] for another `cmd`, and
+/// [`LinuxError::EFAULT`] if `addr` is misaligned or cannot be accessed.
+pub fn sys_riscv_atomic(
+    tf: &mut TrapFrame,
+    cmd: usize,
+    addr: usize,
+    old: usize,
+    new: usize,
+) -> Result<usize, LinuxError> {
+    match cmd {
+        RISCV_ATOMIC_CMPXCHG => cmpxchg_user(tf, Width::W, addr, old, new),
+        RISCV_ATOMIC_CMPXCHG64 => cmpxchg_user(tf, Width::D, addr, old, new),
+        _ => Err(LinuxError::EINVAL),
+    }
+}
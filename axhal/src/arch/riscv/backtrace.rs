@@ -0,0 +1,61 @@
+//! Stack backtraces, by walking the frame pointers.
+//!
+//! The kernel must be built with frame pointers (`-C
+//! force-frame-pointers=yes`): each frame then saves `ra` at `fp - 8` and
+//! the `fp` of its caller at `fp - 16`. The walk stops at the first frame
+//! out of the bounds of the kernel stack, misaligned, or not above the
+//! previous one.
+
+use super::TrapFrame;
+
+/// The maximum number of frames of a backtrace.
+pub const MAX_BACKTRACE_DEPTH: usize = 32;
+
+/// Calls `f` with each return address of the frame pointer chain, starting
+/// at the trapped context of `tf`, or at the caller of this function.
+///
+/// For a trapped context, the first address is its `sepc`. A user context
+/// only has this one, as the user stacks are not walked.
+#[inline(never)]
+pub fn backtrace(tf: Option<&TrapFrame>, mut f: impl FnMut(usize)) {
+    let (sp, mut fp) = match tf {
+        Some(tf) => {
+            f(tf.sepc);
+            if tf.from_user() {
+                return;
+            }
+            (tf.regs.sp, tf.regs.s0)
+        }
+        None => {
+            let (sp, fp): (usize, usize);
+            unsafe { core::arch::asm!("mv {}, sp", "mv {}, s0", out(reg) sp, out(reg) fp) };
+            (sp, fp)
+        }
+    };
+    let stack = super::stack_guard::kernel_stack_bounds(sp);
+    for _ in 0..MAX_BACKTRACE_DEPTH {
+        if fp % 8 != 0 || fp < stack.start + 16 || fp > stack.end {
+            break;
+        }
+        let (ra, prev_fp) = unsafe { (*(fp as *const usize).sub(1), *(fp as *const usize).sub(2)) };
+        if ra == 0 {
+            break;
+        }
+        f(ra);
+        if prev_fp <= fp {
+            break;
+        }
+        fp = prev_fp;
+    }
+}
+
+/// Prints the backtrace of the trapped context of `tf`, or of the caller,
+/// at the error level, e.g. from the panic handler.
+pub fn print_backtrace(tf: Option<&TrapFrame>) {
+    error!("backtrace:");
+    let mut depth = 0;
+    backtrace(tf, |pc| {
+        error!("  #{} {:#x}", depth, pc);
+        depth += 1;
+    });
+}
@@ -0,0 +1,179 @@
+//! Enabling paging on the boot CPU.
+
+use memory_addr::{PhysAddr, VirtAddr};
+use riscv::register::satp;
+
+use super::page_walk::{PTE_PPN_MASK, PTE_PPN_SHIFT, PTE_R, PTE_V, PTE_W, PTE_X};
+use crate::mem::{phys_to_virt, virt_to_phys, PAGE_SIZE_4K};
+
+extern "C" {
+    fn _stext();
+    fn _ekernel();
+}
+
+/// Translates `va` in the page table at `root`, in the selected paging mode.
+///
+/// The tables are accessed through the linear mapping if paging is enabled,
+/// or by their physical addresses otherwise.
+fn translate(root: PhysAddr, va: usize) -> Option<PhysAddr> {
+    let bare = satp::read().mode() == satp::Mode::Bare;
+    let mut table = root;
+    for level in (0..super::paging_mode().levels()).rev() {
+        let index = (va >> (12 + 9 * level)) & 0x1ff;
+        let pte_paddr = table.as_usize() + index * 8;
+        let pte_ptr = if bare {
+            pte_paddr as *const usize
+        } else {
+            phys_to_virt(PhysAddr::from(pte_paddr))
+                .as_ptr()
+                .cast::<usize>()
+        };
+        let pte = unsafe { pte_ptr.read_volatile() };
+        if pte & PTE_V == 0 {
+            return None;
+        }
+        let paddr = ((pte >> PTE_PPN_SHIFT) & PTE_PPN_MASK) << 12;
+        if pte & (PTE_R | PTE_W | PTE_X) != 0 {
+            let page_size = 1 << (12 + 9 * level);
+            return Some(PhysAddr::from(paddr + (va & (page_size - 1))));
+        }
+        table = PhysAddr::from(paddr);
+    }
+    None
+}
+
+/// Returns the physical address of `addr`, which may be either a physical
+/// address (before paging) or an address of the linear mapping.
+fn to_phys(addr: usize) -> usize {
+    if addr >= axconfig::PHYS_VIRT_OFFSET {
+        virt_to_phys(VirtAddr::from(addr)).as_usize()
+    } else {
+        addr
+    }
+}
+
+/// Asserts that `root` maps the pages of `[paddr, paddr + size)` at `va`.
+fn assert_mapped(root: PhysAddr, va: usize, paddr: usize, size: usize, what: &str) {
+    let mut offset = 0;
+    while offset < size.max(1) {
+        let translated = translate(root, va + offset).map(|pa| pa.as_usize());
+        assert_eq!(
+            translated,
+            Some(paddr + offset),
+            "enable_paging: {} at {:#x} is not mapped",
+            what,
+            va + offset
+        );
+        offset += PAGE_SIZE_4K;
+    }
+}
+
+/// Checks the preconditions of [`enable_paging`] in debug builds.
+fn check_boot_mappings(root: PhysAddr, running_low: bool) {
+    let kernel_start = to_phys(_stext as usize) & !(PAGE_SIZE_4K - 1);
+    let kernel_size = to_phys(_ekernel as usize) - kernel_start;
+    let kernel_vaddr = phys_to_virt(PhysAddr::from(kernel_start)).as_usize();
+    assert_mapped(
+        root,
+        kernel_vaddr,
+        kernel_start,
+        kernel_size,
+        "kernel image",
+    );
+    if running_low {
+        assert_mapped(
+            root,
+            kernel_start,
+            kernel_start,
+            kernel_size,
+            "kernel identity map",
+        );
+    }
+
+    let sp: usize;
+    unsafe { core::arch::asm!("mv {}, sp", out(reg) sp) };
+    let sp_page = to_phys(sp) & !(PAGE_SIZE_4K - 1);
+    let sp_vaddr = phys_to_virt(PhysAddr::from(sp_page)).as_usize();
+    assert_mapped(root, sp_vaddr, sp_page, PAGE_SIZE_4K, "current stack");
+    if running_low {
+        assert_mapped(
+            root,
+            sp_page,
+            sp_page,
+            PAGE_SIZE_4K,
+            "current stack identity map",
+        );
+    }
+
+    if let Some(dtb) = crate::platform::dt::dtb_paddr() {
+        let dtb_page = dtb.as_usize() & !(PAGE_SIZE_4K - 1);
+        let dtb_vaddr = phys_to_virt(PhysAddr::from(dtb_page)).as_usize();
+        assert_mapped(root, dtb_vaddr, dtb_page, PAGE_SIZE_4K, "device tree");
+    }
+}
+
+// Switches `satp` to `a0`, moves `sp` and the PC up by `a1`, and jumps to
+// `a2` with `a3` as its argument. It is an assembly function, the compiler
+// must not keep anything in the old frame across the switch.
+core::arch::global_asm!(
+    r"
+    .section .text
+    .balign 4
+    .global __enable_paging_trampoline
+    __enable_paging_trampoline:
+    csrw    satp, a0
+    sfence.vma
+    // Still at the physical PC (identity map), move to the high alias.
+    add     sp, sp, a1
+    add     a2, a2, a1
+    mv      a0, a3
+    // The old frames are left behind: end the backtraces here.
+    li      ra, 0
+    li      s0, 0
+    jr      a2
+    "
+);
+
+extern "C" {
+    fn __enable_paging_trampoline(satp: usize, offset: usize, entry: usize, arg: usize) -> !;
+}
+
+/// Switches to the page table at `root`, in the selected paging mode (see
+/// [`set_paging_mode`](super::set_paging_mode)), and continues with
+/// `entry(arg)`.
+///
+/// If it is called before paging is enabled (at a physical PC), it also
+/// moves `sp` to the linear mapping and calls `entry` at its high virtual
+/// alias. It never returns: the frames of the callers stay on the stack but
+/// are not used any more, and other registers (e.g. the per-CPU base in
+/// `gp`) are not moved.
+///
+/// # Safety
+///
+/// `root` must map, with the permissions the kernel uses them:
+///
+/// - The kernel image (`_stext` to `_ekernel`) at `phys_to_virt` of its
+///   physical address.
+/// - The current stack page, likewise.
+/// - The device tree, likewise.
+/// - If paging is not enabled yet: the kernel image and the current stack
+///   at their physical addresses too (an identity map), as the instructions
+///   right after the `satp` write are still fetched at the physical PC. The
+///   identity map must stay until nothing uses a physical pointer any more.
+///
+/// The SBI firmware needs no mapping: `ecall` traps to M-mode, which does
+/// not translate addresses. The preconditions are asserted in debug builds.
+pub unsafe fn enable_paging(root: PhysAddr, entry: extern "C" fn(usize) -> !, arg: usize) -> ! {
+    let pc: usize;
+    core::arch::asm!("auipc {}, 0", out(reg) pc);
+    let running_low = pc < axconfig::PHYS_VIRT_OFFSET;
+    if cfg!(debug_assertions) {
+        check_boot_mappings(root, running_low);
+    }
+    if !running_low {
+        super::write_page_table_root(root);
+        entry(arg)
+    }
+    let new_satp = super::make_satp(super::paging_mode().satp_mode(), 0, root);
+    __enable_paging_trampoline(new_satp, axconfig::PHYS_VIRT_OFFSET, entry as usize, arg)
+}
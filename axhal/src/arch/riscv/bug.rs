@@ -0,0 +1,119 @@
+//! Breakpoint exceptions, and the [`bug!`](crate::bug) assertions built on
+//! them.
+//!
+//! [`bug!`](crate::bug) executes `ebreak` with [`BUG_MAGIC`] in `t0` and a
+//! pointer to a [`BugEntry`] in `t1`. The trap handler routes `scause` 3 to
+//! [`handle_breakpoint`], which reports the entry with a backtrace and halts
+//! for kernel-mode breakpoints. It also reports the stack smashing detected
+//! by [`__stack_chk_fail`](super::__stack_chk_fail).
+
+use spinbase::SpinNoIrq;
+
+use super::TrapFrame;
+
+/// The value of `t0` at an `ebreak` emitted by [`bug!`](crate::bug).
+pub const BUG_MAGIC: usize = 0x4255_475f_4255_475f; // "BUG_BUG_"
+
+/// The location of a [`bug!`](crate::bug).
+#[derive(Debug)]
+pub struct BugEntry {
+    /// The source file.
+    pub file: &'static str,
+    /// The line in the source file.
+    pub line: u32,
+}
+
+/// Reports a kernel bug at the current location, and halts.
+///
+/// It is a cheap assertion: only an `ebreak` and two register loads are
+/// emitted at the call site, the report is made by the breakpoint handler.
+#[macro_export]
+macro_rules! bug {
+    () => {{
+        static BUG_ENTRY: $crate::arch::BugEntry = $crate::arch::BugEntry {
+            file: file!(),
+            line: line!(),
+        };
+        unsafe {
+            core::arch::asm!(
+                "ebreak",
+                in("t0") $crate::arch::BUG_MAGIC,
+                in("t1") &BUG_ENTRY as *const $crate::arch::BugEntry,
+                options(noreturn, nostack),
+            )
+        }
+    }};
+}
+
+/// The type of a user breakpoint handler, which should deliver `SIGTRAP` to
+/// the current task.
+pub type UserBreakpointHandler = fn(&mut TrapFrame);
+
+static USER_BREAKPOINT_HANDLER: SpinNoIrq<Option<UserBreakpointHandler>> = SpinNoIrq::new(None);
+
+/// Registers the handler of user-mode breakpoints.
+pub fn set_user_breakpoint_handler(f: UserBreakpointHandler) {
+    *USER_BREAKPOINT_HANDLER.lock() = Some(f);
+}
+
+/// Handles a breakpoint exception (`scause` 3).
+///
+/// A kernel-mode breakpoint is fatal: the [`bug!`](crate::bug) location (if
+/// any) is printed with a backtrace, and then it panics. A user-mode
+/// breakpoint is passed to the handler registered with
+/// [`set_user_breakpoint_handler`]. Returns `false` for a user-mode
+/// breakpoint that is not owned by the HAL if no handler is registered, so
+/// that the trap handler kills the task (for `SIGTRAP`) instead of skipping
+/// the `ebreak`.
+///
+/// The hardware breakpoints go to
+/// [`handle_hw_breakpoint`](super::handle_hw_breakpoint), and the kprobes to
+/// [`handle_kprobe`](super::handle_kprobe). The temporary
+/// breakpoints of [`set_single_step`](super::set_single_step) are removed
+/// first; the user handler is then called as for the other
+/// breakpoints (e.g. to deliver `SIGTRAP` to the tracer), and the kernel
+/// ones just resume. With `gdbstub`, the kernel breakpoints stop in the
+/// [GDB stub](super::init_gdbstub) first.
+pub fn handle_breakpoint(tf: &mut TrapFrame) -> bool {
+    if super::handle_hw_breakpoint(tf, riscv::register::stval::read()) || super::handle_kprobe(tf) {
+        return true;
+    }
+    let stepped = super::handle_single_step(tf);
+    if tf.from_user() {
+        let handler = *USER_BREAKPOINT_HANDLER.lock();
+        return match handler {
+            Some(f) => {
+                f(tf);
+                true
+            }
+            None if stepped => true,
+            None => {
+                warn!("Unhandled user breakpoint at {:#x}", tf.sepc);
+                false
+            }
+        };
+    }
+
+    #[cfg(feature = "gdbstub")]
+    if super::gdbstub::handle_gdb_breakpoint(tf, stepped) {
+        return true;
+    }
+    if stepped {
+        return true;
+    }
+    if tf.regs.t0 == BUG_MAGIC && tf.regs.t1 != 0 {
+        let entry = unsafe { &*(tf.regs.t1 as *const BugEntry) };
+        error!("kernel BUG at {}:{}", entry.file, entry.line);
+        super::print_backtrace(Some(tf));
+        panic!("kernel BUG at {}:{}", entry.file, entry.line);
+    }
+    if tf.regs.t0 == super::stack_protector::STACK_CHK_MAGIC {
+        error!("stack smashing detected in the caller of {:#x}", tf.regs.ra);
+        error!("{:#x?}", tf);
+        super::print_backtrace(Some(tf));
+        panic!("stack smashing detected in the caller of {:#x}", tf.regs.ra);
+    }
+    error!("kernel breakpoint at {:#x}", tf.sepc);
+    super::print_backtrace(Some(tf));
+    panic!("kernel breakpoint at {:#x}:\n{:#x?}", tf.sepc, tf);
+}
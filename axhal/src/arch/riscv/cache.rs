@@ -0,0 +1,292 @@
+//! Cache maintenance, for the DMA of the devices that do not snoop the
+//! caches, and for the code written at run time.
+//!
+//! The cache blocks are written back or invalidated with Zicbom, or with the
+//! vendor instructions of the T-Head cores
+//! ([`ALT_ERRATA_THEAD_CMO`]), patched in by the alternatives. Otherwise,
+//! the physical ranges go to the [`CacheOps`] registered by the board code,
+//! or found in the device tree by [`init_cache_ops`] (the SiFive composable
+//! cache of the U74 cores, e.g. on the VisionFive 2). Without any of them,
+//! the DMA is assumed coherent and the operations do nothing.
+//!
+//! Blocks are zeroed with Zicboz, and the instruction caches are
+//! synchronized with `fence.i`, which has no range.
+
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use lazy_init::LazyInit;
+use memory_addr::{PhysAddr, VirtAddr};
+use spinbase::SpinNoIrq;
+
+use super::alternative::alt_key;
+use super::cpufeature::{cpu_has, Feature};
+use super::errata::{has_erratum, ALT_ERRATA_THEAD_CMO};
+use crate::mem::{phys_to_virt, virt_to_phys};
+use crate::platform::dt;
+
+/// The block size when the device tree has no `riscv,cbo*-block-size`. The
+/// line size of the data caches is not used, as the blocks may differ.
+const DEFAULT_BLOCK_SIZE: usize = 64;
+
+/// The non-standard operations on the physical ranges of the caches, e.g. of
+/// an outer cache controller.
+pub trait CacheOps: Sync {
+    /// Writes back the cache lines of `[paddr, paddr + size)`.
+    fn clean(&self, paddr: PhysAddr, size: usize);
+    /// Discards the cache lines of `[paddr, paddr + size)`.
+    fn invalidate(&self, paddr: PhysAddr, size: usize);
+    /// Writes back and discards the cache lines of `[paddr, paddr + size)`.
+    fn flush(&self, paddr: PhysAddr, size: usize);
+}
+
+static CACHE_OPS: SpinNoIrq<Option<&'static dyn CacheOps>> = SpinNoIrq::new(None);
+
+static CBOM_BLOCK_SIZE: AtomicUsize = AtomicUsize::new(0);
+static CBOZ_BLOCK_SIZE: AtomicUsize = AtomicUsize::new(0);
+
+/// Makes `ops` the cache operations used without Zicbom, in place of the
+/// previous ones.
+pub fn register_cache_ops(ops: &'static dyn CacheOps) {
+    *CACHE_OPS.lock() = Some(ops);
+}
+
+/// Reads `prop` of the boot CPU once into `cache`, or else 64 bytes.
+fn block_size(cache: &AtomicUsize, prop: &str) -> usize {
+    match cache.load(Ordering::Relaxed) {
+        0 => {
+            let size = crate::cpu::cpu_to_hartid(0)
+                .and_then(dt::cpu_node)
+                .and_then(|node| node.property(prop)?.as_usize())
+                .filter(|size| size.is_power_of_two())
+                .unwrap_or(DEFAULT_BLOCK_SIZE);
+            cache.store(size, Ordering::Relaxed);
+            size
+        }
+        size => size,
+    }
+}
+
+/// Returns the size of the cache blocks managed by Zicbom: the
+/// `riscv,cbom-block-size` of the boot CPU, or else 64 bytes.
+pub fn cbom_block_size() -> usize {
+    block_size(&CBOM_BLOCK_SIZE, "riscv,cbom-block-size")
+}
+
+/// Returns the size of the cache blocks zeroed by Zicboz: the
+/// `riscv,cboz-block-size` of the boot CPU, or else 64 bytes.
+pub fn cboz_block_size() -> usize {
+    block_size(&CBOZ_BLOCK_SIZE, "riscv,cboz-block-size")
+}
+
+/// Writes back the cache block of `addr`.
+unsafe fn clean_block(addr: usize) {
+    asm_alternative_2!(
+        "nop",
+        ".4byte 0x0015200f", // cbo.clean (a0)
+        alt_key(Feature::Zicbom),
+        ".4byte 0x0255000b", // th.dcache.cva a0
+        ALT_ERRATA_THEAD_CMO,
+        in("a0") addr
+    )
+}
+
+/// Invalidates the cache block of `addr`.
+unsafe fn inval_block(addr: usize) {
+    asm_alternative_2!(
+        "nop",
+        ".4byte 0x0005200f", // cbo.inval (a0)
+        alt_key(Feature::Zicbom),
+        ".4byte 0x0265000b", // th.dcache.iva a0
+        ALT_ERRATA_THEAD_CMO,
+        in("a0") addr
+    )
+}
+
+/// Writes back and invalidates the cache block of `addr`.
+unsafe fn flush_block(addr: usize) {
+    asm_alternative_2!(
+        "nop",
+        ".4byte 0x0025200f", // cbo.flush (a0)
+        alt_key(Feature::Zicbom),
+        ".4byte 0x0275000b", // th.dcache.civa a0
+        ALT_ERRATA_THEAD_CMO,
+        in("a0") addr
+    )
+}
+
+/// Waits for the cache operations to complete.
+fn cache_sync() {
+    unsafe {
+        asm_alternative!(
+            "fence rw, rw",
+            ".4byte 0x0190000b", // th.sync.s
+            ALT_ERRATA_THEAD_CMO
+        )
+    }
+}
+
+/// Returns whether the blocks are managed with instructions.
+fn has_cmo_insns() -> bool {
+    cpu_has(Feature::Zicbom) || has_erratum(ALT_ERRATA_THEAD_CMO)
+}
+
+/// Runs `op` on each cache block of `[vaddr, vaddr + size)`, or `ops` on
+/// its physical range without the instructions. The range must be in the
+/// linear mapping for `ops`.
+fn for_each_block(
+    vaddr: VirtAddr,
+    size: usize,
+    op: unsafe fn(usize),
+    ops: fn(&dyn CacheOps, PhysAddr, usize),
+) {
+    if size == 0 {
+        return;
+    }
+    if !has_cmo_insns() {
+        if let Some(cache_ops) = *CACHE_OPS.lock() {
+            ops(cache_ops, virt_to_phys(vaddr), size);
+        }
+        return;
+    }
+    let block = cbom_block_size();
+    let start = vaddr.as_usize() & !(block - 1);
+    for addr in (start..vaddr.as_usize() + size).step_by(block) {
+        unsafe { op(addr) };
+    }
+    cache_sync();
+}
+
+/// Writes back the cache blocks of `[vaddr, vaddr + size)` to the memory,
+/// e.g. before a device reads them.
+pub fn dcache_clean_range(vaddr: VirtAddr, size: usize) {
+    for_each_block(vaddr, size, clean_block, |ops, paddr, size| {
+        ops.clean(paddr, size)
+    });
+}
+
+/// Discards the cache blocks of `[vaddr, vaddr + size)`, e.g. after a device
+/// wrote to the memory. The blocks that the range only partly covers are
+/// discarded too, so the buffers should be aligned to [`cbom_block_size`].
+pub fn dcache_invalidate_range(vaddr: VirtAddr, size: usize) {
+    for_each_block(vaddr, size, inval_block, |ops, paddr, size| {
+        ops.invalidate(paddr, size)
+    });
+}
+
+/// Writes back and discards the cache blocks of `[vaddr, vaddr + size)`.
+pub fn dcache_flush_range(vaddr: VirtAddr, size: usize) {
+    for_each_block(vaddr, size, flush_block, |ops, paddr, size| {
+        ops.flush(paddr, size)
+    });
+}
+
+/// Zeroes `[vaddr, vaddr + size)`, the whole blocks with `cbo.zero` (without
+/// reading them from the memory) if all CPUs have Zicboz.
+///
+/// # Safety
+///
+/// The range must be mapped and writable.
+pub unsafe fn dcache_zero_range(vaddr: VirtAddr, size: usize) {
+    let (start, end) = (vaddr.as_usize(), vaddr.as_usize() + size);
+    if !cpu_has(Feature::Zicboz) {
+        return core::ptr::write_bytes(start as *mut u8, 0, size);
+    }
+    let block = cboz_block_size();
+    let first = (start + block - 1) & !(block - 1);
+    let last = end & !(block - 1);
+    if first >= last {
+        return core::ptr::write_bytes(start as *mut u8, 0, size);
+    }
+    core::ptr::write_bytes(start as *mut u8, 0, first - start);
+    for addr in (first..last).step_by(block) {
+        // cbo.zero (a0)
+        core::arch::asm!(".4byte 0x0045200f", in("a0") addr);
+    }
+    core::ptr::write_bytes(last as *mut u8, 0, end - last);
+}
+
+/// Makes the instructions written to `[vaddr, vaddr + size)` visible to
+/// the instruction fetches of all online CPUs.
+pub fn icache_flush_range(_vaddr: VirtAddr, size: usize) {
+    if size == 0 {
+        return;
+    }
+    super::local_flush_icache_all();
+    #[cfg(feature = "smp")]
+    {
+        let mut others = crate::cpu::online_cpus();
+        others.remove(crate::cpu::_this_cpu_id());
+        if super::remote_fence_i(others).is_err() {
+            super::smp_call_function(others, &super::local_flush_icache_all);
+        }
+    }
+}
+
+/// The `Flush64` register of the SiFive composable cache: writing a physical
+/// address to it writes back and invalidates its 64-byte line.
+const CCACHE_FLUSH64: usize = 0x200;
+const CCACHE_LINE_SIZE: usize = 64;
+
+/// The SiFive composable cache (the L2 of the U54 and U74 cores), which only
+/// flushes.
+pub struct SifiveCcache {
+    base: VirtAddr,
+}
+
+impl SifiveCcache {
+    /// Creates the driver of the cache controller mapped at `base`.
+    pub const fn new(base: VirtAddr) -> Self {
+        Self { base }
+    }
+
+    fn flush_lines(&self, paddr: PhysAddr, size: usize) {
+        let reg = (self.base.as_usize() + CCACHE_FLUSH64) as *mut u64;
+        let start = paddr.as_usize() & !(CCACHE_LINE_SIZE - 1);
+        unsafe { core::arch::asm!("fence rw, rw") };
+        for line in (start..paddr.as_usize() + size).step_by(CCACHE_LINE_SIZE) {
+            unsafe { reg.write_volatile(line as u64) };
+        }
+        unsafe { core::arch::asm!("fence rw, rw") };
+    }
+}
+
+impl CacheOps for SifiveCcache {
+    fn clean(&self, paddr: PhysAddr, size: usize) {
+        self.flush_lines(paddr, size);
+    }
+
+    fn invalidate(&self, paddr: PhysAddr, size: usize) {
+        self.flush_lines(paddr, size);
+    }
+
+    fn flush(&self, paddr: PhysAddr, size: usize) {
+        self.flush_lines(paddr, size);
+    }
+}
+
+static SIFIVE_CCACHE: LazyInit<SifiveCcache> = LazyInit::new();
+
+/// Finds the SiFive composable cache in the device tree, and registers it
+/// if the CPUs have no cache instructions. The platform initialization
+/// calls it.
+pub fn init_cache_ops() {
+    if has_cmo_insns() {
+        return;
+    }
+    let Some(fdt) = dt::fdt() else {
+        return;
+    };
+    let Some(node) = fdt
+        .find_compatible(&["sifive,ccache0", "starfive,jh7110-ccache"])
+        .filter(|&node| dt::node_enabled(node))
+    else {
+        return;
+    };
+    let Some(reg) = node.reg().and_then(|mut reg| reg.next()) else {
+        return;
+    };
+    let paddr = PhysAddr::from(reg.starting_address as usize);
+    info!("SiFive composable cache @ {:#x}", paddr);
+    SIFIVE_CCACHE.init_by(SifiveCcache::new(phys_to_virt(paddr)));
+    register_cache_ops(&*SIFIVE_CCACHE);
+}
@@ -0,0 +1,276 @@
+//! Control-flow integrity of the user tasks: shadow stacks (Zicfiss) and
+//! landing pads (Zicfilp).
+//!
+//! Each task enables them with [`set_shadow_stack`] and [`set_landing_pad`].
+//! The `senvcfg.SSE` and `senvcfg.LPE` bits and the U-mode shadow stack
+//! pointer (the `ssp` CSR) are installed on context switch, and `ssp` is
+//! saved back. A trap does not change `ssp`, and whether a landing pad is
+//! expected after the `sret` (`sstatus.SPELP`) is in the trap frame.
+//!
+//! The shadow stacks are user pages allocated by the OS (e.g. of
+//! [`shadow_stack_size`]) and mapped with [`shadow_stack_pte`]. A violation
+//! raises a software-check exception, that [`cfi_violation`] decodes for the
+//! trap handler to deliver `SIGSEGV`.
+
+use axerrno::LinuxError;
+use riscv::register::{scause, stval};
+
+use super::page_walk::{PTE_R, PTE_W, PTE_X};
+use super::pointer_masking::CSR_SENVCFG;
+use super::{TaskContext, TrapFrame, SR_SPELP};
+use crate::mem::PAGE_SIZE_4K;
+
+/// CSR number of `ssp`.
+const CSR_SSP: usize = 0x011;
+
+const SENVCFG_LPE: usize = 1 << 2;
+const SENVCFG_SSE: usize = 1 << 3;
+
+/// The `scause` of a software-check exception.
+const EXC_SOFTWARE_CHECK: usize = 18;
+/// The `stval` of a software-check exception: a missing landing pad.
+const SW_CHECK_LANDING_PAD: usize = 2;
+/// The `stval` of a software-check exception: a shadow stack mismatch.
+const SW_CHECK_SHADOW_STACK: usize = 3;
+
+/// The `si_code` of `SIGSEGV`: control protection fault.
+pub const SEGV_CPERR: i32 = 10;
+
+/// The maximum size of a shadow stack from [`shadow_stack_size`], as in
+/// Linux.
+pub const MAX_SHADOW_STACK_SIZE: usize = 1 << 32;
+
+/// The CFI state of a task.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct UserCfi {
+    /// Whether the shadow stack is enabled.
+    pub shadow_stack: bool,
+    /// Whether the landing pads are enforced.
+    pub landing_pad: bool,
+    /// The shadow stack pointer while the task is switched out.
+    pub ssp: usize,
+}
+
+impl UserCfi {
+    const fn senvcfg_bits(&self) -> usize {
+        let mut bits = 0;
+        if self.shadow_stack {
+            bits |= SENVCFG_SSE;
+        }
+        if self.landing_pad {
+            bits |= SENVCFG_LPE;
+        }
+        bits
+    }
+}
+
+/// The `senvcfg` CFI bits installed on this CPU.
+#[percpu2::def_percpu]
+static CFI_SENVCFG: usize = 0;
+
+/// Returns whether all CPUs support shadow stacks (Zicfiss).
+///
+/// It needs the device tree, i.e. must be called after `arch_init_early`.
+pub fn has_zicfiss() -> bool {
+    super::cpu_has(super::Feature::Zicfiss)
+}
+
+/// Returns whether all CPUs support landing pads (Zicfilp).
+///
+/// It needs the device tree, i.e. must be called after `arch_init_early`.
+pub fn has_zicfilp() -> bool {
+    super::cpu_has(super::Feature::Zicfilp)
+}
+
+/// Enables the shadow stack of the task of `ctx` with the shadow stack
+/// pointer `ssp` (the top of its shadow stack), or disables it with `None`.
+///
+/// Returns [`LinuxError::EINVAL`] if there is no Zicfiss or `ssp` is not
+/// 8-byte aligned. It takes effect the next time the task is switched to,
+/// or at once with [`load_user_cfi`].
+pub fn set_shadow_stack(ctx: &mut TaskContext, ssp: Option<usize>) -> Result<(), LinuxError> {
+    match ssp {
+        Some(ssp) if !has_zicfiss() || ssp % 8 != 0 => Err(LinuxError::EINVAL),
+        Some(ssp) => {
+            ctx.cfi.shadow_stack = true;
+            ctx.cfi.ssp = ssp;
+            Ok(())
+        }
+        None => {
+            ctx.cfi.shadow_stack = false;
+            ctx.cfi.ssp = 0;
+            Ok(())
+        }
+    }
+}
+
+/// Enforces the landing pads of the task of `ctx` if `enable`, or stops.
+///
+/// Returns [`LinuxError::EINVAL`] to enable them without Zicfilp. It takes
+/// effect the next time the task is switched to, or at once with
+/// [`load_user_cfi`].
+pub fn set_landing_pad(ctx: &mut TaskContext, enable: bool) -> Result<(), LinuxError> {
+    if enable && !has_zicfilp() {
+        return Err(LinuxError::EINVAL);
+    }
+    ctx.cfi.landing_pad = enable;
+    Ok(())
+}
+
+/// Writes the `senvcfg` CFI bits and `ssp` of `cfi` to this CPU.
+fn install_user_cfi(cfi: &UserCfi) {
+    let bits = cfi.senvcfg_bits();
+    unsafe {
+        if CFI_SENVCFG.read_current_raw() != bits {
+            let senvcfg: usize;
+            core::arch::asm!("csrr {}, {csr}", out(reg) senvcfg, csr = const CSR_SENVCFG);
+            let senvcfg = (senvcfg & !(SENVCFG_SSE | SENVCFG_LPE)) | bits;
+            core::arch::asm!("csrw {csr}, {}", in(reg) senvcfg, csr = const CSR_SENVCFG);
+            CFI_SENVCFG.write_current_raw(bits);
+        }
+        if cfi.shadow_stack {
+            core::arch::asm!("csrw {csr}, {}", in(reg) cfi.ssp, csr = const CSR_SSP);
+        }
+    }
+}
+
+/// Installs the CFI state of `ctx` at once, for the current task (e.g. after
+/// an exec or a `prctl`).
+pub fn load_user_cfi(ctx: &TaskContext) {
+    install_user_cfi(&ctx.cfi);
+}
+
+/// Saves `ssp` into `prev` and installs `next`, on context switch.
+pub(super) fn switch_user_cfi(prev: &mut UserCfi, next: &UserCfi) {
+    // Nothing to do if neither task uses CFI, the common case.
+    if unsafe { CFI_SENVCFG.read_current_raw() } == 0 && next.senvcfg_bits() == 0 {
+        return;
+    }
+    if let Some(ssp) = user_ssp() {
+        prev.ssp = ssp;
+    }
+    install_user_cfi(next);
+}
+
+/// Returns the shadow stack pointer of the current task, if its shadow
+/// stack is enabled, e.g. to push a token on signal delivery.
+pub fn user_ssp() -> Option<usize> {
+    if unsafe { CFI_SENVCFG.read_current_raw() } & SENVCFG_SSE == 0 {
+        return None;
+    }
+    let ssp: usize;
+    unsafe { core::arch::asm!("csrr {}, {csr}", out(reg) ssp, csr = const CSR_SSP) };
+    Some(ssp)
+}
+
+/// Sets the shadow stack pointer of the current task, if its shadow stack
+/// is enabled, e.g. on signal return. Returns whether it is.
+pub fn set_user_ssp(ssp: usize) -> bool {
+    if unsafe { CFI_SENVCFG.read_current_raw() } & SENVCFG_SSE == 0 {
+        return false;
+    }
+    unsafe { core::arch::asm!("csrw {csr}, {}", in(reg) ssp, csr = const CSR_SSP) };
+    true
+}
+
+impl TrapFrame {
+    /// Returns whether a landing pad is expected at `sepc` (`sstatus.SPELP`).
+    #[inline]
+    pub const fn landing_pad_expected(&self) -> bool {
+        self.sstatus & SR_SPELP != 0
+    }
+
+    /// Sets whether a landing pad is expected at `sepc`, e.g. cleared to
+    /// enter a signal handler and restored on signal return.
+    #[inline]
+    pub fn set_landing_pad_expected(&mut self, expected: bool) {
+        if expected {
+            self.sstatus |= SR_SPELP;
+        } else {
+            self.sstatus &= !SR_SPELP;
+        }
+    }
+}
+
+/// Returns the user PTE `pte` of a shadow stack page: writable only by the
+/// shadow stack instructions (`W` without `R` and `X`).
+pub const fn shadow_stack_pte(pte: usize) -> usize {
+    (pte & !(PTE_R | PTE_X)) | PTE_W
+}
+
+/// Returns the size of the shadow stack of a task whose stack is limited to
+/// `stack_limit` bytes, as in Linux: the same, up to
+/// [`MAX_SHADOW_STACK_SIZE`], in whole pages.
+pub const fn shadow_stack_size(stack_limit: usize) -> usize {
+    let size = if stack_limit < MAX_SHADOW_STACK_SIZE {
+        stack_limit
+    } else {
+        MAX_SHADOW_STACK_SIZE
+    };
+    (size + PAGE_SIZE_4K - 1) & !(PAGE_SIZE_4K - 1)
+}
+
+/// The kind of a CFI violation.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CfiViolationKind {
+    /// An indirect jump did not land on an `lpad`.
+    LandingPad,
+    /// A return address did not match the shadow stack.
+    ShadowStack,
+}
+
+/// A CFI violation, for the trap handler to deliver `SIGSEGV` to the task.
+#[derive(Debug, Clone, Copy)]
+pub struct CfiViolation {
+    /// The address of the faulting instruction (`si_addr`).
+    pub addr: usize,
+    /// What was violated.
+    pub kind: CfiViolationKind,
+    /// The `si_code`, [`SEGV_CPERR`].
+    pub code: i32,
+}
+
+/// Returns the CFI violation of the current trap, or [`None`] if it is not
+/// a software-check exception.
+///
+/// It reads `scause` and `stval`, so it must be called while handling this
+/// trap.
+pub fn cfi_violation(tf: &TrapFrame) -> Option<CfiViolation> {
+    let scause = scause::read();
+    if scause.is_interrupt() || scause.code() != EXC_SOFTWARE_CHECK {
+        return None;
+    }
+    let kind = match stval::read() {
+        SW_CHECK_LANDING_PAD => CfiViolationKind::LandingPad,
+        SW_CHECK_SHADOW_STACK => CfiViolationKind::ShadowStack,
+        _ => return None,
+    };
+    Some(CfiViolation {
+        addr: tf.sepc,
+        kind,
+        code: SEGV_CPERR,
+    })
+}
+
+/// Returns whether the `senvcfg` bit `bit` sticks on the current CPU.
+fn probe_senvcfg_bit(bit: usize) -> bool {
+    let senvcfg: usize;
+    let probed: usize;
+    unsafe {
+        core::arch::asm!("csrr {}, {csr}", out(reg) senvcfg, csr = const CSR_SENVCFG);
+        core::arch::asm!("csrw {csr}, {}", in(reg) senvcfg | bit, csr = const CSR_SENVCFG);
+        core::arch::asm!("csrr {}, {csr}", out(reg) probed, csr = const CSR_SENVCFG);
+        core::arch::asm!("csrw {csr}, {}", in(reg) senvcfg, csr = const CSR_SENVCFG);
+    }
+    probed & bit != 0
+}
+
+/// Returns whether `senvcfg.SSE` can be set on the current CPU.
+pub(super) fn probe_zicfiss() -> bool {
+    probe_senvcfg_bit(SENVCFG_SSE)
+}
+
+/// Returns whether `senvcfg.LPE` can be set on the current CPU.
+pub(super) fn probe_zicfilp() -> bool {
+    probe_senvcfg_bit(SENVCFG_LPE)
+}
@@ -0,0 +1,142 @@
+//! 32-bit (RV32) user processes on the RV64 kernel.
+//!
+//! A task runs in RV32 when its saved `sstatus.UXL` is 32, see
+//! [`set_compat_task`]. Its address space is limited to
+//! [`COMPAT_TASK_SIZE`], and its syscalls use the numbers of
+//! [`sysno_compat`](super::sysno_compat).
+//!
+//! In RV32 U-mode, the registers are sign-extended from bit 31 when written.
+//! The user pointers of a compat task are thus checked against
+//! [`COMPAT_TASK_SIZE`] (below 2G, so that a valid pointer is the same
+//! sign-extended or not), and the other 32-bit syscall arguments must be
+//! zero-extended with [`compat_ptr`] or [`compat_syscall_args`].
+
+use axerrno::LinuxError;
+use core::sync::atomic::{AtomicU8, Ordering};
+
+use super::{task_size, TaskContext, TrapFrame, SR_UXL, SR_UXL_32, SR_UXL_64};
+use crate::mem::PAGE_SIZE_4K;
+
+/// The size of the address space of a compat task, as in Linux.
+pub const COMPAT_TASK_SIZE: usize = 0x8000_0000 - PAGE_SIZE_4K;
+/// The top of the user stack of a compat task.
+pub const COMPAT_STACK_TOP: usize = COMPAT_TASK_SIZE;
+/// The load address of an `ET_DYN` compat program.
+pub const COMPAT_ELF_ET_DYN_BASE: usize = (COMPAT_TASK_SIZE / 3) * 2;
+/// The base of the `mmap` area of a compat task.
+pub const COMPAT_TASK_UNMAPPED_BASE: usize = (COMPAT_TASK_SIZE / 3) & !(PAGE_SIZE_4K - 1);
+
+/// Whether the task running on this CPU is a compat one.
+#[percpu2::def_percpu]
+static COMPAT_TASK: bool = false;
+
+/// Whether `sstatus.UXL` can be set to 32: 0 if not probed, 1 if not, 2 if
+/// so.
+static COMPAT_SUPPORTED: AtomicU8 = AtomicU8::new(0);
+
+/// Returns whether U-mode can run in RV32, i.e. whether `sstatus.UXL` is
+/// writable.
+///
+/// It is probed on the first call, on the current CPU: all CPUs are assumed
+/// to be the same.
+pub fn compat_supported() -> bool {
+    match COMPAT_SUPPORTED.load(Ordering::Relaxed) {
+        0 => {
+            let supported = probe_uxl_32();
+            COMPAT_SUPPORTED.store(1 + supported as u8, Ordering::Relaxed);
+            supported
+        }
+        probed => probed == 2,
+    }
+}
+
+fn probe_uxl_32() -> bool {
+    let sstatus: usize;
+    unsafe {
+        // Only U-mode depends on `UXL`, so it can be changed for a moment.
+        core::arch::asm!(
+            "csrc sstatus, {uxl}",
+            "csrs sstatus, {uxl_32}",
+            "csrr {sstatus}, sstatus",
+            "csrc sstatus, {uxl}",
+            "csrs sstatus, {uxl_64}",
+            uxl = in(reg) SR_UXL,
+            uxl_32 = in(reg) SR_UXL_32,
+            uxl_64 = in(reg) SR_UXL_64,
+            sstatus = out(reg) sstatus,
+        );
+    }
+    sstatus & SR_UXL == SR_UXL_32
+}
+
+impl TrapFrame {
+    /// Returns whether the trap came from a task in RV32.
+    #[inline]
+    pub const fn is_compat(&self) -> bool {
+        self.sstatus & SR_UXL == SR_UXL_32
+    }
+}
+
+/// Makes the task of `ctx`, returning to U-mode with `tf`, run in RV32 if
+/// `compat`, or else in RV64.
+///
+/// It is called by the current task after [`start_thread`](super::start_thread),
+/// which sets RV64, for an exec of an `ELFCLASS32` program (or of an RV64
+/// one from a compat task), and the user access checks of this CPU follow
+/// at once. The task must also get a compat address space, see
+/// [`UserLayout::new_compat`](super::UserLayout::new_compat).
+///
+/// Returns [`LinuxError::ENOEXEC`] if `compat` and U-mode cannot run in
+/// RV32.
+pub fn set_compat_task(
+    ctx: &mut TaskContext,
+    tf: &mut TrapFrame,
+    compat: bool,
+) -> Result<(), LinuxError> {
+    if compat && !compat_supported() {
+        return Err(LinuxError::ENOEXEC);
+    }
+    let uxl = if compat { SR_UXL_32 } else { SR_UXL_64 };
+    tf.sstatus = (tf.sstatus & !SR_UXL) | uxl;
+    ctx.compat = compat;
+    switch_compat(compat);
+    Ok(())
+}
+
+/// Records whether the next task is a compat one, on context switch.
+pub(super) fn switch_compat(compat: bool) {
+    unsafe { COMPAT_TASK.write_current_raw(compat) };
+}
+
+/// Returns whether the current task is a compat one.
+#[inline]
+pub fn is_compat_task() -> bool {
+    COMPAT_TASK.read_current()
+}
+
+/// Returns the size of the address space of the current task:
+/// [`COMPAT_TASK_SIZE`] for a compat one, [`task_size`] otherwise.
+#[inline]
+pub fn current_task_size() -> usize {
+    if is_compat_task() {
+        COMPAT_TASK_SIZE
+    } else {
+        task_size()
+    }
+}
+
+/// Returns the 32-bit user value `reg` of a compat task (a pointer, a size,
+/// or any unsigned argument), without the sign extension of its register.
+#[inline]
+pub const fn compat_ptr(reg: usize) -> usize {
+    reg as u32 as usize
+}
+
+/// Returns the arguments of the syscall of a compat task trapped with `tf`,
+/// zero-extended.
+///
+/// The signed ones must be cast back with `as u32 as i32`.
+pub fn compat_syscall_args(tf: &TrapFrame) -> [usize; 6] {
+    let regs = &tf.regs;
+    [regs.a0, regs.a1, regs.a2, regs.a3, regs.a4, regs.a5].map(compat_ptr)
+}
@@ -1,6 +1,12 @@
-use crate::arch::{SR_FS_INITIAL, SR_SPIE, SR_SUM, SR_UXL_64};
+use crate::arch::{SR_FS, SR_FS_INITIAL, SR_SD, SR_SPIE, SR_SPP, SR_SUM, SR_UXL_64, SR_VS};
+use crate::trap::TRAPFRAME_SIZE;
+use axerrno::{LinuxError, LinuxResult};
 use core::arch::asm;
+use core::sync::atomic::AtomicUsize;
+#[cfg(feature = "fp_simd")]
+use core::sync::atomic::Ordering;
 use memory_addr::VirtAddr;
+use spinbase::SpinNoIrq;
 
 include_asm_marcos!();
 
@@ -54,6 +60,351 @@ pub struct TrapFrame {
     pub sstatus: usize,
 }
 
+impl TrapFrame {
+    /// Returns whether the trap came from U-mode.
+    #[inline]
+    pub const fn from_user(&self) -> bool {
+        self.sstatus & SR_SPP == 0
+    }
+
+    /// Returns the instruction that caused the current synchronous exception,
+    /// for tracing.
+    ///
+    /// It reads `scause`, so it must be called while handling this trap.
+    /// Returns [`None`] for interrupts, and for the exceptions where the
+    /// instruction itself cannot be fetched (instruction access faults, page
+    /// faults and misaligned fetches).
+    pub fn trap_instruction(&self) -> Option<u32> {
+        use riscv::register::scause::{self, Exception, Trap};
+        match scause::read().cause() {
+            Trap::Interrupt(_) => None,
+            Trap::Exception(
+                Exception::InstructionMisaligned
+                | Exception::InstructionFault
+                | Exception::InstructionPageFault,
+            ) => None,
+            Trap::Exception(_) => super::fetch_instruction(self.sepc, self.from_user()),
+        }
+    }
+}
+
+/// The state of the floating-point (or vector) registers, as in the `FS`
+/// (or `VS`) field of `sstatus`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FpuDirtyState {
+    /// The unit is disabled, any access traps.
+    Off = 0,
+    /// The registers hold their initial values.
+    Initial = 1,
+    /// The registers are unchanged since they were last saved.
+    Clean = 2,
+    /// The registers have been changed since they were last saved.
+    Dirty = 3,
+}
+
+impl FpuDirtyState {
+    const fn from_field(field: usize) -> Self {
+        match field & 0b11 {
+            0 => Self::Off,
+            1 => Self::Initial,
+            2 => Self::Clean,
+            _ => Self::Dirty,
+        }
+    }
+
+    /// Returns whether the registers must be saved when switching away from
+    /// the task, i.e. whether the state is [`Dirty`](Self::Dirty).
+    pub const fn needs_save(self) -> bool {
+        matches!(self, Self::Dirty)
+    }
+}
+
+const SR_FS_SHIFT: usize = SR_FS.trailing_zeros() as usize;
+const SR_VS_SHIFT: usize = SR_VS.trailing_zeros() as usize;
+
+fn set_state_field(ctx: &mut TrapFrame, mask: usize, shift: usize, state: FpuDirtyState) {
+    ctx.sstatus = (ctx.sstatus & !mask) | ((state as usize) << shift);
+    // SD summarizes whether either FS or VS is dirty (XS is always off).
+    let fs_dirty = ctx.sstatus & SR_FS == SR_FS;
+    let vs_dirty = ctx.sstatus & SR_VS == SR_VS;
+    if fs_dirty || vs_dirty {
+        ctx.sstatus |= SR_SD;
+    } else {
+        ctx.sstatus &= !SR_SD;
+    }
+}
+
+/// Returns the state of the floating-point registers of the trapped task,
+/// from its saved `sstatus.FS`.
+///
+/// The context switch only needs to save the registers when it is
+/// [`Dirty`](FpuDirtyState::Dirty).
+pub fn fpu_state(ctx: &TrapFrame) -> FpuDirtyState {
+    FpuDirtyState::from_field(ctx.sstatus >> SR_FS_SHIFT)
+}
+
+/// Sets the saved `sstatus.FS` of the trapped task to `state`.
+///
+/// A debugger that writes the floating-point registers must set it to
+/// [`Dirty`](FpuDirtyState::Dirty), so that they are saved on the next
+/// switch.
+pub fn set_fpu_state(ctx: &mut TrapFrame, state: FpuDirtyState) {
+    set_state_field(ctx, SR_FS, SR_FS_SHIFT, state)
+}
+
+/// Returns the state of the vector registers of the trapped task, from its
+/// saved `sstatus.VS`.
+pub fn vector_state(ctx: &TrapFrame) -> FpuDirtyState {
+    FpuDirtyState::from_field(ctx.sstatus >> SR_VS_SHIFT)
+}
+
+/// Sets the saved `sstatus.VS` of the trapped task to `state`.
+pub fn set_vector_state(ctx: &mut TrapFrame, state: FpuDirtyState) {
+    set_state_field(ctx, SR_VS, SR_VS_SHIFT, state)
+}
+
+/// Floating-point registers.
+#[repr(C)]
+#[derive(Debug, Default)]
+pub struct FpState {
+    /// The `f0`..`f31` registers.
+    pub regs: [u64; 32],
+    /// Floating-point Control and Status Register (`fcsr`).
+    pub fcsr: usize,
+    /// The task has used the FPU, i.e. `regs` must be restored.
+    used: bool,
+    /// The CPU whose registers were last loaded with `regs`.
+    last_cpu: AtomicUsize,
+}
+
+/// The address of the [`FpState`] loaded in the registers of this CPU, 0 if
+/// none.
+#[cfg(feature = "fp_simd")]
+#[percpu2::def_percpu]
+static FP_OWNER: usize = 0;
+
+/// Forgets the [`FpState`] loaded in the registers of this CPU, after they
+/// were lost (in a non-retentive suspend).
+#[cfg(feature = "fp_simd")]
+pub(super) fn forget_fp_owner() {
+    unsafe { FP_OWNER.write_current_raw(0) };
+}
+
+#[cfg(feature = "fp_simd")]
+fn set_live_fs(state: FpuDirtyState) {
+    let sstatus: usize;
+    unsafe {
+        core::arch::asm!("csrr {}, sstatus", out(reg) sstatus);
+        let sstatus = (sstatus & !SR_FS) | ((state as usize) << SR_FS_SHIFT);
+        core::arch::asm!("csrw sstatus, {}", in(reg) sstatus);
+    }
+}
+
+#[cfg(feature = "fp_simd")]
+fn live_fs() -> FpuDirtyState {
+    let sstatus: usize;
+    unsafe { core::arch::asm!("csrr {}, sstatus", out(reg) sstatus) };
+    FpuDirtyState::from_field(sstatus >> SR_FS_SHIFT)
+}
+
+#[cfg(feature = "fp_simd")]
+impl FpState {
+    /// Loads the registers from `self`, and makes it the owner of this CPU.
+    fn restore(&self) {
+        set_live_fs(FpuDirtyState::Clean);
+        unsafe {
+            fpstate_restore(self);
+            FP_OWNER.write_current_raw(self as *const _ as usize);
+        }
+        self.last_cpu
+            .store(crate::cpu::_this_cpu_id(), Ordering::Relaxed);
+    }
+
+    /// Whether the registers of this CPU still hold `self`.
+    fn is_loaded(&self) -> bool {
+        self.last_cpu.load(Ordering::Relaxed) == crate::cpu::_this_cpu_id()
+            && unsafe { FP_OWNER.read_current_raw() } == self as *const _ as usize
+    }
+
+    /// Saves the registers of this CPU to `self` if they hold it and have
+    /// changed, so that `self` is up to date (e.g. for a signal frame).
+    pub(super) fn sync(&mut self) {
+        if self.is_loaded() && live_fs().needs_save() {
+            unsafe { fpstate_save(self) };
+            set_live_fs(FpuDirtyState::Clean);
+        }
+    }
+
+    /// Makes `self`, just written, the registers of a task that is not
+    /// running: they are loaded on its next switch or use.
+    pub(super) fn set_changed(&mut self) {
+        self.used = true;
+        self.last_cpu.store(usize::MAX, Ordering::Relaxed);
+    }
+
+    /// Makes `self`, just written, the registers of the current task of the
+    /// trap `tf`: loaded now if its unit is on, or on its next use.
+    pub(super) fn reload(&mut self, tf: &mut TrapFrame) {
+        self.used = true;
+        if fpu_state(tf) != FpuDirtyState::Off {
+            self.restore();
+            set_fpu_state(tf, FpuDirtyState::Clean);
+        }
+    }
+
+    fn switch_to(&mut self, next_fpstate: &FpState) {
+        // Lazy save: only the registers changed since they were last loaded.
+        if live_fs().needs_save() {
+            unsafe { fpstate_save(self) };
+            set_live_fs(FpuDirtyState::Clean);
+        }
+        // The first use of a new task is left to `handle_fpu_trap`.
+        if next_fpstate.used && !next_fpstate.is_loaded() {
+            next_fpstate.restore();
+        }
+    }
+}
+
+/// Handles the trap of the first floating-point instruction of the task of
+/// `ctx`, which starts with `sstatus.FS` off.
+///
+/// It must be called on an illegal instruction exception from U-mode. If
+/// `FS` is off in `tf`, it loads the registers of the task (all zeros the
+/// first time), sets `FS` to [`Clean`](FpuDirtyState::Clean) and returns
+/// `true`: the instruction is retried when returning to user space. Returns
+/// `false` (a real illegal instruction) otherwise.
+#[cfg(feature = "fp_simd")]
+pub fn handle_fpu_trap(tf: &mut TrapFrame, ctx: &mut TaskContext) -> bool {
+    if fpu_state(tf) != FpuDirtyState::Off {
+        return false;
+    }
+    ctx.fp_state.used = true;
+    ctx.fp_state.restore();
+    set_fpu_state(tf, FpuDirtyState::Clean);
+    true
+}
+
+#[naked]
+#[cfg(feature = "fp_simd")]
+unsafe extern "C" fn fpstate_save(_fpstate: &mut FpState) {
+    asm!(
+        "
+        fsd     f0, 0 * 8(a0)
+        fsd     f1, 1 * 8(a0)
+        fsd     f2, 2 * 8(a0)
+        fsd     f3, 3 * 8(a0)
+        fsd     f4, 4 * 8(a0)
+        fsd     f5, 5 * 8(a0)
+        fsd     f6, 6 * 8(a0)
+        fsd     f7, 7 * 8(a0)
+        fsd     f8, 8 * 8(a0)
+        fsd     f9, 9 * 8(a0)
+        fsd     f10, 10 * 8(a0)
+        fsd     f11, 11 * 8(a0)
+        fsd     f12, 12 * 8(a0)
+        fsd     f13, 13 * 8(a0)
+        fsd     f14, 14 * 8(a0)
+        fsd     f15, 15 * 8(a0)
+        fsd     f16, 16 * 8(a0)
+        fsd     f17, 17 * 8(a0)
+        fsd     f18, 18 * 8(a0)
+        fsd     f19, 19 * 8(a0)
+        fsd     f20, 20 * 8(a0)
+        fsd     f21, 21 * 8(a0)
+        fsd     f22, 22 * 8(a0)
+        fsd     f23, 23 * 8(a0)
+        fsd     f24, 24 * 8(a0)
+        fsd     f25, 25 * 8(a0)
+        fsd     f26, 26 * 8(a0)
+        fsd     f27, 27 * 8(a0)
+        fsd     f28, 28 * 8(a0)
+        fsd     f29, 29 * 8(a0)
+        fsd     f30, 30 * 8(a0)
+        fsd     f31, 31 * 8(a0)
+        frcsr   t0
+        sd      t0, 32 * 8(a0)
+        ret",
+        options(noreturn),
+    )
+}
+
+#[naked]
+#[cfg(feature = "fp_simd")]
+unsafe extern "C" fn fpstate_restore(_fpstate: &FpState) {
+    asm!(
+        "
+        fld     f0, 0 * 8(a0)
+        fld     f1, 1 * 8(a0)
+        fld     f2, 2 * 8(a0)
+        fld     f3, 3 * 8(a0)
+        fld     f4, 4 * 8(a0)
+        fld     f5, 5 * 8(a0)
+        fld     f6, 6 * 8(a0)
+        fld     f7, 7 * 8(a0)
+        fld     f8, 8 * 8(a0)
+        fld     f9, 9 * 8(a0)
+        fld     f10, 10 * 8(a0)
+        fld     f11, 11 * 8(a0)
+        fld     f12, 12 * 8(a0)
+        fld     f13, 13 * 8(a0)
+        fld     f14, 14 * 8(a0)
+        fld     f15, 15 * 8(a0)
+        fld     f16, 16 * 8(a0)
+        fld     f17, 17 * 8(a0)
+        fld     f18, 18 * 8(a0)
+        fld     f19, 19 * 8(a0)
+        fld     f20, 20 * 8(a0)
+        fld     f21, 21 * 8(a0)
+        fld     f22, 22 * 8(a0)
+        fld     f23, 23 * 8(a0)
+        fld     f24, 24 * 8(a0)
+        fld     f25, 25 * 8(a0)
+        fld     f26, 26 * 8(a0)
+        fld     f27, 27 * 8(a0)
+        fld     f28, 28 * 8(a0)
+        fld     f29, 29 * 8(a0)
+        fld     f30, 30 * 8(a0)
+        fld     f31, 31 * 8(a0)
+        ld      t0, 32 * 8(a0)
+        fscsr   t0
+        ret",
+        options(noreturn),
+    )
+}
+
+/// The maximum number of context switch hooks, and of the extension slots
+/// of a [`TaskContext`].
+pub const MAX_CONTEXT_SWITCH_HOOKS: usize = 4;
+
+/// A piece of per-task state switched by [`TaskContext::switch_to`], e.g. a
+/// lazily saved register file, without the scheduler knowing about it.
+///
+/// A hook owns an extension slot of each [`TaskContext`] (`ext[slot]`),
+/// zeroed for a new task, where it keeps its state of the task (e.g. a
+/// pointer to it).
+pub trait ContextSwitchHook: Sync {
+    /// Called on the CPU before it switches from one task to another, with
+    /// the slots of the previous and the next task.
+    fn pre_switch(&self, prev: &mut usize, next: &usize);
+    /// Called in a task when it is switched back to, with its slot. It is
+    /// not called for the first run of a new task.
+    fn post_switch(&self, _slot: &mut usize) {}
+}
+
+static SWITCH_HOOKS: SpinNoIrq<[Option<&'static dyn ContextSwitchHook>; MAX_CONTEXT_SWITCH_HOOKS]> =
+    SpinNoIrq::new([None; MAX_CONTEXT_SWITCH_HOOKS]);
+
+/// Registers a context switch hook, and returns its extension slot.
+///
+/// The hooks run in the order of the slots, after the state switched by the
+/// HAL itself. Returns [`None`] if all the slots are taken.
+pub fn register_context_switch_hook(hook: &'static dyn ContextSwitchHook) -> Option<usize> {
+    let mut hooks = SWITCH_HOOKS.lock();
+    let slot = hooks.iter().position(Option::is_none)?;
+    hooks[slot] = Some(hook);
+    Some(slot)
+}
+
 /// Saved hardware states of a task.
 ///
 /// The context usually includes:
@@ -87,7 +438,33 @@ pub struct TaskContext {
     pub s11: usize,
 
     pub tp: usize,
-    // TODO: FP states
+    /// The number of the ignored top bits of user addresses (pointer
+    /// masking), see [`set_pointer_masking`](super::set_pointer_masking).
+    pub pmlen: u8,
+    /// Whether the task runs in RV32, see
+    /// [`set_compat_task`](super::set_compat_task).
+    pub compat: bool,
+    /// The shadow stack and landing pad state of the task, see
+    /// [`set_shadow_stack`](super::set_shadow_stack).
+    pub cfi: super::UserCfi,
+    /// The bottom of the kernel stack, if its guard page is installed by
+    /// [`install_stack_guard`](super::install_stack_guard), 0 otherwise. The
+    /// trap entry detects the overflows of the stack with it.
+    pub kstack_bottom: usize,
+    /// The stack protector canary of the task, see
+    /// [`STACK_CANARY_GP_OFFSET`](super::STACK_CANARY_GP_OFFSET).
+    pub stack_canary: usize,
+    /// The performance events counted for the task, see
+    /// [`task_pmu_add_event`](super::task_pmu_add_event).
+    #[cfg(feature = "irq")]
+    pub pmu: super::TaskPmu,
+    #[cfg(feature = "fp_simd")]
+    pub fp_state: FpState,
+    #[cfg(feature = "fp_simd")]
+    pub vector_state: super::VectorState,
+    /// The extension slots of the context switch hooks, see
+    /// [`register_context_switch_hook`].
+    pub ext: [usize; MAX_CONTEXT_SWITCH_HOOKS],
 }
 
 impl TaskContext {
@@ -102,6 +479,46 @@ impl TaskContext {
         self.sp = kstack_top.as_usize();
         self.ra = entry;
         self.tp = tls_area.as_usize();
+        self.stack_canary = super::stack_protector::new_stack_canary();
+    }
+
+    /// Initializes the context for a new task that returns to user space,
+    /// with the trap frame at the top of its kernel stack.
+    ///
+    /// The trap frame (see [`fork_trap_frame`](Self::fork_trap_frame)) must
+    /// be set up before the task runs, e.g. as a copy of the one of the
+    /// parent, with `a0` cleared.
+    pub fn init_fork(&mut self, kstack_top: VirtAddr, tls_area: VirtAddr) {
+        let tf = kstack_top.as_usize() - TRAPFRAME_SIZE;
+        self.init(super::trap::__ret_from_fork as usize, tf.into(), tls_area);
+        self.s0 = 0;
+    }
+
+    /// Initializes the context for a new kernel thread, which runs `f(arg)`
+    /// on its kernel stack.
+    ///
+    /// If `f` returns, the thread returns to user space as with
+    /// [`init_fork`](Self::init_fork), with the trap frame set up by `f`
+    /// meanwhile (e.g. with [`start_thread`]): it must not return otherwise.
+    /// The thread starts with the interrupts as the scheduler leaves them on
+    /// the switch.
+    pub fn init_kernel_thread(
+        &mut self,
+        f: extern "C" fn(usize),
+        arg: usize,
+        kstack_top: VirtAddr,
+        tls_area: VirtAddr,
+    ) {
+        self.init_fork(kstack_top, tls_area);
+        self.s0 = f as usize;
+        self.s1 = arg;
+    }
+
+    /// Returns the trap frame of a task initialized with
+    /// [`init_fork`](Self::init_fork) or
+    /// [`init_kernel_thread`](Self::init_kernel_thread) on `kstack_top`.
+    pub fn fork_trap_frame(kstack_top: VirtAddr) -> *mut TrapFrame {
+        (kstack_top.as_usize() - TRAPFRAME_SIZE) as *mut TrapFrame
     }
 
     /// Switches to another task.
@@ -114,9 +531,31 @@ impl TaskContext {
             self.tp = super::read_thread_pointer();
             unsafe { super::write_thread_pointer(next_ctx.tp) };
         }
-        unsafe {
-            // TODO: switch FP states
-            context_switch(self, next_ctx)
+        super::pointer_masking::switch_pointer_masking(next_ctx.pmlen);
+        super::compat::switch_compat(next_ctx.compat);
+        super::cfi::switch_user_cfi(&mut self.cfi, &next_ctx.cfi);
+        super::atomic_emul::clear_reservation();
+        super::stack_guard::switch_stack_guard(next_ctx.kstack_bottom);
+        super::stack_protector::switch_stack_canary(&mut self.stack_canary, next_ctx.stack_canary);
+        #[cfg(feature = "irq")]
+        super::pmu::switch_task_pmu(&mut self.pmu, &next_ctx.pmu);
+        #[cfg(feature = "fp_simd")]
+        self.fp_state.switch_to(&next_ctx.fp_state);
+        #[cfg(feature = "fp_simd")]
+        self.vector_state.switch_to(&next_ctx.vector_state);
+        let hooks = *SWITCH_HOOKS.lock();
+        for (slot, hook) in hooks.iter().enumerate() {
+            if let Some(hook) = hook {
+                hook.pre_switch(&mut self.ext[slot], &next_ctx.ext[slot]);
+            }
+        }
+        unsafe { context_switch(self, next_ctx) }
+        // Back in this task, maybe with other hooks registered meanwhile.
+        let hooks = *SWITCH_HOOKS.lock();
+        for (slot, hook) in hooks.iter().enumerate() {
+            if let Some(hook) = hook {
+                hook.post_switch(&mut self.ext[slot]);
+            }
         }
     }
 }
@@ -162,10 +601,26 @@ unsafe extern "C" fn context_switch(_current_task: &mut TaskContext, _next_task:
     )
 }
 
-pub fn start_thread(regs: usize, pc: usize, sp: usize) {
+/// Sets up `regs` to enter user space at `pc` with the stack at `sp`.
+///
+/// `sp` is used as is, not moved below the red zone: it points to the
+/// argc/argv block, as the ABI requires at the entry, and that block has
+/// been placed with `user_stack_reserve` by `build_user_stack`.
+///
+/// Returns [`LinuxError::EINVAL`] if `sp` is not aligned to
+/// [`STACK_ALIGN`](crate::trap::STACK_ALIGN), leaving `regs` untouched.
+pub fn start_thread(regs: usize, pc: usize, sp: usize) -> LinuxResult {
+    if sp % crate::trap::STACK_ALIGN != 0 {
+        return Err(LinuxError::EINVAL);
+    }
     let regs = unsafe { core::slice::from_raw_parts_mut(regs as *mut TrapFrame, 1) };
     regs[0].sepc = pc;
     // default to open the sum bit
     regs[0].sstatus = SR_SPIE | SR_FS_INITIAL | SR_UXL_64 | SR_SUM;
+    // With `fp_simd`, the FPU is enabled by the first use, see
+    // `handle_fpu_trap`.
+    #[cfg(feature = "fp_simd")]
+    set_fpu_state(&mut regs[0], FpuDirtyState::Off);
     regs[0].regs.sp = sp;
+    Ok(())
 }
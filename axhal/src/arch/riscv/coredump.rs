@@ -0,0 +1,233 @@
+//! The notes of ELF core files, in the layouts that gdb reads for riscv64.
+//!
+//! A core dump of a user process has a `PT_NOTE` segment with the notes of
+//! the process ([`write_process_notes`]: `NT_PRPSINFO` and `NT_AUXV`), then
+//! those of each thread ([`write_thread_notes`]: `NT_PRSTATUS`, followed by
+//! `NT_PRFPREG` and `NT_RISCV_VECTOR` if the thread has them), in the order
+//! of Linux. The kernel writes the ELF header, the `PT_LOAD` segments and
+//! the memory itself.
+
+use axerrno::LinuxError;
+use core::mem::size_of;
+
+use super::{
+    get_fpregs, get_gregs, get_vregs, vregs_size, ElfFpregs, ElfGregs, TaskContext, TrapFrame,
+    NT_PRFPREG, NT_PRSTATUS, NT_RISCV_VECTOR,
+};
+
+/// The note type of the process information.
+pub const NT_PRPSINFO: u32 = 3;
+/// The note type of the auxiliary vector.
+pub const NT_AUXV: u32 = 6;
+
+/// The `struct elf_prstatus` of Linux: the status of a thread, and its
+/// general registers.
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ElfPrstatus {
+    /// The signal number, code and errno of the signal (`pr_info`).
+    pub info: [i32; 3],
+    /// The current signal.
+    pub cursig: i16,
+    /// The pending signals.
+    pub sigpend: usize,
+    /// The blocked signals.
+    pub sighold: usize,
+    /// The thread ID.
+    pub pid: i32,
+    /// The parent process ID.
+    pub ppid: i32,
+    /// The process group ID.
+    pub pgrp: i32,
+    /// The session ID.
+    pub sid: i32,
+    /// The user, system, cumulative user and cumulative system times, as
+    /// `(seconds, microseconds)`.
+    pub times: [[usize; 2]; 4],
+    /// The general registers.
+    pub reg: ElfGregs,
+    /// Whether the `NT_PRFPREG` note follows.
+    pub fpvalid: i32,
+}
+
+static_assertions::const_assert_eq!(size_of::<ElfPrstatus>(), 376);
+
+/// The `struct elf_prpsinfo` of Linux: the information of a process.
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct ElfPrpsinfo {
+    /// The numeric state.
+    pub state: u8,
+    /// The state as a letter, e.g. `b'R'`.
+    pub sname: u8,
+    /// Whether the process is a zombie.
+    pub zomb: u8,
+    /// The nice value.
+    pub nice: i8,
+    /// The flags of the process.
+    pub flag: usize,
+    /// The user ID.
+    pub uid: u32,
+    /// The group ID.
+    pub gid: u32,
+    /// The process ID.
+    pub pid: i32,
+    /// The parent process ID.
+    pub ppid: i32,
+    /// The process group ID.
+    pub pgrp: i32,
+    /// The session ID.
+    pub sid: i32,
+    /// The name of the executable, NUL-padded.
+    pub fname: [u8; 16],
+    /// The start of the command line, NUL-padded.
+    pub psargs: [u8; 80],
+}
+
+static_assertions::const_assert_eq!(size_of::<ElfPrpsinfo>(), 136);
+
+#[repr(C)]
+struct Elf64Nhdr {
+    namesz: u32,
+    descsz: u32,
+    ty: u32,
+}
+
+const fn align4(size: usize) -> usize {
+    (size + 3) & !3
+}
+
+/// Returns the size of a note named `name` (without its NUL) with a
+/// descriptor of `desc_size` bytes.
+pub const fn note_size(name: &str, desc_size: usize) -> usize {
+    size_of::<Elf64Nhdr>() + align4(name.len() + 1) + align4(desc_size)
+}
+
+/// Writes a note named `name` of type `ty` at the start of `buf`, and
+/// returns its size.
+///
+/// Returns [`LinuxError::ENOSPC`] if it does not fit in `buf`.
+pub fn write_note(buf: &mut [u8], name: &str, ty: u32, desc: &[u8]) -> Result<usize, LinuxError> {
+    write_note_with(buf, name, ty, desc.len(), |dst| {
+        dst.copy_from_slice(desc);
+        Ok(())
+    })
+}
+
+/// Writes a note as [`write_note`], whose descriptor of `desc_size` bytes is
+/// written in place by `fill`.
+fn write_note_with(
+    buf: &mut [u8],
+    name: &str,
+    ty: u32,
+    desc_size: usize,
+    fill: impl FnOnce(&mut [u8]) -> Result<(), LinuxError>,
+) -> Result<usize, LinuxError> {
+    let size = note_size(name, desc_size);
+    let buf = buf.get_mut(..size).ok_or(LinuxError::ENOSPC)?;
+    buf.fill(0);
+    let nhdr = Elf64Nhdr {
+        namesz: (name.len() + 1) as u32,
+        descsz: desc_size as u32,
+        ty,
+    };
+    buf[..size_of::<Elf64Nhdr>()].copy_from_slice(as_bytes(&nhdr));
+    let name_offset = size_of::<Elf64Nhdr>();
+    buf[name_offset..name_offset + name.len()].copy_from_slice(name.as_bytes());
+    let desc_offset = name_offset + align4(name.len() + 1);
+    fill(&mut buf[desc_offset..desc_offset + desc_size])?;
+    Ok(size)
+}
+
+fn as_bytes<T>(value: &T) -> &[u8] {
+    unsafe { core::slice::from_raw_parts(value as *const T as *const u8, size_of::<T>()) }
+}
+
+/// Returns the `NT_PRSTATUS` of a thread with the registers of the trap
+/// `tf`, for the kernel to fill the rest.
+pub fn elf_prstatus(tf: &TrapFrame) -> ElfPrstatus {
+    ElfPrstatus {
+        reg: get_gregs(tf),
+        fpvalid: cfg!(feature = "fp_simd") as i32,
+        ..Default::default()
+    }
+}
+
+/// Writes the `NT_PRSTATUS` note of `prstatus` at the start of `buf`, and
+/// returns its size.
+pub(super) fn write_prstatus_note(
+    buf: &mut [u8],
+    prstatus: &ElfPrstatus,
+) -> Result<usize, LinuxError> {
+    write_note(buf, "CORE", NT_PRSTATUS, as_bytes(prstatus))
+}
+
+/// Returns the size of the notes of the process, with `auxv_len` entries in
+/// the auxiliary vector (without `AT_NULL`).
+pub const fn process_notes_size(auxv_len: usize) -> usize {
+    note_size("CORE", size_of::<ElfPrpsinfo>())
+        + note_size("CORE", (auxv_len + 1) * 2 * size_of::<usize>())
+}
+
+/// Writes the notes of the process at the start of `buf`: its `psinfo`,
+/// and its auxiliary vector `auxv` (without `AT_NULL`, which is added).
+/// Returns their size, [`process_notes_size`].
+///
+/// Returns [`LinuxError::ENOSPC`] if they do not fit in `buf`.
+pub fn write_process_notes(
+    buf: &mut [u8],
+    psinfo: &ElfPrpsinfo,
+    auxv: &[(usize, usize)],
+) -> Result<usize, LinuxError> {
+    let mut offset = write_note(buf, "CORE", NT_PRPSINFO, as_bytes(psinfo))?;
+    let desc_size = (auxv.len() + 1) * 2 * size_of::<usize>();
+    offset += write_note_with(&mut buf[offset..], "CORE", NT_AUXV, desc_size, |desc| {
+        let entries = auxv.iter().chain(&[(0, 0)]);
+        for (pair, &(key, value)) in desc.chunks_exact_mut(2 * size_of::<usize>()).zip(entries) {
+            pair[..size_of::<usize>()].copy_from_slice(&key.to_ne_bytes());
+            pair[size_of::<usize>()..].copy_from_slice(&value.to_ne_bytes());
+        }
+        Ok(())
+    })?;
+    Ok(offset)
+}
+
+/// Returns the size of the notes of the thread of `ctx`.
+pub fn thread_notes_size(ctx: &mut TaskContext) -> usize {
+    let mut size = note_size("CORE", size_of::<ElfPrstatus>());
+    if cfg!(feature = "fp_simd") {
+        size += note_size("CORE", size_of::<ElfFpregs>());
+    }
+    match vregs_size(ctx) {
+        0 => size,
+        vsize => size + note_size("LINUX", vsize),
+    }
+}
+
+/// Writes the notes of a thread at the start of `buf`: its `prstatus` (see
+/// [`elf_prstatus`]), then its FP and vector registers from `ctx` if it has
+/// them. Returns their size, [`thread_notes_size`].
+///
+/// Returns [`LinuxError::ENOSPC`] if they do not fit in `buf`.
+pub fn write_thread_notes(
+    buf: &mut [u8],
+    prstatus: &ElfPrstatus,
+    ctx: &mut TaskContext,
+) -> Result<usize, LinuxError> {
+    let mut offset = write_prstatus_note(buf, prstatus)?;
+    if cfg!(feature = "fp_simd") {
+        let fpregs = get_fpregs(ctx);
+        offset += write_note(&mut buf[offset..], "CORE", NT_PRFPREG, as_bytes(&fpregs))?;
+    }
+    let vsize = vregs_size(ctx);
+    if vsize != 0 {
+        offset += write_note_with(
+            &mut buf[offset..],
+            "LINUX",
+            NT_RISCV_VECTOR,
+            vsize,
+            |desc| get_vregs(ctx, desc),
+        )?;
+    }
+    Ok(offset)
+}
@@ -0,0 +1,278 @@
+//! CPU feature detection.
+//!
+//! The ISA extensions of each hart are read once from the
+//! `riscv,isa-extensions` (or `riscv,isa`) of its node in the device tree.
+//! The ones whose enable bits are WARL are also written and read back on
+//! each CPU as it starts, and dropped if the bits do not stick (e.g. a V
+//! whose `sstatus.VS` stays off): the rest of the HAL asks [`cpu_has`], for
+//! all CPUs, or [`cpu_has_on`], for one.
+
+use core::fmt;
+use core::sync::atomic::{AtomicU32, AtomicU8, Ordering};
+
+use super::pointer_masking::CSR_SENVCFG;
+use super::SR_VS;
+use crate::cpu::{_this_cpu_id, cpu_to_hartid};
+
+/// A RISC-V ISA extension used by the HAL.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Feature {
+    /// Hypervisor.
+    H,
+    /// Vector.
+    V,
+    /// S-mode pointer masking, under the control of the firmware.
+    Smnpm,
+    /// Counter-overflow interrupts.
+    Sscofpmf,
+    /// U-mode pointer masking.
+    Ssnpm,
+    /// S-mode timer compare (`stimecmp`).
+    Sstc,
+    /// Hardware updates of the A and D bits of the PTEs.
+    Svadu,
+    /// NAPOT (64 KiB) pages.
+    Svnapot,
+    /// Page-based memory types.
+    Svpbmt,
+    /// Cache-block management (`cbo.clean` / `cbo.flush` / `cbo.inval`).
+    Zicbom,
+    /// Cache-block zeroing (`cbo.zero`).
+    Zicboz,
+    /// Landing pads.
+    Zicfilp,
+    /// Shadow stacks.
+    Zicfiss,
+    /// The `seed` entropy source CSR.
+    Zkr,
+    /// Address generation (e.g. `sh1add`).
+    Zba,
+    /// Basic bit manipulation (e.g. `orc.b`, `rev8`).
+    Zbb,
+    /// Single-bit instructions (e.g. `bset`).
+    Zbs,
+    /// The `pause` hint.
+    Zihintpause,
+}
+
+impl Feature {
+    /// All the features.
+    pub const ALL: [Feature; 18] = [
+        Feature::H,
+        Feature::V,
+        Feature::Smnpm,
+        Feature::Sscofpmf,
+        Feature::Ssnpm,
+        Feature::Sstc,
+        Feature::Svadu,
+        Feature::Svnapot,
+        Feature::Svpbmt,
+        Feature::Zicbom,
+        Feature::Zicboz,
+        Feature::Zicfilp,
+        Feature::Zicfiss,
+        Feature::Zkr,
+        Feature::Zba,
+        Feature::Zbb,
+        Feature::Zbs,
+        Feature::Zihintpause,
+    ];
+
+    /// Returns the name of the extension in the ISA string, e.g. `"sstc"`.
+    pub const fn name(self) -> &'static str {
+        match self {
+            Feature::H => "h",
+            Feature::V => "v",
+            Feature::Smnpm => "smnpm",
+            Feature::Sscofpmf => "sscofpmf",
+            Feature::Ssnpm => "ssnpm",
+            Feature::Sstc => "sstc",
+            Feature::Svadu => "svadu",
+            Feature::Svnapot => "svnapot",
+            Feature::Svpbmt => "svpbmt",
+            Feature::Zicbom => "zicbom",
+            Feature::Zicboz => "zicboz",
+            Feature::Zicfilp => "zicfilp",
+            Feature::Zicfiss => "zicfiss",
+            Feature::Zkr => "zkr",
+            Feature::Zba => "zba",
+            Feature::Zbb => "zbb",
+            Feature::Zbs => "zbs",
+            Feature::Zihintpause => "zihintpause",
+        }
+    }
+
+    const fn bit(self) -> u32 {
+        1 << self as u32
+    }
+}
+
+/// The names of a set of features, for the log.
+struct FeatureNames(u32);
+
+impl fmt::Display for FeatureNames {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut features = Feature::ALL.iter().filter(|&f| self.0 & f.bit() != 0);
+        match features.next() {
+            Some(first) => f.write_str(first.name())?,
+            None => return f.write_str("none"),
+        }
+        features.try_for_each(|feature| write!(f, " {}", feature.name()))
+    }
+}
+
+#[allow(clippy::declare_interior_mutable_const)]
+const NO_FEATURES: AtomicU32 = AtomicU32::new(0);
+
+/// The features of each CPU, as [`Feature::bit`]s.
+static CPU_FEATURES: [AtomicU32; axconfig::SMP] = [NO_FEATURES; axconfig::SMP];
+
+/// 0: not read from the device tree, 1: being read, 2: read.
+static FEATURES_STATE: AtomicU8 = AtomicU8::new(0);
+
+/// Returns the features of each CPU, read from the device tree the first
+/// time.
+fn cpu_features() -> &'static [AtomicU32; axconfig::SMP] {
+    if FEATURES_STATE.load(Ordering::Acquire) == 2 {
+        return &CPU_FEATURES;
+    }
+    if FEATURES_STATE
+        .compare_exchange(0, 1, Ordering::Acquire, Ordering::Acquire)
+        .is_ok()
+    {
+        for (cpu_id, features) in CPU_FEATURES.iter().enumerate() {
+            let Some(hwid) = cpu_to_hartid(cpu_id) else {
+                continue;
+            };
+            let bits = Feature::ALL
+                .iter()
+                .filter(|f| crate::platform::dt::cpu_isa_extension_supported(hwid, f.name()))
+                .fold(0, |bits, f| bits | f.bit());
+            features.store(bits, Ordering::Relaxed);
+        }
+        FEATURES_STATE.store(2, Ordering::Release);
+    } else {
+        while FEATURES_STATE.load(Ordering::Acquire) != 2 {
+            core::hint::spin_loop();
+        }
+    }
+    &CPU_FEATURES
+}
+
+/// Returns whether all CPUs have `feature`.
+///
+/// It needs the device tree, i.e. must be called after `arch_init_early`.
+pub fn cpu_has(feature: Feature) -> bool {
+    let count = crate::cpu::cpu_count();
+    count != 0
+        && cpu_features()[..count]
+            .iter()
+            .all(|features| features.load(Ordering::Relaxed) & feature.bit() != 0)
+}
+
+/// Returns whether the CPU `cpu_id` has `feature`.
+pub fn cpu_has_on(cpu_id: usize, feature: Feature) -> bool {
+    cpu_features()
+        .get(cpu_id)
+        .is_some_and(|features| features.load(Ordering::Relaxed) & feature.bit() != 0)
+}
+
+/// Returns the `AT_HWCAP` bits of the CPU `cpu_id`: its single-letter
+/// extensions usable by the user programs, bit `letter - 'a'`.
+///
+/// F, D and V are only reported if the kernel switches their registers
+/// (`fp_simd`), and V only if it can be enabled.
+pub fn cpu_hwcap(cpu_id: usize) -> usize {
+    let Some(hwid) = cpu_to_hartid(cpu_id) else {
+        return 0;
+    };
+    let letters: &[&str] = if cfg!(feature = "fp_simd") {
+        &["i", "m", "a", "f", "d", "c"]
+    } else {
+        &["i", "m", "a", "c"]
+    };
+    let bit = |letter: &str| 1 << (letter.as_bytes()[0] - b'a');
+    let mut hwcap = letters
+        .iter()
+        .filter(|letter| crate::platform::dt::cpu_isa_extension_supported(hwid, letter))
+        .fold(0, |hwcap, letter| hwcap | bit(letter));
+    if cfg!(feature = "fp_simd") && cpu_has_on(cpu_id, Feature::V) {
+        hwcap |= bit("v");
+    }
+    hwcap
+}
+
+/// Returns the `AT_HWCAP` of the user programs: the [`cpu_hwcap`] bits
+/// common to all CPUs.
+pub fn elf_hwcap() -> usize {
+    match crate::cpu::cpu_count() {
+        0 => 0,
+        count => (0..count).fold(usize::MAX, |hwcap, cpu_id| hwcap & cpu_hwcap(cpu_id)),
+    }
+}
+
+/// Returns whether `sstatus.VS` can be turned on.
+fn probe_vs() -> bool {
+    let old: usize;
+    let probed: usize;
+    unsafe {
+        core::arch::asm!("csrrs {}, sstatus, {}", out(reg) old, in(reg) SR_VS);
+        core::arch::asm!("csrr {}, sstatus", out(reg) probed);
+        core::arch::asm!("csrc sstatus, {}", in(reg) SR_VS & !old);
+    }
+    probed & SR_VS != 0
+}
+
+/// `senvcfg.CBZE`: `cbo.zero` is allowed in U-mode.
+const SENVCFG_CBZE: usize = 1 << 7;
+
+/// Allows `cbo.zero` in U-mode, and returns whether `senvcfg.CBZE` sticks.
+fn enable_user_cbo_zero() -> bool {
+    let probed: usize;
+    unsafe {
+        core::arch::asm!("csrs {csr}, {}", in(reg) SENVCFG_CBZE, csr = const CSR_SENVCFG);
+        core::arch::asm!("csrr {}, {csr}", out(reg) probed, csr = const CSR_SENVCFG);
+    }
+    probed & SENVCFG_CBZE != 0
+}
+
+/// Checks the features of the current CPU whose enable bits can be read
+/// back, drops the ones that cannot be enabled, and logs the features.
+/// Called as each CPU starts.
+pub(crate) fn probe_cpu_features() {
+    let cpu_id = _this_cpu_id();
+    let Some(features) = cpu_features().get(cpu_id) else {
+        return;
+    };
+    let bits = features.load(Ordering::Relaxed);
+    let mut dropped = 0;
+    if bits & Feature::V.bit() != 0 && !probe_vs() {
+        dropped |= Feature::V.bit();
+    }
+    // Zicboz is reported to user space, which needs `cbo.zero` enabled.
+    if bits & Feature::Zicboz.bit() != 0 && !enable_user_cbo_zero() {
+        dropped |= Feature::Zicboz.bit();
+    }
+    if bits & Feature::Ssnpm.bit() != 0 && !super::pointer_masking::probe_ssnpm() {
+        dropped |= Feature::Ssnpm.bit();
+    }
+    if bits & Feature::Zicfiss.bit() != 0 && !super::cfi::probe_zicfiss() {
+        dropped |= Feature::Zicfiss.bit();
+    }
+    if bits & Feature::Zicfilp.bit() != 0 && !super::cfi::probe_zicfilp() {
+        dropped |= Feature::Zicfilp.bit();
+    }
+    if dropped != 0 {
+        warn!(
+            "CPU {}: cannot enable {}, ignored",
+            cpu_id,
+            FeatureNames(dropped)
+        );
+        features.fetch_and(!dropped, Ordering::Relaxed);
+    }
+    info!(
+        "CPU {}: ISA extensions: {}",
+        cpu_id,
+        FeatureNames(bits & !dropped)
+    );
+}
@@ -0,0 +1,218 @@
+//! CPU idle states, entered with the SBI HSM `sbi_hart_suspend`.
+//!
+//! [`init_cpuidle`] reads the `riscv,idle-state` nodes of the device tree
+//! ([`idle_states`](crate::platform::dt::idle_states)), after state 0, a
+//! plain `wfi`. In a retentive state the hart keeps its registers, and
+//! `sbi_hart_suspend` returns when it wakes up. In a non-retentive state it
+//! loses them: its context is saved as for suspend to RAM, it resumes at
+//! `__cpu_resume_entry`, and the `resume_percpu` callbacks of the
+//! [`SuspendOps`](super::SuspendOps) restore the timer and the interrupt
+//! controller.
+//!
+//! The idle loop of the scheduler picks a state with [`select_idle_state`]
+//! from the predicted idle time (e.g. until the next timer event, or from
+//! [`last_idle_residency`]) and enters it with [`enter_idle_state`], or
+//! does both with [`cpu_idle`]. The states with `local-timer-stop` are
+//! skipped, as there is no broadcast timer to wake the harts up.
+
+use core::time::Duration;
+
+use axerrno::LinuxError;
+use lazy_init::LazyInit;
+
+use super::sbi;
+use super::suspend::{cpu_suspend, finisher_ret, restore_percpu_state, resume_entry_paddr};
+use crate::platform::dt;
+
+/// The maximum number of idle states, including `wfi`.
+pub const MAX_IDLE_STATES: usize = 8;
+
+/// A low-power state of the CPUs.
+#[derive(Debug, Clone, Copy)]
+pub struct IdleState {
+    /// The name of the device tree node, `"wfi"` for state 0.
+    pub name: &'static str,
+    /// The suspend type of `sbi_hart_suspend`, [`None`] for `wfi`.
+    pub suspend_param: Option<u32>,
+    /// Whether the hart keeps its registers in the state.
+    pub retentive: bool,
+    /// The time to enter and leave the state.
+    pub exit_latency: Duration,
+    /// The shortest idle time worth entering the state.
+    pub target_residency: Duration,
+}
+
+const WFI_STATE: IdleState = IdleState {
+    name: "wfi",
+    suspend_param: None,
+    retentive: true,
+    exit_latency: Duration::ZERO,
+    target_residency: Duration::ZERO,
+};
+
+struct IdleStates {
+    states: [IdleState; MAX_IDLE_STATES],
+    count: usize,
+}
+
+static IDLE_STATES: LazyInit<IdleStates> = LazyInit::new();
+static WFI_ONLY: [IdleState; 1] = [WFI_STATE];
+
+/// The suspend type of the non-retentive state being entered.
+#[percpu2::def_percpu]
+static SUSPEND_PARAM: usize = 0;
+
+/// The time spent in the last idle state, in nanoseconds.
+#[percpu2::def_percpu]
+static LAST_RESIDENCY_NS: u64 = 0;
+
+/// The last suspend type of the platform-specific retentive and
+/// non-retentive ranges, the others are reserved.
+const SUSPEND_PARAM_PLATFORM_MAX: u32 = 0x0fff_ffff;
+
+fn suspend_param_valid(param: u32) -> bool {
+    param & !sbi::HSM_SUSPEND_NON_RETENTIVE <= SUSPEND_PARAM_PLATFORM_MAX
+}
+
+/// Reads the idle states from the device tree, on the primary CPU. The
+/// platform initialization calls it.
+///
+/// Before, or without the SBI HSM extension, only `wfi` is available.
+pub fn init_cpuidle() {
+    let mut states = [WFI_STATE; MAX_IDLE_STATES];
+    let mut count = 1;
+    let hsm = sbi::probe_extension(sbi::EID_HSM);
+    for node in dt::idle_states() {
+        if !hsm {
+            warn!("cpuidle: no SBI HSM extension, {} ignored", node.name);
+            continue;
+        }
+        if !suspend_param_valid(node.suspend_param) {
+            warn!(
+                "cpuidle: {} has a reserved suspend type {:#x}, ignored",
+                node.name, node.suspend_param
+            );
+            continue;
+        }
+        if node.local_timer_stop {
+            warn!("cpuidle: {} stops the timer, ignored", node.name);
+            continue;
+        }
+        if count == MAX_IDLE_STATES {
+            warn!("cpuidle: too many idle states, {} ignored", node.name);
+            break;
+        }
+        let state = IdleState {
+            name: node.name,
+            suspend_param: Some(node.suspend_param),
+            retentive: node.suspend_param & sbi::HSM_SUSPEND_NON_RETENTIVE == 0,
+            exit_latency: Duration::from_micros(
+                node.entry_latency_us as u64 + node.exit_latency_us as u64,
+            ),
+            target_residency: Duration::from_micros(node.min_residency_us as u64),
+        };
+        info!(
+            "cpuidle: state {}: {} ({}), exit latency {:?}, target residency {:?}",
+            count,
+            state.name,
+            if state.retentive {
+                "retentive"
+            } else {
+                "non-retentive"
+            },
+            state.exit_latency,
+            state.target_residency
+        );
+        states[count] = state;
+        count += 1;
+    }
+    IDLE_STATES.init_by(IdleStates { states, count });
+}
+
+/// Returns the idle states, from the shallowest (`wfi`) to the deepest.
+pub fn idle_states() -> &'static [IdleState] {
+    if IDLE_STATES.is_init() {
+        &IDLE_STATES.states[..IDLE_STATES.count]
+    } else {
+        &WFI_ONLY
+    }
+}
+
+/// Returns the index of the deepest idle state whose target residency is at
+/// most `predicted_idle`, and whose exit latency is at most
+/// `latency_limit`.
+pub fn select_idle_state(predicted_idle: Duration, latency_limit: Duration) -> usize {
+    idle_states()
+        .iter()
+        .rposition(|state| {
+            state.target_residency <= predicted_idle && state.exit_latency <= latency_limit
+        })
+        .unwrap_or(0)
+}
+
+extern "C" fn non_retentive_finisher(ctx_paddr: usize) -> isize {
+    let param = unsafe { SUSPEND_PARAM.read_current_raw() } as u32;
+    finisher_ret(sbi::hart_suspend(param, resume_entry_paddr(), ctx_paddr))
+}
+
+/// Enters the idle state `index` of [`idle_states`] on the current CPU, and
+/// returns when an interrupt enabled in `sie` is pending.
+///
+/// It must be called by the idle task with interrupts disabled: the
+/// interrupt that wakes the CPU up is taken when the caller enables them.
+///
+/// Returns [`LinuxError::EINVAL`] if there is no such state. If
+/// `sbi_hart_suspend` fails (the CPU did not idle), returns its translated
+/// error for a retentive state, and [`LinuxError::EIO`] for a non-retentive
+/// one.
+pub fn enter_idle_state(index: usize) -> Result<(), LinuxError> {
+    let state = idle_states().get(index).ok_or(LinuxError::EINVAL)?;
+    let start = crate::time::current_time_nanos();
+    let result = match state.suspend_param {
+        None => {
+            unsafe { riscv::asm::wfi() };
+            Ok(())
+        }
+        Some(param) if state.retentive => sbi::hart_suspend(param, 0, 0),
+        Some(param) => {
+            unsafe { SUSPEND_PARAM.write_current_raw(param as usize) };
+            match cpu_suspend(non_retentive_finisher) {
+                Ok(()) => {
+                    #[cfg(feature = "fp_simd")]
+                    {
+                        super::context::forget_fp_owner();
+                        super::vector::forget_vector_owner();
+                    }
+                    restore_percpu_state();
+                    Ok(())
+                }
+                Err(err) => {
+                    debug!("cpuidle: sbi_hart_suspend returned {}", err);
+                    Err(LinuxError::EIO)
+                }
+            }
+        }
+    };
+    let residency = crate::time::current_time_nanos() - start;
+    unsafe { LAST_RESIDENCY_NS.write_current_raw(residency) };
+    result.inspect_err(|err| debug!("cpuidle: failed to enter {}: {:?}", state.name, err))
+}
+
+/// Idles the current CPU in the state chosen by [`select_idle_state`], or
+/// in `wfi` if it cannot be entered, and returns the index of the state.
+///
+/// As [`enter_idle_state`], it must be called by the idle task with
+/// interrupts disabled.
+pub fn cpu_idle(predicted_idle: Duration, latency_limit: Duration) -> usize {
+    let index = select_idle_state(predicted_idle, latency_limit);
+    if index != 0 && enter_idle_state(index).is_ok() {
+        return index;
+    }
+    let _ = enter_idle_state(0);
+    0
+}
+
+/// Returns how long the current CPU stayed in its last idle state.
+pub fn last_idle_residency() -> Duration {
+    Duration::from_nanos(unsafe { LAST_RESIDENCY_NS.read_current_raw() })
+}
@@ -0,0 +1,278 @@
+//! DMA memory: coherent buffers and streaming mappings.
+//!
+//! A [`DmaDevice`] knows from the device tree whether a device snoops the
+//! CPU caches, and how its bus addresses translate to the physical ones
+//! (`dma-ranges`). The coherent buffers are allocated from the
+//! `shared-dma-pool` of `/reserved-memory` (or the pool given to
+//! [`init_dma_pool`]), mapped non-cacheable with Svpbmt. Without it, the
+//! pool is cacheable: a non-coherent device then needs the cache
+//! maintenance of the streaming mappings on the coherent buffers too.
+//!
+//! A streaming mapping ([`DmaDevice::map_single`]) hands a buffer of the
+//! linear mapping to a device for one transfer: for a non-coherent device,
+//! the caches are written back before, and discarded after the transfer
+//! ([`DmaDevice::unmap_single`]).
+
+use axerrno::LinuxError;
+use memory_addr::{PhysAddr, VirtAddr};
+use spinbase::SpinNoIrq;
+
+use super::ioremap::{ioremap, MmioRegion};
+use super::svpbmt::{has_svpbmt, MemAttr};
+use super::{dcache_clean_range, dcache_flush_range, dcache_invalidate_range};
+use crate::mem::{phys_to_virt, virt_to_phys, PAGE_SIZE_4K};
+use crate::platform::dt::{self, DmaRange, MAX_DMA_RANGES};
+
+/// The maximum number of coherent buffers allocated at the same time.
+pub const MAX_DMA_BUFFERS: usize = 64;
+
+/// The direction of a DMA transfer.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DmaDirection {
+    /// The device reads the memory.
+    ToDevice,
+    /// The device writes the memory.
+    FromDevice,
+    /// The device reads and writes the memory.
+    Bidirectional,
+}
+
+/// A device that does DMA.
+#[derive(Debug, Clone, Copy)]
+pub struct DmaDevice {
+    coherent: bool,
+    ranges: [Option<DmaRange>; MAX_DMA_RANGES],
+}
+
+/// A coherent DMA buffer, from [`DmaDevice::alloc_coherent`].
+#[derive(Debug)]
+pub struct DmaBuffer {
+    vaddr: VirtAddr,
+    bus_addr: u64,
+    size: usize,
+}
+
+impl DmaBuffer {
+    /// Returns the virtual address of the buffer, for the CPU.
+    pub const fn vaddr(&self) -> VirtAddr {
+        self.vaddr
+    }
+
+    /// Returns the bus address of the buffer, for the device.
+    pub const fn bus_addr(&self) -> u64 {
+        self.bus_addr
+    }
+
+    /// Returns the size in bytes of the buffer.
+    pub const fn size(&self) -> usize {
+        self.size
+    }
+}
+
+struct DmaPool {
+    region: Option<MmioRegion>,
+    /// The allocated `(offset, size)` ranges, sorted.
+    buffers: [(usize, usize); MAX_DMA_BUFFERS],
+    len: usize,
+}
+
+impl DmaPool {
+    /// Returns the index and the offset of a free range of `size` bytes.
+    fn find_free(&self, pool_size: usize, size: usize) -> Option<(usize, usize)> {
+        let mut cursor = 0;
+        for (i, &(offset, len)) in self.buffers[..self.len].iter().enumerate() {
+            if cursor + size <= offset {
+                return Some((i, cursor));
+            }
+            cursor = offset + len;
+        }
+        (cursor + size <= pool_size).then_some((self.len, cursor))
+    }
+}
+
+static DMA_POOL: SpinNoIrq<DmaPool> = SpinNoIrq::new(DmaPool {
+    region: None,
+    buffers: [(0, 0); MAX_DMA_BUFFERS],
+    len: 0,
+});
+
+/// Makes the `size` bytes of RAM at `paddr` the pool of the coherent
+/// buffers, and maps it. The RAM should not be in the linear mapping
+/// (`no-map`), which would alias it with another memory type.
+///
+/// Returns [`LinuxError::EINVAL`] if `paddr` is not page-aligned,
+/// [`LinuxError::EBUSY`] if there is already a pool, and the errors of
+/// [`ioremap`] otherwise.
+pub fn init_dma_pool(paddr: PhysAddr, size: usize) -> Result<(), LinuxError> {
+    if !paddr.is_aligned_4k() {
+        return Err(LinuxError::EINVAL);
+    }
+    let mut pool = DMA_POOL.lock();
+    if pool.region.is_some() {
+        return Err(LinuxError::EBUSY);
+    }
+    let attr = if has_svpbmt() {
+        MemAttr::NonCacheable
+    } else {
+        MemAttr::Normal
+    };
+    let size = size & !(PAGE_SIZE_4K - 1);
+    let region = ioremap(paddr, size, attr)?;
+    info!("DMA pool: [{:#x}, {:#x})", paddr, paddr.as_usize() + size);
+    pool.region = Some(region);
+    Ok(())
+}
+
+/// Sets up the pool of the coherent buffers from the `shared-dma-pool` of
+/// the device tree, if any. The platform initialization calls it.
+pub fn init_dma() {
+    if let Some((paddr, size)) = dt::dma_pool() {
+        if let Err(err) = init_dma_pool(PhysAddr::from(paddr), size) {
+            warn!("DMA pool not mapped: {:?}", err);
+        }
+    }
+}
+
+impl DmaDevice {
+    /// A coherent device whose bus addresses are the physical addresses.
+    pub const fn coherent() -> Self {
+        Self {
+            coherent: true,
+            ranges: [None; MAX_DMA_RANGES],
+        }
+    }
+
+    /// Creates the device of the first enabled node compatible with one of
+    /// `compatible`, with its coherency and the `dma-ranges` of its bus.
+    ///
+    /// Returns [`LinuxError::ENODEV`] if there is no such node.
+    pub fn from_dt(compatible: &[&str]) -> Result<Self, LinuxError> {
+        let node = dt::dma_device(compatible).ok_or(LinuxError::ENODEV)?;
+        Ok(Self {
+            coherent: node.coherent,
+            ranges: node.ranges,
+        })
+    }
+
+    /// Returns whether the device snoops the CPU caches.
+    pub const fn is_coherent(&self) -> bool {
+        self.coherent
+    }
+
+    /// Returns the bus address of `[paddr, paddr + size)` for the device.
+    ///
+    /// Returns [`LinuxError::ERANGE`] if the range is outside its
+    /// `dma-ranges`.
+    pub fn phys_to_bus(&self, paddr: PhysAddr, size: usize) -> Result<u64, LinuxError> {
+        let (start, end) = (paddr.as_usize() as u64, (paddr.as_usize() + size) as u64);
+        if self.ranges.iter().all(Option::is_none) {
+            return Ok(start);
+        }
+        self.ranges
+            .iter()
+            .flatten()
+            .find(|range| range.paddr <= start && end <= range.paddr + range.size)
+            .map(|range| start - range.paddr + range.bus_addr)
+            .ok_or(LinuxError::ERANGE)
+    }
+
+    /// Returns the physical address of the bus address `bus_addr` of the
+    /// device, if it is in its `dma-ranges`.
+    pub fn bus_to_phys(&self, bus_addr: u64) -> Option<PhysAddr> {
+        if self.ranges.iter().all(Option::is_none) {
+            return Some(PhysAddr::from(bus_addr as usize));
+        }
+        self.ranges
+            .iter()
+            .flatten()
+            .find(|range| (range.bus_addr..range.bus_addr + range.size).contains(&bus_addr))
+            .map(|range| PhysAddr::from((bus_addr - range.bus_addr + range.paddr) as usize))
+    }
+
+    /// Allocates a zeroed coherent buffer of `size` bytes (rounded up to
+    /// pages) for the device.
+    ///
+    /// Returns [`LinuxError::EINVAL`] if `size` is 0, [`LinuxError::ENOMEM`]
+    /// if there is no pool or it is full, and [`LinuxError::ERANGE`] if the
+    /// device cannot reach the pool.
+    pub fn alloc_coherent(&self, size: usize) -> Result<DmaBuffer, LinuxError> {
+        if size == 0 {
+            return Err(LinuxError::EINVAL);
+        }
+        let size = (size + PAGE_SIZE_4K - 1) & !(PAGE_SIZE_4K - 1);
+        let mut pool = DMA_POOL.lock();
+        let region = pool.region.as_ref().ok_or(LinuxError::ENOMEM)?;
+        let (base, pool_size) = (region.paddr(), region.size());
+        let vbase = region.vaddr();
+        if pool.len == MAX_DMA_BUFFERS {
+            return Err(LinuxError::ENOMEM);
+        }
+        let (index, offset) = pool.find_free(pool_size, size).ok_or(LinuxError::ENOMEM)?;
+        let bus_addr = self.phys_to_bus(base + offset, size)?;
+        let len = pool.len;
+        pool.buffers.copy_within(index..len, index + 1);
+        pool.buffers[index] = (offset, size);
+        pool.len += 1;
+        drop(pool);
+
+        let vaddr = vbase + offset;
+        unsafe { core::ptr::write_bytes(vaddr.as_mut_ptr(), 0, size) };
+        if !self.coherent {
+            // The maintenance goes through the cacheable linear alias: `vaddr`
+            // is in the MMIO window, out of reach of `virt_to_phys`.
+            dcache_flush_range(phys_to_virt(base + offset), size);
+        }
+        Ok(DmaBuffer {
+            vaddr,
+            bus_addr,
+            size,
+        })
+    }
+
+    /// Frees a buffer from [`alloc_coherent`](Self::alloc_coherent).
+    pub fn free_coherent(&self, buffer: DmaBuffer) {
+        let mut pool = DMA_POOL.lock();
+        let Some(vbase) = pool.region.as_ref().map(|region| region.vaddr()) else {
+            return;
+        };
+        let offset = buffer.vaddr.as_usize() - vbase.as_usize();
+        let len = pool.len;
+        if let Some(index) = pool.buffers[..len].iter().position(|&(o, _)| o == offset) {
+            pool.buffers.copy_within(index + 1..len, index);
+            pool.len -= 1;
+        }
+    }
+
+    /// Hands `[vaddr, vaddr + size)` of the linear mapping to the device for
+    /// a transfer in `dir`, and returns its bus address. The CPU must not
+    /// touch the buffer until [`unmap_single`](Self::unmap_single).
+    ///
+    /// Returns [`LinuxError::ERANGE`] if the device cannot reach it.
+    pub fn map_single(
+        &self,
+        vaddr: VirtAddr,
+        size: usize,
+        dir: DmaDirection,
+    ) -> Result<u64, LinuxError> {
+        let bus_addr = self.phys_to_bus(virt_to_phys(vaddr), size)?;
+        if !self.coherent {
+            // Written back for FromDevice too, so that no dirty line is
+            // evicted over the data of the device.
+            match dir {
+                DmaDirection::ToDevice | DmaDirection::FromDevice => {
+                    dcache_clean_range(vaddr, size)
+                }
+                DmaDirection::Bidirectional => dcache_flush_range(vaddr, size),
+            }
+        }
+        Ok(bus_addr)
+    }
+
+    /// Gives back to the CPU a buffer from [`map_single`](Self::map_single)
+    /// with the same arguments, once the transfer is done.
+    pub fn unmap_single(&self, vaddr: VirtAddr, size: usize, dir: DmaDirection) {
+        if !self.coherent && dir != DmaDirection::ToDevice {
+            dcache_invalidate_range(vaddr, size);
+        }
+    }
+}
@@ -0,0 +1,531 @@
+//! The EFI stub: the kernel image doubles as a PE/COFF EFI application.
+//!
+//! With the `efi` feature, the image header starts with the `"MZ"` magic of
+//! a PE image (the instruction `c.li s4, -13`, harmless on the non-EFI boot
+//! path) and points to a PE/COFF header after it, as Linux does; the other
+//! fields of the header are unchanged, so the image still boots with
+//! `booti` or `-kernel`. The firmware loads the image anywhere and calls
+//! `efi_pe_entry` with paging off, which:
+//!
+//! - copies the image to `KERNEL_BASE_PADDR`, where the boot code expects
+//!   it (the kernel is not relocatable, its `_start` comes from
+//!   `arch_boot`);
+//! - looks up the FDT and the ACPI RSDP in the configuration tables, and
+//!   the boot hart ID with the `RISCV_EFI_BOOT_PROTOCOL`;
+//! - loads the initial ramdisk with the `LoadFile2` protocol of the
+//!   `LINUX_EFI_INITRD_MEDIA_GUID` device path, if the bootloader has one;
+//! - retrieves the memory map into the copy, and exits the boot services;
+//! - jumps to `_start` in the copy with `a0` the hart ID and `a1` the FDT,
+//!   the normal boot path.
+//!
+//! The stub runs at the address where the firmware loaded it, not the one
+//! the kernel is linked at, so it only uses PC-relative addressing: no
+//! pointers in statics, no trait objects and no formatting. The kernel then
+//! finds what the stub found in [`efi_boot_info`].
+
+use core::ffi::c_void;
+use core::ptr::addr_of;
+
+use memory_addr::PAGE_SIZE_4K;
+
+use super::image_header::TEXT_OFFSET;
+
+/// The size of the buffer of the memory map.
+const EFI_MMAP_SIZE: usize = 16 * 1024;
+
+// The image header of `image_header.rs` with the "MZ" magic in `code0`, the
+// offset of the PE header in `res3`, then the PE/COFF headers. They take the
+// first page, so that the `.text` section is aligned in the PE image.
+core::arch::global_asm!(
+    r#"
+    .section .head.text, "ax"
+    .global _image_header
+_image_header:
+    .option push
+    .option norvc
+    .2byte  0x5a4d
+    j       _start
+    .2byte  0
+    .option pop
+    .dword  {text_offset}
+    .dword  _ekernel - _skernel
+    .dword  0
+    .word   {version}
+    .word   0
+    .dword  0
+    .ascii  "RISCV\0\0\0"
+    .ascii  "RSC\x05"
+    .word   pe_header - _image_header
+
+pe_header:
+    .ascii  "PE\0\0"
+    .2byte  0x5064
+    .2byte  2
+    .word   0
+    .word   0
+    .word   0
+    .2byte  section_table - optional_header
+    .2byte  0x0206
+
+optional_header:
+    .2byte  0x020b
+    .byte   0x02
+    .byte   0x14
+    .word   _etext - efi_header_end
+    .word   _edata - _etext
+    .word   0
+    .word   efi_pe_entry - _image_header
+    .word   efi_header_end - _image_header
+
+    .dword  0
+    .word   0x1000
+    .word   0x1000
+    .2byte  0
+    .2byte  0
+    .2byte  0
+    .2byte  0
+    .2byte  0
+    .2byte  0
+    .word   0
+    .word   _ekernel - _image_header
+    .word   efi_header_end - _image_header
+    .word   0
+    .2byte  10
+    .2byte  0
+    .dword  0
+    .dword  0
+    .dword  0
+    .dword  0
+    .word   0
+    .word   6
+    .dword  0
+    .dword  0
+    .dword  0
+    .dword  0
+    .dword  0
+    .dword  0
+
+section_table:
+    .ascii  ".text\0\0\0"
+    .word   _etext - efi_header_end
+    .word   efi_header_end - _image_header
+    .word   _etext - efi_header_end
+    .word   efi_header_end - _image_header
+    .word   0
+    .word   0
+    .2byte  0
+    .2byte  0
+    .word   0x60000020
+
+    .ascii  ".data\0\0\0"
+    .word   _ekernel - _etext
+    .word   _etext - _image_header
+    .word   _edata - _etext
+    .word   _etext - _image_header
+    .word   0
+    .word   0
+    .2byte  0
+    .2byte  0
+    .word   0xc0000040
+
+    .balign 0x1000
+efi_header_end:
+    .previous
+    "#,
+    text_offset = const TEXT_OFFSET,
+    version = const super::RISCV_HEADER_VERSION,
+);
+
+extern "C" {
+    static _image_header: u8;
+    static _start: u8;
+    static _edata: u8;
+    static _ekernel: u8;
+}
+
+type EfiStatus = usize;
+type EfiHandle = *mut c_void;
+
+const EFI_SUCCESS: EfiStatus = 0;
+const EFI_ERROR: EfiStatus = 1 << (usize::BITS - 1);
+const EFI_LOAD_ERROR: EfiStatus = EFI_ERROR | 1;
+const EFI_UNSUPPORTED: EfiStatus = EFI_ERROR | 3;
+const EFI_BUFFER_TOO_SMALL: EfiStatus = EFI_ERROR | 5;
+
+const EFI_ALLOCATE_ANY_PAGES: u32 = 0;
+const EFI_ALLOCATE_ADDRESS: u32 = 2;
+const EFI_LOADER_CODE: u32 = 1;
+const EFI_LOADER_DATA: u32 = 2;
+
+#[repr(C)]
+#[derive(Clone, Copy, PartialEq, Eq)]
+struct EfiGuid(u32, u16, u16, [u8; 8]);
+
+const DEVICE_TREE_GUID: EfiGuid = EfiGuid(
+    0xb1b6_21d5,
+    0xf19c,
+    0x41a5,
+    [0x83, 0x0b, 0xd9, 0x15, 0x2c, 0x69, 0xaa, 0xe0],
+);
+const ACPI_20_TABLE_GUID: EfiGuid = EfiGuid(
+    0x8868_e871,
+    0xe4f1,
+    0x11d3,
+    [0xbc, 0x22, 0x00, 0x80, 0xc7, 0x3c, 0x88, 0x81],
+);
+const RISCV_EFI_BOOT_PROTOCOL_GUID: EfiGuid = EfiGuid(
+    0xccd1_5fec,
+    0x6f73,
+    0x4eec,
+    [0x83, 0x95, 0x3e, 0x69, 0xe4, 0xb9, 0x40, 0xbf],
+);
+const LINUX_EFI_INITRD_MEDIA_GUID: EfiGuid = EfiGuid(
+    0x5568_e427,
+    0x68fc,
+    0x4f3d,
+    [0xac, 0x74, 0xca, 0x55, 0x52, 0x31, 0xcc, 0x68],
+);
+const EFI_LOAD_FILE2_PROTOCOL_GUID: EfiGuid = EfiGuid(
+    0x4006_c0c1,
+    0xfcb3,
+    0x403e,
+    [0x99, 0x6d, 0x4a, 0x6c, 0x87, 0x24, 0xe0, 0x6d],
+);
+
+#[repr(C)]
+struct EfiTableHeader {
+    signature: u64,
+    revision: u32,
+    header_size: u32,
+    crc32: u32,
+    reserved: u32,
+}
+
+#[repr(C)]
+struct EfiSystemTable {
+    hdr: EfiTableHeader,
+    firmware_vendor: *const u16,
+    firmware_revision: u32,
+    _console: [usize; 6],
+    runtime_services: *mut c_void,
+    boot_services: *const EfiBootServices,
+    number_of_table_entries: usize,
+    configuration_table: *const EfiConfigurationTable,
+}
+
+/// The boot services, with placeholders for the ones the stub does not
+/// call.
+#[repr(C)]
+struct EfiBootServices {
+    hdr: EfiTableHeader,
+    _tpl: [usize; 2],
+    allocate_pages: unsafe extern "efiapi" fn(u32, u32, usize, *mut u64) -> EfiStatus,
+    _free_pages: usize,
+    get_memory_map: unsafe extern "efiapi" fn(
+        *mut usize,
+        *mut u8,
+        *mut usize,
+        *mut usize,
+        *mut u32,
+    ) -> EfiStatus,
+    _pool_to_reinstall_protocol: [usize; 11],
+    handle_protocol:
+        unsafe extern "efiapi" fn(EfiHandle, *const EfiGuid, *mut *mut c_void) -> EfiStatus,
+    _reserved_to_locate_handle: [usize; 3],
+    locate_device_path:
+        unsafe extern "efiapi" fn(*const EfiGuid, *mut *const c_void, *mut EfiHandle) -> EfiStatus,
+    _install_configuration_table_to_unload_image: [usize; 5],
+    exit_boot_services: unsafe extern "efiapi" fn(EfiHandle, usize) -> EfiStatus,
+    _monotonic_to_locate_handle_buffer: [usize; 10],
+    locate_protocol:
+        unsafe extern "efiapi" fn(*const EfiGuid, *mut c_void, *mut *mut c_void) -> EfiStatus,
+}
+
+#[repr(C)]
+struct EfiConfigurationTable {
+    guid: EfiGuid,
+    table: *mut c_void,
+}
+
+#[repr(C)]
+struct EfiLoadFile2Protocol {
+    load_file: unsafe extern "efiapi" fn(
+        *mut Self,
+        *const c_void,
+        bool,
+        *mut usize,
+        *mut c_void,
+    ) -> EfiStatus,
+}
+
+/// The device path of the initial ramdisk: a vendor media node of
+/// `LINUX_EFI_INITRD_MEDIA_GUID`, and the end node.
+#[repr(C)]
+struct InitrdDevicePath {
+    vendor: [u8; 4],
+    guid: EfiGuid,
+    end: [u8; 4],
+}
+
+const INITRD_DEVICE_PATH: InitrdDevicePath = InitrdDevicePath {
+    vendor: [4, 3, 20, 0],
+    guid: LINUX_EFI_INITRD_MEDIA_GUID,
+    end: [0x7f, 0xff, 4, 0],
+};
+
+#[repr(C)]
+struct RiscvEfiBootProtocol {
+    revision: u64,
+    get_boot_hartid: unsafe extern "efiapi" fn(*mut Self, *mut usize) -> EfiStatus,
+}
+
+/// A descriptor of the EFI memory map.
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct EfiMemoryDescriptor {
+    /// The type of the memory (`EfiConventionalMemory` is 7).
+    pub ty: u32,
+    /// The physical address of the start of the region.
+    pub phys_start: u64,
+    /// The virtual address of the start of the region.
+    pub virt_start: u64,
+    /// The number of 4K pages of the region.
+    pub num_pages: u64,
+    /// The attributes of the region.
+    pub attribute: u64,
+}
+
+/// What the EFI stub found before it exited the boot services.
+#[repr(C)]
+#[derive(Debug)]
+pub struct EfiBootInfo {
+    /// The physical address of the EFI system table.
+    pub system_table: usize,
+    /// The physical address of the FDT, 0 if there is none.
+    pub fdt: usize,
+    /// The physical address of the ACPI 2.0 RSDP, 0 if there is none.
+    pub acpi_rsdp: usize,
+    /// The hart ID of the boot hart.
+    pub boot_hartid: usize,
+    /// The physical address of the initial ramdisk loaded by the stub, 0 if
+    /// there is none.
+    pub initrd_start: usize,
+    /// The size of the initial ramdisk.
+    pub initrd_size: usize,
+    mmap_size: usize,
+    desc_size: usize,
+    desc_version: u32,
+}
+
+impl EfiBootInfo {
+    /// Returns the physical address and the size of the initial ramdisk
+    /// loaded by the stub, if any.
+    pub fn initrd(&self) -> Option<(usize, usize)> {
+        (self.initrd_size != 0).then_some((self.initrd_start, self.initrd_size))
+    }
+
+    /// Returns the descriptors of the memory map at the exit of the boot
+    /// services.
+    pub fn memory_map(&self) -> impl Iterator<Item = EfiMemoryDescriptor> + '_ {
+        let base = unsafe { addr_of!(EFI_MEMORY_MAP) }.cast::<u8>();
+        let count = if self.desc_size == 0 {
+            0
+        } else {
+            self.mmap_size / self.desc_size
+        };
+        (0..count).map(move |i| unsafe {
+            base.add(i * self.desc_size)
+                .cast::<EfiMemoryDescriptor>()
+                .read_unaligned()
+        })
+    }
+}
+
+// In `.data`, not `.bss`: the stub fills them before the boot code clears
+// the `.bss`.
+#[link_section = ".data"]
+static mut EFI_BOOT_INFO: EfiBootInfo = EfiBootInfo {
+    system_table: 0,
+    fdt: 0,
+    acpi_rsdp: 0,
+    boot_hartid: 0,
+    initrd_start: 0,
+    initrd_size: 0,
+    mmap_size: 0,
+    desc_size: 0,
+    desc_version: 0,
+};
+
+#[link_section = ".data"]
+static mut EFI_MEMORY_MAP: [u64; EFI_MMAP_SIZE / 8] = [0; EFI_MMAP_SIZE / 8];
+
+/// Returns what the EFI stub found, or `None` if the kernel was not booted
+/// by the EFI firmware.
+pub fn efi_boot_info() -> Option<&'static EfiBootInfo> {
+    let info = unsafe { &*addr_of!(EFI_BOOT_INFO) };
+    (info.system_table != 0).then_some(info)
+}
+
+/// Returns the address in the copy at `dest` of `sym` in the running image.
+fn in_copy<T>(sym: *const T, dest: usize) -> usize {
+    sym as usize - unsafe { addr_of!(_image_header) } as usize + dest
+}
+
+/// Looks up the configuration table of `guid`.
+unsafe fn config_table(st: &EfiSystemTable, guid: &EfiGuid) -> usize {
+    for i in 0..st.number_of_table_entries {
+        let entry = &*st.configuration_table.add(i);
+        if entry.guid == *guid {
+            return entry.table as usize;
+        }
+    }
+    0
+}
+
+/// Loads the initial ramdisk of the bootloader in new pages, and returns
+/// their address and the size of the ramdisk.
+///
+/// Returns `None` if the bootloader has no initial ramdisk for the kernel.
+unsafe fn load_initrd(bs: &EfiBootServices) -> Option<(usize, usize)> {
+    let path = INITRD_DEVICE_PATH;
+    let mut remaining = (&path as *const InitrdDevicePath).cast::<c_void>();
+    let mut handle = core::ptr::null_mut();
+    if (bs.locate_device_path)(&EFI_LOAD_FILE2_PROTOCOL_GUID, &mut remaining, &mut handle)
+        != EFI_SUCCESS
+    {
+        return None;
+    }
+    let mut proto = core::ptr::null_mut();
+    if (bs.handle_protocol)(handle, &EFI_LOAD_FILE2_PROTOCOL_GUID, &mut proto) != EFI_SUCCESS {
+        return None;
+    }
+    let proto = proto.cast::<EfiLoadFile2Protocol>();
+    let path = (&path as *const InitrdDevicePath).cast::<c_void>();
+    let mut size = 0;
+    if ((*proto).load_file)(proto, path, false, &mut size, core::ptr::null_mut())
+        != EFI_BUFFER_TOO_SMALL
+        || size == 0
+    {
+        return None;
+    }
+    let mut addr = 0;
+    let pages = size.div_ceil(PAGE_SIZE_4K);
+    if (bs.allocate_pages)(EFI_ALLOCATE_ANY_PAGES, EFI_LOADER_DATA, pages, &mut addr) != EFI_SUCCESS
+    {
+        return None;
+    }
+    if ((*proto).load_file)(proto, path, false, &mut size, addr as *mut c_void) != EFI_SUCCESS {
+        return None;
+    }
+    Some((addr as usize, size))
+}
+
+/// Retrieves the memory map into `info` and the buffer at `mmap`, and
+/// returns its key.
+unsafe fn get_memory_map(
+    bs: &EfiBootServices,
+    info: &mut EfiBootInfo,
+    mmap: *mut u8,
+) -> Result<usize, EfiStatus> {
+    let mut key = 0;
+    info.mmap_size = EFI_MMAP_SIZE;
+    match (bs.get_memory_map)(
+        &mut info.mmap_size,
+        mmap,
+        &mut key,
+        &mut info.desc_size,
+        &mut info.desc_version,
+    ) {
+        EFI_SUCCESS => Ok(key),
+        status => Err(status),
+    }
+}
+
+/// The entry of the PE image, called by the EFI firmware.
+///
+/// Returns an EFI error to the firmware if it cannot boot the kernel:
+/// `EFI_LOAD_ERROR` if `KERNEL_BASE_PADDR` is not free, or
+/// `EFI_UNSUPPORTED` without the `RISCV_EFI_BOOT_PROTOCOL`. Jumps to the
+/// kernel and does not return otherwise.
+///
+/// # Safety
+///
+/// It must only be called by the firmware, with paging off.
+#[no_mangle]
+unsafe extern "efiapi" fn efi_pe_entry(
+    image_handle: EfiHandle,
+    system_table: *const EfiSystemTable,
+) -> EfiStatus {
+    let st = &*system_table;
+    let bs = &*st.boot_services;
+
+    let image = addr_of!(_image_header) as usize;
+    let file_size = addr_of!(_edata) as usize - image;
+    let image_size = addr_of!(_ekernel) as usize - image;
+    let dest = axconfig::KERNEL_BASE_PADDR;
+    if image != dest {
+        let mut addr = dest as u64;
+        let pages = image_size.div_ceil(PAGE_SIZE_4K);
+        if (bs.allocate_pages)(EFI_ALLOCATE_ADDRESS, EFI_LOADER_CODE, pages, &mut addr)
+            != EFI_SUCCESS
+        {
+            return EFI_LOAD_ERROR;
+        }
+        core::ptr::copy_nonoverlapping(image as *const u8, dest as *mut u8, file_size);
+        core::ptr::write_bytes((dest + file_size) as *mut u8, 0, image_size - file_size);
+    }
+
+    let info = &mut *(in_copy(addr_of!(EFI_BOOT_INFO), dest) as *mut EfiBootInfo);
+    let mmap = in_copy(addr_of!(EFI_MEMORY_MAP), dest) as *mut u8;
+    info.system_table = system_table as usize;
+    info.fdt = config_table(st, &DEVICE_TREE_GUID);
+    info.acpi_rsdp = config_table(st, &ACPI_20_TABLE_GUID);
+    if let Some((start, size)) = load_initrd(bs) {
+        info.initrd_start = start;
+        info.initrd_size = size;
+    }
+
+    let mut proto = core::ptr::null_mut();
+    if (bs.locate_protocol)(
+        &RISCV_EFI_BOOT_PROTOCOL_GUID,
+        core::ptr::null_mut(),
+        &mut proto,
+    ) != EFI_SUCCESS
+    {
+        return EFI_UNSUPPORTED;
+    }
+    let proto = proto.cast::<RiscvEfiBootProtocol>();
+    if ((*proto).get_boot_hartid)(proto, &mut info.boot_hartid) != EFI_SUCCESS {
+        return EFI_UNSUPPORTED;
+    }
+
+    // The key is stale if the map changes between the two calls, e.g. when
+    // an event allocates memory: get the map again and retry once.
+    let mut status = EFI_LOAD_ERROR;
+    for _ in 0..2 {
+        status = match get_memory_map(bs, info, mmap) {
+            Ok(key) => (bs.exit_boot_services)(image_handle, key),
+            Err(status) => status,
+        };
+        if status == EFI_SUCCESS {
+            break;
+        }
+    }
+    if status != EFI_SUCCESS {
+        return status;
+    }
+
+    let entry = in_copy(addr_of!(_start), dest);
+    core::arch::asm!(
+        "csrw   sie, zero",
+        "csrci  sstatus, 0x2",
+        "csrw   satp, zero",
+        "sfence.vma",
+        "fence.i",
+        "jr     {entry}",
+        entry = in(reg) entry,
+        in("a0") info.boot_hartid,
+        in("a1") info.fdt,
+        options(noreturn),
+    )
+}
@@ -0,0 +1,269 @@
+//! Seed entropy for the random number generators of the kernel.
+//!
+//! [`random_seed`] mixes the available sources: the bytes of the `rng-seed`
+//! of the device tree (each given out once), the sources registered by the
+//! drivers with [`register_entropy_source`] (e.g. a virtio-rng device), the
+//! Zkr `seed` CSR, and the jitter of the cycle and time counters, and it
+//! returns how many bits of entropy the result is credited with. The raw
+//! `seed` CSR output is only credited with 256 bits per 2048, as the Zkr
+//! specification requires it to be conditioned, and the jitter with none.
+//!
+//! The random pool of the OS polls it until enough entropy is credited, and
+//! hashes the results. The random bytes of `AT_RANDOM` and the ASLR offsets
+//! come from [`random_bytes`], a ChaCha20 generator that takes its key from
+//! it until 256 bits are credited. The stack canaries take [`seed_without_pool_sources`]
+//! instead, so as not to use up the `rng-seed` before the pool.
+
+use core::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use spinbase::SpinNoIrq;
+
+/// The maximum number of registered [`EntropySource`]s.
+pub const MAX_ENTROPY_SOURCES: usize = 4;
+
+/// A source of entropy of a driver: it fills the start of the buffer with
+/// random bytes of full entropy, and returns how many it wrote.
+pub type EntropySource = fn(&mut [u8]) -> usize;
+
+static ENTROPY_SOURCES: SpinNoIrq<[Option<EntropySource>; MAX_ENTROPY_SOURCES]> =
+    SpinNoIrq::new([None; MAX_ENTROPY_SOURCES]);
+
+/// CSR number of `seed`.
+const CSR_SEED: usize = 0x015;
+
+const SEED_OPST_SHIFT: usize = 30;
+const SEED_OPST_BIST: usize = 0b00;
+const SEED_OPST_WAIT: usize = 0b01;
+const SEED_OPST_ES16: usize = 0b10;
+const SEED_ENTROPY_MASK: usize = 0xffff;
+
+/// How many times `seed` is polled for 16 bits, before giving up.
+const SEED_POLLS: usize = 100;
+
+/// The offset of the first unused byte of the `rng-seed`.
+static DT_SEED_USED: AtomicUsize = AtomicUsize::new(0);
+
+/// The `seed` CSR reported a fatal error.
+static SEED_DEAD: AtomicBool = AtomicBool::new(false);
+
+static JITTER_STATE: AtomicU64 = AtomicU64::new(0);
+
+/// The number of bits of entropy after which [`random_bytes`] stops adding
+/// seeds to its key.
+const RNG_SEEDED_BITS: usize = 256;
+
+/// The state of the generator of [`random_bytes`].
+struct ChaChaRng {
+    key: [u32; 8],
+    credited: usize,
+}
+
+static RNG: SpinNoIrq<ChaChaRng> = SpinNoIrq::new(ChaChaRng {
+    key: [0; 8],
+    credited: 0,
+});
+
+/// Returns whether all CPUs support Zkr, with the `seed` CSR accessible in
+/// S-mode.
+///
+/// It needs the device tree, i.e. must be called after `arch_init_early`.
+pub fn has_zkr() -> bool {
+    super::cpu_has(super::Feature::Zkr)
+}
+
+/// Registers a source of entropy, polled by [`random_seed`].
+///
+/// Returns `false` if there are already [`MAX_ENTROPY_SOURCES`] of them.
+pub fn register_entropy_source(source: EntropySource) -> bool {
+    let mut sources = ENTROPY_SOURCES.lock();
+    match sources.iter_mut().find(|slot| slot.is_none()) {
+        Some(slot) => {
+            *slot = Some(source);
+            true
+        }
+        None => false,
+    }
+}
+
+/// The SplitMix64 finalizer, whose output bits all depend on all the input
+/// bits.
+fn mix64(mut x: u64) -> u64 {
+    x = (x ^ (x >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    x = (x ^ (x >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    x ^ (x >> 31)
+}
+
+fn xor_into(buf: &mut [u8], bytes: &[u8]) {
+    for (i, &byte) in bytes.iter().enumerate() {
+        buf[i % buf.len()] ^= byte;
+    }
+}
+
+/// Mixes the unused bytes of the `rng-seed` of the device tree, returns how
+/// many.
+fn mix_dt_seed(buf: &mut [u8]) -> usize {
+    let Some(seed) = crate::platform::dt::rng_seed() else {
+        return 0;
+    };
+    let mut used = DT_SEED_USED.load(Ordering::Relaxed);
+    loop {
+        let end = seed.len().min(used + buf.len());
+        if used == end {
+            return 0;
+        }
+        match DT_SEED_USED.compare_exchange(used, end, Ordering::Relaxed, Ordering::Relaxed) {
+            Ok(_) => {
+                xor_into(buf, &seed[used..end]);
+                return end - used;
+            }
+            Err(current) => used = current,
+        }
+    }
+}
+
+/// Mixes the output of the registered sources, returns how many bytes.
+fn mix_sources(buf: &mut [u8]) -> usize {
+    let sources = *ENTROPY_SOURCES.lock();
+    let mut total = 0;
+    for source in sources.iter().flatten() {
+        let mut tmp = [0; 32];
+        for chunk in buf.chunks_mut(tmp.len()) {
+            let len = source(&mut tmp[..chunk.len()]).min(chunk.len());
+            xor_into(chunk, &tmp[..len]);
+            total += len;
+        }
+    }
+    total
+}
+
+/// Polls the `seed` CSR for 16 bits.
+fn read_seed() -> Option<u16> {
+    for _ in 0..SEED_POLLS {
+        let seed: usize;
+        // `seed` must be accessed with a write.
+        unsafe { core::arch::asm!("csrrw {}, {csr}, zero", out(reg) seed, csr = const CSR_SEED) };
+        match (seed >> SEED_OPST_SHIFT) & 0b11 {
+            SEED_OPST_ES16 => return Some((seed & SEED_ENTROPY_MASK) as u16),
+            SEED_OPST_BIST | SEED_OPST_WAIT => core::hint::spin_loop(),
+            _ => {
+                if !SEED_DEAD.swap(true, Ordering::Relaxed) {
+                    warn!("Zkr: the entropy source failed");
+                }
+                return None;
+            }
+        }
+    }
+    None
+}
+
+/// Mixes the output of the `seed` CSR, returns how many bits.
+fn mix_zkr(buf: &mut [u8]) -> usize {
+    if !has_zkr() || SEED_DEAD.load(Ordering::Relaxed) {
+        return 0;
+    }
+    let mut bits = 0;
+    for chunk in buf.chunks_mut(2) {
+        let Some(seed) = read_seed() else {
+            break;
+        };
+        xor_into(chunk, &seed.to_ne_bytes()[..chunk.len()]);
+        bits += 8 * chunk.len();
+    }
+    bits
+}
+
+/// Mixes the jitter of the cycle and time counters.
+fn mix_jitter(buf: &mut [u8]) {
+    let mut state = JITTER_STATE.fetch_add(0x9e37_79b9_7f4a_7c15, Ordering::Relaxed);
+    for chunk in buf.chunks_mut(8) {
+        let cycle: usize;
+        unsafe { core::arch::asm!("rdcycle {}", out(reg) cycle) };
+        let ticks = crate::time::current_ticks();
+        state = mix64(state ^ cycle as u64 ^ ticks.rotate_left(32));
+        xor_into(chunk, &state.to_ne_bytes()[..chunk.len()]);
+    }
+}
+
+/// Fills `buf` with seed material from the `seed` CSR and the jitter only,
+/// keeping the `rng-seed` and the registered sources for the random pool.
+pub(super) fn seed_without_pool_sources(buf: &mut [u8]) {
+    buf.fill(0);
+    mix_zkr(buf);
+    mix_jitter(buf);
+}
+
+fn quarter_round(x: &mut [u32; 16], a: usize, b: usize, c: usize, d: usize) {
+    x[a] = x[a].wrapping_add(x[b]);
+    x[d] = (x[d] ^ x[a]).rotate_left(16);
+    x[c] = x[c].wrapping_add(x[d]);
+    x[b] = (x[b] ^ x[c]).rotate_left(12);
+    x[a] = x[a].wrapping_add(x[b]);
+    x[d] = (x[d] ^ x[a]).rotate_left(8);
+    x[c] = x[c].wrapping_add(x[d]);
+    x[b] = (x[b] ^ x[c]).rotate_left(7);
+}
+
+/// Returns the ChaCha20 block `counter` of `key`, with a zero nonce.
+fn chacha20_block(key: &[u32; 8], counter: u64) -> [u32; 16] {
+    let mut state = [0; 16];
+    state[..4].copy_from_slice(&[0x6170_7865, 0x3320_646e, 0x7962_2d32, 0x6b20_6574]);
+    state[4..12].copy_from_slice(key);
+    state[12] = counter as u32;
+    state[13] = (counter >> 32) as u32;
+    let mut x = state;
+    for _ in 0..10 {
+        quarter_round(&mut x, 0, 4, 8, 12);
+        quarter_round(&mut x, 1, 5, 9, 13);
+        quarter_round(&mut x, 2, 6, 10, 14);
+        quarter_round(&mut x, 3, 7, 11, 15);
+        quarter_round(&mut x, 0, 5, 10, 15);
+        quarter_round(&mut x, 1, 6, 11, 12);
+        quarter_round(&mut x, 2, 7, 8, 13);
+        quarter_round(&mut x, 3, 4, 9, 14);
+    }
+    for (x, state) in x.iter_mut().zip(state) {
+        *x = x.wrapping_add(state);
+    }
+    x
+}
+
+/// Fills `buf` with random bytes from a ChaCha20 generator.
+///
+/// Until its key is credited with 256 bits, each call adds one 32-byte seed
+/// of [`random_seed`] to it; after that, the seed sources are not used
+/// anymore. The key is replaced with the next block after each call, so that
+/// the bytes already given out cannot be recovered from it.
+pub fn random_bytes(buf: &mut [u8]) {
+    let mut rng = RNG.lock();
+    if rng.credited < RNG_SEEDED_BITS {
+        let mut seed = [0; 32];
+        rng.credited += random_seed(&mut seed);
+        for (word, bytes) in rng.key.iter_mut().zip(seed.chunks_exact(4)) {
+            *word ^= u32::from_le_bytes(bytes.try_into().unwrap());
+        }
+    }
+    // The block 0 is the next key, the output starts at the block 1.
+    for (counter, chunk) in (1..).zip(buf.chunks_mut(64)) {
+        let block = chacha20_block(&rng.key, counter);
+        for (bytes, word) in chunk.chunks_mut(4).zip(block) {
+            bytes.copy_from_slice(&word.to_le_bytes()[..bytes.len()]);
+        }
+    }
+    let block = chacha20_block(&rng.key, 0);
+    rng.key.copy_from_slice(&block[..8]);
+}
+
+/// Fills `buf` with seed material from all the available sources, and
+/// returns the number of bits of entropy it is credited with.
+///
+/// It does not block: it returns 0 if there is no source of entropy but the
+/// jitter, and the random pool should then poll it again later.
+pub fn random_seed(buf: &mut [u8]) -> usize {
+    if buf.is_empty() {
+        return 0;
+    }
+    buf.fill(0);
+    let full_bytes = mix_dt_seed(buf) + mix_sources(buf);
+    let zkr_bits = mix_zkr(buf);
+    mix_jitter(buf);
+    (8 * full_bytes + zkr_bits / 8).min(8 * buf.len())
+}
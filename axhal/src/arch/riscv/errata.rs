@@ -0,0 +1,93 @@
+//! Errata of the cores, by vendor.
+//!
+//! The cores are identified by their `mvendorid`, `marchid` and `mimpid`,
+//! read through the SBI base extension. Each erratum is a key of the
+//! alternatives (from [`ALT_ERRATA_BASE`]), so the code that works around
+//! it is patched in at boot, and can also be checked with [`has_erratum`].
+//! The harts are assumed to be the same cores as the primary one.
+
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use super::alternative::ALT_ERRATA_BASE;
+use super::sbi;
+
+/// SiFive CIP-1200: `sfence.vma` with an address may not flush the TLB
+/// entry (some U54 and U74 cores). The whole TLB must be flushed instead.
+pub(crate) const ALT_ERRATA_SIFIVE_CIP_1200: u32 = ALT_ERRATA_BASE;
+/// T-Head C906/C910: the memory type of a page is in bits 63:59 of the leaf
+/// entry (`MAEE`), not in the Svpbmt field.
+pub(crate) const ALT_ERRATA_THEAD_PBMT: u32 = ALT_ERRATA_BASE + 1;
+/// T-Head C906/C910: the cache blocks are managed with the vendor
+/// `th.dcache.*` instructions (`THEADISAEE`), not with Zicbom.
+pub(crate) const ALT_ERRATA_THEAD_CMO: u32 = ALT_ERRATA_BASE + 2;
+
+const SIFIVE_VENDOR_ID: usize = 0x489;
+const THEAD_VENDOR_ID: usize = 0x5b7;
+
+/// The errata of the cores, as bits from [`ALT_ERRATA_BASE`], with
+/// `DETECTED` set once they are read.
+static ERRATA: AtomicU64 = AtomicU64::new(0);
+const DETECTED: u64 = 1 << 63;
+
+const fn erratum_bit(key: u32) -> u64 {
+    1 << (key - ALT_ERRATA_BASE)
+}
+
+fn sifive_errata(arch: usize, imp: usize) -> u64 {
+    let mut errata = 0;
+    if (arch == 0x8000_0000_0000_0007 || arch == 0x1)
+        && (imp & 0xff_ffff) <= 0x20_0630
+        && imp != 0x120_0626
+    {
+        errata |= erratum_bit(ALT_ERRATA_SIFIVE_CIP_1200);
+    }
+    errata
+}
+
+/// The C906 and C910 report 0 for `marchid` and `mimpid`. The firmware
+/// must have set `MAEE` and `THEADISAEE` in `mxstatus` (OpenSBI does).
+fn thead_errata(arch: usize, imp: usize) -> u64 {
+    if arch == 0 && imp == 0 {
+        erratum_bit(ALT_ERRATA_THEAD_PBMT) | erratum_bit(ALT_ERRATA_THEAD_CMO)
+    } else {
+        0
+    }
+}
+
+fn detect_errata() -> u64 {
+    let (vendor, arch, imp) = (sbi::mvendorid(), sbi::marchid(), sbi::mimpid());
+    let errata = match vendor {
+        SIFIVE_VENDOR_ID => sifive_errata(arch, imp),
+        THEAD_VENDOR_ID => thead_errata(arch, imp),
+        _ => 0,
+    };
+    for (key, name) in [
+        (ALT_ERRATA_SIFIVE_CIP_1200, "SiFive CIP-1200"),
+        (ALT_ERRATA_THEAD_PBMT, "T-Head PBMT"),
+        (ALT_ERRATA_THEAD_CMO, "T-Head CMO"),
+    ] {
+        if errata & erratum_bit(key) != 0 {
+            info!("Erratum: {}", name);
+        }
+    }
+    errata
+}
+
+/// Returns the errata of the cores, as bits from [`ALT_ERRATA_BASE`]. They
+/// are read the first time.
+pub(crate) fn errata() -> u64 {
+    match ERRATA.load(Ordering::Relaxed) {
+        0 => {
+            let errata = detect_errata();
+            ERRATA.store(errata | DETECTED, Ordering::Relaxed);
+            errata
+        }
+        errata => errata & !DETECTED,
+    }
+}
+
+/// Returns whether the cores have the erratum `key` (an `ALT_ERRATA_*`).
+pub(crate) fn has_erratum(key: u32) -> bool {
+    key.checked_sub(ALT_ERRATA_BASE)
+        .is_some_and(|erratum| erratum < 63 && errata() & (1 << erratum) != 0)
+}
@@ -0,0 +1,63 @@
+//! Dispatching the synchronous exceptions to the handlers registered with
+//! [`register_trap_handler`](crate::trap::register_trap_handler).
+
+use riscv::register::{scause, stval};
+
+use super::TrapFrame;
+use crate::trap::{dispatch_trap, TrapCause, TrapStat};
+
+/// Returns the cause of the current trap, or [`None`] for the interrupts and
+/// the exceptions that cannot be hooked (the page faults are delivered by
+/// [`handle_page_fault_trap`](super::handle_page_fault_trap)).
+pub fn trap_cause() -> Option<TrapCause> {
+    let scause = scause::read();
+    if scause.is_interrupt() {
+        return None;
+    }
+    // The codes of `scause`, matched by number as the `riscv` crate does not
+    // decode all of them (e.g. misaligned loads, ecalls from S-mode).
+    let cause = match scause.code() {
+        0 => TrapCause::InstructionMisaligned,
+        2 => TrapCause::IllegalInstruction,
+        3 => TrapCause::Breakpoint,
+        4 => TrapCause::LoadMisaligned,
+        6 => TrapCause::StoreMisaligned,
+        8 => TrapCause::UserEcall,
+        9 => TrapCause::KernelEcall,
+        18 => TrapCause::SoftwareCheck,
+        _ => return None,
+    };
+    Some(cause)
+}
+
+/// Handles the synchronous exception of `tf`.
+///
+/// It must be called by the trap handler for the exceptions. The page faults
+/// go to [`handle_page_fault_trap`](super::handle_page_fault_trap); the other
+/// exceptions go to the handler registered for their cause, and then to the
+/// default handling of the HAL: [`handle_breakpoint`](super::handle_breakpoint)
+/// for the breakpoints, [`handle_illegal_instruction`](super::handle_illegal_instruction)
+/// for the illegal instructions, [`handle_misaligned_access`](super::handle_misaligned_access)
+/// for the misaligned loads and stores. Returns `false` if the exception is still unhandled,
+/// e.g. for the trap handler to panic or kill the task (with `SIGSEGV` for a
+/// [`cfi_violation`](super::cfi_violation)).
+pub fn handle_exception(tf: &mut TrapFrame) -> bool {
+    crate::trap::count_trap(TrapStat::Exception(scause::read().code()));
+    if let Some(resolved) = super::page_fault::try_handle_page_fault(tf) {
+        return resolved;
+    }
+    let Some(cause) = trap_cause() else {
+        return false;
+    };
+    if dispatch_trap(cause, tf) {
+        return true;
+    }
+    match cause {
+        TrapCause::Breakpoint => super::handle_breakpoint(tf),
+        TrapCause::IllegalInstruction => super::handle_illegal_instruction(tf).is_ok(),
+        TrapCause::LoadMisaligned | TrapCause::StoreMisaligned => {
+            super::handle_misaligned_access(tf, stval::read())
+        }
+        _ => false,
+    }
+}
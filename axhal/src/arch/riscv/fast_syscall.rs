@@ -0,0 +1,198 @@
+//! The syscall fast path of the user trap entry.
+//!
+//! A full [`TrapFrame`] save and restore moves all 31 GPRs, but s0-s11 are
+//! callee-saved: the Rust syscall handler preserves them by itself, so on the
+//! way back to user space they still hold the user values. The fast path only
+//! saves and restores the caller-saved registers (plus `sp`, `gp`, `tp`,
+//! `sepc` and `sstatus`), and leaves s0-s11 of the frame unset.
+//!
+//! The frame is completed (s0-s11 stored) and the full restore is used
+//! whenever the frame may be inspected or changed as a whole:
+//!
+//! - For a trap other than `ecall` from U-mode.
+//! - For a syscall that needs the complete frame, see [`needs_full_frame`].
+//! - When there is work before returning to user space (pending signals,
+//!   rescheduling), see [`SyscallFastPath::exit_work_pending`].
+//! - For a traced task, whose syscalls stop (see
+//!   [`trace_syscall`](super::trace_syscall)).
+//!
+//! [`bench_syscall_frame`] measures what the fast path saves, and the
+//! boot-time self-tests print it.
+
+use lazy_init::LazyInit;
+use riscv::register::scause::{self, Exception, Trap};
+
+use super::sysno::*;
+use super::TrapFrame;
+use crate::trap::TRAPFRAME_SIZE;
+
+include_asm_marcos!();
+
+/// The handlers called by the fast path.
+pub struct SyscallFastPath {
+    /// Handles a syscall, as the full trap handler does (including advancing
+    /// `sepc`). On the fast path, s0-s11 of the frame are not valid.
+    pub syscall: fn(&mut TrapFrame),
+    /// Handles a user trap other than a syscall, with a complete frame.
+    pub user_trap: fn(&mut TrapFrame),
+    /// Returns whether there is work to do before returning to user space.
+    pub exit_work_pending: fn() -> bool,
+    /// Does the work before returning to user space, with a complete frame.
+    pub exit_work: fn(&mut TrapFrame),
+}
+
+static HANDLERS: LazyInit<SyscallFastPath> = LazyInit::new();
+
+/// Registers the handlers of the syscall fast path.
+///
+/// It must be called before [`__syscall_fast_entry`] is used.
+pub fn init_syscall_fast_path(handlers: SyscallFastPath) {
+    HANDLERS.init_by(handlers);
+}
+
+/// Returns whether the syscall `sysno` reads or writes the whole trap frame
+/// (the child copies it, or it is replaced), so that it cannot run on the
+/// fast path.
+pub const fn needs_full_frame(sysno: usize) -> bool {
+    matches!(
+        sysno,
+        LINUX_SYSCALL_CLONE
+            | LINUX_SYSCALL_CLONE3
+            | LINUX_SYSCALL_EXECVE
+            | LINUX_SYSCALL_RT_SIGRETURN
+    )
+}
+
+/// Completion states of the fast path.
+const FAST_DONE: usize = 0;
+const SLOW_EXIT_WORK: usize = 1;
+const SLOW_SYSCALL: usize = 2;
+const SLOW_TRAP: usize = 3;
+
+extern "C" fn syscall_fast_dispatch(tf: &mut TrapFrame) -> usize {
+    if !matches!(
+        scause::read().cause(),
+        Trap::Exception(Exception::UserEnvCall)
+    ) {
+        return SLOW_TRAP;
+    }
+    if needs_full_frame(tf.regs.a7) || super::syscall_traced() {
+        return SLOW_SYSCALL;
+    }
+    super::trace_syscall(tf, HANDLERS.syscall);
+    if (HANDLERS.exit_work_pending)() {
+        SLOW_EXIT_WORK
+    } else {
+        FAST_DONE
+    }
+}
+
+extern "C" fn syscall_slow_dispatch(tf: &mut TrapFrame, state: usize) {
+    match state {
+        SLOW_SYSCALL => super::trace_syscall(tf, HANDLERS.syscall),
+        SLOW_TRAP => (HANDLERS.user_trap)(tf),
+        _ => {}
+    }
+    (HANDLERS.exit_work)(tf);
+}
+
+// The entry for traps from user space.
+//
+// The trap vector jumps here after switching to the kernel stack, with `sp`
+// pointing to the trap frame (`TRAPFRAME_SIZE` below the kernel stack top),
+// `sscratch` holding the user `sp`, and all other registers untouched.
+core::arch::global_asm!(
+    r"
+    .section .text
+    .balign 4
+    .global __syscall_fast_entry
+    __syscall_fast_entry:
+    SAVE_SYSCALL_REGS
+
+    mv      a0, sp
+    call    {fast_dispatch}
+    bnez    a0, 1f
+
+    addi    t0, sp, {trapframe_size}
+    csrw    sscratch, t0
+    RESTORE_SYSCALL_REGS
+    sret
+
+1:
+    // s0-s11 still hold the user values, complete the frame with them.
+    PUSH_POP_CALLEE_REGS STR
+    mv      a1, a0
+    mv      a0, sp
+    call    {slow_dispatch}
+
+    addi    t0, sp, {trapframe_size}
+    csrw    sscratch, t0
+    RESTORE_REGS 1
+    sret
+    ",
+    fast_dispatch = sym syscall_fast_dispatch,
+    slow_dispatch = sym syscall_slow_dispatch,
+    trapframe_size = const TRAPFRAME_SIZE,
+);
+
+// Saves and restores the frame at `a0` `a1` times as the entry for the user
+// traps does, in full if `a2` is nonzero, and returns the cycles. `sscratch`
+// is set to `sp` as the user `sp` and put back at the end, and the frame must
+// hold `gp` and `tp` as the supervisor ones: the registers are unchanged
+// after each round.
+core::arch::global_asm!(
+    r"
+    .section .text
+    .balign 4
+    .global __bench_syscall_frame
+    __bench_syscall_frame:
+    csrr    a4, sscratch
+    rdcycle a3
+    bnez    a2, 2f
+1:
+    csrw    sscratch, sp
+    mv      sp, a0
+    SAVE_SYSCALL_REGS
+    RESTORE_SYSCALL_REGS
+    addi    a1, a1, -1
+    bnez    a1, 1b
+    j       3f
+2:
+    csrw    sscratch, sp
+    mv      sp, a0
+    SAVE_REGS 1
+    RESTORE_REGS 1
+    addi    a1, a1, -1
+    bnez    a1, 2b
+3:
+    rdcycle a0
+    sub     a0, a0, a3
+    csrw    sscratch, a4
+    ret
+    "
+);
+
+extern "C" {
+    fn __bench_syscall_frame(frame: &mut TrapFrame, iters: usize, full: bool) -> u64;
+}
+
+/// Returns the average cycles that saving and restoring the trap frame of a
+/// syscall takes, on the fast path and in full, over `iters` rounds.
+///
+/// The frame is not the one of a trap: only the register saves are
+/// measured, not the trap itself nor the dispatch.
+pub fn bench_syscall_frame(iters: usize) -> (u64, u64) {
+    if iters == 0 {
+        return (0, 0);
+    }
+    // `sscratch` holds a user `sp` meanwhile.
+    let _guard = kernel_guard_base::IrqSave::new();
+    let mut frame = TrapFrame::default();
+    unsafe {
+        core::arch::asm!("mv {}, gp", out(reg) frame.regs.gp);
+        core::arch::asm!("mv {}, tp", out(reg) frame.regs.tp);
+        let fast = __bench_syscall_frame(&mut frame, iters, false);
+        let full = __bench_syscall_frame(&mut frame, iters, true);
+        (fast / iters as u64, full / iters as u64)
+    }
+}
@@ -0,0 +1,146 @@
+//! Early fixmap: fixed virtual slots for MMIO, mapped without an allocator.
+//!
+//! The fixmap is a 2M window at [`FIXMAP_BASE`], split into statically
+//! reserved slots (see [`FixmapSlot`]). Its page tables are static, so a slot
+//! can be mapped as soon as paging is enabled, e.g. to reach a UART that the
+//! boot page table does not map, before the frame allocator is up.
+//!
+//! The tables are installed in the page table that is active on the first
+//! [`set_fixmap`]. A kernel that switches to another page table keeps the
+//! fixmap by copying the root entry of [`FIXMAP_BASE`].
+
+use axerrno::LinuxError;
+use memory_addr::{PhysAddr, VirtAddr};
+use spinbase::SpinNoIrq;
+
+use super::huge_page::{map_page, unmap_page, PageSize};
+use super::ioremap::{TableFrame, TABLE_FRAME_INIT};
+use super::page_walk::{dump_page_table_walk, PageWalkEnd};
+use super::svpbmt::MemAttr;
+use super::tlb::TlbBatch;
+use crate::mem::{phys_to_virt, virt_to_phys, MemRegionFlags, PAGE_SIZE_4K};
+
+/// The start of the fixmap window, right after the ioremap window.
+pub const FIXMAP_BASE: usize = super::IOREMAP_BASE + super::IOREMAP_SIZE;
+
+/// The number of static tables: one per level below the root (Sv57).
+const FIXMAP_TABLES: usize = 4;
+
+static mut TABLES: [TableFrame; FIXMAP_TABLES] = [TABLE_FRAME_INIT; FIXMAP_TABLES];
+
+/// The number of [`TABLES`] in use.
+static USED_TABLES: SpinNoIrq<usize> = SpinNoIrq::new(0);
+
+/// A fixmap slot.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FixmapSlot {
+    /// The early console UART, 4K.
+    EarlyConsole,
+    /// The debug UART, 4K.
+    DebugUart,
+    /// The writable alias of the kernel text being patched, 8K.
+    TextPoke,
+    /// The early interrupt controller registers (e.g. a PLIC context), 64K.
+    EarlyPlic,
+    /// The device tree blob, 1M.
+    Fdt,
+}
+
+impl FixmapSlot {
+    /// Returns the first page and the number of pages of the slot.
+    const fn pages(self) -> (usize, usize) {
+        match self {
+            Self::EarlyConsole => (0, 1),
+            Self::DebugUart => (1, 1),
+            Self::TextPoke => (2, 2),
+            Self::EarlyPlic => (16, 16),
+            Self::Fdt => (256, 256),
+        }
+    }
+
+    /// Returns the virtual address of the start of the slot.
+    pub const fn vaddr(self) -> VirtAddr {
+        VirtAddr::from(FIXMAP_BASE + self.pages().0 * PAGE_SIZE_4K)
+    }
+
+    /// Returns the size of the slot.
+    pub const fn size(self) -> usize {
+        self.pages().1 * PAGE_SIZE_4K
+    }
+}
+
+fn alloc_table() -> Option<PhysAddr> {
+    let mut used = USED_TABLES.lock();
+    if *used == FIXMAP_TABLES {
+        return None;
+    }
+    let frame = unsafe { core::ptr::addr_of!(TABLES[*used]) };
+    *used += 1;
+    Some(virt_to_phys(VirtAddr::from(frame as usize)))
+}
+
+/// Maps the `size` bytes at `paddr` in `slot`, readable and writable, with
+/// the memory type `attr`, and returns the virtual address of `paddr`.
+///
+/// The previous mapping of the slot is replaced. Returns
+/// [`LinuxError::EINVAL`] if the range does not fit in the slot.
+pub fn set_fixmap(
+    slot: FixmapSlot,
+    paddr: PhysAddr,
+    size: usize,
+    attr: MemAttr,
+) -> Result<VirtAddr, LinuxError> {
+    let start = paddr.align_down_4k();
+    let offset = paddr.as_usize() - start.as_usize();
+    let map_size = (offset + size.max(1) + PAGE_SIZE_4K - 1) & !(PAGE_SIZE_4K - 1);
+    if map_size > slot.size() {
+        return Err(LinuxError::EINVAL);
+    }
+    clear_fixmap(slot);
+    let root = super::read_page_table_root();
+    for page in (0..map_size).step_by(PAGE_SIZE_4K) {
+        let vaddr = VirtAddr::from(slot.vaddr().as_usize() + page);
+        let paddr = PhysAddr::from(start.as_usize() + page);
+        unsafe {
+            map_page(
+                root,
+                vaddr,
+                paddr,
+                PageSize::Size4K,
+                MemRegionFlags::READ | MemRegionFlags::WRITE,
+                attr,
+                &mut alloc_table,
+            )?;
+        }
+    }
+    Ok(VirtAddr::from(slot.vaddr().as_usize() + offset))
+}
+
+/// Unmaps `slot`.
+pub fn clear_fixmap(slot: FixmapSlot) {
+    let root = super::read_page_table_root();
+    let mut batch = TlbBatch::new();
+    for page in (0..slot.size()).step_by(PAGE_SIZE_4K) {
+        let vaddr = VirtAddr::from(slot.vaddr().as_usize() + page);
+        if unsafe { unmap_page(root, vaddr) }.is_ok() {
+            batch.queue(vaddr);
+        }
+    }
+}
+
+/// Returns the address to access the `size` bytes of MMIO at `paddr`: its
+/// linear mapping if the current page table maps it, otherwise `slot` mapped
+/// to it as I/O memory.
+///
+/// For the early drivers, that run before the kernel maps the MMIO regions.
+pub fn fixmap_if_unmapped(slot: FixmapSlot, paddr: PhysAddr, size: usize) -> VirtAddr {
+    let vaddr = phys_to_virt(paddr);
+    let walk = dump_page_table_walk(super::read_page_table_root(), vaddr);
+    match walk.end {
+        PageWalkEnd::Mapped { .. } | PageWalkEnd::Bare => vaddr,
+        _ => set_fixmap(slot, paddr, size, MemAttr::Io).unwrap_or_else(|err| {
+            warn!("Failed to fixmap {:#x}: {:?}", paddr, err);
+            vaddr
+        }),
+    }
+}
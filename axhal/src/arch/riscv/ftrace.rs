@@ -0,0 +1,234 @@
+//! Function entry tracing, with `-Z instrument-mcount`.
+//!
+//! Built with `-Z instrument-mcount`, each function calls `mcount` right
+//! after its prologue. `mcount` starts with a [`StaticKey`] jump, so while
+//! tracing is stopped the call costs a `nop` and a `ret`. Once started with
+//! [`ftrace_start`], each call is recorded as an [`FtraceEntry`] in the ring
+//! of the current CPU, read by [`ftrace_read`].
+//!
+//! [`ftrace_set_function`] removes the call of one function (patched to
+//! `nop`s) and puts it back. The functions called by the recording itself
+//! are traced too, their calls are dropped by a per-CPU recursion flag.
+
+use axerrno::LinuxError;
+use core::sync::atomic::AtomicU32;
+use spinbase::SpinNoIrq;
+
+use super::kprobes::{insn_len, read_insn};
+use super::single_step::{jal_offset, sign_extend};
+use super::StaticKey;
+use crate::cpu::_this_cpu_id;
+
+include_asm_marcos!();
+
+/// The number of entries in the ring of each CPU.
+pub const FTRACE_RING_LEN: usize = 4096;
+
+/// The maximum number of functions whose call is removed.
+pub const MAX_FTRACE_FILTERS: usize = 64;
+
+/// How far the call of `mcount` is searched from the start of a function.
+const FTRACE_SCAN_SIZE: usize = 64;
+
+/// A traced call.
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct FtraceEntry {
+    /// The time of the call, in ticks (see
+    /// [`ticks_to_nanos`](crate::time::ticks_to_nanos)).
+    pub ticks: u64,
+    /// The return address of the call of `mcount`, in the traced function a
+    /// few instructions after its start.
+    pub pc: usize,
+}
+
+struct FtraceRing {
+    entries: [FtraceEntry; FTRACE_RING_LEN],
+    /// The number of entries ever written.
+    written: usize,
+}
+
+const FTRACE_RING_INIT: FtraceRing = FtraceRing {
+    entries: [FtraceEntry { ticks: 0, pc: 0 }; FTRACE_RING_LEN],
+    written: 0,
+};
+
+static mut FTRACE_RINGS: [FtraceRing; axconfig::SMP] = [FTRACE_RING_INIT; axconfig::SMP];
+
+/// Whether the tracing is started.
+static FTRACE_KEY: StaticKey = StaticKey::new();
+
+/// Whether this CPU is recording a call, set by `mcount` (the `.percpu`
+/// section starts at 0, so its address is its offset from the per-CPU base
+/// in `gp`).
+#[no_mangle]
+#[link_section = ".percpu"]
+static __PERCPU_FTRACE_BUSY: AtomicU32 = AtomicU32::new(0);
+
+/// A call of `mcount` removed by [`ftrace_set_function`].
+#[derive(Clone, Copy)]
+struct RemovedCall {
+    func: usize,
+    addr: usize,
+    insns: [u32; 2],
+    len: usize,
+}
+
+static REMOVED_CALLS: SpinNoIrq<[Option<RemovedCall>; MAX_FTRACE_FILTERS]> =
+    SpinNoIrq::new([None; MAX_FTRACE_FILTERS]);
+
+extern "C" {
+    fn mcount();
+}
+
+/// Records a call returning to `pc`, with the recursion flag of this CPU set.
+extern "C" fn ftrace_record(pc: usize) {
+    let ring = unsafe { &mut *core::ptr::addr_of_mut!(FTRACE_RINGS[_this_cpu_id()]) };
+    let index = ring.written;
+    ring.entries[index % FTRACE_RING_LEN] = FtraceEntry {
+        ticks: crate::time::current_ticks(),
+        pc,
+    };
+    ring.written = index + 1;
+}
+
+// The recursion flag also drops the calls from the interrupts that come
+// during the recording, so the ring needs no lock.
+core::arch::global_asm!(
+    r"
+    .section .text
+    .balign 4
+    .global mcount
+    .global _mcount
+    mcount:
+    _mcount:
+    STATIC_JUMP {key}, 1f
+    ret
+1:
+    lui     t0, %hi({busy})
+    add     t0, t0, gp
+    lw      t1, %lo({busy})(t0)
+    bnez    t1, 2f
+    li      t1, 1
+    sw      t1, %lo({busy})(t0)
+    addi    sp, sp, -16
+    STR     ra, sp, 0
+    STR     t0, sp, 1
+    mv      a0, ra
+    call    {record}
+    LDR     t0, sp, 1
+    LDR     ra, sp, 0
+    addi    sp, sp, 16
+    sw      zero, %lo({busy})(t0)
+2:
+    ret
+    ",
+    key = sym FTRACE_KEY,
+    busy = sym __PERCPU_FTRACE_BUSY,
+    record = sym ftrace_record,
+);
+
+/// Starts recording the traced calls, on all CPUs.
+///
+/// Returns the errors of [`StaticKey::enable`].
+pub fn ftrace_start() -> Result<(), LinuxError> {
+    FTRACE_KEY.enable()
+}
+
+/// Stops recording the traced calls, on all CPUs.
+///
+/// Returns the errors of [`StaticKey::disable`].
+pub fn ftrace_stop() -> Result<(), LinuxError> {
+    FTRACE_KEY.disable()
+}
+
+/// Copies the last entries of the ring of the CPU `cpu_id` to `out`, the
+/// oldest first, and returns their number.
+///
+/// The tracing should be stopped, or the CPU may overwrite the entries
+/// being read.
+pub fn ftrace_read(cpu_id: usize, out: &mut [FtraceEntry]) -> usize {
+    let ring = unsafe { &*core::ptr::addr_of!(FTRACE_RINGS[cpu_id]) };
+    let written = unsafe { core::ptr::read_volatile(&ring.written) };
+    let count = written.min(FTRACE_RING_LEN).min(out.len());
+    for (i, entry) in out[..count].iter_mut().enumerate() {
+        *entry = ring.entries[(written - count + i) % FTRACE_RING_LEN];
+    }
+    count
+}
+
+/// Empties the ring of the CPU `cpu_id`.
+pub fn ftrace_reset(cpu_id: usize) {
+    unsafe { core::ptr::addr_of_mut!(FTRACE_RINGS[cpu_id].written).write_volatile(0) };
+}
+
+/// Returns the address and the length of the call of `mcount` in the
+/// function at `func`: a `jal ra`, or an `auipc ra` and a `jalr ra`.
+fn find_mcount_call(func: usize) -> Option<(usize, usize)> {
+    let target = mcount as usize;
+    let mut addr = func;
+    while addr < func + FTRACE_SCAN_SIZE {
+        let insn = read_insn(addr)?;
+        // jal ra, offset
+        if insn & 0xfff == 0x0ef && addr.wrapping_add(jal_offset(insn)) == target {
+            return Some((addr, 4));
+        }
+        // auipc ra, hi; jalr ra, lo(ra)
+        if insn & 0xfff == 0x097 {
+            let next = read_insn(addr + 4)?;
+            let hi = sign_extend(insn & 0xffff_f000, 32);
+            let lo = sign_extend(next >> 20, 12);
+            if next & 0xf_ffff == 0x0_80e7 && addr.wrapping_add(hi).wrapping_add(lo) == target {
+                return Some((addr, 8));
+            }
+        }
+        addr += insn_len(insn);
+    }
+    None
+}
+
+/// Removes (`traced` false) or puts back the call of `mcount` of the
+/// function at `func`, on all CPUs.
+///
+/// Returns [`LinuxError::ENOENT`] if the function has no call of `mcount`
+/// (e.g. it is not instrumented), [`LinuxError::ENOSPC`] if the calls of
+/// [`MAX_FTRACE_FILTERS`] functions are already removed, or the errors of
+/// [`patch_text`](super::patch_text).
+pub fn ftrace_set_function(func: usize, traced: bool) -> Result<(), LinuxError> {
+    let mut removed = REMOVED_CALLS.lock();
+    let index = removed
+        .iter()
+        .position(|call| matches!(call, Some(call) if call.func == func));
+    match (index, traced) {
+        (Some(index), true) => {
+            let call = removed[index].unwrap();
+            let insns = call.insns[..call.len / 4].iter().enumerate();
+            super::text_patch::patch_insns(insns.map(|(i, &insn)| (call.addr + i * 4, insn)))?;
+            removed[index] = None;
+            Ok(())
+        }
+        (None, false) => {
+            let (addr, len) = find_mcount_call(func).ok_or(LinuxError::ENOENT)?;
+            let slot = removed
+                .iter_mut()
+                .find(|call| call.is_none())
+                .ok_or(LinuxError::ENOSPC)?;
+            let insns = [
+                read_insn(addr).unwrap_or(0),
+                read_insn(addr + 4).unwrap_or(0),
+            ];
+            // `addi x0, x0, 0`
+            let nops = (0..len / 4).map(|i| (addr + i * 4, 0x0000_0013));
+            super::text_patch::patch_insns(nops)?;
+            *slot = Some(RemovedCall {
+                func,
+                addr,
+                insns,
+                len,
+            });
+            Ok(())
+        }
+        (None, true) => find_mcount_call(func).map(|_| ()).ok_or(LinuxError::ENOENT),
+        (Some(_), false) => Ok(()),
+    }
+}
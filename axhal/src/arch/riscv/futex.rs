@@ -0,0 +1,150 @@
+//! Atomic operations on user memory, for futexes.
+//!
+//! The user accesses have `__ex_table` entries, so a fault on `uaddr` makes
+//! them return [`LinuxError::EFAULT`] instead of panicking.
+
+use axerrno::LinuxError;
+
+use super::{access_ok, untagged_addr};
+
+/// The operation of `FUTEX_WAKE_OP`, as encoded in the `op` field.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u32)]
+pub enum FutexOp {
+    /// `*uaddr = oparg`
+    Set = 0,
+    /// `*uaddr += oparg`
+    Add = 1,
+    /// `*uaddr |= oparg`
+    Or = 2,
+    /// `*uaddr &= !oparg`
+    AndNot = 3,
+    /// `*uaddr ^= oparg`
+    Xor = 4,
+}
+
+impl FutexOp {
+    /// Converts the raw `FUTEX_OP_*` value.
+    pub const fn from_raw(op: u32) -> Option<Self> {
+        Some(match op {
+            0 => Self::Set,
+            1 => Self::Add,
+            2 => Self::Or,
+            3 => Self::AndNot,
+            4 => Self::Xor,
+            _ => return None,
+        })
+    }
+}
+
+/// Checks the user address of a futex word, and returns it with the pointer
+/// tag removed (see [`untagged_addr`]), as it is accessed.
+fn check_futex_addr(uaddr: usize) -> Result<usize, LinuxError> {
+    let uaddr = untagged_addr(uaddr);
+    if uaddr % 4 != 0 {
+        return Err(LinuxError::EINVAL);
+    }
+    if !access_ok(uaddr, 4) {
+        return Err(LinuxError::EFAULT);
+    }
+    Ok(uaddr)
+}
+
+/// Atomically sets the `u32` at the user address `uaddr` to `newval` if it
+/// equals `oldval`, with an LR/SC sequence.
+///
+/// Returns the value read from `uaddr`, i.e. it has succeeded if the result
+/// is `oldval`.
+pub fn futex_atomic_cmpxchg_inuser(
+    uaddr: usize,
+    oldval: u32,
+    newval: u32,
+) -> Result<u32, LinuxError> {
+    let uaddr = check_futex_addr(uaddr)?;
+    let mut err: usize = 0;
+    let val: usize;
+    unsafe {
+        core::arch::asm!(
+            "1:",
+            "   lr.w.aqrl {val}, ({uaddr})",
+            "   bne {val}, {old}, 3f",
+            "2:",
+            "   sc.w.aqrl {tmp}, {new}, ({uaddr})",
+            "   bnez {tmp}, 1b",
+            "3:",
+            "   .section .fixup,\"ax\"",
+            "   .balign 4",
+            "4:",
+            "   li {err}, {err_val}",
+            "   jump 3b, {tmp}",
+            "   .previous",
+            "   .section __ex_table,\"a\"",
+            "   .balign 8",
+            "   .dword 1b, 4b",
+            "   .dword 2b, 4b",
+            "   .previous",
+            err = inout(reg) err,
+            val = out(reg) val,
+            tmp = out(reg) _,
+            uaddr = in(reg) uaddr,
+            // `lr.w` sign-extends, so must the compared value.
+            old = in(reg) oldval as i32 as isize,
+            new = in(reg) newval,
+            err_val = const (-(LinuxError::EFAULT as isize)),
+        );
+    }
+    if err != 0 {
+        return Err(LinuxError::EFAULT);
+    }
+    Ok(val as u32)
+}
+
+/// Does an atomic `op` with `oparg` on the `u32` at the user address
+/// `uaddr`, i.e. the first half of `FUTEX_WAKE_OP`.
+///
+/// Returns the previous value, for the caller to evaluate the comparison.
+pub fn futex_atomic_op_inuser(op: FutexOp, oparg: u32, uaddr: usize) -> Result<u32, LinuxError> {
+    let uaddr = check_futex_addr(uaddr)?;
+    macro_rules! amo {
+        ($inst:literal, $arg:expr) => {{
+            let mut err: usize = 0;
+            let old: usize;
+            unsafe {
+                core::arch::asm!(
+                    "1:",
+                    concat!("   ", $inst, " {old}, {arg}, ({uaddr})"),
+                    "2:",
+                    "   .section .fixup,\"ax\"",
+                    "   .balign 4",
+                    "3:",
+                    "   li {err}, {err_val}",
+                    "   jump 2b, {tmp}",
+                    "   .previous",
+                    "   .section __ex_table,\"a\"",
+                    "   .balign 8",
+                    "   .dword 1b, 3b",
+                    "   .previous",
+                    err = inout(reg) err,
+                    old = out(reg) old,
+                    tmp = out(reg) _,
+                    uaddr = in(reg) uaddr,
+                    arg = in(reg) $arg,
+                    err_val = const (-(LinuxError::EFAULT as isize)),
+                );
+            }
+            (old, err)
+        }};
+    }
+
+    let (old, err) = match op {
+        FutexOp::Set => amo!("amoswap.w.aqrl", oparg),
+        FutexOp::Add => amo!("amoadd.w.aqrl", oparg),
+        FutexOp::Or => amo!("amoor.w.aqrl", oparg),
+        FutexOp::AndNot => amo!("amoand.w.aqrl", !oparg),
+        FutexOp::Xor => amo!("amoxor.w.aqrl", oparg),
+    };
+    if err != 0 {
+        return Err(LinuxError::EFAULT);
+    }
+    Ok(old as u32)
+}
@@ -0,0 +1,586 @@
+//! A GDB stub, speaking the GDB remote serial protocol over a dedicated UART.
+//!
+//! [`init_gdbstub`] probes the UART (not the console one) and arms the stub.
+//! From then on, the kernel breakpoints stop in the stub: the software
+//! breakpoints inserted by GDB, the steps of `stepi`, the hardware
+//! breakpoints and watchpoints, and [`gdb_breakpoint`] (e.g. called by the
+//! panic handler). The other ones ([`bug!`](crate::bug), stack smashing)
+//! stop in the stub too, and the panic follows when GDB continues.
+//!
+//! The stub polls the UART with the interrupts disabled, on the CPU that
+//! stopped; the other CPUs keep running, and the hardware breakpoints are
+//! only installed on the stopped CPU. The registers are `x0` to `x31` and
+//! `pc` of the trap frame. The memory accesses have fault fixup, so a bad
+//! address makes the command fail. The software breakpoints and the
+//! single-stepping write the kernel text with
+//! [`patch_text`](super::patch_text), as it is read-only.
+
+use core::sync::atomic::{AtomicBool, Ordering};
+use lazy_init::LazyInit;
+use memory_addr::VirtAddr;
+use spinbase::SpinNoIrq;
+
+use super::misaligned::{read_gpr, write_gpr};
+use super::{HwBreakpoint, HwBreakpointKind, TrapFrame, MAX_HW_BREAKPOINTS};
+use crate::platform::uart::ConsoleDevice;
+
+/// The value of `t0` at the `ebreak` of [`gdb_breakpoint`].
+pub const GDB_MAGIC: usize = 0x4744_425f_4744_425f; // "GDB_GDB_"
+
+/// The maximum size of the data of a packet.
+const MAX_PACKET_SIZE: usize = 1024;
+
+/// The maximum number of software breakpoints.
+const MAX_SW_BREAKPOINTS: usize = 32;
+
+/// The registers of the `g` packet: `x0` to `x31`, then `pc`.
+const NUM_REGS: usize = 33;
+const REG_SIZE: usize = core::mem::size_of::<usize>();
+
+const SIGTRAP: u8 = 5;
+
+const EBREAK: u32 = 0x0010_0073;
+const C_EBREAK: u32 = 0x9002;
+
+const HEX_DIGITS: &[u8; 16] = b"0123456789abcdef";
+
+static GDB_UART: LazyInit<&'static dyn ConsoleDevice> = LazyInit::new();
+
+/// Whether the last resume was a `s` (step) packet.
+static STEPPING: AtomicBool = AtomicBool::new(false);
+
+#[derive(Clone, Copy)]
+struct SwBreakpoint {
+    addr: usize,
+    /// 2 for `c.ebreak`, 4 for `ebreak`.
+    len: usize,
+    /// The original bytes at `addr`.
+    orig: [u8; 4],
+}
+
+/// Why the CPU has stopped, for the stop reply.
+#[derive(Clone, Copy)]
+enum StopReason {
+    Trap,
+    Watch(HwBreakpointKind, usize),
+}
+
+/// How the stopped CPU goes on after a packet.
+enum Resume {
+    /// Wait for the next packet.
+    Stay,
+    Continue,
+    Step,
+}
+
+/// The reply being built.
+struct Reply {
+    buf: [u8; MAX_PACKET_SIZE],
+    len: usize,
+}
+
+impl Reply {
+    /// Appends `bytes`, truncated to the maximum packet size.
+    fn push(&mut self, bytes: &[u8]) {
+        let n = bytes.len().min(MAX_PACKET_SIZE - self.len);
+        self.buf[self.len..self.len + n].copy_from_slice(&bytes[..n]);
+        self.len += n;
+    }
+
+    /// Appends `bytes` encoded in hex, two digits per byte.
+    fn push_hex(&mut self, bytes: &[u8]) {
+        for &b in bytes {
+            self.push(&[
+                HEX_DIGITS[(b >> 4) as usize],
+                HEX_DIGITS[(b & 0xf) as usize],
+            ]);
+        }
+    }
+
+    /// Appends `OK`, or an error if not `done`.
+    fn push_result(&mut self, done: bool) {
+        self.push(if done { b"OK" } else { b"E14" });
+    }
+
+    /// Appends `value` as a hex number.
+    fn push_number(&mut self, value: usize) {
+        let bytes = value.to_be_bytes();
+        let first = bytes
+            .iter()
+            .position(|&b| b != 0)
+            .unwrap_or(bytes.len() - 1);
+        self.push_hex(&bytes[first..]);
+    }
+}
+
+struct GdbState {
+    /// Whether GDB has sent a packet, and not detached: the stop replies are
+    /// only sent then.
+    connected: bool,
+    sw_breakpoints: [Option<SwBreakpoint>; MAX_SW_BREAKPOINTS],
+    hw_breakpoints: [Option<HwBreakpoint>; MAX_HW_BREAKPOINTS],
+    packet: [u8; MAX_PACKET_SIZE],
+    reply: Reply,
+}
+
+static GDB: SpinNoIrq<GdbState> = SpinNoIrq::new(GdbState {
+    connected: false,
+    sw_breakpoints: [None; MAX_SW_BREAKPOINTS],
+    hw_breakpoints: [None; MAX_HW_BREAKPOINTS],
+    packet: [0; MAX_PACKET_SIZE],
+    reply: Reply {
+        buf: [0; MAX_PACKET_SIZE],
+        len: 0,
+    },
+});
+
+fn getc() -> u8 {
+    loop {
+        if let Some(c) = GDB_UART.getchar() {
+            return c;
+        }
+        core::hint::spin_loop();
+    }
+}
+
+fn hex_digit(c: u8) -> Option<u8> {
+    match c {
+        b'0'..=b'9' => Some(c - b'0'),
+        b'a'..=b'f' => Some(c - b'a' + 10),
+        b'A'..=b'F' => Some(c - b'A' + 10),
+        _ => None,
+    }
+}
+
+/// Parses a hex number.
+fn parse_number(s: &[u8]) -> Option<usize> {
+    if s.is_empty() || s.len() > 2 * REG_SIZE {
+        return None;
+    }
+    s.iter()
+        .try_fold(0, |acc, &c| Some((acc << 4) | hex_digit(c)? as usize))
+}
+
+/// Decodes the hex bytes `s` into `out`, which must be as long.
+fn decode_hex(s: &[u8], out: &mut [u8]) -> Option<()> {
+    if s.len() != 2 * out.len() {
+        return None;
+    }
+    for (b, pair) in out.iter_mut().zip(s.chunks_exact(2)) {
+        *b = (hex_digit(pair[0])? << 4) | hex_digit(pair[1])?;
+    }
+    Some(())
+}
+
+/// Decodes a register value, in the target byte order.
+fn decode_reg(s: &[u8]) -> Option<usize> {
+    let mut bytes = [0; REG_SIZE];
+    decode_hex(s, &mut bytes)?;
+    Some(usize::from_le_bytes(bytes))
+}
+
+fn split_at_byte(s: &[u8], sep: u8) -> Option<(&[u8], &[u8])> {
+    let i = s.iter().position(|&c| c == sep)?;
+    Some((&s[..i], &s[i + 1..]))
+}
+
+/// Parses `addr,len`.
+fn parse_range(s: &[u8]) -> Option<(usize, usize)> {
+    let (addr, len) = split_at_byte(s, b',')?;
+    Some((parse_number(addr)?, parse_number(len)?))
+}
+
+/// Waits for a packet with a valid checksum, acknowledges it, and returns
+/// the length of its data in `buf`.
+fn read_packet(buf: &mut [u8]) -> usize {
+    loop {
+        while getc() != b'$' {}
+        let mut len = 0;
+        let mut sum = 0u8;
+        let mut overflow = false;
+        loop {
+            let c = getc();
+            if c == b'#' {
+                break;
+            }
+            sum = sum.wrapping_add(c);
+            match buf.get_mut(len) {
+                Some(b) => *b = c,
+                None => overflow = true,
+            }
+            len += 1;
+        }
+        let checksum = hex_digit(getc()).zip(hex_digit(getc()));
+        if !overflow && checksum == Some((sum >> 4, sum & 0xf)) {
+            GDB_UART.putchar(b'+');
+            return len;
+        }
+        GDB_UART.putchar(b'-');
+    }
+}
+
+/// Sends a packet, until GDB acknowledges it.
+fn write_packet(data: &[u8]) {
+    let sum = data.iter().fold(0u8, |sum, &c| sum.wrapping_add(c));
+    let trailer = [
+        b'#',
+        HEX_DIGITS[(sum >> 4) as usize],
+        HEX_DIGITS[(sum & 0xf) as usize],
+    ];
+    loop {
+        GDB_UART.putchar(b'$');
+        data.iter()
+            .chain(&trailer)
+            .for_each(|&c| GDB_UART.putchar(c));
+        loop {
+            match getc() {
+                b'+' => return,
+                b'-' => break,
+                _ => {}
+            }
+        }
+    }
+}
+
+fn stop_reply(reply: &mut Reply, reason: StopReason) {
+    reply.push(b"T");
+    reply.push_hex(&[SIGTRAP]);
+    if let StopReason::Watch(kind, addr) = reason {
+        reply.push(match kind {
+            HwBreakpointKind::Read => b"rwatch:",
+            HwBreakpointKind::ReadWrite => b"awatch:",
+            _ => b"watch:",
+        });
+        reply.push_number(addr);
+        reply.push(b";");
+    }
+}
+
+fn read_reg(tf: &mut TrapFrame, reg: usize) -> Option<usize> {
+    match reg {
+        0..=31 => Some(read_gpr(tf, reg)),
+        32 => Some(tf.sepc),
+        _ => None,
+    }
+}
+
+fn write_reg(tf: &mut TrapFrame, reg: usize, value: usize) -> bool {
+    match reg {
+        0..=31 => write_gpr(tf, reg, value),
+        32 => tf.sepc = value,
+        _ => return false,
+    }
+    true
+}
+
+fn read_memory(reply: &mut Reply, addr: usize, len: usize) -> bool {
+    let mut buf = [0; 64];
+    let len = len.min((MAX_PACKET_SIZE - reply.len) / 2);
+    for offset in (0..len).step_by(buf.len()) {
+        let chunk = &mut buf[..(len - offset).min(64)];
+        if super::copy_from_kernel_nofault(chunk, addr + offset) != 0 {
+            return false;
+        }
+        reply.push_hex(chunk);
+    }
+    true
+}
+
+/// Writes `bytes` at the kernel address `addr`, with
+/// [`patch_text`](super::patch_text) in `.text`, and with fault fixup
+/// elsewhere. Returns whether it is written.
+fn write_kernel(addr: usize, bytes: &[u8]) -> bool {
+    let text = &super::kernel_sections()[0].range;
+    let end = addr.wrapping_add(bytes.len());
+    if text.start.as_usize() <= addr && end <= text.end.as_usize() {
+        super::patch_text(addr, bytes).is_ok()
+    } else {
+        super::copy_to_kernel_nofault(addr, bytes) == 0
+    }
+}
+
+fn write_memory(addr: usize, data: &[u8]) -> bool {
+    let mut buf = [0; 64];
+    for (i, hex) in data.chunks(2 * buf.len()).enumerate() {
+        let chunk = &mut buf[..hex.len() / 2];
+        if decode_hex(hex, chunk).is_none() || !write_kernel(addr + i * 64, chunk) {
+            return false;
+        }
+    }
+    // The text may have been patched.
+    super::local_flush_icache_all();
+    true
+}
+
+fn insert_sw_breakpoint(state: &mut GdbState, addr: usize, len: usize) -> bool {
+    if state
+        .sw_breakpoints
+        .iter()
+        .flatten()
+        .any(|bp| bp.addr == addr)
+    {
+        return true;
+    }
+    let insn = match len {
+        2 => C_EBREAK,
+        4 => EBREAK,
+        _ => return false,
+    };
+    let Some(slot) = state.sw_breakpoints.iter_mut().find(|slot| slot.is_none()) else {
+        return false;
+    };
+    let mut orig = [0; 4];
+    if super::copy_from_kernel_nofault(&mut orig[..len], addr) != 0
+        || !write_kernel(addr, &insn.to_le_bytes()[..len])
+    {
+        return false;
+    }
+    *slot = Some(SwBreakpoint { addr, len, orig });
+    true
+}
+
+fn remove_sw_breakpoint(state: &mut GdbState, addr: usize) -> bool {
+    let Some(slot) = state
+        .sw_breakpoints
+        .iter_mut()
+        .find(|slot| slot.is_some_and(|bp| bp.addr == addr))
+    else {
+        return false;
+    };
+    let bp = slot.take().unwrap();
+    write_kernel(bp.addr, &bp.orig[..bp.len])
+}
+
+/// Handles a `Z` (`insert`) or `z` packet of type 1 to 4: the hardware
+/// breakpoints and watchpoints. Returns [`None`] if they are not supported.
+fn set_hw_breakpoint(state: &mut GdbState, ty: u8, addr: usize, insert: bool) -> Option<bool> {
+    let kind = match ty {
+        b'1' => HwBreakpointKind::Execute,
+        b'2' => HwBreakpointKind::Write,
+        b'3' => HwBreakpointKind::Read,
+        b'4' => HwBreakpointKind::ReadWrite,
+        _ => return None,
+    };
+    if !super::has_hw_breakpoints() {
+        return None;
+    }
+    let slot = state
+        .hw_breakpoints
+        .iter_mut()
+        .find(|slot| slot.is_some_and(|bp| bp.addr == addr && bp.kind == kind));
+    Some(match (insert, slot) {
+        (true, Some(_)) => true,
+        (true, None) => match super::install_hw_breakpoint(VirtAddr::from(addr), kind, true) {
+            Ok(bp) => {
+                state.hw_breakpoints[bp.slot] = Some(bp);
+                true
+            }
+            Err(_) => false,
+        },
+        (false, Some(slot)) => {
+            let bp = slot.take().unwrap();
+            super::uninstall_hw_breakpoint(bp.slot).is_ok()
+        }
+        (false, None) => false,
+    })
+}
+
+/// Handles the packet in `state.packet[..len]`, with the reply (if any) in
+/// `state.reply`.
+fn handle_packet(
+    state: &mut GdbState,
+    tf: &mut TrapFrame,
+    len: usize,
+    reason: StopReason,
+) -> Resume {
+    // A copy, as the breakpoint commands need the state.
+    let packet = state.packet;
+    let Some((&cmd, args)) = packet[..len.min(MAX_PACKET_SIZE)].split_first() else {
+        return Resume::Stay;
+    };
+    match cmd {
+        b'?' => stop_reply(&mut state.reply, reason),
+        b'g' => {
+            for reg in 0..NUM_REGS {
+                let value = read_reg(tf, reg).unwrap();
+                state.reply.push_hex(&value.to_le_bytes());
+            }
+        }
+        b'G' if args.len() >= NUM_REGS * 2 * REG_SIZE => {
+            let values = args.chunks_exact(2 * REG_SIZE).take(NUM_REGS);
+            let valid = values.clone().all(|hex| decode_reg(hex).is_some());
+            if valid {
+                for (reg, hex) in values.enumerate() {
+                    write_reg(tf, reg, decode_reg(hex).unwrap());
+                }
+            }
+            state.reply.push_result(valid);
+        }
+        b'p' => match parse_number(args).and_then(|reg| read_reg(tf, reg)) {
+            Some(value) => state.reply.push_hex(&value.to_le_bytes()),
+            None => state.reply.push(b"E22"),
+        },
+        b'P' => {
+            let done = split_at_byte(args, b'=')
+                .and_then(|(reg, value)| Some((parse_number(reg)?, decode_reg(value)?)))
+                .is_some_and(|(reg, value)| write_reg(tf, reg, value));
+            state.reply.push_result(done);
+        }
+        b'm' => {
+            let mark = state.reply.len;
+            let done = parse_range(args)
+                .is_some_and(|(addr, len)| read_memory(&mut state.reply, addr, len));
+            if !done {
+                state.reply.len = mark;
+                state.reply.push(b"E14");
+            }
+        }
+        b'M' => {
+            let done = split_at_byte(args, b':')
+                .and_then(|(range, data)| Some((parse_range(range)?, data)))
+                .is_some_and(|((addr, len), data)| {
+                    data.len() == 2 * len && write_memory(addr, data)
+                });
+            state.reply.push_result(done);
+        }
+        b'c' | b's' => {
+            if let Some(addr) = parse_number(args) {
+                tf.sepc = addr;
+            }
+            if cmd == b'c' {
+                return Resume::Continue;
+            }
+            return Resume::Step;
+        }
+        b'Z' | b'z' => {
+            let insert = cmd == b'Z';
+            let parsed = split_at_byte(args, b',')
+                .and_then(|(ty, range)| Some((*ty.first()?, parse_range(range)?)));
+            let done = match parsed {
+                Some((b'0', (addr, len))) if insert => Some(insert_sw_breakpoint(state, addr, len)),
+                Some((b'0', (addr, _))) => Some(remove_sw_breakpoint(state, addr)),
+                Some((ty, (addr, _))) => set_hw_breakpoint(state, ty, addr, insert),
+                None => Some(false),
+            };
+            // An empty reply: not supported.
+            if let Some(done) = done {
+                state.reply.push_result(done);
+            }
+        }
+        b'D' => {
+            state.connected = false;
+            write_packet(b"OK");
+            return Resume::Continue;
+        }
+        b'k' => {
+            state.connected = false;
+            return Resume::Continue;
+        }
+        b'H' | b'T' => state.reply.push(b"OK"),
+        b'q' if args.starts_with(b"Supported") => {
+            state.reply.push(b"PacketSize=");
+            state.reply.push_number(MAX_PACKET_SIZE);
+        }
+        b'q' if args == b"Attached" => state.reply.push(b"1"),
+        _ => {}
+    }
+    Resume::Stay
+}
+
+/// Stops in the stub until GDB resumes the CPU.
+fn gdb_session(tf: &mut TrapFrame, reason: StopReason) {
+    let mut state = GDB.lock();
+    if state.connected {
+        state.reply.len = 0;
+        stop_reply(&mut state.reply, reason);
+        write_packet(&state.reply.buf[..state.reply.len]);
+    }
+    loop {
+        let len = read_packet(&mut state.packet);
+        state.connected = true;
+        state.reply.len = 0;
+        match handle_packet(&mut state, tf, len, reason) {
+            Resume::Stay => write_packet(&state.reply.buf[..state.reply.len]),
+            Resume::Continue => return,
+            Resume::Step => match super::set_single_step(tf, true) {
+                Ok(()) => {
+                    STEPPING.store(true, Ordering::Release);
+                    return;
+                }
+                Err(_) => write_packet(b"E14"),
+            },
+        }
+    }
+}
+
+fn gdb_hw_breakpoint(tf: &mut TrapFrame, bp: &HwBreakpoint) {
+    let reason = match bp.kind {
+        HwBreakpointKind::Execute => StopReason::Trap,
+        kind => StopReason::Watch(kind, bp.addr),
+    };
+    gdb_session(tf, reason);
+}
+
+/// Arms the GDB stub on the UART at the device tree `path` (e.g.
+/// `/soc/serial@10001000`), which must not be the console UART. Returns
+/// `false` if it has no driver.
+///
+/// It registers the hardware breakpoint handler, see
+/// [`set_hw_breakpoint_handler`](super::set_hw_breakpoint_handler). GDB can
+/// attach at the next stop, e.g. at [`gdb_breakpoint`].
+pub fn init_gdbstub(path: &str) -> bool {
+    let Some(uart) = crate::platform::uart::probe_debug_uart(path) else {
+        return false;
+    };
+    if !GDB_UART.is_init() {
+        GDB_UART.init_by(uart);
+    }
+    super::set_hw_breakpoint_handler(gdb_hw_breakpoint);
+    true
+}
+
+/// Stops in the GDB stub, if it is armed, e.g. from the panic handler or to
+/// wait for GDB at boot. It returns when GDB continues.
+pub fn gdb_breakpoint() {
+    unsafe { core::arch::asm!("ebreak", in("t0") GDB_MAGIC, options(nostack)) }
+}
+
+/// Handles a kernel breakpoint in the GDB stub, once it is armed; `stepped`
+/// tells if it was a temporary breakpoint of
+/// [`set_single_step`](super::set_single_step).
+///
+/// Returns whether the breakpoint was for the stub and the CPU can resume,
+/// `false` for the other steps, and for the breakpoints that should be
+/// reported after GDB continues (e.g. [`bug!`](crate::bug)).
+pub(super) fn handle_gdb_breakpoint(tf: &mut TrapFrame, stepped: bool) -> bool {
+    if !GDB_UART.is_init() {
+        return false;
+    }
+    if stepped {
+        if !STEPPING.swap(false, Ordering::AcqRel) {
+            return false;
+        }
+        gdb_session(tf, StopReason::Trap);
+        return true;
+    }
+    let inserted = GDB
+        .lock()
+        .sw_breakpoints
+        .iter()
+        .flatten()
+        .any(|bp| bp.addr == tf.sepc);
+    if inserted {
+        gdb_session(tf, StopReason::Trap);
+        return true;
+    }
+    if tf.regs.t0 == GDB_MAGIC {
+        tf.sepc += match super::fetch_instruction(tf.sepc, false) {
+            Some(insn) if insn & 0b11 != 0b11 => 2,
+            _ => 4,
+        };
+        gdb_session(tf, StopReason::Trap);
+        return true;
+    }
+    gdb_session(tf, StopReason::Trap);
+    false
+}
@@ -0,0 +1,399 @@
+//! The G-stage (second-stage) translation of the guests: guest physical
+//! addresses to host physical addresses.
+//!
+//! The G-stage tables are the Sv39/Sv48/Sv57 ones with a root 4 times
+//! larger (2 more bits of address), at [`GSTAGE_ROOT_SIZE`]. Their leaf
+//! entries all have `U`, as the G-stage accesses are checked as U-mode
+//! ones. The changes are not flushed by the helpers, the caller flushes
+//! them with [`hfence_gvma`] on all the harts that run the guest.
+
+use axerrno::LinuxError;
+use memory_addr::PhysAddr;
+
+use super::huge_page::{is_leaf, leaf_flags, pte_paddr, table_entries, table_pte, PageSize};
+use super::hypervisor::{GuestTrap, CSR_HGATP, HSTATUS_GVA};
+use super::page_walk::{PTE_PPN_SHIFT, PTE_U, PTE_V};
+use super::svpbmt::MemAttr;
+use crate::mem::{phys_to_virt, MemRegionFlags, PAGE_SIZE_4K};
+
+/// The size and alignment of the root table of the G-stage (x4), 16K.
+pub const GSTAGE_ROOT_SIZE: usize = 4 * PAGE_SIZE_4K;
+
+/// The number of bits of the VMID in `hgatp`.
+const HGATP_VMID_BITS: usize = 14;
+
+/// The translation mode of the G-stage, with a 4x larger root table.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum GStageMode {
+    /// 41-bit guest physical addresses, 3 levels.
+    Sv39x4 = 8,
+    /// 50-bit guest physical addresses, 4 levels.
+    Sv48x4 = 9,
+    /// 59-bit guest physical addresses, 5 levels.
+    Sv57x4 = 10,
+}
+
+fn read_hgatp() -> usize {
+    let value: usize;
+    unsafe { core::arch::asm!("csrr {}, {csr}", out(reg) value, csr = const CSR_HGATP) };
+    value
+}
+
+fn write_hgatp(value: usize) {
+    unsafe { core::arch::asm!("csrw {csr}, {}", in(reg) value, csr = const CSR_HGATP) };
+}
+
+impl GStageMode {
+    /// Returns the number of levels.
+    pub const fn levels(self) -> usize {
+        self as usize - 5
+    }
+
+    /// Returns the number of bits of the guest physical addresses.
+    pub const fn gpa_bits(self) -> usize {
+        12 + 9 * self.levels() + 2
+    }
+
+    /// Returns the largest mode that the current hart supports, by writing
+    /// them to `hgatp`, which is left at 0.
+    pub fn probe() -> Option<Self> {
+        let supported = [Self::Sv57x4, Self::Sv48x4, Self::Sv39x4]
+            .into_iter()
+            .find(|&mode| {
+                write_hgatp((mode as usize) << 60);
+                read_hgatp() >> 60 == mode as usize
+            });
+        write_hgatp(0);
+        hfence_gvma_all();
+        supported
+    }
+}
+
+/// Sets the G-stage of the current hart to the table at `root` (aligned to
+/// [`GSTAGE_ROOT_SIZE`]) in `mode`, for the guest `vmid`, and flushes its
+/// translations.
+///
+/// Returns [`LinuxError::EINVAL`] if `root` is not aligned or `vmid` does
+/// not fit.
+pub fn set_hgatp(mode: GStageMode, vmid: usize, root: PhysAddr) -> Result<(), LinuxError> {
+    if root.as_usize() % GSTAGE_ROOT_SIZE != 0 || vmid >> HGATP_VMID_BITS != 0 {
+        return Err(LinuxError::EINVAL);
+    }
+    write_hgatp((mode as usize) << 60 | vmid << 44 | root.as_usize() >> 12);
+    hfence_gvma(None, Some(vmid));
+    Ok(())
+}
+
+/// Flushes the G-stage translations of all guests on the current hart.
+pub fn hfence_gvma_all() {
+    hfence_gvma(None, None);
+}
+
+/// Flushes the G-stage translations on the current hart of the guest
+/// physical address `gpa` (or all of them), for the guest `vmid` (or all
+/// the guests).
+pub fn hfence_gvma(gpa: Option<usize>, vmid: Option<usize>) {
+    // hfence.gvma rs1, rs2, with rs1 the address shifted right by 2.
+    unsafe {
+        match (gpa, vmid) {
+            (None, None) => core::arch::asm!(".insn r 0x73, 0, 0x31, zero, zero, zero"),
+            (Some(gpa), None) => {
+                core::arch::asm!(".insn r 0x73, 0, 0x31, zero, {}, zero", in(reg) gpa >> 2)
+            }
+            (None, Some(vmid)) => {
+                core::arch::asm!(".insn r 0x73, 0, 0x31, zero, zero, {}", in(reg) vmid)
+            }
+            (Some(gpa), Some(vmid)) => core::arch::asm!(
+                ".insn r 0x73, 0, 0x31, zero, {}, {}",
+                in(reg) gpa >> 2,
+                in(reg) vmid,
+            ),
+        }
+    }
+}
+
+/// Flushes the VS-stage translations of the current guest on the current
+/// hart, e.g. after switching vCPUs with the same VMID.
+pub fn hfence_vvma_all() {
+    hfence_vvma(None, None);
+}
+
+/// Flushes the VS-stage translations of the current guest (the VMID of
+/// `hgatp`) on the current hart, of the guest virtual address `gva` (or all
+/// of them), for the guest address space `asid` (or all of them).
+pub fn hfence_vvma(gva: Option<usize>, asid: Option<usize>) {
+    // hfence.vvma rs1, rs2
+    unsafe {
+        match (gva, asid) {
+            (None, None) => core::arch::asm!(".insn r 0x73, 0, 0x11, zero, zero, zero"),
+            (Some(gva), None) => {
+                core::arch::asm!(".insn r 0x73, 0, 0x11, zero, {}, zero", in(reg) gva)
+            }
+            (None, Some(asid)) => {
+                core::arch::asm!(".insn r 0x73, 0, 0x11, zero, zero, {}", in(reg) asid)
+            }
+            (Some(gva), Some(asid)) => core::arch::asm!(
+                ".insn r 0x73, 0, 0x11, zero, {}, {}",
+                in(reg) gva,
+                in(reg) asid,
+            ),
+        }
+    }
+}
+
+/// Returns the entry at `level` that maps `gpa`, walking down from `root`.
+///
+/// The missing tables above `level` are allocated with `alloc_frame` if it
+/// is given, otherwise [`LinuxError::EFAULT`] is returned. A superpage above
+/// `level` gives [`LinuxError::EEXIST`].
+unsafe fn gstage_entry(
+    root: PhysAddr,
+    mode: GStageMode,
+    gpa: usize,
+    level: usize,
+    mut alloc_frame: Option<&mut dyn FnMut() -> Option<PhysAddr>>,
+) -> Result<&'static mut usize, LinuxError> {
+    let levels = mode.levels();
+    if level >= levels || gpa >> mode.gpa_bits() != 0 {
+        return Err(LinuxError::EINVAL);
+    }
+    let mut table = root;
+    for l in (level..levels).rev() {
+        // The root has 2 more index bits, over 4 pages.
+        let bits = if l == levels - 1 { 11 } else { 9 };
+        let index = (gpa >> (12 + 9 * l)) & ((1 << bits) - 1);
+        let pte = &mut *phys_to_virt(table).as_mut_ptr().cast::<usize>().add(index);
+        if l == level {
+            return Ok(pte);
+        }
+        if *pte & PTE_V == 0 {
+            let alloc_frame = alloc_frame.as_mut().ok_or(LinuxError::EFAULT)?;
+            let next = alloc_frame().ok_or(LinuxError::ENOMEM)?;
+            table_entries(next).fill(0);
+            core::ptr::write_volatile(pte, table_pte(next));
+        } else if is_leaf(*pte) {
+            return Err(LinuxError::EEXIST);
+        }
+        table = pte_paddr(*pte);
+    }
+    unreachable!()
+}
+
+/// Returns the leaf entry that maps `gpa`, and the size of its page.
+///
+/// Returns [`LinuxError::EFAULT`] if `gpa` is not mapped, and
+/// [`LinuxError::EEXIST`] for a 512G superpage of Sv48x4/Sv57x4.
+unsafe fn gstage_leaf(
+    root: PhysAddr,
+    mode: GStageMode,
+    gpa: usize,
+) -> Result<(&'static mut usize, PageSize), LinuxError> {
+    for page_size in [PageSize::Size1G, PageSize::Size2M, PageSize::Size4K] {
+        let pte = gstage_entry(root, mode, gpa, page_size.level(), None)?;
+        if *pte & PTE_V == 0 {
+            return Err(LinuxError::EFAULT);
+        }
+        if is_leaf(*pte) {
+            return Ok((pte, page_size));
+        }
+    }
+    Err(LinuxError::EFAULT)
+}
+
+unsafe fn gstage_map_leaf(
+    root: PhysAddr,
+    mode: GStageMode,
+    gpa: usize,
+    hpa: PhysAddr,
+    page_size: PageSize,
+    flags: usize,
+    alloc_frame: &mut dyn FnMut() -> Option<PhysAddr>,
+) -> Result<(), LinuxError> {
+    if (gpa | hpa.as_usize()) % page_size.size() != 0 {
+        return Err(LinuxError::EINVAL);
+    }
+    let pte = gstage_entry(root, mode, gpa, page_size.level(), Some(alloc_frame))?;
+    if *pte & PTE_V != 0 {
+        return Err(LinuxError::EEXIST);
+    }
+    core::ptr::write_volatile(pte, (hpa.as_usize() >> 12) << PTE_PPN_SHIFT | flags);
+    Ok(())
+}
+
+/// Maps the guest page of `page_size` at `gpa` to `hpa` in the G-stage
+/// table at `root` in `mode`, with the permissions of `flags` (and `U`).
+///
+/// The missing tables are allocated with `alloc_frame`. Both addresses must
+/// be aligned to `page_size`. Returns [`LinuxError::EEXIST`] if the page (or
+/// a part of it) is already mapped, or [`LinuxError::ENOMEM`] if a table
+/// cannot be allocated.
+///
+/// # Safety
+///
+/// `root` must be a valid G-stage table in `mode`, not modified
+/// concurrently.
+pub unsafe fn gstage_map_page(
+    root: PhysAddr,
+    mode: GStageMode,
+    gpa: usize,
+    hpa: PhysAddr,
+    page_size: PageSize,
+    flags: MemRegionFlags,
+    alloc_frame: &mut impl FnMut() -> Option<PhysAddr>,
+) -> Result<(), LinuxError> {
+    let flags = leaf_flags(flags, MemAttr::Normal)? | PTE_U;
+    gstage_map_leaf(root, mode, gpa, hpa, page_size, flags, alloc_frame)
+}
+
+/// Maps the guest `[gpa, gpa + size)` to `[hpa, hpa + size)`, with the
+/// largest pages the alignment of the addresses allows (see
+/// [`PageSize::largest_for`]), e.g. for the RAM of a guest. The pages
+/// mapped before an error stay mapped.
+///
+/// # Safety
+///
+/// See [`gstage_map_page`].
+pub unsafe fn gstage_map_region(
+    root: PhysAddr,
+    mode: GStageMode,
+    gpa: usize,
+    hpa: PhysAddr,
+    size: usize,
+    flags: MemRegionFlags,
+    alloc_frame: &mut impl FnMut() -> Option<PhysAddr>,
+) -> Result<(), LinuxError> {
+    if (gpa | hpa.as_usize() | size) % PAGE_SIZE_4K != 0 {
+        return Err(LinuxError::EINVAL);
+    }
+    let flags = leaf_flags(flags, MemAttr::Normal)? | PTE_U;
+    let mut offset = 0;
+    while offset < size {
+        let ga = gpa + offset;
+        let ha = PhysAddr::from(hpa.as_usize() + offset);
+        let page_size = PageSize::largest_for(ga.into(), ha, size - offset);
+        gstage_map_leaf(root, mode, ga, ha, page_size, flags, alloc_frame)?;
+        offset += page_size.size();
+    }
+    Ok(())
+}
+
+/// Unmaps the guest page (of any size) that contains `gpa`, and returns the
+/// host physical address and the size of the page.
+///
+/// The intermediate tables are kept. Returns [`LinuxError::EFAULT`] if
+/// `gpa` is not mapped.
+///
+/// # Safety
+///
+/// See [`gstage_map_page`].
+pub unsafe fn gstage_unmap_page(
+    root: PhysAddr,
+    mode: GStageMode,
+    gpa: usize,
+) -> Result<(PhysAddr, PageSize), LinuxError> {
+    let (pte, page_size) = gstage_leaf(root, mode, gpa)?;
+    let hpa = pte_paddr(*pte);
+    core::ptr::write_volatile(pte, 0);
+    Ok((hpa, page_size))
+}
+
+/// Returns the host physical address that the guest `gpa` is mapped to.
+///
+/// Returns [`LinuxError::EFAULT`] if `gpa` is not mapped.
+///
+/// # Safety
+///
+/// See [`gstage_map_page`].
+pub unsafe fn gstage_translate(
+    root: PhysAddr,
+    mode: GStageMode,
+    gpa: usize,
+) -> Result<PhysAddr, LinuxError> {
+    let (pte, page_size) = gstage_leaf(root, mode, gpa)?;
+    Ok(pte_paddr(*pte) + (gpa & (page_size.size() - 1)))
+}
+
+/// The access of a guest-page fault.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum GuestAccess {
+    /// An instruction fetch.
+    Fetch,
+    /// A load.
+    Load,
+    /// A store or an AMO.
+    Store,
+}
+
+/// A load or a store of an integer register, decoded from the transformed
+/// instruction of a guest-page fault, e.g. to emulate the MMIO of a device.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct GuestMmioAccess {
+    /// The size of the access in bytes: 1, 2, 4 or 8.
+    pub width: usize,
+    /// The destination register of a load, or the source of a store.
+    pub reg: usize,
+    /// Whether a load sign-extends the value.
+    pub signed: bool,
+    /// The length of the faulting instruction, 2 or 4, to advance `sepc`.
+    pub insn_len: usize,
+}
+
+/// A fault of the G-stage translation.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct GuestPageFaultInfo {
+    /// The faulting access.
+    pub access: GuestAccess,
+    /// The guest physical address.
+    pub gpa: usize,
+    /// The guest virtual address, if the fault had one ([`HSTATUS_GVA`]).
+    pub gva: Option<usize>,
+    /// The transformed instruction (`htinst`), 0 if not provided.
+    pub htinst: usize,
+    /// The decoded access, if `htinst` is a (transformed) integer load or
+    /// store. Otherwise the caller decodes the instruction at `sepc`.
+    pub mmio: Option<GuestMmioAccess>,
+}
+
+impl GuestPageFaultInfo {
+    /// Decodes the guest-page fault `trap` taken with `hstatus`, or returns
+    /// [`None`] if it is another trap.
+    pub fn from_trap(trap: &GuestTrap, hstatus: usize) -> Option<Self> {
+        let access = match trap.scause {
+            20 => GuestAccess::Fetch,
+            21 => GuestAccess::Load,
+            23 => GuestAccess::Store,
+            _ => return None,
+        };
+        Some(Self {
+            access,
+            gpa: (trap.htval << 2) | (trap.stval & 3),
+            gva: (hstatus & HSTATUS_GVA != 0).then_some(trap.stval),
+            htinst: trap.htinst,
+            mmio: decode_htinst(trap.htinst),
+        })
+    }
+}
+
+/// Decodes a transformed load or store: bit 0 set, bit 1 clear for a
+/// compressed instruction, and the fields of the 32-bit one.
+fn decode_htinst(htinst: usize) -> Option<GuestMmioAccess> {
+    // 0 or a pseudoinstruction of an implicit access of the VS-stage.
+    if htinst & 1 == 0 {
+        return None;
+    }
+    let insn = htinst as u32 | 2;
+    let funct3 = (insn >> 12) & 7;
+    let (reg, signed) = match insn & 0x7f {
+        // LB, LH, LW, LD, LBU, LHU, LWU
+        0x03 if funct3 != 7 => ((insn >> 7) & 0x1f, funct3 < 4),
+        // SB, SH, SW, SD
+        0x23 if funct3 < 4 => ((insn >> 20) & 0x1f, false),
+        _ => return None,
+    };
+    Some(GuestMmioAccess {
+        width: 1 << (funct3 & 3),
+        reg: reg as usize,
+        signed,
+        insn_len: if htinst & 2 != 0 { 4 } else { 2 },
+    })
+}
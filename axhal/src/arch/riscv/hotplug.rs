@@ -0,0 +1,80 @@
+//! CPU hotplug: the callbacks of the per-CPU subsystems, and the CPU going
+//! down.
+//!
+//! [`cpu_down`](crate::mp::cpu_down) sends [`IpiKind::CPU_DOWN`] to the
+//! CPU. It runs the `teardown` callbacks (e.g. the interrupts routed to it
+//! are moved to another CPU, its timer is disarmed), goes offline, serves
+//! the IPI requests still queued, and stops with `sbi_hart_stop`.
+//! [`cpu_up`](crate::mp::cpu_up) starts it again through the secondary boot
+//! trampoline, where it runs the `startup` callbacks.
+//!
+//! [`IpiKind::CPU_DOWN`]: super::IpiKind::CPU_DOWN
+
+use spinbase::SpinNoIrq;
+
+use crate::cpu::_this_cpu_id;
+
+/// The maximum number of registered [`HotplugCallbacks`].
+pub const MAX_HOTPLUG_CALLBACKS: usize = 8;
+
+/// The callbacks of a per-CPU subsystem, called on the CPU that goes down
+/// or comes up, with interrupts disabled.
+#[derive(Clone, Copy)]
+pub struct HotplugCallbacks {
+    /// Sets up the subsystem on a CPU coming up, before it runs the entry
+    /// of the kernel.
+    pub startup: Option<fn(cpu_id: usize)>,
+    /// Tears the subsystem down on a CPU going down, while it is still
+    /// online.
+    pub teardown: Option<fn(cpu_id: usize)>,
+}
+
+static HOTPLUG_CALLBACKS: SpinNoIrq<[Option<HotplugCallbacks>; MAX_HOTPLUG_CALLBACKS]> =
+    SpinNoIrq::new([None; MAX_HOTPLUG_CALLBACKS]);
+
+/// Registers the hotplug callbacks of a per-CPU subsystem.
+///
+/// The `startup` callbacks are called in the order of registration, and
+/// the `teardown` ones in the reverse order. Returns `false` if there are
+/// already [`MAX_HOTPLUG_CALLBACKS`] of them.
+pub fn register_cpu_hotplug(callbacks: HotplugCallbacks) -> bool {
+    let mut registered = HOTPLUG_CALLBACKS.lock();
+    match registered.iter_mut().find(|slot| slot.is_none()) {
+        Some(slot) => {
+            *slot = Some(callbacks);
+            true
+        }
+        None => false,
+    }
+}
+
+/// Runs the `startup` callbacks on a secondary CPU coming up, from the
+/// secondary boot path of the platform.
+pub(crate) fn cpu_starting(cpu_id: usize) {
+    let callbacks = *HOTPLUG_CALLBACKS.lock();
+    for startup in callbacks.iter().flatten().filter_map(|cb| cb.startup) {
+        startup(cpu_id);
+    }
+}
+
+/// Takes the current CPU down, from the IPI handler.
+pub(super) fn cpu_die() -> ! {
+    super::disable_irqs();
+    let cpu_id = _this_cpu_id();
+    let callbacks = *HOTPLUG_CALLBACKS.lock();
+    for teardown in callbacks
+        .iter()
+        .flatten()
+        .rev()
+        .filter_map(|cb| cb.teardown)
+    {
+        teardown(cpu_id);
+    }
+    crate::cpu::cpu_offline();
+    super::ipi::flush_pending_ipis();
+    super::sbi::hart_stop();
+    warn!("CPU {} cannot stop, halt it", cpu_id);
+    loop {
+        super::wait_for_irqs();
+    }
+}
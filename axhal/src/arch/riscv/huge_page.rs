@@ -0,0 +1,472 @@
+//! Superpage (2M and 1G) mappings.
+//!
+//! A superpage is a leaf entry above the last level: at level 1 it maps 2M,
+//! at level 2 it maps 1G. Both its virtual and physical addresses must be
+//! aligned to its size. An `sfence.vma` of any address in a superpage
+//! flushes its whole TLB entry.
+//!
+//! The tables are accessed through the linear mapping. The TLB flushes are
+//! local to the current CPU.
+
+use axerrno::LinuxError;
+use memory_addr::{PhysAddr, VirtAddr};
+
+use super::page_walk::{current_levels, PTE_PPN_MASK, PTE_PPN_SHIFT};
+use super::page_walk::{PTE_A, PTE_D, PTE_N, PTE_R, PTE_V, PTE_W, PTE_X};
+use super::svpbmt::MemAttr;
+use crate::mem::{phys_to_virt, MemRegionFlags, PAGE_SIZE_4K};
+
+const PTE_PPN_FIELD: usize = PTE_PPN_MASK << PTE_PPN_SHIFT;
+
+/// The number of entries in a page table.
+pub(super) const PTE_COUNT: usize = 512;
+
+/// The size of a page mapped by a single leaf entry.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PageSize {
+    /// A 4K page, at level 0.
+    Size4K,
+    /// A 2M superpage, at level 1 (a PMD block).
+    Size2M,
+    /// A 1G superpage, at level 2 (a PUD block).
+    Size1G,
+}
+
+impl PageSize {
+    /// Returns the size in bytes.
+    pub const fn size(self) -> usize {
+        1 << (12 + 9 * self.level())
+    }
+
+    /// Returns the level of the leaf entry.
+    pub const fn level(self) -> usize {
+        match self {
+            Self::Size4K => 0,
+            Self::Size2M => 1,
+            Self::Size1G => 2,
+        }
+    }
+
+    /// Returns the size of the pages one level below, that a superpage is
+    /// split into, or [`None`] for 4K pages.
+    pub const fn smaller(self) -> Option<Self> {
+        match self {
+            Self::Size4K => None,
+            Self::Size2M => Some(Self::Size4K),
+            Self::Size1G => Some(Self::Size2M),
+        }
+    }
+
+    /// Returns the largest page size to which `vaddr` and `paddr` are both
+    /// aligned, and that fits in `size` bytes.
+    pub const fn largest_for(vaddr: VirtAddr, paddr: PhysAddr, size: usize) -> Self {
+        let align = vaddr.as_usize() | paddr.as_usize();
+        if align % Self::Size1G.size() == 0 && size >= Self::Size1G.size() {
+            Self::Size1G
+        } else if align % Self::Size2M.size() == 0 && size >= Self::Size2M.size() {
+            Self::Size2M
+        } else {
+            Self::Size4K
+        }
+    }
+}
+
+/// Converts the permissions of `flags` and the memory type `attr` to the
+/// flags of a leaf entry.
+pub(super) fn leaf_flags(flags: MemRegionFlags, attr: MemAttr) -> Result<usize, LinuxError> {
+    let mut pte = PTE_V | PTE_A | PTE_D | attr.pte_bits();
+    if flags.contains(MemRegionFlags::READ) {
+        pte |= PTE_R;
+    }
+    if flags.contains(MemRegionFlags::WRITE) {
+        pte |= PTE_W;
+    }
+    if flags.contains(MemRegionFlags::EXECUTE) {
+        pte |= PTE_X;
+    }
+    // Neither a pointer to the next level, nor writable without `R`.
+    match pte & (PTE_R | PTE_W | PTE_X) {
+        0 | PTE_W => Err(LinuxError::EINVAL),
+        _ => Ok(pte),
+    }
+}
+
+pub(super) const fn is_leaf(pte: usize) -> bool {
+    pte & (PTE_R | PTE_W | PTE_X) != 0
+}
+
+pub(super) const fn pte_paddr(pte: usize) -> PhysAddr {
+    PhysAddr::from(((pte >> PTE_PPN_SHIFT) & PTE_PPN_MASK) << 12)
+}
+
+pub(super) const fn table_pte(table: PhysAddr) -> usize {
+    ((table.as_usize() >> 12) << PTE_PPN_SHIFT) | PTE_V
+}
+
+/// Returns the entries of the table at `table`.
+pub(super) unsafe fn table_entries(table: PhysAddr) -> &'static mut [usize] {
+    core::slice::from_raw_parts_mut(phys_to_virt(table).as_mut_ptr().cast(), PTE_COUNT)
+}
+
+/// Allocates a zeroed page table with `alloc_frame`.
+unsafe fn alloc_table(
+    alloc_frame: &mut dyn FnMut() -> Option<PhysAddr>,
+) -> Result<PhysAddr, LinuxError> {
+    let table = alloc_frame().ok_or(LinuxError::ENOMEM)?;
+    table_entries(table).fill(0);
+    Ok(table)
+}
+
+/// Returns the entry at `level` that maps `vaddr`, walking down from `root`.
+///
+/// The missing tables above `level` are allocated with `alloc_frame` if it
+/// is given, otherwise [`LinuxError::EFAULT`] is returned. A superpage above
+/// `level` gives [`LinuxError::EEXIST`].
+pub(super) unsafe fn entry_at(
+    root: PhysAddr,
+    vaddr: VirtAddr,
+    level: usize,
+    mut alloc_frame: Option<&mut dyn FnMut() -> Option<PhysAddr>>,
+) -> Result<&'static mut usize, LinuxError> {
+    let levels = current_levels().ok_or(LinuxError::EINVAL)?;
+    if level >= levels {
+        return Err(LinuxError::EINVAL);
+    }
+    let mut table = root;
+    for l in (level..levels).rev() {
+        let index = (vaddr.as_usize() >> (12 + 9 * l)) & (PTE_COUNT - 1);
+        let pte = &mut table_entries(table)[index];
+        if l == level {
+            return Ok(pte);
+        }
+        if *pte & PTE_V == 0 {
+            let alloc_frame = alloc_frame.as_mut().ok_or(LinuxError::EFAULT)?;
+            let next = alloc_table(&mut **alloc_frame)?;
+            core::ptr::write_volatile(pte, table_pte(next));
+        } else if is_leaf(*pte) {
+            return Err(LinuxError::EEXIST);
+        }
+        table = pte_paddr(*pte);
+    }
+    unreachable!()
+}
+
+/// Maps the page of `page_size` at `vaddr` to `paddr`, with the permissions
+/// of `flags` and the memory type `attr` (e.g. [`MemAttr::Io`] for device
+/// MMIO, see also `MemAttr::from(&flags)`).
+///
+/// The missing intermediate tables are allocated with `alloc_frame`, which
+/// returns the physical address of a free 4K frame. Both addresses must be
+/// aligned to `page_size`. Returns [`LinuxError::EEXIST`] if the page (or a
+/// part of it) is already mapped, and [`LinuxError::ENOMEM`] if a table
+/// cannot be allocated.
+///
+/// # Safety
+///
+/// `root` must be a valid page table, and nobody else may modify the entries
+/// concurrently.
+pub unsafe fn map_page(
+    root: PhysAddr,
+    vaddr: VirtAddr,
+    paddr: PhysAddr,
+    page_size: PageSize,
+    flags: MemRegionFlags,
+    attr: MemAttr,
+    alloc_frame: &mut impl FnMut() -> Option<PhysAddr>,
+) -> Result<(), LinuxError> {
+    let flags = leaf_flags(flags, attr)?;
+    map_leaf(root, vaddr, paddr, page_size, flags, alloc_frame)
+}
+
+unsafe fn map_leaf(
+    root: PhysAddr,
+    vaddr: VirtAddr,
+    paddr: PhysAddr,
+    page_size: PageSize,
+    flags: usize,
+    alloc_frame: &mut dyn FnMut() -> Option<PhysAddr>,
+) -> Result<(), LinuxError> {
+    if (vaddr.as_usize() | paddr.as_usize()) % page_size.size() != 0 {
+        return Err(LinuxError::EINVAL);
+    }
+    let pte = entry_at(root, vaddr, page_size.level(), Some(alloc_frame))?;
+    if *pte & PTE_V != 0 {
+        return Err(LinuxError::EEXIST);
+    }
+    core::ptr::write_volatile(pte, ((paddr.as_usize() >> 12) << PTE_PPN_SHIFT) | flags);
+    Ok(())
+}
+
+/// Maps `[vaddr, vaddr + size)` to `[paddr, paddr + size)`, with the largest
+/// pages the alignment of the addresses allows (see [`PageSize::largest_for`]).
+///
+/// It is meant for large physically contiguous regions, e.g. the linear
+/// mapping of the physical memory, that take far fewer TLB entries with
+/// superpages. The pages mapped before an error stay mapped.
+///
+/// # Safety
+///
+/// See [`map_page`].
+pub unsafe fn map_region(
+    root: PhysAddr,
+    vaddr: VirtAddr,
+    paddr: PhysAddr,
+    size: usize,
+    flags: MemRegionFlags,
+    attr: MemAttr,
+    alloc_frame: &mut impl FnMut() -> Option<PhysAddr>,
+) -> Result<(), LinuxError> {
+    if (vaddr.as_usize() | paddr.as_usize() | size) % PAGE_SIZE_4K != 0 {
+        return Err(LinuxError::EINVAL);
+    }
+    let flags = leaf_flags(flags, attr)?;
+    let mut offset = 0;
+    while offset < size {
+        let va = VirtAddr::from(vaddr.as_usize() + offset);
+        let pa = PhysAddr::from(paddr.as_usize() + offset);
+        let page_size = PageSize::largest_for(va, pa, size - offset);
+        map_leaf(root, va, pa, page_size, flags, alloc_frame)?;
+        offset += page_size.size();
+    }
+    Ok(())
+}
+
+/// Unmaps the page (of any size) that contains `vaddr`, and returns the
+/// physical address and the size of the page.
+///
+/// The intermediate tables are kept. The TLB is not flushed, the caller
+/// flushes the page (e.g. with [`flush_tlb_page`]) once it is unmapped.
+///
+/// # Safety
+///
+/// See [`map_page`].
+pub unsafe fn unmap_page(
+    root: PhysAddr,
+    vaddr: VirtAddr,
+) -> Result<(PhysAddr, PageSize), LinuxError> {
+    let (pte, page_size) = leaf_entry(root, vaddr)?;
+    let paddr = pte_paddr(*pte);
+    core::ptr::write_volatile(pte, 0);
+    Ok((paddr, page_size))
+}
+
+/// Returns the leaf entry that maps `vaddr`, and the size of its page.
+///
+/// Returns [`LinuxError::EFAULT`] if `vaddr` is not mapped, and
+/// [`LinuxError::EEXIST`] for a 512G superpage of Sv48/Sv57.
+pub(super) unsafe fn leaf_entry(
+    root: PhysAddr,
+    vaddr: VirtAddr,
+) -> Result<(&'static mut usize, PageSize), LinuxError> {
+    for page_size in [PageSize::Size1G, PageSize::Size2M, PageSize::Size4K] {
+        let pte = entry_at(root, vaddr, page_size.level(), None)?;
+        if *pte & PTE_V == 0 {
+            return Err(LinuxError::EFAULT);
+        }
+        if is_leaf(*pte) {
+            return Ok((pte, page_size));
+        }
+    }
+    Err(LinuxError::EFAULT)
+}
+
+/// Calls `update` with each leaf entry in `[vaddr, vaddr + size)`, after
+/// splitting the superpages that are only partly in the range.
+unsafe fn update_region(
+    root: PhysAddr,
+    vaddr: VirtAddr,
+    size: usize,
+    alloc_frame: &mut dyn FnMut() -> Option<PhysAddr>,
+    update: &mut dyn FnMut(&mut usize),
+) -> Result<(), LinuxError> {
+    if (vaddr.as_usize() | size) % PAGE_SIZE_4K != 0 {
+        return Err(LinuxError::EINVAL);
+    }
+    let end = vaddr.as_usize() + size;
+    let mut va = vaddr.as_usize();
+    while va < end {
+        let (pte, page_size) = leaf_entry(root, VirtAddr::from(va))?;
+        let page = page_size.size();
+        let base = va & !(page - 1);
+        if base == va && va + page <= end {
+            if *pte & PTE_N != 0 {
+                // A page of a NAPOT mapping, see `napot_split_64k`.
+                return Err(LinuxError::EINVAL);
+            }
+            update(pte);
+            va += page;
+        } else {
+            split_page(
+                root,
+                VirtAddr::from(base),
+                page_size,
+                &mut &mut *alloc_frame,
+            )?;
+        }
+    }
+    Ok(())
+}
+
+/// Changes the permissions of the pages in `[vaddr, vaddr + size)` to those
+/// of `flags`, keeping the other bits of the entries.
+///
+/// The superpages that are only partly in the range are split first, with
+/// the tables allocated by `alloc_frame`. The TLB is not flushed, the caller
+/// flushes the range once it is done. Returns [`LinuxError::EFAULT`] if a
+/// page is not mapped.
+///
+/// # Safety
+///
+/// See [`map_page`].
+pub unsafe fn protect_region(
+    root: PhysAddr,
+    vaddr: VirtAddr,
+    size: usize,
+    flags: MemRegionFlags,
+    alloc_frame: &mut impl FnMut() -> Option<PhysAddr>,
+) -> Result<(), LinuxError> {
+    const PERM: usize = PTE_R | PTE_W | PTE_X;
+    let perm = leaf_flags(flags, MemAttr::Normal)? & PERM;
+    update_region(root, vaddr, size, alloc_frame, &mut |pte| {
+        core::ptr::write_volatile(pte, (*pte & !PERM) | perm)
+    })
+}
+
+/// Unmaps the pages in `[vaddr, vaddr + size)`, splitting the superpages
+/// that are only partly in the range.
+///
+/// See [`protect_region`].
+///
+/// # Safety
+///
+/// See [`map_page`].
+pub unsafe fn unmap_region(
+    root: PhysAddr,
+    vaddr: VirtAddr,
+    size: usize,
+    alloc_frame: &mut impl FnMut() -> Option<PhysAddr>,
+) -> Result<(), LinuxError> {
+    update_region(root, vaddr, size, alloc_frame, &mut |pte| {
+        core::ptr::write_volatile(pte, 0)
+    })
+}
+
+/// Splits the superpage of `page_size` at `vaddr` into the pages one level
+/// below, with the same flags, e.g. before changing the permissions of a
+/// part of it.
+///
+/// The new table is allocated with `alloc_frame`. It does nothing if the
+/// range is already mapped by a table.
+///
+/// # Safety
+///
+/// See [`map_page`].
+pub unsafe fn split_page(
+    root: PhysAddr,
+    vaddr: VirtAddr,
+    page_size: PageSize,
+    alloc_frame: &mut impl FnMut() -> Option<PhysAddr>,
+) -> Result<(), LinuxError> {
+    let smaller = page_size.smaller().ok_or(LinuxError::EINVAL)?;
+    let pte = entry_at(root, vaddr, page_size.level(), None)?;
+    if *pte & PTE_V == 0 {
+        return Err(LinuxError::EFAULT);
+    }
+    if !is_leaf(*pte) {
+        return Ok(());
+    }
+    let flags = *pte & !PTE_PPN_FIELD;
+    let base = pte_paddr(*pte).as_usize();
+    let table = alloc_table(alloc_frame)?;
+    for (i, entry) in table_entries(table).iter_mut().enumerate() {
+        let paddr = base + i * smaller.size();
+        *entry = ((paddr >> 12) << PTE_PPN_SHIFT) | flags;
+    }
+    core::ptr::write_volatile(pte, table_pte(table));
+    // The cached superpage translates the same, one fence is enough.
+    flush_tlb_page(vaddr, page_size);
+    Ok(())
+}
+
+/// Merges the table that maps the range of a `page_size` superpage at
+/// `vaddr` back into the superpage, and returns the physical address of the
+/// table for the caller to free.
+///
+/// The entries of the table must all be valid leaves with the same flags,
+/// mapping contiguous pages that start at an address aligned to
+/// `page_size`, otherwise [`LinuxError::EINVAL`] is returned and nothing is
+/// changed.
+///
+/// # Safety
+///
+/// See [`map_page`].
+pub unsafe fn merge_page(
+    root: PhysAddr,
+    vaddr: VirtAddr,
+    page_size: PageSize,
+) -> Result<PhysAddr, LinuxError> {
+    let smaller = page_size.smaller().ok_or(LinuxError::EINVAL)?;
+    let pte = entry_at(root, vaddr, page_size.level(), None)?;
+    if *pte & PTE_V == 0 || is_leaf(*pte) {
+        return Err(LinuxError::EINVAL);
+    }
+    let table = pte_paddr(*pte);
+    let entries = table_entries(table);
+    let first = entries[0];
+    let base = pte_paddr(first).as_usize();
+    let flags = first & !PTE_PPN_FIELD & !(PTE_A | PTE_D);
+    if first & PTE_V == 0 || !is_leaf(first) || base % page_size.size() != 0 {
+        return Err(LinuxError::EINVAL);
+    }
+    // The accessed and dirty bits may differ, the superpage gets the union.
+    let contiguous = entries.iter().enumerate().all(|(i, &entry)| {
+        let paddr = base + i * smaller.size();
+        entry & !(PTE_A | PTE_D) == ((paddr >> 12) << PTE_PPN_SHIFT) | flags
+    });
+    if !contiguous {
+        return Err(LinuxError::EINVAL);
+    }
+    let accessed_dirty = entries
+        .iter()
+        .fold(0, |ad, &pte| ad | (pte & (PTE_A | PTE_D)));
+
+    core::ptr::write_volatile(
+        pte,
+        ((base >> 12) << PTE_PPN_SHIFT) | flags | accessed_dirty,
+    );
+    // An `sfence.vma` of an address only flushes the leaf entries, the
+    // cached pointer to the old table needs a full flush.
+    super::flush_tlb(None);
+    Ok(table)
+}
+
+/// Flushes the TLB entry of the page of `page_size` that contains `vaddr`.
+///
+/// A single `sfence.vma` flushes the whole page, whatever its size.
+#[inline]
+pub fn flush_tlb_page(vaddr: VirtAddr, _page_size: PageSize) {
+    super::flush_tlb(Some(vaddr));
+}
+
+/// Flushes the TLB entries of `[start, start + size)`, mapped by pages of
+/// `page_size`, on the current CPU.
+///
+/// It issues one `sfence.vma` per page, so a range of superpages takes far
+/// fewer fences than [`flush_tlb_range`](super::flush_tlb_range), or a
+/// single full flush if it covers more than
+/// [`TLB_FLUSH_RANGE_THRESHOLD`](super::TLB_FLUSH_RANGE_THRESHOLD) pages.
+pub fn flush_tlb_range_sized(start: VirtAddr, size: usize, page_size: PageSize) {
+    if size == 0 {
+        return;
+    }
+    let page = page_size.size();
+    let first = start.as_usize() & !(page - 1);
+    let pages = (start.as_usize() + size - first + page - 1) / page;
+    if super::tlb_flush_all_better(pages) {
+        super::flush_tlb(None);
+    } else {
+        for i in 0..pages {
+            super::flush_tlb(Some(VirtAddr::from(first + i * page)));
+        }
+    }
+}
@@ -0,0 +1,219 @@
+//! Hardware breakpoints and watchpoints, with the Sdtrig triggers.
+//!
+//! The trigger CSRs (`tselect`, `tdata1`, `tdata2`) are only accessible
+//! from M-mode, so the triggers are programmed through the SBI debug
+//! triggers extension, as `mcontrol6` address matches. They are per hart:
+//! [`install_hw_breakpoint`] uses a slot of the current CPU.
+//!
+//! A trigger raises a breakpoint exception, with `stval` the matched
+//! address. [`handle_breakpoint`](super::handle_breakpoint) routes it to the
+//! handler registered with [`set_hw_breakpoint_handler`], which must move
+//! `sepc` or uninstall the trigger before returning, as a breakpoint on an
+//! instruction fires again when it is retried.
+
+use axerrno::LinuxError;
+use core::sync::atomic::{AtomicU8, Ordering};
+use memory_addr::VirtAddr;
+use spinbase::SpinNoIrq;
+
+use super::sbi;
+use super::TrapFrame;
+use crate::mem::virt_to_phys;
+
+/// The maximum number of hardware breakpoints of each CPU.
+pub const MAX_HW_BREAKPOINTS: usize = 4;
+
+/// The type of `mcontrol6` in `tdata1`.
+const TDATA1_TYPE_MCONTROL6: usize = 6;
+const TDATA1_TYPE_SHIFT: usize = usize::BITS as usize - 4;
+const MCONTROL6_LOAD: usize = 1 << 0;
+const MCONTROL6_STORE: usize = 1 << 1;
+const MCONTROL6_EXECUTE: usize = 1 << 2;
+const MCONTROL6_U: usize = 1 << 3;
+const MCONTROL6_S: usize = 1 << 4;
+
+/// What a hardware breakpoint matches.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HwBreakpointKind {
+    /// The execution of the instruction at the address.
+    Execute,
+    /// The loads from the address.
+    Read,
+    /// The stores to the address.
+    Write,
+    /// The loads and the stores.
+    ReadWrite,
+}
+
+impl HwBreakpointKind {
+    const fn tdata1_bits(self) -> usize {
+        match self {
+            Self::Execute => MCONTROL6_EXECUTE,
+            Self::Read => MCONTROL6_LOAD,
+            Self::Write => MCONTROL6_STORE,
+            Self::ReadWrite => MCONTROL6_LOAD | MCONTROL6_STORE,
+        }
+    }
+}
+
+/// A hardware breakpoint installed on the current CPU.
+#[derive(Debug, Clone, Copy)]
+pub struct HwBreakpoint {
+    /// The slot on the CPU, for [`uninstall_hw_breakpoint`].
+    pub slot: usize,
+    /// The matched address.
+    pub addr: usize,
+    /// What it matches.
+    pub kind: HwBreakpointKind,
+}
+
+/// The type of a hardware breakpoint handler.
+pub type HwBreakpointHandler = fn(&mut TrapFrame, &HwBreakpoint);
+
+static HW_BREAKPOINT_HANDLER: SpinNoIrq<Option<HwBreakpointHandler>> = SpinNoIrq::new(None);
+
+/// An entry of the SBI shared memory.
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct TriggerEntry {
+    /// `tstate` read back, or the index of an installed trigger.
+    idx: usize,
+    tdata1: usize,
+    tdata2: usize,
+    tdata3: usize,
+}
+
+#[derive(Clone, Copy)]
+struct Slot {
+    breakpoint: HwBreakpoint,
+    /// The SBI index of the trigger.
+    trigger: usize,
+}
+
+#[percpu2::def_percpu]
+static TRIGGER_SHMEM: TriggerEntry = TriggerEntry {
+    idx: 0,
+    tdata1: 0,
+    tdata2: 0,
+    tdata3: 0,
+};
+
+#[percpu2::def_percpu]
+static SHMEM_SET: bool = false;
+
+#[percpu2::def_percpu]
+static HW_BREAKPOINTS: [Option<Slot>; MAX_HW_BREAKPOINTS] = [None; MAX_HW_BREAKPOINTS];
+
+/// Returns whether the SBI debug triggers extension is available, with
+/// address match triggers.
+pub fn has_hw_breakpoints() -> bool {
+    // 0: unknown, 1: not supported, 2: supported
+    static DBTR: AtomicU8 = AtomicU8::new(0);
+    match DBTR.load(Ordering::Relaxed) {
+        0 => {
+            let supported = sbi::probe_extension(sbi::EID_DBTR)
+                && sbi::dbtr_num_triggers(TDATA1_TYPE_MCONTROL6 << TDATA1_TYPE_SHIFT).unwrap_or(0)
+                    > 0;
+            DBTR.store(if supported { 2 } else { 1 }, Ordering::Relaxed);
+            supported
+        }
+        state => state == 2,
+    }
+}
+
+/// Registers the handler of the hardware breakpoints.
+pub fn set_hw_breakpoint_handler(f: HwBreakpointHandler) {
+    *HW_BREAKPOINT_HANDLER.lock() = Some(f);
+}
+
+/// Installs a hardware breakpoint on `addr` on the current CPU, for the
+/// accesses from U-mode and (with `kernel`) from S-mode.
+///
+/// Returns [`LinuxError::ENODEV`] without the SBI debug triggers extension,
+/// and [`LinuxError::ENOSPC`] if all the slots or triggers of the CPU are in
+/// use.
+pub fn install_hw_breakpoint(
+    addr: VirtAddr,
+    kind: HwBreakpointKind,
+    kernel: bool,
+) -> Result<HwBreakpoint, LinuxError> {
+    if !has_hw_breakpoints() {
+        return Err(LinuxError::ENODEV);
+    }
+    let _guard = kernel_guard_base::IrqSave::new();
+    let slots = unsafe { HW_BREAKPOINTS.current_ref_mut_raw() };
+    let slot = slots
+        .iter()
+        .position(Option::is_none)
+        .ok_or(LinuxError::ENOSPC)?;
+    let shmem = unsafe { TRIGGER_SHMEM.current_ref_mut_raw() };
+    if !unsafe { SHMEM_SET.read_current_raw() } {
+        let paddr = virt_to_phys(VirtAddr::from(shmem as *mut _ as usize));
+        if sbi::dbtr_set_shmem(paddr.as_usize()).is_err() {
+            return Err(LinuxError::ENODEV);
+        }
+        unsafe { SHMEM_SET.write_current_raw(true) };
+    }
+    let mode = if kernel {
+        MCONTROL6_U | MCONTROL6_S
+    } else {
+        MCONTROL6_U
+    };
+    *shmem = TriggerEntry {
+        idx: 0,
+        tdata1: (TDATA1_TYPE_MCONTROL6 << TDATA1_TYPE_SHIFT) | mode | kind.tdata1_bits(),
+        tdata2: addr.as_usize(),
+        tdata3: 0,
+    };
+    if sbi::dbtr_install_triggers(1).is_err() {
+        return Err(LinuxError::ENOSPC);
+    }
+    let breakpoint = HwBreakpoint {
+        slot,
+        addr: addr.as_usize(),
+        kind,
+    };
+    slots[slot] = Some(Slot {
+        breakpoint,
+        trigger: shmem.idx,
+    });
+    Ok(breakpoint)
+}
+
+/// Uninstalls the hardware breakpoint in `slot` of the current CPU.
+pub fn uninstall_hw_breakpoint(slot: usize) -> Result<(), LinuxError> {
+    let _guard = kernel_guard_base::IrqSave::new();
+    let slots = unsafe { HW_BREAKPOINTS.current_ref_mut_raw() };
+    let installed = slots
+        .get_mut(slot)
+        .and_then(Option::take)
+        .ok_or(LinuxError::EINVAL)?;
+    sbi::dbtr_uninstall_triggers(installed.trigger, 1).ok();
+    Ok(())
+}
+
+/// Handles a breakpoint exception raised by a hardware breakpoint of the
+/// current CPU: calls the registered handler and returns `true`. Returns
+/// `false` if it is not one (e.g. an `ebreak`).
+pub fn handle_hw_breakpoint(tf: &mut TrapFrame, stval: usize) -> bool {
+    let slots = unsafe { HW_BREAKPOINTS.current_ref_raw() };
+    let hit = slots.iter().flatten().find(|slot| {
+        let bp = &slot.breakpoint;
+        match bp.kind {
+            HwBreakpointKind::Execute => bp.addr == tf.sepc,
+            _ => bp.addr == stval,
+        }
+    });
+    let Some(slot) = hit.copied() else {
+        return false;
+    };
+    let handler = *HW_BREAKPOINT_HANDLER.lock();
+    match handler {
+        Some(f) => f(tf, &slot.breakpoint),
+        None => {
+            warn!("Unhandled hardware breakpoint {:x?}", slot.breakpoint);
+            let _ = uninstall_hw_breakpoint(slot.breakpoint.slot);
+        }
+    }
+    true
+}
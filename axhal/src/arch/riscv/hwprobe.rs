@@ -0,0 +1,131 @@
+//! The data of the `riscv_hwprobe` syscall.
+//!
+//! [`hwprobe`] answers the key/value pairs of the user, for a set of CPUs,
+//! with the values common to all of them; the syscall layer only copies the
+//! pairs from and to the user.
+
+use crate::cpu::CpuMask;
+
+use super::{cpu_has_on, cpu_hwcap, Feature};
+
+/// The keys of `riscv_hwprobe`, as in `<asm/hwprobe.h>`.
+#[allow(missing_docs)]
+pub mod key {
+    pub const MVENDORID: i64 = 0;
+    pub const MARCHID: i64 = 1;
+    pub const MIMPID: i64 = 2;
+    pub const BASE_BEHAVIOR: i64 = 3;
+    pub const IMA_EXT_0: i64 = 4;
+    pub const CPUPERF_0: i64 = 5;
+    pub const ZICBOZ_BLOCK_SIZE: i64 = 6;
+    pub const HIGHEST_VIRT_ADDRESS: i64 = 7;
+    pub const TIME_CSR_FREQ: i64 = 8;
+}
+
+/// The user ABI is the IMA one of the Linux kernel.
+const BASE_BEHAVIOR_IMA: u64 = 1 << 0;
+
+const IMA_FD: u64 = 1 << 0;
+const IMA_C: u64 = 1 << 1;
+const IMA_V: u64 = 1 << 2;
+const EXT_ZBA: u64 = 1 << 3;
+const EXT_ZBB: u64 = 1 << 4;
+const EXT_ZBS: u64 = 1 << 5;
+const EXT_ZICBOZ: u64 = 1 << 6;
+const EXT_ZIHINTPAUSE: u64 = 1 << 36;
+
+/// The speed of the misaligned accesses is unknown.
+const MISALIGNED_UNKNOWN: u64 = 0;
+
+/// A key/value pair of `riscv_hwprobe`.
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct HwprobePair {
+    /// The key, set to -1 if it is unknown.
+    pub key: i64,
+    /// The value, 0 for an unknown key.
+    pub value: u64,
+}
+
+/// Returns the `IMA_EXT_0` bits of the CPU `cpu_id`.
+fn ima_ext_0(cpu_id: usize) -> u64 {
+    let hwcap = cpu_hwcap(cpu_id);
+    let letter = |c: u8| hwcap & (1 << (c - b'a')) != 0;
+    let mut ext = 0;
+    if letter(b'f') && letter(b'd') {
+        ext |= IMA_FD;
+    }
+    if letter(b'c') {
+        ext |= IMA_C;
+    }
+    if letter(b'v') {
+        ext |= IMA_V;
+    }
+    for (feature, bit) in [
+        (Feature::Zba, EXT_ZBA),
+        (Feature::Zbb, EXT_ZBB),
+        (Feature::Zbs, EXT_ZBS),
+        (Feature::Zicboz, EXT_ZICBOZ),
+        (Feature::Zihintpause, EXT_ZIHINTPAUSE),
+    ] {
+        if cpu_has_on(cpu_id, feature) {
+            ext |= bit;
+        }
+    }
+    ext
+}
+
+/// Returns the value of `key` on the CPU `cpu_id`, and whether it is a
+/// bitmask (combined with AND over the CPUs) or a plain value (only kept if
+/// all CPUs agree).
+fn probe_cpu(key: i64, cpu_id: usize) -> Option<(u64, bool)> {
+    let value = match key {
+        key::MVENDORID => super::sbi::mvendorid() as u64,
+        key::MARCHID => super::sbi::marchid() as u64,
+        key::MIMPID => super::sbi::mimpid() as u64,
+        key::BASE_BEHAVIOR => return Some((BASE_BEHAVIOR_IMA, true)),
+        key::IMA_EXT_0 => return Some((ima_ext_0(cpu_id), true)),
+        key::CPUPERF_0 => MISALIGNED_UNKNOWN,
+        key::ZICBOZ_BLOCK_SIZE => match cpu_has_on(cpu_id, Feature::Zicboz) {
+            true => super::cboz_block_size() as u64,
+            false => 0,
+        },
+        key::HIGHEST_VIRT_ADDRESS => super::task_size() as u64 - 1,
+        key::TIME_CSR_FREQ => crate::time::timer_frequency(),
+        _ => return None,
+    };
+    Some((value, false))
+}
+
+/// Fills the values of `pairs` for the CPUs of `cpus`, or all the online
+/// CPUs if it is empty.
+///
+/// The bitmask keys (e.g. [`key::IMA_EXT_0`]) get the bits of all the CPUs,
+/// the other ones their value if all the CPUs have the same, -1 otherwise.
+/// The unknown keys are set to -1, with a value of 0.
+///
+/// The SBI reports the machine IDs of the calling hart only, which are
+/// assumed to be the same on all harts.
+pub fn hwprobe(pairs: &mut [HwprobePair], cpus: &CpuMask) {
+    let online = crate::cpu::online_cpus();
+    let cpus = if cpus.is_empty() { &online } else { cpus };
+    for pair in pairs {
+        let mut value = None;
+        for cpu_id in cpus.iter() {
+            let Some((cpu_value, bitmask)) = probe_cpu(pair.key, cpu_id) else {
+                value = None;
+                break;
+            };
+            value = Some(match value {
+                None => cpu_value,
+                Some(value) if bitmask => value & cpu_value,
+                Some(value) if value == cpu_value => value,
+                Some(_) => u64::MAX,
+            });
+        }
+        match value {
+            Some(value) => pair.value = value,
+            None => *pair = HwprobePair { key: -1, value: 0 },
+        }
+    }
+}
@@ -0,0 +1,426 @@
+//! The hypervisor extension (H): running guests in VS-mode.
+//!
+//! [`init_hypervisor`] sets the delegation to VS-mode on the current hart.
+//! [`run_guest`] enters a vCPU with `sret` (`hstatus.SPV` set) and returns
+//! on the next trap from the guest, decoded as a [`GuestExit`]. While the
+//! guest runs, `stvec` points to the exit path, so all the traps from V=1
+//! (including the host interrupts, which are always enabled there) come
+//! back to [`run_guest`]. An interrupt is taken by the host trap handler as
+//! soon as [`run_guest`] restores the `sstatus` of the host.
+//!
+//! The VS-level CSRs ([`VsCsrs`]) and the G-stage translation ([`set_hgatp`](super::set_hgatp))
+//! are switched by the kernel when another vCPU or guest runs on the hart,
+//! as the FP and vector registers of the guest.
+
+use axerrno::LinuxError;
+
+use super::cpufeature::{cpu_has, Feature};
+use super::gstage::{hfence_gvma_all, GuestPageFaultInfo};
+use super::{GeneralRegisters, SR_FS_INITIAL, SR_SPP};
+
+include_asm_marcos!();
+
+const CSR_HSTATUS: u16 = 0x600;
+const CSR_HEDELEG: u16 = 0x602;
+const CSR_HIDELEG: u16 = 0x603;
+const CSR_HTIMEDELTA: u16 = 0x605;
+const CSR_HCOUNTEREN: u16 = 0x606;
+const CSR_HVIP: u16 = 0x645;
+pub(super) const CSR_HGATP: u16 = 0x680;
+const CSR_VSSTATUS: u16 = 0x200;
+const CSR_VSIE: u16 = 0x204;
+const CSR_VSTVEC: u16 = 0x205;
+const CSR_VSSCRATCH: u16 = 0x240;
+const CSR_VSEPC: u16 = 0x241;
+const CSR_VSCAUSE: u16 = 0x242;
+const CSR_VSTVAL: u16 = 0x243;
+const CSR_VSATP: u16 = 0x280;
+
+/// `hstatus.GVA`: `stval` is a guest virtual address.
+pub const HSTATUS_GVA: usize = 1 << 6;
+/// `hstatus.SPV`: the trap came from V=1, `sret` returns to it.
+pub const HSTATUS_SPV: usize = 1 << 7;
+/// `hstatus.SPVP`: the privilege of the guest (VS), for `hlv`/`hsv`.
+pub const HSTATUS_SPVP: usize = 1 << 8;
+/// `hstatus.VSXL` for a 64-bit VS-mode.
+pub const HSTATUS_VSXL_64: usize = 2 << 32;
+
+/// The exceptions handled by the guest itself: misaligned fetch,
+/// breakpoint, ecall from VU-mode, and the page faults of the VS-stage.
+const HEDELEG: usize = 1 << 0 | 1 << 3 | 1 << 8 | 1 << 12 | 1 << 13 | 1 << 15;
+/// The VS-level interrupts: software, timer and external.
+const HIDELEG: usize = 1 << 2 | 1 << 6 | 1 << 10;
+
+macro_rules! csr_read {
+    ($csr:expr) => {{
+        let value: usize;
+        unsafe { core::arch::asm!("csrr {}, {csr}", out(reg) value, csr = const $csr) };
+        value
+    }};
+}
+
+macro_rules! csr_write {
+    ($csr:expr, $value:expr) => {
+        unsafe { core::arch::asm!("csrw {csr}, {}", in(reg) $value, csr = const $csr) }
+    };
+}
+
+/// Returns whether all CPUs have the hypervisor extension.
+pub fn has_hypervisor() -> bool {
+    cpu_has(Feature::H)
+}
+
+/// Prepares the current hart to run guests: the delegation of the traps of
+/// the guests to VS-mode, the counters they can read, and no G-stage.
+///
+/// Returns [`LinuxError::ENODEV`] without the hypervisor extension.
+pub fn init_hypervisor() -> Result<(), LinuxError> {
+    if !has_hypervisor() {
+        return Err(LinuxError::ENODEV);
+    }
+    csr_write!(CSR_HEDELEG, HEDELEG);
+    csr_write!(CSR_HIDELEG, HIDELEG);
+    csr_write!(CSR_HCOUNTEREN, u32::MAX as usize);
+    csr_write!(CSR_HTIMEDELTA, 0usize);
+    csr_write!(CSR_HVIP, 0usize);
+    csr_write!(CSR_HGATP, 0usize);
+    hfence_gvma_all();
+    Ok(())
+}
+
+/// The context of the host, saved by [`run_guest`].
+#[repr(C)]
+#[derive(Debug, Default, Clone)]
+struct HostContext {
+    regs: GeneralRegisters,
+    sstatus: usize,
+    hstatus: usize,
+    stvec: usize,
+    sscratch: usize,
+}
+
+/// The trap CSRs of the last exit of a guest.
+#[repr(C)]
+#[derive(Debug, Default, Clone, Copy)]
+pub struct GuestTrap {
+    /// `scause`.
+    pub scause: usize,
+    /// `stval`.
+    pub stval: usize,
+    /// `htval`: the guest physical address of a guest-page fault, >> 2.
+    pub htval: usize,
+    /// `htinst`: the transformed trapping instruction, or 0.
+    pub htinst: usize,
+}
+
+/// The state of a vCPU run by [`run_guest`].
+#[repr(C)]
+#[derive(Debug, Default, Clone)]
+pub struct GuestContext {
+    /// The general registers of the guest.
+    pub regs: GeneralRegisters,
+    /// The PC of the guest.
+    pub sepc: usize,
+    /// The `sstatus` of the entry: its `SPP` is the privilege of the guest,
+    /// VS (set) or VU.
+    pub sstatus: usize,
+    /// The `hstatus` of the guest, with `SPV` set.
+    pub hstatus: usize,
+    host: HostContext,
+    /// The trap of the last exit.
+    pub trap: GuestTrap,
+}
+
+impl GuestContext {
+    /// Creates the context of a 64-bit vCPU starting in VS-mode at `entry`,
+    /// with `a0` and `a1` (e.g. the hart ID and the device tree of the
+    /// guest, as an SBI firmware would).
+    pub fn new(entry: usize, a0: usize, a1: usize) -> Self {
+        Self {
+            regs: GeneralRegisters {
+                a0,
+                a1,
+                ..Default::default()
+            },
+            sepc: entry,
+            sstatus: SR_SPP | SR_FS_INITIAL,
+            hstatus: HSTATUS_SPV | HSTATUS_SPVP | HSTATUS_VSXL_64,
+            ..Default::default()
+        }
+    }
+}
+
+/// The reason of an exit of a guest.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum GuestExit {
+    /// A host interrupt, with its cause (already handled by the host when
+    /// [`run_guest`] returns, if it runs with the interrupts enabled).
+    Interrupt(usize),
+    /// An `ecall` from VS-mode, i.e. an SBI call. `sepc` still points to the
+    /// `ecall`.
+    SupervisorEcall,
+    /// A fault of the G-stage translation.
+    GuestPageFault(GuestPageFaultInfo),
+    /// A virtual instruction exception (e.g. a `wfi` or a CSR access the
+    /// guest may not do), with the instruction if `stval` has it.
+    VirtualInstruction {
+        /// The instruction, or 0.
+        insn: usize,
+    },
+    /// Another exception, not delegated to the guest.
+    Exception {
+        /// `scause`.
+        cause: usize,
+        /// `stval`.
+        stval: usize,
+    },
+}
+
+impl GuestExit {
+    /// Decodes the exit of `ctx`.
+    pub fn from_trap(ctx: &GuestContext) -> Self {
+        let trap = &ctx.trap;
+        let interrupt = 1 << (usize::BITS - 1);
+        if trap.scause & interrupt != 0 {
+            return Self::Interrupt(trap.scause & !interrupt);
+        }
+        match trap.scause {
+            10 => Self::SupervisorEcall,
+            22 => Self::VirtualInstruction { insn: trap.stval },
+            _ => match GuestPageFaultInfo::from_trap(trap, ctx.hstatus) {
+                Some(info) => Self::GuestPageFault(info),
+                None => Self::Exception {
+                    cause: trap.scause,
+                    stval: trap.stval,
+                },
+            },
+        }
+    }
+}
+
+/// Runs the vCPU of `ctx` on the current hart until its next trap to
+/// HS-mode, and returns why.
+///
+/// The G-stage and the VS-level CSRs of the vCPU must be loaded (see
+/// [`set_hgatp`](super::set_hgatp) and [`VsCsrs::restore`]). An `ecall` or an emulated
+/// instruction is not skipped, the caller advances `sepc`.
+pub fn run_guest(ctx: &mut GuestContext) -> GuestExit {
+    unsafe { __guest_run(ctx) };
+    GuestExit::from_trap(ctx)
+}
+
+extern "C" {
+    fn __guest_run(ctx: &mut GuestContext);
+}
+
+core::arch::global_asm!(
+    r"
+    .section .text
+    .balign 4
+    .global __guest_run
+    __guest_run:
+    // The host callee-saved registers.
+    STR     ra, a0, ({host}+0)
+    STR     sp, a0, ({host}+1)
+    STR     gp, a0, ({host}+2)
+    STR     tp, a0, ({host}+3)
+    STR     s0, a0, ({host}+7)
+    STR     s1, a0, ({host}+8)
+    STR     s2, a0, ({host}+17)
+    STR     s3, a0, ({host}+18)
+    STR     s4, a0, ({host}+19)
+    STR     s5, a0, ({host}+20)
+    STR     s6, a0, ({host}+21)
+    STR     s7, a0, ({host}+22)
+    STR     s8, a0, ({host}+23)
+    STR     s9, a0, ({host}+24)
+    STR     s10, a0, ({host}+25)
+    STR     s11, a0, ({host}+26)
+
+    // The CSRs, `sstatus` first: the guest one masks the host interrupts
+    // until the `sret`.
+    csrr    t0, sstatus
+    STR     t0, a0, ({host}+31)
+    LDR     t0, a0, 32
+    csrw    sstatus, t0
+    LDR     t0, a0, 33
+    csrrw   t0, {hstatus}, t0
+    STR     t0, a0, ({host}+32)
+    la      t0, .Lguest_exit
+    csrrw   t0, stvec, t0
+    STR     t0, a0, ({host}+33)
+    csrrw   t0, sscratch, a0
+    STR     t0, a0, ({host}+34)
+    LDR     t0, a0, 31
+    csrw    sepc, t0
+
+    // The guest registers, `a0` last.
+    LDR     ra, a0, 0
+    LDR     sp, a0, 1
+    LDR     gp, a0, 2
+    LDR     tp, a0, 3
+    LDR     t0, a0, 4
+    LDR     t1, a0, 5
+    LDR     t2, a0, 6
+    LDR     s0, a0, 7
+    LDR     s1, a0, 8
+    LDR     a1, a0, 10
+    LDR     a2, a0, 11
+    LDR     a3, a0, 12
+    LDR     a4, a0, 13
+    LDR     a5, a0, 14
+    LDR     a6, a0, 15
+    LDR     a7, a0, 16
+    LDR     s2, a0, 17
+    LDR     s3, a0, 18
+    LDR     s4, a0, 19
+    LDR     s5, a0, 20
+    LDR     s6, a0, 21
+    LDR     s7, a0, 22
+    LDR     s8, a0, 23
+    LDR     s9, a0, 24
+    LDR     s10, a0, 25
+    LDR     s11, a0, 26
+    LDR     t3, a0, 27
+    LDR     t4, a0, 28
+    LDR     t5, a0, 29
+    LDR     t6, a0, 30
+    LDR     a0, a0, 9
+    sret
+
+    .balign 4
+.Lguest_exit:
+    csrrw   a0, sscratch, a0
+    STR     ra, a0, 0
+    STR     sp, a0, 1
+    STR     gp, a0, 2
+    STR     tp, a0, 3
+    STR     t0, a0, 4
+    STR     t1, a0, 5
+    STR     t2, a0, 6
+    STR     s0, a0, 7
+    STR     s1, a0, 8
+    STR     a1, a0, 10
+    STR     a2, a0, 11
+    STR     a3, a0, 12
+    STR     a4, a0, 13
+    STR     a5, a0, 14
+    STR     a6, a0, 15
+    STR     a7, a0, 16
+    STR     s2, a0, 17
+    STR     s3, a0, 18
+    STR     s4, a0, 19
+    STR     s5, a0, 20
+    STR     s6, a0, 21
+    STR     s7, a0, 22
+    STR     s8, a0, 23
+    STR     s9, a0, 24
+    STR     s10, a0, 25
+    STR     s11, a0, 26
+    STR     t3, a0, 27
+    STR     t4, a0, 28
+    STR     t5, a0, 29
+    STR     t6, a0, 30
+    csrr    t0, sscratch
+    STR     t0, a0, 9
+
+    csrr    t0, sepc
+    STR     t0, a0, 31
+    csrr    t0, sstatus
+    STR     t0, a0, 32
+    csrr    t0, scause
+    STR     t0, a0, ({trap}+0)
+    csrr    t0, stval
+    STR     t0, a0, ({trap}+1)
+    csrr    t0, {htval}
+    STR     t0, a0, ({trap}+2)
+    csrr    t0, {htinst}
+    STR     t0, a0, ({trap}+3)
+
+    LDR     t0, a0, ({host}+32)
+    csrrw   t0, {hstatus}, t0
+    STR     t0, a0, 33
+    LDR     t0, a0, ({host}+33)
+    csrw    stvec, t0
+    LDR     t0, a0, ({host}+34)
+    csrw    sscratch, t0
+    LDR     t0, a0, ({host}+31)
+    csrw    sstatus, t0
+
+    LDR     ra, a0, ({host}+0)
+    LDR     sp, a0, ({host}+1)
+    LDR     gp, a0, ({host}+2)
+    LDR     tp, a0, ({host}+3)
+    LDR     s0, a0, ({host}+7)
+    LDR     s1, a0, ({host}+8)
+    LDR     s2, a0, ({host}+17)
+    LDR     s3, a0, ({host}+18)
+    LDR     s4, a0, ({host}+19)
+    LDR     s5, a0, ({host}+20)
+    LDR     s6, a0, ({host}+21)
+    LDR     s7, a0, ({host}+22)
+    LDR     s8, a0, ({host}+23)
+    LDR     s9, a0, ({host}+24)
+    LDR     s10, a0, ({host}+25)
+    LDR     s11, a0, ({host}+26)
+    ret
+    ",
+    host = const 34,
+    trap = const 34 + 35,
+    hstatus = const CSR_HSTATUS,
+    htval = const 0x643,
+    htinst = const 0x64a,
+);
+
+static_assertions::const_assert_eq!(
+    core::mem::size_of::<GuestContext>(),
+    (34 + 35 + 4) * core::mem::size_of::<usize>()
+);
+
+/// The VS-level CSRs of a vCPU, and its virtual interrupts and time.
+#[derive(Debug, Default, Clone, Copy)]
+#[allow(missing_docs)]
+pub struct VsCsrs {
+    pub vsstatus: usize,
+    pub vsie: usize,
+    pub vstvec: usize,
+    pub vsscratch: usize,
+    pub vsepc: usize,
+    pub vscause: usize,
+    pub vstval: usize,
+    pub vsatp: usize,
+    /// The pending VS-level interrupts injected by the host.
+    pub hvip: usize,
+    /// The offset of the guest `time` from the host one.
+    pub htimedelta: usize,
+}
+
+impl VsCsrs {
+    /// Saves the CSRs of the vCPU that ran last on the current hart.
+    pub fn save(&mut self) {
+        self.vsstatus = csr_read!(CSR_VSSTATUS);
+        self.vsie = csr_read!(CSR_VSIE);
+        self.vstvec = csr_read!(CSR_VSTVEC);
+        self.vsscratch = csr_read!(CSR_VSSCRATCH);
+        self.vsepc = csr_read!(CSR_VSEPC);
+        self.vscause = csr_read!(CSR_VSCAUSE);
+        self.vstval = csr_read!(CSR_VSTVAL);
+        self.vsatp = csr_read!(CSR_VSATP);
+        self.hvip = csr_read!(CSR_HVIP);
+        self.htimedelta = csr_read!(CSR_HTIMEDELTA);
+    }
+
+    /// Loads the CSRs of a vCPU on the current hart, before [`run_guest`].
+    pub fn restore(&self) {
+        csr_write!(CSR_VSSTATUS, self.vsstatus);
+        csr_write!(CSR_VSIE, self.vsie);
+        csr_write!(CSR_VSTVEC, self.vstvec);
+        csr_write!(CSR_VSSCRATCH, self.vsscratch);
+        csr_write!(CSR_VSEPC, self.vsepc);
+        csr_write!(CSR_VSCAUSE, self.vscause);
+        csr_write!(CSR_VSTVAL, self.vstval);
+        csr_write!(CSR_VSATP, self.vsatp);
+        csr_write!(CSR_HVIP, self.hvip);
+        csr_write!(CSR_HTIMEDELTA, self.htimedelta);
+    }
+}
@@ -0,0 +1,124 @@
+//! Emulation of the illegal instructions.
+//!
+//! On an illegal instruction exception, [`handle_illegal_instruction`]
+//! fetches the instruction and passes it to the first registered emulator
+//! whose pattern matches it, e.g. to run the binaries built for a richer
+//! ISA profile (Zbb) on a core without the extension. The reads of `time`
+//! (`rdtime`), which some firmware does not emulate, and the atomic
+//! instructions of the user binaries on cores without the A extension are
+//! handled by default.
+
+use spinbase::SpinNoIrq;
+
+use super::misaligned::write_gpr;
+use super::TrapFrame;
+
+/// The `si_code` of `SIGILL`: illegal opcode.
+pub const ILL_ILLOPC: i32 = 1;
+
+/// The maximum number of emulators registered with
+/// [`register_insn_emulator`].
+const MAX_INSN_EMULATORS: usize = 16;
+
+/// The type of an instruction emulator. It receives the trap frame and the
+/// instruction, and returns whether it has emulated it; the instruction is
+/// then skipped.
+pub type InsnEmulator = fn(&mut TrapFrame, u32) -> bool;
+
+#[derive(Clone, Copy)]
+struct InsnEmulatorEntry {
+    mask: u32,
+    value: u32,
+    emulator: InsnEmulator,
+}
+
+static INSN_EMULATORS: SpinNoIrq<[Option<InsnEmulatorEntry>; MAX_INSN_EMULATORS]> =
+    SpinNoIrq::new([None; MAX_INSN_EMULATORS]);
+
+/// An instruction that could not be emulated, for the trap handler to
+/// deliver `SIGILL` to the task.
+#[derive(Debug, Clone, Copy)]
+pub struct IllegalInstruction {
+    /// The address of the instruction (`si_addr`).
+    pub addr: usize,
+    /// The instruction, if it could be fetched.
+    pub insn: Option<u32>,
+    /// The `si_code`, e.g. [`ILL_ILLOPC`].
+    pub code: i32,
+}
+
+/// Registers `emulator` for the instructions `insn` such that
+/// `insn & mask == value`. Returns `false` if the table is full.
+///
+/// The emulators are tried in the order of registration, after the default
+/// ones.
+pub fn register_insn_emulator(mask: u32, value: u32, emulator: InsnEmulator) -> bool {
+    let mut emulators = INSN_EMULATORS.lock();
+    let Some(slot) = emulators.iter_mut().find(|slot| slot.is_none()) else {
+        warn!("Too many instruction emulators");
+        return false;
+    };
+    *slot = Some(InsnEmulatorEntry {
+        mask,
+        value,
+        emulator,
+    });
+    true
+}
+
+/// `csrrs rd, <csr>, x0`, i.e. `csrr rd, <csr>`, without `rd`.
+const fn csrr(csr: u32) -> u32 {
+    (csr << 20) | (0b010 << 12) | 0x73
+}
+
+const CSRR_MASK: u32 = 0xffff_f07f;
+const CSR_TIME: u32 = 0xc01;
+#[cfg(target_arch = "riscv32")]
+const CSR_TIMEH: u32 = 0xc81;
+
+/// Writes `value` to the destination register of `insn` in `tf`.
+fn write_rd(tf: &mut TrapFrame, insn: u32, value: usize) {
+    write_gpr(tf, ((insn >> 7) & 0x1f) as usize, value);
+}
+
+/// Emulates `rdtime` (and `rdtimeh` on RV32).
+fn emulate_rdtime(tf: &mut TrapFrame, insn: u32) -> bool {
+    let ticks = crate::time::current_ticks();
+    match insn & CSRR_MASK {
+        x if x == csrr(CSR_TIME) => write_rd(tf, insn, ticks as usize),
+        #[cfg(target_arch = "riscv32")]
+        x if x == csrr(CSR_TIMEH) => write_rd(tf, insn, (ticks >> 32) as usize),
+        _ => return false,
+    }
+    true
+}
+
+/// Handles an illegal instruction exception (`scause` 2).
+///
+/// The instruction is passed to the default emulators and then to the ones
+/// registered with [`register_insn_emulator`]; if one emulates it, it is
+/// skipped. Otherwise the information for `SIGILL` is returned.
+pub fn handle_illegal_instruction(tf: &mut TrapFrame) -> Result<(), IllegalInstruction> {
+    let from_user = tf.from_user();
+    let insn = super::fetch_instruction(tf.sepc, from_user);
+    let illegal = IllegalInstruction {
+        addr: tf.sepc,
+        insn,
+        code: ILL_ILLOPC,
+    };
+    let insn = insn.ok_or(illegal)?;
+    // Do not hold the lock while the emulator runs.
+    let emulators = *INSN_EMULATORS.lock();
+    let emulated = emulate_rdtime(tf, insn)
+        || super::atomic_emul::emulate_atomic(tf, insn)
+        || emulators
+            .iter()
+            .flatten()
+            .filter(|entry| insn & entry.mask == entry.value)
+            .any(|entry| (entry.emulator)(tf, insn));
+    if !emulated {
+        return Err(illegal);
+    }
+    tf.sepc += if insn & 0b11 == 0b11 { 4 } else { 2 };
+    Ok(())
+}
@@ -0,0 +1,112 @@
+//! The header of the Linux RISC-V boot image (`Documentation/riscv/boot-image-header.rst`).
+//!
+//! The kernel image starts with the same 64-byte header as Linux, in
+//! `.head.text` at the start of `.text`, so that the loaders of Linux
+//! (U-Boot `booti`, QEMU `-kernel`, a kexec of another kernel) load the
+//! flat binary at the right offset from the start of the RAM, and jump to
+//! its first instruction with `a0` the hart ID and `a1` the physical address
+//! of the FDT. The first instruction jumps to `_start`, which runs with
+//! paging off at the load address. With the `efi` feature, the EFI stub
+//! emits the header instead, with the magic of a PE image.
+//!
+//! Only the header is here: `_start`, with its initial page tables, is the
+//! boot code of the `arch_boot` crate, which links the kernel at a fixed
+//! address. The image is thus not relocatable: `text_offset` is the offset
+//! of `KERNEL_BASE_PADDR` in the RAM, and the loaders must honor it (as
+//! `booti` and `-kernel` do). Loading it anywhere else needs a relocatable
+//! `_start` in `arch_boot`, which this header does not provide.
+//!
+//! [`RiscvImageHeader::parse`] reads the header of another image, e.g. to
+//! load it with [`kexec_load`](super::kexec_load).
+
+use axerrno::LinuxError;
+
+/// `"RISCV\0\0\0"`, the deprecated magic of the header.
+pub const RISCV_IMAGE_MAGIC: u64 = 0x0000_0056_4353_4952;
+/// `"RSC\x05"`, the magic of the header.
+pub const RISCV_IMAGE_MAGIC2: u32 = 0x0543_5352;
+
+/// The version of the header, 0.2.
+pub const RISCV_HEADER_VERSION: u32 = 2;
+
+/// The offset of the image from the start of the RAM.
+pub(super) const TEXT_OFFSET: usize = axconfig::KERNEL_BASE_PADDR - axconfig::PHYS_MEMORY_BASE;
+
+// `image_size` is the size of the loaded image, with the `.bss`; `flags` is
+// 0, a little-endian kernel.
+#[cfg(not(feature = "efi"))]
+core::arch::global_asm!(
+    r#"
+    .section .head.text, "ax"
+    .global _image_header
+_image_header:
+    .option push
+    .option norvc
+    j       _start
+    .option pop
+    .word   0
+    .dword  {text_offset}
+    .dword  _ekernel - _skernel
+    .dword  0
+    .word   {version}
+    .word   0
+    .dword  0
+    .ascii  "RISCV\0\0\0"
+    .ascii  "RSC\x05"
+    .word   0
+    .previous
+    "#,
+    text_offset = const TEXT_OFFSET,
+    version = const RISCV_HEADER_VERSION,
+);
+
+/// The header of a Linux RISC-V boot image.
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct RiscvImageHeader {
+    /// The first instruction, a jump to the entry.
+    pub code0: u32,
+    /// The second instruction.
+    pub code1: u32,
+    /// The offset of the image from the start of the RAM, where it must be
+    /// loaded (aligned to 2M on RV64).
+    pub text_offset: u64,
+    /// The size of the loaded image, with its `.bss`.
+    pub image_size: u64,
+    /// The flags, bit 0 set for a big-endian kernel.
+    pub flags: u64,
+    /// The version of the header, the major number in bits 31:16.
+    pub version: u32,
+    res1: u32,
+    res2: u64,
+    /// [`RISCV_IMAGE_MAGIC`].
+    pub magic: u64,
+    /// [`RISCV_IMAGE_MAGIC2`].
+    pub magic2: u32,
+    res3: u32,
+}
+
+static_assertions::const_assert_eq!(core::mem::size_of::<RiscvImageHeader>(), 64);
+
+impl RiscvImageHeader {
+    /// Reads the header at the start of `image`.
+    ///
+    /// Returns [`LinuxError::EINVAL`] if `image` is too short, or does not
+    /// have the magic of the header.
+    pub fn parse(image: &[u8]) -> Result<Self, LinuxError> {
+        if image.len() < core::mem::size_of::<Self>() {
+            return Err(LinuxError::EINVAL);
+        }
+        let header = unsafe { image.as_ptr().cast::<Self>().read_unaligned() };
+        if header.magic2 != RISCV_IMAGE_MAGIC2 && header.magic != RISCV_IMAGE_MAGIC {
+            return Err(LinuxError::EINVAL);
+        }
+        Ok(header)
+    }
+
+    /// Returns the size of the image to reserve, at least `file_size` (the
+    /// headers of the version 0.1 may have no `image_size`).
+    pub fn load_size(&self, file_size: usize) -> usize {
+        (self.image_size as usize).max(file_size)
+    }
+}
@@ -0,0 +1,624 @@
+//! RISC-V IOMMU (the ratified 1.0 specification).
+//!
+//! [`init_iommu`] finds the `riscv,iommu` node of the device tree, sets up
+//! its command and fault queues, and its device directory table (DDT) with
+//! as many levels as it supports. The memory comes from the allocator given
+//! to it, and is assumed to be coherent with the IOMMU.
+//!
+//! An [`IommuDomain`] is an I/O address space: a first-stage (Sv39/48/57,
+//! tagged with a PSCID) or a second-stage (Sv39x4/48x4/57x4, tagged with a
+//! GSCID, e.g. the guest physical addresses of a passthrough device) page
+//! table, filled with [`iommu_map`] and [`iommu_unmap`] in 4 KiB pages. The
+//! devices are attached to it by their device ID, given by the `iommus` of
+//! their node ([`dt::iommu_device_id`]) or the `iommu-map` of the PCI host
+//! bridge ([`dt::pci_iommu_device_id`]). A device that is not attached
+//! cannot do DMA: its accesses are reported as faults, read with
+//! [`iommu_handle_faults`].
+
+use core::sync::atomic::{AtomicU32, Ordering};
+use core::time::Duration;
+
+use axerrno::LinuxError;
+use lazy_init::LazyInit;
+use memory_addr::{PhysAddr, VirtAddr};
+use spinbase::SpinNoIrq;
+
+use super::page_walk::{PTE_A, PTE_D, PTE_PPN_MASK, PTE_PPN_SHIFT, PTE_R, PTE_U, PTE_V, PTE_W};
+use crate::mem::{phys_to_virt, MemRegionFlags, PAGE_SIZE_4K};
+use crate::platform::dt;
+
+const REG_CAPABILITIES: usize = 0x00;
+const REG_DDTP: usize = 0x10;
+const REG_CQB: usize = 0x18;
+const REG_CQH: usize = 0x20;
+const REG_CQT: usize = 0x24;
+const REG_FQB: usize = 0x28;
+const REG_FQH: usize = 0x30;
+const REG_FQT: usize = 0x34;
+const REG_CQCSR: usize = 0x48;
+const REG_FQCSR: usize = 0x4c;
+
+const CAP_SV39: u64 = 1 << 9;
+const CAP_SV48: u64 = 1 << 10;
+const CAP_SV57: u64 = 1 << 11;
+const CAP_SV39X4: u64 = 1 << 17;
+const CAP_SV48X4: u64 = 1 << 18;
+const CAP_SV57X4: u64 = 1 << 19;
+/// Extended (64-byte) device contexts, for the MSI page tables.
+const CAP_MSI_FLAT: u64 = 1 << 22;
+
+const DDTP_MODE_MASK: u64 = 0xf;
+const DDTP_MODE_1LVL: u64 = 2;
+const DDTP_MODE_2LVL: u64 = 3;
+const DDTP_MODE_3LVL: u64 = 4;
+const DDTP_BUSY: u64 = 1 << 4;
+
+/// The bits of `cqcsr` and `fqcsr`.
+const QCSR_EN: u32 = 1 << 0;
+const QCSR_MF: u32 = 1 << 8;
+const CQCSR_CMD_TO: u32 = 1 << 9;
+const CQCSR_CMD_ILL: u32 = 1 << 10;
+const FQCSR_OF: u32 = 1 << 9;
+const QCSR_ON: u32 = 1 << 16;
+
+/// One page for each queue.
+const CQ_ENTRIES: u32 = (PAGE_SIZE_4K / 16) as u32;
+const FQ_ENTRIES: u32 = (PAGE_SIZE_4K / 32) as u32;
+
+const DC_TC_V: u64 = 1 << 0;
+const ATP_MODE_SHIFT: u32 = 60;
+const IOHGATP_GSCID_SHIFT: u32 = 44;
+const TA_PSCID_SHIFT: u32 = 12;
+
+const CMD_IOTINVAL_VMA: u64 = 1;
+const CMD_IOTINVAL_GVMA: u64 = 1 | (1 << 7);
+const CMD_IOFENCE_C: u64 = 2;
+const CMD_IODIR_INVAL_DDT: u64 = 3;
+const CMD_AV: u64 = 1 << 10;
+const CMD_IOFENCE_PR: u64 = 1 << 12;
+const CMD_IOFENCE_PW: u64 = 1 << 13;
+const CMD_PSCV: u64 = 1 << 32;
+const CMD_GV: u64 = 1 << 33;
+const CMD_DV: u64 = 1 << 33;
+
+/// Above this number of pages, the whole domain is invalidated.
+const IOTINVAL_PAGES_MAX: usize = 64;
+
+/// How long the IOMMU may take to process the commands.
+const TIMEOUT: Duration = Duration::from_millis(100);
+
+/// The allocator of the IOMMU memory: it returns the physical address of
+/// `num_pages` contiguous pages of the linear mapping (a power of two),
+/// aligned to their size.
+pub type IommuAllocFn = fn(num_pages: usize) -> Option<PhysAddr>;
+
+/// The translation stage of an [`IommuDomain`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IommuStage {
+    /// First stage: I/O virtual addresses to physical addresses.
+    First,
+    /// Second stage: guest physical addresses to physical addresses.
+    Second,
+}
+
+struct Queues {
+    cq: PhysAddr,
+    cq_tail: u32,
+    fq: PhysAddr,
+}
+
+struct Iommu {
+    regs: VirtAddr,
+    caps: u64,
+    alloc_pages: IommuAllocFn,
+    ddt_root: PhysAddr,
+    ddt_levels: usize,
+    /// The size of a device context, 32 or 64 bytes.
+    dc_size: usize,
+    queues: SpinNoIrq<Queues>,
+}
+
+static IOMMU: LazyInit<Iommu> = LazyInit::new();
+
+/// The last PSCID or GSCID, 0 is not used.
+static LAST_DOMAIN_ID: AtomicU32 = AtomicU32::new(0);
+
+fn alloc_zeroed(alloc_pages: IommuAllocFn, num_pages: usize) -> Result<PhysAddr, LinuxError> {
+    let paddr = alloc_pages(num_pages).ok_or(LinuxError::ENOMEM)?;
+    unsafe {
+        core::ptr::write_bytes(
+            phys_to_virt(paddr).as_mut_ptr(),
+            0,
+            num_pages * PAGE_SIZE_4K,
+        )
+    };
+    Ok(paddr)
+}
+
+/// Returns the PPN of `paddr` in the field at bit 10 of an entry or a
+/// register.
+fn ppn_field(paddr: PhysAddr) -> u64 {
+    ((paddr.as_usize() >> 12) << PTE_PPN_SHIFT) as u64
+}
+
+fn ppn_to_paddr(entry: usize) -> PhysAddr {
+    PhysAddr::from(((entry >> PTE_PPN_SHIFT) & PTE_PPN_MASK) << 12)
+}
+
+fn wait_for(mut done: impl FnMut() -> bool) -> Result<(), LinuxError> {
+    let deadline = crate::time::current_time() + TIMEOUT;
+    while !done() {
+        if crate::time::current_time() >= deadline {
+            return Err(LinuxError::ETIMEDOUT);
+        }
+        core::hint::spin_loop();
+    }
+    Ok(())
+}
+
+impl Iommu {
+    fn read32(&self, reg: usize) -> u32 {
+        unsafe { ((self.regs.as_usize() + reg) as *const u32).read_volatile() }
+    }
+
+    fn write32(&self, reg: usize, value: u32) {
+        unsafe { ((self.regs.as_usize() + reg) as *mut u32).write_volatile(value) }
+    }
+
+    fn read64(&self, reg: usize) -> u64 {
+        unsafe { ((self.regs.as_usize() + reg) as *const u64).read_volatile() }
+    }
+
+    fn write64(&self, reg: usize, value: u64) {
+        unsafe { ((self.regs.as_usize() + reg) as *mut u64).write_volatile(value) }
+    }
+
+    /// Sets the queues and the DDT of the IOMMU at `regs` up.
+    fn probe(regs: VirtAddr, alloc_pages: IommuAllocFn) -> Result<Self, LinuxError> {
+        let mut iommu = Self {
+            regs,
+            caps: 0,
+            alloc_pages,
+            ddt_root: PhysAddr::from(0),
+            ddt_levels: 0,
+            dc_size: 32,
+            queues: SpinNoIrq::new(Queues {
+                cq: PhysAddr::from(0),
+                cq_tail: 0,
+                fq: PhysAddr::from(0),
+            }),
+        };
+        iommu.caps = iommu.read64(REG_CAPABILITIES);
+        if iommu.caps & CAP_MSI_FLAT != 0 {
+            iommu.dc_size = 64;
+        }
+        wait_for(|| iommu.read64(REG_DDTP) & DDTP_BUSY == 0)?;
+
+        let cq = alloc_zeroed(alloc_pages, 1)?;
+        iommu.write64(REG_CQB, ppn_field(cq) | (CQ_ENTRIES.ilog2() - 1) as u64);
+        iommu.write32(REG_CQT, 0);
+        iommu.write32(REG_CQCSR, QCSR_EN);
+        wait_for(|| iommu.read32(REG_CQCSR) & QCSR_ON != 0)?;
+        let fq = alloc_zeroed(alloc_pages, 1)?;
+        iommu.write64(REG_FQB, ppn_field(fq) | (FQ_ENTRIES.ilog2() - 1) as u64);
+        iommu.write32(REG_FQH, 0);
+        iommu.write32(REG_FQCSR, QCSR_EN);
+        wait_for(|| iommu.read32(REG_FQCSR) & QCSR_ON != 0)?;
+        iommu.queues = SpinNoIrq::new(Queues { cq, cq_tail: 0, fq });
+
+        // The supported modes read back as written.
+        iommu.ddt_root = alloc_zeroed(alloc_pages, 1)?;
+        for (mode, levels) in [
+            (DDTP_MODE_3LVL, 3),
+            (DDTP_MODE_2LVL, 2),
+            (DDTP_MODE_1LVL, 1),
+        ] {
+            iommu.write64(REG_DDTP, ppn_field(iommu.ddt_root) | mode);
+            wait_for(|| iommu.read64(REG_DDTP) & DDTP_BUSY == 0)?;
+            if iommu.read64(REG_DDTP) & DDTP_MODE_MASK == mode {
+                iommu.ddt_levels = levels;
+                break;
+            }
+        }
+        if iommu.ddt_levels == 0 {
+            return Err(LinuxError::ENOTSUP);
+        }
+        let mut queues = iommu.queues.lock();
+        iommu.submit(&mut queues, [CMD_IODIR_INVAL_DDT, 0])?;
+        iommu.sync(&mut queues)?;
+        drop(queues);
+        Ok(iommu)
+    }
+
+    /// Queues the command `cmd`.
+    fn submit(&self, queues: &mut Queues, cmd: [u64; 2]) -> Result<(), LinuxError> {
+        let tail = queues.cq_tail;
+        let next = (tail + 1) % CQ_ENTRIES;
+        wait_for(|| self.read32(REG_CQH) != next)?;
+        let slot = (phys_to_virt(queues.cq).as_usize() + 16 * tail as usize) as *mut u64;
+        unsafe {
+            slot.write_volatile(cmd[0]);
+            slot.add(1).write_volatile(cmd[1]);
+            core::arch::asm!("fence w, o");
+        }
+        self.write32(REG_CQT, next);
+        queues.cq_tail = next;
+        Ok(())
+    }
+
+    /// Waits until the IOMMU has processed the queued commands, and their
+    /// effects are visible.
+    fn sync(&self, queues: &mut Queues) -> Result<(), LinuxError> {
+        self.submit(queues, [CMD_IOFENCE_C | CMD_IOFENCE_PR | CMD_IOFENCE_PW, 0])?;
+        let tail = queues.cq_tail;
+        let result = wait_for(|| {
+            self.read32(REG_CQH) == tail
+                || self.read32(REG_CQCSR) & (QCSR_MF | CQCSR_CMD_TO | CQCSR_CMD_ILL) != 0
+        });
+        let errors = self.read32(REG_CQCSR) & (QCSR_MF | CQCSR_CMD_TO | CQCSR_CMD_ILL);
+        if errors != 0 {
+            error!("IOMMU command queue error: cqcsr {:#x}", errors);
+            self.write32(REG_CQCSR, QCSR_EN | errors);
+            return Err(LinuxError::EIO);
+        }
+        result
+    }
+
+    /// Returns the index of `device_id` in a DDT table of `level`, 0 being
+    /// the leaf level of the device contexts.
+    fn ddi(&self, device_id: u32, level: usize) -> usize {
+        let leaf_bits = if self.dc_size == 64 { 6 } else { 7 };
+        match level {
+            0 => device_id as usize & ((1 << leaf_bits) - 1),
+            _ => (device_id as usize >> (leaf_bits + 9 * (level - 1))) & 0x1ff,
+        }
+    }
+
+    /// Returns the device context of `device_id`, allocating the tables on
+    /// the way if `alloc`.
+    fn device_context(&self, device_id: u32, alloc: bool) -> Result<*mut u64, LinuxError> {
+        let leaf_bits = if self.dc_size == 64 { 6 } else { 7 };
+        let id_bits = (leaf_bits + 9 * (self.ddt_levels - 1)).min(24);
+        if device_id >> id_bits != 0 {
+            return Err(LinuxError::EINVAL);
+        }
+        let mut table = self.ddt_root;
+        for level in (1..self.ddt_levels).rev() {
+            let entry = unsafe {
+                (phys_to_virt(table).as_usize() as *mut usize).add(self.ddi(device_id, level))
+            };
+            let mut value = unsafe { entry.read_volatile() };
+            if value & PTE_V == 0 {
+                if !alloc {
+                    return Err(LinuxError::ENOENT);
+                }
+                value = ppn_field(alloc_zeroed(self.alloc_pages, 1)?) as usize | PTE_V;
+                unsafe { entry.write_volatile(value) };
+            }
+            table = ppn_to_paddr(value);
+        }
+        let offset = self.ddi(device_id, 0) * self.dc_size;
+        Ok((phys_to_virt(table).as_usize() + offset) as *mut u64)
+    }
+
+    /// Writes the device context of `device_id`, `tc` last, and makes the
+    /// IOMMU reload it.
+    fn set_device_context(
+        &self,
+        device_id: u32,
+        [tc, iohgatp, ta, fsc]: [u64; 4],
+    ) -> Result<(), LinuxError> {
+        let mut queues = self.queues.lock();
+        let dc = self.device_context(device_id, tc & DC_TC_V != 0)?;
+        unsafe {
+            dc.write_volatile(0);
+            core::arch::asm!("fence w, w");
+            dc.add(1).write_volatile(iohgatp);
+            dc.add(2).write_volatile(ta);
+            dc.add(3).write_volatile(fsc);
+            core::arch::asm!("fence w, w");
+            dc.write_volatile(tc);
+        }
+        self.submit(
+            &mut queues,
+            [CMD_IODIR_INVAL_DDT | CMD_DV | ((device_id as u64) << 40), 0],
+        )?;
+        self.sync(&mut queues)
+    }
+}
+
+/// Finds the RISC-V IOMMU in the device tree and enables it, with its
+/// memory from `alloc_pages`. From then on, the devices can only do DMA
+/// once attached to a domain.
+///
+/// Returns [`LinuxError::ENODEV`] if there is no IOMMU,
+/// [`LinuxError::EBUSY`] if it is already enabled, [`LinuxError::ENOMEM`]
+/// if `alloc_pages` fails, and [`LinuxError::ETIMEDOUT`] if it does not
+/// respond.
+pub fn init_iommu(alloc_pages: IommuAllocFn) -> Result<(), LinuxError> {
+    if IOMMU.is_init() {
+        return Err(LinuxError::EBUSY);
+    }
+    let paddr = PhysAddr::from(dt::iommu().ok_or(LinuxError::ENODEV)?);
+    let iommu = Iommu::probe(phys_to_virt(paddr), alloc_pages)?;
+    info!(
+        "RISC-V IOMMU @ {:#x}: capabilities {:#x}, {}-level DDT",
+        paddr, iommu.caps, iommu.ddt_levels
+    );
+    IOMMU.init_by(iommu);
+    Ok(())
+}
+
+fn iommu() -> Result<&'static Iommu, LinuxError> {
+    if IOMMU.is_init() {
+        Ok(&IOMMU)
+    } else {
+        Err(LinuxError::ENODEV)
+    }
+}
+
+/// An I/O address space of the IOMMU.
+pub struct IommuDomain {
+    stage: IommuStage,
+    root: PhysAddr,
+    levels: usize,
+    /// The `MODE` of `iosatp` or `iohgatp`.
+    mode: u64,
+    /// The PSCID or GSCID.
+    id: u32,
+    lock: SpinNoIrq<()>,
+}
+
+impl IommuDomain {
+    /// Creates an empty domain of `stage`, with the smallest page table mode
+    /// the IOMMU supports.
+    ///
+    /// Returns [`LinuxError::ENODEV`] if the IOMMU is not enabled,
+    /// [`LinuxError::ENOTSUP`] if it does not support the stage, and
+    /// [`LinuxError::ENOMEM`] if there is no memory or ID left.
+    pub fn new(stage: IommuStage) -> Result<Self, LinuxError> {
+        let iommu = iommu()?;
+        let modes = match stage {
+            IommuStage::First => [CAP_SV39, CAP_SV48, CAP_SV57],
+            IommuStage::Second => [CAP_SV39X4, CAP_SV48X4, CAP_SV57X4],
+        };
+        let index = modes
+            .iter()
+            .position(|&cap| iommu.caps & cap != 0)
+            .ok_or(LinuxError::ENOTSUP)?;
+        let id_bits = match stage {
+            IommuStage::First => 20,
+            IommuStage::Second => 16,
+        };
+        let id = LAST_DOMAIN_ID.fetch_add(1, Ordering::Relaxed) + 1;
+        if id >> id_bits != 0 {
+            return Err(LinuxError::ENOMEM);
+        }
+        let root_pages = match stage {
+            IommuStage::First => 1,
+            IommuStage::Second => 4,
+        };
+        Ok(Self {
+            stage,
+            root: alloc_zeroed(iommu.alloc_pages, root_pages)?,
+            levels: 3 + index,
+            mode: 8 + index as u64,
+            id,
+            lock: SpinNoIrq::new(()),
+        })
+    }
+
+    /// Returns the translation stage of the domain.
+    pub const fn stage(&self) -> IommuStage {
+        self.stage
+    }
+
+    /// Returns the PSCID (first stage) or GSCID (second stage) of the
+    /// domain.
+    pub const fn id(&self) -> u32 {
+        self.id
+    }
+
+    /// Returns the size of the address space.
+    fn iova_limit(&self) -> usize {
+        let extra = match self.stage {
+            IommuStage::First => 0,
+            IommuStage::Second => 2,
+        };
+        1 << (12 + 9 * self.levels + extra)
+    }
+
+    fn pte_index(&self, iova: usize, level: usize) -> usize {
+        let bits = match self.stage {
+            IommuStage::Second if level == self.levels - 1 => 11,
+            _ => 9,
+        };
+        (iova >> (12 + 9 * level)) & ((1 << bits) - 1)
+    }
+
+    /// Returns the leaf entry of `iova`, allocating the tables on the way if
+    /// `alloc`.
+    fn leaf_entry(&self, iova: usize, alloc: bool) -> Result<*mut usize, LinuxError> {
+        let mut table = self.root;
+        for level in (0..self.levels).rev() {
+            let entry = unsafe {
+                (phys_to_virt(table).as_usize() as *mut usize).add(self.pte_index(iova, level))
+            };
+            if level == 0 {
+                return Ok(entry);
+            }
+            let mut value = unsafe { entry.read_volatile() };
+            if value & PTE_V == 0 {
+                if !alloc {
+                    return Err(LinuxError::ENOENT);
+                }
+                value = ppn_field(alloc_zeroed(iommu()?.alloc_pages, 1)?) as usize | PTE_V;
+                unsafe { entry.write_volatile(value) };
+            }
+            table = ppn_to_paddr(value);
+        }
+        unreachable!()
+    }
+
+    /// Invalidates the IOTLB entries of the domain for `iova`, or all of
+    /// them if [`None`], and waits for it.
+    pub fn flush_iotlb(&self, iova: Option<usize>) -> Result<(), LinuxError> {
+        let iommu = iommu()?;
+        let mut queues = iommu.queues.lock();
+        self.queue_iotinval(iommu, &mut queues, iova)?;
+        iommu.sync(&mut queues)
+    }
+
+    fn queue_iotinval(
+        &self,
+        iommu: &Iommu,
+        queues: &mut Queues,
+        iova: Option<usize>,
+    ) -> Result<(), LinuxError> {
+        let mut cmd = match self.stage {
+            IommuStage::First => CMD_IOTINVAL_VMA | CMD_PSCV | ((self.id as u64) << TA_PSCID_SHIFT),
+            IommuStage::Second => {
+                CMD_IOTINVAL_GVMA | CMD_GV | ((self.id as u64) << IOHGATP_GSCID_SHIFT)
+            }
+        };
+        let addr = match iova {
+            Some(iova) => {
+                cmd |= CMD_AV;
+                ((iova >> 12) << 10) as u64
+            }
+            None => 0,
+        };
+        iommu.submit(queues, [cmd, addr])
+    }
+}
+
+/// Makes the device `device_id` translate its DMA with `domain`.
+///
+/// Returns [`LinuxError::ENODEV`] if the IOMMU is not enabled,
+/// [`LinuxError::EINVAL`] if `device_id` is too wide for its DDT, and
+/// [`LinuxError::ENOMEM`] if a table cannot be allocated.
+pub fn iommu_attach_device(domain: &IommuDomain, device_id: u32) -> Result<(), LinuxError> {
+    let atp = (domain.mode << ATP_MODE_SHIFT) | (domain.root.as_usize() >> 12) as u64;
+    let dc = match domain.stage {
+        IommuStage::First => [DC_TC_V, 0, (domain.id as u64) << TA_PSCID_SHIFT, atp],
+        IommuStage::Second => [
+            DC_TC_V,
+            atp | ((domain.id as u64) << IOHGATP_GSCID_SHIFT),
+            0,
+            0,
+        ],
+    };
+    iommu()?.set_device_context(device_id, dc)
+}
+
+/// Detaches the device `device_id` from its domain: its DMA faults from
+/// then on.
+///
+/// Returns [`LinuxError::ENOENT`] if it was never attached.
+pub fn iommu_detach_device(device_id: u32) -> Result<(), LinuxError> {
+    iommu()?.set_device_context(device_id, [0; 4])
+}
+
+/// Maps `[iova, iova + size)` of `domain` to the physical memory at `paddr`,
+/// readable and writable as `flags` says, in 4 KiB pages. A write-only
+/// mapping is readable too.
+///
+/// Returns [`LinuxError::EINVAL`] if the range is not page-aligned, out of
+/// the address space or neither readable nor writable,
+/// [`LinuxError::EEXIST`] if a page of it is already mapped (nothing is
+/// mapped then), and [`LinuxError::ENOMEM`] if a table cannot be allocated.
+pub fn iommu_map(
+    domain: &IommuDomain,
+    iova: usize,
+    paddr: PhysAddr,
+    size: usize,
+    flags: MemRegionFlags,
+) -> Result<(), LinuxError> {
+    if (iova | paddr.as_usize() | size) & (PAGE_SIZE_4K - 1) != 0
+        || iova
+            .checked_add(size)
+            .map_or(true, |end| end > domain.iova_limit())
+        || !flags.intersects(MemRegionFlags::READ | MemRegionFlags::WRITE)
+    {
+        return Err(LinuxError::EINVAL);
+    }
+    let mut perm = PTE_V | PTE_R | PTE_U | PTE_A | PTE_D;
+    if flags.contains(MemRegionFlags::WRITE) {
+        perm |= PTE_W;
+    }
+    let _guard = domain.lock.lock();
+    for offset in (0..size).step_by(PAGE_SIZE_4K) {
+        let entry = domain.leaf_entry(iova + offset, true)?;
+        if unsafe { entry.read_volatile() } & PTE_V != 0 {
+            for undo in (0..offset).step_by(PAGE_SIZE_4K) {
+                unsafe { domain.leaf_entry(iova + undo, false)?.write_volatile(0) };
+            }
+            return Err(LinuxError::EEXIST);
+        }
+        let value = ppn_field(paddr + offset) as usize | perm;
+        unsafe { entry.write_volatile(value) };
+    }
+    Ok(())
+}
+
+/// Unmaps `[iova, iova + size)` of `domain`, skipping the pages not mapped,
+/// and invalidates the IOTLB entries. The devices no longer access the
+/// memory on return.
+///
+/// Returns [`LinuxError::EINVAL`] if the range is not page-aligned, and
+/// [`LinuxError::ETIMEDOUT`] if the IOMMU does not complete the
+/// invalidation.
+pub fn iommu_unmap(domain: &IommuDomain, iova: usize, size: usize) -> Result<(), LinuxError> {
+    if (iova | size) & (PAGE_SIZE_4K - 1) != 0 {
+        return Err(LinuxError::EINVAL);
+    }
+    let iommu = iommu()?;
+    let _guard = domain.lock.lock();
+    let pages = size / PAGE_SIZE_4K;
+    for offset in (0..size).step_by(PAGE_SIZE_4K) {
+        if let Ok(entry) = domain.leaf_entry(iova + offset, false) {
+            unsafe { entry.write_volatile(0) };
+        }
+    }
+    let mut queues = iommu.queues.lock();
+    if pages > IOTINVAL_PAGES_MAX {
+        domain.queue_iotinval(iommu, &mut queues, None)?;
+    } else {
+        for offset in (0..size).step_by(PAGE_SIZE_4K) {
+            domain.queue_iotinval(iommu, &mut queues, Some(iova + offset))?;
+        }
+    }
+    iommu.sync(&mut queues)
+}
+
+/// Logs the fault records that the IOMMU queued (e.g. the DMA of a device
+/// that is not attached, or out of its mappings), and returns how many. It
+/// may be called from the interrupt handler of the fault queue.
+pub fn iommu_handle_faults() -> usize {
+    let Ok(iommu) = iommu() else {
+        return 0;
+    };
+    let fq = phys_to_virt(iommu.queues.lock().fq).as_usize();
+    let tail = iommu.read32(REG_FQT);
+    let mut head = iommu.read32(REG_FQH);
+    let mut count = 0;
+    while head != tail {
+        let record = (fq + 32 * head as usize) as *const u64;
+        let (header, iotval) = unsafe { (record.read_volatile(), record.add(2).read_volatile()) };
+        warn!(
+            "IOMMU fault: cause {}, device {:#x}, iova {:#x}",
+            header & 0xfff,
+            header >> 40,
+            iotval
+        );
+        head = (head + 1) % FQ_ENTRIES;
+        count += 1;
+    }
+    iommu.write32(REG_FQH, head);
+    let errors = iommu.read32(REG_FQCSR) & (QCSR_MF | FQCSR_OF);
+    if errors != 0 {
+        warn!("IOMMU fault queue error: fqcsr {:#x}", errors);
+        iommu.write32(REG_FQCSR, QCSR_EN | errors);
+    }
+    count
+}
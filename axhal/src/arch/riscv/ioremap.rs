@@ -0,0 +1,293 @@
+//! Mapping device MMIO outside the linear mapping.
+//!
+//! [`ioremap`] maps a physical range in a dedicated window of the kernel
+//! half, `[IOREMAP_BASE, IOREMAP_BASE + IOREMAP_SIZE)`, with the memory type
+//! of the device, and returns an [`MmioRegion`] to access it. This works for
+//! the regions the linear mapping does not cover (e.g. the BARs of a PCIe
+//! host bridge above the RAM), and for the regions it maps as cacheable.
+//!
+//! The intermediate page tables of the window come from a static pool. The
+//! entries of the root that cover the window are allocated by
+//! [`init_ioremap`], so that the page tables cloned from the kernel one
+//! share the window.
+
+use axerrno::LinuxError;
+use core::mem::{align_of, size_of};
+use memory_addr::{PhysAddr, VirtAddr};
+use spinbase::SpinNoIrq;
+
+use super::huge_page::{entry_at, map_region, unmap_page, PageSize};
+use super::page_walk::current_levels;
+use super::svpbmt::MemAttr;
+use super::tlb::TlbBatch;
+use crate::mem::{virt_to_phys, MemRegionFlags, PAGE_SIZE_4K};
+
+/// The start of the ioremap window.
+pub const IOREMAP_BASE: usize = 0xffff_fffe_0000_0000;
+/// The size of the ioremap window.
+pub const IOREMAP_SIZE: usize = 0x1_0000_0000;
+
+/// The maximum number of regions mapped at the same time.
+const MAX_IOREMAPS: usize = 64;
+/// The number of page table frames for the window.
+const TABLE_POOL_SIZE: usize = 32;
+
+/// A static page table frame.
+#[repr(C, align(4096))]
+pub(super) struct TableFrame([u8; PAGE_SIZE_4K]);
+
+pub(super) const TABLE_FRAME_INIT: TableFrame = TableFrame([0; PAGE_SIZE_4K]);
+
+static mut TABLE_POOL: [TableFrame; TABLE_POOL_SIZE] = [TABLE_FRAME_INIT; TABLE_POOL_SIZE];
+
+struct IoremapState {
+    /// The page table the window is mapped in, set by [`init_ioremap`].
+    root: Option<PhysAddr>,
+    /// The mapped ranges of the window, `(vaddr, size)` sorted by address.
+    ranges: [(usize, usize); MAX_IOREMAPS],
+    len: usize,
+    /// The number of frames of [`TABLE_POOL`] in use, they are never freed.
+    used_tables: usize,
+}
+
+impl IoremapState {
+    const fn new() -> Self {
+        Self {
+            root: None,
+            ranges: [(0, 0); MAX_IOREMAPS],
+            len: 0,
+            used_tables: 0,
+        }
+    }
+
+    fn alloc_table(&mut self) -> Option<PhysAddr> {
+        if self.used_tables == TABLE_POOL_SIZE {
+            warn!("ioremap: out of page tables");
+            return None;
+        }
+        let frame = unsafe { core::ptr::addr_of!(TABLE_POOL[self.used_tables]) };
+        self.used_tables += 1;
+        Some(virt_to_phys(VirtAddr::from(frame as usize)))
+    }
+
+    /// Allocates the entries of `root` that cover the window.
+    fn init(&mut self, root: PhysAddr) -> Result<(), LinuxError> {
+        let levels = current_levels().ok_or(LinuxError::EINVAL)?;
+        let top_size = 1 << (12 + 9 * (levels - 1));
+        for vaddr in (IOREMAP_BASE..IOREMAP_BASE + IOREMAP_SIZE).step_by(top_size) {
+            unsafe {
+                entry_at(
+                    root,
+                    VirtAddr::from(vaddr),
+                    levels - 2,
+                    Some(&mut || self.alloc_table()),
+                )?;
+            }
+        }
+        self.root = Some(root);
+        Ok(())
+    }
+
+    /// Finds a free range of `size` bytes in the window, whose address has
+    /// the same offset as `paddr` in a 2M superpage if it is large enough
+    /// to use some. Returns the index where to insert it, and its address.
+    fn find_free(&self, paddr: usize, size: usize) -> Option<(usize, usize)> {
+        if self.len == MAX_IOREMAPS {
+            return None;
+        }
+        let align = if size >= PageSize::Size2M.size() {
+            PageSize::Size2M.size()
+        } else {
+            PAGE_SIZE_4K
+        };
+        let place = |cursor: usize| ((cursor + align - 1) & !(align - 1)) + (paddr & (align - 1));
+        let mut cursor = IOREMAP_BASE;
+        for (i, &(start, len)) in self.ranges[..self.len].iter().enumerate() {
+            let vaddr = place(cursor);
+            if vaddr + size <= start {
+                return Some((i, vaddr));
+            }
+            cursor = start + len;
+        }
+        let vaddr = place(cursor);
+        (vaddr + size <= IOREMAP_BASE + IOREMAP_SIZE).then_some((self.len, vaddr))
+    }
+}
+
+static IOREMAP: SpinNoIrq<IoremapState> = SpinNoIrq::new(IoremapState::new());
+
+/// Unmaps `[vaddr, vaddr + size)`, skipping the pages not mapped.
+unsafe fn unmap_range(root: PhysAddr, vaddr: usize, size: usize) {
+    let mut batch = TlbBatch::new();
+    let mut offset = 0;
+    while offset < size {
+        let va = VirtAddr::from(vaddr + offset);
+        match unmap_page(root, va) {
+            Ok((_, page_size)) => {
+                batch.queue(va);
+                offset += page_size.size();
+            }
+            Err(_) => offset += PAGE_SIZE_4K,
+        }
+    }
+}
+
+/// Prepares the ioremap window in the kernel page table at `root`.
+///
+/// It must be called before the page tables that share the kernel mappings
+/// are cloned from `root`. Otherwise [`ioremap`] calls it with the current
+/// page table on its first call. Returns [`LinuxError::EBUSY`] if it has
+/// already been done.
+pub fn init_ioremap(root: PhysAddr) -> Result<(), LinuxError> {
+    let mut state = IOREMAP.lock();
+    if state.root.is_some() {
+        return Err(LinuxError::EBUSY);
+    }
+    state.init(root)
+}
+
+/// Maps the `size` bytes of device memory at `paddr` in the ioremap window,
+/// readable and writable, with the memory type `attr` (usually
+/// [`MemAttr::Io`]).
+///
+/// Returns [`LinuxError::ENOMEM`] if the window or the page table pool is
+/// full.
+pub fn ioremap(paddr: PhysAddr, size: usize, attr: MemAttr) -> Result<MmioRegion, LinuxError> {
+    if size == 0 {
+        return Err(LinuxError::EINVAL);
+    }
+    let start = paddr.align_down_4k().as_usize();
+    let offset = paddr.as_usize() - start;
+    let map_size = (offset + size + PAGE_SIZE_4K - 1) & !(PAGE_SIZE_4K - 1);
+
+    let mut state = IOREMAP.lock();
+    let root = match state.root {
+        Some(root) => root,
+        None => {
+            let root = super::read_page_table_root();
+            state.init(root)?;
+            root
+        }
+    };
+    let (index, vaddr) = state.find_free(start, map_size).ok_or(LinuxError::ENOMEM)?;
+    let flags = MemRegionFlags::READ | MemRegionFlags::WRITE;
+    let mapped = unsafe {
+        map_region(
+            root,
+            VirtAddr::from(vaddr),
+            PhysAddr::from(start),
+            map_size,
+            flags,
+            attr,
+            &mut || state.alloc_table(),
+        )
+    };
+    if let Err(err) = mapped {
+        unsafe { unmap_range(root, vaddr, map_size) };
+        return Err(err);
+    }
+    let len = state.len;
+    state.ranges.copy_within(index..len, index + 1);
+    state.ranges[index] = (vaddr, map_size);
+    state.len += 1;
+    Ok(MmioRegion {
+        vaddr: VirtAddr::from(vaddr + offset),
+        paddr,
+        size,
+    })
+}
+
+/// Unmaps a region mapped by [`ioremap`].
+pub fn iounmap(region: MmioRegion) -> Result<(), LinuxError> {
+    let vaddr = region.vaddr.align_down_4k().as_usize();
+    let mut state = IOREMAP.lock();
+    let root = state.root.ok_or(LinuxError::EINVAL)?;
+    let len = state.len;
+    let index = state.ranges[..len]
+        .iter()
+        .position(|&(start, _)| start == vaddr)
+        .ok_or(LinuxError::EINVAL)?;
+    let (start, size) = state.ranges[index];
+    unsafe { unmap_range(root, start, size) };
+    state.ranges.copy_within(index + 1..len, index);
+    state.len -= 1;
+    Ok(())
+}
+
+/// A device MMIO region mapped by [`ioremap`].
+///
+/// The accessors take the offset of the register in the region, and panic
+/// if it is out of the region or not aligned to the width of the access.
+#[derive(Debug)]
+pub struct MmioRegion {
+    vaddr: VirtAddr,
+    paddr: PhysAddr,
+    size: usize,
+}
+
+impl MmioRegion {
+    /// Returns the virtual address of the start of the region.
+    pub const fn vaddr(&self) -> VirtAddr {
+        self.vaddr
+    }
+
+    /// Returns the physical address of the start of the region.
+    pub const fn paddr(&self) -> PhysAddr {
+        self.paddr
+    }
+
+    /// Returns the size of the region.
+    pub const fn size(&self) -> usize {
+        self.size
+    }
+
+    fn ptr<T>(&self, offset: usize) -> *mut T {
+        assert!(
+            offset + size_of::<T>() <= self.size && offset % align_of::<T>() == 0,
+            "invalid MMIO access at {:#x} of {:#x}",
+            offset,
+            self.paddr
+        );
+        (self.vaddr.as_usize() + offset) as *mut T
+    }
+
+    /// Reads the byte at `offset`.
+    pub fn read_u8(&self, offset: usize) -> u8 {
+        unsafe { self.ptr::<u8>(offset).read_volatile() }
+    }
+
+    /// Reads the 16-bit register at `offset`.
+    pub fn read_u16(&self, offset: usize) -> u16 {
+        unsafe { self.ptr::<u16>(offset).read_volatile() }
+    }
+
+    /// Reads the 32-bit register at `offset`.
+    pub fn read_u32(&self, offset: usize) -> u32 {
+        unsafe { self.ptr::<u32>(offset).read_volatile() }
+    }
+
+    /// Reads the 64-bit register at `offset`.
+    pub fn read_u64(&self, offset: usize) -> u64 {
+        unsafe { self.ptr::<u64>(offset).read_volatile() }
+    }
+
+    /// Writes the byte at `offset`.
+    pub fn write_u8(&self, offset: usize, value: u8) {
+        unsafe { self.ptr::<u8>(offset).write_volatile(value) }
+    }
+
+    /// Writes the 16-bit register at `offset`.
+    pub fn write_u16(&self, offset: usize, value: u16) {
+        unsafe { self.ptr::<u16>(offset).write_volatile(value) }
+    }
+
+    /// Writes the 32-bit register at `offset`.
+    pub fn write_u32(&self, offset: usize, value: u32) {
+        unsafe { self.ptr::<u32>(offset).write_volatile(value) }
+    }
+
+    /// Writes the 64-bit register at `offset`.
+    pub fn write_u64(&self, offset: usize, value: u64) {
+        unsafe { self.ptr::<u64>(offset).write_volatile(value) }
+    }
+}
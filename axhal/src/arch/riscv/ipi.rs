@@ -0,0 +1,447 @@
+//! Inter-processor interrupts, and the TLB shootdown and remote function
+//! calls over them.
+//!
+//! The kinds of IPIs pending on a CPU are kept in a bitmap, so that one
+//! supervisor software interrupt can carry several of them. A TLB shootdown
+//! or a function call also queues its request on each target CPU, and the
+//! sender can wait until all of them have acknowledged it.
+
+use axerrno::LinuxError;
+use core::ops::Range;
+use core::sync::atomic::{AtomicUsize, Ordering};
+use core::time::Duration;
+use memory_addr::VirtAddr;
+use spinbase::SpinNoIrq;
+
+use crate::cpu::{_this_cpu_id, cpu_to_hartid, online_cpus, CpuMask};
+use crate::mem::PAGE_SIZE_4K;
+
+bitflags::bitflags! {
+    /// The kinds of IPIs.
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub struct IpiKind: usize {
+        /// Asks the target CPU to reschedule.
+        const RESCHED       = 1 << 0;
+        /// Asks the target CPU to flush the TLB ranges in its queue.
+        const TLB_SHOOTDOWN = 1 << 1;
+        /// Asks the target CPU to run the functions in its queue.
+        const CALL_FUNCTION = 1 << 2;
+        /// Asks the target CPU to go offline and halt.
+        const STOP          = 1 << 3;
+        /// Asks the target CPU to dump its state to the log, then to go
+        /// offline and halt.
+        const DUMP_AND_STOP = 1 << 4;
+        /// Asks the target CPU to go offline and return to the firmware, to
+        /// be started again later (CPU hotplug).
+        const CPU_DOWN      = 1 << 5;
+    }
+}
+
+#[allow(clippy::declare_interior_mutable_const)]
+const IPI_PENDING_INIT: AtomicUsize = AtomicUsize::new(0);
+
+/// The pending IPI kinds of each CPU, indexed by the logical CPU ID.
+static IPI_PENDING: [AtomicUsize; axconfig::SMP] = [IPI_PENDING_INIT; axconfig::SMP];
+
+/// The maximum number of requests of a kind queued on a CPU, a sender waits
+/// for a free slot when the queue is full.
+const QUEUE_CAPACITY: usize = 8;
+
+/// A flush request in the queue of a target CPU.
+#[derive(Clone, Copy)]
+struct FlushRequest {
+    /// The range to flush, [`None`] for the whole address space.
+    range: Option<(usize, usize)>,
+    /// The ASID to flush, [`None`] for all of them.
+    asid: Option<usize>,
+    /// The number of CPUs that have not done the flush yet, on the stack of
+    /// the sender (which waits for it to reach 0).
+    pending: *const AtomicUsize,
+}
+
+// The sender does not return before the request is done.
+unsafe impl Send for FlushRequest {}
+
+/// A function call request in the queue of a target CPU.
+#[derive(Clone, Copy)]
+struct CallRequest {
+    /// The function, which outlives the request: it is either `'static`, or
+    /// on the stack of a sender that waits.
+    func: *const (dyn Fn() + Sync),
+    /// The number of CPUs that have not run the function yet, on the stack
+    /// of the sender, or null if it does not wait.
+    pending: *const AtomicUsize,
+}
+
+// The function is `Sync`, and outlives the request.
+unsafe impl Send for CallRequest {}
+
+struct RequestQueue<T> {
+    requests: [Option<T>; QUEUE_CAPACITY],
+}
+
+impl<T: Copy> RequestQueue<T> {
+    const fn new() -> Self {
+        Self {
+            requests: [None; QUEUE_CAPACITY],
+        }
+    }
+
+    /// Queues `request`, returns `false` if the queue is full.
+    fn push(&mut self, request: T) -> bool {
+        match self.requests.iter_mut().find(|slot| slot.is_none()) {
+            Some(slot) => {
+                *slot = Some(request);
+                true
+            }
+            None => false,
+        }
+    }
+}
+
+#[allow(clippy::declare_interior_mutable_const)]
+const FLUSH_QUEUE_INIT: SpinNoIrq<RequestQueue<FlushRequest>> = SpinNoIrq::new(RequestQueue::new());
+
+/// The pending flushes of each CPU, indexed by the logical CPU ID.
+static FLUSH_QUEUES: [SpinNoIrq<RequestQueue<FlushRequest>>; axconfig::SMP] =
+    [FLUSH_QUEUE_INIT; axconfig::SMP];
+
+#[allow(clippy::declare_interior_mutable_const)]
+const CALL_QUEUE_INIT: SpinNoIrq<RequestQueue<CallRequest>> = SpinNoIrq::new(RequestQueue::new());
+
+/// The pending function calls of each CPU, indexed by the logical CPU ID.
+static CALL_QUEUES: [SpinNoIrq<RequestQueue<CallRequest>>; axconfig::SMP] =
+    [CALL_QUEUE_INIT; axconfig::SMP];
+
+/// Queues `request` in the `queue` of the CPU `cpu_id` if it is online,
+/// returns whether it did. `wait` runs while the queue is full.
+///
+/// The CPU is checked under the lock of the queue, which [`ipi_cpu_dead`]
+/// empties once the CPU is offline: the request is either in the queue
+/// then, or not queued at all.
+fn queue_request<T: Copy>(
+    queue: &SpinNoIrq<RequestQueue<T>>,
+    cpu_id: usize,
+    request: T,
+    wait: impl Fn(),
+) -> bool {
+    loop {
+        let mut requests = queue.lock();
+        if !crate::cpu::cpu_online(cpu_id) {
+            return false;
+        }
+        if requests.push(request) {
+            return true;
+        }
+        drop(requests);
+        wait();
+        core::hint::spin_loop();
+    }
+}
+
+/// Sends an IPI of `kind` to the CPU `cpu_id`.
+pub fn send_ipi(cpu_id: usize, kind: IpiKind) {
+    let Some(hartid) = cpu_to_hartid(cpu_id) else {
+        return;
+    };
+    IPI_PENDING[cpu_id].fetch_or(kind.bits(), Ordering::Release);
+    if let Err(err) = super::sbi::send_ipi(1, hartid) {
+        warn!("sbi_send_ipi to hart {} failed: {:?}", hartid, err);
+    }
+}
+
+/// Handles the IPIs sent to the current CPU.
+///
+/// It must be called by the supervisor software interrupt handler
+/// ([`IPI_IRQ_NUM`](crate::platform::irq::IPI_IRQ_NUM)).
+pub fn handle_ipi() {
+    // Clear `sip.SSIP` before reading the kinds, so a later IPI is not lost.
+    unsafe { core::arch::asm!("csrc sip, {}", in(reg) 1 << 1) };
+    let kinds = IpiKind::from_bits_truncate(IPI_PENDING[_this_cpu_id()].swap(0, Ordering::Acquire));
+    for bit in 0..usize::BITS as usize {
+        if kinds.bits() & (1 << bit) != 0 {
+            crate::trap::count_trap(crate::trap::TrapStat::Ipi(bit));
+        }
+    }
+    if kinds.contains(IpiKind::TLB_SHOOTDOWN) {
+        do_pending_flushes();
+    }
+    if kinds.contains(IpiKind::CALL_FUNCTION) {
+        do_pending_calls();
+    }
+    if kinds.contains(IpiKind::RESCHED) {
+        crate::cpu::set_need_resched();
+    }
+    if kinds.contains(IpiKind::DUMP_AND_STOP) {
+        dump_this_cpu();
+        stop_this_cpu();
+    }
+    if kinds.contains(IpiKind::STOP) {
+        stop_this_cpu();
+    }
+    if kinds.contains(IpiKind::CPU_DOWN) {
+        super::hotplug::cpu_die();
+    }
+}
+
+/// Serves the requests still queued on the current CPU, which is offline
+/// and about to stop.
+pub(super) fn flush_pending_ipis() {
+    do_pending_flushes();
+    do_pending_calls();
+}
+
+/// Acknowledges the requests left in the queues of the CPU `cpu_id`, which
+/// is stopped, so that their senders do not wait for it forever.
+///
+/// Its flushes are not needed (it flushes the TLB when it starts again),
+/// and its function calls are dropped. No request is queued after this, the
+/// senders see that the CPU is offline.
+pub fn ipi_cpu_dead(cpu_id: usize) {
+    for request in FLUSH_QUEUES[cpu_id].lock().requests.iter_mut() {
+        if let Some(request) = request.take() {
+            unsafe { (*request.pending).fetch_sub(1, Ordering::Release) };
+        }
+    }
+    for request in CALL_QUEUES[cpu_id].lock().requests.iter_mut() {
+        if let Some(request) = request.take() {
+            warn!("Drop a function call to the offline CPU {}", cpu_id);
+            if !request.pending.is_null() {
+                unsafe { (*request.pending).fetch_sub(1, Ordering::Release) };
+            }
+        }
+    }
+    IPI_PENDING[cpu_id].store(0, Ordering::Release);
+}
+
+/// How long [`smp_dump_and_stop_other_cpus`] waits for the dumps.
+pub const DUMP_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// The number of CPUs that have not dumped their state yet.
+static DUMP_PENDING: AtomicUsize = AtomicUsize::new(0);
+
+/// Serializes the dumps, so that they do not interleave in the log.
+static DUMP_LOCK: SpinNoIrq<()> = SpinNoIrq::new(());
+
+/// Logs the registers of the interrupted context and its backtrace.
+fn dump_this_cpu() {
+    {
+        let _lock = DUMP_LOCK.lock();
+        error!("CPU {} stopped:", _this_cpu_id());
+        let tf = super::irq_regs();
+        if tf.is_null() {
+            super::print_backtrace(None);
+        } else {
+            let tf = unsafe { &*tf };
+            error!("{:#x?}", tf);
+            super::print_backtrace(Some(tf));
+        }
+    }
+    DUMP_PENDING.fetch_sub(1, Ordering::Release);
+}
+
+/// Takes the current CPU offline, and halts it with interrupts disabled.
+fn stop_this_cpu() -> ! {
+    super::disable_irqs();
+    crate::cpu::cpu_offline();
+    loop {
+        super::wait_for_irqs();
+    }
+}
+
+/// Flushes the local TLB entries of `range` in `asid`.
+fn local_flush(range: Option<(usize, usize)>, asid: Option<usize>) {
+    let pages = range.map(|(start, end)| (end - start + PAGE_SIZE_4K - 1) / PAGE_SIZE_4K);
+    match (pages, asid) {
+        (Some(pages), _) if !super::tlb_flush_all_better(pages) => {
+            let start = range.unwrap().0;
+            for i in 0..pages {
+                let vaddr = VirtAddr::from(start + i * PAGE_SIZE_4K);
+                match asid {
+                    Some(asid) => super::asid::local_flush_tlb_page_asid(vaddr, asid),
+                    None => super::flush_tlb(Some(vaddr)),
+                }
+            }
+        }
+        (_, Some(asid)) => super::flush_tlb_asid(asid),
+        (_, None) => super::flush_tlb(None),
+    }
+}
+
+/// Flushes `range` in `asid` on the CPUs of `cpus` with a remote fence.
+fn remote_flush(
+    cpus: CpuMask,
+    range: Option<(usize, usize)>,
+    asid: Option<usize>,
+) -> Result<(), LinuxError> {
+    let (start, size) = match range {
+        Some((start, end)) => (VirtAddr::from(start), end - start),
+        None => (VirtAddr::from(0), usize::MAX),
+    };
+    match asid {
+        Some(asid) => super::remote_sfence_vma_asid(cpus, start, size, asid),
+        None => super::remote_sfence_vma(cpus, start, size),
+    }
+}
+
+/// Does the flushes queued on the current CPU, and acknowledges them.
+fn do_pending_flushes() {
+    let mut queue = FLUSH_QUEUES[_this_cpu_id()].lock();
+    for slot in queue.requests.iter_mut() {
+        if let Some(request) = slot.take() {
+            local_flush(request.range, request.asid);
+            unsafe { (*request.pending).fetch_sub(1, Ordering::Release) };
+        }
+    }
+}
+
+/// Flushes the TLB entries of `vaddr_range` (the whole address space if
+/// [`None`]) in `asid` (all ASIDs if [`None`]) on all online CPUs.
+///
+/// With the SBI RFENCE extension, the other online CPUs are flushed with a
+/// remote fence. Otherwise it sends an IPI to them and waits until all of
+/// them have done the flush. While waiting, it does the flushes that other
+/// CPUs ask of this one, so two CPUs shooting down each other do not
+/// deadlock.
+pub fn flush_tlb_all_cpus(vaddr_range: Option<Range<VirtAddr>>, asid: Option<usize>) {
+    if vaddr_range.as_ref().is_some_and(|r| r.start >= r.end) {
+        return;
+    }
+    let _guard = kernel_guard_base::NoPreempt::new();
+    let range = vaddr_range.map(|r| (r.start.align_down_4k().as_usize(), r.end.as_usize()));
+    local_flush(range, asid);
+
+    let mut cpus = online_cpus();
+    let this_cpu = _this_cpu_id();
+    cpus.remove(this_cpu);
+    if remote_flush(cpus, range, asid).is_ok() {
+        return;
+    }
+    let pending = AtomicUsize::new(0);
+    for cpu_id in cpus.iter() {
+        let request = FlushRequest {
+            range,
+            asid,
+            pending: &pending,
+        };
+        pending.fetch_add(1, Ordering::Relaxed);
+        // Wait for a free slot, doing our own flushes meanwhile.
+        if queue_request(&FLUSH_QUEUES[cpu_id], cpu_id, request, do_pending_flushes) {
+            send_ipi(cpu_id, IpiKind::TLB_SHOOTDOWN);
+        } else {
+            pending.fetch_sub(1, Ordering::Relaxed);
+        }
+    }
+    while pending.load(Ordering::Acquire) != 0 {
+        do_pending_flushes();
+        core::hint::spin_loop();
+    }
+}
+
+/// Runs the function calls queued on the current CPU, and acknowledges them.
+fn do_pending_calls() {
+    // Take the requests out first, a function may call other CPUs.
+    let requests = core::mem::replace(
+        &mut CALL_QUEUES[_this_cpu_id()].lock().requests,
+        [None; QUEUE_CAPACITY],
+    );
+    for request in requests.into_iter().flatten() {
+        unsafe { (*request.func)() };
+        if !request.pending.is_null() {
+            unsafe { (*request.pending).fetch_sub(1, Ordering::Release) };
+        }
+    }
+}
+
+/// Queues `func` on the CPUs of `cpus` and sends them an IPI. The current
+/// CPU runs it directly if it is in `cpus`.
+fn call_function_many(cpus: CpuMask, func: *const (dyn Fn() + Sync), pending: *const AtomicUsize) {
+    let this_cpu = _this_cpu_id();
+    for cpu_id in cpus.iter() {
+        if cpu_id == this_cpu {
+            continue;
+        }
+        if !pending.is_null() {
+            unsafe { (*pending).fetch_add(1, Ordering::Relaxed) };
+        }
+        let request = CallRequest { func, pending };
+        // Wait for a free slot, serving our own calls and flushes meanwhile.
+        let queued = queue_request(&CALL_QUEUES[cpu_id], cpu_id, request, || {
+            do_pending_calls();
+            do_pending_flushes();
+        });
+        if queued {
+            send_ipi(cpu_id, IpiKind::CALL_FUNCTION);
+        } else if !pending.is_null() {
+            unsafe { (*pending).fetch_sub(1, Ordering::Relaxed) };
+        }
+    }
+    if cpus.contains(this_cpu) {
+        unsafe { (*func)() };
+    }
+}
+
+/// Runs `func` on each online CPU of `cpus`, and waits until all of them
+/// have returned from it.
+///
+/// The other CPUs run it in their IPI handler, with interrupts disabled, so
+/// it must not block. While waiting, this CPU serves the calls and flushes
+/// that other CPUs ask of it, so two CPUs calling each other do not
+/// deadlock.
+pub fn smp_call_function(cpus: CpuMask, func: &(dyn Fn() + Sync)) {
+    let _guard = kernel_guard_base::NoPreempt::new();
+    let pending = AtomicUsize::new(0);
+    // The function outlives the requests, as we wait for all of them.
+    let func: &'static (dyn Fn() + Sync) = unsafe { core::mem::transmute(func) };
+    call_function_many(cpus, func, &pending);
+    while pending.load(Ordering::Acquire) != 0 {
+        do_pending_calls();
+        do_pending_flushes();
+        core::hint::spin_loop();
+    }
+}
+
+/// Runs `func` on each online CPU of `cpus`, without waiting for the other
+/// CPUs to run it.
+///
+/// The current CPU, if it is in `cpus`, has run it on return.
+pub fn smp_call_function_nowait(cpus: CpuMask, func: &'static (dyn Fn() + Sync)) {
+    let _guard = kernel_guard_base::NoPreempt::new();
+    call_function_many(cpus, func, core::ptr::null());
+}
+
+/// Stops all the other online CPUs after each of them has dumped the
+/// registers and the backtrace of the context it was running to the log,
+/// e.g. from the panic handler.
+///
+/// It waits for the dumps at most [`DUMP_TIMEOUT`], as a CPU spinning with
+/// interrupts disabled does not answer. The registers are those set by
+/// [`set_irq_regs`](super::set_irq_regs) in the trap handler.
+pub fn smp_dump_and_stop_other_cpus() {
+    let mut others = online_cpus();
+    others.remove(_this_cpu_id());
+    DUMP_PENDING.store(others.count(), Ordering::Release);
+    for cpu_id in others.iter() {
+        send_ipi(cpu_id, IpiKind::DUMP_AND_STOP);
+    }
+    let deadline = crate::time::current_time() + DUMP_TIMEOUT;
+    while DUMP_PENDING.load(Ordering::Acquire) != 0 {
+        if crate::time::current_time() >= deadline {
+            error!(
+                "{} CPUs did not dump their state",
+                DUMP_PENDING.load(Ordering::Acquire)
+            );
+            break;
+        }
+        core::hint::spin_loop();
+    }
+}
+
+/// Stops all the other online CPUs: each of them goes offline and halts
+/// with interrupts disabled, e.g. before a panic or a reboot.
+pub fn smp_stop_other_cpus() {
+    let this_cpu = _this_cpu_id();
+    for cpu_id in online_cpus().iter().filter(|&cpu_id| cpu_id != this_cpu) {
+        send_ipi(cpu_id, IpiKind::STOP);
+    }
+}
@@ -0,0 +1,27 @@
+//! The trap frame of the interrupt being handled on each CPU.
+//!
+//! The IRQ handlers do not receive the trap frame: the trap handler sets it
+//! with [`set_irq_regs`] around the dispatch, for the handlers that report
+//! the interrupted context (e.g. the dump of
+//! [`smp_dump_and_stop_other_cpus`](super::smp_dump_and_stop_other_cpus)).
+
+use super::TrapFrame;
+
+#[percpu2::def_percpu]
+static IRQ_REGS: usize = 0;
+
+/// Sets the trap frame of the interrupt being handled on this CPU, and
+/// returns the previous one, to be restored after the dispatch as the
+/// interrupts may nest.
+pub fn set_irq_regs(tf: *mut TrapFrame) -> *mut TrapFrame {
+    let _guard = kernel_guard_base::IrqSave::new();
+    let old = unsafe { IRQ_REGS.read_current_raw() };
+    unsafe { IRQ_REGS.write_current_raw(tf as usize) };
+    old as *mut TrapFrame
+}
+
+/// Returns the trap frame set by [`set_irq_regs`], or null outside of an
+/// interrupt.
+pub fn irq_regs() -> *mut TrapFrame {
+    unsafe { IRQ_REGS.read_current_raw() as *mut TrapFrame }
+}
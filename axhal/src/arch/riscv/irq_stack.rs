@@ -0,0 +1,127 @@
+//! Dedicated interrupt stacks, one per CPU.
+//!
+//! [`call_on_irq_stack`] runs the dispatching of an interrupt on the IRQ
+//! stack of the current CPU, instead of the kernel stack of the interrupted
+//! task: the trap frame stays on the task stack, only the handlers run on
+//! the IRQ stack. The vectored entries use it, and so should the trap
+//! handler of the kernel for the interrupts in direct mode. A nested
+//! interrupt (see `set_irq_preemptible`) goes on below the first one on the
+//! IRQ stack.
+//!
+//! The stacks are static, each with a guard page below it, unmapped by
+//! [`install_irq_stack_guards`]. While a CPU runs on its IRQ stack, that
+//! guard page is the one that the trap entry checks, so an overflow is
+//! reported as for the task stacks (see `stack_guard.rs`).
+
+use axerrno::LinuxError;
+use core::ops::Range;
+use core::sync::atomic::{AtomicBool, Ordering};
+use memory_addr::{PhysAddr, VirtAddr};
+
+use super::stack_guard::{current_stack_guard, install_stack_guard, switch_stack_guard};
+use crate::cpu::_this_cpu_id;
+use crate::mem::PAGE_SIZE_4K;
+
+include_asm_marcos!();
+
+/// The size of the IRQ stack of each CPU.
+pub const IRQ_STACK_SIZE: usize = 4 * PAGE_SIZE_4K;
+
+const GUARD_SIZE: usize = super::STACK_GUARD_SIZE;
+
+#[repr(C, align(4096))]
+struct IrqStack([u8; GUARD_SIZE + IRQ_STACK_SIZE]);
+
+const IRQ_STACK_INIT: IrqStack = IrqStack([0; GUARD_SIZE + IRQ_STACK_SIZE]);
+
+static mut IRQ_STACKS: [IrqStack; axconfig::SMP] = [IRQ_STACK_INIT; axconfig::SMP];
+
+/// Whether the guard pages are unmapped.
+static GUARDS_INSTALLED: AtomicBool = AtomicBool::new(false);
+
+/// Returns the bottom of the IRQ stack of the CPU `cpu_id`, above its guard
+/// page.
+fn irq_stack_bottom(cpu_id: usize) -> usize {
+    unsafe { core::ptr::addr_of!(IRQ_STACKS[cpu_id]) as usize + GUARD_SIZE }
+}
+
+/// Returns the bounds of the IRQ stack of a CPU that contains `sp`, if any.
+pub(super) fn irq_stack_bounds(sp: usize) -> Option<Range<usize>> {
+    (0..axconfig::SMP)
+        .map(irq_stack_bottom)
+        .map(|bottom| bottom..bottom + IRQ_STACK_SIZE)
+        .find(|stack| stack.contains(&sp))
+}
+
+/// Switches to `top`, and calls `trampoline(f, arg)` there.
+#[naked]
+unsafe extern "C" fn switch_and_call(_f: usize, _arg: usize, _top: usize) {
+    core::arch::asm!(
+        "
+        addi    sp, sp, -2 * XLENB
+        STR     ra, sp, 1
+        STR     s0, sp, 0
+        mv      s0, sp
+        mv      sp, a2
+        call    {trampoline}
+        mv      sp, s0
+        LDR     s0, sp, 0
+        LDR     ra, sp, 1
+        addi    sp, sp, 2 * XLENB
+        ret",
+        trampoline = sym trampoline,
+        options(noreturn),
+    )
+}
+
+extern "C" fn trampoline(f: usize, arg: usize) {
+    let f: fn(usize) = unsafe { core::mem::transmute(f) };
+    f(arg);
+}
+
+/// Runs `f(arg)` on the IRQ stack of the current CPU, or on the current
+/// stack if it is already that one (a nested interrupt).
+///
+/// The interrupts must be disabled, as in the trap handler: the CPU must not
+/// change, and only the nesting allowed by the IRQ dispatching may use the
+/// stack meanwhile.
+pub fn call_on_irq_stack(f: fn(usize), arg: usize) {
+    let sp: usize;
+    unsafe { core::arch::asm!("mv {}, sp", out(reg) sp) };
+    let bottom = irq_stack_bottom(_this_cpu_id());
+    if (bottom..bottom + IRQ_STACK_SIZE).contains(&sp) {
+        return f(arg);
+    }
+    let guarded = GUARDS_INSTALLED.load(Ordering::Relaxed);
+    let task_guard = current_stack_guard();
+    if guarded {
+        switch_stack_guard(bottom);
+    }
+    unsafe { switch_and_call(f as usize, arg, bottom + IRQ_STACK_SIZE) };
+    if guarded {
+        switch_stack_guard(task_guard);
+    }
+}
+
+/// Unmaps the guard pages below the IRQ stacks of all CPUs, with the page
+/// tables of the split superpages allocated by `alloc_frame`.
+///
+/// Returns [`LinuxError::EBUSY`] if they are already unmapped, and the
+/// errors of [`install_stack_guard`] otherwise.
+///
+/// # Safety
+///
+/// As for [`install_stack_guard`]: the page tables that share the kernel
+/// mappings must share the split tables.
+pub unsafe fn install_irq_stack_guards(
+    alloc_frame: &mut impl FnMut() -> Option<PhysAddr>,
+) -> Result<(), LinuxError> {
+    if GUARDS_INSTALLED.load(Ordering::Relaxed) {
+        return Err(LinuxError::EBUSY);
+    }
+    for cpu_id in 0..axconfig::SMP {
+        install_stack_guard(VirtAddr::from(irq_stack_bottom(cpu_id)), alloc_frame)?;
+    }
+    GUARDS_INSTALLED.store(true, Ordering::Relaxed);
+    Ok(())
+}
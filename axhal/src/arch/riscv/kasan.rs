@@ -0,0 +1,258 @@
+//! The shadow memory of a KASAN-like sanitizer (`-Z sanitizer=kernel-address`).
+//!
+//! Each 8-byte granule of the kernel half has a shadow byte at
+//! [`kasan_mem_to_shadow`]: 0 if it is all accessible, `n` if only its
+//! first `n` bytes are, a negative value if none is. The shadow of the
+//! kernel half of Sv39 is the 32G window [`KASAN_SHADOW_START`], right below
+//! the ioremap window. The kernel is built with the matching offset:
+//!
+//! ```text
+//! -Z sanitizer=kernel-address
+//! -C llvm-args=-asan-mapping-offset=0xdffffffe00000000
+//! ```
+//!
+//! [`kasan_early_init`] maps the whole shadow to a single zero page with
+//! static tables, so the instrumented code runs before any allocator. Then
+//! [`kasan_populate_shadow`] gives its own pages to the shadow of the
+//! regions that are checked (the linear mapping, the kernel stacks), and
+//! the poison helpers mark them. The sanitizer itself (the checks and the
+//! reports) is up to the kernel.
+
+use axerrno::LinuxError;
+use core::ptr::addr_of;
+use memory_addr::{PhysAddr, VirtAddr};
+
+use super::huge_page::{leaf_flags, pte_paddr, table_entries, table_pte, PTE_COUNT};
+use super::page_walk::{current_levels, PTE_PPN_SHIFT, PTE_V};
+use super::svpbmt::MemAttr;
+use crate::mem::{phys_to_virt, virt_to_phys, MemRegionFlags, PAGE_SIZE_4K};
+
+/// The start of the shadow window.
+pub const KASAN_SHADOW_START: usize = 0xffff_fff6_0000_0000;
+/// The size of the shadow window, 1/8 of the kernel half of Sv39.
+pub const KASAN_SHADOW_SIZE: usize = 0x8_0000_0000;
+/// The offset of [`kasan_mem_to_shadow`], the `-asan-mapping-offset`.
+pub const KASAN_SHADOW_OFFSET: usize = 0xdfff_fffe_0000_0000;
+/// The size of the memory described by one shadow byte.
+pub const KASAN_GRANULE_SIZE: usize = 8;
+
+/// The shadow byte of a free page.
+pub const KASAN_PAGE_FREE: u8 = 0xff;
+
+static_assertions::const_assert_eq!(KASAN_SHADOW_START + KASAN_SHADOW_SIZE, super::IOREMAP_BASE);
+
+/// The start of the memory with a shadow, the kernel half of Sv39.
+const KASAN_MEM_START: usize = (KASAN_SHADOW_START - KASAN_SHADOW_OFFSET) << 3;
+
+/// The size covered by one root entry of Sv39.
+const ROOT_ENTRY_SIZE: usize = 1 << 30;
+
+#[repr(C, align(4096))]
+struct ShadowTable([usize; PTE_COUNT]);
+
+/// The page all the shadow is mapped to, then the rest of it.
+static mut EARLY_SHADOW_PAGE: ShadowTable = ShadowTable([0; PTE_COUNT]);
+/// The last-level table whose entries all map [`EARLY_SHADOW_PAGE`].
+static mut EARLY_SHADOW_PTE: ShadowTable = ShadowTable([0; PTE_COUNT]);
+/// The middle table whose entries all point to [`EARLY_SHADOW_PTE`].
+static mut EARLY_SHADOW_PMD: ShadowTable = ShadowTable([0; PTE_COUNT]);
+
+fn table_paddr(table: *const ShadowTable) -> PhysAddr {
+    virt_to_phys(VirtAddr::from(table as usize))
+}
+
+fn early_page_paddr() -> PhysAddr {
+    table_paddr(unsafe { addr_of!(EARLY_SHADOW_PAGE) })
+}
+
+/// Returns the address of the shadow byte of `addr`.
+pub const fn kasan_mem_to_shadow(addr: usize) -> usize {
+    (addr >> 3).wrapping_add(KASAN_SHADOW_OFFSET)
+}
+
+/// Maps the whole shadow window in the kernel page table at `root` to a
+/// zero page, with static tables: all the memory is accessible.
+///
+/// It must be called right after paging is enabled, before the page tables
+/// that share the kernel mappings are cloned from `root`. Returns
+/// [`LinuxError::EINVAL`] if the paging mode is not Sv39, or
+/// [`LinuxError::EEXIST`] if the window is already mapped.
+pub fn kasan_early_init(root: PhysAddr) -> Result<(), LinuxError> {
+    if current_levels() != Some(3) {
+        return Err(LinuxError::EINVAL);
+    }
+    let page_pte = leaf_pte(
+        early_page_paddr(),
+        leaf_flags(
+            MemRegionFlags::READ | MemRegionFlags::WRITE,
+            MemAttr::Normal,
+        )?,
+    );
+    unsafe {
+        let window = KASAN_SHADOW_START..KASAN_SHADOW_START + KASAN_SHADOW_SIZE;
+        let root_entries = table_entries(root);
+        let indices = window
+            .step_by(ROOT_ENTRY_SIZE)
+            .map(|va| (va >> 30) % PTE_COUNT);
+        if indices.clone().any(|i| root_entries[i] & PTE_V != 0) {
+            return Err(LinuxError::EEXIST);
+        }
+        (*core::ptr::addr_of_mut!(EARLY_SHADOW_PTE))
+            .0
+            .fill(page_pte);
+        let pte_table = table_pte(table_paddr(addr_of!(EARLY_SHADOW_PTE)));
+        (*core::ptr::addr_of_mut!(EARLY_SHADOW_PMD))
+            .0
+            .fill(pte_table);
+        let pmd_table = table_pte(table_paddr(addr_of!(EARLY_SHADOW_PMD)));
+        for i in indices {
+            core::ptr::write_volatile(&mut root_entries[i], pmd_table);
+        }
+        riscv::asm::sfence_vma_all();
+    }
+    Ok(())
+}
+
+const fn leaf_pte(paddr: PhysAddr, flags: usize) -> usize {
+    ((paddr.as_usize() >> 12) << PTE_PPN_SHIFT) | flags
+}
+
+/// Replaces the entry `pte` pointing to the early `shared` table (or page)
+/// with a frame allocated with `alloc_frame` and filled with `init`, with
+/// the flags `flags`, and returns the frame.
+unsafe fn unshare(
+    pte: &mut usize,
+    shared: *const ShadowTable,
+    init: &[usize; PTE_COUNT],
+    flags: usize,
+    alloc_frame: &mut impl FnMut() -> Option<PhysAddr>,
+) -> Result<PhysAddr, LinuxError> {
+    if pte_paddr(*pte) != table_paddr(shared) {
+        return Ok(pte_paddr(*pte));
+    }
+    let frame = alloc_frame().ok_or(LinuxError::ENOMEM)?;
+    table_entries(frame).copy_from_slice(init);
+    core::ptr::write_volatile(pte, leaf_pte(frame, flags));
+    Ok(frame)
+}
+
+/// Gives its own zeroed pages, allocated with `alloc_frame`, to the shadow
+/// of `[start, start + size)` in the kernel page table at `root`, so that
+/// it can be poisoned. The memory stays accessible.
+///
+/// It is meant for the boot, with the other CPUs still offline: only the
+/// local TLB is flushed. Returns [`LinuxError::EINVAL`] if the range is
+/// not in the kernel half, or [`LinuxError::ENOMEM`] if a frame cannot be
+/// allocated, the pages allocated before staying in place.
+pub fn kasan_populate_shadow(
+    root: PhysAddr,
+    start: usize,
+    size: usize,
+    alloc_frame: &mut impl FnMut() -> Option<PhysAddr>,
+) -> Result<(), LinuxError> {
+    if start < KASAN_MEM_START || start.checked_add(size).is_none() {
+        return Err(LinuxError::EINVAL);
+    }
+    if size == 0 {
+        return Ok(());
+    }
+    let rw = leaf_flags(
+        MemRegionFlags::READ | MemRegionFlags::WRITE,
+        MemAttr::Normal,
+    )?;
+    let shadow_start = kasan_mem_to_shadow(start) & !(PAGE_SIZE_4K - 1);
+    let shadow_end = kasan_mem_to_shadow(start + size - 1) + 1;
+    let result = (shadow_start..shadow_end)
+        .step_by(PAGE_SIZE_4K)
+        .try_for_each(|va| unsafe {
+            let pmd_init = &(*addr_of!(EARLY_SHADOW_PMD)).0;
+            let pte_init = &(*addr_of!(EARLY_SHADOW_PTE)).0;
+            let root_pte = &mut table_entries(root)[(va >> 30) % PTE_COUNT];
+            let pmd = unshare(
+                root_pte,
+                addr_of!(EARLY_SHADOW_PMD),
+                pmd_init,
+                PTE_V,
+                alloc_frame,
+            )?;
+            let pmd_pte = &mut table_entries(pmd)[(va >> 21) % PTE_COUNT];
+            let pte = unshare(
+                pmd_pte,
+                addr_of!(EARLY_SHADOW_PTE),
+                pte_init,
+                PTE_V,
+                alloc_frame,
+            )?;
+            let page_pte = &mut table_entries(pte)[(va >> 12) % PTE_COUNT];
+            let early_page = addr_of!(EARLY_SHADOW_PAGE);
+            unshare(page_pte, early_page, &[0; PTE_COUNT], rw, alloc_frame)?;
+            Ok(())
+        });
+    unsafe { riscv::asm::sfence_vma_all() };
+    result
+}
+
+/// Ends the boot of the sanitizer, once the checked regions are populated:
+/// the zero page of the rest of the shadow is cleared of the early writes
+/// (e.g. the stack redzones), and mapped read-only.
+pub fn kasan_finish_init() {
+    let read_only = leaf_flags(MemRegionFlags::READ, MemAttr::Normal).unwrap_or(0);
+    let page_pte = leaf_pte(early_page_paddr(), read_only);
+    unsafe {
+        (*core::ptr::addr_of_mut!(EARLY_SHADOW_PTE))
+            .0
+            .fill(page_pte);
+        riscv::asm::sfence_vma_all();
+        (*core::ptr::addr_of_mut!(EARLY_SHADOW_PAGE)).0.fill(0);
+    }
+}
+
+/// Sets the shadow of `[addr, addr + size)` to `value`, both aligned to
+/// [`KASAN_GRANULE_SIZE`], e.g. [`KASAN_PAGE_FREE`] for freed memory.
+///
+/// # Safety
+///
+/// The shadow of the range must be populated (see
+/// [`kasan_populate_shadow`]).
+pub unsafe fn kasan_poison(addr: usize, size: usize, value: u8) {
+    let shadow = kasan_mem_to_shadow(addr) as *mut u8;
+    core::ptr::write_bytes(shadow, value, size / KASAN_GRANULE_SIZE);
+}
+
+/// Marks `[addr, addr + size)` accessible, with `addr` aligned to
+/// [`KASAN_GRANULE_SIZE`]. The rest of the last granule is not.
+///
+/// # Safety
+///
+/// See [`kasan_poison`].
+pub unsafe fn kasan_unpoison(addr: usize, size: usize) {
+    kasan_poison(addr, size, 0);
+    let tail = size % KASAN_GRANULE_SIZE;
+    if tail != 0 {
+        let shadow = kasan_mem_to_shadow(addr + size) as *mut u8;
+        shadow.write(tail as u8);
+    }
+}
+
+/// Marks the `count` pages at `paddr` of the linear mapping accessible
+/// (`poisoned` false, when they are allocated) or free.
+///
+/// # Safety
+///
+/// See [`kasan_poison`].
+pub unsafe fn kasan_poison_pages(paddr: PhysAddr, count: usize, poisoned: bool) {
+    let vaddr = phys_to_virt(paddr).as_usize();
+    let value = if poisoned { KASAN_PAGE_FREE } else { 0 };
+    kasan_poison(vaddr, count * PAGE_SIZE_4K, value);
+}
+
+/// Marks the whole kernel stack `[bottom, top)` accessible, for a stack
+/// left without returning from its frames (e.g. by a new task, or a CPU
+/// going offline) whose redzones are still poisoned.
+///
+/// # Safety
+///
+/// See [`kasan_poison`].
+pub unsafe fn kasan_unpoison_stack(bottom: usize, top: usize) {
+    kasan_unpoison(bottom, top - bottom);
+}
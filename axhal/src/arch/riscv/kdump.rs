@@ -0,0 +1,303 @@
+//! Kdump: booting a crash kernel on panic, to save the memory of this one.
+//!
+//! The memory of the crash kernel is reserved at boot, as given by the
+//! `crashkernel=` boot parameter or the device tree (see
+//! [`crash_kernel_region`]). [`kexec_load_crash`] loads the crash kernel in
+//! it. On panic, [`crash_kexec`] saves the registers of the CPUs, writes an
+//! ELF core header describing them and the memory of this kernel, then boots
+//! the crash kernel with kexec.
+//!
+//! The header is at [`crash_elfcorehdr`], at the end of the region. The
+//! loader passes it to the crash kernel in the `linux,elfcorehdr` of its
+//! `/chosen` (and the region in `linux,usable-memory-range`), as Linux does.
+//! It is an `ET_CORE` file with a `PT_NOTE` of the `NT_PRSTATUS` of each
+//! CPU, and a `PT_LOAD` for each RAM region, whose offset is the physical
+//! address.
+
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use axerrno::LinuxError;
+use memory_addr::{PhysAddr, PAGE_SIZE_4K};
+use spinbase::SpinNoIrq;
+
+use super::coredump::{note_size, write_prstatus_note, ElfPrstatus};
+use super::kexec::{self, KexecImage, KexecSegment};
+use super::misaligned::read_gpr;
+use super::TrapFrame;
+use crate::mem::{memory_regions, phys_to_virt, MemRegionFlags};
+use crate::platform::mem::crash_kernel_region;
+
+/// The maximum number of memory regions in the ELF core header.
+const MAX_CORE_LOADS: usize = 64;
+
+const EM_RISCV: u16 = 243;
+const ET_CORE: u16 = 4;
+const PT_LOAD: u32 = 1;
+const PT_NOTE: u32 = 4;
+
+#[repr(C)]
+struct Elf64Ehdr {
+    ident: [u8; 16],
+    ty: u16,
+    machine: u16,
+    version: u32,
+    entry: u64,
+    phoff: u64,
+    shoff: u64,
+    flags: u32,
+    ehsize: u16,
+    phentsize: u16,
+    phnum: u16,
+    shentsize: u16,
+    shnum: u16,
+    shstrndx: u16,
+}
+
+#[repr(C)]
+struct Elf64Phdr {
+    ty: u32,
+    flags: u32,
+    offset: u64,
+    vaddr: u64,
+    paddr: u64,
+    filesz: u64,
+    memsz: u64,
+    align: u64,
+}
+
+const NOTE_SIZE: usize = note_size("CORE", core::mem::size_of::<ElfPrstatus>());
+
+const NOTES_OFFSET: usize =
+    core::mem::size_of::<Elf64Ehdr>() + (1 + MAX_CORE_LOADS) * core::mem::size_of::<Elf64Phdr>();
+
+/// The size of the ELF core header, at the end of the crash kernel region.
+const ELFCOREHDR_SIZE: usize =
+    (NOTES_OFFSET + axconfig::SMP * NOTE_SIZE + PAGE_SIZE_4K - 1) & !(PAGE_SIZE_4K - 1);
+
+static CRASH_IMAGE: SpinNoIrq<Option<KexecImage>> = SpinNoIrq::new(None);
+
+/// The registers of each CPU saved by [`crash_kexec`], in the `pr_reg`
+/// layout. Each CPU only writes its own slot, then sets its flag in
+/// [`CRASH_REGS_SAVED`], so that the CPUs crashing at once all save theirs.
+static mut CRASH_REGS: [[usize; 32]; axconfig::SMP] = [[0; 32]; axconfig::SMP];
+
+#[allow(clippy::declare_interior_mutable_const)]
+const SAVED_INIT: AtomicBool = AtomicBool::new(false);
+
+/// Whether each slot of [`CRASH_REGS`] is saved.
+static CRASH_REGS_SAVED: [AtomicBool; axconfig::SMP] = [SAVED_INIT; axconfig::SMP];
+
+/// Whether a CPU is already in [`crash_kexec`].
+static CRASHING: AtomicBool = AtomicBool::new(false);
+
+/// Returns the physical address and the size of the ELF core header that
+/// [`crash_kexec`] writes, for the `linux,elfcorehdr` of the crash kernel,
+/// or [`None`] if no memory is reserved for it.
+pub fn crash_elfcorehdr() -> Option<(PhysAddr, usize)> {
+    let (base, size) = crash_kernel_region()?;
+    let offset = size.checked_sub(ELFCOREHDR_SIZE)?;
+    Some((base + offset, ELFCOREHDR_SIZE))
+}
+
+/// Loads the crash kernel, as [`kexec_load`](super::kexec_load), in the
+/// memory reserved for it: the segments must be in the region, below
+/// [`crash_elfcorehdr`], and the rest of it is the staging area. A previously
+/// loaded crash kernel is replaced.
+///
+/// Returns [`LinuxError::ENOMEM`] if no memory is reserved or it is too
+/// small, and [`LinuxError::EINVAL`] if a segment is outside the region.
+pub fn kexec_load_crash(
+    segments: &[KexecSegment],
+    entry: PhysAddr,
+    fdt: PhysAddr,
+) -> Result<(), LinuxError> {
+    let (base, _) = crash_kernel_region().ok_or(LinuxError::ENOMEM)?;
+    let (hdr, _) = crash_elfcorehdr().ok_or(LinuxError::ENOMEM)?;
+    let mut staging = base.as_usize();
+    for seg in segments {
+        let (dest, end) = (seg.dest.as_usize(), seg.dest.as_usize() + seg.data.len());
+        if dest < base.as_usize() || end > hdr.as_usize() {
+            return Err(LinuxError::EINVAL);
+        }
+        staging = staging.max(end);
+    }
+    let staging = PhysAddr::from(staging).align_up_4k();
+    let staging_size = hdr.as_usize().saturating_sub(staging.as_usize());
+    let image = kexec::stage_image(segments, entry, fdt, staging, staging_size)?;
+    *CRASH_IMAGE.lock() = Some(image);
+    Ok(())
+}
+
+/// Unloads the crash kernel loaded by [`kexec_load_crash`].
+pub fn kexec_unload_crash() {
+    *CRASH_IMAGE.lock() = None;
+}
+
+/// Returns the registers of the caller, for a CPU that did not trap: only
+/// `pc`, `sp` and `s0` (the frame pointer).
+fn current_regs() -> [usize; 32] {
+    let (pc, sp, fp): (usize, usize, usize);
+    unsafe {
+        core::arch::asm!(
+            "auipc {pc}, 0",
+            "mv {sp}, sp",
+            "mv {fp}, s0",
+            pc = out(reg) pc,
+            sp = out(reg) sp,
+            fp = out(reg) fp,
+        );
+    }
+    let mut regs = [0; 32];
+    regs[0] = pc;
+    regs[2] = sp;
+    regs[8] = fp;
+    regs
+}
+
+fn trap_frame_regs(tf: &TrapFrame) -> [usize; 32] {
+    let mut tf = tf.clone();
+    let mut regs = [0; 32];
+    regs[0] = tf.sepc;
+    for (i, reg) in regs.iter_mut().enumerate().skip(1) {
+        *reg = read_gpr(&mut tf, i);
+    }
+    regs
+}
+
+fn crash_save_regs(regs: [usize; 32]) {
+    let cpu_id = crate::cpu::_this_cpu_id();
+    unsafe { core::ptr::addr_of_mut!(CRASH_REGS[cpu_id]).write(regs) };
+    CRASH_REGS_SAVED[cpu_id].store(true, Ordering::Release);
+}
+
+/// Saves the interrupted registers of this CPU, then stops it.
+#[cfg(feature = "smp")]
+fn crash_stop_this_cpu() {
+    let tf = super::irq_regs();
+    if tf.is_null() {
+        crash_save_regs(current_regs());
+    } else {
+        crash_save_regs(trap_frame_regs(unsafe { &*tf }));
+    }
+    kexec::stop_this_hart();
+}
+
+/// Writes `value` at `offset` in `buf`, and returns the offset after it.
+fn put<T>(buf: &mut [u8], offset: usize, value: T) -> usize {
+    let size = core::mem::size_of::<T>();
+    let dst = &mut buf[offset..offset + size];
+    unsafe { (dst.as_mut_ptr() as *mut T).write_unaligned(value) };
+    offset + size
+}
+
+/// Writes the ELF core header at [`crash_elfcorehdr`].
+fn write_elfcorehdr() {
+    let Some(((hdr, _), (crash_base, _))) = crash_elfcorehdr().zip(crash_kernel_region()) else {
+        return;
+    };
+    let buf =
+        unsafe { core::slice::from_raw_parts_mut(phys_to_virt(hdr).as_mut_ptr(), ELFCOREHDR_SIZE) };
+    buf.fill(0);
+
+    // The notes, after the program headers.
+    let mut end = NOTES_OFFSET;
+    for (cpu_id, saved) in CRASH_REGS_SAVED.iter().enumerate() {
+        if !saved.load(Ordering::Acquire) {
+            continue;
+        }
+        let regs = unsafe { core::ptr::addr_of!(CRASH_REGS[cpu_id]).read() };
+        let prstatus = ElfPrstatus {
+            // No tasks here: the CPU ID, from 1.
+            pid: cpu_id as i32 + 1,
+            reg: regs,
+            ..Default::default()
+        };
+        end += write_prstatus_note(&mut buf[end..], &prstatus).unwrap_or(0);
+    }
+
+    let mut phnum = 0;
+    let mut offset = core::mem::size_of::<Elf64Ehdr>();
+    offset = put(
+        buf,
+        offset,
+        Elf64Phdr {
+            ty: PT_NOTE,
+            flags: 0,
+            offset: (hdr.as_usize() + NOTES_OFFSET) as u64,
+            vaddr: 0,
+            paddr: (hdr.as_usize() + NOTES_OFFSET) as u64,
+            filesz: (end - NOTES_OFFSET) as u64,
+            memsz: (end - NOTES_OFFSET) as u64,
+            align: 0,
+        },
+    );
+    phnum += 1;
+    let ram = memory_regions()
+        .filter(|r| !r.flags.contains(MemRegionFlags::DEVICE) && r.paddr != crash_base);
+    for region in ram.take(MAX_CORE_LOADS) {
+        offset = put(
+            buf,
+            offset,
+            Elf64Phdr {
+                ty: PT_LOAD,
+                // PF_R | PF_W | PF_X
+                flags: 7,
+                offset: region.paddr.as_usize() as u64,
+                vaddr: phys_to_virt(region.paddr).as_usize() as u64,
+                paddr: region.paddr.as_usize() as u64,
+                filesz: region.size as u64,
+                memsz: region.size as u64,
+                align: 0,
+            },
+        );
+        phnum += 1;
+    }
+
+    let mut ident = [0; 16];
+    // ELFCLASS64, ELFDATA2LSB, EV_CURRENT
+    ident[..7].copy_from_slice(b"\x7fELF\x02\x01\x01");
+    put(
+        buf,
+        0,
+        Elf64Ehdr {
+            ident,
+            ty: ET_CORE,
+            machine: EM_RISCV,
+            version: 1,
+            entry: 0,
+            phoff: core::mem::size_of::<Elf64Ehdr>() as u64,
+            shoff: 0,
+            flags: 0,
+            ehsize: core::mem::size_of::<Elf64Ehdr>() as u16,
+            phentsize: core::mem::size_of::<Elf64Phdr>() as u16,
+            phnum,
+            shentsize: 0,
+            shnum: 0,
+            shstrndx: 0,
+        },
+    );
+}
+
+/// Boots the crash kernel loaded by [`kexec_load_crash`], if any, from the
+/// panic handler, with `tf` the registers of the context that crashed (the
+/// caller's if [`None`]).
+///
+/// It saves the registers of the CPUs (the other ones those set by
+/// [`set_irq_regs`](super::set_irq_regs), as they are stopped by an IPI),
+/// writes the ELF core header, and does not return. It returns if there is
+/// no crash kernel, or if another CPU is already in it.
+pub fn crash_kexec(tf: Option<&TrapFrame>) {
+    let Some(image) = CRASH_IMAGE.try_lock().and_then(|image| *image) else {
+        return;
+    };
+    if CRASHING.swap(true, Ordering::AcqRel) {
+        return;
+    }
+    super::disable_irqs();
+    crash_save_regs(tf.map_or_else(current_regs, trap_frame_regs));
+    #[cfg(feature = "smp")]
+    kexec::stop_other_harts(&crash_stop_this_cpu);
+    write_elfcorehdr();
+    error!("kdump: booting the crash kernel");
+    kexec::boot_image(image)
+}
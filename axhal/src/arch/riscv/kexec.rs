@@ -0,0 +1,288 @@
+//! Kexec: loading a new kernel image, and jumping to it.
+//!
+//! [`kexec_load`] copies the segments of the new kernel (the image, the FDT,
+//! an initrd...) to a staging area provided by the caller, with the code
+//! that moves them to their load addresses. [`kexec_execute`] stops the
+//! other harts with the SBI HSM extension (so the new kernel can start them
+//! again), disables the interrupts and paging, copies the segments and
+//! jumps to the entry with the boot protocol registers: `a0` the hart ID and
+//! `a1` the physical address of the FDT.
+//!
+//! Paging is turned off in the kernel text: `stvec` is pointed at the
+//! physical address of the next instruction, which the fetch after clearing
+//! `satp` faults to. The copy then runs from the staging area, as the
+//! segments may overwrite the current kernel.
+
+use axerrno::LinuxError;
+use memory_addr::PhysAddr;
+use spinbase::SpinNoIrq;
+
+use crate::mem::phys_to_virt;
+
+include_asm_marcos!();
+
+/// The maximum number of segments of a kexec image.
+pub const MAX_KEXEC_SEGMENTS: usize = 16;
+
+const XLENB: usize = core::mem::size_of::<usize>();
+
+/// A segment of the new kernel: `data` is loaded at `dest`.
+#[derive(Debug, Clone, Copy)]
+pub struct KexecSegment<'a> {
+    /// The content of the segment.
+    pub data: &'a [u8],
+    /// The physical address where it is loaded, `usize`-aligned.
+    pub dest: PhysAddr,
+}
+
+/// A segment copied by `__kexec_copy`, read by the assembly.
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct StagedSegment {
+    src: usize,
+    dest: usize,
+    /// A multiple of `XLENB`.
+    size: usize,
+}
+
+/// A loaded image, with the physical addresses in the staging area.
+#[derive(Clone, Copy)]
+pub(super) struct KexecImage {
+    entry: usize,
+    fdt: usize,
+    copy_code: usize,
+    segments: usize,
+    count: usize,
+}
+
+static KEXEC_IMAGE: SpinNoIrq<Option<KexecImage>> = SpinNoIrq::new(None);
+
+core::arch::global_asm!(
+    r"
+    .section .text
+    .balign 4
+    // a0: hart ID, a1: FDT, a2: entry, a3: segments, a4: count,
+    // a5: `__kexec_copy` in the staging area, a6: PHYS_VIRT_OFFSET.
+    .global __kexec_start
+    __kexec_start:
+        csrw    sie, zero
+        csrw    sip, zero
+        la      t0, 1f
+        sub     t0, t0, a6
+        csrw    stvec, t0
+        csrw    satp, zero
+    .balign 4
+    1:
+        sfence.vma
+        fence.i
+        jr      a5
+
+    // Position independent, it runs from the staging area.
+    .global __kexec_copy
+    __kexec_copy:
+        beqz    a4, 3f
+    1:
+        LDR     t0, a3, 0                   // src
+        LDR     t1, a3, 1                   // dest
+        LDR     t2, a3, 2                   // size
+    2:
+        beqz    t2, 4f
+        LDR     t3, t0, 0
+        STR     t3, t1, 0
+        addi    t0, t0, XLENB
+        addi    t1, t1, XLENB
+        addi    t2, t2, -XLENB
+        j       2b
+    4:
+        addi    a3, a3, 3 * XLENB
+        addi    a4, a4, -1
+        bnez    a4, 1b
+    3:
+        fence.i
+        mv      t0, a2
+        li      a2, 0
+        li      a3, 0
+        li      a4, 0
+        li      a5, 0
+        li      a6, 0
+        jr      t0
+    .global __kexec_copy_end
+    __kexec_copy_end:
+    "
+);
+
+extern "C" {
+    fn __kexec_start(
+        hartid: usize,
+        fdt: usize,
+        entry: usize,
+        segments: usize,
+        count: usize,
+        copy_code: usize,
+        phys_virt_offset: usize,
+    ) -> !;
+    fn __kexec_copy();
+    fn __kexec_copy_end();
+}
+
+const fn align_up(value: usize, align: usize) -> usize {
+    (value + align - 1) & !(align - 1)
+}
+
+/// Copies `segments` and the copy code to the staging area, for
+/// [`boot_image`].
+pub(super) fn stage_image(
+    segments: &[KexecSegment],
+    entry: PhysAddr,
+    fdt: PhysAddr,
+    staging: PhysAddr,
+    staging_size: usize,
+) -> Result<KexecImage, LinuxError> {
+    let staging = staging.as_usize();
+    if segments.len() > MAX_KEXEC_SEGMENTS || staging % XLENB != 0 {
+        return Err(LinuxError::EINVAL);
+    }
+    for seg in segments {
+        let dest = seg.dest.as_usize();
+        let end = dest + align_up(seg.data.len(), XLENB);
+        if dest % XLENB != 0 || (dest < staging + staging_size && staging < end) {
+            return Err(LinuxError::EINVAL);
+        }
+    }
+    // The copy code, the segment table, then the data of the segments.
+    let copy_code_size = __kexec_copy_end as usize - __kexec_copy as usize;
+    let table_offset = align_up(copy_code_size, XLENB);
+    let data_offset = table_offset + segments.len() * core::mem::size_of::<StagedSegment>();
+    let total = segments.iter().fold(data_offset, |offset, seg| {
+        offset + align_up(seg.data.len(), XLENB)
+    });
+    if total > staging_size {
+        return Err(LinuxError::ENOMEM);
+    }
+
+    let staged = |offset: usize| phys_to_virt(PhysAddr::from(staging + offset)).as_mut_ptr();
+    unsafe {
+        let code = __kexec_copy as usize as *const u8;
+        core::ptr::copy_nonoverlapping(code, staged(0), copy_code_size);
+        let table = staged(table_offset) as *mut StagedSegment;
+        let mut offset = data_offset;
+        for (i, seg) in segments.iter().enumerate() {
+            let size = align_up(seg.data.len(), XLENB);
+            let dst = staged(offset);
+            core::ptr::copy_nonoverlapping(seg.data.as_ptr(), dst, seg.data.len());
+            core::ptr::write_bytes(dst.add(seg.data.len()), 0, size - seg.data.len());
+            table.add(i).write(StagedSegment {
+                src: staging + offset,
+                dest: seg.dest.as_usize(),
+                size,
+            });
+            offset += size;
+        }
+    }
+    Ok(KexecImage {
+        entry: entry.as_usize(),
+        fdt: fdt.as_usize(),
+        copy_code: staging,
+        segments: staging + table_offset,
+        count: segments.len(),
+    })
+}
+
+/// Loads the new kernel: copies `segments` to the `staging_size` bytes at
+/// `staging`, for [`kexec_execute`] to jump to `entry`, with the FDT at
+/// `fdt` (in one of the segments).
+///
+/// The staging area must be RAM not used for anything else (e.g. allocated
+/// frames), and must not overlap the segments. Their sizes are rounded up to
+/// `usize`. A previously loaded image is replaced.
+///
+/// Returns [`LinuxError::EINVAL`] if there are too many segments, or if they
+/// or the staging area are misaligned or overlap, and
+/// [`LinuxError::ENOMEM`] if the staging area is too small.
+pub fn kexec_load(
+    segments: &[KexecSegment],
+    entry: PhysAddr,
+    fdt: PhysAddr,
+    staging: PhysAddr,
+    staging_size: usize,
+) -> Result<(), LinuxError> {
+    let image = stage_image(segments, entry, fdt, staging, staging_size)?;
+    *KEXEC_IMAGE.lock() = Some(image);
+    Ok(())
+}
+
+/// Unloads the image loaded by [`kexec_load`].
+pub fn kexec_unload() {
+    *KEXEC_IMAGE.lock() = None;
+}
+
+/// How long [`kexec_execute`] waits for the other harts to stop.
+#[cfg(feature = "smp")]
+const HART_STOP_TIMEOUT: core::time::Duration = core::time::Duration::from_secs(1);
+
+/// Takes this hart offline and stops it, from an IPI handler.
+#[cfg(feature = "smp")]
+pub(super) fn stop_this_hart() {
+    super::disable_irqs();
+    crate::cpu::cpu_offline();
+    super::sbi::hart_stop();
+    // Without HSM, at least keep it out of the way.
+    loop {
+        super::wait_for_irqs();
+    }
+}
+
+/// Makes the other online harts run `stop`, which ends with
+/// [`stop_this_hart`], and waits until the firmware reports them stopped.
+#[cfg(feature = "smp")]
+pub(super) fn stop_other_harts(stop: &'static (dyn Fn() + Sync)) {
+    let mut others = crate::cpu::online_cpus();
+    others.remove(crate::cpu::_this_cpu_id());
+    super::smp_call_function_nowait(others, stop);
+    let deadline = crate::time::current_time() + HART_STOP_TIMEOUT;
+    for hartid in others.iter().filter_map(crate::cpu::cpu_to_hartid) {
+        while super::sbi::hart_status(hartid)
+            .is_ok_and(|status| status != super::sbi::HSM_STATUS_STOPPED)
+        {
+            if crate::time::current_time() >= deadline {
+                warn!("kexec: hart {} did not stop", hartid);
+                break;
+            }
+            core::hint::spin_loop();
+        }
+    }
+}
+
+/// Jumps to the kernel loaded by [`kexec_load`], after stopping the other
+/// harts and disabling the interrupts and paging.
+///
+/// It does not return, unless no image is loaded
+/// ([`LinuxError::EINVAL`]). The devices should be shut down first, as
+/// their DMA would corrupt the new kernel.
+pub fn kexec_execute() -> Result<(), LinuxError> {
+    let image = (*KEXEC_IMAGE.lock()).ok_or(LinuxError::EINVAL)?;
+    super::disable_irqs();
+    #[cfg(feature = "smp")]
+    stop_other_harts(&stop_this_hart);
+    boot_image(image)
+}
+
+/// Jumps to `image` on this hart, the other ones being stopped.
+pub(super) fn boot_image(image: KexecImage) -> ! {
+    let hartid = crate::cpu::cpu_to_hartid(crate::cpu::_this_cpu_id()).unwrap_or(0);
+    info!(
+        "kexec: jumping to {:#x} on hart {}, FDT at {:#x}",
+        image.entry, hartid, image.fdt
+    );
+    unsafe {
+        __kexec_start(
+            hartid,
+            image.fdt,
+            image.entry,
+            image.segments,
+            image.count,
+            image.copy_code,
+            axconfig::PHYS_VIRT_OFFSET,
+        )
+    }
+}
@@ -0,0 +1,323 @@
+//! Kprobes: dynamic instrumentation of the kernel instructions.
+//!
+//! [`register_kprobe`] replaces the instruction at an address with an
+//! `ebreak` (or `c.ebreak`), with [`patch_text`](super::patch_text). When it
+//! is hit, [`handle_kprobe`] calls the pre-handler, runs the displaced
+//! instruction, then calls the post-handler:
+//!
+//! - the PC-relative instructions (`auipc`, the jumps and the branches) are
+//!   simulated on the trap frame;
+//! - the other ones are single-stepped out of line, in a slot of
+//!   [`__kprobe_insn_slots`] followed by an `ebreak`, with the interrupts
+//!   disabled until it is hit.
+//!
+//! The privileged instructions, `ebreak`, and the `lr`/`sc` sequences cannot
+//! be probed.
+
+use axerrno::LinuxError;
+use spinbase::SpinNoIrq;
+
+use super::misaligned::{read_gpr, write_gpr};
+use super::single_step::{branch_offset, c_branch_offset, c_jump_offset, jal_offset, sign_extend};
+use super::{TrapFrame, SR_SPIE};
+
+/// The maximum number of kprobes.
+pub const MAX_KPROBES: usize = 64;
+
+/// The size of an out-of-line slot: the instruction and an `ebreak`.
+const KPROBE_SLOT_SIZE: usize = 8;
+
+const EBREAK: u32 = 0x0010_0073;
+const C_EBREAK: u32 = 0x9002;
+
+/// The type of a kprobe handler. It receives the trap frame and the address
+/// of the probe.
+///
+/// A pre-handler which changes `sepc` skips the displaced instruction and
+/// the post-handler, e.g. to override the probed function.
+pub type KprobeHandler = fn(&mut TrapFrame, usize);
+
+#[derive(Clone, Copy)]
+struct Kprobe {
+    addr: usize,
+    insn: u32,
+    pre_handler: Option<KprobeHandler>,
+    post_handler: Option<KprobeHandler>,
+}
+
+impl Kprobe {
+    const fn len(&self) -> usize {
+        insn_len(self.insn)
+    }
+}
+
+/// The probe whose instruction is running out of line on a CPU.
+#[derive(Clone, Copy)]
+struct OutOfLine {
+    slot: usize,
+    probe: Kprobe,
+    /// The `SPIE` bit of the probed context.
+    spie: usize,
+}
+
+static KPROBES: SpinNoIrq<[Option<Kprobe>; MAX_KPROBES]> = SpinNoIrq::new([None; MAX_KPROBES]);
+
+#[percpu2::def_percpu]
+static KPROBE_OUT_OF_LINE: Option<OutOfLine> = None;
+
+core::arch::global_asm!(
+    ".section .text",
+    ".balign 4",
+    ".global __kprobe_insn_slots",
+    "__kprobe_insn_slots:",
+    ".space {size}",
+    size = const MAX_KPROBES * KPROBE_SLOT_SIZE,
+);
+
+extern "C" {
+    /// The out-of-line slots of the displaced instructions, in the kernel
+    /// text.
+    pub fn __kprobe_insn_slots();
+}
+
+pub(super) const fn insn_len(insn: u32) -> usize {
+    if insn & 0b11 == 0b11 {
+        4
+    } else {
+        2
+    }
+}
+
+fn slot_addr(slot: usize) -> usize {
+    __kprobe_insn_slots as usize + slot * KPROBE_SLOT_SIZE
+}
+
+/// Reads the instruction at `addr`, with fault fixup.
+pub(super) fn read_insn(addr: usize) -> Option<u32> {
+    let mut buf = [0; 4];
+    if super::copy_from_kernel_nofault(&mut buf[..2], addr) != 0 {
+        return None;
+    }
+    if buf[0] & 0b11 == 0b11 && super::copy_from_kernel_nofault(&mut buf[2..], addr + 2) != 0 {
+        return None;
+    }
+    let insn = u32::from_le_bytes(buf);
+    Some(if insn_len(insn) == 2 {
+        insn & 0xffff
+    } else {
+        insn
+    })
+}
+
+fn can_probe(insn: u32) -> bool {
+    if insn_len(insn) == 2 {
+        // c.ebreak, or the illegal instruction 0.
+        return insn != C_EBREAK && insn != 0;
+    }
+    match insn & 0x7f {
+        // ecall, ebreak, sret, wfi, sfence.vma...
+        0x73 => (insn >> 12) & 0b111 != 0,
+        // lr, sc
+        0x2f => !matches!(insn >> 27, 0b00010 | 0b00011),
+        _ => true,
+    }
+}
+
+/// Simulates the PC-relative instruction `insn` at `pc` on `tf`, and returns
+/// whether it was one.
+fn simulate(tf: &mut TrapFrame, pc: usize, insn: u32) -> bool {
+    let next = pc + insn_len(insn);
+    let rd = ((insn >> 7) & 0x1f) as usize;
+    let rs1 = ((insn >> 15) & 0x1f) as usize;
+    let rs2 = ((insn >> 20) & 0x1f) as usize;
+    let target = if insn_len(insn) == 4 {
+        match insn & 0x7f {
+            // auipc
+            0x17 => {
+                write_gpr(tf, rd, pc.wrapping_add(sign_extend(insn & 0xffff_f000, 32)));
+                next
+            }
+            // jal
+            0x6f => {
+                write_gpr(tf, rd, next);
+                pc.wrapping_add(jal_offset(insn))
+            }
+            // jalr
+            0x67 => {
+                let target = read_gpr(tf, rs1).wrapping_add(sign_extend(insn >> 20, 12)) & !1;
+                write_gpr(tf, rd, next);
+                target
+            }
+            // beq, bne, blt, bge, bltu, bgeu
+            0x63 => {
+                let (a, b) = (read_gpr(tf, rs1), read_gpr(tf, rs2));
+                let taken = match (insn >> 12) & 0b111 {
+                    0b000 => a == b,
+                    0b001 => a != b,
+                    0b100 => (a as isize) < (b as isize),
+                    0b101 => (a as isize) >= (b as isize),
+                    0b110 => a < b,
+                    0b111 => a >= b,
+                    _ => return false,
+                };
+                if taken {
+                    pc.wrapping_add(branch_offset(insn))
+                } else {
+                    next
+                }
+            }
+            _ => return false,
+        }
+    } else {
+        let funct3 = (insn >> 13) & 0b111;
+        match (insn & 0b11, funct3) {
+            // c.j
+            (0b01, 0b101) => pc.wrapping_add(c_jump_offset(insn)),
+            // c.jal on RV32
+            (0b01, 0b001) if usize::BITS == 32 => {
+                write_gpr(tf, 1, next);
+                pc.wrapping_add(c_jump_offset(insn))
+            }
+            // c.beqz, c.bnez
+            (0b01, 0b110) | (0b01, 0b111) => {
+                let zero = read_gpr(tf, ((insn >> 7) & 0b111) as usize + 8) == 0;
+                if zero == (funct3 == 0b110) {
+                    pc.wrapping_add(c_branch_offset(insn))
+                } else {
+                    next
+                }
+            }
+            // c.jr, c.jalr
+            (0b10, 0b100) if (insn >> 2) & 0x1f == 0 && rd != 0 => {
+                let target = read_gpr(tf, rd) & !1;
+                if (insn >> 12) & 1 == 1 {
+                    write_gpr(tf, 1, next);
+                }
+                target
+            }
+            _ => return false,
+        }
+    };
+    tf.sepc = target;
+    true
+}
+
+/// Registers a kprobe at the kernel instruction `addr`, and returns its
+/// handle for [`unregister_kprobe`].
+///
+/// Returns [`LinuxError::EINVAL`] if the instruction cannot be probed,
+/// [`LinuxError::EEXIST`] if there is already a probe at `addr`,
+/// [`LinuxError::ENOSPC`] if there are too many probes, or
+/// [`LinuxError::EFAULT`] if the text cannot be read or patched.
+pub fn register_kprobe(
+    addr: usize,
+    pre_handler: Option<KprobeHandler>,
+    post_handler: Option<KprobeHandler>,
+) -> Result<usize, LinuxError> {
+    if addr % 2 != 0 {
+        return Err(LinuxError::EINVAL);
+    }
+    let insn = read_insn(addr).ok_or(LinuxError::EFAULT)?;
+    if !can_probe(insn) {
+        return Err(LinuxError::EINVAL);
+    }
+    let probe = Kprobe {
+        addr,
+        insn,
+        pre_handler,
+        post_handler,
+    };
+    let slot = {
+        let mut kprobes = KPROBES.lock();
+        if kprobes.iter().flatten().any(|p| p.addr == addr) {
+            return Err(LinuxError::EEXIST);
+        }
+        let slot = kprobes
+            .iter()
+            .position(Option::is_none)
+            .ok_or(LinuxError::ENOSPC)?;
+        kprobes[slot] = Some(probe);
+        slot
+    };
+    // The lock is not held while patching: the other CPUs may need it to
+    // finish the probes they are running before they can be parked.
+    let mut out_of_line = [0; KPROBE_SLOT_SIZE];
+    let len = probe.len();
+    out_of_line[..len].copy_from_slice(&insn.to_le_bytes()[..len]);
+    out_of_line[len..len + 4].copy_from_slice(&EBREAK.to_le_bytes());
+    let breakpoint = if len == 2 { C_EBREAK } else { EBREAK };
+    let patched = super::patch_text(slot_addr(slot), &out_of_line)
+        .and_then(|_| super::patch_text(addr, &breakpoint.to_le_bytes()[..len]));
+    if let Err(err) = patched {
+        KPROBES.lock()[slot] = None;
+        return Err(err);
+    }
+    Ok(slot)
+}
+
+/// Unregisters the kprobe `handle`, and restores its instruction.
+pub fn unregister_kprobe(handle: usize) -> Result<(), LinuxError> {
+    let probe = KPROBES
+        .lock()
+        .get(handle)
+        .copied()
+        .flatten()
+        .ok_or(LinuxError::EINVAL)?;
+    // The breakpoint may be hit until it is restored.
+    super::patch_text(probe.addr, &probe.insn.to_le_bytes()[..probe.len()])?;
+    KPROBES.lock()[handle] = None;
+    Ok(())
+}
+
+/// Handles a kernel breakpoint exception at a kprobe, or at the end of its
+/// out-of-line instruction, and returns `true`. Returns `false` if it is
+/// not one.
+///
+/// [`handle_breakpoint`](super::handle_breakpoint) calls it first.
+pub fn handle_kprobe(tf: &mut TrapFrame) -> bool {
+    if tf.from_user() {
+        return false;
+    }
+    let pc = tf.sepc;
+    let out_of_line = unsafe { KPROBE_OUT_OF_LINE.current_ref_mut_raw() };
+    if let Some(ool) = *out_of_line {
+        if pc == slot_addr(ool.slot) + ool.probe.len() {
+            *out_of_line = None;
+            tf.sepc = ool.probe.addr + ool.probe.len();
+            tf.sstatus |= ool.spie;
+            if let Some(post) = ool.probe.post_handler {
+                post(tf, ool.probe.addr);
+            }
+            return true;
+        }
+    }
+    let found = KPROBES
+        .lock()
+        .iter()
+        .enumerate()
+        .find_map(|(slot, p)| p.filter(|p| p.addr == pc).map(|p| (slot, p)));
+    let Some((slot, probe)) = found else {
+        return false;
+    };
+    if let Some(pre) = probe.pre_handler {
+        pre(tf, pc);
+        if tf.sepc != pc {
+            return true;
+        }
+    }
+    if simulate(tf, pc, probe.insn) {
+        if let Some(post) = probe.post_handler {
+            post(tf, pc);
+        }
+        return true;
+    }
+    *out_of_line = Some(OutOfLine {
+        slot,
+        probe,
+        spie: tf.sstatus & SR_SPIE,
+    });
+    // No interrupt until the out-of-line `ebreak`.
+    tf.sstatus &= !SR_SPIE;
+    tf.sepc = slot_addr(slot);
+    true
+}
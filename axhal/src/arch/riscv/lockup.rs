@@ -0,0 +1,276 @@
+//! Soft and hard lockup detector.
+//!
+//! A soft lockup is a CPU that still takes its interrupts, but on which the
+//! scheduler makes no progress (a task hogs it in the kernel). A hard lockup
+//! is a CPU that takes no timer interrupt either (it spins with the
+//! interrupts disabled). The scheduler reports its progress with
+//! [`touch_softlockup_watchdog`], also from the idle loop, and the timer
+//! interrupt handler counts the ticks: the timer must tick more often than
+//! the threshold on a CPU with the detector (a tickless idle CPU stops it).
+//!
+//! [`lockup_detector_start`] enables the checks on the current CPU, twice
+//! per threshold: in the overflow interrupt of a CPU cycles counter
+//! (Sscofpmf), which does not depend on the timer, or else in the timer
+//! interrupt. As S-mode has no NMI, a CPU spinning with the interrupts
+//! disabled cannot check itself: each CPU also checks the next online CPU
+//! with the detector (its buddy), whose registers cannot be dumped then.
+
+use axerrno::LinuxError;
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use core::time::Duration;
+use spinbase::SpinNoIrq;
+
+use super::TrapFrame;
+use crate::cpu::_this_cpu_id;
+use crate::time::current_time_nanos;
+
+/// The kind of a lockup.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LockupKind {
+    /// The scheduler made no progress.
+    Soft,
+    /// The CPU took no timer interrupt.
+    Hard,
+}
+
+/// The type of a lockup handler, it receives the stuck CPU and, if it is
+/// the current one, the interrupted trap frame.
+pub type LockupHandler = fn(cpu_id: usize, kind: LockupKind, tf: Option<&TrapFrame>);
+
+static LOCKUP_HANDLER: SpinNoIrq<Option<LockupHandler>> = SpinNoIrq::new(None);
+
+/// The lockup threshold, in nanoseconds.
+static THRESHOLD_NS: AtomicU64 = AtomicU64::new(0);
+
+/// The frequency of the `cycle` counter, measured once.
+static CYCLES_PER_SEC: AtomicU64 = AtomicU64::new(0);
+
+struct LockupState {
+    enabled: AtomicBool,
+    /// Whether the checks run in a counter-overflow interrupt.
+    pmu_driven: AtomicBool,
+    /// The timer interrupts taken.
+    ticks: AtomicU64,
+    /// The last report of the scheduler progress, in nanoseconds.
+    touched_ns: AtomicU64,
+    /// The last check of this CPU, by itself.
+    checked_ns: AtomicU64,
+    /// `ticks` and `touched_ns` when they last changed, seen by a hard
+    /// lockup check, and when.
+    seen_ticks: AtomicU64,
+    seen_touched_ns: AtomicU64,
+    seen_ns: AtomicU64,
+    /// The lockup has been reported, until the CPU makes progress again.
+    soft_reported: AtomicBool,
+    hard_reported: AtomicBool,
+}
+
+#[allow(clippy::declare_interior_mutable_const)]
+const LOCKUP_STATE_INIT: LockupState = LockupState {
+    enabled: AtomicBool::new(false),
+    pmu_driven: AtomicBool::new(false),
+    ticks: AtomicU64::new(0),
+    touched_ns: AtomicU64::new(0),
+    checked_ns: AtomicU64::new(0),
+    seen_ticks: AtomicU64::new(0),
+    seen_touched_ns: AtomicU64::new(0),
+    seen_ns: AtomicU64::new(0),
+    soft_reported: AtomicBool::new(false),
+    hard_reported: AtomicBool::new(false),
+};
+
+static LOCKUP_STATES: [LockupState; axconfig::SMP] = [LOCKUP_STATE_INIT; axconfig::SMP];
+
+/// No sampling counter.
+const NO_COUNTER: u32 = u32::MAX;
+
+/// The sampling counter of the detector on each CPU, or [`NO_COUNTER`].
+#[percpu2::def_percpu]
+static LOCKUP_COUNTER: u32 = NO_COUNTER;
+
+/// Registers the handler of the lockups, in place of the default report
+/// (the registers and the backtrace of the current CPU to the log).
+pub fn set_lockup_handler(f: LockupHandler) {
+    *LOCKUP_HANDLER.lock() = Some(f);
+}
+
+fn read_cycle() -> u64 {
+    let cycle: usize;
+    unsafe { core::arch::asm!("rdcycle {}", out(reg) cycle) };
+    cycle as u64
+}
+
+/// Returns the frequency of the `cycle` counter, measured against the
+/// `time` counter for 10 ms the first time.
+fn cycles_per_sec() -> u64 {
+    match CYCLES_PER_SEC.load(Ordering::Relaxed) {
+        0 => {
+            const CALIBRATION: Duration = Duration::from_millis(10);
+            let start = read_cycle();
+            crate::time::busy_wait(CALIBRATION);
+            let freq = (read_cycle() - start) * (1000 / CALIBRATION.as_millis() as u64);
+            CYCLES_PER_SEC.store(freq, Ordering::Relaxed);
+            freq
+        }
+        freq => freq,
+    }
+}
+
+/// Starts the lockup detector on the current CPU: a lockup is reported when
+/// the CPU makes no progress for `threshold`, which is shared by all CPUs.
+///
+/// Returns [`LinuxError::EINVAL`] if `threshold` is shorter than 1 ms, and
+/// [`LinuxError::EBUSY`] if it is already started.
+pub fn lockup_detector_start(threshold: Duration) -> Result<(), LinuxError> {
+    if threshold < Duration::from_millis(1) {
+        return Err(LinuxError::EINVAL);
+    }
+    let _guard = kernel_guard_base::IrqSave::new();
+    let state = &LOCKUP_STATES[_this_cpu_id()];
+    if state.enabled.load(Ordering::Relaxed) {
+        return Err(LinuxError::EBUSY);
+    }
+    THRESHOLD_NS.store(threshold.as_nanos() as u64, Ordering::Relaxed);
+    let now = current_time_nanos();
+    state.touched_ns.store(now, Ordering::Relaxed);
+    state.checked_ns.store(now, Ordering::Relaxed);
+    state
+        .seen_ticks
+        .store(state.ticks.load(Ordering::Relaxed), Ordering::Relaxed);
+    state.seen_touched_ns.store(now, Ordering::Relaxed);
+    state.seen_ns.store(now, Ordering::Relaxed);
+    state.soft_reported.store(false, Ordering::Relaxed);
+    state.hard_reported.store(false, Ordering::Relaxed);
+
+    let counter = super::has_sscofpmf()
+        .then(|| {
+            let period = (cycles_per_sec() as u128 * threshold.as_nanos() / 2_000_000_000) as u64;
+            super::pmu::start_sampling(
+                super::PMU_EVENT_CPU_CYCLES,
+                period.max(1),
+                Some(lockup_overflow_handler),
+            )
+            .ok()
+        })
+        .flatten();
+    unsafe { LOCKUP_COUNTER.write_current_raw(counter.unwrap_or(NO_COUNTER)) };
+    state.pmu_driven.store(counter.is_some(), Ordering::Relaxed);
+    state.enabled.store(true, Ordering::Release);
+    Ok(())
+}
+
+/// Stops the lockup detector on the current CPU.
+pub fn lockup_detector_stop() {
+    let _guard = kernel_guard_base::IrqSave::new();
+    LOCKUP_STATES[_this_cpu_id()]
+        .enabled
+        .store(false, Ordering::Release);
+    let counter = unsafe { LOCKUP_COUNTER.read_current_raw() };
+    if counter != NO_COUNTER {
+        super::pmu_stop_sampling(counter);
+        unsafe { LOCKUP_COUNTER.write_current_raw(NO_COUNTER) };
+    }
+}
+
+/// Reports that the scheduler made progress on the current CPU.
+///
+/// It must be called more often than the threshold: at least on each
+/// context switch and in the idle loop.
+pub fn touch_softlockup_watchdog() {
+    let state = &LOCKUP_STATES[_this_cpu_id()];
+    state
+        .touched_ns
+        .store(current_time_nanos(), Ordering::Relaxed);
+    state.soft_reported.store(false, Ordering::Relaxed);
+}
+
+/// Counts a timer interrupt on the current CPU, and runs the checks if they
+/// are not driven by a counter overflow. Called by the timer interrupt
+/// handler.
+pub(crate) fn lockup_timer_tick() {
+    let state = &LOCKUP_STATES[_this_cpu_id()];
+    state.ticks.fetch_add(1, Ordering::Relaxed);
+    if !state.enabled.load(Ordering::Acquire) || state.pmu_driven.load(Ordering::Relaxed) {
+        return;
+    }
+    let now = current_time_nanos();
+    let period = THRESHOLD_NS.load(Ordering::Relaxed) / 2;
+    if now - state.checked_ns.load(Ordering::Relaxed) >= period {
+        let tf = super::irq_regs();
+        check(now, unsafe { tf.as_ref() });
+    }
+}
+
+fn lockup_overflow_handler(tf: &mut TrapFrame, _counter: u32) {
+    check(current_time_nanos(), Some(tf));
+}
+
+/// Returns whether the CPU of `state` took no timer interrupt and made no
+/// progress for `threshold`, and it is not reported yet.
+fn hard_locked_up(state: &LockupState, now: u64, threshold: u64) -> bool {
+    let ticks = state.ticks.load(Ordering::Relaxed);
+    let touched_ns = state.touched_ns.load(Ordering::Relaxed);
+    if ticks != state.seen_ticks.load(Ordering::Relaxed)
+        || touched_ns != state.seen_touched_ns.load(Ordering::Relaxed)
+    {
+        state.seen_ticks.store(ticks, Ordering::Relaxed);
+        state.seen_touched_ns.store(touched_ns, Ordering::Relaxed);
+        state.seen_ns.store(now, Ordering::Relaxed);
+        state.hard_reported.store(false, Ordering::Relaxed);
+        return false;
+    }
+    now.saturating_sub(state.seen_ns.load(Ordering::Relaxed)) >= threshold
+        && !state.hard_reported.swap(true, Ordering::Relaxed)
+}
+
+/// Checks the current CPU and its buddy.
+fn check(now: u64, tf: Option<&TrapFrame>) {
+    let cpu_id = _this_cpu_id();
+    let state = &LOCKUP_STATES[cpu_id];
+    state.checked_ns.store(now, Ordering::Relaxed);
+    let threshold = THRESHOLD_NS.load(Ordering::Relaxed);
+    let stalled = now.saturating_sub(state.touched_ns.load(Ordering::Relaxed));
+    if stalled >= threshold && !state.soft_reported.swap(true, Ordering::Relaxed) {
+        report(cpu_id, LockupKind::Soft, stalled, tf);
+    }
+    // Only a check that does not need the timer can see it stop.
+    if state.pmu_driven.load(Ordering::Relaxed) && hard_locked_up(state, now, threshold) {
+        report(cpu_id, LockupKind::Hard, stalled, tf);
+    }
+    #[cfg(feature = "smp")]
+    check_buddy(cpu_id, now, threshold);
+}
+
+/// Checks the next online CPU with the detector.
+#[cfg(feature = "smp")]
+fn check_buddy(cpu_id: usize, now: u64, threshold: u64) {
+    let online = crate::cpu::online_cpus();
+    let Some(buddy) = (cpu_id + 1..axconfig::SMP)
+        .chain(0..cpu_id)
+        .find(|&cpu| online.contains(cpu) && LOCKUP_STATES[cpu].enabled.load(Ordering::Acquire))
+    else {
+        return;
+    };
+    let state = &LOCKUP_STATES[buddy];
+    if hard_locked_up(state, now, threshold) {
+        let stalled = now.saturating_sub(state.touched_ns.load(Ordering::Relaxed));
+        report(buddy, LockupKind::Hard, stalled, None);
+    }
+}
+
+fn report(cpu_id: usize, kind: LockupKind, stalled_ns: u64, tf: Option<&TrapFrame>) {
+    if let Some(handler) = *LOCKUP_HANDLER.lock() {
+        return handler(cpu_id, kind, tf);
+    }
+    let stalled = Duration::from_nanos(stalled_ns);
+    match kind {
+        LockupKind::Soft => error!("Soft lockup on CPU {}, stuck for {:?}", cpu_id, stalled),
+        LockupKind::Hard => error!("Hard lockup on CPU {}, stuck for {:?}", cpu_id, stalled),
+    }
+    if cpu_id == _this_cpu_id() {
+        if let Some(tf) = tf {
+            error!("{:#x?}", tf);
+        }
+        super::print_backtrace(tf);
+    }
+}
@@ -3,7 +3,7 @@ macro_rules! include_asm_marcos {
     () => {
         #[cfg(target_arch = "riscv32")]
         core::arch::global_asm!(
-            r"
+            r#"
         .ifndef XLENB
         .equ XLENB, 4
 
@@ -13,13 +13,33 @@ macro_rules! include_asm_marcos {
         .macro STR rs2, rs1, off
             sw \rs2, \off*XLENB(\rs1)
         .endm
+        // Loads the word `off` of the per-CPU variable `sym` of this CPU,
+        // whose per-CPU area is at `gp`.
+        .macro LDR_PERCPU rd, sym, off
+            lui \rd, %hi(\sym + \off*XLENB)
+            add \rd, \rd, gp
+            lw \rd, %lo(\sym + \off*XLENB)(\rd)
+        .endm
+        // Jumps to `label` if the `StaticKey` `key` is enabled: a `nop`, or
+        // a `j label` once the key is enabled, within 1 MiB.
+        .macro STATIC_JUMP key, label
+            .option push
+            .option norvc
+        .Lstatic_jump_\@:
+            nop
+            .option pop
+            .pushsection .static_key_sites, "a"
+            .balign XLENB
+            .word .Lstatic_jump_\@, \label, \key
+            .popsection
+        .endm
 
-        .endif"
+        .endif"#
         );
 
         #[cfg(target_arch = "riscv64")]
         core::arch::global_asm!(
-            r"
+            r#"
         .ifndef XLENB
         .equ XLENB, 8
 
@@ -29,8 +49,36 @@ macro_rules! include_asm_marcos {
         .macro STR rs2, rs1, off
             sd \rs2, \off*XLENB(\rs1)
         .endm
+        // Loads the word `off` of the per-CPU variable `sym` of this CPU,
+        // whose per-CPU area is at `gp`.
+        .macro LDR_PERCPU rd, sym, off
+            lui \rd, %hi(\sym + \off*XLENB)
+            add \rd, \rd, gp
+            ld \rd, %lo(\sym + \off*XLENB)(\rd)
+        .endm
+        // Jumps to `label` if the `StaticKey` `key` is enabled: a `nop`, or
+        // a `j label` once the key is enabled, within 1 MiB.
+        .macro STATIC_JUMP key, label
+            .option push
+            .option norvc
+        .Lstatic_jump_\@:
+            nop
+            .option pop
+            .pushsection .static_key_sites, "a"
+            .balign XLENB
+            .dword .Lstatic_jump_\@, \label, \key
+            .popsection
+        .endm
 
+        .endif"#,
+        );
+
+        core::arch::global_asm!(
+            r"
+        .ifndef TRAPFRAME_SIZE
+        .equ TRAPFRAME_SIZE, {trapframe_size}
         .endif",
+            trapframe_size = const $crate::trap::TRAPFRAME_SIZE,
         );
 
         core::arch::global_asm!(
@@ -76,11 +124,75 @@ macro_rules! include_asm_marcos {
             PUSH_POP_GENERAL_REGS LDR
         .endm
 
+        // The caller-saved registers (except sp, gp and tp), i.e. the ones a
+        // Rust function called from the trap entry may clobber.
+        .macro PUSH_POP_CALLER_REGS, op
+            \op ra, sp, 0
+            \op t0, sp, 4
+            \op t1, sp, 5
+            \op t2, sp, 6
+            \op a0, sp, 9
+            \op a1, sp, 10
+            \op a2, sp, 11
+            \op a3, sp, 12
+            \op a4, sp, 13
+            \op a5, sp, 14
+            \op a6, sp, 15
+            \op a7, sp, 16
+            \op t3, sp, 27
+            \op t4, sp, 28
+            \op t5, sp, 29
+            \op t6, sp, 30
+        .endm
+
+        // The callee-saved registers, which a Rust function preserves.
+        .macro PUSH_POP_CALLEE_REGS, op
+            \op s0, sp, 7
+            \op s1, sp, 8
+            \op s2, sp, 17
+            \op s3, sp, 18
+            \op s4, sp, 19
+            \op s5, sp, 20
+            \op s6, sp, 21
+            \op s7, sp, 22
+            \op s8, sp, 23
+            \op s9, sp, 24
+            \op s10, sp, 25
+            \op s11, sp, 26
+        .endm
+
         .endif
 
         .ifndef .LSAVE_REGS
         .equ .LSAVE_REGS, 0
         .macro SAVE_REGS, from_user
+        .if \from_user == 0
+            // On entry sp is the frame, sscratch the trapped sp. If the frame
+            // overlaps the guard page below the kernel stack, push it on the
+            // overflow stack instead, see `stack_guard.rs`. `gp` is 0 until
+            // the per-CPU area of this CPU is set, skip the check until then.
+            beqz    gp, .Lstack_ok\@
+            csrrw   t0, sscratch, t0            // sscratch = t0
+            LDR_PERCPU t0, __PERCPU_KSTACK_GUARD, 0
+            bltu    sp, t0, .Lstack_ok_restore\@
+            LDR_PERCPU t0, __PERCPU_KSTACK_GUARD, 1
+            bgeu    sp, t0, .Lstack_ok_restore\@
+            LDR_PERCPU t0, __PERCPU_KSTACK_GUARD, 2
+            addi    sp, sp, TRAPFRAME_SIZE
+            csrrw   sp, sscratch, sp            // sscratch = trapped sp, sp = t0
+            xor     t0, t0, sp                  // swap t0 and sp
+            xor     sp, sp, t0
+            xor     t0, t0, sp
+            addi    sp, sp, -TRAPFRAME_SIZE
+            j       .Lstack_ok\@
+        .Lstack_ok_restore\@:
+            addi    sp, sp, TRAPFRAME_SIZE
+            csrrw   sp, sscratch, sp            // sscratch = trapped sp, sp = t0
+            mv      t0, sp
+            csrr    sp, sscratch
+            addi    sp, sp, -TRAPFRAME_SIZE
+        .Lstack_ok\@:
+        .endif
             PUSH_GENERAL_REGS
 
             csrr    t0, sepc
@@ -121,6 +233,51 @@ macro_rules! include_asm_marcos {
             POP_GENERAL_REGS
             LDR     sp, sp, 1                   // load sp from tf.regs.sp
         .endm
+        .endif
+
+        // Same as `SAVE_REGS 1`, but leaves s0-s11 of the trap frame unset.
+        // `PUSH_POP_CALLEE_REGS STR` afterwards completes the frame, as long
+        // as s0-s11 still hold the user values.
+        .ifndef .LSAVE_SYSCALL_REGS
+        .equ .LSAVE_SYSCALL_REGS, 0
+        .macro SAVE_SYSCALL_REGS
+            PUSH_POP_CALLER_REGS STR
+
+            csrr    t0, sepc
+            csrr    t1, sstatus
+            csrrw   t2, sscratch, zero          // save sscratch (sp) and zero it
+            STR     t0, sp, 31                  // tf.sepc
+            STR     t1, sp, 32                  // tf.sstatus
+            STR     t2, sp, 1                   // tf.regs.sp
+
+            LDR     t1, sp, 2                   // load supervisor gp
+            LDR     t0, sp, 3                   // load supervisor tp
+            STR     gp, sp, 2                   // save user gp and tp
+            STR     tp, sp, 3
+            mv      tp, t0
+            mv      gp, t1
+        .endm
+        .endif
+
+        // Same as `RESTORE_REGS 1`, but does not touch s0-s11.
+        .ifndef .LRESTORE_SYSCALL_REGS
+        .equ .LRESTORE_SYSCALL_REGS, 0
+        .macro RESTORE_SYSCALL_REGS
+            LDR     t1, sp, 2                   // load user gp and tp
+            LDR     t0, sp, 3
+            STR     gp, sp, 2                   // save supervisor gp
+            STR     tp, sp, 3                   // save supervisor tp
+            mv      tp, t0
+            mv      gp, t1
+
+            LDR     t0, sp, 31
+            LDR     t1, sp, 32
+            csrw    sepc, t0
+            csrw    sstatus, t1
+
+            PUSH_POP_CALLER_REGS LDR
+            LDR     sp, sp, 1                   // load sp from tf.regs.sp
+        .endm
         .endif"
         );
     };
@@ -0,0 +1,231 @@
+//! Emulation of the misaligned loads and stores.
+//!
+//! Some cores trap on misaligned accesses, and not all firmware emulates
+//! them. [`handle_misaligned_access`] decodes the faulting instruction
+//! (including the compressed ones, and the FP ones with `fp_simd` on RV64)
+//! and performs the access byte by byte.
+
+use super::TrapFrame;
+
+/// The register of a load or a store.
+#[derive(Debug, Clone, Copy)]
+enum Reg {
+    /// An integer register, `x0` to `x31`.
+    Gpr(usize),
+    /// A floating-point register, `f0` to `f31`.
+    #[allow(dead_code)]
+    Fpr(usize),
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Access {
+    store: bool,
+    size: usize,
+    signed: bool,
+    reg: Reg,
+}
+
+impl Access {
+    const fn load(size: usize, signed: bool, reg: Reg) -> Self {
+        Self {
+            store: false,
+            size,
+            signed,
+            reg,
+        }
+    }
+
+    const fn store(size: usize, reg: Reg) -> Self {
+        Self {
+            store: true,
+            size,
+            signed: false,
+            reg,
+        }
+    }
+}
+
+/// Decodes a load or a store of 2 bytes or more.
+fn decode(inst: u32) -> Option<Access> {
+    use Reg::*;
+    let rv64 = usize::BITS == 64;
+    if inst & 0b11 == 0b11 {
+        let rd = ((inst >> 7) & 0x1f) as usize;
+        let rs2 = ((inst >> 20) & 0x1f) as usize;
+        let access = match (inst & 0x7f, (inst >> 12) & 0b111) {
+            (0x03, 1) => Access::load(2, true, Gpr(rd)), // lh
+            (0x03, 2) => Access::load(4, true, Gpr(rd)), // lw
+            (0x03, 3) if rv64 => Access::load(8, false, Gpr(rd)), // ld
+            (0x03, 5) => Access::load(2, false, Gpr(rd)), // lhu
+            (0x03, 6) if rv64 => Access::load(4, false, Gpr(rd)), // lwu
+            (0x23, 1) => Access::store(2, Gpr(rs2)),     // sh
+            (0x23, 2) => Access::store(4, Gpr(rs2)),     // sw
+            (0x23, 3) if rv64 => Access::store(8, Gpr(rs2)), // sd
+            (0x07, 2) => Access::load(4, false, Fpr(rd)), // flw
+            (0x07, 3) => Access::load(8, false, Fpr(rd)), // fld
+            (0x27, 2) => Access::store(4, Fpr(rs2)),     // fsw
+            (0x27, 3) => Access::store(8, Fpr(rs2)),     // fsd
+            _ => return None,
+        };
+        return Some(access);
+    }
+    let inst = inst as u16;
+    let funct3 = inst >> 13;
+    let access = match inst & 0b11 {
+        // C.FLD, C.LW, C.LD, C.FSD, C.SW, C.SD: rd' / rs2' in bits 4:2.
+        0b00 => {
+            let reg = ((inst >> 2) & 0b111) as usize + 8;
+            match funct3 {
+                0b001 => Access::load(8, false, Fpr(reg)),
+                0b010 => Access::load(4, true, Gpr(reg)),
+                0b011 if rv64 => Access::load(8, false, Gpr(reg)),
+                0b011 => Access::load(4, false, Fpr(reg)), // c.flw
+                0b101 => Access::store(8, Fpr(reg)),
+                0b110 => Access::store(4, Gpr(reg)),
+                0b111 if rv64 => Access::store(8, Gpr(reg)),
+                0b111 => Access::store(4, Fpr(reg)), // c.fsw
+                _ => return None,
+            }
+        }
+        // The `sp`-relative ones: rd in bits 11:7, rs2 in bits 6:2.
+        0b10 => {
+            let rd = ((inst >> 7) & 0x1f) as usize;
+            let rs2 = ((inst >> 2) & 0x1f) as usize;
+            match funct3 {
+                0b001 => Access::load(8, false, Fpr(rd)),
+                0b010 if rd != 0 => Access::load(4, true, Gpr(rd)),
+                0b011 if rv64 && rd != 0 => Access::load(8, false, Gpr(rd)),
+                0b011 if !rv64 => Access::load(4, false, Fpr(rd)), // c.flwsp
+                0b101 => Access::store(8, Fpr(rs2)),
+                0b110 => Access::store(4, Gpr(rs2)),
+                0b111 if rv64 => Access::store(8, Gpr(rs2)),
+                0b111 => Access::store(4, Fpr(rs2)), // c.fswsp
+                _ => return None,
+            }
+        }
+        _ => return None,
+    };
+    Some(access)
+}
+
+/// The integer registers of `tf`, `x1` to `x31`, in the order of
+/// [`GeneralRegisters`](super::GeneralRegisters).
+pub(super) fn gprs(tf: &mut TrapFrame) -> &mut [usize; 31] {
+    unsafe { &mut *(&mut tf.regs as *mut _ as *mut [usize; 31]) }
+}
+
+pub(super) fn read_gpr(tf: &mut TrapFrame, reg: usize) -> usize {
+    match reg {
+        0 => 0,
+        _ => gprs(tf)[reg - 1],
+    }
+}
+
+pub(super) fn write_gpr(tf: &mut TrapFrame, reg: usize, value: usize) {
+    if reg != 0 {
+        gprs(tf)[reg - 1] = value;
+    }
+}
+
+#[cfg(all(feature = "fp_simd", target_arch = "riscv64"))]
+macro_rules! fpr_accessors {
+    ($($n:literal)*) => {
+        fn read_fpr(reg: usize) -> u64 {
+            let value: u64;
+            match reg {
+                $($n => unsafe {
+                    core::arch::asm!(concat!("fmv.x.d {}, f", stringify!($n)), out(reg) value)
+                },)*
+                _ => unreachable!(),
+            }
+            value
+        }
+
+        fn write_fpr(reg: usize, value: u64) {
+            match reg {
+                $($n => unsafe {
+                    core::arch::asm!(concat!("fmv.d.x f", stringify!($n), ", {}"), in(reg) value)
+                },)*
+                _ => unreachable!(),
+            }
+        }
+    };
+}
+
+#[cfg(all(feature = "fp_simd", target_arch = "riscv64"))]
+fpr_accessors!(0 1 2 3 4 5 6 7 8 9 10 11 12 13 14 15 16 17 18 19 20 21 22 23 24 25 26 27 28 29 30 31);
+
+/// Reads the `buf.len()` bytes at `addr`, returns `false` on a fault.
+fn read_bytes(addr: usize, buf: &mut [u8], from_user: bool) -> bool {
+    if from_user {
+        super::copy_from_user(buf, addr) == 0
+    } else {
+        super::copy_from_kernel_nofault(buf, addr) == 0
+    }
+}
+
+/// Writes `buf` at `addr`, returns `false` on a fault.
+fn write_bytes(addr: usize, buf: &[u8], from_user: bool) -> bool {
+    if from_user {
+        super::copy_to_user(addr, buf) == 0
+    } else {
+        super::copy_to_kernel_nofault(addr, buf) == 0
+    }
+}
+
+/// Emulates the misaligned load or store of the trap of `tf`, at `addr` (the
+/// `stval` of a misaligned access exception, `scause` 4 or 6), and skips
+/// the instruction.
+///
+/// Returns `false` if the instruction cannot be emulated (not a load or a
+/// store, FP without `fp_simd`) or if the access faults, e.g. for the trap
+/// handler to deliver `SIGBUS` or `SIGSEGV` to the task.
+pub fn handle_misaligned_access(tf: &mut TrapFrame, addr: usize) -> bool {
+    let from_user = tf.from_user();
+    let Some(inst) = super::fetch_instruction(tf.sepc, from_user) else {
+        return false;
+    };
+    let Some(access) = decode(inst) else {
+        return false;
+    };
+    let mut buf = [0u8; 8];
+    let bytes = &mut buf[..access.size];
+    if access.store {
+        let value = match access.reg {
+            Reg::Gpr(reg) => read_gpr(tf, reg) as u64,
+            #[cfg(all(feature = "fp_simd", target_arch = "riscv64"))]
+            Reg::Fpr(reg) => read_fpr(reg),
+            #[cfg(not(all(feature = "fp_simd", target_arch = "riscv64")))]
+            Reg::Fpr(_) => return false,
+        };
+        bytes.copy_from_slice(&value.to_le_bytes()[..access.size]);
+        if !write_bytes(addr, bytes, from_user) {
+            return false;
+        }
+    } else {
+        if !read_bytes(addr, bytes, from_user) {
+            return false;
+        }
+        let shift = 64 - 8 * access.size as u32;
+        let mut value = u64::from_le_bytes(buf);
+        if access.signed {
+            value = (((value << shift) as i64) >> shift) as u64;
+        }
+        match access.reg {
+            Reg::Gpr(reg) => write_gpr(tf, reg, value as usize),
+            #[cfg(all(feature = "fp_simd", target_arch = "riscv64"))]
+            Reg::Fpr(reg) => {
+                // A single-precision value is NaN-boxed in the 64-bit register.
+                if access.size == 4 {
+                    value |= 0xffff_ffff_0000_0000;
+                }
+                write_fpr(reg, value);
+                super::set_fpu_state(tf, super::FpuDirtyState::Dirty);
+            }
+            #[cfg(not(all(feature = "fp_simd", target_arch = "riscv64")))]
+            Reg::Fpr(_) => return false,
+        }
+    }
+    tf.sepc += if inst & 0b11 == 0b11 { 4 } else { 2 };
+    true
+}
@@ -1,23 +1,336 @@
 #[macro_use]
 mod macros;
 
+mod access_bits;
+#[macro_use]
+mod alternative;
+mod asid;
+mod aslr;
+mod atomic_emul;
+mod backtrace;
+mod boot_paging;
+mod bug;
+mod cache;
+mod cfi;
+mod compat;
 mod context;
+mod coredump;
+mod cpufeature;
+mod cpuidle;
+mod dma;
+#[cfg(feature = "efi")]
+mod efi_stub;
+mod entropy;
+mod errata;
+mod exception;
+#[cfg(feature = "syscall-fast-path")]
+mod fast_syscall;
+mod fixmap;
+#[cfg(feature = "ftrace")]
+mod ftrace;
+mod futex;
+#[cfg(feature = "gdbstub")]
+mod gdbstub;
+#[cfg(feature = "hypervisor")]
+mod gstage;
+#[cfg(feature = "smp")]
+mod hotplug;
+mod huge_page;
+mod hw_breakpoint;
+mod hwprobe;
+#[cfg(feature = "hypervisor")]
+mod hypervisor;
+mod illegal;
+mod image_header;
+mod iommu;
+mod ioremap;
+#[cfg(feature = "smp")]
+mod ipi;
+mod irq_regs;
+mod irq_stack;
+#[cfg(feature = "kasan")]
+mod kasan;
+#[cfg(platform_family = "riscv64-qemu-virt")]
+mod kdump;
+mod kexec;
+mod kprobes;
+#[cfg(feature = "irq")]
+mod lockup;
+mod misaligned;
+mod napot;
+mod page_fault;
+mod page_walk;
+mod paging_mode;
+#[cfg(feature = "irq")]
+mod pmu;
+mod pointer_masking;
+mod regset;
+#[cfg(feature = "smp")]
+mod rfence;
+pub mod sbi;
+mod sections;
+mod signal;
+mod stack_guard;
+mod stack_protector;
+#[macro_use]
+mod static_key;
+mod suspend;
+#[cfg(feature = "self-test")]
+mod self_test;
+mod single_step;
+mod svpbmt;
+mod syscall_trace;
+mod text_patch;
+mod tlb;
 mod trap;
+mod uaccess;
+mod user_stack;
+mod vdso;
+#[cfg(feature = "fp_simd")]
+mod vector;
+#[cfg(feature = "vectored-trap")]
+mod vectored;
 pub use trap::ret_from_fork;
 pub mod sysno;
+pub mod sysno_compat;
 
 use crate::mem::PAGE_SIZE_4K;
+use core::sync::atomic::{AtomicUsize, Ordering};
 use memory_addr::{PhysAddr, VirtAddr};
 use riscv::asm;
 use riscv::register::{satp, sstatus, stvec};
 use axerrno::{LinuxError, linux_err};
 
-pub use self::context::{start_thread, GeneralRegisters, TaskContext, TrapFrame};
+pub use self::access_bits::{
+    harvest_access_bits, test_and_clear_accessed, test_and_clear_dirty, AccessBits,
+};
+pub use self::asid::{
+    alloc_asid, asid_bits, flush_tlb_asid, flush_tlb_page_asid, free_asid,
+    write_page_table_root_asid, MAX_ASID_BITS,
+};
+pub use self::aslr::{
+    init_aslr, mmap_rnd_bits, randomize_va_space, set_mmap_rnd_bits, set_randomize_va_space,
+    RandomizeVaSpace, UserLayout, BRK_RND_SIZE, MMAP_RND_BITS_MAX, MMAP_RND_BITS_MIN,
+    MMAP_RND_COMPAT_BITS, STACK_RND_BITS, STACK_RND_COMPAT_BITS,
+};
+pub use self::atomic_emul::{sys_riscv_atomic, RISCV_ATOMIC_CMPXCHG, RISCV_ATOMIC_CMPXCHG64};
+pub use self::backtrace::{backtrace, print_backtrace, MAX_BACKTRACE_DEPTH};
+pub use self::boot_paging::enable_paging;
+pub use self::bug::{
+    handle_breakpoint, set_user_breakpoint_handler, BugEntry, UserBreakpointHandler, BUG_MAGIC,
+};
+pub use self::cache::{
+    cbom_block_size, cboz_block_size, dcache_clean_range, dcache_flush_range,
+    dcache_invalidate_range, dcache_zero_range, icache_flush_range, init_cache_ops,
+    register_cache_ops, CacheOps, SifiveCcache,
+};
+pub use self::cfi::{
+    cfi_violation, has_zicfilp, has_zicfiss, load_user_cfi, set_landing_pad, set_shadow_stack,
+    set_user_ssp, shadow_stack_pte, shadow_stack_size, user_ssp, CfiViolation, CfiViolationKind,
+    UserCfi, MAX_SHADOW_STACK_SIZE, SEGV_CPERR,
+};
+pub use self::compat::{
+    compat_ptr, compat_supported, compat_syscall_args, current_task_size, is_compat_task,
+    set_compat_task, COMPAT_ELF_ET_DYN_BASE, COMPAT_STACK_TOP, COMPAT_TASK_SIZE,
+    COMPAT_TASK_UNMAPPED_BASE,
+};
+pub use self::context::{register_context_switch_hook, ContextSwitchHook, MAX_CONTEXT_SWITCH_HOOKS};
+pub use self::context::{start_thread, FpState, GeneralRegisters, TaskContext, TrapFrame};
+#[cfg(feature = "fp_simd")]
+pub use self::context::handle_fpu_trap;
+pub use self::context::{fpu_state, set_fpu_state, set_vector_state, vector_state, FpuDirtyState};
+pub use self::coredump::{
+    elf_prstatus, note_size, process_notes_size, thread_notes_size, write_note,
+    write_process_notes, write_thread_notes, ElfPrpsinfo, ElfPrstatus, NT_AUXV, NT_PRPSINFO,
+};
+pub(crate) use self::cpufeature::probe_cpu_features;
+pub use self::cpufeature::{cpu_has, cpu_has_on, cpu_hwcap, elf_hwcap, Feature};
+pub use self::cpuidle::{
+    cpu_idle, enter_idle_state, idle_states, init_cpuidle, last_idle_residency, select_idle_state,
+    IdleState, MAX_IDLE_STATES,
+};
+pub use self::dma::{
+    init_dma, init_dma_pool, DmaBuffer, DmaDevice, DmaDirection, MAX_DMA_BUFFERS,
+};
+#[cfg(feature = "efi")]
+pub use self::efi_stub::{efi_boot_info, EfiBootInfo, EfiMemoryDescriptor};
+pub use self::entropy::{
+    has_zkr, random_bytes, random_seed, register_entropy_source, EntropySource,
+    MAX_ENTROPY_SOURCES,
+};
+#[cfg(feature = "syscall-fast-path")]
+pub use self::fast_syscall::{
+    bench_syscall_frame, init_syscall_fast_path, needs_full_frame, SyscallFastPath,
+};
+pub use self::exception::{handle_exception, trap_cause};
+pub use self::fixmap::{clear_fixmap, fixmap_if_unmapped, set_fixmap, FixmapSlot, FIXMAP_BASE};
+#[cfg(feature = "ftrace")]
+pub use self::ftrace::{
+    ftrace_read, ftrace_reset, ftrace_set_function, ftrace_start, ftrace_stop, FtraceEntry,
+    FTRACE_RING_LEN, MAX_FTRACE_FILTERS,
+};
+pub use self::futex::{futex_atomic_cmpxchg_inuser, futex_atomic_op_inuser, FutexOp};
+#[cfg(feature = "gdbstub")]
+pub use self::gdbstub::{gdb_breakpoint, init_gdbstub, GDB_MAGIC};
+#[cfg(feature = "hypervisor")]
+pub use self::gstage::{
+    gstage_map_page, gstage_map_region, gstage_translate, gstage_unmap_page, hfence_gvma,
+    hfence_gvma_all, hfence_vvma, hfence_vvma_all, set_hgatp, GStageMode, GuestAccess,
+    GuestMmioAccess, GuestPageFaultInfo, GSTAGE_ROOT_SIZE,
+};
+#[cfg(feature = "smp")]
+pub(crate) use self::hotplug::cpu_starting;
+#[cfg(feature = "smp")]
+pub use self::hotplug::{register_cpu_hotplug, HotplugCallbacks, MAX_HOTPLUG_CALLBACKS};
+pub use self::huge_page::{
+    flush_tlb_page, flush_tlb_range_sized, map_page, map_region, merge_page, protect_region,
+    split_page, unmap_page, unmap_region, PageSize,
+};
+pub use self::hw_breakpoint::{
+    handle_hw_breakpoint, has_hw_breakpoints, install_hw_breakpoint, set_hw_breakpoint_handler,
+    uninstall_hw_breakpoint, HwBreakpoint, HwBreakpointHandler, HwBreakpointKind,
+    MAX_HW_BREAKPOINTS,
+};
+pub use self::hwprobe::{hwprobe, key as hwprobe_key, HwprobePair};
+#[cfg(feature = "hypervisor")]
+pub use self::hypervisor::{
+    has_hypervisor, init_hypervisor, run_guest, GuestContext, GuestExit, GuestTrap, VsCsrs,
+    HSTATUS_GVA, HSTATUS_SPV, HSTATUS_SPVP, HSTATUS_VSXL_64,
+};
+pub use self::illegal::{
+    handle_illegal_instruction, register_insn_emulator, IllegalInstruction, InsnEmulator, ILL_ILLOPC,
+};
+pub use self::image_header::{
+    RiscvImageHeader, RISCV_HEADER_VERSION, RISCV_IMAGE_MAGIC, RISCV_IMAGE_MAGIC2,
+};
+pub use self::iommu::{
+    init_iommu, iommu_attach_device, iommu_detach_device, iommu_handle_faults, iommu_map,
+    iommu_unmap, IommuAllocFn, IommuDomain, IommuStage,
+};
+pub use self::ioremap::{init_ioremap, ioremap, iounmap, MmioRegion, IOREMAP_BASE, IOREMAP_SIZE};
+#[cfg(feature = "smp")]
+pub use self::ipi::{
+    flush_tlb_all_cpus, handle_ipi, ipi_cpu_dead, send_ipi, smp_call_function, smp_call_function_nowait,
+    smp_dump_and_stop_other_cpus, smp_stop_other_cpus, IpiKind, DUMP_TIMEOUT,
+};
+pub use self::irq_regs::{irq_regs, set_irq_regs};
+pub use self::irq_stack::{call_on_irq_stack, install_irq_stack_guards, IRQ_STACK_SIZE};
+#[cfg(feature = "kasan")]
+pub use self::kasan::{
+    kasan_early_init, kasan_finish_init, kasan_mem_to_shadow, kasan_poison, kasan_poison_pages,
+    kasan_populate_shadow, kasan_unpoison, kasan_unpoison_stack, KASAN_GRANULE_SIZE,
+    KASAN_PAGE_FREE, KASAN_SHADOW_OFFSET, KASAN_SHADOW_SIZE, KASAN_SHADOW_START,
+};
+#[cfg(platform_family = "riscv64-qemu-virt")]
+pub use self::kdump::{crash_elfcorehdr, crash_kexec, kexec_load_crash, kexec_unload_crash};
+pub use self::kexec::{kexec_execute, kexec_load, kexec_unload, KexecSegment, MAX_KEXEC_SEGMENTS};
+pub use self::kprobes::{
+    __kprobe_insn_slots, handle_kprobe, register_kprobe, unregister_kprobe, KprobeHandler,
+    MAX_KPROBES,
+};
+#[cfg(feature = "irq")]
+pub(crate) use self::lockup::lockup_timer_tick;
+#[cfg(feature = "irq")]
+pub use self::lockup::{
+    lockup_detector_start, lockup_detector_stop, set_lockup_handler, touch_softlockup_watchdog,
+    LockupHandler, LockupKind,
+};
+pub use self::misaligned::handle_misaligned_access;
+pub use self::napot::{
+    has_svnapot, napot_coalesce_64k, napot_eligible, napot_map_64k, napot_split_64k,
+    napot_unmap_64k, NAPOT_64K_SIZE,
+};
+pub use self::page_fault::{handle_page_fault_trap, page_fault_info};
+pub use self::page_walk::{
+    dump_page_table, dump_page_table_walk, page_table_mappings, walk, PageMapping, PageTableMappings,
+    PageWalkEnd, PageWalkResult, PteFlags, PteSnapshot,
+};
+pub use self::paging_mode::{
+    elf_et_dyn_base, max_paging_mode, paging_mode, set_paging_mode, stack_top, task_size,
+    task_unmapped_base, PagingMode,
+};
+#[cfg(feature = "irq")]
+pub use self::pmu::{
+    handle_pmu_overflow, has_sscofpmf, pmu_counters, pmu_read_samples, pmu_start_counting,
+    pmu_start_profiling, pmu_start_sampling, pmu_stop_counting, pmu_stop_sampling,
+    pmu_take_lost_samples, set_pmu_overflow_handler, task_pmu_add_event, task_pmu_clear,
+    task_pmu_read, PmuCounter, PmuOverflowHandler, PmuSample, TaskPmu, MAX_TASK_PMU_EVENTS,
+    PMU_EVENT_BRANCH_INSTRUCTIONS, PMU_EVENT_BRANCH_MISSES, PMU_EVENT_CACHE_MISSES,
+    PMU_EVENT_CACHE_REFERENCES, PMU_EVENT_CPU_CYCLES, PMU_EVENT_INSTRUCTIONS,
+    PMU_SAMPLE_BUFFER_SIZE,
+};
+pub use self::pointer_masking::{has_smnpm, has_ssnpm, set_pointer_masking, untagged_addr};
+pub use self::regset::{
+    get_fpregs, get_gregs, get_vregs, set_fpregs, set_gregs, set_vregs, vregs_size, ElfFpregs,
+    ElfGregs, ElfVregsHeader, ELF_NGREG, NT_PRFPREG, NT_PRSTATUS, NT_RISCV_VECTOR,
+};
+#[cfg(feature = "smp")]
+pub use self::rfence::{has_rfence, remote_fence_i, remote_sfence_vma, remote_sfence_vma_asid};
+#[cfg(feature = "self-test")]
+pub use self::self_test::arch_self_test;
+pub use self::sections::{kernel_sections, protect_kernel_sections, KernelSection};
+pub use self::signal::{
+    restore_signal_frame, setup_signal_frame, SignalDelivery, SignalReturn, SignalStack,
+    SIGINFO_SIZE,
+};
+pub(crate) use self::stack_guard::init_stack_guard;
+pub(crate) use self::stack_protector::init_stack_canary;
+pub use self::stack_protector::{__stack_chk_fail, STACK_CANARY_GP_OFFSET};
+pub use self::static_key::StaticKey;
+pub use self::single_step::{handle_single_step, set_single_step};
+pub use self::stack_guard::{
+    check_kernel_stack_overflow, install_stack_guard, remove_stack_guard, STACK_GUARD_SIZE,
+};
+pub use self::suspend::{register_suspend_ops, system_suspend, SuspendOps, MAX_SUSPEND_OPS};
+pub use self::svpbmt::{has_svpbmt, MemAttr};
+pub use self::syscall_trace::{
+    init_syscall_trace, register_syscall_trace_hooks, syscall_traced, trace_syscall,
+    SyscallEnterHook, SyscallExitHook, SyscallTrace, MAX_SYSCALL_TRACE_HOOKS,
+};
+pub use self::text_patch::patch_text;
+pub use self::tlb::{TlbBatch, TLB_BATCH_CAPACITY};
+pub use self::uaccess::{clear_user, copy_from_user, copy_to_user, strncpy_from_user, strnlen_user};
+pub use self::uaccess::{copy_from_kernel_nofault, copy_to_kernel_nofault};
+pub use self::uaccess::{__get_user_u16, __get_user_u32, __get_user_u64};
+pub use self::uaccess::{__put_user_u16, __put_user_u32, __put_user_u64};
+pub use self::user_stack::{auxv, build_user_stack, setup_user_stack, ExecInfo, ARG_MAX};
+#[cfg(feature = "irq")]
+pub(crate) use self::vdso::vdso_timer_tick;
+pub use self::vdso::{
+    init_vdso, update_vdso_data, vdso_image, VdsoImage, VDSO_DATA_SIZE, VDSO_TEXT_SIZE,
+};
+#[cfg(feature = "fp_simd")]
+pub use self::vector::{
+    handle_vector_trap, has_vector, set_vector_buffer, vector_state_size, VectorState,
+};
+#[cfg(feature = "vectored-trap")]
+pub use self::vectored::{
+    __trap_vector_table, init_vectored_traps, set_trap_vector_vectored, VectoredTrapHandlers,
+};
 
+/// The size of the user address space in Sv39, the default paging mode.
+///
+/// Use [`task_size`] for the one of the selected mode, as well as
+/// [`stack_top`], [`elf_et_dyn_base`] and [`task_unmapped_base`] for the
+/// constants derived from it, and [`UserLayout`] for their randomized values
+/// of a program.
 pub const TASK_SIZE: usize = 0x40_0000_0000;
+/// The size of a kernel stack, without its guard page (see
+/// [`install_stack_guard`]).
 pub const STACK_SIZE: usize = 32 * PAGE_SIZE_4K;
 pub const STACK_TOP: usize = TASK_SIZE;
 
+/// Size of the area below the user stack pointer that the kernel must not
+/// touch when it pushes data onto a user stack.
+///
+/// The RISC-V psABI does not define a redzone, so it is 0 by default. Raise it
+/// with [`set_user_redzone`](crate::trap::set_user_redzone) if the user space
+/// is built by a toolchain that assumes one. Note that the
+/// register save area of a variadic function lies *above* `sp` (in the
+/// caller's frame), and FP callee-saved registers are spilled inside the
+/// normal frame, so neither of them needs to be covered here.
+pub const USER_REDZONE: usize = 0;
+
 /*
  * This is the location that an ET_DYN program is loaded if exec'ed.
  * Typical use of this is to invoke "./ld.so someprog" to test out
@@ -36,9 +349,15 @@ pub const TASK_UNMAPPED_BASE: usize = (TASK_SIZE / 3) & !(PAGE_SIZE_4K - 1);
 /// Status register flags
 pub const SR_SPIE:  usize = 0x00000020; /* Previous Supervisor IE */
 pub const SR_SPP:   usize = 0x00000100; /* Previously Supervisor */
+pub const SR_VS:   usize = 0x00000600; /* Vector Status */
+pub const SR_FS:   usize = 0x00006000; /* Floating-point Status */
 pub const SR_FS_INITIAL: usize = 0x00002000;
+pub const SR_SD:   usize = 1 << 63; /* FS/VS/XS dirty */
+pub const SR_UXL: usize = 0x300000000; /* XLEN mask for U-mode */
+pub const SR_UXL_32: usize = 0x100000000; /* XLEN = 32 for U-mode */
 pub const SR_UXL_64: usize = 0x200000000; /* XLEN = 64 for U-mode */
 pub const SR_SUM: usize = 0x00040000; /* Supervisor User Memory access */
+pub const SR_SPELP: usize = 0x00800000; /* Previous expected landing pad */
 
 #[inline]
 pub fn user_mode() -> bool {
@@ -76,6 +395,73 @@ pub fn wait_for_irqs() {
     unsafe { riscv::asm::wfi() }
 }
 
+/// Number of [`cpu_relax`] calls on a CPU between two vCPU yields.
+///
+/// A larger value keeps short critical sections fast (a yield costs a trip
+/// to the hypervisor and at least one timer interrupt), a smaller one gives
+/// the timeslice back sooner when the lock holder has been descheduled.
+#[cfg(feature = "virt-yield")]
+pub const VIRT_YIELD_SPIN_THRESHOLD: usize = 1 << 10;
+
+/// The longest time a yielded vCPU may sleep before it spins again.
+#[cfg(feature = "virt-yield")]
+pub const VIRT_YIELD_MAX_NANOS: u64 = 100_000;
+
+#[cfg(feature = "virt-yield")]
+#[percpu2::def_percpu]
+static RELAX_COUNT: usize = 0;
+
+/// Returns whether we are running in a virtual machine.
+pub fn is_virtualized() -> bool {
+    use core::sync::atomic::AtomicU8;
+    // 0: unknown, 1: bare metal, 2: virtualized
+    static VIRTUALIZED: AtomicU8 = AtomicU8::new(0);
+    match VIRTUALIZED.load(Ordering::Relaxed) {
+        0 => {
+            let virtualized = sbi::impl_is_hypervisor();
+            VIRTUALIZED.store(if virtualized { 2 } else { 1 }, Ordering::Relaxed);
+            virtualized
+        }
+        state => state == 2,
+    }
+}
+
+/// Hints the CPU that it is in a spin-wait loop.
+///
+/// It executes `pause` on cores with Zihintpause, patched in at boot, and a
+/// `nop` on the others. With the
+/// `virt-yield` feature and in a virtual machine, every
+/// [`VIRT_YIELD_SPIN_THRESHOLD`]-th call on a CPU also gives the vCPU back to
+/// the hypervisor (by a retentive `sbi_hart_suspend`) for at most
+/// [`VIRT_YIELD_MAX_NANOS`], so that a descheduled lock holder can run.
+///
+/// It should be called with preemption disabled, as a spinning lock does.
+#[inline]
+pub fn cpu_relax() {
+    unsafe {
+        asm_alternative!(
+            "nop",
+            ".4byte 0x0100000f", // pause
+            alternative::alt_key(Feature::Zihintpause)
+        )
+    };
+    #[cfg(feature = "virt-yield")]
+    unsafe {
+        let count = RELAX_COUNT.read_current_raw() + 1;
+        if count < VIRT_YIELD_SPIN_THRESHOLD {
+            RELAX_COUNT.write_current_raw(count);
+        } else {
+            RELAX_COUNT.write_current_raw(0);
+            if is_virtualized() {
+                let wakeup = crate::time::current_time_nanos() + VIRT_YIELD_MAX_NANOS;
+                crate::platform::time::wait_with_wakeup(wakeup, || {
+                    sbi::hart_suspend_retentive().ok();
+                });
+            }
+        }
+    }
+}
+
 /// Halt the current CPU.
 #[inline]
 pub fn halt() {
@@ -96,11 +482,29 @@ pub fn read_page_table_root() -> PhysAddr {
 /// # Safety
 ///
 /// This function is unsafe as it changes the virtual memory address space.
+/// The page table must be in the format of [`paging_mode`].
 pub unsafe fn write_page_table_root(root_paddr: PhysAddr) {
-    let old_root = read_page_table_root();
-    trace!("set page table root: {:#x} => {:#x}", old_root, root_paddr);
-    if old_root != root_paddr {
-        satp::set(satp::Mode::Sv39, 0, root_paddr.as_usize() >> 12);
+    write_satp(paging_mode().satp_mode(), 0, root_paddr)
+}
+
+/// Composes the raw `satp` value from the translation mode, the ASID and
+/// the physical address of the page table root.
+#[inline]
+const fn make_satp(mode: satp::Mode, asid: usize, root_paddr: PhysAddr) -> usize {
+    ((mode as usize) << 60) | ((asid & 0xffff) << 44) | (root_paddr.as_usize() >> 12)
+}
+
+/// Writes `satp` and fences the TLB if anything in it has changed.
+///
+/// The whole register is compared instead of only the PPN: switching the
+/// mode or the ASID with the same root also changes the translation, and
+/// some implementations do not pick up the new `satp` until `sfence.vma`.
+unsafe fn write_satp(mode: satp::Mode, asid: usize, root_paddr: PhysAddr) {
+    let old_satp = satp::read().bits();
+    let new_satp = make_satp(mode, asid, root_paddr);
+    trace!("set satp: {:#x} => {:#x}", old_satp, new_satp);
+    if old_satp != new_satp {
+        satp::write(new_satp);
         asm::sfence_vma_all();
     }
 }
@@ -116,24 +520,162 @@ pub unsafe fn write_page_table_root0(root_paddr: PhysAddr) {
 pub fn flush_tlb(vaddr: Option<VirtAddr>) {
     unsafe {
         if let Some(vaddr) = vaddr {
-            asm::sfence_vma(0, vaddr.as_usize())
+            // `zero` as the ASID operand: the entries of all ASIDs.
+            asm_alternative!(
+                "sfence.vma {}, zero",
+                "sfence.vma",
+                errata::ALT_ERRATA_SIFIVE_CIP_1200,
+                in(reg) vaddr.as_usize()
+            )
         } else {
             asm::sfence_vma_all();
         }
     }
 }
 
+/// The number of pages above which a range flush becomes a full flush, one
+/// `sfence.vma` per page would take longer than refilling the TLB.
+pub const TLB_FLUSH_RANGE_THRESHOLD: usize = 32;
+
+/// Returns whether flushing `pages` pages one by one would exceed
+/// [`TLB_FLUSH_RANGE_THRESHOLD`], so that a full flush should be used.
+#[inline]
+pub(crate) const fn tlb_flush_all_better(pages: usize) -> bool {
+    pages > TLB_FLUSH_RANGE_THRESHOLD
+}
+
+/// Flushes the TLB entries of `[start, start + size)` on the current CPU.
+///
+/// It issues one `sfence.vma` per 4K page, or a single full flush if the range
+/// covers more than [`TLB_FLUSH_RANGE_THRESHOLD`] pages.
+pub fn flush_tlb_range(start: VirtAddr, size: usize) {
+    let first = start.align_down_4k().as_usize();
+    let pages = (start.as_usize() + size - first + PAGE_SIZE_4K - 1) / PAGE_SIZE_4K;
+    if tlb_flush_all_better(pages) {
+        flush_tlb(None);
+    } else {
+        for i in 0..pages {
+            flush_tlb(Some(VirtAddr::from(first + i * PAGE_SIZE_4K)));
+        }
+    }
+}
+
 #[inline]
 pub fn local_flush_icache_all() {
     unsafe { core::arch::asm!("fence.i") };
 }
 
-/// Writes Supervisor Trap Vector Base Address Register (`stvec`).
+/// Writes Supervisor Trap Vector Base Address Register (`stvec`), in direct
+/// mode.
 #[inline]
 pub fn set_trap_vector_base(stvec: usize) {
     unsafe { stvec::write(stvec, stvec::TrapMode::Direct) }
 }
 
+bitflags::bitflags! {
+    /// Interrupt sources, as the bits in `sie`.
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub struct IrqSources: usize {
+        /// Supervisor software interrupts (IPIs).
+        const SOFT              = 1 << 1;
+        /// Supervisor timer interrupts.
+        const TIMER             = 1 << 5;
+        /// Supervisor external interrupts.
+        const EXTERNAL          = 1 << 9;
+        /// Local counter-overflow interrupts (Sscofpmf).
+        const COUNTER_OVERFLOW  = 1 << 13;
+    }
+}
+
+/// The sources found by [`probe_irq_sources`], `usize::MAX` before the probe.
+static AVAILABLE_IRQ_SOURCES: AtomicUsize = AtomicUsize::new(usize::MAX);
+
+/// Finds the interrupt sources that are implemented, by writing all ones to
+/// `sie` and reading back the bits that stuck.
+///
+/// It is called once in [`early_init`], the previous `sie` is restored.
+pub fn probe_irq_sources() {
+    let _guard = kernel_guard_base::IrqSave::new();
+    let implemented: usize;
+    unsafe {
+        core::arch::asm!(
+            "csrrw {saved}, sie, {ones}",
+            "csrr {implemented}, sie",
+            "csrw sie, {saved}",
+            saved = out(reg) _,
+            implemented = out(reg) implemented,
+            ones = in(reg) usize::MAX,
+        );
+    }
+    let sources = IrqSources::from_bits_truncate(implemented);
+    AVAILABLE_IRQ_SOURCES.store(sources.bits(), Ordering::Relaxed);
+    info!("Available interrupt sources: {:?}", sources);
+}
+
+/// Returns the interrupt sources that are implemented by this core.
+///
+/// All sources are assumed to be available before [`probe_irq_sources`].
+pub fn available_irq_sources() -> IrqSources {
+    match AVAILABLE_IRQ_SOURCES.load(Ordering::Relaxed) {
+        usize::MAX => IrqSources::all(),
+        bits => IrqSources::from_bits_truncate(bits),
+    }
+}
+
+/// Returns the available ones of `sources`, with a warning for each of the
+/// others.
+fn checked_irq_sources(sources: IrqSources) -> IrqSources {
+    let missing = sources - available_irq_sources();
+    if !missing.is_empty() {
+        warn!("Interrupt sources not implemented: {:?}", missing);
+    }
+    sources - missing
+}
+
+/// Enables the interrupt `sources` in `sie` on the current CPU.
+///
+/// The sources that are not implemented are skipped with a warning, and
+/// the enabled ones are returned.
+pub fn enable_irq_sources(sources: IrqSources) -> IrqSources {
+    let sources = checked_irq_sources(sources);
+    unsafe { core::arch::asm!("csrs sie, {}", in(reg) sources.bits()) };
+    sources
+}
+
+/// Disables the interrupt `sources` in `sie` on the current CPU, and returns
+/// the ones of them that were enabled, e.g. to restore them with
+/// [`enable_irq_sources`].
+pub fn disable_irq_sources(sources: IrqSources) -> IrqSources {
+    let old: usize;
+    unsafe { core::arch::asm!("csrrc {}, sie, {}", out(reg) old, in(reg) sources.bits()) };
+    IrqSources::from_bits_truncate(old) & sources
+}
+
+/// Installs the trap vector and enables the interrupt `sources` on the
+/// current CPU, as one step.
+///
+/// The order matters:
+///
+/// 1. `stvec` first, so that any trap taken from now on, including the
+///    interrupts enabled below, lands in `vector_base`.
+/// 2. Then the sources are unmasked in `sie`. Nothing is delivered yet, as
+///    `sstatus.SIE` is still clear, but already pending ones become visible.
+/// 3. At last `sstatus.SIE` is set.
+///
+/// A fence separates the steps, so that the memory the trap handler depends
+/// on (written before this call) is visible before the first interrupt.
+/// Sources not in `sources` are left as they are, and the ones that are not
+/// implemented (see [`available_irq_sources`]) are skipped with a warning.
+pub fn activate_interrupts(vector_base: usize, sources: IrqSources) {
+    use core::sync::atomic::fence;
+    disable_irqs();
+    set_trap_vector_base(vector_base);
+    fence(Ordering::SeqCst);
+    enable_irq_sources(sources);
+    fence(Ordering::SeqCst);
+    enable_irqs();
+}
+
 /// Reads the thread pointer of the current CPU.
 ///
 /// It is used to implement TLS (Thread Local Storage).
@@ -238,21 +780,41 @@ pub fn __put_user_asm(x: u8, ptr: usize) -> usize {
 //
 #[inline]
 pub fn access_ok(addr: usize, size: usize) -> bool {
-    size <= TASK_SIZE && addr <= TASK_SIZE - size
+    // A tagged pointer is checked as the address it accesses, and a compat
+    // task only has the low 2G.
+    let addr = untagged_addr(addr);
+    let task_size = current_task_size();
+    size <= task_size && addr <= task_size - size
 }
 
+/// Faults in the user page at `addr` for reading.
+///
+/// Returns 0 on success, or the negated `EFAULT` as `usize`. New code should
+/// use [`fault_in_readable_checked`] instead.
 #[inline]
 pub fn fault_in_readable(addr: usize, size: usize) -> usize {
+    match fault_in_readable_checked(addr, size) {
+        Ok(()) => 0,
+        Err(_) => linux_err!(EFAULT),
+    }
+}
+
+/// Faults in the user page at `addr` for reading.
+///
+/// Returns [`LinuxError::EFAULT`] if the range is not in user space or the
+/// page cannot be read.
+#[inline]
+pub fn fault_in_readable_checked(addr: usize, size: usize) -> Result<(), LinuxError> {
     if !access_ok(addr, size) {
-        return linux_err!(EFAULT);
+        return Err(LinuxError::EFAULT);
     }
 
     let (_, err) = __get_user_asm(addr);
     if err != 0 {
         error!("__get_user_asm: err = {:#x}", err);
-        return err;
+        return Err(LinuxError::EFAULT);
     }
-    0
+    Ok(())
 }
 
 #[inline]
@@ -269,9 +831,47 @@ pub fn fault_in_writeable(addr: usize, size: usize) -> usize {
     0
 }
 
-pub const EXC_INST_PAGE_FAULT: usize = 12;
-pub const EXC_LOAD_PAGE_FAULT: usize = 13;
-pub const EXC_STORE_PAGE_FAULT: usize = 15;
+/// Fetches the instruction at `pc`.
+///
+/// Returns the 16-bit parcel for a compressed instruction, or the 32-bit
+/// word otherwise. A user `pc` is read with fault fixup, and [`None`] is
+/// returned if it cannot be read.
+pub fn fetch_instruction(pc: usize, from_user: bool) -> Option<u32> {
+    let read_u16 = |addr: usize| -> Option<u16> {
+        if from_user {
+            if !access_ok(addr, 2) {
+                return None;
+            }
+            let (lo, err_lo) = __get_user_asm(addr);
+            let (hi, err_hi) = __get_user_asm(addr + 1);
+            (err_lo == 0 && err_hi == 0).then(|| u16::from_le_bytes([lo, hi]))
+        } else {
+            // Instructions are only 16-bit aligned.
+            Some(unsafe { (addr as *const u16).read_volatile() })
+        }
+    };
+    let low = read_u16(pc)?;
+    if low & 0b11 != 0b11 {
+        return Some(low as u32);
+    }
+    let high = read_u16(pc + 2)?;
+    Some(((high as u32) << 16) | low as u32)
+}
 
 pub fn early_init() {
+    // Keep the firmware and other reserved memory away from the allocator
+    // before anyone asks for the memory regions.
+    #[cfg(platform_family = "riscv64-qemu-virt")]
+    crate::platform::mem::init_reserved_regions();
+    #[cfg(platform_family = "riscv64-qemu-virt")]
+    crate::platform::time::init_early();
+    probe_cpu_features();
+    alternative::apply_alternatives();
+    probe_irq_sources();
+    asid::probe_asid_bits();
+    #[cfg(feature = "fp_simd")]
+    vector::probe_vector();
+
+    #[cfg(feature = "self-test")]
+    arch_self_test();
 }
@@ -0,0 +1,208 @@
+//! Svnapot: coalescing 16 contiguous 4K mappings into a 64K NAPOT mapping.
+//!
+//! All 16 PTEs of a naturally aligned 64K run carry the `N` bit and the same
+//! PPN with the low 4 bits set to `0b1000`, so that the TLB may cache them as
+//! one entry. A `sfence.vma` of any address in the run flushes the whole
+//! mapping.
+
+use axerrno::LinuxError;
+use memory_addr::{PhysAddr, VirtAddr};
+
+use super::huge_page::{entry_at, leaf_flags};
+use super::page_walk::{current_levels, PTE_PPN_MASK, PTE_PPN_SHIFT};
+use super::page_walk::{PTE_A, PTE_D, PTE_N, PTE_R, PTE_V, PTE_W, PTE_X};
+use super::svpbmt::MemAttr;
+use crate::mem::{phys_to_virt, MemRegionFlags};
+
+/// The size of a NAPOT mapping.
+pub const NAPOT_64K_SIZE: usize = 0x1_0000;
+
+/// The number of 4K PTEs in a NAPOT mapping.
+const NAPOT_64K_PAGES: usize = NAPOT_64K_SIZE >> 12;
+
+/// The low bits of the PPN in a 64K NAPOT PTE.
+const NAPOT_64K_PPN_BITS: usize = 0b1000;
+
+const PTE_PPN_FIELD: usize = PTE_PPN_MASK << PTE_PPN_SHIFT;
+
+/// Returns whether all CPUs support Svnapot.
+///
+/// It needs the device tree, i.e. must be called after `arch_init_early`.
+pub fn has_svnapot() -> bool {
+    super::cpu_has(super::Feature::Svnapot)
+}
+
+/// Returns whether mapping `size` bytes at `vaddr` to `paddr` may use NAPOT
+/// mappings, i.e. both addresses are 64K aligned and the range covers at
+/// least one 64K run.
+///
+/// The mapping path can map each 64K run in such a range with
+/// [`napot_map_64k`], or map it with 4K pages as usual, then call
+/// [`napot_coalesce_64k`] for each 64K run in it.
+pub fn napot_eligible(vaddr: VirtAddr, paddr: PhysAddr, size: usize) -> bool {
+    has_svnapot()
+        && vaddr.as_usize() % NAPOT_64K_SIZE == 0
+        && paddr.as_usize() % NAPOT_64K_SIZE == 0
+        && size >= NAPOT_64K_SIZE
+}
+
+/// Returns the 16 last-level PTEs that map the 64K run at `vaddr`.
+unsafe fn napot_ptes(root: PhysAddr, vaddr: VirtAddr) -> Result<&'static mut [usize], LinuxError> {
+    if vaddr.as_usize() % NAPOT_64K_SIZE != 0 {
+        return Err(LinuxError::EINVAL);
+    }
+    let levels = current_levels().ok_or(LinuxError::EINVAL)?;
+    let mut table = root;
+    for level in (1..levels).rev() {
+        let index = (vaddr.as_usize() >> (12 + 9 * level)) & 0x1ff;
+        let pte = *phys_to_virt(table).as_ptr().cast::<usize>().add(index);
+        if pte & PTE_V == 0 {
+            return Err(LinuxError::EFAULT);
+        }
+        if pte & (PTE_R | PTE_W | PTE_X) != 0 {
+            // Already mapped by a superpage.
+            return Err(LinuxError::EINVAL);
+        }
+        table = PhysAddr::from(((pte >> PTE_PPN_SHIFT) & PTE_PPN_MASK) << 12);
+    }
+    let index = (vaddr.as_usize() >> 12) & 0x1ff;
+    let ptes = phys_to_virt(table).as_mut_ptr().cast::<usize>().add(index);
+    Ok(core::slice::from_raw_parts_mut(ptes, NAPOT_64K_PAGES))
+}
+
+/// Coalesces the 16 4K mappings of the 64K run at `vaddr` into a NAPOT
+/// mapping.
+///
+/// They must all be valid leaves with the same flags, mapping 16 contiguous
+/// pages that start at a 64K aligned physical address, otherwise
+/// [`LinuxError::EINVAL`] is returned and nothing is changed. Returns
+/// [`LinuxError::ENODEV`] without Svnapot.
+///
+/// # Safety
+///
+/// `root` must be a valid page table, and nobody else may modify the entries
+/// concurrently.
+pub unsafe fn napot_coalesce_64k(root: PhysAddr, vaddr: VirtAddr) -> Result<(), LinuxError> {
+    if !has_svnapot() {
+        return Err(LinuxError::ENODEV);
+    }
+    let ptes = napot_ptes(root, vaddr)?;
+    let first = ptes[0];
+    if first & PTE_V == 0 {
+        return Err(LinuxError::EFAULT);
+    }
+    if first & PTE_N != 0 {
+        return Ok(());
+    }
+    let flags = first & !PTE_PPN_FIELD;
+    let ppn = (first >> PTE_PPN_SHIFT) & PTE_PPN_MASK;
+    if flags & (PTE_R | PTE_W | PTE_X) == 0 || ppn % NAPOT_64K_PAGES != 0 {
+        return Err(LinuxError::EINVAL);
+    }
+    // The accessed and dirty bits may differ, the NAPOT PTE gets the union.
+    let contiguous = ptes.iter().enumerate().all(|(i, &pte)| {
+        pte & !(PTE_A | PTE_D) == ((ppn + i) << PTE_PPN_SHIFT) | (flags & !(PTE_A | PTE_D))
+    });
+    if !contiguous {
+        return Err(LinuxError::EINVAL);
+    }
+    let accessed_dirty = ptes.iter().fold(0, |ad, &pte| ad | (pte & (PTE_A | PTE_D)));
+
+    let napot_pte = ((ppn | NAPOT_64K_PPN_BITS) << PTE_PPN_SHIFT) | flags | accessed_dirty | PTE_N;
+    for pte in ptes.iter_mut() {
+        core::ptr::write_volatile(pte, napot_pte);
+    }
+    // The cached 4K entries translate the same, one fence is enough.
+    super::flush_tlb(Some(vaddr));
+    Ok(())
+}
+
+/// Splits the NAPOT mapping of the 64K run at `vaddr` back into 16 4K
+/// mappings, e.g. before changing one of the pages.
+///
+/// It does nothing if the run is not a NAPOT mapping.
+///
+/// # Safety
+///
+/// `root` must be a valid page table, and nobody else may modify the entries
+/// concurrently.
+pub unsafe fn napot_split_64k(root: PhysAddr, vaddr: VirtAddr) -> Result<(), LinuxError> {
+    let ptes = napot_ptes(root, vaddr)?;
+    let first = ptes[0];
+    if first & (PTE_V | PTE_N) != (PTE_V | PTE_N) {
+        return Ok(());
+    }
+    let flags = first & !PTE_PPN_FIELD & !PTE_N;
+    let ppn = ((first >> PTE_PPN_SHIFT) & PTE_PPN_MASK) & !(NAPOT_64K_PAGES - 1);
+    for (i, pte) in ptes.iter_mut().enumerate() {
+        core::ptr::write_volatile(pte, ((ppn + i) << PTE_PPN_SHIFT) | flags);
+    }
+    super::flush_tlb(Some(vaddr));
+    Ok(())
+}
+
+/// Maps the 64K at `vaddr` to `paddr` directly with a NAPOT mapping, with the
+/// permissions of `flags` and the memory type `attr`.
+///
+/// The missing intermediate tables are allocated with `alloc_frame`, which
+/// returns the physical address of a free 4K frame. Both addresses must be
+/// 64K aligned, and none of the 16 pages may be mapped yet
+/// ([`LinuxError::EEXIST`]). Returns [`LinuxError::ENODEV`] without
+/// Svnapot.
+///
+/// # Safety
+///
+/// `root` must be a valid page table, and nobody else may modify the entries
+/// concurrently.
+pub unsafe fn napot_map_64k(
+    root: PhysAddr,
+    vaddr: VirtAddr,
+    paddr: PhysAddr,
+    flags: MemRegionFlags,
+    attr: MemAttr,
+    alloc_frame: &mut impl FnMut() -> Option<PhysAddr>,
+) -> Result<(), LinuxError> {
+    if !has_svnapot() {
+        return Err(LinuxError::ENODEV);
+    }
+    if (vaddr.as_usize() | paddr.as_usize()) % NAPOT_64K_SIZE != 0 {
+        return Err(LinuxError::EINVAL);
+    }
+    let flags = leaf_flags(flags, attr)?;
+    // The 16 entries are in the same last-level table.
+    let first: *mut usize = entry_at(root, vaddr, 0, Some(alloc_frame))?;
+    let ptes = core::slice::from_raw_parts_mut(first, NAPOT_64K_PAGES);
+    if ptes.iter().any(|&pte| pte & PTE_V != 0) {
+        return Err(LinuxError::EEXIST);
+    }
+    let ppn = paddr.as_usize() >> 12;
+    let napot_pte = ((ppn | NAPOT_64K_PPN_BITS) << PTE_PPN_SHIFT) | flags | PTE_N;
+    for pte in ptes.iter_mut() {
+        core::ptr::write_volatile(pte, napot_pte);
+    }
+    Ok(())
+}
+
+/// Tears down the NAPOT mapping of the 64K at `vaddr`, and returns the
+/// physical address it mapped.
+///
+/// Returns [`LinuxError::EINVAL`] if the run is not a NAPOT mapping (use
+/// [`napot_split_64k`] first to unmap only some of its pages).
+///
+/// # Safety
+///
+/// `root` must be a valid page table, and nobody else may modify the entries
+/// concurrently.
+pub unsafe fn napot_unmap_64k(root: PhysAddr, vaddr: VirtAddr) -> Result<PhysAddr, LinuxError> {
+    let ptes = napot_ptes(root, vaddr)?;
+    let first = ptes[0];
+    if first & (PTE_V | PTE_N) != (PTE_V | PTE_N) {
+        return Err(LinuxError::EINVAL);
+    }
+    let ppn = ((first >> PTE_PPN_SHIFT) & PTE_PPN_MASK) & !(NAPOT_64K_PAGES - 1);
+    for pte in ptes.iter_mut() {
+        core::ptr::write_volatile(pte, 0);
+    }
+    super::flush_tlb(Some(vaddr));
+    Ok(PhysAddr::from(ppn << 12))
+}
@@ -0,0 +1,48 @@
+//! Decoding the page faults for [`crate::trap::handle_page_fault`].
+
+use memory_addr::VirtAddr;
+use riscv::register::scause::{self, Exception, Trap};
+use riscv::register::stval;
+
+use super::TrapFrame;
+use crate::trap::{PageFaultAccess, PageFaultInfo};
+
+/// Returns the page fault being handled, from `scause` and `stval`, or
+/// [`None`] if the current trap is not a page fault.
+///
+/// It must be called while handling the trap of `tf`.
+pub fn page_fault_info(tf: &TrapFrame) -> Option<PageFaultInfo> {
+    let access = match scause::read().cause() {
+        Trap::Exception(Exception::LoadPageFault) => PageFaultAccess::Read,
+        Trap::Exception(Exception::StorePageFault) => PageFaultAccess::Write,
+        Trap::Exception(Exception::InstructionPageFault) => PageFaultAccess::Execute,
+        _ => return None,
+    };
+    Some(PageFaultInfo {
+        vaddr: VirtAddr::from(stval::read()),
+        ip: tf.sepc,
+        access,
+        user: tf.from_user(),
+    })
+}
+
+/// Handles a page fault exception (`scause` 12, 13 or 15).
+///
+/// A kernel stack overflow is reported first, see
+/// [`check_kernel_stack_overflow`](super::check_kernel_stack_overflow). The
+/// fault is then passed to the handler registered with
+/// [`register_page_fault_handler`](crate::trap::register_page_fault_handler).
+/// Returns whether it has been resolved, `false` if the trap is not a page
+/// fault.
+pub fn handle_page_fault_trap(tf: &mut TrapFrame) -> bool {
+    try_handle_page_fault(tf).unwrap_or(false)
+}
+
+/// Same as [`handle_page_fault_trap`], but returns [`None`] if the trap is
+/// not a page fault, for [`handle_exception`](super::handle_exception) to
+/// try the other handlers then.
+pub(super) fn try_handle_page_fault(tf: &mut TrapFrame) -> Option<bool> {
+    let info = page_fault_info(tf)?;
+    super::check_kernel_stack_overflow(tf, info.vaddr.as_usize());
+    Some(crate::trap::handle_page_fault(&info, tf))
+}
@@ -0,0 +1,415 @@
+//! Software walk and dump of the RISC-V page table, for debugging.
+
+use core::fmt;
+use core::ops::Range;
+use memory_addr::{PhysAddr, VirtAddr};
+use riscv::register::satp;
+
+use super::napot::NAPOT_64K_SIZE;
+use super::svpbmt::PTE_PBMT_MASK;
+use crate::mem::{phys_to_virt, PAGE_SIZE_4K};
+
+/// The maximum number of levels (Sv57).
+const MAX_LEVELS: usize = 5;
+
+pub(super) const PTE_V: usize = 1 << 0;
+pub(super) const PTE_R: usize = 1 << 1;
+pub(super) const PTE_W: usize = 1 << 2;
+pub(super) const PTE_X: usize = 1 << 3;
+pub(super) const PTE_U: usize = 1 << 4;
+const PTE_G: usize = 1 << 5;
+pub(super) const PTE_A: usize = 1 << 6;
+pub(super) const PTE_D: usize = 1 << 7;
+/// NAPOT (Svnapot) contiguous mapping.
+pub(super) const PTE_N: usize = 1 << 63;
+
+pub(super) const PTE_PPN_SHIFT: usize = 10;
+pub(super) const PTE_PPN_MASK: usize = (1 << 44) - 1;
+
+/// A page table entry read during the walk.
+#[derive(Debug, Clone, Copy)]
+pub struct PteSnapshot {
+    /// The level of the table, the root is `levels - 1` and the last level
+    /// (4K pages) is 0.
+    pub level: usize,
+    /// The physical address of the entry.
+    pub pte_paddr: PhysAddr,
+    /// The raw value of the entry.
+    pub pte: usize,
+}
+
+impl PteSnapshot {
+    /// The physical address in the PPN field of the entry.
+    pub const fn paddr(&self) -> PhysAddr {
+        PhysAddr::from(((self.pte >> PTE_PPN_SHIFT) & PTE_PPN_MASK) << 12)
+    }
+
+    const fn is_valid(&self) -> bool {
+        self.pte & PTE_V != 0
+    }
+
+    const fn is_leaf(&self) -> bool {
+        self.pte & (PTE_R | PTE_W | PTE_X) != 0
+    }
+}
+
+impl fmt::Display for PteSnapshot {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "L{} pte@{:#x} = {:#018x} [{}] -> {:#x}",
+            self.level,
+            self.pte_paddr.as_usize(),
+            self.pte,
+            PteFlags::from_pte(self.pte),
+            self.paddr().as_usize()
+        )
+    }
+}
+
+bitflags::bitflags! {
+    /// The flags of a page table entry.
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub struct PteFlags: usize {
+        /// Valid.
+        const V = PTE_V;
+        /// Readable.
+        const R = PTE_R;
+        /// Writable.
+        const W = PTE_W;
+        /// Executable.
+        const X = PTE_X;
+        /// Accessible in U-mode.
+        const U = PTE_U;
+        /// Global, in all address spaces.
+        const G = PTE_G;
+        /// Accessed.
+        const A = PTE_A;
+        /// Dirty.
+        const D = PTE_D;
+        /// Svpbmt non-cacheable memory type.
+        const PBMT_NC = 1 << 61;
+        /// Svpbmt I/O memory type.
+        const PBMT_IO = 1 << 62;
+        /// Svnapot contiguous mapping.
+        const N = PTE_N;
+    }
+}
+
+impl PteFlags {
+    /// Returns the flags of the entry `pte`.
+    pub const fn from_pte(pte: usize) -> Self {
+        Self::from_bits_truncate(pte)
+    }
+}
+
+impl fmt::Display for PteFlags {
+    /// Writes one letter per flag, or `-` if it is not set, e.g.
+    /// `VRW-XGAD-`, followed by the memory type if there is one.
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for (flag, name) in [
+            (Self::V, 'V'),
+            (Self::R, 'R'),
+            (Self::W, 'W'),
+            (Self::X, 'X'),
+            (Self::U, 'U'),
+            (Self::G, 'G'),
+            (Self::A, 'A'),
+            (Self::D, 'D'),
+            (Self::N, 'N'),
+        ] {
+            let c = if self.contains(flag) { name } else { '-' };
+            write!(f, "{}", c)?;
+        }
+        match self.bits() & PTE_PBMT_MASK {
+            0 => Ok(()),
+            pbmt => write!(f, " PBMT={}", pbmt >> PTE_PBMT_MASK.trailing_zeros()),
+        }
+    }
+}
+
+/// Why a page table walk has stopped.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PageWalkEnd {
+    /// Found a leaf entry, `vaddr` is mapped to `paddr` in a page of
+    /// `page_size` bytes (4K, or a superpage).
+    Mapped {
+        /// The translated physical address.
+        paddr: PhysAddr,
+        /// The size of the page (or superpage).
+        page_size: usize,
+    },
+    /// An entry without `V` at the last read level.
+    NotPresent,
+    /// A reserved encoding (`W` without `R`) at the last read level.
+    Reserved,
+    /// A superpage whose PPN is not aligned to its size.
+    MisalignedSuperpage,
+    /// A non-leaf entry at level 0.
+    TooDeep,
+    /// `vaddr` is not sign-extended from the top bit of the mode.
+    NonCanonical,
+    /// Translation is disabled (`satp.MODE` is Bare).
+    Bare,
+}
+
+/// The result of [`dump_page_table_walk`].
+///
+/// Its [`Display`](fmt::Display) output has one line per level followed by
+/// the result, and is suitable for logging.
+#[derive(Debug, Clone)]
+pub struct PageWalkResult {
+    /// The walked virtual address.
+    pub vaddr: VirtAddr,
+    /// The number of levels of the paging mode (3 for Sv39).
+    pub levels: usize,
+    /// Why the walk has stopped.
+    pub end: PageWalkEnd,
+    ptes: [Option<PteSnapshot>; MAX_LEVELS],
+}
+
+impl PageWalkResult {
+    /// Returns the entries read during the walk, from the root down.
+    pub fn ptes(&self) -> impl Iterator<Item = &PteSnapshot> {
+        self.ptes.iter().flatten()
+    }
+
+    /// Returns the leaf (or the last read) entry.
+    pub fn last_pte(&self) -> Option<&PteSnapshot> {
+        self.ptes().last()
+    }
+}
+
+impl fmt::Display for PageWalkResult {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(
+            f,
+            "page table walk of {:#x} (Sv{}):",
+            self.vaddr.as_usize(),
+            12 + 9 * self.levels
+        )?;
+        for pte in self.ptes() {
+            writeln!(f, "  {}", pte)?;
+        }
+        match self.end {
+            PageWalkEnd::Mapped { paddr, page_size } => write!(
+                f,
+                "  => {:#x} (page size {:#x})",
+                paddr.as_usize(),
+                page_size
+            ),
+            end => write!(f, "  => {:?}", end),
+        }
+    }
+}
+
+/// Returns the number of levels of the current paging mode, or [`None`] if
+/// translation is disabled.
+pub(super) fn current_levels() -> Option<usize> {
+    match satp::read().mode() {
+        satp::Mode::Bare => None,
+        satp::Mode::Sv48 => Some(4),
+        satp::Mode::Sv57 => Some(5),
+        _ => Some(3),
+    }
+}
+
+/// Walks the page table with the root at `root` to translate `va`, recording
+/// the entry read at each level.
+///
+/// The paging mode (Sv39/Sv48/Sv57) is the one in the current `satp`. The
+/// walk stops at the first leaf entry (so superpages are reported with their
+/// size), or at the level where the hardware walk would fault. The table is
+/// accessed through the linear mapping, and nothing is modified (`A`/`D` bits
+/// are reported as they are).
+///
+/// It is meant to be called by the page fault handler in debug builds, e.g.
+/// `debug!("{}", dump_page_table_walk(read_page_table_root(), stval))`.
+pub fn dump_page_table_walk(root: PhysAddr, va: VirtAddr) -> PageWalkResult {
+    let mut result = PageWalkResult {
+        vaddr: va,
+        levels: 0,
+        end: PageWalkEnd::Bare,
+        ptes: [None; MAX_LEVELS],
+    };
+    let Some(levels) = current_levels() else {
+        return result;
+    };
+    result.levels = levels;
+
+    let va_bits = 12 + 9 * levels;
+    let high = (va.as_usize() as isize) >> (va_bits - 1);
+    if high != 0 && high != -1 {
+        result.end = PageWalkEnd::NonCanonical;
+        return result;
+    }
+
+    let mut table = root;
+    for (i, level) in (0..levels).rev().enumerate() {
+        let index = (va.as_usize() >> (12 + 9 * level)) & 0x1ff;
+        let pte_paddr = PhysAddr::from(table.as_usize() + index * 8);
+        let pte = unsafe {
+            phys_to_virt(pte_paddr)
+                .as_ptr()
+                .cast::<usize>()
+                .read_volatile()
+        };
+        let snapshot = PteSnapshot {
+            level,
+            pte_paddr,
+            pte,
+        };
+        result.ptes[i] = Some(snapshot);
+
+        if !snapshot.is_valid() {
+            result.end = PageWalkEnd::NotPresent;
+            return result;
+        }
+        if pte & (PTE_R | PTE_W) == PTE_W {
+            result.end = PageWalkEnd::Reserved;
+            return result;
+        }
+        if snapshot.is_leaf() {
+            let (page_size, base) = if level == 0 && pte & PTE_N != 0 {
+                // A 64K NAPOT page, the low 4 bits of the PPN are `0b1000`.
+                (
+                    NAPOT_64K_SIZE,
+                    snapshot.paddr().as_usize() & !(NAPOT_64K_SIZE - 1),
+                )
+            } else {
+                (1 << (12 + 9 * level), snapshot.paddr().as_usize())
+            };
+            result.end = if base & (page_size - 1) != 0 {
+                PageWalkEnd::MisalignedSuperpage
+            } else {
+                PageWalkEnd::Mapped {
+                    paddr: PhysAddr::from(base | (va.as_usize() & (page_size - 1))),
+                    page_size,
+                }
+            };
+            return result;
+        }
+        table = snapshot.paddr();
+    }
+    result.end = PageWalkEnd::TooDeep;
+    result
+}
+
+/// Translates `vaddr` in the page table with the root at `root`, and returns
+/// the physical address, the flags of the leaf entry and its level (0 for a
+/// 4K page, 1 for a 2M superpage...), or [`None`] if it is not mapped.
+///
+/// See [`dump_page_table_walk`] for the details of the walk.
+pub fn walk(root: PhysAddr, vaddr: VirtAddr) -> Option<(PhysAddr, PteFlags, usize)> {
+    let result = dump_page_table_walk(root, vaddr);
+    match result.end {
+        PageWalkEnd::Mapped { paddr, .. } => {
+            let leaf = result.last_pte()?;
+            Some((paddr, PteFlags::from_pte(leaf.pte), leaf.level))
+        }
+        _ => None,
+    }
+}
+
+/// A page mapped by a leaf entry, see [`page_table_mappings`].
+#[derive(Debug, Clone, Copy)]
+pub struct PageMapping {
+    /// The virtual address of the start of the page.
+    pub vaddr: VirtAddr,
+    /// The physical address of the start of the page.
+    pub paddr: PhysAddr,
+    /// The size of the page (4K, 64K for NAPOT, or a superpage).
+    pub page_size: usize,
+    /// The flags of the leaf entry.
+    pub flags: PteFlags,
+}
+
+impl fmt::Display for PageMapping {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{:#018x} -> {:#x} ({:#x}) [{}]",
+            self.vaddr.as_usize(),
+            self.paddr.as_usize(),
+            self.page_size,
+            self.flags
+        )
+    }
+}
+
+/// The iterator returned by [`page_table_mappings`].
+pub struct PageTableMappings {
+    root: PhysAddr,
+    /// The next address to look up.
+    cursor: usize,
+    end: usize,
+}
+
+impl Iterator for PageTableMappings {
+    type Item = PageMapping;
+
+    fn next(&mut self) -> Option<PageMapping> {
+        while self.cursor < self.end {
+            let result = dump_page_table_walk(self.root, VirtAddr::from(self.cursor));
+            // The size of the range to skip if there is no mapping, a
+            // power of two.
+            let skip = match (result.end, result.last_pte()) {
+                (PageWalkEnd::Mapped { paddr, page_size }, Some(leaf)) => {
+                    let vaddr = self.cursor & !(page_size - 1);
+                    let paddr = paddr.as_usize() - (self.cursor - vaddr);
+                    self.cursor = vaddr.checked_add(page_size).unwrap_or(usize::MAX);
+                    return Some(PageMapping {
+                        vaddr: VirtAddr::from(vaddr),
+                        paddr: PhysAddr::from(paddr),
+                        page_size,
+                        flags: PteFlags::from_pte(leaf.pte),
+                    });
+                }
+                (PageWalkEnd::Bare, _) => return None,
+                (PageWalkEnd::NonCanonical, _) => {
+                    // Jump from the lower half to the upper half.
+                    let va_bits = 12 + 9 * result.levels;
+                    let upper = !((1usize << (va_bits - 1)) - 1);
+                    if self.cursor >= upper {
+                        return None;
+                    }
+                    // Not a power of two, it is not an alignment mask.
+                    self.cursor = upper;
+                    continue;
+                }
+                (_, Some(last)) => 1 << (12 + 9 * last.level),
+                (_, None) => PAGE_SIZE_4K,
+            };
+            let next = (self.cursor & !(skip - 1)).checked_add(skip);
+            self.cursor = next.unwrap_or(usize::MAX);
+        }
+        None
+    }
+}
+
+/// Returns an iterator over the pages mapped in `range` by the page table
+/// with the root at `root`, in the current paging mode.
+///
+/// The pages that only partly overlap `range` are included. It reads the
+/// tables through the linear mapping, with one walk per mapped page or hole.
+pub fn page_table_mappings(root: PhysAddr, range: Range<VirtAddr>) -> PageTableMappings {
+    PageTableMappings {
+        root,
+        cursor: range.start.align_down_4k().as_usize(),
+        end: range.end.as_usize(),
+    }
+}
+
+/// Logs the pages mapped in `range` by the page table with the root at
+/// `root`, one line per page (see [`page_table_mappings`]).
+pub fn dump_page_table(root: PhysAddr, range: Range<VirtAddr>) {
+    info!(
+        "page table {:#x} [{:#x}, {:#x}):",
+        root.as_usize(),
+        range.start.as_usize(),
+        range.end.as_usize()
+    );
+    for mapping in page_table_mappings(root, range) {
+        info!("  {}", mapping);
+    }
+}
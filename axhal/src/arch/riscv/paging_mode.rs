@@ -0,0 +1,169 @@
+//! Runtime selection of the paging mode (Sv39/Sv48/Sv57).
+//!
+//! The mode is Sv39 unless a larger one is selected with [`set_paging_mode`]
+//! before paging is enabled. The size of the user address space (and the
+//! layout derived from it) follows the selected mode.
+
+use axerrno::LinuxError;
+use core::sync::atomic::{AtomicU8, Ordering};
+use riscv::register::satp;
+
+use super::page_walk::{PTE_A, PTE_D, PTE_R, PTE_V, PTE_W, PTE_X};
+use crate::mem::PAGE_SIZE_4K;
+
+/// A paging mode of `satp`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+#[repr(u8)]
+pub enum PagingMode {
+    /// 3 levels, 39-bit virtual addresses.
+    Sv39 = 3,
+    /// 4 levels, 48-bit virtual addresses.
+    Sv48 = 4,
+    /// 5 levels, 57-bit virtual addresses.
+    Sv57 = 5,
+}
+
+impl PagingMode {
+    const fn from_levels(levels: u8) -> Option<Self> {
+        Some(match levels {
+            3 => Self::Sv39,
+            4 => Self::Sv48,
+            5 => Self::Sv57,
+            _ => return None,
+        })
+    }
+
+    /// Returns the number of page table levels.
+    pub const fn levels(self) -> usize {
+        self as usize
+    }
+
+    /// Returns the number of virtual address bits.
+    pub const fn va_bits(self) -> usize {
+        12 + 9 * self.levels()
+    }
+
+    /// Returns the `MODE` field of `satp`.
+    pub const fn satp_mode(self) -> satp::Mode {
+        match self {
+            Self::Sv39 => satp::Mode::Sv39,
+            Self::Sv48 => satp::Mode::Sv48,
+            Self::Sv57 => satp::Mode::Sv57,
+        }
+    }
+
+    /// Returns the size of the user address space, the lower half of the
+    /// virtual address space (as in Linux).
+    pub const fn task_size(self) -> usize {
+        1 << (self.va_bits() - 1)
+    }
+}
+
+/// The selected mode, as its number of levels.
+static PAGING_MODE: AtomicU8 = AtomicU8::new(PagingMode::Sv39 as u8);
+
+/// The largest supported mode as its number of levels, 0 if not probed.
+static MAX_PAGING_MODE: AtomicU8 = AtomicU8::new(0);
+
+/// A root page table that identity-maps the low physical memory with a
+/// single leaf entry, which is valid in both Sv48 and Sv57.
+#[repr(C, align(4096))]
+struct ProbeTable([usize; 512]);
+
+static mut PROBE_TABLE: ProbeTable = ProbeTable([0; 512]);
+
+/// Tries to enable `mode` with the probe table, and reads `satp` back.
+///
+/// An unsupported mode leaves `satp` unchanged, i.e. `Bare`.
+unsafe fn probe(mode: PagingMode) -> bool {
+    let root = core::ptr::addr_of!(PROBE_TABLE) as usize;
+    let probe_satp = super::make_satp(mode.satp_mode(), 0, root.into());
+    satp::write(probe_satp);
+    let supported = satp::read().bits() == probe_satp;
+    satp::write(0);
+    riscv::asm::sfence_vma_all();
+    supported
+}
+
+/// Returns the largest paging mode the CPU supports.
+///
+/// The probe writes `satp`, so it can only run before paging is enabled (at
+/// the physical PC, which the probe table maps). Once paging is enabled,
+/// the modes that have not been probed are reported as not supported.
+pub fn max_paging_mode() -> PagingMode {
+    if let Some(mode) = PagingMode::from_levels(MAX_PAGING_MODE.load(Ordering::Relaxed)) {
+        return mode;
+    }
+    if satp::read().mode() != satp::Mode::Bare {
+        return paging_mode();
+    }
+    let mode = unsafe {
+        let _guard = kernel_guard_base::IrqSave::new();
+        PROBE_TABLE.0[0] = PTE_V | PTE_R | PTE_W | PTE_X | PTE_A | PTE_D;
+        if probe(PagingMode::Sv57) {
+            PagingMode::Sv57
+        } else if probe(PagingMode::Sv48) {
+            PagingMode::Sv48
+        } else {
+            PagingMode::Sv39
+        }
+    };
+    MAX_PAGING_MODE.store(mode as u8, Ordering::Relaxed);
+    mode
+}
+
+/// Returns the selected paging mode.
+#[inline]
+pub fn paging_mode() -> PagingMode {
+    PagingMode::from_levels(PAGING_MODE.load(Ordering::Relaxed)).unwrap_or(PagingMode::Sv39)
+}
+
+/// Selects the paging mode, the page tables passed to
+/// [`write_page_table_root`](super::write_page_table_root) must have its
+/// number of levels from then on.
+///
+/// It must be called before paging is enabled, [`LinuxError::EBUSY`] is
+/// returned otherwise (unless `mode` is the current one). Returns
+/// [`LinuxError::ENODEV`] if the CPU does not support `mode`.
+pub fn set_paging_mode(mode: PagingMode) -> Result<(), LinuxError> {
+    if mode == paging_mode() {
+        return Ok(());
+    }
+    if satp::read().mode() != satp::Mode::Bare {
+        return Err(LinuxError::EBUSY);
+    }
+    if mode > max_paging_mode() {
+        return Err(LinuxError::ENODEV);
+    }
+    PAGING_MODE.store(mode as u8, Ordering::Relaxed);
+    Ok(())
+}
+
+/// Returns the size of the user address space in the selected paging mode.
+#[inline]
+pub fn task_size() -> usize {
+    paging_mode().task_size()
+}
+
+/// Returns the top of the user stack in the selected paging mode, before
+/// randomization by [`UserLayout::stack_top`](super::UserLayout::stack_top).
+#[inline]
+pub fn stack_top() -> usize {
+    task_size()
+}
+
+/// Returns the load address of an `ET_DYN` program in the selected paging
+/// mode, see [`ELF_ET_DYN_BASE`](super::ELF_ET_DYN_BASE), before
+/// randomization by [`UserLayout::elf_et_dyn_base`](super::UserLayout::elf_et_dyn_base).
+#[inline]
+pub fn elf_et_dyn_base() -> usize {
+    (task_size() / 3) * 2
+}
+
+/// Returns the base of the `mmap` area in the selected paging mode, see
+/// [`TASK_UNMAPPED_BASE`](super::TASK_UNMAPPED_BASE), before randomization
+/// by [`UserLayout::mmap_base`](super::UserLayout::mmap_base).
+#[inline]
+pub fn task_unmapped_base() -> usize {
+    (task_size() / 3) & !(PAGE_SIZE_4K - 1)
+}
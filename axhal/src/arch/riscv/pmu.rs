@@ -0,0 +1,544 @@
+//! Performance counters, through the SBI PMU extension.
+//!
+//! The counters of the harts are listed by [`pmu_counters`]. A counter is
+//! configured for an event (e.g. [`PMU_EVENT_CACHE_MISSES`]) and started by
+//! [`pmu_start_counting`]: it counts on the current CPU, in S-mode and
+//! U-mode, until [`pmu_stop_counting`] releases it. The events added to a
+//! task with [`task_pmu_add_event`] are only counted while it runs: their
+//! counters are released and their counts saved in its [`TaskContext`] when
+//! it is switched out, and they are configured again on the CPU it is
+//! switched to.
+//!
+//! With the Sscofpmf extension, a sampling counter is configured to start
+//! at `-period`, so that it overflows after `period` events and raises the
+//! local counter-overflow interrupt (LCOFI). The trap handler routes the
+//! interrupt ([`PMU_OVERFLOW_IRQ_NUM`]) to [`handle_pmu_overflow`], which calls
+//! the registered handler for each overflowed counter and re-arms it. The
+//! counters started by [`pmu_start_profiling`] record the interrupted PC in
+//! a per-CPU buffer instead, which the profiler drains with
+//! [`pmu_read_samples`].
+//!
+//! [`PMU_OVERFLOW_IRQ_NUM`]: crate::platform::irq::PMU_OVERFLOW_IRQ_NUM
+
+use axerrno::LinuxError;
+use core::sync::atomic::{AtomicU8, AtomicUsize, Ordering};
+use spinbase::SpinNoIrq;
+
+use super::sbi;
+use super::{TaskContext, TrapFrame};
+
+/// SBI event index of the hardware CPU cycles event.
+pub const PMU_EVENT_CPU_CYCLES: usize = 0x1;
+/// SBI event index of the hardware retired instructions event.
+pub const PMU_EVENT_INSTRUCTIONS: usize = 0x2;
+/// SBI event index of the hardware cache references event.
+pub const PMU_EVENT_CACHE_REFERENCES: usize = 0x3;
+/// SBI event index of the hardware cache misses event.
+pub const PMU_EVENT_CACHE_MISSES: usize = 0x4;
+/// SBI event index of the hardware retired branches event.
+pub const PMU_EVENT_BRANCH_INSTRUCTIONS: usize = 0x5;
+/// SBI event index of the hardware mispredicted branches event.
+pub const PMU_EVENT_BRANCH_MISSES: usize = 0x6;
+
+/// The maximum number of events counted for a task.
+pub const MAX_TASK_PMU_EVENTS: usize = 4;
+
+/// The number of samples buffered per CPU by [`pmu_start_profiling`].
+pub const PMU_SAMPLE_BUFFER_SIZE: usize = 256;
+
+/// The maximum number of counters that can be used for sampling.
+const MAX_SAMPLING_COUNTERS: usize = 32;
+
+/// CSR number of `cycle`, the first counter.
+const CSR_CYCLE: usize = 0xc00;
+
+/// Bit of LCOFI in `sip`.
+const LCOFI_BIT: usize = 1 << 13;
+
+/// The type of a counter-overflow handler, it receives the interrupted trap
+/// frame (`sepc` is the sampled PC) and the SBI index of the counter.
+pub type PmuOverflowHandler = fn(&mut TrapFrame, u32);
+
+static PMU_OVERFLOW_HANDLER: SpinNoIrq<Option<PmuOverflowHandler>> = SpinNoIrq::new(None);
+
+/// A counter used for sampling on the current CPU.
+#[derive(Clone, Copy)]
+struct SamplingCounter {
+    /// Bit of the counter in `scountovf`.
+    ovf_bit: usize,
+    period: u64,
+    /// The handler of this counter, in place of the registered one.
+    handler: Option<PmuOverflowHandler>,
+}
+
+#[percpu2::def_percpu]
+static SAMPLING_COUNTERS: [Option<SamplingCounter>; MAX_SAMPLING_COUNTERS] =
+    [None; MAX_SAMPLING_COUNTERS];
+
+/// A sample of a profiling counter.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PmuSample {
+    /// The interrupted PC.
+    pub pc: usize,
+    /// The interrupted `ra`, the caller if the PC is in a leaf function.
+    pub ra: usize,
+    /// The interrupted stack pointer.
+    pub sp: usize,
+    /// Whether the CPU was in U-mode.
+    pub user: bool,
+    /// The SBI index of the counter.
+    pub counter: u32,
+    /// The time of the sample, in nanoseconds.
+    pub time_ns: u64,
+}
+
+const EMPTY_SAMPLE: PmuSample = PmuSample {
+    pc: 0,
+    ra: 0,
+    sp: 0,
+    user: false,
+    counter: 0,
+    time_ns: 0,
+};
+
+/// A ring of the samples of a CPU.
+struct SampleBuffer {
+    samples: [PmuSample; PMU_SAMPLE_BUFFER_SIZE],
+    /// The index of the oldest sample.
+    head: usize,
+    len: usize,
+    /// The samples dropped as the buffer was full.
+    lost: u64,
+}
+
+#[allow(clippy::declare_interior_mutable_const)]
+const SAMPLE_BUFFER_INIT: SpinNoIrq<SampleBuffer> = SpinNoIrq::new(SampleBuffer {
+    samples: [EMPTY_SAMPLE; PMU_SAMPLE_BUFFER_SIZE],
+    head: 0,
+    len: 0,
+    lost: 0,
+});
+
+static SAMPLE_BUFFERS: [SpinNoIrq<SampleBuffer>; axconfig::SMP] =
+    [SAMPLE_BUFFER_INIT; axconfig::SMP];
+
+/// A counter of the SBI PMU extension.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PmuCounter {
+    /// The SBI index of the counter.
+    pub index: u32,
+    /// The CSR of a hardware counter, [`None`] for a firmware counter.
+    pub csr: Option<u16>,
+    /// The number of bits of the counter.
+    pub width: u8,
+}
+
+impl PmuCounter {
+    /// Returns the counter of SBI index `index`, if it exists.
+    pub fn get(index: u32) -> Option<Self> {
+        let info = sbi::pmu_counter_info(index as usize).ok()?;
+        Some(if (info as isize) < 0 {
+            Self {
+                index,
+                csr: None,
+                width: 64,
+            }
+        } else {
+            Self {
+                index,
+                csr: Some((info & 0xfff) as u16),
+                width: ((info >> 12) & 0x3f) as u8 + 1,
+            }
+        })
+    }
+
+    /// Reads the counter on the current CPU.
+    pub fn read(&self) -> u64 {
+        match self.csr {
+            Some(csr) => read_counter_csr(csr),
+            None => sbi::pmu_counter_fw_read(self.index as usize).unwrap_or(0),
+        }
+    }
+
+    fn mask(&self) -> u64 {
+        u64::MAX >> (64 - self.width as u32)
+    }
+}
+
+/// The events counted for a task, see [`task_pmu_add_event`].
+#[derive(Debug, Default, Clone, Copy)]
+pub struct TaskPmu {
+    /// The SBI event indexes, 0 for a free slot.
+    events: [usize; MAX_TASK_PMU_EVENTS],
+    /// The counts until the task was last switched out.
+    counts: [u64; MAX_TASK_PMU_EVENTS],
+}
+
+impl TaskPmu {
+    fn is_empty(&self) -> bool {
+        self.events.iter().all(|&event| event == 0)
+    }
+}
+
+/// The [`TaskPmu`] of the task running on this CPU, as an address.
+#[percpu2::def_percpu]
+static CURRENT_TASK_PMU: usize = 0;
+
+/// The counters of the events of the task running on this CPU, and their
+/// values when they were started.
+#[percpu2::def_percpu]
+static TASK_COUNTERS: [Option<(PmuCounter, u64)>; MAX_TASK_PMU_EVENTS] =
+    [None; MAX_TASK_PMU_EVENTS];
+
+/// Reads the counter CSR `csr`, from `cycle` to `hpmcounter31`.
+fn read_counter_csr(csr: u16) -> u64 {
+    macro_rules! read_csr {
+        ($($num:literal)*) => {
+            match csr {
+                $($num => {
+                    let value: usize;
+                    unsafe { core::arch::asm!("csrr {}, {csr}", out(reg) value, csr = const $num) };
+                    value as u64
+                })*
+                _ => 0,
+            }
+        };
+    }
+    read_csr!(
+        0xc00 0xc01 0xc02 0xc03 0xc04 0xc05 0xc06 0xc07
+        0xc08 0xc09 0xc0a 0xc0b 0xc0c 0xc0d 0xc0e 0xc0f
+        0xc10 0xc11 0xc12 0xc13 0xc14 0xc15 0xc16 0xc17
+        0xc18 0xc19 0xc1a 0xc1b 0xc1c 0xc1d 0xc1e 0xc1f
+    )
+}
+
+/// Returns the number of counters, 0 if there is no SBI PMU extension.
+fn num_counters() -> usize {
+    // usize::MAX: unknown
+    static NUM_COUNTERS: AtomicUsize = AtomicUsize::new(usize::MAX);
+    match NUM_COUNTERS.load(Ordering::Relaxed) {
+        usize::MAX => {
+            let num = if sbi::probe_extension(sbi::EID_PMU) {
+                sbi::pmu_num_counters().unwrap_or(0)
+            } else {
+                0
+            };
+            NUM_COUNTERS.store(num, Ordering::Relaxed);
+            num
+        }
+        num => num,
+    }
+}
+
+/// Returns an iterator over the counters of the SBI PMU extension.
+pub fn pmu_counters() -> impl Iterator<Item = PmuCounter> {
+    (0..num_counters() as u32).filter_map(PmuCounter::get)
+}
+
+/// Configures a counter for the SBI event `event_idx` on the current CPU, and
+/// starts it from 0.
+///
+/// Returns [`LinuxError::ENODEV`] if there is no SBI PMU extension,
+/// [`LinuxError::EINVAL`] if `event_idx` is 0, and [`LinuxError::EBUSY`] if
+/// no free counter can count the event.
+pub fn pmu_start_counting(event_idx: usize) -> Result<PmuCounter, LinuxError> {
+    let num = num_counters();
+    if num == 0 {
+        return Err(LinuxError::ENODEV);
+    }
+    if event_idx == 0 {
+        return Err(LinuxError::EINVAL);
+    }
+    let index = sbi::pmu_counter_config_matching(
+        0,
+        usize::MAX >> (usize::BITS as usize - num.min(usize::BITS as usize)),
+        sbi::PMU_CFG_FLAG_CLEAR_VALUE | sbi::PMU_CFG_FLAG_AUTO_START | sbi::PMU_CFG_FLAG_SET_MINH,
+        event_idx,
+        0,
+    )
+    .map_err(|_| LinuxError::EBUSY)?;
+    PmuCounter::get(index as u32).ok_or_else(|| {
+        sbi::pmu_counter_stop(index, true).ok();
+        LinuxError::EBUSY
+    })
+}
+
+/// Stops the counter `counter` on the current CPU and releases it, returns
+/// its final value.
+pub fn pmu_stop_counting(counter: PmuCounter) -> u64 {
+    let value = counter.read();
+    sbi::pmu_counter_stop(counter.index as usize, true).ok();
+    value
+}
+
+/// Returns whether `pmu` is the one of the task running on this CPU.
+fn is_current_task_pmu(pmu: &TaskPmu) -> bool {
+    let current = unsafe { CURRENT_TASK_PMU.read_current_raw() };
+    current == pmu as *const _ as usize
+}
+
+/// Starts the counters of the events in `pmu` on the current CPU. The events
+/// without a free counter are not counted.
+fn start_task_counters(pmu: &TaskPmu) {
+    let counters = unsafe { TASK_COUNTERS.current_ref_mut_raw() };
+    for (slot, &event) in pmu.events.iter().enumerate() {
+        if event != 0 && counters[slot].is_none() {
+            counters[slot] = pmu_start_counting(event)
+                .ok()
+                .map(|counter| (counter, counter.read()));
+        }
+    }
+}
+
+/// Stops the counters of the task running on this CPU, and adds their
+/// counts to `pmu`.
+fn stop_task_counters(pmu: &mut TaskPmu) {
+    let counters = unsafe { TASK_COUNTERS.current_ref_mut_raw() };
+    for (slot, running) in counters.iter_mut().enumerate() {
+        if let Some((counter, start)) = running.take() {
+            let value = pmu_stop_counting(counter);
+            pmu.counts[slot] += value.wrapping_sub(start) & counter.mask();
+        }
+    }
+}
+
+/// Counts the SBI event `event_idx` for the task of `ctx`, while it runs,
+/// from 0. It takes effect at once for the current task, and the next time
+/// it is switched to otherwise.
+///
+/// Returns the slot of the event for [`task_pmu_read`], or
+/// [`LinuxError::ENODEV`] if there is no SBI PMU extension,
+/// [`LinuxError::EINVAL`] if `event_idx` is 0, and [`LinuxError::ENOSPC`]
+/// if [`MAX_TASK_PMU_EVENTS`] are already counted.
+pub fn task_pmu_add_event(ctx: &mut TaskContext, event_idx: usize) -> Result<usize, LinuxError> {
+    if num_counters() == 0 {
+        return Err(LinuxError::ENODEV);
+    }
+    if event_idx == 0 {
+        return Err(LinuxError::EINVAL);
+    }
+    let pmu = &mut ctx.pmu;
+    let slot = pmu
+        .events
+        .iter()
+        .position(|&event| event == 0)
+        .ok_or(LinuxError::ENOSPC)?;
+    pmu.events[slot] = event_idx;
+    pmu.counts[slot] = 0;
+    let _guard = kernel_guard_base::IrqSave::new();
+    if is_current_task_pmu(pmu) {
+        start_task_counters(pmu);
+    }
+    Ok(slot)
+}
+
+/// Returns the count of the event in `slot` for the task of `ctx`, or
+/// [`None`] if there is no event in it.
+pub fn task_pmu_read(ctx: &TaskContext, slot: usize) -> Option<u64> {
+    let pmu = &ctx.pmu;
+    if *pmu.events.get(slot)? == 0 {
+        return None;
+    }
+    let _guard = kernel_guard_base::IrqSave::new();
+    let running = match unsafe { TASK_COUNTERS.current_ref_raw() }[slot] {
+        Some((counter, start)) if is_current_task_pmu(pmu) => {
+            counter.read().wrapping_sub(start) & counter.mask()
+        }
+        _ => 0,
+    };
+    Some(pmu.counts[slot] + running)
+}
+
+/// Stops counting the events of the task of `ctx`, and clears them.
+pub fn task_pmu_clear(ctx: &mut TaskContext) {
+    let _guard = kernel_guard_base::IrqSave::new();
+    if is_current_task_pmu(&ctx.pmu) {
+        stop_task_counters(&mut ctx.pmu);
+    }
+    ctx.pmu = TaskPmu::default();
+}
+
+/// Saves the counts of the events of the current task to `current`, and
+/// starts counting the ones of `next`, on context switch.
+pub(super) fn switch_task_pmu(current: &mut TaskPmu, next: &TaskPmu) {
+    if !current.is_empty() {
+        stop_task_counters(current);
+    }
+    unsafe { CURRENT_TASK_PMU.write_current_raw(next as *const _ as usize) };
+    if !next.is_empty() {
+        start_task_counters(next);
+    }
+}
+
+/// Returns whether the counter-overflow interrupt (Sscofpmf) and the SBI PMU
+/// extension are available.
+///
+/// It needs the device tree, i.e. must be called after `arch_init_early`.
+pub fn has_sscofpmf() -> bool {
+    // 0: unknown, 1: not supported, 2: supported
+    static SSCOFPMF: AtomicU8 = AtomicU8::new(0);
+    match SSCOFPMF.load(Ordering::Relaxed) {
+        0 => {
+            let supported =
+                super::cpu_has(super::Feature::Sscofpmf) && sbi::probe_extension(sbi::EID_PMU);
+            SSCOFPMF.store(if supported { 2 } else { 1 }, Ordering::Relaxed);
+            supported
+        }
+        state => state == 2,
+    }
+}
+
+/// Registers the handler of counter-overflow interrupts.
+pub fn set_pmu_overflow_handler(f: PmuOverflowHandler) {
+    *PMU_OVERFLOW_HANDLER.lock() = Some(f);
+}
+
+/// Starts sampling the SBI event `event_idx` on the current CPU, with an
+/// overflow interrupt every `period` events.
+///
+/// Returns the SBI index of the counter that is used, or
+/// [`LinuxError::EINVAL`] if `event_idx` is 0 (no SBI event has that index)
+/// or `period` is 0, [`LinuxError::ENODEV`] without Sscofpmf, or
+/// [`LinuxError::EBUSY`] if no counter can count the event.
+pub fn pmu_start_sampling(event_idx: usize, period: u64) -> Result<u32, LinuxError> {
+    start_sampling(event_idx, period, None)
+}
+
+/// Starts sampling as [`pmu_start_sampling`], with the overflows of the
+/// counter handled by `handler` instead of the registered handler.
+pub(super) fn start_sampling(
+    event_idx: usize,
+    period: u64,
+    handler: Option<PmuOverflowHandler>,
+) -> Result<u32, LinuxError> {
+    if !has_sscofpmf() {
+        return Err(LinuxError::ENODEV);
+    }
+    if event_idx == 0 || period == 0 {
+        return Err(LinuxError::EINVAL);
+    }
+    let num = sbi::pmu_num_counters()
+        .unwrap_or(0)
+        .min(MAX_SAMPLING_COUNTERS);
+    let counter = sbi::pmu_counter_config_matching(
+        0,
+        (1 << num) - 1,
+        sbi::PMU_CFG_FLAG_CLEAR_VALUE | sbi::PMU_CFG_FLAG_SET_MINH,
+        event_idx,
+        0,
+    )
+    .map_err(|_| LinuxError::EBUSY)?;
+    let ovf_bit = match sbi::pmu_counter_csr(counter) {
+        Some(csr) if counter < MAX_SAMPLING_COUNTERS => csr - CSR_CYCLE,
+        _ => {
+            sbi::pmu_counter_stop(counter, true).ok();
+            return Err(LinuxError::EBUSY);
+        }
+    };
+
+    let _guard = kernel_guard_base::IrqSave::new();
+    unsafe {
+        SAMPLING_COUNTERS.current_ref_mut_raw()[counter] = Some(SamplingCounter {
+            ovf_bit,
+            period,
+            handler,
+        });
+    }
+    super::enable_irq_sources(super::IrqSources::COUNTER_OVERFLOW);
+    sbi::pmu_counter_start(counter, Some(period.wrapping_neg())).ok();
+    Ok(counter as u32)
+}
+
+/// Starts profiling the SBI event `event_idx` on the current CPU: every
+/// `period` events, the interrupted PC is recorded in the sample buffer of
+/// the CPU, for [`pmu_read_samples`].
+///
+/// Returns the SBI index of the counter that is used, to stop with
+/// [`pmu_stop_sampling`].
+pub fn pmu_start_profiling(event_idx: usize, period: u64) -> Result<u32, LinuxError> {
+    start_sampling(event_idx, period, Some(record_sample))
+}
+
+fn record_sample(tf: &mut TrapFrame, counter: u32) {
+    let sample = PmuSample {
+        pc: tf.sepc,
+        ra: tf.regs.ra,
+        sp: tf.regs.sp,
+        user: tf.from_user(),
+        counter,
+        time_ns: crate::time::current_time_nanos(),
+    };
+    let mut buffer = SAMPLE_BUFFERS[crate::cpu::_this_cpu_id()].lock();
+    if buffer.len == PMU_SAMPLE_BUFFER_SIZE {
+        buffer.lost += 1;
+        return;
+    }
+    let tail = (buffer.head + buffer.len) % PMU_SAMPLE_BUFFER_SIZE;
+    buffer.samples[tail] = sample;
+    buffer.len += 1;
+}
+
+/// Moves the oldest samples of the CPU `cpu_id` to `samples`, and returns
+/// how many were moved.
+pub fn pmu_read_samples(cpu_id: usize, samples: &mut [PmuSample]) -> usize {
+    let Some(buffer) = SAMPLE_BUFFERS.get(cpu_id) else {
+        return 0;
+    };
+    let mut buffer = buffer.lock();
+    let count = buffer.len.min(samples.len());
+    for sample in &mut samples[..count] {
+        *sample = buffer.samples[buffer.head];
+        buffer.head = (buffer.head + 1) % PMU_SAMPLE_BUFFER_SIZE;
+    }
+    buffer.len -= count;
+    count
+}
+
+/// Returns the number of samples of the CPU `cpu_id` that were dropped
+/// since the last call, as its buffer was full.
+pub fn pmu_take_lost_samples(cpu_id: usize) -> u64 {
+    SAMPLE_BUFFERS
+        .get(cpu_id)
+        .map_or(0, |buffer| core::mem::take(&mut buffer.lock().lost))
+}
+
+/// Stops sampling with the counter `counter` on the current CPU, and
+/// releases the counter.
+pub fn pmu_stop_sampling(counter: u32) {
+    let _guard = kernel_guard_base::IrqSave::new();
+    let counters = unsafe { SAMPLING_COUNTERS.current_ref_mut_raw() };
+    if let Some(slot) = counters.get_mut(counter as usize) {
+        if slot.take().is_some() {
+            sbi::pmu_counter_stop(counter as usize, true).ok();
+        }
+    }
+    if counters.iter().all(Option::is_none) {
+        super::disable_irq_sources(super::IrqSources::COUNTER_OVERFLOW);
+    }
+}
+
+/// Handles a counter-overflow interrupt on the current CPU.
+///
+/// It must be called by the trap handler with interrupts disabled, for
+/// [`PMU_OVERFLOW_IRQ_NUM`](crate::platform::irq::PMU_OVERFLOW_IRQ_NUM).
+pub fn handle_pmu_overflow(tf: &mut TrapFrame) {
+    let overflowed: usize; // scountovf
+    unsafe { core::arch::asm!("csrr {}, 0xda0", out(reg) overflowed) };
+    let handler = *PMU_OVERFLOW_HANDLER.lock();
+    let counters = unsafe { SAMPLING_COUNTERS.current_ref_mut_raw() };
+    for (counter, slot) in counters.iter().enumerate() {
+        let Some(sampling) = slot else {
+            continue;
+        };
+        if overflowed & (1 << sampling.ovf_bit) == 0 {
+            continue;
+        }
+        sbi::pmu_counter_stop(counter, false).ok();
+        if let Some(f) = sampling.handler.or(handler) {
+            f(tf, counter as u32);
+        }
+        // Starting it again also clears the overflow flag.
+        sbi::pmu_counter_start(counter, Some(sampling.period.wrapping_neg())).ok();
+    }
+    unsafe { core::arch::asm!("csrc sip, {}", in(reg) LCOFI_BIT) };
+}
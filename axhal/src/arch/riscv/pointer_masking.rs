@@ -0,0 +1,99 @@
+//! User pointer masking (Ssnpm).
+//!
+//! With `senvcfg.PMM` set, the top `PMLEN` bits of the addresses used in
+//! U-mode are ignored, so that they can carry tags. The setting is per task,
+//! and is installed on context switch.
+//!
+//! The kernel accesses to user memory are not masked (that is Smnpm, under
+//! the control of the firmware): the user access helpers strip the tags with
+//! [`untagged_addr`] before checking and accessing the addresses.
+
+use axerrno::LinuxError;
+
+use super::TaskContext;
+
+/// CSR number of `senvcfg`.
+pub(super) const CSR_SENVCFG: usize = 0x10a;
+
+const SENVCFG_PMM_SHIFT: usize = 32;
+const SENVCFG_PMM_MASK: usize = 0b11 << SENVCFG_PMM_SHIFT;
+const PMM_PMLEN_7: usize = 0b10;
+const PMM_PMLEN_16: usize = 0b11;
+
+/// The number of masked bits of the task running on this CPU.
+#[percpu2::def_percpu]
+static POINTER_MASKING_BITS: u8 = 0;
+
+/// Returns whether all CPUs support Ssnpm.
+///
+/// It needs the device tree, i.e. must be called after `arch_init_early`.
+pub fn has_ssnpm() -> bool {
+    super::cpu_has(super::Feature::Ssnpm)
+}
+
+/// Returns whether all CPUs support Smnpm, i.e. whether the firmware can
+/// mask the S-mode addresses. The HAL does not need it.
+pub fn has_smnpm() -> bool {
+    super::cpu_has(super::Feature::Smnpm)
+}
+
+/// Sets the number of the top address bits that are ignored in U-mode for
+/// the task of `ctx`, 0 disables pointer masking.
+///
+/// Only 0, 7 and 16 bits are supported, [`LinuxError::EINVAL`] is returned
+/// for the others. Without Ssnpm it does nothing. It takes effect the next
+/// time the task is switched to.
+pub fn set_pointer_masking(ctx: &mut TaskContext, bits: u8) -> Result<(), LinuxError> {
+    if !matches!(bits, 0 | 7 | 16) {
+        return Err(LinuxError::EINVAL);
+    }
+    if has_ssnpm() {
+        ctx.pmlen = bits;
+    }
+    Ok(())
+}
+
+/// Returns whether `senvcfg.PMM` can be set on the current CPU.
+pub(super) fn probe_ssnpm() -> bool {
+    let senvcfg: usize;
+    let probed: usize;
+    unsafe {
+        core::arch::asm!("csrr {}, {csr}", out(reg) senvcfg, csr = const CSR_SENVCFG);
+        let pmm = (senvcfg & !SENVCFG_PMM_MASK) | (PMM_PMLEN_16 << SENVCFG_PMM_SHIFT);
+        core::arch::asm!("csrw {csr}, {}", in(reg) pmm, csr = const CSR_SENVCFG);
+        core::arch::asm!("csrr {}, {csr}", out(reg) probed, csr = const CSR_SENVCFG);
+        core::arch::asm!("csrw {csr}, {}", in(reg) senvcfg, csr = const CSR_SENVCFG);
+    }
+    // Either PMLEN may be the one implemented.
+    probed & SENVCFG_PMM_MASK != 0
+}
+
+/// Installs the pointer masking of the next task, on context switch.
+pub(super) fn switch_pointer_masking(bits: u8) {
+    if unsafe { POINTER_MASKING_BITS.read_current_raw() } == bits || !has_ssnpm() {
+        return;
+    }
+    let pmm = match bits {
+        7 => PMM_PMLEN_7,
+        16 => PMM_PMLEN_16,
+        _ => 0,
+    };
+    unsafe {
+        let senvcfg: usize;
+        core::arch::asm!("csrr {}, {csr}", out(reg) senvcfg, csr = const CSR_SENVCFG);
+        let senvcfg = (senvcfg & !SENVCFG_PMM_MASK) | (pmm << SENVCFG_PMM_SHIFT);
+        core::arch::asm!("csrw {csr}, {}", in(reg) senvcfg, csr = const CSR_SENVCFG);
+        POINTER_MASKING_BITS.write_current_raw(bits);
+    }
+}
+
+/// Returns the user address `addr` with the tag bits of the current task
+/// removed, i.e. the address that U-mode actually accesses.
+#[inline]
+pub fn untagged_addr(addr: usize) -> usize {
+    match POINTER_MASKING_BITS.read_current() as u32 {
+        0 => addr,
+        // The masked bits are replaced by the sign extension of the rest.
+        bits => ((addr << bits) as isize >> bits) as usize,
+    }
+}
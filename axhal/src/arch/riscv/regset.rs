@@ -0,0 +1,181 @@
+//! The register sets of a task, in the layouts of the ELF notes (and of
+//! `PTRACE_GETREGSET`): `NT_PRSTATUS`, `NT_PRFPREG` and `NT_RISCV_VECTOR`.
+//!
+//! They are read from and written to the trap frame of the task and its
+//! [`TaskContext`], for a stopped task (or the current one from its own trap
+//! handler, e.g. for a core dump). The FP and vector registers still live
+//! in a CPU are saved first, and the written ones are loaded on the next
+//! switch to the task.
+
+use axerrno::LinuxError;
+
+use super::misaligned::gprs;
+use super::{TaskContext, TrapFrame};
+
+/// The note type of the general registers.
+pub const NT_PRSTATUS: u32 = 1;
+/// The note type of the floating-point registers.
+pub const NT_PRFPREG: u32 = 2;
+/// The note type of the vector registers.
+pub const NT_RISCV_VECTOR: u32 = 0x901;
+
+/// The number of general registers of `NT_PRSTATUS`.
+pub const ELF_NGREG: usize = 32;
+
+/// The general registers (`struct user_regs_struct`): `pc`, then `x1` to
+/// `x31`.
+pub type ElfGregs = [usize; ELF_NGREG];
+
+/// The floating-point registers (`struct __riscv_d_ext_state`).
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ElfFpregs {
+    /// `f0` to `f31`.
+    pub f: [u64; 32],
+    /// `fcsr`.
+    pub fcsr: u32,
+}
+
+/// The header of the vector registers (`struct __riscv_v_regset_state`),
+/// followed by `v0` to `v31`, `vlenb` bytes each.
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ElfVregsHeader {
+    /// `vstart`.
+    pub vstart: usize,
+    /// `vl`.
+    pub vl: usize,
+    /// `vtype`.
+    pub vtype: usize,
+    /// `vcsr`.
+    pub vcsr: usize,
+    /// `vlenb`, the size of one register.
+    pub vlenb: usize,
+}
+
+/// Returns the general registers of the trap `tf`.
+pub fn get_gregs(tf: &TrapFrame) -> ElfGregs {
+    let mut tf = tf.clone();
+    let mut regs = [0; ELF_NGREG];
+    regs[0] = tf.sepc;
+    regs[1..].copy_from_slice(gprs(&mut tf));
+    regs
+}
+
+/// Sets the general registers of the trap `tf`, `sstatus` is kept.
+pub fn set_gregs(tf: &mut TrapFrame, regs: &ElfGregs) {
+    tf.sepc = regs[0];
+    gprs(tf).copy_from_slice(&regs[1..]);
+}
+
+/// Returns the floating-point registers of the task of `ctx`, all zeros if
+/// the FP registers are not switched (no `fp_simd`).
+#[allow(unused_variables)]
+pub fn get_fpregs(ctx: &mut TaskContext) -> ElfFpregs {
+    #[cfg(feature = "fp_simd")]
+    {
+        ctx.fp_state.sync();
+        ElfFpregs {
+            f: ctx.fp_state.regs,
+            fcsr: ctx.fp_state.fcsr as u32,
+        }
+    }
+    #[cfg(not(feature = "fp_simd"))]
+    ElfFpregs::default()
+}
+
+/// Sets the floating-point registers of the task of `ctx`, which must not
+/// be running.
+///
+/// Returns [`LinuxError::ENODEV`] if the FP registers are not switched (no
+/// `fp_simd`).
+#[allow(unused_variables)]
+pub fn set_fpregs(ctx: &mut TaskContext, regs: &ElfFpregs) -> Result<(), LinuxError> {
+    #[cfg(feature = "fp_simd")]
+    {
+        ctx.fp_state.sync();
+        ctx.fp_state.regs = regs.f;
+        ctx.fp_state.fcsr = regs.fcsr as usize;
+        ctx.fp_state.set_changed();
+        Ok(())
+    }
+    #[cfg(not(feature = "fp_simd"))]
+    Err(LinuxError::ENODEV)
+}
+
+/// Returns the size of the `NT_RISCV_VECTOR` set of the task of `ctx`, 0 if
+/// it has no vector registers.
+#[allow(unused_variables)]
+pub fn vregs_size(ctx: &mut TaskContext) -> usize {
+    #[cfg(feature = "fp_simd")]
+    if ctx.vector_state.data_mut().is_some() {
+        return core::mem::size_of::<ElfVregsHeader>() + super::vector_state_size();
+    }
+    0
+}
+
+/// Reads the vector registers of the task of `ctx` to `buf`, of
+/// [`vregs_size`] bytes.
+///
+/// Returns [`LinuxError::ENODEV`] if the task has no vector registers, or
+/// [`LinuxError::EINVAL`] if `buf` has the wrong size.
+pub fn get_vregs(ctx: &mut TaskContext, buf: &mut [u8]) -> Result<(), LinuxError> {
+    let size = vregs_size(ctx);
+    if size == 0 {
+        return Err(LinuxError::ENODEV);
+    }
+    if buf.len() != size {
+        return Err(LinuxError::EINVAL);
+    }
+    #[cfg(feature = "fp_simd")]
+    {
+        let state = &mut ctx.vector_state;
+        state.sync();
+        let header = ElfVregsHeader {
+            vstart: state.vstart,
+            vl: state.vl,
+            vtype: state.vtype,
+            vcsr: state.vcsr,
+            vlenb: super::vector_state_size() / 32,
+        };
+        let (head, data) = buf.split_at_mut(core::mem::size_of::<ElfVregsHeader>());
+        head.copy_from_slice(unsafe {
+            core::slice::from_raw_parts(&header as *const _ as *const u8, head.len())
+        });
+        data.copy_from_slice(state.data_mut().unwrap_or_default());
+    }
+    Ok(())
+}
+
+/// Writes the vector registers of the task of `ctx`, which must not be
+/// running, from `buf` of [`vregs_size`] bytes.
+///
+/// Returns [`LinuxError::ENODEV`] if the task has no vector registers, or
+/// [`LinuxError::EINVAL`] if `buf` has the wrong size or another `vlenb`.
+pub fn set_vregs(ctx: &mut TaskContext, buf: &[u8]) -> Result<(), LinuxError> {
+    let size = vregs_size(ctx);
+    if size == 0 {
+        return Err(LinuxError::ENODEV);
+    }
+    if buf.len() != size {
+        return Err(LinuxError::EINVAL);
+    }
+    #[cfg(feature = "fp_simd")]
+    {
+        let (head, data) = buf.split_at(core::mem::size_of::<ElfVregsHeader>());
+        let header: ElfVregsHeader =
+            unsafe { core::ptr::read_unaligned(head.as_ptr() as *const ElfVregsHeader) };
+        if header.vlenb != super::vector_state_size() / 32 {
+            return Err(LinuxError::EINVAL);
+        }
+        let state = &mut ctx.vector_state;
+        state.sync();
+        state.vstart = header.vstart;
+        state.vl = header.vl;
+        state.vtype = header.vtype;
+        state.vcsr = header.vcsr;
+        state.data_mut().unwrap_or_default().copy_from_slice(data);
+        state.set_changed();
+    }
+    Ok(())
+}
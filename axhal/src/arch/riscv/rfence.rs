@@ -0,0 +1,95 @@
+//! Remote fences on other harts, with the SBI RFENCE extension.
+//!
+//! The firmware runs the fence on the target harts (in hardware on some
+//! platforms), without interrupting their kernel code, and returns once it
+//! is done everywhere. The TLB shootdowns and the instruction cache flushes
+//! use it when the firmware has it, and fall back to IPIs otherwise.
+
+use axerrno::LinuxError;
+use core::sync::atomic::{AtomicU8, Ordering};
+use memory_addr::VirtAddr;
+
+use super::sbi;
+use crate::cpu::{cpu_to_hartid, CpuMask};
+
+/// Returns whether the firmware has the SBI RFENCE extension.
+pub fn has_rfence() -> bool {
+    // 0: unknown, 1: not supported, 2: supported
+    static RFENCE: AtomicU8 = AtomicU8::new(0);
+    match RFENCE.load(Ordering::Relaxed) {
+        0 => {
+            let supported = sbi::probe_extension(sbi::EID_RFENCE);
+            RFENCE.store(if supported { 2 } else { 1 }, Ordering::Relaxed);
+            supported
+        }
+        state => state == 2,
+    }
+}
+
+/// Calls the SBI function `f` with the hart masks that cover the CPUs of
+/// `cpus`, and returns its first error.
+fn for_each_hart_mask(
+    mut cpus: CpuMask,
+    mut f: impl FnMut(usize, usize) -> Result<(), LinuxError>,
+) -> Result<(), LinuxError> {
+    let mut result = Ok(());
+    // The hart mask covers `usize::BITS` harts from the base, more harts
+    // need more calls.
+    while let Some(base) = cpus.iter().filter_map(cpu_to_hartid).min() {
+        let mut hart_mask = 0;
+        let pending = cpus;
+        for cpu_id in pending.iter() {
+            match cpu_to_hartid(cpu_id) {
+                Some(hartid) if hartid - base < usize::BITS as usize => {
+                    hart_mask |= 1 << (hartid - base);
+                    cpus.remove(cpu_id);
+                }
+                Some(_) => {}
+                None => cpus.remove(cpu_id),
+            }
+        }
+        result = result.and(f(hart_mask, base));
+    }
+    result
+}
+
+/// Flushes the TLB entries of `[start, start + size)` (all of them for a
+/// `size` of `usize::MAX`) on the CPUs of `cpus`.
+///
+/// Returns [`LinuxError::ENOTSUP`] without the RFENCE extension, or the
+/// error of the first failed call.
+pub fn remote_sfence_vma(cpus: CpuMask, start: VirtAddr, size: usize) -> Result<(), LinuxError> {
+    if !has_rfence() {
+        return Err(LinuxError::ENOTSUP);
+    }
+    for_each_hart_mask(cpus, |hart_mask, base| {
+        sbi::remote_sfence_vma(hart_mask, base, start.as_usize(), size)
+    })
+}
+
+/// As [`remote_sfence_vma`], for the (non-global) TLB entries of `asid`
+/// only.
+pub fn remote_sfence_vma_asid(
+    cpus: CpuMask,
+    start: VirtAddr,
+    size: usize,
+    asid: usize,
+) -> Result<(), LinuxError> {
+    if !has_rfence() {
+        return Err(LinuxError::ENOTSUP);
+    }
+    for_each_hart_mask(cpus, |hart_mask, base| {
+        sbi::remote_sfence_vma_asid(hart_mask, base, start.as_usize(), size, asid)
+    })
+}
+
+/// Makes the CPUs of `cpus` run `fence.i`.
+///
+/// Returns [`LinuxError::ENOTSUP`] without the RFENCE extension, or the
+/// error of the first failed call.
+pub fn remote_fence_i(cpus: CpuMask) -> Result<(), LinuxError> {
+    if !has_rfence() {
+        return Err(LinuxError::ENOTSUP);
+    }
+    for_each_hart_mask(cpus, sbi::remote_fence_i)
+}
@@ -0,0 +1,589 @@
+//! Typed calls of the SBI (the RISC-V Supervisor Binary Interface).
+//!
+//! All the standard extensions are covered: base, TIME, IPI, RFENCE, HSM,
+//! SRST, PMU, DBCN, SUSP, CPPC and DBTR. The SBI error codes are translated
+//! to [`LinuxError`] (see [`sbi_error`]). [`probe_extension`] tells which
+//! extensions the firmware provides; a call of a missing one fails with
+//! [`LinuxError::ENOTSUP`].
+//!
+//! The harts are given as a `hart_mask` of `usize::BITS` harts from
+//! `hart_mask_base`, or all of them with a `hart_mask_base` of
+//! [`HART_MASK_BASE_ALL`].
+
+use axerrno::LinuxError;
+
+/// Base extension.
+pub const EID_BASE: usize = 0x10;
+/// Timer extension.
+pub const EID_TIME: usize = 0x5449_4d45;
+/// IPI extension.
+pub const EID_IPI: usize = 0x0073_5049;
+/// Remote fence extension.
+pub const EID_RFENCE: usize = 0x5246_4e43;
+/// Hart state management extension.
+pub const EID_HSM: usize = 0x0048_534d;
+/// System reset extension.
+pub const EID_SRST: usize = 0x5352_5354;
+/// Performance monitoring unit extension.
+pub const EID_PMU: usize = 0x0050_4d55;
+/// Debug console extension.
+pub const EID_DBCN: usize = 0x4442_434e;
+/// System suspend extension.
+pub const EID_SUSP: usize = 0x5355_5350;
+/// Collaborative processor performance control extension.
+pub const EID_CPPC: usize = 0x4350_5043;
+/// Debug triggers extension.
+pub const EID_DBTR: usize = 0x4442_5452;
+
+/// The extensions probed by [`extensions`], with their names.
+pub const EXTENSIONS: [(usize, &str); 10] = [
+    (EID_TIME, "TIME"),
+    (EID_IPI, "IPI"),
+    (EID_RFENCE, "RFENCE"),
+    (EID_HSM, "HSM"),
+    (EID_SRST, "SRST"),
+    (EID_PMU, "PMU"),
+    (EID_DBCN, "DBCN"),
+    (EID_SUSP, "SUSP"),
+    (EID_CPPC, "CPPC"),
+    (EID_DBTR, "DBTR"),
+];
+
+const BASE_GET_SPEC_VERSION: usize = 0;
+const BASE_GET_IMPL_ID: usize = 1;
+const BASE_GET_IMPL_VERSION: usize = 2;
+const BASE_PROBE_EXTENSION: usize = 3;
+const BASE_GET_MVENDORID: usize = 4;
+const BASE_GET_MARCHID: usize = 5;
+const BASE_GET_MIMPID: usize = 6;
+
+const TIME_SET_TIMER: usize = 0;
+
+const IPI_SEND_IPI: usize = 0;
+
+const RFENCE_FENCE_I: usize = 0;
+const RFENCE_SFENCE_VMA: usize = 1;
+const RFENCE_SFENCE_VMA_ASID: usize = 2;
+const RFENCE_HFENCE_GVMA_VMID: usize = 3;
+const RFENCE_HFENCE_GVMA: usize = 4;
+const RFENCE_HFENCE_VVMA_ASID: usize = 5;
+const RFENCE_HFENCE_VVMA: usize = 6;
+
+const HSM_HART_START: usize = 0;
+const HSM_HART_STOP: usize = 1;
+const HSM_HART_GET_STATUS: usize = 2;
+const HSM_HART_SUSPEND: usize = 3;
+
+/// The state of a stopped hart, returned by [`hart_status`].
+pub const HSM_STATUS_STOPPED: usize = 1;
+
+/// Default retentive suspend type of `sbi_hart_suspend`.
+const HSM_SUSPEND_RETENTIVE: usize = 0;
+
+/// The bit of the non-retentive suspend types of `sbi_hart_suspend`.
+pub const HSM_SUSPEND_NON_RETENTIVE: u32 = 1 << 31;
+
+const SRST_SYSTEM_RESET: usize = 0;
+
+const PMU_NUM_COUNTERS: usize = 0;
+const PMU_COUNTER_GET_INFO: usize = 1;
+const PMU_COUNTER_CFG_MATCH: usize = 2;
+const PMU_COUNTER_START: usize = 3;
+const PMU_COUNTER_STOP: usize = 4;
+const PMU_COUNTER_FW_READ: usize = 5;
+
+const DBCN_CONSOLE_WRITE: usize = 0;
+const DBCN_CONSOLE_READ: usize = 1;
+const DBCN_CONSOLE_WRITE_BYTE: usize = 2;
+
+const SUSP_SYSTEM_SUSPEND: usize = 0;
+
+/// The suspend-to-RAM sleep type of `sbi_system_suspend`.
+const SUSP_SLEEP_TYPE_SUSPEND_TO_RAM: usize = 0;
+
+const CPPC_PROBE: usize = 0;
+const CPPC_READ: usize = 1;
+const CPPC_READ_HI: usize = 2;
+const CPPC_WRITE: usize = 3;
+
+const DBTR_NUM_TRIGGERS: usize = 0;
+const DBTR_SET_SHMEM: usize = 1;
+const DBTR_INSTALL_TRIGGERS: usize = 3;
+const DBTR_UNINSTALL_TRIGGERS: usize = 5;
+
+/// `config_flags` of `sbi_pmu_counter_config_matching`: clear the counter.
+pub const PMU_CFG_FLAG_CLEAR_VALUE: usize = 1 << 1;
+/// `config_flags` of `sbi_pmu_counter_config_matching`: start the counter.
+pub const PMU_CFG_FLAG_AUTO_START: usize = 1 << 2;
+/// `config_flags` of `sbi_pmu_counter_config_matching`: do not count in
+/// M-mode.
+pub const PMU_CFG_FLAG_SET_MINH: usize = 1 << 7;
+/// `start_flags` of `sbi_pmu_counter_start`: set the initial value.
+const PMU_START_SET_INIT_VALUE: usize = 1 << 0;
+/// `stop_flags` of `sbi_pmu_counter_stop`: release the counter.
+const PMU_STOP_FLAG_RESET: usize = 1 << 0;
+
+/// The `hart_mask_base` of all the harts.
+pub const HART_MASK_BASE_ALL: usize = usize::MAX;
+
+/// The CPPC register of the highest performance level.
+pub const CPPC_HIGHEST_PERF: usize = 0x00;
+/// The CPPC register of the nominal performance level.
+pub const CPPC_NOMINAL_PERF: usize = 0x01;
+/// The CPPC register of the lowest performance level.
+pub const CPPC_LOWEST_PERF: usize = 0x03;
+/// The CPPC register of the desired performance level.
+pub const CPPC_DESIRED_PERF: usize = 0x05;
+/// The CPPC reference performance counter.
+pub const CPPC_REFERENCE_CTR: usize = 0x0b;
+/// The CPPC delivered performance counter.
+pub const CPPC_DELIVERED_CTR: usize = 0x0c;
+
+/// SBI implementation IDs of the hypervisors.
+const IMPL_ID_XVISOR: usize = 2;
+const IMPL_ID_KVM: usize = 3;
+const IMPL_ID_XEN: usize = 7;
+
+/// The version of the SBI specification.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct SbiVersion {
+    /// The major number.
+    pub major: usize,
+    /// The minor number.
+    pub minor: usize,
+}
+
+/// The type of [`system_reset`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ResetType {
+    /// Powers off the system.
+    Shutdown = 0,
+    /// Resets the whole system, as a power cycle.
+    ColdReboot = 1,
+    /// Resets the harts and some devices only.
+    WarmReboot = 2,
+}
+
+/// The reason of [`system_reset`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ResetReason {
+    /// No particular reason.
+    NoReason = 0,
+    /// A failure of the system.
+    SystemFailure = 1,
+}
+
+/// Does an SBI call, returns `(error, value)`.
+#[inline(always)]
+pub fn sbi_call(eid: usize, fid: usize, arg0: usize, arg1: usize, arg2: usize) -> (isize, usize) {
+    sbi_call5(eid, fid, arg0, arg1, arg2, 0, 0)
+}
+
+/// Does an SBI call with up to 5 arguments, returns `(error, value)`.
+#[inline(always)]
+pub fn sbi_call5(
+    eid: usize,
+    fid: usize,
+    arg0: usize,
+    arg1: usize,
+    arg2: usize,
+    arg3: usize,
+    arg4: usize,
+) -> (isize, usize) {
+    let (error, value);
+    unsafe {
+        core::arch::asm!(
+            "ecall",
+            inlateout("a0") arg0 => error,
+            inlateout("a1") arg1 => value,
+            in("a2") arg2,
+            in("a3") arg3,
+            in("a4") arg4,
+            in("a6") fid,
+            in("a7") eid,
+        );
+    }
+    (error, value)
+}
+
+/// Translates the SBI error code `error` (not 0).
+pub const fn sbi_error(error: isize) -> LinuxError {
+    match error {
+        -2 => LinuxError::ENOTSUP,
+        -3 => LinuxError::EINVAL,
+        -4 => LinuxError::EPERM,
+        -5 => LinuxError::EFAULT,
+        // ALREADY_AVAILABLE, ALREADY_STARTED, ALREADY_STOPPED
+        -8..=-6 => LinuxError::EALREADY,
+        // NO_SHMEM
+        -9 => LinuxError::ENOMEM,
+        // INVALID_STATE
+        -10 => LinuxError::EBUSY,
+        -11 => LinuxError::ERANGE,
+        -12 => LinuxError::ETIMEDOUT,
+        // FAILED, IO, and the unknown ones
+        _ => LinuxError::EIO,
+    }
+}
+
+/// Returns the value of an SBI call, or its translated error.
+pub const fn sbi_result((error, value): (isize, usize)) -> Result<usize, LinuxError> {
+    match error {
+        0 => Ok(value),
+        error => Err(sbi_error(error)),
+    }
+}
+
+fn call(
+    eid: usize,
+    fid: usize,
+    arg0: usize,
+    arg1: usize,
+    arg2: usize,
+) -> Result<usize, LinuxError> {
+    sbi_result(sbi_call(eid, fid, arg0, arg1, arg2))
+}
+
+fn call5(eid: usize, fid: usize, args: [usize; 5]) -> Result<usize, LinuxError> {
+    let [arg0, arg1, arg2, arg3, arg4] = args;
+    sbi_result(sbi_call5(eid, fid, arg0, arg1, arg2, arg3, arg4))
+}
+
+/// Returns the version of the SBI specification of the firmware.
+pub fn spec_version() -> SbiVersion {
+    let version = sbi_call(EID_BASE, BASE_GET_SPEC_VERSION, 0, 0, 0).1;
+    SbiVersion {
+        major: (version >> 24) & 0x7f,
+        minor: version & 0xff_ffff,
+    }
+}
+
+/// Returns the SBI implementation ID.
+pub fn impl_id() -> usize {
+    sbi_call(EID_BASE, BASE_GET_IMPL_ID, 0, 0, 0).1
+}
+
+/// Returns the version of the SBI implementation, in its own encoding.
+pub fn impl_version() -> usize {
+    sbi_call(EID_BASE, BASE_GET_IMPL_VERSION, 0, 0, 0).1
+}
+
+/// Returns whether the SBI implementation provides the given extension.
+pub fn probe_extension(eid: usize) -> bool {
+    let (error, value) = sbi_call(EID_BASE, BASE_PROBE_EXTENSION, eid, 0, 0);
+    error == 0 && value != 0
+}
+
+/// Returns the names of the extensions of [`EXTENSIONS`] that the firmware
+/// provides.
+pub fn extensions() -> impl Iterator<Item = &'static str> {
+    EXTENSIONS
+        .into_iter()
+        .filter(|&(eid, _)| probe_extension(eid))
+        .map(|(_, name)| name)
+}
+
+/// Returns the `mvendorid` of the current hart.
+pub fn mvendorid() -> usize {
+    sbi_call(EID_BASE, BASE_GET_MVENDORID, 0, 0, 0).1
+}
+
+/// Returns the `marchid` of the current hart.
+pub fn marchid() -> usize {
+    sbi_call(EID_BASE, BASE_GET_MARCHID, 0, 0, 0).1
+}
+
+/// Returns the `mimpid` of the current hart.
+pub fn mimpid() -> usize {
+    sbi_call(EID_BASE, BASE_GET_MIMPID, 0, 0, 0).1
+}
+
+/// Returns whether the SBI implementation is a hypervisor, i.e., we are
+/// running in a virtual machine.
+pub fn impl_is_hypervisor() -> bool {
+    matches!(impl_id(), IMPL_ID_XVISOR | IMPL_ID_KVM | IMPL_ID_XEN)
+}
+
+/// Programs the timer of the current hart for the time `stime_value` (in
+/// ticks), and clears its pending timer interrupt.
+pub fn set_timer(stime_value: u64) -> Result<(), LinuxError> {
+    call(EID_TIME, TIME_SET_TIMER, stime_value as usize, 0, 0).map(drop)
+}
+
+/// Sends a software interrupt to the harts of `hart_mask`.
+pub fn send_ipi(hart_mask: usize, hart_mask_base: usize) -> Result<(), LinuxError> {
+    call(EID_IPI, IPI_SEND_IPI, hart_mask, hart_mask_base, 0).map(drop)
+}
+
+/// Makes the harts of `hart_mask` run `fence.i`.
+pub fn remote_fence_i(hart_mask: usize, hart_mask_base: usize) -> Result<(), LinuxError> {
+    call(EID_RFENCE, RFENCE_FENCE_I, hart_mask, hart_mask_base, 0).map(drop)
+}
+
+/// Makes the harts of `hart_mask` run `sfence.vma` on `[start, start +
+/// size)` (all the addresses for a `size` of `usize::MAX`).
+pub fn remote_sfence_vma(
+    hart_mask: usize,
+    hart_mask_base: usize,
+    start: usize,
+    size: usize,
+) -> Result<(), LinuxError> {
+    let args = [hart_mask, hart_mask_base, start, size, 0];
+    call5(EID_RFENCE, RFENCE_SFENCE_VMA, args).map(drop)
+}
+
+/// As [`remote_sfence_vma`], for the address space `asid` only.
+pub fn remote_sfence_vma_asid(
+    hart_mask: usize,
+    hart_mask_base: usize,
+    start: usize,
+    size: usize,
+    asid: usize,
+) -> Result<(), LinuxError> {
+    let args = [hart_mask, hart_mask_base, start, size, asid];
+    call5(EID_RFENCE, RFENCE_SFENCE_VMA_ASID, args).map(drop)
+}
+
+/// Makes the harts of `hart_mask` run `hfence.gvma` on the guest physical
+/// `[start, start + size)`, for the guest `vmid`.
+pub fn remote_hfence_gvma_vmid(
+    hart_mask: usize,
+    hart_mask_base: usize,
+    start: usize,
+    size: usize,
+    vmid: usize,
+) -> Result<(), LinuxError> {
+    let args = [hart_mask, hart_mask_base, start, size, vmid];
+    call5(EID_RFENCE, RFENCE_HFENCE_GVMA_VMID, args).map(drop)
+}
+
+/// As [`remote_hfence_gvma_vmid`], for all the guests.
+pub fn remote_hfence_gvma(
+    hart_mask: usize,
+    hart_mask_base: usize,
+    start: usize,
+    size: usize,
+) -> Result<(), LinuxError> {
+    let args = [hart_mask, hart_mask_base, start, size, 0];
+    call5(EID_RFENCE, RFENCE_HFENCE_GVMA, args).map(drop)
+}
+
+/// Makes the harts of `hart_mask` run `hfence.vvma` on the guest virtual
+/// `[start, start + size)`, for the address space `asid` of the current
+/// guest of each hart.
+pub fn remote_hfence_vvma_asid(
+    hart_mask: usize,
+    hart_mask_base: usize,
+    start: usize,
+    size: usize,
+    asid: usize,
+) -> Result<(), LinuxError> {
+    let args = [hart_mask, hart_mask_base, start, size, asid];
+    call5(EID_RFENCE, RFENCE_HFENCE_VVMA_ASID, args).map(drop)
+}
+
+/// As [`remote_hfence_vvma_asid`], for all the address spaces.
+pub fn remote_hfence_vvma(
+    hart_mask: usize,
+    hart_mask_base: usize,
+    start: usize,
+    size: usize,
+) -> Result<(), LinuxError> {
+    let args = [hart_mask, hart_mask_base, start, size, 0];
+    call5(EID_RFENCE, RFENCE_HFENCE_VVMA, args).map(drop)
+}
+
+/// Starts the stopped hart `hartid` at the physical address `start_addr`,
+/// in S-mode with paging off (with `a0` its hart ID and `a1` `opaque`).
+///
+/// Returns [`LinuxError::EALREADY`] if the hart is already started.
+pub fn hart_start(hartid: usize, start_addr: usize, opaque: usize) -> Result<(), LinuxError> {
+    call(EID_HSM, HSM_HART_START, hartid, start_addr, opaque).map(drop)
+}
+
+/// Stops the current hart, returning it to the firmware, which can start it
+/// again with [`hart_start`].
+///
+/// It only returns on failure (e.g. if the firmware does not support it),
+/// with the error.
+pub fn hart_stop() -> LinuxError {
+    match call(EID_HSM, HSM_HART_STOP, 0, 0, 0) {
+        Ok(_) => LinuxError::EIO,
+        Err(err) => err,
+    }
+}
+
+/// Returns the HSM state of the hart `hartid`, e.g. [`HSM_STATUS_STOPPED`].
+pub fn hart_status(hartid: usize) -> Result<usize, LinuxError> {
+    call(EID_HSM, HSM_HART_GET_STATUS, hartid, 0, 0)
+}
+
+/// Suspends the current hart in the default retentive state, until an
+/// interrupt enabled in `sie` is pending.
+pub fn hart_suspend_retentive() -> Result<(), LinuxError> {
+    hart_suspend(HSM_SUSPEND_RETENTIVE as u32, 0, 0)
+}
+
+/// Suspends the current hart in the state `suspend_type`, until an interrupt
+/// enabled in `sie` is pending. In a non-retentive state, it resumes at the
+/// physical address `resume_addr` (with `a0` its hart ID and `a1` `opaque`).
+///
+/// Returns once resumed from a retentive state, or on failure.
+pub fn hart_suspend(
+    suspend_type: u32,
+    resume_addr: usize,
+    opaque: usize,
+) -> Result<(), LinuxError> {
+    let suspend_type = suspend_type as usize;
+    call(EID_HSM, HSM_HART_SUSPEND, suspend_type, resume_addr, opaque).map(drop)
+}
+
+/// Resets the system, e.g. powers it off.
+///
+/// It only returns on failure, with the error.
+pub fn system_reset(reset_type: ResetType, reason: ResetReason) -> LinuxError {
+    let (reset_type, reason) = (reset_type as usize, reason as usize);
+    match call(EID_SRST, SRST_SYSTEM_RESET, reset_type, reason, 0) {
+        Ok(_) => LinuxError::EIO,
+        Err(err) => err,
+    }
+}
+
+/// Returns the number of PMU counters.
+pub fn pmu_num_counters() -> Result<usize, LinuxError> {
+    call(EID_PMU, PMU_NUM_COUNTERS, 0, 0, 0)
+}
+
+/// Returns the `counter_info` of the counter `counter`: the CSR number in
+/// bits 11:0, the width minus one in bits 17:12, and the top bit set for a
+/// firmware counter.
+pub fn pmu_counter_info(counter: usize) -> Result<usize, LinuxError> {
+    call(EID_PMU, PMU_COUNTER_GET_INFO, counter, 0, 0)
+}
+
+/// Returns the CSR number of the hardware counter `counter`, or [`None`] if
+/// it does not exist or it is a firmware counter.
+pub fn pmu_counter_csr(counter: usize) -> Option<usize> {
+    match pmu_counter_info(counter) {
+        Ok(info) if (info as isize) >= 0 => Some(info & 0xfff),
+        _ => None,
+    }
+}
+
+/// Returns the value of the firmware counter `counter`.
+pub fn pmu_counter_fw_read(counter: usize) -> Result<u64, LinuxError> {
+    call(EID_PMU, PMU_COUNTER_FW_READ, counter, 0, 0).map(|value| value as u64)
+}
+
+/// Finds a counter in `counter_mask` (relative to `counter_base`) that can
+/// count the event `event_idx`, and configures it.
+///
+/// Returns the counter index, or [`LinuxError::ENOTSUP`] if no counter
+/// matches.
+pub fn pmu_counter_config_matching(
+    counter_base: usize,
+    counter_mask: usize,
+    flags: usize,
+    event_idx: usize,
+    event_data: usize,
+) -> Result<usize, LinuxError> {
+    let args = [counter_base, counter_mask, flags, event_idx, event_data];
+    call5(EID_PMU, PMU_COUNTER_CFG_MATCH, args)
+}
+
+/// Starts the counter `counter`, optionally with an initial value.
+pub fn pmu_counter_start(counter: usize, initial_value: Option<u64>) -> Result<(), LinuxError> {
+    let (flags, value) = match initial_value {
+        Some(value) => (PMU_START_SET_INIT_VALUE, value as usize),
+        None => (0, 0),
+    };
+    call5(EID_PMU, PMU_COUNTER_START, [counter, 1, flags, value, 0]).map(drop)
+}
+
+/// Stops the counter `counter`, and releases it if `reset` is true.
+pub fn pmu_counter_stop(counter: usize, reset: bool) -> Result<(), LinuxError> {
+    let flags = if reset { PMU_STOP_FLAG_RESET } else { 0 };
+    call(EID_PMU, PMU_COUNTER_STOP, counter, 1, flags).map(drop)
+}
+
+/// Writes the `len` bytes at the physical address `paddr` to the debug
+/// console, and returns how many were written.
+pub fn console_write(paddr: usize, len: usize) -> Result<usize, LinuxError> {
+    // `base_addr_hi` is 0: the physical addresses fit in a `usize`.
+    call(EID_DBCN, DBCN_CONSOLE_WRITE, len, paddr, 0)
+}
+
+/// Reads at most `len` bytes from the debug console to the physical address
+/// `paddr`, without blocking, and returns how many were read.
+pub fn console_read(paddr: usize, len: usize) -> Result<usize, LinuxError> {
+    call(EID_DBCN, DBCN_CONSOLE_READ, len, paddr, 0)
+}
+
+/// Writes the byte `byte` to the debug console, blocking until it is
+/// written.
+pub fn console_write_byte(byte: u8) -> Result<(), LinuxError> {
+    call(EID_DBCN, DBCN_CONSOLE_WRITE_BYTE, byte as usize, 0, 0).map(drop)
+}
+
+/// Suspends the whole system to RAM, with the calling hart resuming at the
+/// physical address `resume_addr` (with `a0` its hart ID and `a1` `opaque`).
+///
+/// It only returns on failure, with the error.
+pub fn system_suspend(resume_addr: usize, opaque: usize) -> LinuxError {
+    let sleep_type = SUSP_SLEEP_TYPE_SUSPEND_TO_RAM;
+    match call(
+        EID_SUSP,
+        SUSP_SYSTEM_SUSPEND,
+        sleep_type,
+        resume_addr,
+        opaque,
+    ) {
+        Ok(_) => LinuxError::EIO,
+        Err(err) => err,
+    }
+}
+
+/// Returns the width in bits of the CPPC register `reg`, or 0 if it is not
+/// implemented.
+pub fn cppc_probe(reg: usize) -> Result<usize, LinuxError> {
+    call(EID_CPPC, CPPC_PROBE, reg, 0, 0)
+}
+
+/// Returns the value of the CPPC register `reg` of the current hart.
+pub fn cppc_read(reg: usize) -> Result<u64, LinuxError> {
+    let low = call(EID_CPPC, CPPC_READ, reg, 0, 0)?;
+    if usize::BITS == 64 {
+        return Ok(low as u64);
+    }
+    let high = call(EID_CPPC, CPPC_READ_HI, reg, 0, 0)?;
+    Ok((high as u64) << 32 | low as u64)
+}
+
+/// Writes `value` to the CPPC register `reg` of the current hart.
+pub fn cppc_write(reg: usize, value: u64) -> Result<(), LinuxError> {
+    let high = (value >> 32) as usize;
+    call(EID_CPPC, CPPC_WRITE, reg, value as usize, high).map(drop)
+}
+
+/// Returns the number of debug triggers that support the `tdata1` type and
+/// features of `tdata1`.
+pub fn dbtr_num_triggers(tdata1: usize) -> Result<usize, LinuxError> {
+    call(EID_DBTR, DBTR_NUM_TRIGGERS, tdata1, 0, 0)
+}
+
+/// Sets the shared memory of the debug triggers of the current hart, the
+/// physical address of an array of `[tstate/idx, tdata1, tdata2, tdata3]`.
+pub fn dbtr_set_shmem(paddr: usize) -> Result<(), LinuxError> {
+    call(EID_DBTR, DBTR_SET_SHMEM, paddr, 0, 0).map(drop)
+}
+
+/// Installs the `count` triggers described in the shared memory; their
+/// indexes are written back to the first word of the entries.
+pub fn dbtr_install_triggers(count: usize) -> Result<(), LinuxError> {
+    call(EID_DBTR, DBTR_INSTALL_TRIGGERS, count, 0, 0).map(drop)
+}
+
+/// Uninstalls the triggers of `mask` (relative to `base`).
+pub fn dbtr_uninstall_triggers(base: usize, mask: usize) -> Result<(), LinuxError> {
+    call(EID_DBTR, DBTR_UNINSTALL_TRIGGERS, base, mask, 0).map(drop)
+}
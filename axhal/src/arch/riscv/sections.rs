@@ -0,0 +1,123 @@
+//! The sections of the kernel image, and their protection (W^X).
+//!
+//! The boot page table maps the whole kernel image readable, writable and
+//! executable. Once the kernel is initialized, [`protect_kernel_sections`]
+//! remaps each section with only the permissions it needs, and unmaps the
+//! `.init` section (the code and data only used during initialization, in
+//! `.init.text` and `.init.data`).
+
+use axerrno::LinuxError;
+use core::ops::Range;
+use memory_addr::{PhysAddr, VirtAddr};
+
+use super::huge_page::{protect_region, unmap_region};
+use super::tlb::TlbBatch;
+use crate::mem::{virt_to_phys, MemRegionFlags, PAGE_SIZE_4K};
+
+extern "C" {
+    fn _stext();
+    fn _etext();
+    fn _srodata();
+    fn _erodata();
+    fn _sinit();
+    fn _einit();
+    fn _sdata();
+    fn _ekernel();
+}
+
+/// A section of the kernel image, between two linker script symbols.
+#[derive(Debug)]
+pub struct KernelSection {
+    /// The name of the section, e.g. `".text"`.
+    pub name: &'static str,
+    /// The virtual address range, 4K aligned.
+    pub range: Range<VirtAddr>,
+    /// The permissions of the section after [`protect_kernel_sections`].
+    pub flags: MemRegionFlags,
+}
+
+/// Returns the sections of the kernel image, in the address order.
+///
+/// `.data` covers everything writable up to `_ekernel`: `.data`, the TLS
+/// templates, the per-CPU areas, the boot stack, and `.bss`.
+pub fn kernel_sections() -> [KernelSection; 4] {
+    let range = |start: unsafe extern "C" fn(), end: unsafe extern "C" fn()| {
+        VirtAddr::from(start as usize)..VirtAddr::from(end as usize)
+    };
+    [
+        KernelSection {
+            name: ".text",
+            range: range(_stext, _etext),
+            flags: MemRegionFlags::READ | MemRegionFlags::EXECUTE,
+        },
+        KernelSection {
+            name: ".rodata",
+            range: range(_srodata, _erodata),
+            flags: MemRegionFlags::READ,
+        },
+        KernelSection {
+            name: ".init",
+            range: range(_sinit, _einit),
+            flags: MemRegionFlags::empty(),
+        },
+        KernelSection {
+            name: ".data",
+            range: range(_sdata, _ekernel),
+            flags: MemRegionFlags::READ | MemRegionFlags::WRITE,
+        },
+    ]
+}
+
+/// Remaps `.text` read-only and executable, `.rodata` read-only, and the
+/// writable sections non-executable, then unmaps `.init`, in the current
+/// page table. Returns the physical range of `.init`, for the caller to
+/// free.
+///
+/// The superpages of the kernel image are split where the sections do not
+/// cover them entirely, with the tables allocated by `alloc_frame`. The TLB
+/// is flushed on all CPUs.
+///
+/// # Safety
+///
+/// It must be called once, after the initialization: nothing may run or
+/// use anything in `.init` any more, and nothing may write `.text` or
+/// `.rodata`.
+pub unsafe fn protect_kernel_sections(
+    alloc_frame: &mut impl FnMut() -> Option<PhysAddr>,
+) -> Result<Range<PhysAddr>, LinuxError> {
+    let root = super::read_page_table_root();
+    let sections = kernel_sections();
+    for section in &sections {
+        let start = section.range.start;
+        let size = section.range.end.as_usize() - start.as_usize();
+        if size == 0 {
+            continue;
+        }
+        if section.flags.is_empty() {
+            unmap_region(root, start, size, alloc_frame)?;
+        } else {
+            protect_region(
+                root,
+                start,
+                size,
+                MemRegionFlags::from_bits_truncate(section.flags.bits()),
+                alloc_frame,
+            )?;
+        }
+        info!(
+            "{:<8} [{:#x}, {:#x}) {:?}",
+            section.name,
+            start.as_usize(),
+            section.range.end.as_usize(),
+            section.flags
+        );
+    }
+    let (start, end) = (_stext as usize, _ekernel as usize);
+    let mut batch = TlbBatch::new();
+    for page in (start..end).step_by(PAGE_SIZE_4K) {
+        batch.queue(VirtAddr::from(page));
+    }
+    batch.flush();
+    let init = VirtAddr::from(_sinit as usize)..VirtAddr::from(_einit as usize);
+    Ok(virt_to_phys(init.start)..virt_to_phys(init.end))
+}
@@ -0,0 +1,300 @@
+//! Boot-time self-tests of the architecture primitives.
+//!
+//! They run before the logger is set up, so the results are printed to the
+//! early console directly.
+
+use core::fmt::{self, Write};
+use core::ptr::{read_volatile, write_volatile};
+use memory_addr::VirtAddr;
+use riscv::register::{satp, sip, sstatus};
+
+use super::sbi;
+use crate::mem::{phys_to_virt, virt_to_phys};
+use crate::time::{current_ticks, nanos_to_ticks, NANOS_PER_MILLIS};
+
+/// How long to wait for the timer and the IPI before giving up.
+const WAIT_TIMEOUT_NANOS: u64 = 100 * NANOS_PER_MILLIS;
+
+const PTE_V: usize = 1 << 0;
+const PTE_R: usize = 1 << 1;
+const PTE_W: usize = 1 << 2;
+const PTE_U: usize = 1 << 4;
+const PTE_A: usize = 1 << 6;
+const PTE_D: usize = 1 << 7;
+
+const MAGIC_A: usize = 0x5a5a_a5a5_0000_000a;
+const MAGIC_B: usize = 0x5a5a_a5a5_0000_000b;
+
+/// Rounds of the syscall frame benchmark.
+#[cfg(feature = "syscall-fast-path")]
+const BENCH_ITERS: usize = 1000;
+
+#[repr(C, align(4096))]
+struct Page([usize; 512]);
+
+/// Scratch pages: a level-1 and a level-0 table, two data pages, and an
+/// Sv48 root table.
+static mut SCRATCH: [Page; 5] = [
+    Page([0; 512]),
+    Page([0; 512]),
+    Page([0; 512]),
+    Page([0; 512]),
+    Page([0; 512]),
+];
+
+struct EarlyConsole;
+
+impl Write for EarlyConsole {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        crate::console::write_bytes(s.as_bytes());
+        Ok(())
+    }
+}
+
+fn report(name: &str, result: Result<(), &str>) -> bool {
+    let _ = match result {
+        Ok(()) => writeln!(EarlyConsole, "[self-test] {}: pass", name),
+        Err(reason) => writeln!(EarlyConsole, "[self-test] {}: FAIL ({})", name, reason),
+    };
+    result.is_ok()
+}
+
+fn check(cond: bool, reason: &'static str) -> Result<(), &'static str> {
+    if cond {
+        Ok(())
+    } else {
+        Err(reason)
+    }
+}
+
+fn paddr_of<T>(ptr: *const T) -> usize {
+    virt_to_phys(VirtAddr::from(ptr as usize)).as_usize()
+}
+
+const fn table_pte(paddr: usize) -> usize {
+    ((paddr >> 12) << 10) | PTE_V
+}
+
+const fn user_page_pte(paddr: usize) -> usize {
+    ((paddr >> 12) << 10) | PTE_V | PTE_R | PTE_W | PTE_U | PTE_A | PTE_D
+}
+
+/// A user page mapped through the scratch tables, at an unused slot of the
+/// current root page table.
+struct ScratchMapping {
+    root_pte: *mut usize,
+    leaf_pte: *mut usize,
+    vaddr: usize,
+    page_a: usize,
+    page_b: usize,
+}
+
+impl ScratchMapping {
+    unsafe fn new() -> Option<Self> {
+        let root = phys_to_virt(super::read_page_table_root()).as_mut_ptr() as *mut usize;
+        // Only the user half, so that `access_ok` accepts the address.
+        let index = (0..256).rev().find(|&i| read_volatile(root.add(i)) == 0)?;
+        let scratch = &mut *core::ptr::addr_of_mut!(SCRATCH);
+        scratch[3].0[0] = MAGIC_A;
+        scratch[2].0[0] = MAGIC_B;
+        scratch[1].0 = [0; 512];
+        scratch[0].0 = [0; 512];
+        scratch[0].0[0] = table_pte(paddr_of(&scratch[1]));
+        write_volatile(root.add(index), table_pte(paddr_of(&scratch[0])));
+        super::flush_tlb(None);
+        Some(Self {
+            root_pte: root.add(index),
+            leaf_pte: &mut scratch[1].0[0],
+            vaddr: index << 30,
+            page_a: paddr_of(&scratch[3]),
+            page_b: paddr_of(&scratch[2]),
+        })
+    }
+
+    /// Points the scratch page to `paddr`, without flushing the TLB.
+    unsafe fn remap(&self, paddr: usize) {
+        write_volatile(self.leaf_pte, user_page_pte(paddr));
+    }
+
+    unsafe fn read(&self) -> usize {
+        read_volatile(self.vaddr as *const usize)
+    }
+}
+
+impl Drop for ScratchMapping {
+    fn drop(&mut self) {
+        unsafe { write_volatile(self.root_pte, 0) };
+        super::flush_tlb(None);
+    }
+}
+
+/// A TLB flush after a mapping change produces the new translation.
+fn test_tlb_flush(map: &ScratchMapping) -> Result<(), &'static str> {
+    unsafe {
+        map.remap(map.page_a);
+        super::flush_tlb(Some(map.vaddr.into()));
+        check(map.read() == MAGIC_A, "first mapping is not seen")?;
+        map.remap(map.page_b);
+        super::flush_tlb(Some(map.vaddr.into()));
+        check(map.read() == MAGIC_B, "stale translation after flush")
+    }
+}
+
+/// Writing `satp` with another ASID, or with another mode translating the
+/// same addresses, fences the TLB, i.e. a stale entry is not used afterwards.
+fn test_satp_switch(map: &ScratchMapping) -> Result<(), &'static str> {
+    unsafe {
+        let root = super::read_page_table_root();
+        map.remap(map.page_a);
+        super::flush_tlb(Some(map.vaddr.into()));
+        check(map.read() == MAGIC_A, "first mapping is not seen")?;
+        // Only the ASID changes, the root stays the same. The entry of page A
+        // may be cached under both ASIDs then, do not flush it by hand.
+        super::write_satp(satp::Mode::Sv39, 1, root);
+        let first = map.read();
+        map.remap(map.page_b);
+        super::write_satp(satp::Mode::Sv39, 0, root);
+        let back = map.read();
+        super::write_satp(satp::Mode::Sv39, 1, root);
+        let again = map.read();
+        super::write_satp(satp::Mode::Sv39, 0, root);
+        check(
+            first == MAGIC_A,
+            "first mapping is not seen with another ASID",
+        )?;
+        check(
+            back == MAGIC_B && again == MAGIC_B,
+            "stale translation after an ASID switch",
+        )?;
+
+        // An Sv48 root whose lowest and highest entries both point to the
+        // Sv39 root translates every Sv39 address the same way.
+        let sv48_root = &mut (*core::ptr::addr_of_mut!(SCRATCH))[4];
+        sv48_root.0 = [0; 512];
+        sv48_root.0[0] = table_pte(root.as_usize());
+        sv48_root.0[511] = table_pte(root.as_usize());
+        // The entry of page B may be cached now, do not flush it by hand.
+        map.remap(map.page_a);
+        super::write_satp(satp::Mode::Sv48, 0, paddr_of(sv48_root).into());
+        // `satp` ignores the write if the hart does not implement Sv48.
+        let switched = satp::read().mode() == satp::Mode::Sv48;
+        let value = map.read();
+        super::write_satp(satp::Mode::Sv39, 0, root);
+        check(
+            !switched || value == MAGIC_A,
+            "stale translation after a mode switch",
+        )
+    }
+}
+
+/// `access_ok` accepts the last user byte and rejects anything beyond.
+fn test_access_ok() -> Result<(), &'static str> {
+    use super::access_ok;
+    let task_size = super::task_size();
+    check(access_ok(0, task_size), "whole user space")?;
+    check(access_ok(task_size - 1, 1), "last user byte")?;
+    check(!access_ok(task_size, 1), "first kernel byte")?;
+    check(!access_ok(task_size - 4, 8), "range across the boundary")?;
+    check(!access_ok(usize::MAX, 2), "wrapping range")?;
+    check(!access_ok(0, task_size + 1), "oversized range")
+}
+
+/// A `put_user`/`get_user` round-trip on the scratch user page.
+fn test_user_access(map: &ScratchMapping) -> Result<(), &'static str> {
+    unsafe {
+        map.remap(map.page_a);
+        super::flush_tlb(Some(map.vaddr.into()));
+    }
+    let ptr = map.vaddr + 8;
+    check(super::fault_in_writeable(ptr, 1) == 0, "fault_in_writeable")?;
+    check(super::__put_user_asm(0xa5, ptr) == 0, "put_user")?;
+    check(
+        super::fault_in_readable_checked(ptr, 1).is_ok(),
+        "fault_in_readable",
+    )?;
+    let (x, err) = super::__get_user_asm(ptr);
+    check(err == 0, "get_user")?;
+    check(x == 0xa5, "get_user returns a wrong value")
+}
+
+/// Spins until `cond` holds, returns `false` on timeout.
+fn wait_until(cond: impl Fn() -> bool) -> bool {
+    let deadline = current_ticks() + nanos_to_ticks(WAIT_TIMEOUT_NANOS);
+    while !cond() {
+        if current_ticks() > deadline {
+            return false;
+        }
+        core::hint::spin_loop();
+    }
+    true
+}
+
+/// A one-shot timer fires (observed as pending, interrupts are disabled).
+fn test_timer() -> Result<(), &'static str> {
+    let deadline = current_ticks() + nanos_to_ticks(NANOS_PER_MILLIS);
+    check(sbi::set_timer(deadline).is_ok(), "sbi_set_timer")?;
+    let fired = wait_until(|| sip::read().stimer());
+    sbi::set_timer(u64::MAX).ok();
+    check(fired, "no timer interrupt")?;
+    check(!sip::read().stimer(), "timer interrupt is not cleared")
+}
+
+/// An IPI to self is received (observed as pending, interrupts are disabled).
+fn test_ipi_self() -> Result<(), &'static str> {
+    let hartid = crate::cpu::cpu_to_hartid(crate::cpu::_this_cpu_id()).ok_or("no hart ID")?;
+    check(sbi::send_ipi(1, hartid).is_ok(), "sbi_send_ipi")?;
+    let received = wait_until(|| sip::read().ssoft());
+    // `sip.SSIP`, writable from S-mode.
+    unsafe { core::arch::asm!("csrc sip, {}", in(reg) 1 << 1) };
+    check(received, "no software interrupt")
+}
+
+/// Runs the self-tests of the HAL primitives on this hardware, each of them
+/// reports pass/fail on the early console.
+///
+/// It is called late in [`early_init`](super::early_init) on the primary
+/// CPU, with interrupts disabled. The tests that need a page table are
+/// skipped if paging is not in Sv39 mode or there is no free slot in the
+/// root page table.
+///
+/// Returns whether all tests have passed.
+pub fn arch_self_test() -> bool {
+    let _guard = kernel_guard_base::IrqSave::new();
+    let mut ok = true;
+
+    ok &= report("access_ok boundary", test_access_ok());
+    if satp::read().mode() == satp::Mode::Sv39 {
+        let sum = sstatus::read().sum();
+        unsafe { sstatus::set_sum() };
+        match unsafe { ScratchMapping::new() } {
+            Some(map) => {
+                ok &= report("TLB flush after remap", test_tlb_flush(&map));
+                ok &= report("fence on satp ASID switch", test_satp_switch(&map));
+                ok &= report("get_user/put_user round-trip", test_user_access(&map));
+            }
+            None => ok &= report("page table tests", Err("no free root slot")),
+        }
+        if !sum {
+            unsafe { sstatus::clear_sum() };
+        }
+    } else {
+        let _ = writeln!(EarlyConsole, "[self-test] page table tests: skipped");
+    }
+    ok &= report("one-shot timer", test_timer());
+    ok &= report("IPI to self", test_ipi_self());
+    #[cfg(feature = "syscall-fast-path")]
+    {
+        let (fast, full) = super::bench_syscall_frame(BENCH_ITERS);
+        let _ = writeln!(
+            EarlyConsole,
+            "[self-test] syscall frame save/restore: {fast} cycles fast path, {full} full"
+        );
+    }
+
+    let _ = writeln!(
+        EarlyConsole,
+        "[self-test] {}",
+        if ok { "all passed" } else { "FAILED" }
+    );
+    ok
+}
@@ -0,0 +1,324 @@
+//! Signal frames, in the layout of the Linux RISC-V user ABI.
+//!
+//! [`setup_signal_frame`] pushes a `struct rt_sigframe` (the `siginfo_t` and
+//! the `ucontext_t` of the interrupted context) on the user stack, and
+//! redirects the trap frame to the handler. The vector registers follow the
+//! `sigcontext` as an extension, as Linux does. [`restore_signal_frame`]
+//! undoes it on `rt_sigreturn`.
+//!
+//! The handler returns to the restorer of the signal action
+//! (`SA_RESTORER`), or else to a `rt_sigreturn` trampoline written at the
+//! top of the frame, which needs an executable stack. The OS may give the
+//! one of the vDSO as the restorer instead, see
+//! [`VdsoImage::sigreturn_offset`](super::VdsoImage::sigreturn_offset).
+
+use axerrno::LinuxError;
+use core::mem::size_of;
+
+use super::misaligned::gprs;
+use super::{access_ok, copy_from_user, copy_to_user, TaskContext, TrapFrame};
+use crate::trap::user_stack_reserve;
+
+/// The size of a `siginfo_t`.
+pub const SIGINFO_SIZE: usize = 128;
+
+/// The magic of the vector extension context.
+const RISCV_V_MAGIC: u32 = 0x5346_5457;
+
+/// `ss_flags` of a disabled alternate signal stack.
+const SS_DISABLE: i32 = 2;
+
+/// `li a7, __NR_rt_sigreturn` and `ecall`.
+const SIGRETURN_CODE: [u32; 2] = [0x08b0_0893, 0x0000_0073];
+
+/// An alternate signal stack (`stack_t`), as saved in the frame.
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SignalStack {
+    /// The base of the stack (`ss_sp`).
+    pub sp: usize,
+    /// `SS_ONSTACK`, `SS_DISABLE` or 0 (`ss_flags`).
+    pub flags: i32,
+    /// The size of the stack (`ss_size`).
+    pub size: usize,
+}
+
+impl SignalStack {
+    /// Returns whether `sp` is on this stack, as Linux `on_sig_stack`.
+    fn contains(&self, sp: usize) -> bool {
+        self.flags & SS_DISABLE == 0 && sp > self.sp && sp - self.sp <= self.size
+    }
+}
+
+/// What the kernel gives for the delivery of a signal.
+#[derive(Debug, Clone, Copy)]
+pub struct SignalDelivery<'a> {
+    /// The `siginfo_t` of the signal.
+    pub info: &'a [u8; SIGINFO_SIZE],
+    /// The signal mask to restore on `rt_sigreturn`.
+    pub mask: u64,
+    /// The alternate signal stack of the task, saved in the frame.
+    pub stack: SignalStack,
+    /// The stack pointer below which the frame is pushed: the top of the
+    /// alternate stack, or the `sp` of the trap frame.
+    pub sp: usize,
+    /// The restorer of the signal action, 0 to use the trampoline.
+    pub restorer: usize,
+}
+
+/// What `rt_sigreturn` restores besides the registers.
+#[derive(Debug, Clone, Copy)]
+pub struct SignalReturn {
+    /// The signal mask saved in the frame.
+    pub mask: u64,
+    /// The alternate signal stack saved in the frame.
+    pub stack: SignalStack,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy, Default)]
+struct CtxHeader {
+    magic: u32,
+    size: u32,
+}
+
+/// `union __riscv_fp_state`, as the D state and the extension header.
+#[repr(C, align(16))]
+#[derive(Clone, Copy)]
+struct FpRegs {
+    f: [u64; 32],
+    fcsr: u32,
+    padding: [u32; 64],
+    reserved: u32,
+    hdr: CtxHeader,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct SigContext {
+    /// `pc`, then `x1` to `x31`.
+    regs: [usize; 32],
+    fpregs: FpRegs,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct UContext {
+    flags: usize,
+    link: usize,
+    stack: SignalStack,
+    sigmask: u64,
+    unused: [u8; 120],
+    mcontext: SigContext,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct RtSigFrame {
+    info: [u8; SIGINFO_SIZE],
+    uc: UContext,
+}
+
+/// `struct __riscv_v_ext_state`, followed by the registers.
+#[repr(C)]
+#[derive(Clone, Copy, Default)]
+struct VectorContext {
+    vstart: usize,
+    vl: usize,
+    vtype: usize,
+    vcsr: usize,
+    vlenb: usize,
+    datap: usize,
+}
+
+fn as_bytes<T>(value: &T) -> &[u8] {
+    unsafe { core::slice::from_raw_parts(value as *const T as *const u8, size_of::<T>()) }
+}
+
+fn as_bytes_mut<T>(value: &mut T) -> &mut [u8] {
+    unsafe { core::slice::from_raw_parts_mut(value as *mut T as *mut u8, size_of::<T>()) }
+}
+
+fn put_user<T>(dst: usize, value: &T) -> Result<(), LinuxError> {
+    match copy_to_user(dst, as_bytes(value)) {
+        0 => Ok(()),
+        _ => Err(LinuxError::EFAULT),
+    }
+}
+
+fn get_user<T>(value: &mut T, src: usize) -> Result<(), LinuxError> {
+    match copy_from_user(as_bytes_mut(value), src) {
+        0 => Ok(()),
+        _ => Err(LinuxError::EFAULT),
+    }
+}
+
+/// Returns the size of the vector registers saved in a frame, 0 if the task
+/// has none.
+#[allow(unused_variables)]
+fn vector_size(ctx: &mut TaskContext) -> usize {
+    #[cfg(feature = "fp_simd")]
+    if ctx.vector_state.data_mut().is_some() {
+        return super::vector_state_size();
+    }
+    0
+}
+
+/// Returns the address of a signal frame of `size` bytes below
+/// `delivery.sp`, as Linux `get_sigframe`.
+///
+/// `delivery.sp` comes from the user: the frame must be in user space, and
+/// must not overflow the alternate signal stack it is pushed on.
+fn get_sigframe(delivery: &SignalDelivery, size: usize) -> Result<usize, LinuxError> {
+    let addr = user_stack_reserve(delivery.sp, size).ok_or(LinuxError::EFAULT)?;
+    let stack = &delivery.stack;
+    if stack.contains(delivery.sp) && addr < stack.sp {
+        return Err(LinuxError::EFAULT);
+    }
+    if !access_ok(addr, delivery.sp - addr) {
+        return Err(LinuxError::EFAULT);
+    }
+    Ok(addr)
+}
+
+/// Pushes the signal frame of `signal` below `delivery.sp`, with the
+/// context of the trap `tf` and of the task `ctx`, and redirects `tf` to
+/// `handler(sig, &info, &ucontext)`.
+///
+/// Returns [`LinuxError::EFAULT`] if the frame does not fit below
+/// `delivery.sp` (it is out of user space or overflows the alternate signal
+/// stack) or cannot be written. `tf` is unchanged then, and the kernel should
+/// force `SIGSEGV` on the task, as Linux does.
+pub fn setup_signal_frame(
+    tf: &mut TrapFrame,
+    ctx: &mut TaskContext,
+    sig: usize,
+    handler: usize,
+    delivery: &SignalDelivery,
+) -> Result<(), LinuxError> {
+    let vsize = vector_size(ctx);
+    let ext_size = match vsize {
+        0 => 0,
+        _ => size_of::<VectorContext>() + vsize + size_of::<CtxHeader>(),
+    };
+    let frame_size = size_of::<RtSigFrame>() + ext_size + size_of::<[u32; 2]>();
+    let frame_addr = get_sigframe(delivery, frame_size)?;
+    let ext_addr = frame_addr + size_of::<RtSigFrame>();
+    let code_addr = ext_addr + ext_size;
+
+    let mut frame: RtSigFrame = unsafe { core::mem::zeroed() };
+    frame.info = *delivery.info;
+    frame.uc.stack = delivery.stack;
+    frame.uc.sigmask = delivery.mask;
+    let sc = &mut frame.uc.mcontext;
+    sc.regs[0] = tf.sepc;
+    sc.regs[1..].copy_from_slice(gprs(tf));
+    #[cfg(feature = "fp_simd")]
+    {
+        ctx.fp_state.sync();
+        sc.fpregs.f = ctx.fp_state.regs;
+        sc.fpregs.fcsr = ctx.fp_state.fcsr as u32;
+    }
+    if vsize != 0 {
+        sc.fpregs.hdr = CtxHeader {
+            magic: RISCV_V_MAGIC,
+            size: (size_of::<CtxHeader>() + size_of::<VectorContext>() + vsize) as u32,
+        };
+    }
+    put_user(frame_addr, &frame)?;
+
+    #[cfg(feature = "fp_simd")]
+    if vsize != 0 {
+        let state = &mut ctx.vector_state;
+        state.sync();
+        let vctx = VectorContext {
+            vstart: state.vstart,
+            vl: state.vl,
+            vtype: state.vtype,
+            vcsr: state.vcsr,
+            vlenb: vsize / 32,
+            datap: ext_addr + size_of::<VectorContext>(),
+        };
+        put_user(ext_addr, &vctx)?;
+        let data = state.data_mut().unwrap_or_default();
+        if copy_to_user(vctx.datap, data) != 0 {
+            return Err(LinuxError::EFAULT);
+        }
+        put_user(vctx.datap + vsize, &CtxHeader::default())?;
+    }
+
+    let restorer = match delivery.restorer {
+        0 => {
+            put_user(code_addr, &SIGRETURN_CODE)?;
+            // The task resumes on this CPU, which must fetch the new code.
+            super::local_flush_icache_all();
+            code_addr
+        }
+        restorer => restorer,
+    };
+    tf.regs.a0 = sig;
+    tf.regs.a1 = frame_addr;
+    tf.regs.a2 = frame_addr + SIGINFO_SIZE;
+    tf.regs.ra = restorer;
+    tf.regs.sp = frame_addr;
+    tf.sepc = handler;
+    Ok(())
+}
+
+/// Restores the context of the trap `tf` and of the task `ctx` from the
+/// signal frame at the `sp` of `tf`, on `rt_sigreturn`.
+///
+/// Only the registers are restored from the frame, `sstatus` is kept.
+/// Returns [`LinuxError::EFAULT`] if the frame cannot be read, or
+/// [`LinuxError::EINVAL`] if its extensions are not the ones of the task.
+pub fn restore_signal_frame(
+    tf: &mut TrapFrame,
+    ctx: &mut TaskContext,
+) -> Result<SignalReturn, LinuxError> {
+    let frame_addr = tf.regs.sp;
+    let mut frame: RtSigFrame = unsafe { core::mem::zeroed() };
+    get_user(&mut frame, frame_addr)?;
+    let sc = &frame.uc.mcontext;
+
+    let vsize = vector_size(ctx);
+    let hdr = sc.fpregs.hdr;
+    match hdr.magic {
+        0 => {}
+        RISCV_V_MAGIC
+            if vsize != 0
+                && hdr.size as usize
+                    == size_of::<CtxHeader>() + size_of::<VectorContext>() + vsize =>
+        {
+            #[cfg(feature = "fp_simd")]
+            {
+                let ext_addr = frame_addr + size_of::<RtSigFrame>();
+                let mut vctx = VectorContext::default();
+                get_user(&mut vctx, ext_addr)?;
+                let state = &mut ctx.vector_state;
+                let data = state.data_mut().unwrap_or_default();
+                if copy_from_user(data, vctx.datap) != 0 {
+                    return Err(LinuxError::EFAULT);
+                }
+                state.vstart = vctx.vstart;
+                state.vl = vctx.vl;
+                state.vtype = vctx.vtype;
+                state.vcsr = vctx.vcsr;
+                state.reload(tf);
+            }
+        }
+        _ => return Err(LinuxError::EINVAL),
+    }
+    #[cfg(feature = "fp_simd")]
+    {
+        ctx.fp_state.regs = sc.fpregs.f;
+        ctx.fp_state.fcsr = sc.fpregs.fcsr as usize;
+        ctx.fp_state.reload(tf);
+    }
+
+    tf.sepc = sc.regs[0];
+    gprs(tf).copy_from_slice(&sc.regs[1..]);
+    Ok(SignalReturn {
+        mask: frame.uc.sigmask,
+        stack: frame.uc.stack,
+    })
+}
@@ -0,0 +1,218 @@
+//! Software single-stepping, for `PTRACE_SINGLESTEP` and kernel debuggers.
+//!
+//! RISC-V has no single-step mode in S-mode, so [`set_single_step`] places
+//! temporary breakpoints (`c.ebreak`) at the possible next instructions: the
+//! next one, and the target of a jump or a branch (both ways, the condition
+//! is not evaluated). When one is hit, [`handle_single_step`] removes them
+//! all and leaves `sepc` at it, so the original instruction runs when the
+//! task resumes.
+//!
+//! The breakpoints are written in the text of the task: the user ones
+//! through [`copy_to_user`](super::copy_to_user), so its pages must be
+//! writable by the kernel (e.g. with the page fault handler breaking COW for
+//! the tracer), and the kernel ones with [`patch_text`](super::patch_text),
+//! as `.text` is read-only.
+
+use axerrno::LinuxError;
+use spinbase::SpinNoIrq;
+
+use super::misaligned::read_gpr;
+use super::TrapFrame;
+
+/// `c.ebreak`.
+const C_EBREAK: u16 = 0x9002;
+
+/// The maximum number of temporary breakpoints, 2 per stepping task.
+const MAX_STEP_BREAKPOINTS: usize = 32;
+
+#[derive(Clone, Copy)]
+struct StepBreakpoint {
+    /// The page table of the user address, 0 for the kernel ones.
+    root: usize,
+    addr: usize,
+    /// The original 16 bits at `addr`.
+    orig: u16,
+}
+
+static STEP_BREAKPOINTS: SpinNoIrq<[Option<StepBreakpoint>; MAX_STEP_BREAKPOINTS]> =
+    SpinNoIrq::new([None; MAX_STEP_BREAKPOINTS]);
+
+pub(super) fn sign_extend(value: u32, bits: u32) -> usize {
+    let shift = 32 - bits;
+    (((value << shift) as i32) >> shift) as isize as usize
+}
+
+/// The offset of `jal`.
+pub(super) fn jal_offset(insn: u32) -> usize {
+    let imm = ((insn >> 31) << 20)
+        | (((insn >> 21) & 0x3ff) << 1)
+        | (((insn >> 20) & 1) << 11)
+        | (((insn >> 12) & 0xff) << 12);
+    sign_extend(imm, 21)
+}
+
+/// The offset of a conditional branch.
+pub(super) fn branch_offset(insn: u32) -> usize {
+    let imm = ((insn >> 31) << 12)
+        | (((insn >> 25) & 0x3f) << 5)
+        | (((insn >> 8) & 0xf) << 1)
+        | (((insn >> 7) & 1) << 11);
+    sign_extend(imm, 13)
+}
+
+/// The offset of `c.j` and `c.jal`.
+pub(super) fn c_jump_offset(insn: u32) -> usize {
+    let imm = (((insn >> 12) & 1) << 11)
+        | (((insn >> 11) & 1) << 4)
+        | (((insn >> 9) & 0b11) << 8)
+        | (((insn >> 8) & 1) << 10)
+        | (((insn >> 7) & 1) << 6)
+        | (((insn >> 6) & 1) << 7)
+        | (((insn >> 3) & 0b111) << 1)
+        | (((insn >> 2) & 1) << 5);
+    sign_extend(imm, 12)
+}
+
+/// The offset of `c.beqz` and `c.bnez`.
+pub(super) fn c_branch_offset(insn: u32) -> usize {
+    let imm = (((insn >> 12) & 1) << 8)
+        | (((insn >> 10) & 0b11) << 3)
+        | (((insn >> 5) & 0b11) << 6)
+        | (((insn >> 3) & 0b11) << 1)
+        | (((insn >> 2) & 1) << 5);
+    sign_extend(imm, 9)
+}
+
+/// Returns the addresses where the instruction `insn` at `pc` may continue.
+fn next_pcs(tf: &mut TrapFrame, pc: usize, insn: u32) -> [Option<usize>; 2] {
+    if insn & 0b11 == 0b11 {
+        let next = Some(pc + 4);
+        let rs1 = ((insn >> 15) & 0x1f) as usize;
+        return match insn & 0x7f {
+            // jal
+            0x6f => [Some(pc.wrapping_add(jal_offset(insn))), None],
+            // jalr
+            0x67 => {
+                let target = read_gpr(tf, rs1).wrapping_add(sign_extend(insn >> 20, 12));
+                [Some(target & !1), None]
+            }
+            // beq, bne, blt, bge, bltu, bgeu
+            0x63 => [next, Some(pc.wrapping_add(branch_offset(insn)))],
+            _ => [next, None],
+        };
+    }
+    let next = Some(pc + 2);
+    let funct3 = (insn >> 13) & 0b111;
+    match (insn & 0b11, funct3) {
+        // c.j, and c.jal on RV32
+        (0b01, f) if f == 0b101 || (f == 0b001 && usize::BITS == 32) => {
+            [Some(pc.wrapping_add(c_jump_offset(insn))), None]
+        }
+        // c.beqz, c.bnez
+        (0b01, 0b110) | (0b01, 0b111) => [next, Some(pc.wrapping_add(c_branch_offset(insn)))],
+        // c.jr, c.jalr
+        (0b10, 0b100) if (insn >> 2) & 0x1f == 0 && (insn >> 7) & 0x1f != 0 => {
+            [Some(read_gpr(tf, ((insn >> 7) & 0x1f) as usize) & !1), None]
+        }
+        _ => [next, None],
+    }
+}
+
+fn read_u16(addr: usize, from_user: bool) -> Option<u16> {
+    if from_user {
+        let mut buf = [0; 2];
+        (super::copy_from_user(&mut buf, addr) == 0).then(|| u16::from_le_bytes(buf))
+    } else {
+        Some(unsafe { (addr as *const u16).read_volatile() })
+    }
+}
+
+fn write_u16(addr: usize, value: u16, from_user: bool) -> bool {
+    if from_user {
+        super::copy_to_user(addr, &value.to_le_bytes()) == 0
+    } else {
+        super::patch_text(addr, &value.to_le_bytes()).is_ok()
+    }
+}
+
+fn current_root(from_user: bool) -> usize {
+    if from_user {
+        super::read_page_table_root().as_usize()
+    } else {
+        0
+    }
+}
+
+/// Removes the temporary breakpoints of the task of the current page table
+/// (or of the kernel). Returns whether there were some.
+fn remove_step_breakpoints(from_user: bool) -> bool {
+    let root = current_root(from_user);
+    let mut removed = [None; 2];
+    let mut count = 0;
+    // The user memory is not accessed with the lock held, it may fault.
+    for slot in STEP_BREAKPOINTS.lock().iter_mut() {
+        if count < removed.len() && slot.is_some_and(|bp| bp.root == root) {
+            removed[count] = slot.take();
+            count += 1;
+        }
+    }
+    for bp in removed.iter().flatten() {
+        write_u16(bp.addr, bp.orig, from_user);
+    }
+    if count > 0 {
+        super::local_flush_icache_all();
+    }
+    count > 0
+}
+
+/// Enables or disables the single-stepping of the task of `tf`: with
+/// `enable`, it stops at a breakpoint exception after the next instruction.
+///
+/// It must be called with the page table of the task active. Returns
+/// [`LinuxError::EFAULT`] if the breakpoints cannot be written, or
+/// [`LinuxError::ENOSPC`] if too many tasks are stepping.
+pub fn set_single_step(tf: &mut TrapFrame, enable: bool) -> Result<(), LinuxError> {
+    let from_user = tf.from_user();
+    remove_step_breakpoints(from_user);
+    if !enable {
+        return Ok(());
+    }
+    let insn = super::fetch_instruction(tf.sepc, from_user).ok_or(LinuxError::EFAULT)?;
+    let root = current_root(from_user);
+    let [first, second] = next_pcs(tf, tf.sepc, insn);
+    let second = second.filter(|&addr| Some(addr) != first);
+    for addr in [first, second].into_iter().flatten() {
+        let orig = read_u16(addr, from_user).ok_or(LinuxError::EFAULT)?;
+        let bp = StepBreakpoint { root, addr, orig };
+        let recorded = match STEP_BREAKPOINTS
+            .lock()
+            .iter_mut()
+            .find(|slot| slot.is_none())
+        {
+            Some(slot) => *slot.insert(bp),
+            None => return Err(LinuxError::ENOSPC),
+        };
+        if !write_u16(recorded.addr, C_EBREAK, from_user) {
+            remove_step_breakpoints(from_user);
+            return Err(LinuxError::EFAULT);
+        }
+    }
+    super::local_flush_icache_all();
+    Ok(())
+}
+
+/// Handles a breakpoint exception at a temporary breakpoint of
+/// [`set_single_step`]: removes the breakpoints of the task, and returns
+/// `true`. `sepc` is left at the breakpoint, i.e. the next instruction.
+///
+/// [`handle_breakpoint`](super::handle_breakpoint) calls it first; a kernel
+/// debugger hooking the breakpoints calls it itself.
+pub fn handle_single_step(tf: &mut TrapFrame) -> bool {
+    let root = current_root(tf.from_user());
+    let hit = STEP_BREAKPOINTS
+        .lock()
+        .iter()
+        .flatten()
+        .any(|bp| bp.root == root && bp.addr == tf.sepc);
+    hit && remove_step_breakpoints(tf.from_user())
+}
@@ -0,0 +1,212 @@
+//! Guard pages below the kernel stacks, and stack overflow detection.
+//!
+//! A kernel stack is allocated with [`STACK_GUARD_SIZE`] more bytes below
+//! it, which [`install_stack_guard`] unmaps: an overflow then faults on the
+//! guard page instead of silently corrupting the memory below the stack.
+//!
+//! The fault itself cannot be handled on the overflowed stack, pushing the
+//! trap frame would fault again. So the kernel trap entry (`SAVE_REGS 0`)
+//! compares `sp` with the guard page of the current task, kept per CPU by
+//! [`TaskContext::switch_to`](super::TaskContext::switch_to), and pushes the
+//! frame on the overflow stack of the CPU if it would land in the guard
+//! page. The trap handler then calls [`check_kernel_stack_overflow`], which
+//! reports the overflow and panics.
+
+use axerrno::LinuxError;
+use core::ops::Range;
+use core::sync::atomic::{AtomicUsize, Ordering};
+use memory_addr::{PhysAddr, VirtAddr};
+
+use super::huge_page::{map_page, unmap_region, PageSize};
+use super::svpbmt::MemAttr;
+use super::tlb::TlbBatch;
+use super::TrapFrame;
+use crate::mem::{virt_to_phys, MemRegionFlags, PAGE_SIZE_4K};
+use crate::trap::TRAPFRAME_SIZE;
+
+/// The size of the guard page below a kernel stack.
+pub const STACK_GUARD_SIZE: usize = PAGE_SIZE_4K;
+
+/// The size of the stack of each CPU to handle a kernel stack overflow.
+const OVERFLOW_STACK_SIZE: usize = 4 * PAGE_SIZE_4K;
+
+#[repr(C, align(16))]
+struct OverflowStack([u8; OVERFLOW_STACK_SIZE]);
+
+const OVERFLOW_STACK_INIT: OverflowStack = OverflowStack([0; OVERFLOW_STACK_SIZE]);
+
+static mut OVERFLOW_STACKS: [OverflowStack; axconfig::SMP] = [OVERFLOW_STACK_INIT; axconfig::SMP];
+
+/// The stack bounds of the current CPU read by the trap entry, see the
+/// `SAVE_REGS` macro. The fields are in units of `XLENB` there.
+#[repr(C)]
+struct StackGuardPercpu {
+    /// The lowest `sp` at the trap entry whose trap frame overlaps the guard
+    /// page. 0 if the stack of the current task has no guard page.
+    check_lo: AtomicUsize,
+    /// The end of the guard page, i.e. the bottom of the kernel stack.
+    guard_end: AtomicUsize,
+    /// The top of the overflow stack of the CPU.
+    overflow_top: AtomicUsize,
+}
+
+/// Accessed through `gp` as the other per-CPU data (the `.percpu` section
+/// starts at 0, so its address is its offset in the per-CPU area).
+#[no_mangle]
+#[link_section = ".percpu"]
+static __PERCPU_KSTACK_GUARD: StackGuardPercpu = StackGuardPercpu {
+    check_lo: AtomicUsize::new(0),
+    guard_end: AtomicUsize::new(0),
+    overflow_top: AtomicUsize::new(0),
+};
+
+fn local_guard() -> &'static StackGuardPercpu {
+    let ptr: usize;
+    unsafe {
+        core::arch::asm!(
+            "lui {0}, %hi(__PERCPU_KSTACK_GUARD)",
+            "addi {0}, {0}, %lo(__PERCPU_KSTACK_GUARD)",
+            "add {0}, {0}, gp",
+            out(reg) ptr,
+        );
+        &*(ptr as *const StackGuardPercpu)
+    }
+}
+
+/// Sets the overflow stack of the current CPU. Called by the CPU
+/// initialization, right after setting its per-CPU area.
+pub(crate) fn init_stack_guard(cpu_id: usize) {
+    let stack = unsafe { core::ptr::addr_of!(OVERFLOW_STACKS[cpu_id]) } as usize;
+    local_guard()
+        .overflow_top
+        .store(stack + OVERFLOW_STACK_SIZE, Ordering::Relaxed);
+}
+
+/// Sets the kernel stack checked by the trap entry on this CPU: the one
+/// whose guard page ends at `stack_bottom`, or none if it is 0.
+pub(super) fn switch_stack_guard(stack_bottom: usize) {
+    let guard = local_guard();
+    // Disable the check while the bounds are inconsistent.
+    guard.check_lo.store(0, Ordering::Relaxed);
+    guard.guard_end.store(stack_bottom, Ordering::Relaxed);
+    if stack_bottom != 0 {
+        let check_lo = stack_bottom - STACK_GUARD_SIZE - TRAPFRAME_SIZE + 1;
+        guard.check_lo.store(check_lo, Ordering::Relaxed);
+    }
+}
+
+/// Returns the kernel stack checked by the trap entry on this CPU, as given
+/// to [`switch_stack_guard`].
+pub(super) fn current_stack_guard() -> usize {
+    local_guard().guard_end.load(Ordering::Relaxed)
+}
+
+/// Returns whether `addr` is on the overflow stack of a CPU.
+fn on_overflow_stack(addr: usize) -> bool {
+    let start = unsafe { core::ptr::addr_of!(OVERFLOW_STACKS) } as usize;
+    (start..start + OVERFLOW_STACK_SIZE * axconfig::SMP).contains(&addr)
+}
+
+/// Returns the bounds of the kernel stack of this CPU that contains `sp`:
+/// the overflow stack, an IRQ stack, the stack of the current task if it has
+/// a guard page, or at most [`STACK_SIZE`](super::STACK_SIZE) above `sp`
+/// otherwise.
+pub(super) fn kernel_stack_bounds(sp: usize) -> Range<usize> {
+    let guard = local_guard();
+    if on_overflow_stack(sp) {
+        let top = guard.overflow_top.load(Ordering::Relaxed);
+        return top - OVERFLOW_STACK_SIZE..top;
+    }
+    if let Some(stack) = super::irq_stack::irq_stack_bounds(sp) {
+        return stack;
+    }
+    let bottom = guard.guard_end.load(Ordering::Relaxed);
+    let stack = bottom..bottom + super::STACK_SIZE;
+    if bottom != 0 && stack.contains(&sp) {
+        return stack;
+    }
+    sp..sp.saturating_add(super::STACK_SIZE)
+}
+
+/// Unmaps the guard page below the kernel stack that starts at
+/// `stack_bottom`, i.e. `[stack_bottom - STACK_GUARD_SIZE, stack_bottom)`,
+/// in the current page table.
+///
+/// The superpage of the linear mapping that covers it is split, with the
+/// tables allocated by `alloc_frame`. The task of the stack must be given
+/// the same `stack_bottom` in [`TaskContext::kstack_bottom`](super::TaskContext::kstack_bottom),
+/// for the trap entry to detect the overflows.
+///
+/// # Safety
+///
+/// The guard page must be a part of the stack allocation in the linear
+/// mapping, that nothing else uses. The page tables that share the kernel
+/// mappings must share the split tables, i.e. not copy the root entries of
+/// the linear mapping before it.
+pub unsafe fn install_stack_guard(
+    stack_bottom: VirtAddr,
+    alloc_frame: &mut impl FnMut() -> Option<PhysAddr>,
+) -> Result<(), LinuxError> {
+    let guard = VirtAddr::from(stack_bottom.as_usize() - STACK_GUARD_SIZE);
+    unmap_region(
+        super::read_page_table_root(),
+        guard,
+        STACK_GUARD_SIZE,
+        alloc_frame,
+    )?;
+    let mut batch = TlbBatch::new();
+    batch.queue(guard);
+    batch.flush();
+    Ok(())
+}
+
+/// Maps back the guard page installed by [`install_stack_guard`], before
+/// the stack allocation is freed.
+///
+/// # Safety
+///
+/// The stack must not be in use any more.
+pub unsafe fn remove_stack_guard(
+    stack_bottom: VirtAddr,
+    alloc_frame: &mut impl FnMut() -> Option<PhysAddr>,
+) -> Result<(), LinuxError> {
+    let guard = VirtAddr::from(stack_bottom.as_usize() - STACK_GUARD_SIZE);
+    map_page(
+        super::read_page_table_root(),
+        guard,
+        virt_to_phys(guard),
+        PageSize::Size4K,
+        MemRegionFlags::READ | MemRegionFlags::WRITE,
+        MemAttr::Normal,
+        alloc_frame,
+    )
+}
+
+/// Reports a kernel stack overflow and panics, if the trap of `tf` is one.
+///
+/// The trap handler calls it first for the traps from S-mode, with `stval`
+/// as `fault_addr` for the page faults (0 otherwise). It is an overflow if
+/// the fault address is in the guard page of the current stack, or if the
+/// trap entry has switched to the overflow stack. Returns otherwise.
+pub fn check_kernel_stack_overflow(tf: &TrapFrame, fault_addr: usize) {
+    if tf.from_user() {
+        return;
+    }
+    let guard = local_guard();
+    let guard_end = guard.guard_end.load(Ordering::Relaxed);
+    let in_guard =
+        guard_end != 0 && (guard_end - STACK_GUARD_SIZE..guard_end).contains(&fault_addr);
+    if !in_guard && !on_overflow_stack(tf as *const _ as usize) {
+        return;
+    }
+    error!(
+        "kernel stack overflow: sepc {:#x}, sp {:#x}, fault address {:#x}, guard page [{:#x}, {:#x})",
+        tf.sepc,
+        tf.regs.sp,
+        fault_addr,
+        guard_end.saturating_sub(STACK_GUARD_SIZE),
+        guard_end
+    );
+    super::print_backtrace(Some(tf));
+    panic!("kernel stack overflow at {:#x}", tf.sepc);
+}
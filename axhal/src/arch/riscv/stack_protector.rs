@@ -0,0 +1,96 @@
+//! The stack protector (`-Z stack-protector`) support.
+//!
+//! The protected functions push a canary below their return address, and
+//! call [`__stack_chk_fail`] if it has changed when they return. The kernel
+//! should be built with the canary in a slot relative to the per-CPU base in
+//! `gp`, so that each task has its own one:
+//!
+//! ```text
+//! -Z stack-protector=strong
+//! -C llvm-args=-stack-protector-guard=tls
+//! -C llvm-args=-stack-protector-guard-reg=gp
+//! -C llvm-args=-stack-protector-guard-offset=0
+//! ```
+//!
+//! The slot is the per-CPU variable `__PERCPU_STACK_CANARY`, which holds the
+//! canary of the task running on the CPU: [`TaskContext::switch_to`]
+//! swaps it with the one of the next task. The linker script puts its
+//! section `.percpu.stack_canary` first and asserts that it is at
+//! [`STACK_CANARY_GP_OFFSET`] in the per-CPU area. Without the `llvm-args`,
+//! LLVM uses the single global `__stack_chk_guard`, which is only set once at
+//! boot.
+//!
+//! [`TaskContext::switch_to`]: super::TaskContext::switch_to
+
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+/// The offset of the canary of the current task from the per-CPU base in
+/// `gp`, i.e. the value of `-stack-protector-guard-offset`.
+pub const STACK_CANARY_GP_OFFSET: usize = 0;
+
+/// The `t0` value at the `ebreak` of [`__stack_chk_fail`].
+pub(super) const STACK_CHK_MAGIC: usize = 0x4853_414d_534b_5453; // "STKSMASH"
+
+#[no_mangle]
+#[link_section = ".percpu.stack_canary"]
+static __PERCPU_STACK_CANARY: AtomicUsize = AtomicUsize::new(0);
+
+/// The canary of the global guard mode (LLVM's default).
+#[no_mangle]
+static mut __stack_chk_guard: usize = 0x595e_9fbd_94fd_a766;
+
+fn local_canary() -> &'static AtomicUsize {
+    let ptr: usize;
+    unsafe {
+        core::arch::asm!(
+            "lui {0}, %hi(__PERCPU_STACK_CANARY)",
+            "addi {0}, {0}, %lo(__PERCPU_STACK_CANARY)",
+            "add {0}, {0}, gp",
+            out(reg) ptr,
+        );
+        &*(ptr as *const AtomicUsize)
+    }
+}
+
+/// Returns a new random canary. Its low byte is 0, so that a string copy
+/// cannot overwrite it with the same value.
+pub(super) fn new_stack_canary() -> usize {
+    let mut seed = [0; core::mem::size_of::<usize>()];
+    super::entropy::seed_without_pool_sources(&mut seed);
+    usize::from_ne_bytes(seed) & !0xff
+}
+
+/// Sets the canary of the initial task of the current CPU, and on the
+/// primary CPU, the global canary. Called by the CPU initialization, right
+/// after setting its per-CPU area.
+///
+/// The functions running when it is called must not return, their canary
+/// would not match anymore.
+pub(crate) fn init_stack_canary(is_bsp: bool) {
+    local_canary().store(new_stack_canary(), Ordering::Relaxed);
+    if is_bsp {
+        unsafe { core::ptr::addr_of_mut!(__stack_chk_guard).write_volatile(new_stack_canary()) };
+    }
+}
+
+/// Saves the canary of the current task to `current`, and sets `next` as the
+/// one of this CPU.
+pub(super) fn switch_stack_canary(current: &mut usize, next: usize) {
+    *current = local_canary().swap(next, Ordering::Relaxed);
+}
+
+/// Called by a protected function whose canary has been overwritten.
+///
+/// It traps with `ebreak`, for [`handle_breakpoint`](super::handle_breakpoint)
+/// to dump the trap frame, where `ra` is in the corrupted function, and
+/// panic.
+#[no_mangle]
+pub extern "C" fn __stack_chk_fail() -> ! {
+    unsafe {
+        core::arch::asm!(
+            "ebreak",
+            in("t0") STACK_CHK_MAGIC,
+            options(noreturn, nostack),
+        )
+    }
+}
@@ -0,0 +1,157 @@
+//! Static keys: flags tested by patched instructions instead of loads.
+//!
+//! The sites of the keys are recorded in `.static_key_sites`, of two kinds:
+//!
+//! - In assembly, `STATIC_JUMP key, label` is a `nop`, patched to a
+//!   `j label` while the key is enabled, as the jump labels of Linux.
+//! - In Rust, without `asm goto`, `static_branch!(KEY)` evaluates to whether
+//!   the key is enabled with a single `li` of 0 or 1, so a disabled hook
+//!   costs one `li` and a not-taken branch on a hot path.
+//!
+//! [`StaticKey::enable`] and [`StaticKey::disable`] rewrite all the sites of
+//! the key at once, with the other CPUs parked as in
+//! [`patch_text`](super::patch_text), and all the CPUs run `fence.i` before
+//! resuming. The sites are assembled without compressed instructions, so
+//! that they are always 32-bit.
+
+use axerrno::LinuxError;
+use core::sync::atomic::{AtomicBool, Ordering};
+use spinbase::SpinNoIrq;
+
+/// A flag of the hot paths, changed rarely.
+pub struct StaticKey {
+    enabled: AtomicBool,
+}
+
+/// Evaluates to whether the [`StaticKey`] `$key` (a path to a `static`) is
+/// enabled.
+macro_rules! static_branch {
+    ($key:path) => {{
+        let enabled: usize;
+        unsafe {
+            core::arch::asm!(
+                ".option push",
+                ".option norvc",
+                "1: addi {enabled}, zero, 0",
+                ".option pop",
+                ".pushsection .static_key_sites, \"a\"",
+                ".balign 8",
+                ".dword 1b, 0, {key}",
+                ".popsection",
+                enabled = out(reg) enabled,
+                key = sym $key,
+                options(nomem, nostack, preserves_flags),
+            )
+        };
+        enabled != 0
+    }};
+}
+
+/// An entry of `.static_key_sites`.
+#[repr(C)]
+struct KeySite {
+    /// The address of the `addi` or the `nop`.
+    addr: usize,
+    /// The target of the jump, 0 for an `addi`.
+    target: usize,
+    /// The address of the [`StaticKey`].
+    key: usize,
+}
+
+extern "C" {
+    static __static_key_sites: KeySite;
+    static __static_key_sites_end: KeySite;
+}
+
+fn key_sites() -> &'static [KeySite] {
+    unsafe {
+        let start = core::ptr::addr_of!(__static_key_sites);
+        let end = core::ptr::addr_of!(__static_key_sites_end);
+        core::slice::from_raw_parts(start, end.offset_from(start) as usize)
+    }
+}
+
+/// Serializes the changes of the keys, whose sites must all agree.
+static KEY_LOCK: SpinNoIrq<()> = SpinNoIrq::new(());
+
+impl StaticKey {
+    /// Creates a disabled key.
+    pub const fn new() -> Self {
+        Self {
+            enabled: AtomicBool::new(false),
+        }
+    }
+
+    /// Returns whether the key is enabled, with a load (for the slow paths).
+    pub fn is_enabled(&self) -> bool {
+        self.enabled.load(Ordering::Relaxed)
+    }
+
+    /// Enables the key, patching its sites.
+    ///
+    /// Returns [`LinuxError::EINVAL`] if a jump of a site is out of range, or
+    /// the errors of [`patch_text`](super::patch_text).
+    pub fn enable(&'static self) -> Result<(), LinuxError> {
+        self.set(true)
+    }
+
+    /// Disables the key, patching its sites.
+    ///
+    /// Returns the errors of [`patch_text`](super::patch_text).
+    pub fn disable(&'static self) -> Result<(), LinuxError> {
+        self.set(false)
+    }
+
+    fn set(&'static self, enabled: bool) -> Result<(), LinuxError> {
+        let _lock = KEY_LOCK.lock();
+        if self.enabled.load(Ordering::Relaxed) == enabled {
+            return Ok(());
+        }
+        let key = self as *const Self as usize;
+        let sites = key_sites().iter().filter(|site| site.key == key);
+        if sites.clone().any(|site| !jump_in_range(site)) {
+            return Err(LinuxError::EINVAL);
+        }
+        if sites.clone().next().is_none() {
+            self.enabled.store(enabled, Ordering::Relaxed);
+            return Ok(());
+        }
+        super::text_patch::patch_insns(sites.map(|site| (site.addr, site_insn(site, enabled))))?;
+        self.enabled.store(enabled, Ordering::Relaxed);
+        Ok(())
+    }
+}
+
+/// `addi x0, x0, 0`.
+const NOP: u32 = 0x0000_0013;
+
+/// Returns whether the jump of `site` (if any) fits in a `jal`, ±1 MiB.
+fn jump_in_range(site: &KeySite) -> bool {
+    let offset = site.target.wrapping_sub(site.addr) as isize;
+    site.target == 0 || (-(1 << 20)..1 << 20).contains(&offset)
+}
+
+/// Returns the instruction of `site` for a key `enabled`.
+fn site_insn(site: &KeySite, enabled: bool) -> u32 {
+    if site.target == 0 {
+        let insn = unsafe { (site.addr as *const u32).read_unaligned() };
+        // The immediate of the `addi`, in bits 31:20.
+        return (insn & 0x000f_ffff) | ((enabled as u32) << 20);
+    }
+    if !enabled {
+        return NOP;
+    }
+    // `jal zero, offset`, see `jump_in_range`.
+    let offset = site.target.wrapping_sub(site.addr) as u32;
+    let imm = ((offset >> 20) & 1) << 31
+        | ((offset >> 1) & 0x3ff) << 21
+        | ((offset >> 11) & 1) << 20
+        | ((offset >> 12) & 0xff) << 12;
+    imm | 0x6f
+}
+
+impl Default for StaticKey {
+    fn default() -> Self {
+        Self::new()
+    }
+}
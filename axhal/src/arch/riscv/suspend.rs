@@ -0,0 +1,345 @@
+//! Suspend to RAM, with the SBI System Suspend (SUSP) extension.
+//!
+//! [`system_suspend`] freezes the other CPUs (they stop with the SBI HSM
+//! extension, after saving their context), calls the `suspend` callbacks
+//! of the drivers registered with [`register_suspend_ops`], saves the
+//! context of the CPU and calls `sbi_system_suspend`. The CPU resumes at
+//! `__cpu_resume_entry` with paging off: it restores `satp`, `stvec`,
+//! `sscratch`, `sie`, `sstatus` and the callee-saved registers (with `tp`
+//! and `gp`), and returns from [`cpu_suspend`] as if the SBI call did. The
+//! drivers are then resumed, and the other CPUs are started again at the
+//! same entry, back where they stopped.
+
+use axerrno::LinuxError;
+use spinbase::SpinNoIrq;
+
+use super::sbi;
+use crate::cpu::_this_cpu_id;
+use crate::mem::virt_to_phys;
+
+#[cfg(feature = "smp")]
+use crate::cpu::CpuMask;
+
+include_asm_marcos!();
+
+/// The maximum number of registered [`SuspendOps`].
+pub const MAX_SUSPEND_OPS: usize = 8;
+
+/// The context saved by `__cpu_suspend_enter`, in `XLENB` words: `ra`, `sp`,
+/// `gp`, `tp`, `s0`-`s11`, `satp`, `stvec`, `sscratch`, `sie` and `sstatus`.
+const SUSPEND_CONTEXT_WORDS: usize = 21;
+
+#[repr(C)]
+struct SuspendContext([usize; SUSPEND_CONTEXT_WORDS]);
+
+/// The suspend contexts, indexed by the logical CPU ID.
+static mut SUSPEND_CONTEXTS: [SuspendContext; axconfig::SMP] = [CONTEXT_INIT; axconfig::SMP];
+
+const CONTEXT_INIT: SuspendContext = SuspendContext([0; SUSPEND_CONTEXT_WORDS]);
+
+/// The `suspend` and `resume` callbacks of a driver whose device loses its
+/// state in suspend to RAM, e.g. the console or the interrupt controller.
+#[derive(Clone, Copy)]
+pub struct SuspendOps {
+    /// Saves the state of the device, on the suspending CPU, after the
+    /// other CPUs are frozen. An error aborts the suspend.
+    pub suspend: fn() -> Result<(), LinuxError>,
+    /// Restores the state of the device, before the other CPUs are started
+    /// again.
+    pub resume: fn(),
+    /// Restores the per-CPU state (e.g. the timer), on each CPU after
+    /// `resume`.
+    pub resume_percpu: Option<fn()>,
+}
+
+static SUSPEND_OPS: SpinNoIrq<[Option<SuspendOps>; MAX_SUSPEND_OPS]> =
+    SpinNoIrq::new([None; MAX_SUSPEND_OPS]);
+
+/// How long [`system_suspend`] waits for the other CPUs to stop, or to
+/// come back.
+#[cfg(feature = "smp")]
+const FREEZE_TIMEOUT: core::time::Duration = core::time::Duration::from_secs(1);
+
+core::arch::global_asm!(
+    r"
+    .section .text
+    .balign 4
+    // a0: the context, a1: the finisher, a2: its argument.
+    .global __cpu_suspend_enter
+    __cpu_suspend_enter:
+        STR     ra, a0, 0
+        STR     sp, a0, 1
+        STR     gp, a0, 2
+        STR     tp, a0, 3
+        STR     s0, a0, 4
+        STR     s1, a0, 5
+        STR     s2, a0, 6
+        STR     s3, a0, 7
+        STR     s4, a0, 8
+        STR     s5, a0, 9
+        STR     s6, a0, 10
+        STR     s7, a0, 11
+        STR     s8, a0, 12
+        STR     s9, a0, 13
+        STR     s10, a0, 14
+        STR     s11, a0, 15
+        csrr    t0, satp
+        STR     t0, a0, 16
+        csrr    t0, stvec
+        STR     t0, a0, 17
+        csrr    t0, sscratch
+        STR     t0, a0, 18
+        csrr    t0, sie
+        STR     t0, a0, 19
+        csrr    t0, sstatus
+        STR     t0, a0, 20
+        mv      s1, a0
+        mv      a0, a2
+        jalr    a1
+        // The finisher returned: not suspended.
+        LDR     ra, s1, 0
+        LDR     s1, s1, 5
+        ret
+
+    // Paging off, a0: hart ID, a1: the physical address of the context.
+    .balign 4
+    .global __cpu_resume_entry
+    __cpu_resume_entry:
+        csrw    sie, zero
+        csrci   sstatus, 0x2
+        LDR     t0, a1, 16                  // satp
+        li      t1, {offset}
+        lla     t2, 1f
+        add     t2, t2, t1
+        csrw    stvec, t2
+        add     a1, a1, t1                  // the context in the linear mapping
+        csrw    satp, t0
+        sfence.vma
+    .balign 4
+    1:
+        LDR     t0, a1, 17
+        csrw    stvec, t0
+        LDR     t0, a1, 18
+        csrw    sscratch, t0
+        LDR     ra, a1, 0
+        LDR     sp, a1, 1
+        LDR     gp, a1, 2
+        LDR     tp, a1, 3
+        LDR     s0, a1, 4
+        LDR     s1, a1, 5
+        LDR     s2, a1, 6
+        LDR     s3, a1, 7
+        LDR     s4, a1, 8
+        LDR     s5, a1, 9
+        LDR     s6, a1, 10
+        LDR     s7, a1, 11
+        LDR     s8, a1, 12
+        LDR     s9, a1, 13
+        LDR     s10, a1, 14
+        LDR     s11, a1, 15
+        LDR     t0, a1, 19
+        csrw    sie, t0
+        LDR     t0, a1, 20
+        csrw    sstatus, t0
+        li      a0, 0
+        ret
+    ",
+    offset = const axconfig::PHYS_VIRT_OFFSET,
+);
+
+extern "C" {
+    fn __cpu_suspend_enter(
+        ctx: *mut SuspendContext,
+        finisher: extern "C" fn(usize) -> isize,
+        arg: usize,
+    ) -> isize;
+    fn __cpu_resume_entry();
+}
+
+/// Returns the physical address where a suspended CPU resumes, with `a1`
+/// the physical address of its context ([`suspend_context_paddr`]).
+pub(super) fn resume_entry_paddr() -> usize {
+    virt_to_phys((__cpu_resume_entry as usize).into()).as_usize()
+}
+
+/// Returns the physical address of the suspend context of the CPU
+/// `cpu_id`.
+pub(super) fn suspend_context_paddr(cpu_id: usize) -> usize {
+    let ctx = unsafe { core::ptr::addr_of!(SUSPEND_CONTEXTS[cpu_id]) };
+    virt_to_phys((ctx as usize).into()).as_usize()
+}
+
+/// Saves the context of the current CPU, and calls `finisher` with its
+/// physical address, to suspend the CPU in a state that loses it.
+///
+/// Returns `Ok(())` when the CPU resumes at [`resume_entry_paddr`], or if
+/// `finisher` returns 0 (e.g. a retentive suspend), and the error it
+/// returns otherwise (see [`finisher_ret`]). The interrupts must be
+/// disabled.
+pub(super) fn cpu_suspend(finisher: extern "C" fn(usize) -> isize) -> Result<(), isize> {
+    let cpu_id = _this_cpu_id();
+    let ctx = unsafe { core::ptr::addr_of_mut!(SUSPEND_CONTEXTS[cpu_id]) };
+    match unsafe { __cpu_suspend_enter(ctx, finisher, suspend_context_paddr(cpu_id)) } {
+        0 => Ok(()),
+        err => Err(err),
+    }
+}
+
+/// Returns the value of a finisher of [`cpu_suspend`] for the result of its
+/// SBI call: 0, or the negated error number.
+pub(super) fn finisher_ret(result: Result<(), LinuxError>) -> isize {
+    match result {
+        Ok(()) => 0,
+        Err(err) => -(err.code() as isize),
+    }
+}
+
+/// Registers the suspend and resume callbacks of a driver.
+///
+/// The `suspend` callbacks are called in the reverse order of registration,
+/// and the `resume` ones in the order. Returns `false` if there are already
+/// [`MAX_SUSPEND_OPS`] of them.
+pub fn register_suspend_ops(ops: SuspendOps) -> bool {
+    let mut registered = SUSPEND_OPS.lock();
+    match registered.iter_mut().find(|slot| slot.is_none()) {
+        Some(slot) => {
+            *slot = Some(ops);
+            true
+        }
+        None => false,
+    }
+}
+
+fn resume_percpu(ops: &[Option<SuspendOps>]) {
+    for resume in ops.iter().flatten().filter_map(|ops| ops.resume_percpu) {
+        resume();
+    }
+}
+
+/// Calls the `resume_percpu` callbacks on the current CPU, after it lost
+/// its state in a non-retentive suspend.
+pub(super) fn restore_percpu_state() {
+    let ops = *SUSPEND_OPS.lock();
+    resume_percpu(&ops);
+}
+
+extern "C" fn system_suspend_finisher(ctx_paddr: usize) -> isize {
+    finisher_ret(Err(sbi::system_suspend(resume_entry_paddr(), ctx_paddr)))
+}
+
+#[cfg(feature = "smp")]
+extern "C" fn hart_stop_finisher(_ctx_paddr: usize) -> isize {
+    finisher_ret(Err(sbi::hart_stop()))
+}
+
+/// Saves the context of this CPU and stops it, from an IPI handler, until
+/// [`thaw_other_cpus`] starts it again.
+#[cfg(feature = "smp")]
+fn freeze_this_cpu() {
+    crate::cpu::cpu_offline();
+    let resumed = cpu_suspend(hart_stop_finisher).is_ok();
+    crate::cpu::cpu_back_online();
+    if resumed {
+        restore_percpu_state();
+    }
+}
+
+/// Starts the CPUs frozen by [`freeze_other_cpus`] again, and waits until
+/// they are online.
+#[cfg(feature = "smp")]
+fn thaw_other_cpus(cpus: CpuMask) {
+    for cpu_id in cpus.iter() {
+        let Some(hartid) = crate::cpu::cpu_to_hartid(cpu_id) else {
+            continue;
+        };
+        if sbi::hart_status(hartid) == Ok(sbi::HSM_STATUS_STOPPED) {
+            let ctx_paddr = suspend_context_paddr(cpu_id);
+            if let Err(err) = sbi::hart_start(hartid, resume_entry_paddr(), ctx_paddr) {
+                warn!("Failed to restart hart {}: {:?}", hartid, err);
+            }
+        }
+    }
+    let deadline = crate::time::current_time() + FREEZE_TIMEOUT;
+    for cpu_id in cpus.iter() {
+        while !crate::cpu::cpu_online(cpu_id) {
+            if crate::time::current_time() >= deadline {
+                warn!("CPU {} did not come back", cpu_id);
+                break;
+            }
+            core::hint::spin_loop();
+        }
+    }
+}
+
+/// Freezes the other online CPUs, and returns them.
+///
+/// Returns [`LinuxError::EBUSY`] if a CPU does not stop in time (then the
+/// others are thawed).
+#[cfg(feature = "smp")]
+fn freeze_other_cpus() -> Result<CpuMask, LinuxError> {
+    let mut others = crate::cpu::online_cpus();
+    others.remove(_this_cpu_id());
+    super::smp_call_function_nowait(others, &freeze_this_cpu);
+    let deadline = crate::time::current_time() + FREEZE_TIMEOUT;
+    for hartid in others.iter().filter_map(crate::cpu::cpu_to_hartid) {
+        while sbi::hart_status(hartid) != Ok(sbi::HSM_STATUS_STOPPED) {
+            if crate::time::current_time() >= deadline {
+                warn!("Hart {} did not stop, abort the suspend", hartid);
+                thaw_other_cpus(others);
+                return Err(LinuxError::EBUSY);
+            }
+            core::hint::spin_loop();
+        }
+    }
+    Ok(others)
+}
+
+/// Suspends the system to RAM, and returns when it is woken up.
+///
+/// It must not be called with a user context to return to, as the FP and
+/// vector registers are not saved.
+///
+/// Returns [`LinuxError::ENOTSUP`] if the firmware has no SUSP extension,
+/// [`LinuxError::EBUSY`] if the other CPUs cannot be frozen, the error of a
+/// `suspend` callback, or [`LinuxError::EIO`] if `sbi_system_suspend`
+/// fails. The system runs as before in all cases.
+pub fn system_suspend() -> Result<(), LinuxError> {
+    if !sbi::probe_extension(sbi::EID_SUSP) {
+        return Err(LinuxError::ENOTSUP);
+    }
+    let _guard = kernel_guard_base::IrqSave::new();
+    #[cfg(feature = "smp")]
+    let frozen = freeze_other_cpus()?;
+
+    let ops = *SUSPEND_OPS.lock();
+    let count = ops.iter().flatten().count();
+    let mut suspended = 0;
+    let mut result = Ok(());
+    for op in ops.iter().flatten().rev() {
+        if let Err(err) = (op.suspend)() {
+            result = Err(err);
+            break;
+        }
+        suspended += 1;
+    }
+    if result.is_ok() {
+        info!("Suspending to RAM...");
+        match cpu_suspend(system_suspend_finisher) {
+            Ok(()) => info!("Resumed from suspend to RAM"),
+            Err(err) => {
+                warn!("sbi_system_suspend failed: {}", err);
+                result = Err(LinuxError::EIO);
+            }
+        }
+    }
+    for op in ops.iter().flatten().skip(count - suspended) {
+        (op.resume)();
+    }
+    if result.is_ok() {
+        resume_percpu(&ops);
+    }
+
+    #[cfg(feature = "smp")]
+    thaw_other_cpus(frozen);
+    result
+}
@@ -0,0 +1,84 @@
+//! Svpbmt: page-based memory types.
+//!
+//! The `PBMT` field (bits 62:61) of a leaf entry overrides the memory type
+//! that the PMAs give to the page: non-cacheable, or non-cacheable and
+//! strongly ordered for I/O. Without Svpbmt the field is reserved and must
+//! be zero, so everything keeps the PMA type.
+//!
+//! The T-Head C906/C910 predate Svpbmt and take the memory type from bits
+//! 63:59 instead ([`ALT_ERRATA_THEAD_PBMT`]), where a normal page must be
+//! marked cacheable too.
+
+use super::errata::{has_erratum, ALT_ERRATA_THEAD_PBMT};
+use crate::mem::MemRegionFlags;
+
+const PTE_PBMT_SHIFT: usize = 61;
+/// The `PBMT` field of a PTE.
+pub(super) const PTE_PBMT_MASK: usize = 0b11 << PTE_PBMT_SHIFT;
+const PBMT_NC: usize = 1;
+const PBMT_IO: usize = 2;
+
+/// The T-Head memory types: weakly ordered, cacheable, bufferable and
+/// shareable for the RAM, non-cacheable for NC, and strongly ordered,
+/// non-cacheable, non-bufferable for I/O.
+const THEAD_PMA: usize = (1 << 62) | (1 << 61) | (1 << 60);
+const THEAD_NC: usize = (1 << 61) | (1 << 60);
+const THEAD_IO: usize = (1 << 63) | (1 << 60);
+
+/// Returns whether all CPUs support Svpbmt, or the T-Head memory types.
+///
+/// It needs the device tree, i.e. must be called after `arch_init_early`.
+pub fn has_svpbmt() -> bool {
+    super::cpu_has(super::Feature::Svpbmt) || has_erratum(ALT_ERRATA_THEAD_PBMT)
+}
+
+/// The memory type of a mapping.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum MemAttr {
+    /// The type given by the PMAs, i.e. cacheable for the RAM.
+    #[default]
+    Normal,
+    /// Non-cacheable, weakly ordered (e.g. a framebuffer).
+    NonCacheable,
+    /// Non-cacheable, strongly ordered (device MMIO).
+    Io,
+}
+
+impl MemAttr {
+    /// Returns the `PBMT` bits of a leaf entry with this memory type (the
+    /// T-Head ones on these cores), or 0 without Svpbmt.
+    pub fn pte_bits(self) -> usize {
+        if has_erratum(ALT_ERRATA_THEAD_PBMT) {
+            return match self {
+                Self::Normal => THEAD_PMA,
+                Self::NonCacheable => THEAD_NC,
+                Self::Io => THEAD_IO,
+            };
+        }
+        let pbmt = match self {
+            Self::Normal => return 0,
+            Self::NonCacheable => PBMT_NC,
+            Self::Io => PBMT_IO,
+        };
+        if has_svpbmt() {
+            pbmt << PTE_PBMT_SHIFT
+        } else {
+            0
+        }
+    }
+}
+
+impl From<&MemRegionFlags> for MemAttr {
+    /// The memory type of a region: [`Io`](Self::Io) for
+    /// [`DEVICE`](MemRegionFlags::DEVICE), [`NonCacheable`](Self::NonCacheable)
+    /// for [`UNCACHED`](MemRegionFlags::UNCACHED).
+    fn from(flags: &MemRegionFlags) -> Self {
+        if flags.contains(MemRegionFlags::DEVICE) {
+            Self::Io
+        } else if flags.contains(MemRegionFlags::UNCACHED) {
+            Self::NonCacheable
+        } else {
+            Self::Normal
+        }
+    }
+}
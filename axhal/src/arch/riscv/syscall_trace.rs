@@ -0,0 +1,123 @@
+//! The instrumentation of the syscalls: the syscall-entry and syscall-exit
+//! stops of the traced tasks (e.g. for `PTRACE_SYSCALL`), and the hooks of
+//! all syscalls (e.g. for tracing, filtering or audit).
+//!
+//! The syscalls go through [`trace_syscall`]: the syscall fast path calls
+//! it, and the trap handler of the kernel calls it instead of its syscall
+//! handler. A syscall of a task that [`SyscallTrace::traced`] reports has a
+//! complete trap frame (the fast path leaves it to the full path). The
+//! hooks are tested with a [`StaticKey`], enabled by the first one.
+
+use lazy_init::LazyInit;
+use spinbase::SpinNoIrq;
+
+use super::{StaticKey, TrapFrame};
+
+/// The maximum number of syscall hook pairs.
+pub const MAX_SYSCALL_TRACE_HOOKS: usize = 4;
+
+/// A hook called before a syscall, with its number and its arguments.
+/// Returns the result of the syscall to skip it (e.g. `-EPERM`), or
+/// [`None`] to run it.
+pub type SyscallEnterHook = fn(sysno: usize, args: &[usize; 6]) -> Option<isize>;
+
+/// A hook called after a syscall (also a skipped one), with its number and
+/// its result.
+pub type SyscallExitHook = fn(sysno: usize, ret: isize);
+
+static HOOKS: SpinNoIrq<[Option<(SyscallEnterHook, SyscallExitHook)>; MAX_SYSCALL_TRACE_HOOKS]> =
+    SpinNoIrq::new([None; MAX_SYSCALL_TRACE_HOOKS]);
+
+/// Whether a hook is registered.
+static HOOKS_KEY: StaticKey = StaticKey::new();
+
+/// The stops of the traced syscalls.
+pub struct SyscallTrace {
+    /// Returns whether the syscalls of the current task are traced.
+    pub traced: fn() -> bool,
+    /// The syscall-entry stop, with the arguments in the trap frame. Returns
+    /// `false` to skip the syscall, with `a0` of the frame as its result.
+    pub entry: fn(&mut TrapFrame) -> bool,
+    /// The syscall-exit stop, with the result in `a0` of the trap frame.
+    pub exit: fn(&mut TrapFrame),
+}
+
+static TRACE: LazyInit<SyscallTrace> = LazyInit::new();
+
+/// Registers the stops of the traced syscalls.
+pub fn init_syscall_trace(trace: SyscallTrace) {
+    TRACE.init_by(trace);
+}
+
+/// Returns whether the syscalls of the current task are traced.
+pub fn syscall_traced() -> bool {
+    TRACE.is_init() && (TRACE.traced)()
+}
+
+/// Registers the hooks `enter` and `exit` of all syscalls, on all CPUs.
+///
+/// The enter hooks run in the order of registration, the first one that
+/// skips the syscall stops the others; the exit hooks all run. Returns
+/// `false` if [`MAX_SYSCALL_TRACE_HOOKS`] pairs are already registered, or
+/// the hooks could not be enabled.
+pub fn register_syscall_trace_hooks(enter: SyscallEnterHook, exit: SyscallExitHook) -> bool {
+    {
+        let mut hooks = HOOKS.lock();
+        let Some(slot) = hooks.iter_mut().find(|slot| slot.is_none()) else {
+            return false;
+        };
+        *slot = Some((enter, exit));
+    }
+    HOOKS_KEY.enable().is_ok()
+}
+
+fn run_enter_hooks(sysno: usize, args: &[usize; 6]) -> Option<isize> {
+    let hooks = *HOOKS.lock();
+    hooks
+        .iter()
+        .flatten()
+        .find_map(|(enter, _)| enter(sysno, args))
+}
+
+fn run_exit_hooks(sysno: usize, ret: isize) {
+    let hooks = *HOOKS.lock();
+    for (_, exit) in hooks.iter().flatten() {
+        exit(sysno, ret);
+    }
+}
+
+/// Runs the syscall of `tf` with `syscall` (which advances `sepc`), between
+/// the entry and the exit stops if the current task is traced, and the
+/// hooks if any.
+///
+/// The entry stop runs first, so the hooks see the arguments as the tracer
+/// left them. A syscall skipped by the entry stop or a hook still has its
+/// `ecall` skipped, and still gets the exit hooks and stop.
+pub fn trace_syscall(tf: &mut TrapFrame, syscall: fn(&mut TrapFrame)) {
+    let hooked = static_branch!(HOOKS_KEY);
+    let traced = syscall_traced();
+    if !hooked && !traced {
+        return syscall(tf);
+    }
+    let mut run = !traced || (TRACE.entry)(tf);
+    let sysno = tf.regs.a7;
+    if run && hooked {
+        let r = &tf.regs;
+        let args = [r.a0, r.a1, r.a2, r.a3, r.a4, r.a5];
+        if let Some(ret) = run_enter_hooks(sysno, &args) {
+            tf.regs.a0 = ret as usize;
+            run = false;
+        }
+    }
+    if run {
+        syscall(tf);
+    } else {
+        tf.sepc += 4;
+    }
+    if hooked {
+        run_exit_hooks(sysno, tf.regs.a0 as isize);
+    }
+    if traced {
+        (TRACE.exit)(tf);
+    }
+}
@@ -68,10 +68,12 @@ pub const LINUX_SYSCALL_MMAP: usize = 0xde;
 pub const LINUX_SYSCALL_MPROTECT: usize = 0xe2;
 pub const LINUX_SYSCALL_MSYNC: usize = 0xe3;
 pub const LINUX_SYSCALL_MADVISE: usize = 0xe9;
+pub const LINUX_SYSCALL_SYSRISCV: usize = 0xf4;
 pub const LINUX_SYSCALL_WAIT4: usize = 0x104;
 pub const LINUX_SYSCALL_PRLIMIT64: usize = 0x105;
 pub const LINUX_SYSCALL_GETRANDOM: usize = 0x116;
 pub const LINUX_SYSCALL_RSEQ: usize = 0x125;
+pub const LINUX_SYSCALL_CLONE3: usize = 0x1b3;
 
 pub const LINUX_SYSCALL_SET_TID_ADDRESS: usize = 0x60;
 pub const LINUX_SYSCALL_SET_ROBUST_LIST: usize = 0x63;
@@ -0,0 +1,162 @@
+//!
+//! Linux syscalls of the RV32 (compat) tasks
+//!
+//! RV32 has the same generic syscall table as RV64, but without the calls
+//! that use a 32-bit `time_t` (replaced by their `*_time64` versions) or a
+//! 32-bit `off_t` (replaced by their `64` versions), and without `fstatat`
+//! (replaced by `statx`). [`to_native`] maps each of them to the RV64 one,
+//! and [`to_native_args`] also their arguments.
+//!
+
+use super::sysno as native;
+
+pub const LINUX_SYSCALL_FGETXATTR: usize = 0xa;
+pub const LINUX_SYSCALL_GETCWD: usize = 0x11;
+pub const LINUX_SYSCALL_DUP: usize = 0x17;
+pub const LINUX_SYSCALL_DUP3: usize = 0x18;
+pub const LINUX_SYSCALL_FCNTL64: usize = 0x19;
+pub const LINUX_SYSCALL_IOCTL: usize = 0x1d;
+pub const LINUX_SYSCALL_MKNODAT: usize = 0x21;
+pub const LINUX_SYSCALL_MKDIRAT: usize = 0x22;
+pub const LINUX_SYSCALL_UNLINKAT: usize = 0x23;
+pub const LINUX_SYSCALL_SYMLINKAT: usize = 0x24;
+pub const LINUX_SYSCALL_LINKAT: usize = 0x25;
+pub const LINUX_SYSCALL_UMOUNT2: usize = 0x27;
+pub const LINUX_SYSCALL_MOUNT: usize = 0x28;
+pub const LINUX_SYSCALL_STATFS64: usize = 0x2b;
+pub const LINUX_SYSCALL_FTRUNCATE64: usize = 0x2e;
+pub const LINUX_SYSCALL_FALLOCATE: usize = 0x2f;
+pub const LINUX_SYSCALL_FACCESSAT: usize = 0x30;
+pub const LINUX_SYSCALL_CHDIR: usize = 0x31;
+pub const LINUX_SYSCALL_FCHMOD: usize = 0x34;
+pub const LINUX_SYSCALL_FCHMODAT: usize = 0x35;
+pub const LINUX_SYSCALL_FCHOWNAT: usize = 0x36;
+pub const LINUX_SYSCALL_FCHOWN: usize = 0x37;
+pub const LINUX_SYSCALL_OPENAT: usize = 0x38;
+pub const LINUX_SYSCALL_CLOSE: usize = 0x39;
+pub const LINUX_SYSCALL_PIPE2: usize = 0x3b;
+pub const LINUX_SYSCALL_GETDENTS64: usize = 0x3d;
+pub const LINUX_SYSCALL_LLSEEK: usize = 0x3e;
+pub const LINUX_SYSCALL_READ: usize = 0x3f;
+pub const LINUX_SYSCALL_WRITE: usize = 0x40;
+pub const LINUX_SYSCALL_WRITEV: usize = 0x42;
+pub const LINUX_SYSCALL_PREAD64: usize = 0x43;
+pub const LINUX_SYSCALL_SENDFILE64: usize = 0x47;
+pub const LINUX_SYSCALL_READLINKAT: usize = 0x4e;
+pub const LINUX_SYSCALL_CAPGET: usize = 0x5a;
+pub const LINUX_SYSCALL_EXIT: usize = 0x5d;
+pub const LINUX_SYSCALL_EXIT_GROUP: usize = 0x5e;
+pub const LINUX_SYSCALL_SET_TID_ADDRESS: usize = 0x60;
+pub const LINUX_SYSCALL_SET_ROBUST_LIST: usize = 0x63;
+pub const LINUX_SYSCALL_SETITIMER: usize = 0x67;
+pub const LINUX_SYSCALL_SCHED_GETAFFINITY: usize = 0x7b;
+pub const LINUX_SYSCALL_KILL: usize = 0x81;
+pub const LINUX_SYSCALL_TGKILL: usize = 0x83;
+pub const LINUX_SYSCALL_RT_SIGACTION: usize = 0x86;
+pub const LINUX_SYSCALL_RT_SIGPROCMASK: usize = 0x87;
+pub const LINUX_SYSCALL_RT_SIGRETURN: usize = 0x8b;
+pub const LINUX_SYSCALL_SETGID: usize = 0x90;
+pub const LINUX_SYSCALL_SETREUID: usize = 0x91;
+pub const LINUX_SYSCALL_SETUID: usize = 0x92;
+pub const LINUX_SYSCALL_SETRESUID: usize = 0x93;
+pub const LINUX_SYSCALL_SETPGID: usize = 0x9a;
+pub const LINUX_SYSCALL_UNAME: usize = 0xa0;
+pub const LINUX_SYSCALL_UMASK: usize = 0xa6;
+pub const LINUX_SYSCALL_GETPID: usize = 0xac;
+pub const LINUX_SYSCALL_GETPPID: usize = 0xad;
+pub const LINUX_SYSCALL_GETUID: usize = 0xae;
+pub const LINUX_SYSCALL_GETEUID: usize = 0xaf;
+pub const LINUX_SYSCALL_GETGID: usize = 0xb0;
+pub const LINUX_SYSCALL_GETEGID: usize = 0xb1;
+pub const LINUX_SYSCALL_GETTID: usize = 0xb2;
+pub const LINUX_SYSCALL_SOCKET: usize = 0xc6;
+pub const LINUX_SYSCALL_BRK: usize = 0xd6;
+pub const LINUX_SYSCALL_MUNMAP: usize = 0xd7;
+pub const LINUX_SYSCALL_MREMAP: usize = 0xd8;
+pub const LINUX_SYSCALL_CLONE: usize = 0xdc;
+pub const LINUX_SYSCALL_EXECVE: usize = 0xdd;
+pub const LINUX_SYSCALL_MMAP2: usize = 0xde;
+pub const LINUX_SYSCALL_MPROTECT: usize = 0xe2;
+pub const LINUX_SYSCALL_MSYNC: usize = 0xe3;
+pub const LINUX_SYSCALL_MADVISE: usize = 0xe9;
+pub const LINUX_SYSCALL_SYSRISCV: usize = 0xf4;
+pub const LINUX_SYSCALL_PRLIMIT64: usize = 0x105;
+pub const LINUX_SYSCALL_GETRANDOM: usize = 0x116;
+pub const LINUX_SYSCALL_STATX: usize = 0x123;
+pub const LINUX_SYSCALL_RSEQ: usize = 0x125;
+pub const LINUX_SYSCALL_WAITID: usize = 0x5f;
+
+pub const LINUX_SYSCALL_CLOCK_GETTIME64: usize = 0x193;
+pub const LINUX_SYSCALL_CLOCK_NANOSLEEP_TIME64: usize = 0x197;
+pub const LINUX_SYSCALL_UTIMENSAT_TIME64: usize = 0x19c;
+pub const LINUX_SYSCALL_FUTEX_TIME64: usize = 0x1a6;
+
+/// Returns the RV64 syscall number of the compat syscall `sysno`, if it has
+/// an RV64 one with the same arguments (once zero-extended, see
+/// [`compat_syscall_args`](super::compat_syscall_args)).
+///
+/// `mmap2` is mapped to `mmap`: its offset must be multiplied by 4096 by the
+/// caller. `statx` and `waitid` have no RV64 counterpart in
+/// [`sysno`](super::sysno), they return [`None`], as do `_llseek` (which
+/// returns the offset through a pointer) and `statfs64` (whose buffer has
+/// the 32-bit layout). The calls with 64-bit arguments, split in two
+/// registers, also return [`None`]: see [`to_native_args`].
+pub const fn to_native(sysno: usize) -> Option<usize> {
+    Some(match sysno {
+        LINUX_SYSCALL_FCNTL64 => native::LINUX_SYSCALL_FCNTL,
+        LINUX_SYSCALL_SENDFILE64 => native::LINUX_SYSCALL_SENDFILE,
+        LINUX_SYSCALL_MMAP2 => native::LINUX_SYSCALL_MMAP,
+        LINUX_SYSCALL_CLOCK_GETTIME64 => native::LINUX_SYSCALL_CLOCK_GETTIME,
+        LINUX_SYSCALL_CLOCK_NANOSLEEP_TIME64 => native::LINUX_SYSCALL_CLOCK_NANOSLEEP,
+        LINUX_SYSCALL_UTIMENSAT_TIME64 => native::LINUX_SYSCALL_UTIMENSAT,
+        LINUX_SYSCALL_FUTEX_TIME64 => native::LINUX_SYSCALL_FUTEX,
+        LINUX_SYSCALL_STATX | LINUX_SYSCALL_WAITID => return None,
+        LINUX_SYSCALL_LLSEEK | LINUX_SYSCALL_STATFS64 => return None,
+        LINUX_SYSCALL_FTRUNCATE64 | LINUX_SYSCALL_PREAD64 | LINUX_SYSCALL_FALLOCATE => return None,
+        // The 64-bit-only calls do not exist in RV32.
+        native::LINUX_SYSCALL_FSTATAT
+        | native::LINUX_SYSCALL_WAIT4
+        | native::LINUX_SYSCALL_CLOCK_GETTIME
+        | native::LINUX_SYSCALL_CLOCK_NANOSLEEP
+        | native::LINUX_SYSCALL_UTIMENSAT
+        | native::LINUX_SYSCALL_FUTEX => return None,
+        sysno => sysno,
+    })
+}
+
+/// Returns the 64-bit value of the register pair `lo`, `hi` of a 64-bit
+/// compat syscall argument.
+const fn join(lo: usize, hi: usize) -> usize {
+    (hi << 32) | (lo & 0xffff_ffff)
+}
+
+/// Returns the RV64 syscall number and arguments of the compat syscall
+/// `sysno` with the zero-extended arguments `args`, if it has an RV64 one.
+///
+/// It is [`to_native`] with the same arguments, and the calls with 64-bit
+/// arguments: `ftruncate64`, `pread64` and `fallocate` get each of them
+/// from its two registers (the low half first).
+pub const fn to_native_args(sysno: usize, args: [usize; 6]) -> Option<(usize, [usize; 6])> {
+    let [a0, a1, a2, a3, a4, a5] = args;
+    Some(match sysno {
+        // ftruncate64(fd, length)
+        LINUX_SYSCALL_FTRUNCATE64 => (
+            native::LINUX_SYSCALL_FTRUNCATE,
+            [a0, join(a1, a2), 0, 0, 0, 0],
+        ),
+        // pread64(fd, buf, count, pos)
+        LINUX_SYSCALL_PREAD64 => (
+            native::LINUX_SYSCALL_PREAD64,
+            [a0, a1, a2, join(a3, a4), 0, 0],
+        ),
+        // fallocate(fd, mode, offset, len)
+        LINUX_SYSCALL_FALLOCATE => (
+            native::LINUX_SYSCALL_FALLOCATE,
+            [a0, a1, join(a2, a3), join(a4, a5), 0, 0],
+        ),
+        sysno => match to_native(sysno) {
+            Some(native) => (native, args),
+            None => return None,
+        },
+    })
+}
@@ -0,0 +1,102 @@
+//! Live patching of the kernel text.
+//!
+//! [`patch_text`] writes through a writable alias of the text in the
+//! [fixmap](super::FixmapSlot::TextPoke), so it also works once `.text` is
+//! read-only. A 32-bit instruction is only 16-bit aligned and cannot be
+//! written atomically, so with `smp` the other CPUs are parked in an IPI
+//! handler during the write (as with `stop_machine` in Linux), and each of
+//! them runs `fence.i` before resuming.
+
+use axerrno::LinuxError;
+use memory_addr::VirtAddr;
+use spinbase::SpinNoIrq;
+
+use super::{FixmapSlot, MemAttr};
+use crate::mem::virt_to_phys;
+
+#[cfg(feature = "smp")]
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+
+/// Serializes the patching, which uses one fixmap slot.
+static PATCH_LOCK: SpinNoIrq<()> = SpinNoIrq::new(());
+
+/// The number of CPUs parked by [`patch_text`].
+#[cfg(feature = "smp")]
+static PARKED_CPUS: AtomicUsize = AtomicUsize::new(0);
+
+/// Whether the parked CPUs can resume.
+#[cfg(feature = "smp")]
+static PATCH_DONE: AtomicBool = AtomicBool::new(false);
+
+#[cfg(feature = "smp")]
+fn park_cpu() {
+    PARKED_CPUS.fetch_add(1, Ordering::AcqRel);
+    while !PATCH_DONE.load(Ordering::Acquire) {
+        core::hint::spin_loop();
+    }
+    super::local_flush_icache_all();
+    PARKED_CPUS.fetch_sub(1, Ordering::AcqRel);
+}
+
+/// Writes `bytes` at `addr` in the kernel text, through the fixmap.
+pub(super) fn poke_text(addr: usize, bytes: &[u8]) -> Result<(), LinuxError> {
+    let paddr = virt_to_phys(VirtAddr::from(addr));
+    let alias = super::set_fixmap(FixmapSlot::TextPoke, paddr, bytes.len(), MemAttr::Normal)?;
+    let not_copied = super::copy_to_kernel_nofault(alias.as_usize(), bytes);
+    super::clear_fixmap(FixmapSlot::TextPoke);
+    if not_copied != 0 {
+        return Err(LinuxError::EFAULT);
+    }
+    Ok(())
+}
+
+/// Writes `bytes` (e.g. an instruction) at `addr` in the kernel text, and
+/// makes all the CPUs fetch the new instructions.
+///
+/// `bytes` must not be longer than a page. With `smp`, the other online
+/// CPUs do not run any code until the write is done. Returns
+/// [`LinuxError::EFAULT`] if `addr` cannot be written.
+pub fn patch_text(addr: usize, bytes: &[u8]) -> Result<(), LinuxError> {
+    stop_machine(|| poke_text(addr, bytes))
+}
+
+/// Writes the 32-bit instructions `insns` (as `(addr, insn)`) in the kernel
+/// text as [`patch_text`], parking the other CPUs only once for all of
+/// them.
+///
+/// Stops at the first error, the previous instructions staying written.
+pub(super) fn patch_insns(insns: impl Iterator<Item = (usize, u32)>) -> Result<(), LinuxError> {
+    stop_machine(|| {
+        for (addr, insn) in insns {
+            poke_text(addr, &insn.to_le_bytes())?;
+        }
+        Ok(())
+    })
+}
+
+/// Runs `poke` while the other online CPUs are parked, then makes all the
+/// CPUs fetch the new instructions.
+fn stop_machine(poke: impl FnOnce() -> Result<(), LinuxError>) -> Result<(), LinuxError> {
+    let _lock = PATCH_LOCK.lock();
+    #[cfg(feature = "smp")]
+    {
+        let mut others = crate::cpu::online_cpus();
+        others.remove(crate::cpu::_this_cpu_id());
+        let count = others.count();
+        PATCH_DONE.store(false, Ordering::Release);
+        super::smp_call_function_nowait(others, &park_cpu);
+        while PARKED_CPUS.load(Ordering::Acquire) != count {
+            core::hint::spin_loop();
+        }
+    }
+    let result = poke();
+    super::local_flush_icache_all();
+    #[cfg(feature = "smp")]
+    {
+        PATCH_DONE.store(true, Ordering::Release);
+        while PARKED_CPUS.load(Ordering::Acquire) != 0 {
+            core::hint::spin_loop();
+        }
+    }
+    result
+}
@@ -0,0 +1,120 @@
+//! Batched TLB flushes.
+
+use memory_addr::VirtAddr;
+
+#[cfg(feature = "smp")]
+use crate::mem::PAGE_SIZE_4K;
+
+/// The maximum number of addresses a [`TlbBatch`] holds, a batch that queues
+/// more is flushed with a full flush.
+pub const TLB_BATCH_CAPACITY: usize = super::TLB_FLUSH_RANGE_THRESHOLD;
+
+/// Accumulates the pages whose TLB entries must be flushed, e.g. during a
+/// multi-page unmap, to flush them all at once.
+///
+/// The flush is done by [`flush`](Self::flush), or when the batch is dropped.
+/// With `smp`, it is also sent to the other online CPUs, by a single remote
+/// fence request for the whole batch.
+pub struct TlbBatch {
+    vaddrs: [usize; TLB_BATCH_CAPACITY],
+    len: usize,
+    /// More pages than the capacity have been queued.
+    overflowed: bool,
+}
+
+impl TlbBatch {
+    /// Creates an empty batch.
+    pub const fn new() -> Self {
+        Self {
+            vaddrs: [0; TLB_BATCH_CAPACITY],
+            len: 0,
+            overflowed: false,
+        }
+    }
+
+    /// Returns whether nothing is queued.
+    pub const fn is_empty(&self) -> bool {
+        self.len == 0 && !self.overflowed
+    }
+
+    /// Queues the page at `va`.
+    pub fn queue(&mut self, va: VirtAddr) {
+        if self.overflowed {
+            return;
+        }
+        let va = va.align_down_4k().as_usize();
+        if self.vaddrs[..self.len].contains(&va) {
+            return;
+        }
+        if super::tlb_flush_all_better(self.len + 1) {
+            self.overflowed = true;
+        } else {
+            self.vaddrs[self.len] = va;
+            self.len += 1;
+        }
+    }
+
+    /// Flushes the queued pages, and empties the batch.
+    ///
+    /// It issues one `sfence.vma` per page, or a single full flush if more
+    /// than [`TLB_BATCH_CAPACITY`] pages have been queued.
+    pub fn flush(&mut self) {
+        if self.is_empty() {
+            return;
+        }
+        if self.overflowed {
+            super::flush_tlb(None);
+        } else {
+            for &va in &self.vaddrs[..self.len] {
+                super::flush_tlb(Some(VirtAddr::from(va)));
+            }
+        }
+        #[cfg(feature = "smp")]
+        self.flush_remote();
+        self.len = 0;
+        self.overflowed = false;
+    }
+
+    /// Returns the range to flush on a remote CPU, `(start, size)`, with
+    /// `size == usize::MAX` for a full flush.
+    #[cfg(feature = "smp")]
+    fn remote_range(&self) -> (usize, usize) {
+        if self.overflowed {
+            return (0, usize::MAX);
+        }
+        let vaddrs = &self.vaddrs[..self.len];
+        let start = vaddrs.iter().copied().min().unwrap_or(0);
+        let end = vaddrs.iter().copied().max().unwrap_or(0) + PAGE_SIZE_4K;
+        if super::tlb_flush_all_better((end - start) / PAGE_SIZE_4K) {
+            (0, usize::MAX)
+        } else {
+            (start, end - start)
+        }
+    }
+
+    /// Sends the flush to the other online CPUs, with a remote fence or
+    /// IPIs.
+    #[cfg(feature = "smp")]
+    fn flush_remote(&self) {
+        let (start, size) = self.remote_range();
+        let mut others = crate::cpu::online_cpus();
+        others.remove(crate::cpu::_this_cpu_id());
+        if super::remote_sfence_vma(others, VirtAddr::from(start), size).is_err() {
+            let range =
+                (size != usize::MAX).then(|| VirtAddr::from(start)..VirtAddr::from(start + size));
+            super::flush_tlb_all_cpus(range, None);
+        }
+    }
+}
+
+impl Default for TlbBatch {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Drop for TlbBatch {
+    fn drop(&mut self) {
+        self.flush();
+    }
+}
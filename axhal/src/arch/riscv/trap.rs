@@ -1,21 +1,63 @@
 include_asm_marcos!();
 use crate::trap::TRAPFRAME_SIZE;
+
+/// Returns to user space with the trap frame at `kstack_sp`, e.g. in a
+/// forked task.
 pub fn ret_from_fork(kstack_sp: usize) {
     unsafe {
         core::arch::asm!(
             r"
             mv  sp, {kstack_sp}
-            addi t0, sp, {tramframe_size}
-            csrw sscratch, t0
-            RESTORE_REGS 1
-            sret
+            j   __ret_to_user
             ",
             kstack_sp = in(reg) kstack_sp,
-            tramframe_size = const TRAPFRAME_SIZE,
+            options(noreturn),
         );
     };
 }
 
+extern "C" {
+    /// The entry of a task initialized by
+    /// [`TaskContext::init_fork`](super::TaskContext::init_fork) or
+    /// [`TaskContext::init_kernel_thread`](super::TaskContext::init_kernel_thread),
+    /// with the trap frame at `sp`.
+    ///
+    /// A kernel thread (`s0` is not 0) first runs `s0(s1)`, without access to
+    /// the user memory (`sstatus.SUM` cleared), on the stack below the trap
+    /// frame aligned to 16 bytes. If it returns, the thread goes on to user
+    /// space as a forked task, with the trap frame that it has set up
+    /// meanwhile (e.g. by an `execve`). `gp` (the per-CPU area) and `tp`
+    /// are the ones of the switch, they become the supervisor ones of the
+    /// trap frame.
+    pub(super) fn __ret_from_fork();
+}
+
+core::arch::global_asm!(
+    r"
+    .section .text
+    .balign 4
+    .global __ret_from_fork
+    __ret_from_fork:
+    beqz s0, __ret_to_user
+    li t0, {sr_sum}
+    csrc sstatus, t0
+    mv a0, s1
+    mv s1, sp
+    andi sp, sp, -16
+    jalr s0
+    mv sp, s1
+
+    .global __ret_to_user
+    __ret_to_user:
+    addi t0, sp, {tramframe_size}
+    csrw sscratch, t0
+    RESTORE_REGS 1
+    sret
+    ",
+    sr_sum = const super::SR_SUM,
+    tramframe_size = const TRAPFRAME_SIZE,
+);
+
 core::arch::global_asm!(
     r"
     .section .text
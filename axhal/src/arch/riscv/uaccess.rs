@@ -0,0 +1,339 @@
+//! Accesses to user memory, with fault fixup.
+//!
+//! Every user access below has an `__ex_table` entry. A fault makes the bulk
+//! routines return early with the number of bytes not copied (or cleared),
+//! as in Linux, and the typed ones return the negated `EFAULT`.
+//!
+//! The user addresses may be tagged (see
+//! [`set_pointer_masking`](super::set_pointer_masking)): the tags are
+//! stripped before the addresses are checked and accessed.
+
+use axerrno::LinuxError;
+
+use super::{access_ok, untagged_addr};
+
+/// Emits an `__ex_table` entry for the user access at the local label
+/// `label`, with `fixup` as the fixup code.
+macro_rules! ex_table {
+    ($label:literal, $fixup:literal) => {
+        concat!(
+            ".pushsection __ex_table, \"a\"\n",
+            ".balign 8\n",
+            ".dword ",
+            $label,
+            ", ",
+            $fixup,
+            "\n",
+            ".popsection",
+        )
+    };
+}
+
+core::arch::global_asm!(
+    ".section .text",
+    ".balign 4",
+    // a0: dst, a1: src, a2: n. Returns the bytes not copied.
+    ".global __asm_copy_user",
+    "__asm_copy_user:",
+    "   beqz    a2, 3f",
+    "   or      t0, a0, a1",
+    "   andi    t0, t0, 7",
+    "   bnez    t0, 2f",
+    "1:",
+    "   li      t0, 8",
+    "   bltu    a2, t0, 2f",
+    "10:",
+    "   ld      t1, 0(a1)",
+    ex_table!("10b", "__copy_user_fixup"),
+    "11:",
+    "   sd      t1, 0(a0)",
+    ex_table!("11b", "__copy_user_fixup"),
+    "   addi    a0, a0, 8",
+    "   addi    a1, a1, 8",
+    "   addi    a2, a2, -8",
+    "   j       1b",
+    "2:",
+    "   beqz    a2, 3f",
+    "20:",
+    "   lbu     t1, 0(a1)",
+    ex_table!("20b", "__copy_user_fixup"),
+    "21:",
+    "   sb      t1, 0(a0)",
+    ex_table!("21b", "__copy_user_fixup"),
+    "   addi    a0, a0, 1",
+    "   addi    a1, a1, 1",
+    "   addi    a2, a2, -1",
+    "   j       2b",
+    "3:",
+    "   mv      a0, a2",
+    "   ret",
+    "",
+    // a0: dst, a1: n. Returns the bytes not cleared.
+    ".global __asm_clear_user",
+    "__asm_clear_user:",
+    "   beqz    a1, 3f",
+    "   andi    t0, a0, 7",
+    "   bnez    t0, 2f",
+    "1:",
+    "   li      t0, 8",
+    "   bltu    a1, t0, 2f",
+    "10:",
+    "   sd      zero, 0(a0)",
+    ex_table!("10b", "__clear_user_fixup"),
+    "   addi    a0, a0, 8",
+    "   addi    a1, a1, -8",
+    "   j       1b",
+    "2:",
+    "   beqz    a1, 3f",
+    "20:",
+    "   sb      zero, 0(a0)",
+    ex_table!("20b", "__clear_user_fixup"),
+    "   addi    a0, a0, 1",
+    "   addi    a1, a1, -1",
+    "   j       2b",
+    "3:",
+    "   mv      a0, a1",
+    "   ret",
+    "",
+    // The counts are only decremented after a successful store, so they
+    // hold the bytes not done yet.
+    ".pushsection .fixup, \"ax\"",
+    ".balign 4",
+    "__copy_user_fixup:",
+    "   mv      a0, a2",
+    "   ret",
+    "__clear_user_fixup:",
+    "   mv      a0, a1",
+    "   ret",
+    ".popsection",
+);
+
+extern "C" {
+    fn __asm_copy_user(dst: usize, src: usize, n: usize) -> usize;
+    fn __asm_clear_user(dst: usize, n: usize) -> usize;
+}
+
+/// Copies `dst.len()` bytes from the user address `src` to `dst`.
+///
+/// Returns the number of bytes that could not be copied, 0 on success. If
+/// the range is not in user space, nothing is copied.
+pub fn copy_from_user(dst: &mut [u8], src: usize) -> usize {
+    let src = untagged_addr(src);
+    if !access_ok(src, dst.len()) {
+        return dst.len();
+    }
+    unsafe { __asm_copy_user(dst.as_mut_ptr() as usize, src, dst.len()) }
+}
+
+/// Copies `src` to the user address `dst`.
+///
+/// Returns the number of bytes that could not be copied, 0 on success. If
+/// the range is not in user space, nothing is copied.
+pub fn copy_to_user(dst: usize, src: &[u8]) -> usize {
+    let dst = untagged_addr(dst);
+    if !access_ok(dst, src.len()) {
+        return src.len();
+    }
+    unsafe { __asm_copy_user(dst, src.as_ptr() as usize, src.len()) }
+}
+
+/// Fills `n` bytes at the user address `dst` with zeros.
+///
+/// Returns the number of bytes that could not be cleared, 0 on success. If
+/// the range is not in user space, nothing is cleared.
+pub fn clear_user(dst: usize, n: usize) -> usize {
+    let dst = untagged_addr(dst);
+    if !access_ok(dst, n) {
+        return n;
+    }
+    unsafe { __asm_clear_user(dst, n) }
+}
+
+/// Copies `dst.len()` bytes from the kernel address `src` to `dst`, with
+/// fault fixup, e.g. for a debugger reading arbitrary memory.
+///
+/// Returns the number of bytes that could not be copied, 0 on success.
+pub fn copy_from_kernel_nofault(dst: &mut [u8], src: usize) -> usize {
+    unsafe { __asm_copy_user(dst.as_mut_ptr() as usize, src, dst.len()) }
+}
+
+/// Copies `src` to the kernel address `dst`, with fault fixup.
+///
+/// Returns the number of bytes that could not be copied, 0 on success.
+pub fn copy_to_kernel_nofault(dst: usize, src: &[u8]) -> usize {
+    unsafe { __asm_copy_user(dst, src.as_ptr() as usize, src.len()) }
+}
+
+macro_rules! typed_user_access {
+    ($get:ident, $put:ident, $ty:ty, $load:literal, $store:literal) => {
+        #[doc = concat!("Reads a `", stringify!($ty), "` from the user address `ptr`.")]
+        ///
+        /// `ptr` must be naturally aligned. Returns the value and 0, or 0 and
+        /// the negated `EFAULT` as `usize` if it is misaligned or the read
+        /// faults.
+        #[inline]
+        pub fn $get(ptr: usize) -> ($ty, usize) {
+            let ptr = untagged_addr(ptr);
+            if ptr % core::mem::size_of::<$ty>() != 0 {
+                return (0, -(LinuxError::EFAULT as isize) as usize);
+            }
+            let mut _tmp = 0;
+            let mut x: $ty;
+            let mut err: usize = 0;
+            unsafe { core::arch::asm!(
+                "1:",
+                concat!("   ", $load, " {x}, ({ptr})"),
+                "2:",
+                "   .section .fixup,\"ax\"",
+                "   .balign 4",
+                "3:",
+                "   li {err}, {err_val}",
+                "   li {x}, 0",
+                "   jump 2b, {_tmp}",
+                "   .previous",
+                "   .section __ex_table,\"a\"",
+                "   .balign 8",
+                "   .dword 1b, 3b",
+                "   .previous",
+                err = inout(reg) err,
+                x = out(reg) x,
+                ptr = in(reg) ptr,
+                err_val = const (-(LinuxError::EFAULT as isize)),
+                _tmp = out(reg) _tmp,
+            )}
+            (x, err)
+        }
+
+        #[doc = concat!("Writes a `", stringify!($ty), "` to the user address `ptr`.")]
+        ///
+        /// `ptr` must be naturally aligned. Returns 0, or the negated `EFAULT`
+        /// as `usize` if it is misaligned or the write faults.
+        #[inline]
+        pub fn $put(x: $ty, ptr: usize) -> usize {
+            let ptr = untagged_addr(ptr);
+            if ptr % core::mem::size_of::<$ty>() != 0 {
+                return -(LinuxError::EFAULT as isize) as usize;
+            }
+            let mut _tmp = 0;
+            let mut err: usize = 0;
+            unsafe { core::arch::asm!(
+                "1:",
+                concat!("   ", $store, " {x}, ({ptr})"),
+                "2:",
+                "   .section .fixup,\"ax\"",
+                "   .balign 4",
+                "3:",
+                "   li {err}, {err_val}",
+                "   jump 2b, {_tmp}",
+                "   .previous",
+                "   .section __ex_table,\"a\"",
+                "   .balign 8",
+                "   .dword 1b, 3b",
+                "   .previous",
+                err = inout(reg) err,
+                x = in(reg) x,
+                ptr = in(reg) ptr,
+                err_val = const (-(LinuxError::EFAULT as isize)),
+                _tmp = out(reg) _tmp,
+            )}
+            err
+        }
+    };
+}
+
+typed_user_access!(__get_user_u16, __put_user_u16, u16, "lhu", "sh");
+typed_user_access!(__get_user_u32, __put_user_u32, u32, "lwu", "sw");
+typed_user_access!(__get_user_u64, __put_user_u64, u64, "ld", "sd");
+
+const ONES: u64 = u64::from_ne_bytes([0x01; 8]);
+const HIGHS: u64 = u64::from_ne_bytes([0x80; 8]);
+
+/// Returns whether any byte of `word` is zero.
+#[inline]
+const fn has_zero_byte(word: u64) -> bool {
+    word.wrapping_sub(ONES) & !word & HIGHS != 0
+}
+
+/// Scans the user string at `src` for at most `max` bytes, a word at a
+/// time, passing each chunk of bytes read to `f`.
+///
+/// The words are read at aligned addresses, so a read never touches a page
+/// that does not hold any of the scanned bytes. Returns the length of the
+/// string (without the NUL) if it is shorter than `max`, or `max` otherwise.
+/// Returns [`LinuxError::EFAULT`] if the string cannot be read, including
+/// if it reaches the end of user space before `max` bytes without a NUL.
+fn scan_user_str(
+    src: usize,
+    max: usize,
+    mut f: impl FnMut(usize, &[u8]),
+) -> Result<usize, LinuxError> {
+    if max == 0 {
+        return Ok(0);
+    }
+    let src = untagged_addr(src);
+    if !access_ok(src, 1) {
+        return Err(LinuxError::EFAULT);
+    }
+    // Stop at the end of user space, a longer string ends with a fault.
+    let limit = max.min(super::current_task_size() - src);
+    let mut len = 0;
+    while len < limit {
+        let addr = src + len;
+        let word_addr = addr & !7;
+        let (word, err) = __get_user_u64(word_addr);
+        if err != 0 {
+            return Err(LinuxError::EFAULT);
+        }
+        let bytes = word.to_ne_bytes();
+        let bytes = &bytes[addr - word_addr..];
+        let bytes = &bytes[..bytes.len().min(limit - len)];
+        if bytes.len() == 8 && !has_zero_byte(word) {
+            f(len, bytes);
+            len += 8;
+            continue;
+        }
+        match bytes.iter().position(|&b| b == 0) {
+            Some(pos) => {
+                f(len, &bytes[..pos]);
+                return Ok(len + pos);
+            }
+            None => {
+                f(len, bytes);
+                len += bytes.len();
+            }
+        }
+    }
+    if limit < max {
+        return Err(LinuxError::EFAULT);
+    }
+    Ok(max)
+}
+
+/// Copies the NUL-terminated string at the user address `src` to `dst`.
+///
+/// Returns the length of the string (without the NUL), the NUL is copied as
+/// well. If the string does not fit, `dst.len()` is returned and `dst` is not
+/// NUL-terminated, as in Linux. Returns [`LinuxError::EFAULT`] if the string
+/// cannot be read.
+pub fn strncpy_from_user(dst: &mut [u8], src: usize) -> Result<usize, LinuxError> {
+    let len = scan_user_str(src, dst.len(), |offset, bytes| {
+        dst[offset..offset + bytes.len()].copy_from_slice(bytes)
+    })?;
+    if len < dst.len() {
+        dst[len] = 0;
+    }
+    Ok(len)
+}
+
+/// Returns the size of the NUL-terminated string at the user address `src`,
+/// including the NUL, scanning at most `n` bytes.
+///
+/// As in Linux, it returns 0 if the string cannot be read, and a value
+/// greater than `n` if there is no NUL in the first `n` bytes.
+pub fn strnlen_user(src: usize, n: usize) -> usize {
+    match scan_user_str(src, n, |_, _| {}) {
+        Ok(len) if len < n => len + 1,
+        Ok(_) => n + 1,
+        Err(_) => 0,
+    }
+}
@@ -0,0 +1,198 @@
+//! Building the initial user stack on exec.
+
+use axerrno::LinuxError;
+use core::mem::size_of;
+
+use crate::mem::PAGE_SIZE_4K;
+use crate::trap::{user_redzone, user_stack_reserve, STACK_ALIGN};
+
+/// The maximum total size of the argv/envp strings and the pointer arrays
+/// (argc, argv, envp and auxv) on the initial user stack.
+pub const ARG_MAX: usize = 128 * 1024;
+
+/// Copies `src` to the user address `dst`, with fault fixup.
+fn put_user_bytes(dst: usize, src: &[u8]) -> Result<(), LinuxError> {
+    match super::copy_to_user(dst, src) {
+        0 => Ok(()),
+        _ => Err(LinuxError::EFAULT),
+    }
+}
+
+fn put_user_usize(dst: usize, value: usize) -> Result<(), LinuxError> {
+    put_user_bytes(dst, &value.to_ne_bytes())
+}
+
+/// Builds the initial user stack below `sp_top`, and returns the new `sp`.
+///
+/// From `sp` upwards, the layout is:
+///
+/// ```text
+/// argc
+/// argv[0] .. argv[argc - 1], NULL
+/// envp[0] .. envp[envc - 1], NULL
+/// auxv (key, value) pairs, (AT_NULL, 0)
+/// (padding)
+/// argv strings, envp strings (NUL-terminated)
+/// ```
+///
+/// `args` and `envs` are the strings without the NUL terminator, `auxv`
+/// does not include the terminating `AT_NULL` entry. The pointer arrays are
+/// placed with [`user_stack_reserve`], so `sp` is aligned as the ABI requires.
+///
+/// Returns [`LinuxError::E2BIG`] if the strings and the pointer arrays take
+/// more than [`ARG_MAX`] bytes, or [`LinuxError::EFAULT`] if the stack is not
+/// in user space or cannot be written. Nothing is copied for an over-limit
+/// input.
+pub fn build_user_stack(
+    sp_top: usize,
+    args: &[&[u8]],
+    envs: &[&[u8]],
+    auxv: &[(usize, usize)],
+) -> Result<usize, LinuxError> {
+    let strings_size = args
+        .iter()
+        .chain(envs)
+        .try_fold(0usize, |size, s| size.checked_add(s.len().checked_add(1)?))
+        .ok_or(LinuxError::E2BIG)?;
+    let words = 1 + (args.len() + 1) + (envs.len() + 1) + (auxv.len() + 1) * 2;
+    let ptrs_size = words
+        .checked_mul(size_of::<usize>())
+        .ok_or(LinuxError::E2BIG)?;
+    let total = strings_size
+        .checked_add(ptrs_size)
+        .ok_or(LinuxError::E2BIG)?;
+    if total > ARG_MAX {
+        return Err(LinuxError::E2BIG);
+    }
+    // The most `user_stack_reserve` may take, with the redzone and padding.
+    let reserve = total
+        .checked_add(user_redzone())
+        .and_then(|size| size.checked_add(STACK_ALIGN))
+        .ok_or(LinuxError::E2BIG)?;
+    if sp_top < reserve || !super::access_ok(sp_top - reserve, reserve) {
+        return Err(LinuxError::EFAULT);
+    }
+
+    // Strings at the top, argv first.
+    let strings_base = sp_top - strings_size;
+    let mut pos = strings_base;
+    for s in args.iter().chain(envs) {
+        put_user_bytes(pos, s)?;
+        put_user_bytes(pos + s.len(), &[0])?;
+        pos += s.len() + 1;
+    }
+
+    // Then the pointer arrays, from `sp` upwards.
+    let sp = user_stack_reserve(strings_base, ptrs_size).ok_or(LinuxError::EFAULT)?;
+    let mut slot = sp;
+    let mut push = |value: usize| -> Result<(), LinuxError> {
+        put_user_usize(slot, value)?;
+        slot += size_of::<usize>();
+        Ok(())
+    };
+    push(args.len())?;
+    let mut pos = strings_base;
+    for strings in [args, envs] {
+        for s in strings {
+            push(pos)?;
+            pos += s.len() + 1;
+        }
+        push(0)?;
+    }
+    for &(key, value) in auxv {
+        push(key)?;
+        push(value)?;
+    }
+    push(0)?; // AT_NULL
+    push(0)?;
+    Ok(sp)
+}
+
+/// The `AT_*` keys of the auxiliary vector set by [`setup_user_stack`].
+#[allow(missing_docs)]
+pub mod auxv {
+    pub const AT_NULL: usize = 0;
+    pub const AT_PHDR: usize = 3;
+    pub const AT_PHENT: usize = 4;
+    pub const AT_PHNUM: usize = 5;
+    pub const AT_PAGESZ: usize = 6;
+    pub const AT_BASE: usize = 7;
+    pub const AT_FLAGS: usize = 8;
+    pub const AT_ENTRY: usize = 9;
+    pub const AT_HWCAP: usize = 16;
+    pub const AT_CLKTCK: usize = 17;
+    pub const AT_SECURE: usize = 23;
+    pub const AT_RANDOM: usize = 25;
+    pub const AT_SYSINFO_EHDR: usize = 33;
+}
+
+/// The size of the random bytes that `AT_RANDOM` points to.
+const AT_RANDOM_SIZE: usize = 16;
+
+/// The clock ticks per second reported in `AT_CLKTCK` (`USER_HZ`).
+const USER_HZ: usize = 100;
+
+/// What the auxiliary vector describes of the executable being started.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ExecInfo {
+    /// The user address of the program headers of the executable.
+    pub phdr: usize,
+    /// The size of a program header.
+    pub phent: usize,
+    /// The number of program headers.
+    pub phnum: usize,
+    /// The entry point of the executable (not of its interpreter).
+    pub entry: usize,
+    /// The load address of the interpreter, 0 if there is none.
+    pub base: usize,
+    /// The user address of the vDSO text (see
+    /// [`VdsoImage`](super::VdsoImage)), 0 if it is not mapped.
+    pub vdso_base: usize,
+}
+
+/// Builds the initial user stack of an ELF executable below `sp_top`, and
+/// returns the new `sp`.
+///
+/// It is [`build_user_stack`] with the auxiliary vector of `exec`, plus
+/// `AT_PAGESZ`, `AT_CLKTCK`, `AT_HWCAP` (see [`elf_hwcap`](super::elf_hwcap))
+/// and `AT_RANDOM`, whose 16 bytes from [`random_bytes`](super::random_bytes)
+/// are placed at the top of the stack. `AT_SYSINFO_EHDR` is only there if
+/// the vDSO is mapped.
+///
+/// Returns the errors of [`build_user_stack`].
+pub fn setup_user_stack(
+    sp_top: usize,
+    args: &[&[u8]],
+    envs: &[&[u8]],
+    exec: &ExecInfo,
+) -> Result<usize, LinuxError> {
+    use self::auxv::*;
+    let random_addr = sp_top
+        .checked_sub(AT_RANDOM_SIZE)
+        .ok_or(LinuxError::EFAULT)?
+        & !(STACK_ALIGN - 1);
+    if !super::access_ok(random_addr, AT_RANDOM_SIZE) {
+        return Err(LinuxError::EFAULT);
+    }
+    let mut random = [0; AT_RANDOM_SIZE];
+    super::random_bytes(&mut random);
+    put_user_bytes(random_addr, &random)?;
+
+    let auxv = [
+        (AT_PHDR, exec.phdr),
+        (AT_PHENT, exec.phent),
+        (AT_PHNUM, exec.phnum),
+        (AT_PAGESZ, PAGE_SIZE_4K),
+        (AT_BASE, exec.base),
+        (AT_FLAGS, 0),
+        (AT_ENTRY, exec.entry),
+        (AT_HWCAP, super::elf_hwcap()),
+        (AT_CLKTCK, USER_HZ),
+        (AT_SECURE, 0),
+        (AT_RANDOM, random_addr),
+        (AT_SYSINFO_EHDR, exec.vdso_base),
+    ];
+    // No `AT_SYSINFO_EHDR` without a vDSO.
+    let len = auxv.len() - (exec.vdso_base == 0) as usize;
+    build_user_stack(random_addr, args, envs, &auxv[..len])
+}
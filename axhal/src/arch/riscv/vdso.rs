@@ -0,0 +1,336 @@
+//! The vDSO: a small shared object that the OS maps into each process, so
+//! that reading the clocks does not need a trap.
+//!
+//! It is a data page followed by a text page, both in the kernel image, that
+//! must be mapped in the same order at the same distance in user space
+//! ([`vdso_image`]): their code finds the data PC-relatively. The text page
+//! is a prebuilt ELF image (for `AT_SYSINFO_EHDR`), with:
+//!
+//! - `__vdso_rt_sigreturn`, the return trampoline of the signal handlers;
+//! - `__vdso_clock_gettime`, which reads `time` and converts it with the
+//!   data page for `CLOCK_REALTIME`, `CLOCK_MONOTONIC` and their variants,
+//!   and falls back to the syscall for the other clocks;
+//! - `__vdso_getcpu`, which is the syscall, as in Linux.
+//!
+//! The data page is under a sequence counter. It is updated with
+//! [`update_vdso_data`] when the wall clock is set, and on the timer
+//! interrupts. If it is older than 10 minutes, e.g. with the tick stopped,
+//! the code falls back to the syscall, as the conversion of the ticks would
+//! overflow. The code is RV64: the compat tasks use the syscalls.
+
+use core::sync::atomic::{fence, Ordering};
+use spinbase::SpinNoIrq;
+
+use crate::mem::{virt_to_phys, PhysAddr, PAGE_SIZE_4K};
+use crate::time::{current_ticks, ticks_to_nanos, timer_frequency, NANOS_PER_SEC};
+
+/// The size of the data page.
+pub const VDSO_DATA_SIZE: usize = PAGE_SIZE_4K;
+/// The size of the text (the ELF image).
+pub const VDSO_TEXT_SIZE: usize = PAGE_SIZE_4K;
+
+/// The longest time between two updates for which the conversion of the
+/// ticks since the last one does not overflow, in seconds.
+const MAX_UPDATE_INTERVAL_SECS: u64 = 600;
+
+/// The wall-clock time at boot, in nanoseconds. The lock also serializes the
+/// updates of the data page.
+static REALTIME_OFFSET_NS: SpinNoIrq<u64> = SpinNoIrq::new(0);
+
+/// The data page, as read by `__vdso_clock_gettime`.
+#[repr(C)]
+struct VdsoData {
+    /// Odd while being updated.
+    seq: u32,
+    /// 1 if the code can read `time`, 0 to make it fall back to the syscall.
+    clock_mode: u32,
+    /// The ticks at the last update.
+    cycle_last: u64,
+    /// The ticks are converted to nanoseconds as `(ticks * mult) >> shift`.
+    mult: u32,
+    shift: u32,
+    /// `CLOCK_MONOTONIC` at the last update, in nanoseconds.
+    monotonic_ns: u64,
+    /// `CLOCK_REALTIME` at the last update, in nanoseconds.
+    realtime_ns: u64,
+    /// The ticks of [`MAX_UPDATE_INTERVAL_SECS`]: the code falls back to the
+    /// syscall if more have passed since the last update.
+    max_delta: u64,
+}
+
+// The offsets of the fields of `VdsoData`.
+const VD_SEQ: usize = 0;
+const VD_CLOCK_MODE: usize = 4;
+const VD_CYCLE_LAST: usize = 8;
+const VD_MULT: usize = 16;
+const VD_SHIFT: usize = 20;
+const VD_MONOTONIC_NS: usize = 24;
+const VD_REALTIME_NS: usize = 32;
+const VD_MAX_DELTA: usize = 40;
+
+const NR_RT_SIGRETURN: usize = 139;
+const NR_GETCPU: usize = 168;
+const NR_CLOCK_GETTIME: usize = 113;
+
+/// The clocks of `__vdso_clock_gettime`, as a bit mask of their IDs:
+/// `CLOCK_REALTIME` (0), `CLOCK_MONOTONIC` (1), `CLOCK_MONOTONIC_RAW` (4),
+/// `CLOCK_REALTIME_COARSE` (5), `CLOCK_MONOTONIC_COARSE` (6) and
+/// `CLOCK_BOOTTIME` (7).
+const VDSO_CLOCKS: usize = 0b1111_0011;
+
+core::arch::global_asm!(
+    ".pushsection .data",
+    ".balign 4096",
+    ".global __vdso_data",
+    "__vdso_data:",
+    ".zero 4096",
+    ".global __vdso_start",
+    "__vdso_start:",
+    ".option push",
+    ".option norelax",
+    // ELF header: ELFCLASS64, ELFDATA2LSB, ET_DYN, EM_RISCV, RVC and the
+    // double-float ABI. No sections.
+    ".byte 0x7f, 0x45, 0x4c, 0x46, 2, 1, 1, 0",
+    ".byte 0, 0, 0, 0, 0, 0, 0, 0",
+    ".2byte 3, 243",
+    ".4byte 1",
+    ".dword 0",
+    ".dword 64",
+    ".dword 0",
+    ".4byte 0x5",
+    ".2byte 64, 56, 2, 64, 0, 0",
+    // PT_LOAD of the whole page, R+X.
+    ".4byte 1, 5",
+    ".dword 0, 0, 0, {text_size}, {text_size}, 4096",
+    // PT_DYNAMIC, R.
+    ".4byte 2, 4",
+    ".dword 3f - __vdso_start, 3f - __vdso_start, 3f - __vdso_start",
+    ".dword 4f - 3f, 4f - 3f, 8",
+    // .dynamic: DT_HASH, DT_STRTAB, DT_SYMTAB, DT_STRSZ, DT_SYMENT, DT_NULL.
+    ".balign 8",
+    "3:",
+    ".dword 4, 5f - __vdso_start",
+    ".dword 5, 7f - __vdso_start",
+    ".dword 6, 6f - __vdso_start",
+    ".dword 10, 8f - 7f",
+    ".dword 11, 24",
+    ".dword 0, 0",
+    "4:",
+    // .hash: a single bucket that chains the 3 symbols.
+    "5:",
+    ".4byte 1, 4",
+    ".4byte 1",
+    ".4byte 0, 2, 3, 0",
+    // .dynsym: STB_GLOBAL STT_FUNC, in the (nonexistent) section 1.
+    ".balign 8",
+    "6:",
+    ".4byte 0",
+    ".byte 0, 0",
+    ".2byte 0",
+    ".dword 0, 0",
+    ".4byte 71f - 7f",
+    ".byte 0x12, 0",
+    ".2byte 1",
+    ".dword __vdso_rt_sigreturn - __vdso_start, __vdso_getcpu - __vdso_rt_sigreturn",
+    ".4byte 72f - 7f",
+    ".byte 0x12, 0",
+    ".2byte 1",
+    ".dword __vdso_clock_gettime - __vdso_start, 9f - __vdso_clock_gettime",
+    ".4byte 73f - 7f",
+    ".byte 0x12, 0",
+    ".2byte 1",
+    ".dword __vdso_getcpu - __vdso_start, __vdso_clock_gettime - __vdso_getcpu",
+    // .dynstr
+    "7:",
+    ".byte 0",
+    "71:",
+    ".asciz \"__vdso_rt_sigreturn\"",
+    "72:",
+    ".asciz \"__vdso_clock_gettime\"",
+    "73:",
+    ".asciz \"__vdso_getcpu\"",
+    "8:",
+    // The code runs in U-mode, at any address.
+    ".balign 4",
+    ".global __vdso_rt_sigreturn",
+    "__vdso_rt_sigreturn:",
+    "   li      a7, {nr_rt_sigreturn}",
+    "   ecall",
+    "__vdso_getcpu:",
+    "   li      a7, {nr_getcpu}",
+    "   ecall",
+    "   ret",
+    // a0: clockid, a1: struct timespec *
+    "__vdso_clock_gettime:",
+    "   li      t0, 7",
+    "   bgtu    a0, t0, 20f",
+    "   li      t0, {vdso_clocks}",
+    "   srl     t0, t0, a0",
+    "   andi    t0, t0, 1",
+    "   beqz    t0, 20f",
+    "   lla     t6, __vdso_data",
+    "10:",
+    "   lw      t0, {vd_seq}(t6)",
+    "   andi    t1, t0, 1",
+    "   bnez    t1, 10b",
+    "   fence   r, r",
+    "   lwu     t1, {vd_clock_mode}(t6)",
+    "   beqz    t1, 20f",
+    "   rdtime  t2",
+    "   ld      t3, {vd_cycle_last}(t6)",
+    "   lwu     t4, {vd_mult}(t6)",
+    "   lwu     t5, {vd_shift}(t6)",
+    "   sub     t2, t2, t3",
+    "   ld      t3, {vd_max_delta}(t6)",
+    "   bgtu    t2, t3, 20f",
+    "   mul     t2, t2, t4",
+    "   srl     t2, t2, t5",
+    "   ld      t3, {vd_monotonic_ns}(t6)",
+    "   beqz    a0, 11f",
+    "   li      t4, 5",
+    "   bne     a0, t4, 12f",
+    "11:",
+    "   ld      t3, {vd_realtime_ns}(t6)",
+    "12:",
+    "   add     t2, t2, t3",
+    "   fence   r, r",
+    "   lw      t4, {vd_seq}(t6)",
+    "   bne     t0, t4, 10b",
+    "   li      t3, {nanos_per_sec}",
+    "   divu    t4, t2, t3",
+    "   remu    t5, t2, t3",
+    "   sd      t4, 0(a1)",
+    "   sd      t5, 8(a1)",
+    "   li      a0, 0",
+    "   ret",
+    "20:",
+    "   li      a7, {nr_clock_gettime}",
+    "   ecall",
+    "   ret",
+    "9:",
+    ".option pop",
+    ".balign 4096",
+    ".global __vdso_end",
+    "__vdso_end:",
+    ".popsection",
+    text_size = const VDSO_TEXT_SIZE,
+    nr_rt_sigreturn = const NR_RT_SIGRETURN,
+    nr_getcpu = const NR_GETCPU,
+    nr_clock_gettime = const NR_CLOCK_GETTIME,
+    vdso_clocks = const VDSO_CLOCKS,
+    vd_seq = const VD_SEQ,
+    vd_clock_mode = const VD_CLOCK_MODE,
+    vd_cycle_last = const VD_CYCLE_LAST,
+    vd_mult = const VD_MULT,
+    vd_shift = const VD_SHIFT,
+    vd_monotonic_ns = const VD_MONOTONIC_NS,
+    vd_realtime_ns = const VD_REALTIME_NS,
+    vd_max_delta = const VD_MAX_DELTA,
+    nanos_per_sec = const NANOS_PER_SEC,
+);
+
+extern "C" {
+    static mut __vdso_data: VdsoData;
+    static __vdso_start: u8;
+    static __vdso_rt_sigreturn: u8;
+}
+
+/// The pages of the vDSO, to map into each process.
+#[derive(Debug, Clone, Copy)]
+pub struct VdsoImage {
+    /// The physical address of the data page, to map read-only right below
+    /// the text.
+    pub data_paddr: PhysAddr,
+    /// The physical address of the text, to map readable and executable.
+    /// Its user address is the `AT_SYSINFO_EHDR` of the process.
+    pub text_paddr: PhysAddr,
+    /// The offset of `__vdso_rt_sigreturn` in the text, the restorer of the
+    /// signal handlers without `SA_RESTORER`.
+    pub sigreturn_offset: usize,
+}
+
+/// Returns the pages of the vDSO.
+pub fn vdso_image() -> VdsoImage {
+    let data = unsafe { core::ptr::addr_of!(__vdso_data) } as usize;
+    let text = unsafe { core::ptr::addr_of!(__vdso_start) } as usize;
+    let sigreturn = unsafe { core::ptr::addr_of!(__vdso_rt_sigreturn) } as usize;
+    VdsoImage {
+        data_paddr: virt_to_phys(data.into()),
+        text_paddr: virt_to_phys(text.into()),
+        sigreturn_offset: sigreturn - text,
+    }
+}
+
+/// Returns the `mult` and `shift` that convert ticks of `freq` Hz to
+/// nanoseconds, for up to [`MAX_UPDATE_INTERVAL_SECS`] of ticks.
+fn clock_mult_shift(freq: u64) -> (u32, u32) {
+    let max_ticks = freq * MAX_UPDATE_INTERVAL_SECS;
+    for shift in (0..=32).rev() {
+        let mult = ((NANOS_PER_SEC as u128) << shift) / freq as u128;
+        if mult <= u32::MAX as u128 && (max_ticks as u128) * mult <= u64::MAX as u128 {
+            return (mult as u32, shift);
+        }
+    }
+    (0, 0)
+}
+
+/// Writes the clocks now into the data page.
+fn write_vdso_data(realtime_offset_ns: u64) {
+    let ticks = current_ticks();
+    let monotonic_ns = ticks_to_nanos(ticks);
+    let data = unsafe { core::ptr::addr_of_mut!(__vdso_data) };
+    unsafe {
+        let seq = core::ptr::addr_of_mut!((*data).seq);
+        let count = seq.read_volatile();
+        seq.write_volatile(count.wrapping_add(1));
+        fence(Ordering::Release);
+        core::ptr::addr_of_mut!((*data).cycle_last).write_volatile(ticks);
+        core::ptr::addr_of_mut!((*data).monotonic_ns).write_volatile(monotonic_ns);
+        core::ptr::addr_of_mut!((*data).realtime_ns)
+            .write_volatile(monotonic_ns.wrapping_add(realtime_offset_ns));
+        fence(Ordering::Release);
+        seq.write_volatile(count.wrapping_add(2));
+    }
+}
+
+/// Updates the data page with the clocks now: `CLOCK_MONOTONIC` is the
+/// time since boot, and `CLOCK_REALTIME` is it plus `realtime_offset_ns`,
+/// the wall-clock time at boot, which the next updates keep.
+///
+/// [`set_epoch_ns`](crate::time::set_epoch_ns) calls it. The OS calls it
+/// when it sets the wall clock otherwise (e.g. without an RTC).
+pub fn update_vdso_data(realtime_offset_ns: u64) {
+    let mut offset = REALTIME_OFFSET_NS.lock();
+    *offset = realtime_offset_ns;
+    write_vdso_data(realtime_offset_ns);
+}
+
+/// Updates the data page with the clocks now, unless another CPU is doing
+/// it. Called on each timer interrupt.
+#[cfg(feature = "irq")]
+pub(crate) fn vdso_timer_tick() {
+    if let Some(offset) = REALTIME_OFFSET_NS.try_lock() {
+        write_vdso_data(*offset);
+    }
+}
+
+/// Fills the data page: the conversion of the ticks, and the clocks with the
+/// wall clock of the RTC, if any. The platform initialization calls it.
+///
+/// Without a `time` CSR readable from U-mode, the vDSO falls back to the
+/// syscalls.
+pub fn init_vdso() {
+    let freq = timer_frequency();
+    let (mult, shift) = clock_mult_shift(freq);
+    let data = unsafe { core::ptr::addr_of_mut!(__vdso_data) };
+    unsafe {
+        core::ptr::addr_of_mut!((*data).mult).write_volatile(mult);
+        core::ptr::addr_of_mut!((*data).shift).write_volatile(shift);
+        core::ptr::addr_of_mut!((*data).max_delta).write_volatile(freq * MAX_UPDATE_INTERVAL_SECS);
+        core::ptr::addr_of_mut!((*data).clock_mode).write_volatile((mult != 0) as u32);
+    }
+    let realtime_offset_ns = crate::time::read_epoch_ns().map_or(0, |epoch_ns| {
+        epoch_ns.saturating_sub(crate::time::current_time_nanos())
+    });
+    update_vdso_data(realtime_offset_ns);
+}
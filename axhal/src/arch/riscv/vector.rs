@@ -0,0 +1,260 @@
+//! Vector (V) extension registers, switched lazily with `sstatus.VS`.
+//!
+//! The size of the register file depends on `VLEN`, which is probed at boot,
+//! so the registers of a task are kept in a buffer given by the kernel (of
+//! [`vector_state_size`] bytes). A new task starts with `VS` off, and its
+//! vector unit is enabled by [`handle_vector_trap`] on its first vector
+//! instruction.
+
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use super::context::{set_vector_state, vector_state, FpuDirtyState};
+use super::{TaskContext, TrapFrame, SR_VS};
+
+/// `vlenb`, the vector register length in bytes, 0 if there is no V.
+static VLENB: AtomicUsize = AtomicUsize::new(0);
+
+/// The address of the [`VectorState`] loaded in the registers of this CPU,
+/// 0 if none.
+#[percpu2::def_percpu]
+static VECTOR_OWNER: usize = 0;
+
+const SR_VS_SHIFT: usize = SR_VS.trailing_zeros() as usize;
+
+/// Forgets the [`VectorState`] loaded in the registers of this CPU, after
+/// they were lost (in a non-retentive suspend).
+pub(super) fn forget_vector_owner() {
+    unsafe { VECTOR_OWNER.write_current_raw(0) };
+}
+
+fn set_live_vs(state: FpuDirtyState) {
+    unsafe {
+        let sstatus: usize;
+        core::arch::asm!("csrr {}, sstatus", out(reg) sstatus);
+        let sstatus = (sstatus & !SR_VS) | ((state as usize) << SR_VS_SHIFT);
+        core::arch::asm!("csrw sstatus, {}", in(reg) sstatus);
+    }
+}
+
+fn live_vs() -> FpuDirtyState {
+    let sstatus: usize;
+    unsafe { core::arch::asm!("csrr {}, sstatus", out(reg) sstatus) };
+    match (sstatus & SR_VS) >> SR_VS_SHIFT {
+        0 => FpuDirtyState::Off,
+        1 => FpuDirtyState::Initial,
+        2 => FpuDirtyState::Clean,
+        _ => FpuDirtyState::Dirty,
+    }
+}
+
+/// Probes `VLEN` if all CPUs support V, called by
+/// [`early_init`](super::early_init).
+pub(super) fn probe_vector() {
+    if !super::cpu_has(super::Feature::V) {
+        return;
+    }
+    let vlenb: usize;
+    unsafe {
+        set_live_vs(FpuDirtyState::Initial);
+        core::arch::asm!("csrr {}, vlenb", out(reg) vlenb);
+        set_live_vs(FpuDirtyState::Off);
+    }
+    info!("Vector extension: VLEN = {}", vlenb * 8);
+    VLENB.store(vlenb, Ordering::Relaxed);
+}
+
+/// Returns whether all CPUs support the vector extension.
+#[inline]
+pub fn has_vector() -> bool {
+    VLENB.load(Ordering::Relaxed) != 0
+}
+
+/// Returns the size of the buffer for the vector registers of a task, 0 if
+/// there is no V.
+#[inline]
+pub fn vector_state_size() -> usize {
+    32 * VLENB.load(Ordering::Relaxed)
+}
+
+/// Vector registers of a task.
+#[repr(C)]
+#[derive(Debug, Default)]
+pub struct VectorState {
+    /// Vector start position (`vstart`).
+    pub vstart: usize,
+    /// Vector data type register (`vtype`).
+    pub vtype: usize,
+    /// Vector length (`vl`).
+    pub vl: usize,
+    /// Vector control and status register (`vcsr`).
+    pub vcsr: usize,
+    /// The buffer of `v0`..`v31`, [`vector_state_size`] bytes.
+    datap: usize,
+    /// The task has used the vector unit, i.e. the registers must be
+    /// restored.
+    used: bool,
+    /// The CPU whose registers were last loaded with this state.
+    last_cpu: AtomicUsize,
+}
+
+impl VectorState {
+    /// Loads the registers from `self`, and makes it the owner of this CPU.
+    fn restore(&self) {
+        set_live_vs(FpuDirtyState::Clean);
+        unsafe {
+            vstate_restore(self);
+            VECTOR_OWNER.write_current_raw(self as *const _ as usize);
+        }
+        self.last_cpu
+            .store(crate::cpu::_this_cpu_id(), Ordering::Relaxed);
+    }
+
+    /// Whether the registers of this CPU still hold `self`.
+    fn is_loaded(&self) -> bool {
+        self.last_cpu.load(Ordering::Relaxed) == crate::cpu::_this_cpu_id()
+            && unsafe { VECTOR_OWNER.read_current_raw() } == self as *const _ as usize
+    }
+
+    /// Returns the buffer of the registers, if the task has one.
+    pub(super) fn data_mut(&mut self) -> Option<&mut [u8]> {
+        (self.datap != 0).then(|| unsafe {
+            core::slice::from_raw_parts_mut(self.datap as *mut u8, vector_state_size())
+        })
+    }
+
+    /// Saves the registers of this CPU to `self` if they hold it and have
+    /// changed, so that `self` is up to date (e.g. for a signal frame).
+    pub(super) fn sync(&mut self) {
+        if has_vector() && self.datap != 0 && self.is_loaded() && live_vs().needs_save() {
+            unsafe { vstate_save(self) };
+            set_live_vs(FpuDirtyState::Clean);
+        }
+    }
+
+    /// Makes `self`, just written, the registers of a task that is not
+    /// running: they are loaded on its next switch or use.
+    pub(super) fn set_changed(&mut self) {
+        self.used = true;
+        self.last_cpu.store(usize::MAX, Ordering::Relaxed);
+    }
+
+    /// Makes `self`, just written, the registers of the current task of the
+    /// trap `tf`: loaded now if its unit is on, or on its next use.
+    pub(super) fn reload(&mut self, tf: &mut TrapFrame) {
+        if !has_vector() || self.datap == 0 {
+            return;
+        }
+        self.used = true;
+        if vector_state(tf) != FpuDirtyState::Off {
+            self.restore();
+            set_vector_state(tf, FpuDirtyState::Clean);
+        }
+    }
+
+    pub(super) fn switch_to(&mut self, next: &VectorState) {
+        if !has_vector() {
+            return;
+        }
+        // Lazy save: only the registers changed since they were last loaded.
+        if self.datap != 0 && live_vs().needs_save() {
+            unsafe { vstate_save(self) };
+            set_live_vs(FpuDirtyState::Clean);
+        }
+        // The first use of a new task is left to `handle_vector_trap`.
+        if next.used && !next.is_loaded() {
+            next.restore();
+        }
+    }
+}
+
+/// Gives the task of `ctx` the buffer for its vector registers, which must
+/// be [`vector_state_size`] bytes and stay valid as long as the task.
+///
+/// The buffer is zeroed, as the initial values of the registers.
+///
+/// # Safety
+///
+/// `buf` must be valid for writes of [`vector_state_size`] bytes.
+pub unsafe fn set_vector_buffer(ctx: &mut TaskContext, buf: *mut u8) {
+    core::ptr::write_bytes(buf, 0, vector_state_size());
+    ctx.vector_state.datap = buf as usize;
+}
+
+/// Handles the trap of the first vector instruction of the task of `ctx`,
+/// which starts with `sstatus.VS` off.
+///
+/// It must be called on an illegal instruction exception from U-mode. If
+/// the CPU supports V, the task has a buffer and `VS` is off in `tf`, it
+/// loads the registers of the task, sets `VS` to
+/// [`Clean`](FpuDirtyState::Clean) and returns `true`: the instruction is
+/// retried when returning to user space. Returns `false` otherwise.
+pub fn handle_vector_trap(tf: &mut TrapFrame, ctx: &mut TaskContext) -> bool {
+    if !has_vector() || ctx.vector_state.datap == 0 || vector_state(tf) != FpuDirtyState::Off {
+        return false;
+    }
+    ctx.vector_state.used = true;
+    ctx.vector_state.restore();
+    set_vector_state(tf, FpuDirtyState::Clean);
+    true
+}
+
+#[naked]
+unsafe extern "C" fn vstate_save(_vstate: &mut VectorState) {
+    core::arch::asm!(
+        "
+        .option push
+        .option arch, +v
+        csrr    t0, vstart
+        csrr    t1, vtype
+        csrr    t2, vl
+        csrr    t3, vcsr
+        sd      t0, 0 * 8(a0)
+        sd      t1, 1 * 8(a0)
+        sd      t2, 2 * 8(a0)
+        sd      t3, 3 * 8(a0)
+        ld      a1, 4 * 8(a0)
+        vsetvli t4, x0, e8, m8, ta, ma
+        vs8r.v  v0, (a1)
+        add     a1, a1, t4
+        vs8r.v  v8, (a1)
+        add     a1, a1, t4
+        vs8r.v  v16, (a1)
+        add     a1, a1, t4
+        vs8r.v  v24, (a1)
+        // The registers stay loaded: put back the `vl`, `vtype` and
+        // `vstart` of the task, clobbered by the `vsetvli` above.
+        vsetvl  x0, t2, t1
+        csrw    vstart, t0
+        .option pop
+        ret",
+        options(noreturn),
+    )
+}
+
+#[naked]
+unsafe extern "C" fn vstate_restore(_vstate: &VectorState) {
+    core::arch::asm!(
+        "
+        .option push
+        .option arch, +v
+        ld      a1, 4 * 8(a0)
+        vsetvli t4, x0, e8, m8, ta, ma
+        vl8re8.v v0, (a1)
+        add     a1, a1, t4
+        vl8re8.v v8, (a1)
+        add     a1, a1, t4
+        vl8re8.v v16, (a1)
+        add     a1, a1, t4
+        vl8re8.v v24, (a1)
+        ld      t0, 0 * 8(a0)
+        ld      t1, 1 * 8(a0)
+        ld      t2, 2 * 8(a0)
+        ld      t3, 3 * 8(a0)
+        vsetvl  x0, t2, t1
+        csrw    vstart, t0
+        csrw    vcsr, t3
+        .option pop
+        ret",
+        options(noreturn),
+    )
+}
@@ -0,0 +1,159 @@
+//! The vectored mode of `stvec`.
+//!
+//! In vectored mode, an interrupt of cause `n` jumps to `BASE + 4 * n`, and
+//! all the exceptions jump to `BASE`. [`__trap_vector_table`] has a
+//! dedicated entry for each of the local interrupts (software, timer,
+//! external and counter-overflow), which saves the trap frame and dispatches
+//! the IRQ directly on the IRQ stack, without decoding `scause`. The exceptions and the
+//! other causes go through the trap handler of the kernel, as in direct
+//! mode.
+//!
+//! It is only enabled with the `vectored-trap` feature and
+//! [`set_trap_vector_vectored`], as some CPUs have errata with it.
+
+use lazy_init::LazyInit;
+
+use super::TrapFrame;
+use crate::trap::TRAPFRAME_SIZE;
+
+include_asm_marcos!();
+
+/// The handlers called by the vectored entries.
+pub struct VectoredTrapHandlers {
+    /// Handles a trap as in direct mode (decoding `scause`), with whether it
+    /// came from U-mode.
+    pub trap: fn(&mut TrapFrame, bool),
+    /// Called after an interrupt has been dispatched and the IRQ exit hooks
+    /// have run, before returning to the trapped code, e.g. to reschedule or
+    /// to deliver signals.
+    pub irq_return: fn(&mut TrapFrame, bool),
+}
+
+static HANDLERS: LazyInit<VectoredTrapHandlers> = LazyInit::new();
+
+/// The `cause` argument of [`vectored_trap_dispatch`] for the traps handled
+/// as in direct mode.
+const CAUSE_GENERIC: usize = usize::MAX;
+
+extern "C" fn vectored_trap_dispatch(tf: &mut TrapFrame, cause: usize, from_user: bool) {
+    if cause == CAUSE_GENERIC {
+        (HANDLERS.trap)(tf, from_user);
+        return;
+    }
+    let cpu = crate::percpu::current_cpu_data();
+    let old_regs = super::set_irq_regs(tf);
+    cpu.irq_enter();
+    super::call_on_irq_stack(
+        crate::platform::irq::dispatch_irq,
+        (1 << (usize::BITS - 1)) | cause,
+    );
+    cpu.irq_exit();
+    super::set_irq_regs(old_regs);
+    drop(cpu);
+    crate::irq::run_irq_exit_hooks();
+    (HANDLERS.irq_return)(tf, from_user);
+}
+
+// The same entry as the direct-mode vector of the kernel, for `cause`.
+core::arch::global_asm!(
+    r"
+    .macro VECTORED_ENTRY, cause
+        csrrw   sp, sscratch, sp
+        bnez    sp, 1f
+
+        csrr    sp, sscratch
+        addi    sp, sp, -{trapframe_size}
+        SAVE_REGS 0
+        mv      a0, sp
+        li      a1, \cause
+        li      a2, 0
+        call    {dispatch}
+        RESTORE_REGS 0
+        sret
+
+    1:
+        addi    sp, sp, -{trapframe_size}
+        SAVE_REGS 1
+        mv      a0, sp
+        li      a1, \cause
+        li      a2, 1
+        call    {dispatch}
+        addi    t0, sp, {trapframe_size}
+        csrw    sscratch, t0
+        RESTORE_REGS 1
+        sret
+    .endm
+
+    .section .text
+    .balign 256
+    .global __trap_vector_table
+    __trap_vector_table:
+    .option push
+    .option norvc
+        j       .Lvectored_generic          // exceptions
+        j       .Lvectored_soft             // supervisor software
+        j       .Lvectored_generic
+        j       .Lvectored_generic
+        j       .Lvectored_generic
+        j       .Lvectored_timer            // supervisor timer
+        j       .Lvectored_generic
+        j       .Lvectored_generic
+        j       .Lvectored_generic
+        j       .Lvectored_external         // supervisor external
+        j       .Lvectored_generic
+        j       .Lvectored_generic
+        j       .Lvectored_generic
+        j       .Lvectored_counter_overflow // local counter-overflow
+        j       .Lvectored_generic
+        j       .Lvectored_generic
+    .option pop
+
+    .Lvectored_generic:
+        VECTORED_ENTRY -1
+    .Lvectored_soft:
+        VECTORED_ENTRY 1
+    .Lvectored_timer:
+        VECTORED_ENTRY 5
+    .Lvectored_external:
+        VECTORED_ENTRY 9
+    .Lvectored_counter_overflow:
+        VECTORED_ENTRY 13
+
+    .purgem VECTORED_ENTRY
+    ",
+    dispatch = sym vectored_trap_dispatch,
+    trapframe_size = const TRAPFRAME_SIZE,
+);
+
+extern "C" {
+    /// The vector table installed by [`set_trap_vector_vectored`].
+    pub fn __trap_vector_table();
+}
+
+/// Registers the handlers of the vectored entries.
+///
+/// It must be called before [`set_trap_vector_vectored`].
+pub fn init_vectored_traps(handlers: VectoredTrapHandlers) {
+    HANDLERS.init_by(handlers);
+}
+
+/// Installs [`__trap_vector_table`] in `stvec` in vectored mode, on the
+/// current CPU.
+///
+/// Returns `false` (and leaves `stvec` in direct mode at the table, which
+/// then handles everything as in direct mode) if the CPU does not
+/// implement the vectored mode.
+pub fn set_trap_vector_vectored() -> bool {
+    const MODE_VECTORED: usize = 1;
+    let base = __trap_vector_table as usize;
+    let stvec: usize;
+    unsafe {
+        core::arch::asm!(
+            "csrw stvec, {0}",
+            "csrr {1}, stvec",
+            in(reg) base | MODE_VECTORED,
+            out(reg) stvec,
+        );
+    }
+    stvec & 0b11 == MODE_VECTORED
+}
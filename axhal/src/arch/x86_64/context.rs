@@ -41,6 +41,13 @@ impl TrapFrame {
     pub const fn is_user(&self) -> bool {
         self.cs & 0b11 == 3
     }
+
+    /// Returns whether the trap came from user mode, as on the other
+    /// architectures.
+    #[inline]
+    pub const fn from_user(&self) -> bool {
+        self.is_user()
+    }
 }
 
 #[repr(C)]
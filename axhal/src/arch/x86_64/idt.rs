@@ -0,0 +1,75 @@
+use core::fmt;
+
+use x86_64::addr::VirtAddr;
+use x86_64::structures::idt::{Entry, HandlerFunc, InterruptDescriptorTable};
+use x86_64::structures::DescriptorTablePointer;
+use x86_64::PrivilegeLevel;
+
+/// The number of interrupt vectors.
+pub const NUM_INT: usize = 256;
+
+/// Vector of the breakpoint exception (`int3`).
+const BREAKPOINT_VECTOR: usize = 0x3;
+/// Vector of the legacy syscall gate (`int 0x80`).
+const LEGACY_SYSCALL_VECTOR: usize = 0x80;
+
+/// A wrapper of the Interrupt Descriptor Table (IDT).
+#[repr(transparent)]
+pub struct IdtStruct {
+    table: InterruptDescriptorTable,
+}
+
+impl IdtStruct {
+    /// Constructs a new IDT struct whose entry `i` jumps to `handlers[i]`,
+    /// the trap entries of each vector.
+    ///
+    /// The breakpoint and the legacy syscall vectors can be raised from
+    /// ring 3, the others only by the hardware or the kernel.
+    pub fn new(handlers: &[usize; NUM_INT]) -> Self {
+        let mut idt = Self {
+            table: InterruptDescriptorTable::new(),
+        };
+        let entries = unsafe {
+            core::slice::from_raw_parts_mut(
+                &mut idt.table as *mut _ as *mut Entry<HandlerFunc>,
+                NUM_INT,
+            )
+        };
+        for (i, entry) in entries.iter_mut().enumerate() {
+            let handler: HandlerFunc = unsafe { core::mem::transmute(handlers[i]) };
+            let opt = entry.set_handler_fn(handler);
+            if i == BREAKPOINT_VECTOR || i == LEGACY_SYSCALL_VECTOR {
+                opt.set_privilege_level(PrivilegeLevel::Ring3);
+            }
+        }
+        idt
+    }
+
+    /// Returns the IDT pointer (base and limit) that can be used in `lidt`
+    /// instruction.
+    pub fn pointer(&self) -> DescriptorTablePointer {
+        DescriptorTablePointer {
+            base: VirtAddr::new(&self.table as *const _ as u64),
+            limit: (core::mem::size_of::<InterruptDescriptorTable>() - 1) as u16,
+        }
+    }
+
+    /// Loads the IDT into the CPU (executes the `lidt` instruction).
+    ///
+    /// # Safety
+    ///
+    /// This function is unsafe because it manipulates the CPU's privileged
+    /// states.
+    pub unsafe fn load(&'static self) {
+        self.table.load();
+    }
+}
+
+impl fmt::Debug for IdtStruct {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("IdtStruct")
+            .field("pointer", &self.pointer())
+            .field("table", &self.table)
+            .finish()
+    }
+}
@@ -1,5 +1,7 @@
 mod context;
 mod gdt;
+mod idt;
+mod syscall;
 
 pub mod sysno;
 
@@ -13,11 +15,20 @@ use x86_64::instructions::interrupts;
 
 pub use self::context::{start_thread, ExtendedState, FxsaveArea, TaskContext, TrapFrame};
 pub use self::gdt::GdtStruct;
+pub use self::idt::{IdtStruct, NUM_INT};
+pub use self::syscall::init_syscall;
 pub use x86_64::structures::tss::TaskStateSegment;
 pub const TASK_SIZE: usize = 0x40_0000_0000;
 pub const STACK_SIZE: usize = 32 * PAGE_SIZE_4K;
 pub const STACK_TOP: usize = TASK_SIZE;
 
+/// Size of the area below the user stack pointer that the kernel must not
+/// touch when it pushes data onto a user stack.
+///
+/// The System V x86_64 ABI reserves 128 bytes below `rsp` for leaf functions,
+/// which may live there without adjusting `rsp`.
+pub const USER_REDZONE: usize = 128;
+
 pub const TASK_UNMAPPED_BASE: usize = (TASK_SIZE / 3) & !(PAGE_SIZE_4K - 1);
 /*
  * This is the location that an ET_DYN program is loaded if exec'ed.
@@ -121,6 +132,24 @@ pub unsafe fn write_thread_pointer(fs_base: usize) {
     unsafe { msr::wrmsr(msr::IA32_FS_BASE, fs_base as u64) }
 }
 
+/// Reads the `GS` base of the kernel that `swapgs` exchanges with
+/// (`IA32_KERNEL_GS_BASE`).
+#[inline]
+pub fn read_kernel_gs_base() -> usize {
+    unsafe { msr::rdmsr(msr::IA32_KERNEL_GSBASE) as usize }
+}
+
+/// Writes the `GS` base that `swapgs` exchanges with
+/// (`IA32_KERNEL_GS_BASE`).
+///
+/// # Safety
+///
+/// This function is unsafe as it changes the CPU states.
+#[inline]
+pub unsafe fn write_kernel_gs_base(gs_base: usize) {
+    msr::wrmsr(msr::IA32_KERNEL_GSBASE, gs_base as u64)
+}
+
 pub unsafe fn write_page_table_root0(root_paddr: PhysAddr) {
     write_page_table_root(root_paddr)
 }
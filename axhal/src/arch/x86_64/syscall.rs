@@ -0,0 +1,35 @@
+use x86_64::registers::model_specific::{Efer, EferFlags, LStar, SFMask, Star};
+use x86_64::registers::rflags::RFlags;
+use x86_64::VirtAddr;
+
+use super::GdtStruct;
+
+/// Enables the `syscall`/`sysret` instructions on the current CPU, with
+/// `entry` as the `syscall` entry point.
+///
+/// `STAR` takes the selectors from [`GdtStruct`]: `syscall` enters with the
+/// 64-bit kernel code and data segments, and `sysret` returns with the
+/// 64-bit user code and data segments. Interrupts, single-stepping, the
+/// direction flag and the alignment check are cleared on entry (`SFMASK`).
+///
+/// # Safety
+///
+/// This function is unsafe because it manipulates the CPU's privileged
+/// states. `entry` must switch to the kernel stack before it uses any.
+pub unsafe fn init_syscall(entry: usize) {
+    LStar::write(VirtAddr::new(entry as u64));
+    Star::write(
+        GdtStruct::UCODE64_SELECTOR,
+        GdtStruct::UDATA_SELECTOR,
+        GdtStruct::KCODE64_SELECTOR,
+        GdtStruct::KDATA_SELECTOR,
+    )
+    .unwrap();
+    SFMask::write(
+        RFlags::INTERRUPT_FLAG
+            | RFlags::TRAP_FLAG
+            | RFlags::DIRECTION_FLAG
+            | RFlags::ALIGNMENT_CHECK,
+    );
+    Efer::update(|efer| *efer |= EferFlags::SYSTEM_CALL_EXTENSIONS);
+}
@@ -1,5 +1,7 @@
 //! CPU-related operations.
 
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+
 #[percpu2::def_percpu]
 static CPU_ID: usize = 0;
 
@@ -17,6 +19,30 @@ pub fn _this_cpu_id() -> usize {
     unsafe { CPU_ID.read_current_raw() }
 }
 
+/// Returns the logical ID of the current CPU.
+///
+/// The result is only stable while preemption is disabled, otherwise the
+/// task may migrate right after the call.
+#[inline]
+pub fn cpu_id() -> usize {
+    _this_cpu_id()
+}
+
+/// Returns the number of usable CPUs: the enabled harts of the device tree
+/// (at most [`axconfig::SMP`]) on RISC-V, [`axconfig::SMP`] otherwise.
+pub fn cpu_count() -> usize {
+    #[cfg(any(target_arch = "riscv32", target_arch = "riscv64"))]
+    {
+        (0..axconfig::SMP)
+            .filter(|&cpu_id| cpu_to_hartid(cpu_id).is_some())
+            .count()
+    }
+    #[cfg(not(any(target_arch = "riscv32", target_arch = "riscv64")))]
+    {
+        axconfig::SMP
+    }
+}
+
 /// Safety: Makesure that it will be called under No-Preemption.
 ///
 /// Returns whether the current CPU is the primary CPU (aka the bootstrap
@@ -37,9 +63,13 @@ pub fn current_task_ptr<T>() -> *const T {
         // on x86, only one instruction is needed to read the per-CPU task pointer from `gs:[off]`.
         CURRENT_TASK_PTR.read_current_raw() as _
     }
-    #[cfg(any(target_arch = "riscv32", target_arch = "riscv64"))]
+    #[cfg(any(
+        target_arch = "riscv32",
+        target_arch = "riscv64",
+        target_arch = "loongarch64"
+    ))]
     unsafe {
-        // on RISC-V, reading `CURRENT_TASK_PTR` requires multiple instruction, so we disable local IRQs.
+        // on RISC-V and LoongArch, reading `CURRENT_TASK_PTR` requires multiple instruction, so we disable local IRQs.
         let _guard = kernel_guard_base::IrqSave::new();
         CURRENT_TASK_PTR.read_current_raw() as _
     }
@@ -65,7 +95,11 @@ pub unsafe fn set_current_task_ptr<T>(ptr: *const T) {
     {
         CURRENT_TASK_PTR.write_current_raw(ptr as usize)
     }
-    #[cfg(any(target_arch = "riscv32", target_arch = "riscv64"))]
+    #[cfg(any(
+        target_arch = "riscv32",
+        target_arch = "riscv64",
+        target_arch = "loongarch64"
+    ))]
     {
         let _guard = kernel_guard_base::IrqSave::new();
         CURRENT_TASK_PTR.write_current_raw(ptr as usize)
@@ -77,6 +111,172 @@ pub unsafe fn set_current_task_ptr<T>(ptr: *const T) {
     }
 }
 
+#[allow(clippy::declare_interior_mutable_const)]
+const NEED_RESCHED_INIT: AtomicBool = AtomicBool::new(false);
+
+/// The `need_resched` flag of each CPU, indexed by the logical CPU ID.
+///
+/// They are atomics rather than plain per-CPU variables, as a remote CPU may
+/// set the flag while the owner is checking it.
+static NEED_RESCHED: [AtomicBool; axconfig::SMP] = [NEED_RESCHED_INIT; axconfig::SMP];
+
+/// Requests a reschedule on the current CPU.
+///
+/// It is usually called by the timer handler, or by the IPI handler when a
+/// remote CPU asks this one to reschedule.
+#[inline]
+pub fn set_need_resched() {
+    set_need_resched_on(_this_cpu_id());
+}
+
+/// Requests a reschedule on the given CPU.
+///
+/// It only sets the flag, the caller should then kick the target CPU (e.g.,
+/// by an IPI) so that it checks the flag soon.
+#[inline]
+pub fn set_need_resched_on(cpu_id: usize) {
+    NEED_RESCHED[cpu_id].store(true, Ordering::Release);
+}
+
+/// Atomically reads and clears the `need_resched` flag of the current CPU.
+///
+/// Returns whether a reschedule was requested. A request that arrives right
+/// after this call is kept for the next check, instead of being lost as a
+/// separate load and store would do.
+///
+/// Safety: Makesure that it will be called under No-Preemption.
+#[inline]
+pub fn take_need_resched() -> bool {
+    NEED_RESCHED[_this_cpu_id()].swap(false, Ordering::AcqRel)
+}
+
+const CPUMASK_BITS: usize = usize::BITS as usize;
+const CPUMASK_WORDS: usize = (axconfig::SMP + CPUMASK_BITS - 1) / CPUMASK_BITS;
+
+/// A set of logical CPUs.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct CpuMask {
+    bits: [usize; CPUMASK_WORDS],
+}
+
+impl CpuMask {
+    /// Creates an empty set.
+    pub const fn new() -> Self {
+        Self {
+            bits: [0; CPUMASK_WORDS],
+        }
+    }
+
+    /// Returns whether `cpu_id` is in the set.
+    pub const fn contains(&self, cpu_id: usize) -> bool {
+        cpu_id < axconfig::SMP
+            && self.bits[cpu_id / CPUMASK_BITS] & (1 << (cpu_id % CPUMASK_BITS)) != 0
+    }
+
+    /// Adds `cpu_id` to the set.
+    pub fn insert(&mut self, cpu_id: usize) {
+        if cpu_id < axconfig::SMP {
+            self.bits[cpu_id / CPUMASK_BITS] |= 1 << (cpu_id % CPUMASK_BITS);
+        }
+    }
+
+    /// Removes `cpu_id` from the set.
+    pub fn remove(&mut self, cpu_id: usize) {
+        if cpu_id < axconfig::SMP {
+            self.bits[cpu_id / CPUMASK_BITS] &= !(1 << (cpu_id % CPUMASK_BITS));
+        }
+    }
+
+    /// Returns the number of CPUs in the set.
+    pub fn count(&self) -> usize {
+        self.bits.iter().map(|w| w.count_ones() as usize).sum()
+    }
+
+    /// Returns whether the set is empty.
+    pub fn is_empty(&self) -> bool {
+        self.bits.iter().all(|&w| w == 0)
+    }
+
+    /// Returns an iterator over the CPUs in the set, in ascending order.
+    pub fn iter(&self) -> impl Iterator<Item = usize> + '_ {
+        (0..axconfig::SMP).filter(move |&cpu_id| self.contains(cpu_id))
+    }
+}
+
+#[allow(clippy::declare_interior_mutable_const)]
+const CPU_ONLINE_INIT: AtomicUsize = AtomicUsize::new(0);
+
+/// The online bitmap, bit `i` is set if the logical CPU `i` is online.
+static CPU_ONLINE: [AtomicUsize; CPUMASK_WORDS] = [CPU_ONLINE_INIT; CPUMASK_WORDS];
+
+fn set_cpu_online(cpu_id: usize, online: bool) {
+    let word = &CPU_ONLINE[cpu_id / CPUMASK_BITS];
+    let bit = 1 << (cpu_id % CPUMASK_BITS);
+    if online {
+        word.fetch_or(bit, Ordering::Release);
+    } else {
+        word.fetch_and(!bit, Ordering::Release);
+    }
+}
+
+/// Returns whether the logical CPU `cpu_id` is online.
+///
+/// Cross-CPU operations (TLB shootdown, `on_each_cpu`, the watchdog) must
+/// only wait on online CPUs.
+#[inline]
+pub fn cpu_online(cpu_id: usize) -> bool {
+    cpu_id < axconfig::SMP
+        && CPU_ONLINE[cpu_id / CPUMASK_BITS].load(Ordering::Acquire)
+            & (1 << (cpu_id % CPUMASK_BITS))
+            != 0
+}
+
+/// Returns a snapshot of the online CPUs.
+///
+/// A CPU may come online or go offline right after the snapshot is taken.
+pub fn online_cpus() -> CpuMask {
+    let mut mask = CpuMask::new();
+    for (bits, word) in mask.bits.iter_mut().zip(CPU_ONLINE.iter()) {
+        *bits = word.load(Ordering::Acquire);
+    }
+    mask
+}
+
+/// Returns the number of online CPUs.
+pub fn num_online_cpus() -> usize {
+    CPU_ONLINE
+        .iter()
+        .map(|word| word.load(Ordering::Acquire).count_ones() as usize)
+        .sum()
+}
+
+/// Marks the current CPU offline.
+///
+/// It must be called on the CPU being taken down, with interrupts disabled,
+/// before it stops responding to IPIs.
+pub fn cpu_offline() {
+    set_cpu_online(_this_cpu_id(), false);
+}
+
+/// Marks the current CPU online again, when it resumes after
+/// [`cpu_offline`] without going through [`init_secondary`].
+#[cfg(all(feature = "smp", any(target_arch = "riscv32", target_arch = "riscv64")))]
+pub(crate) fn cpu_back_online() {
+    set_cpu_online(_this_cpu_id(), true);
+}
+
+/// Returns the hardware ID of the logical CPU `cpu_id`.
+fn cpu_hwid(cpu_id: usize) -> usize {
+    #[cfg(any(target_arch = "riscv32", target_arch = "riscv64"))]
+    {
+        cpu_to_hartid(cpu_id).unwrap_or(cpu_id)
+    }
+    #[cfg(not(any(target_arch = "riscv32", target_arch = "riscv64")))]
+    {
+        cpu_id
+    }
+}
+
 #[allow(dead_code)]
 /// Initializes the primary CPU.
 pub fn init_primary(cpu_id: usize) {
@@ -86,6 +286,12 @@ pub fn init_primary(cpu_id: usize) {
         CPU_ID.write_current_raw(cpu_id);
         IS_BSP.write_current_raw(true);
     }
+    #[cfg(any(target_arch = "riscv32", target_arch = "riscv64"))]
+    crate::arch::init_stack_guard(cpu_id);
+    #[cfg(any(target_arch = "riscv32", target_arch = "riscv64"))]
+    crate::arch::init_stack_canary(true);
+    crate::percpu::init_percpu(cpu_hwid(cpu_id));
+    set_cpu_online(cpu_id, true);
 }
 
 #[allow(dead_code)]
@@ -96,4 +302,109 @@ pub fn init_secondary(cpu_id: usize) {
         CPU_ID.write_current_raw(cpu_id);
         IS_BSP.write_current_raw(false);
     }
+    #[cfg(any(target_arch = "riscv32", target_arch = "riscv64"))]
+    crate::arch::init_stack_guard(cpu_id);
+    #[cfg(any(target_arch = "riscv32", target_arch = "riscv64"))]
+    crate::arch::init_stack_canary(false);
+    crate::percpu::init_percpu(cpu_hwid(cpu_id));
+    set_cpu_online(cpu_id, true);
+}
+
+#[cfg(any(target_arch = "riscv32", target_arch = "riscv64"))]
+const INVALID_HARTID: usize = usize::MAX;
+
+#[cfg(any(target_arch = "riscv32", target_arch = "riscv64"))]
+#[allow(clippy::declare_interior_mutable_const)]
+const HARTID_INIT: AtomicUsize = AtomicUsize::new(INVALID_HARTID);
+
+/// The hart ID of each logical CPU, indexed by the logical CPU ID.
+#[cfg(any(target_arch = "riscv32", target_arch = "riscv64"))]
+static CPU_HARTIDS: [AtomicUsize; axconfig::SMP] = [HARTID_INIT; axconfig::SMP];
+
+/// Builds the mapping between logical CPU IDs and hart IDs.
+///
+/// The enabled `/cpus/cpu@*` nodes of the device tree get logical IDs in node
+/// order, whatever their `reg` values are, so neither the hart IDs nor the
+/// logical ID of the boot hart need to start from 0. The boot hart is always
+/// given an ID, even if there is no device tree.
+#[cfg(any(target_arch = "riscv32", target_arch = "riscv64"))]
+pub(crate) fn init_hartid_map(boot_hartid: usize) {
+    let mut count = 0;
+    let mut boot_hart_found = false;
+    for cpu in crate::platform::dt::cpus() {
+        if !cpu.enabled {
+            debug!("Skip disabled hart {}", cpu.hwid);
+            continue;
+        }
+        if count == axconfig::SMP {
+            warn!("Too many harts, only {} of them are used", axconfig::SMP);
+            break;
+        }
+        CPU_HARTIDS[count].store(cpu.hwid, Ordering::Relaxed);
+        boot_hart_found |= cpu.hwid == boot_hartid;
+        count += 1;
+    }
+    if !boot_hart_found {
+        // Not described by the device tree, or beyond the `SMP` limit.
+        let cpu_id = count.min(axconfig::SMP - 1);
+        CPU_HARTIDS[cpu_id].store(boot_hartid, Ordering::Relaxed);
+    }
+}
+
+/// Returns the hart ID of the given logical CPU, or [`None`] if the CPU does
+/// not exist.
+#[cfg(any(target_arch = "riscv32", target_arch = "riscv64"))]
+pub fn cpu_to_hartid(cpu_id: usize) -> Option<usize> {
+    CPU_HARTIDS
+        .get(cpu_id)
+        .map(|hartid| hartid.load(Ordering::Relaxed))
+        .filter(|&hartid| hartid != INVALID_HARTID)
+}
+
+/// Returns the logical CPU ID of the given hart, or [`None`] if the hart is
+/// not used.
+#[cfg(any(target_arch = "riscv32", target_arch = "riscv64"))]
+pub fn hartid_to_cpu(hartid: usize) -> Option<usize> {
+    CPU_HARTIDS
+        .iter()
+        .position(|id| id.load(Ordering::Relaxed) == hartid)
+}
+
+/// A guard that keeps both interrupts and preemption disabled on the current
+/// CPU, for the data that is also reachable from interrupt handlers.
+///
+/// Interrupts are disabled first, then preemption. On drop they are released
+/// in the reverse order: preemption is enabled first, then the saved
+/// interrupt state is restored, so that guards nest properly with the
+/// [`kernel_guard_base`] ones.
+pub struct CriticalGuard {
+    // Fields are dropped in declaration order.
+    _preempt: kernel_guard_base::NoPreempt,
+    _irq: kernel_guard_base::IrqSave,
+}
+
+impl CriticalGuard {
+    /// Disables interrupts (saving their state) and preemption.
+    #[inline]
+    pub fn new() -> Self {
+        let irq = kernel_guard_base::IrqSave::new();
+        let preempt = kernel_guard_base::NoPreempt::new();
+        Self {
+            _preempt: preempt,
+            _irq: irq,
+        }
+    }
+}
+
+impl Default for CriticalGuard {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Runs `f` with interrupts and preemption disabled, see [`CriticalGuard`].
+#[inline]
+pub fn critical_section<R>(f: impl FnOnce() -> R) -> R {
+    let _guard = CriticalGuard::new();
+    f()
 }
@@ -0,0 +1,82 @@
+//! Early boot log ring buffer.
+//!
+//! The console output emitted before the console UART is initialized is
+//! also captured in a static ring buffer, so that it can be replayed on the
+//! real console once it comes up (e.g. if the firmware console went
+//! nowhere), or read after a panic.
+//!
+//! The buffer is lock-free: a writer reserves its bytes by advancing the
+//! head, and stores them one by one. It can be written from any context,
+//! including trap handlers and other CPUs. When it wraps, the oldest bytes
+//! are overwritten; a reader racing with writers may see some bytes not
+//! written yet.
+
+use core::sync::atomic::{AtomicBool, AtomicU8, AtomicUsize, Ordering};
+
+/// The size of the ring buffer.
+pub const EARLY_LOG_SIZE: usize = 16 * 1024;
+
+#[allow(clippy::declare_interior_mutable_const)]
+const BYTE_INIT: AtomicU8 = AtomicU8::new(0);
+
+static BUF: [AtomicU8; EARLY_LOG_SIZE] = [BYTE_INIT; EARLY_LOG_SIZE];
+
+/// The number of bytes ever written.
+static HEAD: AtomicUsize = AtomicUsize::new(0);
+
+/// Whether the capture is still on.
+static CAPTURING: AtomicBool = AtomicBool::new(true);
+
+/// Appends `bytes` to the ring buffer, if the capture is still on.
+pub fn write_bytes(bytes: &[u8]) {
+    if !CAPTURING.load(Ordering::Relaxed) || bytes.is_empty() {
+        return;
+    }
+    // Only the last `EARLY_LOG_SIZE` bytes matter.
+    let bytes = &bytes[bytes.len().saturating_sub(EARLY_LOG_SIZE)..];
+    let start = HEAD.fetch_add(bytes.len(), Ordering::Relaxed);
+    for (i, &b) in bytes.iter().enumerate() {
+        BUF[(start + i) % EARLY_LOG_SIZE].store(b, Ordering::Relaxed);
+    }
+}
+
+/// Stops capturing, e.g. once the real console is up.
+///
+/// The bytes captured so far stay readable.
+pub fn stop_capture() {
+    CAPTURING.store(false, Ordering::Relaxed);
+}
+
+/// Returns the number of captured bytes still in the buffer.
+pub fn len() -> usize {
+    HEAD.load(Ordering::Acquire).min(EARLY_LOG_SIZE)
+}
+
+/// Calls `f` with each captured byte still in the buffer, oldest first.
+pub fn for_each_byte(mut f: impl FnMut(u8)) {
+    let head = HEAD.load(Ordering::Acquire);
+    let start = head.saturating_sub(EARLY_LOG_SIZE);
+    for pos in start..head {
+        f(BUF[pos % EARLY_LOG_SIZE].load(Ordering::Relaxed));
+    }
+}
+
+/// Copies the last captured bytes into `buf`, oldest first, and returns the
+/// number of bytes copied.
+///
+/// It only reads the buffer, so it can be called after a panic.
+pub fn read(buf: &mut [u8]) -> usize {
+    let head = HEAD.load(Ordering::Acquire);
+    let count = head.min(EARLY_LOG_SIZE).min(buf.len());
+    for (i, b) in buf[..count].iter_mut().enumerate() {
+        *b = BUF[(head - count + i) % EARLY_LOG_SIZE].load(Ordering::Relaxed);
+    }
+    count
+}
+
+/// Writes the captured bytes to `putchar` (e.g. the console driver that
+/// has just come up), and stops capturing.
+pub fn replay(mut putchar: impl FnMut(u8)) {
+    stop_capture();
+    for_each_byte(&mut putchar);
+}
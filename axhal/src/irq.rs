@@ -1,8 +1,189 @@
 //! Interrupt management.
 
-use handler_table::HandlerTable;
+use axerrno::LinuxError;
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use spinbase::SpinNoIrq;
 
 use crate::platform::irq::MAX_IRQ_COUNT;
 
+pub use crate::platform::irq::set_enable;
+
 /// The type if an IRQ handler.
-pub type IrqHandler = handler_table::Handler;
+pub type IrqHandler = fn();
+
+/// The type of a hook run on the exit of the outermost interrupt, see
+/// [`register_irq_exit_hook`].
+pub type IrqExitHook = fn();
+
+/// The maximum number of IRQ exit hooks.
+pub const MAX_IRQ_EXIT_HOOKS: usize = 8;
+
+/// An interrupt controller that routes device interrupts to the CPUs, e.g.
+/// the RISC-V PLIC.
+///
+/// The IRQ numbers are the ones of the controller (the interrupt source
+/// IDs), not the CPU-local interrupt causes.
+pub trait IrqController: Sync {
+    /// Enables the interrupt source `irq`, on its target CPU.
+    fn enable(&self, irq: usize);
+    /// Disables the interrupt source `irq`.
+    fn disable(&self, irq: usize);
+    /// Claims the highest-priority pending interrupt for the current CPU.
+    ///
+    /// Returns [`None`] if there is none.
+    fn claim(&self) -> Option<usize>;
+    /// Signals the completion of the handling of `irq`, claimed by
+    /// [`claim`](Self::claim) on the current CPU.
+    fn complete(&self, irq: usize);
+    /// Sets the priority of `irq`, 0 never interrupts.
+    fn set_priority(&self, irq: usize, priority: u32);
+    /// Routes `irq` to the CPU `cpu_id`.
+    ///
+    /// Returns [`LinuxError::EINVAL`] if `irq` or `cpu_id` is out of range.
+    fn set_affinity(&self, irq: usize, cpu_id: usize) -> Result<(), LinuxError>;
+    /// Returns the CPU that `irq` is routed to, or [`None`] if it is not
+    /// known.
+    fn affinity(&self, _irq: usize) -> Option<usize> {
+        None
+    }
+}
+
+static IRQ_HANDLER_TABLE: SpinNoIrq<[Option<IrqHandler>; MAX_IRQ_COUNT]> =
+    SpinNoIrq::new([None; MAX_IRQ_COUNT]);
+
+#[allow(clippy::declare_interior_mutable_const)]
+const COUNT_INIT: AtomicUsize = AtomicUsize::new(0);
+#[allow(clippy::declare_interior_mutable_const)]
+const CPU_COUNTS_INIT: [AtomicUsize; MAX_IRQ_COUNT] = [COUNT_INIT; MAX_IRQ_COUNT];
+
+/// The number of deliveries of each IRQ, per CPU.
+static IRQ_COUNTS: [[AtomicUsize; MAX_IRQ_COUNT]; axconfig::SMP] = [CPU_COUNTS_INIT; axconfig::SMP];
+
+/// Returns the number of times `irq_num` was delivered to the CPU `cpu_id`,
+/// handled or not.
+///
+/// With the totals of [`irq_total_count`], it lets a policy above the HAL
+/// balance the IRQs over the CPUs.
+pub fn irq_count(irq_num: usize, cpu_id: usize) -> usize {
+    IRQ_COUNTS
+        .get(cpu_id)
+        .and_then(|counts| counts.get(irq_num))
+        .map_or(0, |count| count.load(Ordering::Relaxed))
+}
+
+/// Returns the number of times `irq_num` was delivered, to all CPUs.
+pub fn irq_total_count(irq_num: usize) -> usize {
+    (0..axconfig::SMP)
+        .map(|cpu_id| irq_count(irq_num, cpu_id))
+        .sum()
+}
+
+/// Platform-independent IRQ dispatching.
+///
+/// Returns `false` if there is no handler for `irq_num`.
+#[allow(dead_code)]
+pub(crate) fn dispatch_irq_common(irq_num: usize) -> bool {
+    trace!("IRQ {}", irq_num);
+    if let Some(count) = IRQ_COUNTS[crate::cpu::_this_cpu_id()].get(irq_num) {
+        count.fetch_add(1, Ordering::Relaxed);
+    }
+    // Do not hold the lock while the handler runs.
+    let handler = IRQ_HANDLER_TABLE.lock().get(irq_num).copied().flatten();
+    match handler {
+        Some(handler) => {
+            handler();
+            true
+        }
+        None => {
+            warn!("Unhandled IRQ {}", irq_num);
+            false
+        }
+    }
+}
+
+/// Platform-independent IRQ handler registration.
+///
+/// It also enables the IRQ if the registration succeeds. It returns `false` if
+/// the registration failed.
+#[allow(dead_code)]
+pub(crate) fn register_handler_common(irq_num: usize, handler: IrqHandler) -> bool {
+    if irq_num < MAX_IRQ_COUNT {
+        let mut table = IRQ_HANDLER_TABLE.lock();
+        if table[irq_num].is_none() {
+            table[irq_num] = Some(handler);
+            drop(table);
+            set_enable(irq_num, true);
+            return true;
+        }
+    }
+    warn!("register handler for IRQ {} failed", irq_num);
+    false
+}
+
+/// Platform-independent IRQ handler unregistration.
+///
+/// It does not disable the IRQ. It returns `false` if there was no handler.
+#[allow(dead_code)]
+pub(crate) fn unregister_handler_common(irq_num: usize) -> bool {
+    IRQ_HANDLER_TABLE
+        .lock()
+        .get_mut(irq_num)
+        .and_then(Option::take)
+        .is_some()
+}
+
+static IRQ_EXIT_HOOKS: SpinNoIrq<[Option<IrqExitHook>; MAX_IRQ_EXIT_HOOKS]> =
+    SpinNoIrq::new([None; MAX_IRQ_EXIT_HOOKS]);
+
+#[allow(clippy::declare_interior_mutable_const)]
+const IN_EXIT_HOOKS_INIT: AtomicBool = AtomicBool::new(false);
+
+/// Whether each CPU is running the IRQ exit hooks.
+static IN_EXIT_HOOKS: [AtomicBool; axconfig::SMP] = [IN_EXIT_HOOKS_INIT; axconfig::SMP];
+
+/// Registers `hook` to run on the exit of the outermost interrupt of each
+/// CPU, with the interrupts enabled, before returning to the interrupted
+/// code: the mechanism for the bottom halves, the RCU quiescent states or
+/// the timer wheel of the OS.
+///
+/// The hooks run in the order of registration. An interrupt taken while
+/// they run does not run them again. Returns `false` if there are already
+/// [`MAX_IRQ_EXIT_HOOKS`] hooks.
+pub fn register_irq_exit_hook(hook: IrqExitHook) -> bool {
+    let mut hooks = IRQ_EXIT_HOOKS.lock();
+    match hooks.iter_mut().find(|slot| slot.is_none()) {
+        Some(slot) => {
+            *slot = Some(hook);
+            true
+        }
+        None => {
+            warn!("register IRQ exit hook failed");
+            false
+        }
+    }
+}
+
+/// Runs the IRQ exit hooks if the current CPU is returning from its
+/// outermost interrupt, i.e. its [`irq_depth`](crate::percpu::CpuData::irq_depth)
+/// is back to 0.
+///
+/// The trap handler calls it with the interrupts disabled, after the IRQ is
+/// dispatched, and they are disabled again on return. The vectored entries
+/// of RISC-V call it.
+pub fn run_irq_exit_hooks() {
+    let cpu = unsafe { crate::percpu::current_cpu_data_raw() };
+    if cpu.irq_depth() != 0 {
+        return;
+    }
+    let in_hooks = &IN_EXIT_HOOKS[crate::cpu::_this_cpu_id()];
+    if in_hooks.swap(true, Ordering::Relaxed) {
+        return;
+    }
+    let hooks = *IRQ_EXIT_HOOKS.lock();
+    crate::arch::enable_irqs();
+    for hook in hooks.iter().flatten() {
+        hook();
+    }
+    crate::arch::disable_irqs();
+    in_hooks.store(false, Ordering::Relaxed);
+}
@@ -20,6 +20,11 @@
 //! - `fp_simd`: Enable floating-point and SIMD support.
 //! - `paging`: Enable page table manipulation.
 //! - `irq`: Enable interrupt handling support.
+//! - `syscall-fast-path`: Save only the caller-saved registers on syscalls.
+//! - `vectored-trap`: Dispatch the local interrupts of RISC-V through a
+//!    vectored `stvec`.
+//! - `gdbstub`: Enable the GDB stub of RISC-V, over a dedicated UART.
+//! - `self-test`: Run self-tests of the architecture primitives at boot.
 //!
 //! [ArceOS]: https://github.com/rcore-os/arceos
 //! [cargo test]: https://doc.rust-lang.org/cargo/guide/tests.html
@@ -38,7 +43,9 @@ pub mod platform;
 
 pub mod arch;
 pub mod cpu;
+pub mod early_log;
 pub mod mem;
+pub mod percpu;
 pub mod time;
 pub mod trap;
 
@@ -48,15 +55,23 @@ pub mod misc;
 #[cfg(feature = "tls")]
 pub mod tls;
 
-// #[cfg(feature = "irq")]
-// pub mod irq;
+#[cfg(feature = "irq")]
+pub mod irq;
 
 //pub mod paging;
 
 // replace it with early_console
 // /// Console input and output.
 pub mod console {
+    #[cfg(not(platform_family = "riscv64-qemu-virt"))]
     pub use early_console::*;
+
+    // In place of the ones of `early_console`, which write a byte at a time.
+    #[cfg(platform_family = "riscv64-qemu-virt")]
+    pub use super::platform::console::{getchar, putchar, write_bytes};
+
+    #[cfg(all(feature = "irq", platform_family = "riscv64-qemu-virt"))]
+    pub use super::platform::console::register_console_input_handler;
 }
 
 #[cfg(target_arch = "x86_64")]
@@ -75,9 +90,29 @@ pub use self::platform::platform_init;
 #[cfg(feature = "smp")]
 pub use self::platform::platform_init_secondary;
 
-pub fn arch_init_early(cpu_id: usize) {
+/// Does the earliest initialization on the primary CPU.
+///
+/// `cpu_id` is the hardware ID of the boot CPU (the hart ID on RISC-V), and
+/// `dtb_pa` is the physical address of the device tree blob, or 0 if there is
+/// none.
+#[cfg_attr(target_arch = "x86_64", allow(unused_variables))]
+pub fn arch_init_early(cpu_id: usize, dtb_pa: usize) {
     axconfig::init_once!();
 
+    #[cfg(not(target_arch = "x86_64"))]
+    crate::platform::dt::init(dtb_pa);
+    #[cfg(not(target_arch = "x86_64"))]
+    crate::platform::cmdline::init();
+
+    // Use the logical CPU ID from now on.
+    #[cfg(any(target_arch = "riscv32", target_arch = "riscv64"))]
+    let cpu_id = {
+        crate::cpu::init_hartid_map(cpu_id);
+        crate::cpu::hartid_to_cpu(cpu_id).unwrap()
+    };
+
     crate::cpu::init_primary(cpu_id);
     crate::arch::early_init();
+    #[cfg(not(target_arch = "x86_64"))]
+    crate::platform::uart::init_earlycon();
 }
@@ -71,10 +71,33 @@ pub const fn phys_to_virt(paddr: PhysAddr) -> VirtAddr {
 }
 
 /// Returns an iterator over all physical memory regions.
+///
+/// On platforms with a device tree, the free and MMIO regions come from it,
+/// and the reserved ones (the firmware, the device tree blob, and the
+/// initial ramdisk) are carved out of the free memory, so an allocator fed
+/// with the [`FREE`](MemRegionFlags::FREE) regions never hands them out.
 pub fn memory_regions() -> impl Iterator<Item = MemRegion> {
     kernel_image_regions().chain(crate::platform::mem::platform_regions())
 }
 
+/// Returns the physical address and the size of the initial ramdisk left
+/// in the memory by the bootloader: the `linux,initrd-start` and
+/// `linux,initrd-end` of the device tree, or else the one the EFI stub
+/// loaded.
+///
+/// It is a reserved region of [`memory_regions`].
+pub fn initrd_region() -> Option<(PhysAddr, usize)> {
+    #[cfg(not(target_arch = "x86_64"))]
+    if let Some((paddr, size)) = crate::platform::dt::initrd() {
+        return Some((paddr.into(), size));
+    }
+    #[cfg(all(feature = "efi", any(target_arch = "riscv32", target_arch = "riscv64")))]
+    if let Some((paddr, size)) = crate::arch::efi_boot_info().and_then(|info| info.initrd()) {
+        return Some((paddr.into(), size));
+    }
+    None
+}
+
 /// Returns the memory regions of the kernel image (code and data sections).
 fn kernel_image_regions() -> impl Iterator<Item = MemRegion> {
     [
@@ -90,6 +113,15 @@ fn kernel_image_regions() -> impl Iterator<Item = MemRegion> {
             flags: MemRegionFlags::RESERVED | MemRegionFlags::READ,
             name: ".rodata",
         },
+        MemRegion {
+            paddr: virt_to_phys((_sinit as usize).into()),
+            size: _einit as usize - _sinit as usize,
+            flags: MemRegionFlags::RESERVED
+                | MemRegionFlags::READ
+                | MemRegionFlags::WRITE
+                | MemRegionFlags::EXECUTE,
+            name: ".init",
+        },
         MemRegion {
             paddr: virt_to_phys((_sdata as usize).into()),
             size: _edata as usize - _sdata as usize,
@@ -153,6 +185,8 @@ extern "C" {
     fn _etext();
     fn _srodata();
     fn _erodata();
+    fn _sinit();
+    fn _einit();
     fn _sdata();
     fn _edata();
     fn _sbss();
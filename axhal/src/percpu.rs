@@ -0,0 +1,130 @@
+//! Per-CPU data.
+//!
+//! Each CPU has its own copy of the per-CPU area, whose base is kept in a
+//! register (`gp` on RISC-V, `$r21` on LoongArch, `TPIDR_EL1` on AArch64,
+//! the `gs` base on x86_64) by [`init_primary`](crate::cpu::init_primary) and
+//! [`init_secondary`](crate::cpu::init_secondary). On RISC-V, `tp` is the
+//! TLS pointer of the current task, and the kernel has no
+//! `__global_pointer$`, so the linker never relaxes accesses against `gp`.
+//!
+//! Per-CPU variables are declared with [`def_percpu`]:
+//!
+//! ```ignore
+//! #[axhal::percpu::def_percpu]
+//! static COUNTER: usize = 0;
+//!
+//! COUNTER.with_current(|counter| *counter += 1);
+//! ```
+//!
+//! The HAL keeps its own data of each CPU in a [`CpuData`], see
+//! [`current_cpu_data`].
+
+use core::ops::Deref;
+use core::sync::atomic::{AtomicUsize, Ordering};
+use kernel_guard_base::NoPreempt;
+
+pub use percpu2::def_percpu;
+
+/// The data the HAL keeps for each CPU.
+///
+/// The logical ID and whether the CPU is the primary one are not repeated
+/// here, see [`_this_cpu_id`](crate::cpu::_this_cpu_id) and
+/// [`_this_cpu_is_bsp`](crate::cpu::_this_cpu_is_bsp).
+///
+/// The fields are atomics, as an interrupt handler on the same CPU may
+/// update them.
+#[derive(Debug)]
+pub struct CpuData {
+    hwid: AtomicUsize,
+    irq_depth: AtomicUsize,
+}
+
+impl CpuData {
+    const fn new() -> Self {
+        Self {
+            hwid: AtomicUsize::new(0),
+            irq_depth: AtomicUsize::new(0),
+        }
+    }
+
+    /// The hardware ID of the CPU (the hart ID on RISC-V).
+    #[inline]
+    pub fn hwid(&self) -> usize {
+        self.hwid.load(Ordering::Relaxed)
+    }
+
+    /// The nesting depth of the interrupt handlers running on the CPU, 0
+    /// in task context.
+    #[inline]
+    pub fn irq_depth(&self) -> usize {
+        self.irq_depth.load(Ordering::Relaxed)
+    }
+
+    /// Counts the entry into an interrupt handler, to be paired with
+    /// [`irq_exit`](Self::irq_exit).
+    #[inline]
+    pub fn irq_enter(&self) {
+        self.irq_depth.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Counts the exit from an interrupt handler.
+    #[inline]
+    pub fn irq_exit(&self) {
+        self.irq_depth.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+#[def_percpu]
+static CPU_DATA: CpuData = CpuData::new();
+
+/// A reference to the [`CpuData`] of the current CPU, with preemption
+/// disabled as long as it is held, so the task cannot migrate to another CPU
+/// meanwhile.
+pub struct CpuDataRef {
+    data: &'static CpuData,
+    _guard: NoPreempt,
+}
+
+impl Deref for CpuDataRef {
+    type Target = CpuData;
+
+    #[inline]
+    fn deref(&self) -> &CpuData {
+        self.data
+    }
+}
+
+/// Returns the [`CpuData`] of the current CPU, with preemption disabled
+/// until the result is dropped.
+#[inline]
+pub fn current_cpu_data() -> CpuDataRef {
+    let guard = NoPreempt::new();
+    CpuDataRef {
+        data: unsafe { current_cpu_data_raw() },
+        _guard: guard,
+    }
+}
+
+/// Returns the [`CpuData`] of the current CPU, without disabling preemption.
+///
+/// # Safety
+///
+/// The caller must ensure that the task does not migrate to another CPU as
+/// long as it uses the result, e.g. preemption or interrupts are disabled.
+#[inline]
+pub unsafe fn current_cpu_data_raw() -> &'static CpuData {
+    &*CPU_DATA.current_ptr()
+}
+
+/// Returns the base address of the per-CPU area of the CPU `cpu_id`.
+#[inline]
+pub fn percpu_area_base(cpu_id: usize) -> usize {
+    percpu2::percpu_area_base(cpu_id)
+}
+
+/// Fills the [`CpuData`] of the current CPU, after its per-CPU base is set.
+pub(crate) fn init_percpu(hwid: usize) {
+    let data = unsafe { current_cpu_data_raw() };
+    data.hwid.store(hwid, Ordering::Relaxed);
+    data.irq_depth.store(0, Ordering::Relaxed);
+}
@@ -0,0 +1,423 @@
+//! ACPI tables, for the platforms that describe their hardware only with
+//! ACPI.
+//!
+//! The tables are found from the RSDP that the EFI stub got from the
+//! firmware ([`rsdp`]), through the XSDT. Only what the HAL uses is parsed:
+//!
+//! - the MADT: the harts (RINTC), the IMSICs, APLICs and PLICs;
+//! - the RHCT: the timebase frequency and the ISA string of each hart;
+//! - the MCFG: the ECAM windows of the PCIe segments;
+//! - the GTDT: the interrupts of the generic timers of AArch64;
+//! - the SRAT and the SLIT, for NUMA.
+//!
+//! Without a device tree, the discovery functions of [`dt`] fall back to
+//! them: the CPUs, the timebase frequency, the ISA extensions, the interrupt
+//! controllers and the PCIe host bridge (only its ECAM window: the windows
+//! and the INTx routing are in the AML of the DSDT, that is not parsed). The
+//! PLIC driver finds its PLIC here, the AIA drivers still need the device
+//! tree.
+//!
+//! The tables are read through the linear mapping, they must be in the
+//! memory mapped at boot.
+
+use crate::mem::{phys_to_virt, PhysAddr};
+use crate::platform::dt::{self, CpuNode, DeviceNode, PciHostNode};
+
+/// The size of the header of a system description table.
+const HEADER_SIZE: usize = 36;
+
+/// An entry of the SRAT.
+#[derive(Debug, Clone, Copy)]
+pub enum SratEntry {
+    /// A memory affinity structure: the range `[paddr, paddr + size)` is in
+    /// the proximity domain `domain`.
+    Memory {
+        /// The proximity domain.
+        domain: u32,
+        /// The start physical address.
+        paddr: usize,
+        /// The size in bytes.
+        size: usize,
+        /// Whether the entry is enabled.
+        enabled: bool,
+    },
+    /// A RINTC affinity structure: the hart of the ACPI processor UID `uid`
+    /// is in the proximity domain `domain`.
+    Rintc {
+        /// The proximity domain.
+        domain: u32,
+        /// The ACPI processor UID.
+        uid: u32,
+        /// Whether the entry is enabled.
+        enabled: bool,
+    },
+}
+
+fn read_u16(bytes: &[u8], offset: usize) -> Option<u16> {
+    Some(u16::from_le_bytes(
+        bytes.get(offset..offset + 2)?.try_into().unwrap(),
+    ))
+}
+
+fn read_u32(bytes: &[u8], offset: usize) -> Option<u32> {
+    Some(u32::from_le_bytes(
+        bytes.get(offset..offset + 4)?.try_into().unwrap(),
+    ))
+}
+
+fn read_u64(bytes: &[u8], offset: usize) -> Option<u64> {
+    Some(u64::from_le_bytes(
+        bytes.get(offset..offset + 8)?.try_into().unwrap(),
+    ))
+}
+
+/// Returns the `len` bytes at the physical address `paddr`.
+fn phys_bytes(paddr: usize, len: usize) -> &'static [u8] {
+    unsafe { core::slice::from_raw_parts(phys_to_virt(paddr.into()).as_ptr(), len) }
+}
+
+/// Returns the table at `paddr` if its checksum is valid.
+fn table_at(paddr: usize) -> Option<&'static [u8]> {
+    let len = read_u32(phys_bytes(paddr, HEADER_SIZE), 4)? as usize;
+    let table = phys_bytes(paddr, len.max(HEADER_SIZE));
+    let sum = table.iter().fold(0u8, |sum, &b| sum.wrapping_add(b));
+    (sum == 0).then_some(table)
+}
+
+/// Returns the physical address of the RSDP, if the kernel was booted by
+/// the EFI firmware with ACPI tables.
+pub fn rsdp() -> Option<PhysAddr> {
+    #[cfg(all(feature = "efi", any(target_arch = "riscv32", target_arch = "riscv64")))]
+    if let Some(info) = crate::arch::efi_boot_info().filter(|info| info.acpi_rsdp != 0) {
+        return Some(info.acpi_rsdp.into());
+    }
+    None
+}
+
+/// Returns the table whose signature is `signature` (e.g. `b"SRAT"`), with
+/// its header, from the XSDT of the RSDP at `rsdp`.
+///
+/// Returns [`None`] if there is no such table, or the RSDP or the table is
+/// malformed.
+pub fn find_table(rsdp: PhysAddr, signature: &[u8; 4]) -> Option<&'static [u8]> {
+    // An ACPI 2.0 RSDP, with the XSDT.
+    let rsdp = phys_bytes(rsdp.as_usize(), 36);
+    if &rsdp[..8] != b"RSD PTR " || rsdp[15] < 2 {
+        return None;
+    }
+    let xsdt = table_at(read_u64(rsdp, 24)? as usize)?;
+    xsdt[HEADER_SIZE..]
+        .chunks_exact(8)
+        .filter_map(|entry| table_at(read_u64(entry, 0)? as usize))
+        .find(|table| &table[..4] == signature)
+}
+
+/// Returns an iterator over the structures of `table` after `offset`, as
+/// their type and bytes.
+fn structures(table: &'static [u8], offset: usize) -> impl Iterator<Item = (u8, &'static [u8])> {
+    let mut rest = table.get(offset..).unwrap_or(&[]);
+    core::iter::from_fn(move || {
+        let len = *rest.get(1)? as usize;
+        if len < 2 || len > rest.len() {
+            return None;
+        }
+        let (entry, next) = rest.split_at(len);
+        rest = next;
+        Some((entry[0], entry))
+    })
+}
+
+/// Returns an iterator over the memory and RINTC affinity structures of the
+/// SRAT `srat`.
+pub fn srat_entries(srat: &'static [u8]) -> impl Iterator<Item = SratEntry> {
+    structures(srat, HEADER_SIZE + 12).filter_map(|(ty, entry)| match ty {
+        1 => Some(SratEntry::Memory {
+            domain: read_u32(entry, 2)?,
+            paddr: read_u64(entry, 8)? as usize,
+            size: read_u64(entry, 16)? as usize,
+            enabled: read_u32(entry, 28)? & 1 != 0,
+        }),
+        7 => Some(SratEntry::Rintc {
+            domain: read_u32(entry, 4)?,
+            uid: read_u32(entry, 8)?,
+            enabled: read_u32(entry, 12)? & 1 != 0,
+        }),
+        _ => None,
+    })
+}
+
+/// Returns an iterator over the entries of the SLIT `slit`: the distance
+/// from a proximity domain to another.
+pub fn slit_distances(slit: &'static [u8]) -> impl Iterator<Item = (u32, u32, u32)> {
+    let count = read_u64(slit, HEADER_SIZE).unwrap_or(0) as usize;
+    let matrix = slit.get(HEADER_SIZE + 8..).unwrap_or(&[]);
+    let count = match count.checked_mul(count) {
+        Some(entries) if entries <= matrix.len() => count,
+        _ => 0,
+    };
+    (0..count * count).map(move |i| ((i / count) as u32, (i % count) as u32, matrix[i] as u32))
+}
+
+/// Returns the table whose signature is `signature`, from the RSDP of
+/// [`rsdp`].
+pub fn table(signature: &[u8; 4]) -> Option<&'static [u8]> {
+    find_table(rsdp()?, signature)
+}
+
+/// An interrupt controller structure of the MADT.
+#[derive(Debug, Clone, Copy)]
+pub enum MadtEntry {
+    /// A RINTC: the local interrupt controller of a hart.
+    Rintc {
+        /// The hart ID.
+        hartid: usize,
+        /// The ACPI processor UID.
+        uid: u32,
+        /// Whether the hart is enabled.
+        enabled: bool,
+        /// The physical address of the S-level interrupt file of its
+        /// IMSIC, 0 if none.
+        imsic_paddr: usize,
+        /// The size of the interrupt file.
+        imsic_size: usize,
+    },
+    /// The IMSICs of all the harts.
+    Imsic {
+        /// The number of interrupt identities of an S-level file.
+        num_ids: u16,
+        /// The number of interrupt identities of a guest file.
+        num_guest_ids: u16,
+        /// The number of guest index bits.
+        guest_index_bits: u8,
+        /// The number of hart index bits.
+        hart_index_bits: u8,
+        /// The number of group index bits.
+        group_index_bits: u8,
+        /// The shift of the group index.
+        group_index_shift: u8,
+    },
+    /// An APLIC domain.
+    Aplic {
+        /// The ID of the APLIC.
+        id: u8,
+        /// The physical address of its registers.
+        paddr: usize,
+        /// The size of its registers.
+        size: usize,
+        /// The first global system interrupt of its sources.
+        gsi_base: u32,
+        /// The number of its sources.
+        num_sources: u16,
+        /// The number of its interrupt delivery controls, 0 in MSI mode.
+        num_idcs: u16,
+    },
+    /// A PLIC.
+    Plic {
+        /// The ID of the PLIC.
+        id: u8,
+        /// The physical address of its registers.
+        paddr: usize,
+        /// The size of its registers.
+        size: usize,
+        /// The first global system interrupt of its sources.
+        gsi_base: u32,
+        /// The number of its sources.
+        num_sources: u16,
+    },
+}
+
+/// Returns an iterator over the RISC-V interrupt controller structures of
+/// the MADT.
+pub fn madt_entries() -> impl Iterator<Item = MadtEntry> {
+    let madt = table(b"APIC").unwrap_or(&[]);
+    structures(madt, HEADER_SIZE + 8).filter_map(|(ty, e)| match ty {
+        0x18 => Some(MadtEntry::Rintc {
+            hartid: read_u64(e, 8)? as usize,
+            uid: read_u32(e, 16)?,
+            enabled: read_u32(e, 4)? & 1 != 0,
+            imsic_paddr: read_u64(e, 24)? as usize,
+            imsic_size: read_u32(e, 32)? as usize,
+        }),
+        0x19 => Some(MadtEntry::Imsic {
+            num_ids: read_u16(e, 8)?,
+            num_guest_ids: read_u16(e, 10)?,
+            guest_index_bits: *e.get(12)?,
+            hart_index_bits: *e.get(13)?,
+            group_index_bits: *e.get(14)?,
+            group_index_shift: *e.get(15)?,
+        }),
+        0x1a => Some(MadtEntry::Aplic {
+            id: *e.get(3)?,
+            num_idcs: read_u16(e, 16)?,
+            num_sources: read_u16(e, 18)?,
+            gsi_base: read_u32(e, 20)?,
+            paddr: read_u64(e, 24)? as usize,
+            size: read_u32(e, 32)? as usize,
+        }),
+        0x1b => Some(MadtEntry::Plic {
+            id: *e.get(3)?,
+            num_sources: read_u16(e, 12)?,
+            size: read_u32(e, 20)? as usize,
+            paddr: read_u64(e, 24)? as usize,
+            gsi_base: read_u32(e, 32)?,
+        }),
+        _ => None,
+    })
+}
+
+/// Returns the ISA string of the hart of the ACPI processor UID `uid`, from
+/// its hart info node of the RHCT.
+fn rhct_isa(uid: u32) -> Option<&'static str> {
+    let rhct = table(b"RHCT")?;
+    let num_nodes = read_u32(rhct, 48)? as usize;
+    let nodes = read_u32(rhct, 52)? as usize;
+    let node_at = |offset: usize| {
+        let node = rhct.get(offset..)?;
+        let len = read_u16(node, 2)? as usize;
+        Some((read_u16(node, 0)?, node.get(..len)?))
+    };
+    let mut offset = nodes;
+    for _ in 0..num_nodes {
+        let (ty, node) = node_at(offset)?;
+        offset += node.len().max(1);
+        // A hart info node, with the offsets of the nodes of the hart.
+        if ty != 0xffff || read_u32(node, 8)? != uid {
+            continue;
+        }
+        let num_offsets = read_u16(node, 6)? as usize;
+        return (0..num_offsets).find_map(|i| {
+            let (ty, isa) = node_at(read_u32(node, 12 + i * 4)? as usize)?;
+            let len = read_u16(isa, 6)? as usize;
+            let isa = core::str::from_utf8(isa.get(8..8 + len)?).ok()?;
+            (ty == 0).then(|| isa.trim_end_matches('\0'))
+        });
+    }
+    None
+}
+
+/// Returns the timebase frequency of the RHCT.
+pub fn timebase_frequency() -> Option<usize> {
+    read_u64(table(b"RHCT")?, 40)
+        .map(|freq| freq as usize)
+        .filter(|&freq| freq != 0)
+}
+
+/// Returns an iterator over the harts of the MADT, as the nodes of the
+/// device tree, with their ISA strings from the RHCT.
+pub fn cpus() -> impl Iterator<Item = CpuNode> {
+    madt_entries().filter_map(|entry| match entry {
+        MadtEntry::Rintc {
+            hartid,
+            uid,
+            enabled,
+            ..
+        } => Some(CpuNode {
+            hwid: hartid,
+            enabled,
+            isa: rhct_isa(uid),
+            numa_node: None,
+        }),
+        _ => None,
+    })
+}
+
+/// Returns the ISA string of the hart `hartid`, from the RHCT.
+pub fn cpu_isa(hartid: usize) -> Option<&'static str> {
+    cpus().find(|cpu| cpu.hwid == hartid)?.isa
+}
+
+/// Returns an iterator over the APLICs and PLICs of the MADT, as the nodes
+/// of the device tree.
+pub fn interrupt_controllers() -> impl Iterator<Item = DeviceNode> {
+    madt_entries().filter_map(|entry| match entry {
+        MadtEntry::Aplic { paddr, size, .. } => Some(DeviceNode {
+            name: "aplic",
+            compatible: "riscv,aplic",
+            paddr,
+            size,
+            irq: None,
+        }),
+        MadtEntry::Plic { paddr, size, .. } => Some(DeviceNode {
+            name: "plic",
+            compatible: "riscv,plic0",
+            paddr,
+            size,
+            irq: None,
+        }),
+        _ => None,
+    })
+}
+
+/// Returns the physical address and the number of sources of the first
+/// PLIC of the MADT.
+pub fn plic() -> Option<(usize, usize)> {
+    madt_entries().find_map(|entry| match entry {
+        MadtEntry::Plic {
+            paddr, num_sources, ..
+        } => Some((paddr, num_sources as usize)),
+        _ => None,
+    })
+}
+
+/// An ECAM window of the MCFG.
+#[derive(Debug, Clone, Copy)]
+pub struct McfgEntry {
+    /// The physical address of the window.
+    pub paddr: usize,
+    /// The PCI segment.
+    pub segment: u16,
+    /// The first and last bus numbers.
+    pub bus_range: (u8, u8),
+}
+
+/// Returns an iterator over the ECAM windows of the MCFG.
+pub fn mcfg_entries() -> impl Iterator<Item = McfgEntry> {
+    let mcfg = table(b"MCFG").unwrap_or(&[]);
+    mcfg.get(HEADER_SIZE + 8..)
+        .unwrap_or(&[])
+        .chunks_exact(16)
+        .filter_map(|e| {
+            Some(McfgEntry {
+                paddr: read_u64(e, 0)? as usize,
+                segment: read_u16(e, 8)?,
+                bus_range: (e[10], e[11]),
+            })
+        })
+}
+
+/// Returns the host bridge of the first ECAM window of the MCFG, without
+/// windows nor INTx routing.
+pub fn pci_host() -> Option<PciHostNode> {
+    let ecam = mcfg_entries().next()?;
+    let buses = (ecam.bus_range.1 as usize + 1).saturating_sub(ecam.bus_range.0 as usize);
+    Some(PciHostNode {
+        // 1M of configuration space per bus.
+        ecam_paddr: ecam.paddr + ((ecam.bus_range.0 as usize) << 20),
+        ecam_size: buses << 20,
+        bus_range: ecam.bus_range,
+        ranges: [None; dt::MAX_PCI_RANGES],
+        intx_mask: (0, 0),
+        intx_map: [None; dt::MAX_PCI_INTX_MAP],
+    })
+}
+
+/// The interrupts of the generic timers of the GTDT.
+#[derive(Debug, Clone, Copy)]
+pub struct GtdtTimers {
+    /// The GSIV of the non-secure EL1 physical timer.
+    pub nonsecure_el1: u32,
+    /// The GSIV of the EL1 virtual timer.
+    pub virtual_el1: u32,
+    /// The GSIV of the EL2 physical timer.
+    pub el2: u32,
+}
+
+/// Returns the interrupts of the generic timers of the GTDT.
+pub fn gtdt_timers() -> Option<GtdtTimers> {
+    let gtdt = table(b"GTDT")?;
+    Some(GtdtTimers {
+        nonsecure_el1: read_u32(gtdt, 56)?,
+        virtual_el1: read_u32(gtdt, 64)?,
+        el2: read_u32(gtdt, 72)?,
+    })
+}
@@ -0,0 +1,216 @@
+//! The boot command line: the `bootargs` of the device tree `/chosen` node.
+//!
+//! The parameters are `name` or `name=value`, separated by whitespace. The
+//! ones of the HAL (`earlycon=`, `console=`, `crashkernel=`, `nosmp` and
+//! `maxcpus=`) are parsed once at boot into [`BootParams`]; the other
+//! subsystems register a handler for theirs with [`register_early_param`].
+
+use axerrno::LinuxError;
+use core::sync::atomic::{AtomicBool, Ordering};
+use lazy_init::LazyInit;
+use spinbase::SpinNoIrq;
+
+use crate::platform::dt;
+
+/// The maximum number of early parameter handlers.
+pub const MAX_EARLY_PARAMS: usize = 16;
+
+/// A handler of an early parameter, called with its value (`None` for a
+/// parameter without `=`).
+pub type EarlyParamHandler = fn(Option<&'static str>) -> Result<(), LinuxError>;
+
+/// The console of `earlycon=`, used from the earliest boot until the
+/// console UART is found.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Earlycon {
+    /// `earlycon=sbi`: the firmware console, the default.
+    Sbi,
+    /// `earlycon` without a value: the UART of the `stdout-path` of
+    /// `/chosen`.
+    StdoutPath,
+    /// `earlycon=<driver>,mmio[32],<address>[,<options>]`: the UART of the
+    /// device tree at the physical address `paddr`.
+    Uart {
+        /// The name of the driver, e.g. `"uart8250"`.
+        driver: &'static str,
+        /// The physical address of the UART.
+        paddr: usize,
+    },
+}
+
+/// The boot parameters consumed by the HAL.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct BootParams {
+    /// `earlycon=`.
+    pub earlycon: Option<Earlycon>,
+    /// `console=`: the device tree path or alias of the console UART (e.g.
+    /// `"serial0"`), instead of the `stdout-path`. `"sbi"` and `"hvc0"`
+    /// keep the firmware console.
+    pub console: Option<&'static str>,
+    /// `crashkernel=<size>[@<base>]`: the size and the physical address (if
+    /// fixed) of the memory to reserve for a crash kernel.
+    pub crashkernel: Option<(usize, Option<usize>)>,
+    /// `nosmp`: only the boot CPU runs.
+    pub nosmp: bool,
+    /// `maxcpus=<n>`: at most `n` CPUs run, the boot one included.
+    pub maxcpus: Option<usize>,
+}
+
+impl BootParams {
+    /// Returns the maximum number of running CPUs, if limited by `nosmp` or
+    /// `maxcpus=` (at least 1).
+    pub fn max_cpus(&self) -> Option<usize> {
+        if self.nosmp {
+            Some(1)
+        } else {
+            self.maxcpus.map(|n| n.max(1))
+        }
+    }
+}
+
+static BOOT_PARAMS: LazyInit<BootParams> = LazyInit::new();
+
+/// Whether [`init`] has run the registered handlers.
+static PARSED: AtomicBool = AtomicBool::new(false);
+
+static EARLY_PARAMS: SpinNoIrq<[Option<(&'static str, EarlyParamHandler)>; MAX_EARLY_PARAMS]> =
+    SpinNoIrq::new([None; MAX_EARLY_PARAMS]);
+
+/// Returns the boot command line, `""` if there is none.
+pub fn cmdline() -> &'static str {
+    dt::bootargs().unwrap_or("")
+}
+
+/// Returns an iterator over the parameters of the command line, with their
+/// values.
+pub fn params() -> impl Iterator<Item = (&'static str, Option<&'static str>)> {
+    cmdline()
+        .split_ascii_whitespace()
+        .map(|arg| match arg.split_once('=') {
+            Some((name, value)) => (name, Some(value)),
+            None => (arg, None),
+        })
+}
+
+/// Returns the value of the last parameter `name`: `Some(None)` if it has
+/// no value, `None` if it is not on the command line.
+pub fn param(name: &str) -> Option<Option<&'static str>> {
+    params().filter(|&(n, _)| n == name).last().map(|(_, v)| v)
+}
+
+/// Returns the boot parameters consumed by the HAL, all unset before
+/// [`init`].
+pub fn boot_params() -> BootParams {
+    if BOOT_PARAMS.is_init() {
+        *BOOT_PARAMS
+    } else {
+        BootParams::default()
+    }
+}
+
+/// Parses a size with an optional `K`, `M` or `G` suffix, e.g. `"256M"`, or
+/// an address (`0x` for hexadecimal).
+pub fn parse_size(s: &str) -> Option<usize> {
+    let (digits, shift) = match s.as_bytes().last()? {
+        b'K' | b'k' => (&s[..s.len() - 1], 10),
+        b'M' | b'm' => (&s[..s.len() - 1], 20),
+        b'G' | b'g' => (&s[..s.len() - 1], 30),
+        _ => (s, 0),
+    };
+    let value = match digits.strip_prefix("0x") {
+        Some(hex) => usize::from_str_radix(hex, 16).ok()?,
+        None => digits.parse().ok()?,
+    };
+    value.checked_mul(1 << shift)
+}
+
+fn parse_earlycon(value: Option<&'static str>) -> Option<Earlycon> {
+    let value = match value {
+        None | Some("") => return Some(Earlycon::StdoutPath),
+        Some("sbi") => return Some(Earlycon::Sbi),
+        Some(value) => value,
+    };
+    let mut fields = value.split(',');
+    let driver = fields.next()?;
+    let paddr = match fields.next()? {
+        "mmio" | "mmio32" => parse_size(fields.next()?)?,
+        // `earlycon=<driver>,<address>`
+        addr => parse_size(addr)?,
+    };
+    Some(Earlycon::Uart { driver, paddr })
+}
+
+fn parse_crashkernel(value: &str) -> Option<(usize, Option<usize>)> {
+    let (size, base) = match value.split_once('@') {
+        Some((size, base)) => (parse_size(size)?, Some(parse_size(base)?)),
+        None => (parse_size(value)?, None),
+    };
+    (size != 0).then_some((size, base))
+}
+
+/// Calls the handler of `name`, if one is registered.
+fn run_handler(name: &str, value: Option<&'static str>) {
+    // Not under the lock: the handler may register others.
+    let handler = EARLY_PARAMS
+        .lock()
+        .iter()
+        .flatten()
+        .find(|(n, _)| *n == name)
+        .map(|&(_, handler)| handler);
+    if let Some(Err(err)) = handler.map(|handler| handler(value)) {
+        warn!("Bad boot parameter {}: {:?}", name, err);
+    }
+}
+
+/// Registers `handler` for the parameter `name`.
+///
+/// The handlers run at boot, in the order of the command line, right after
+/// the device tree is parsed; a handler registered later runs at once for
+/// each occurrence of `name` already on the command line. Returns `false`
+/// if there are already [`MAX_EARLY_PARAMS`] handlers.
+pub fn register_early_param(name: &'static str, handler: EarlyParamHandler) -> bool {
+    {
+        let mut handlers = EARLY_PARAMS.lock();
+        let Some(slot) = handlers.iter_mut().find(|slot| slot.is_none()) else {
+            warn!("Too many early parameter handlers");
+            return false;
+        };
+        *slot = Some((name, handler));
+    }
+    if PARSED.load(Ordering::Acquire) {
+        for (_, value) in params().filter(|&(n, _)| n == name) {
+            if let Err(err) = handler(value) {
+                warn!("Bad boot parameter {}: {:?}", name, err);
+            }
+        }
+    }
+    true
+}
+
+/// Parses the command line, and runs the handlers of the early parameters
+/// registered so far.
+///
+/// It must be called after [`dt::init`].
+pub(crate) fn init() {
+    let mut boot_params = BootParams::default();
+    for (name, value) in params() {
+        match (name, value) {
+            ("earlycon", value) => {
+                boot_params.earlycon = parse_earlycon(value);
+                if boot_params.earlycon.is_none() {
+                    warn!("Bad boot parameter earlycon={}", value.unwrap_or(""));
+                }
+            }
+            ("console", Some(value)) => boot_params.console = Some(value),
+            ("crashkernel", Some(value)) => boot_params.crashkernel = parse_crashkernel(value),
+            ("nosmp", _) => boot_params.nosmp = true,
+            ("maxcpus", Some(value)) => boot_params.maxcpus = value.parse().ok(),
+            _ => {}
+        }
+    }
+    BOOT_PARAMS.init_by(boot_params);
+    for (name, value) in params() {
+        run_handler(name, value);
+    }
+    PARSED.store(true, Ordering::Release);
+}
@@ -0,0 +1,114 @@
+//! The CPU clock of the StarFive JH7110.
+//!
+//! The four U74 cores share `cpu_core`, a divider (1 to 7) of `cpu_root`,
+//! which is muxed from the 24 MHz oscillator or PLL0. The rate of PLL0 is
+//! read from the system syscon; it is left unchanged, so the OPPs must be
+//! PLL0 divided by 1 to 7 (e.g. 1.5 GHz, 750, 500 and 375 MHz).
+
+use axerrno::LinuxError;
+use lazy_init::LazyInit;
+use memory_addr::{PhysAddr, VirtAddr};
+
+use super::CpufreqDriver;
+use crate::mem::phys_to_virt;
+use crate::platform::dt;
+
+/// `cpu_root` in the system CRG, bit 24 selects PLL0.
+const CRG_CPU_ROOT: usize = 0x00;
+const CPU_ROOT_MUX_PLL0: u32 = 1 << 24;
+/// `cpu_core` in the system CRG, the divider in bits 23:0.
+const CRG_CPU_CORE: usize = 0x04;
+const CPU_CORE_DIV_MASK: u32 = 0xff_ffff;
+const CPU_CORE_DIV_MAX: u64 = 7;
+
+/// The PLL0 configuration in the system syscon: the registers of `fbdiv`
+/// (bits 0-11), `postdiv1` (bits 28-29) and `prediv` (bits 0-5).
+const SYSCON_PLL0_FBDIV: usize = 0x1c;
+const SYSCON_PLL0_POSTDIV1: usize = 0x20;
+const SYSCON_PLL0_PREDIV: usize = 0x24;
+const PLL0_FBDIV_MASK: u32 = 0xfff;
+const PLL0_POSTDIV1_SHIFT: u32 = 28;
+const PLL0_POSTDIV1_MASK: u32 = 0x3;
+const PLL0_PREDIV_MASK: u32 = 0x3f;
+
+const OSC_HZ: u64 = 24_000_000;
+
+/// The driver of the CPU clock of the JH7110.
+pub struct Jh7110Cpufreq {
+    crg: VirtAddr,
+    syscon: VirtAddr,
+}
+
+impl Jh7110Cpufreq {
+    /// Creates the driver of the system CRG mapped at `crg` and the system
+    /// syscon mapped at `syscon`.
+    pub const fn new(crg: VirtAddr, syscon: VirtAddr) -> Self {
+        Self { crg, syscon }
+    }
+
+    fn crg_reg(&self, offset: usize) -> *mut u32 {
+        (self.crg.as_usize() + offset) as *mut u32
+    }
+
+    fn syscon_read(&self, offset: usize) -> u32 {
+        unsafe { ((self.syscon.as_usize() + offset) as *const u32).read_volatile() }
+    }
+
+    /// Returns the rate of `cpu_root`, in Hz.
+    fn root_hz(&self) -> u64 {
+        if unsafe { self.crg_reg(CRG_CPU_ROOT).read_volatile() } & CPU_ROOT_MUX_PLL0 == 0 {
+            return OSC_HZ;
+        }
+        let fbdiv = (self.syscon_read(SYSCON_PLL0_FBDIV) & PLL0_FBDIV_MASK) as u64;
+        let postdiv1 =
+            (self.syscon_read(SYSCON_PLL0_POSTDIV1) >> PLL0_POSTDIV1_SHIFT) & PLL0_POSTDIV1_MASK;
+        let prediv = (self.syscon_read(SYSCON_PLL0_PREDIV) & PLL0_PREDIV_MASK).max(1) as u64;
+        (OSC_HZ * fbdiv / prediv) >> postdiv1
+    }
+}
+
+impl CpufreqDriver for Jh7110Cpufreq {
+    fn set_frequency(&self, _cpu_id: usize, khz: u32) -> Result<(), LinuxError> {
+        let root_hz = self.root_hz();
+        let target_hz = khz as u64 * 1000;
+        if target_hz == 0 {
+            return Err(LinuxError::EINVAL);
+        }
+        let div = root_hz.div_ceil(target_hz);
+        if !(1..=CPU_CORE_DIV_MAX).contains(&div) || (root_hz / div).abs_diff(target_hz) >= 1000 {
+            return Err(LinuxError::EINVAL);
+        }
+        let reg = self.crg_reg(CRG_CPU_CORE);
+        unsafe {
+            let value = reg.read_volatile() & !CPU_CORE_DIV_MASK;
+            reg.write_volatile(value | div as u32);
+        }
+        Ok(())
+    }
+
+    fn frequency(&self, _cpu_id: usize) -> Result<u32, LinuxError> {
+        let div = unsafe { self.crg_reg(CRG_CPU_CORE).read_volatile() } & CPU_CORE_DIV_MASK;
+        Ok((self.root_hz() / div.max(1) as u64 / 1000) as u32)
+    }
+}
+
+static JH7110_CPUFREQ: LazyInit<Jh7110Cpufreq> = LazyInit::new();
+
+/// Finds the system CRG and syscon of the JH7110 in the device tree, and
+/// initializes the driver.
+pub(super) fn probe() -> Option<&'static Jh7110Cpufreq> {
+    let fdt = dt::fdt()?;
+    let paddr = |compatible| {
+        let node = fdt
+            .find_compatible(&[compatible])
+            .filter(|&node| dt::node_enabled(node))?;
+        Some(PhysAddr::from(
+            node.reg()?.next()?.starting_address as usize,
+        ))
+    };
+    let crg = paddr("starfive,jh7110-syscrg")?;
+    let syscon = paddr("starfive,jh7110-sys-syscon")?;
+    info!("JH7110 cpufreq: CRG @ {:#x}, syscon @ {:#x}", crg, syscon);
+    JH7110_CPUFREQ.init_by(Jh7110Cpufreq::new(phys_to_virt(crg), phys_to_virt(syscon)));
+    Some(&JH7110_CPUFREQ)
+}
@@ -0,0 +1,152 @@
+//! CPU frequency scaling (DVFS).
+//!
+//! The operating points (OPPs) of a CPU are read from its
+//! `operating-points-v2` table in the device tree ([`opps`]). Its frequency
+//! is changed by a [`CpufreqDriver`]: the CPU clock of the StarFive JH7110,
+//! found in the device tree by [`init`], or one registered by the board code
+//! with [`register_cpufreq_driver`] (e.g. an [`ScmiPerf`] on the mailbox the
+//! board provides). The functions registered with
+//! [`register_cpufreq_notifier`] are called after each change, e.g. to
+//! recalibrate the code that counts CPU cycles.
+//!
+//! The voltage is scaled only if the firmware does it (SCMI): with a clock
+//! driver, it stays as the firmware set it for the boot frequency.
+
+mod jh7110;
+mod scmi;
+
+use axerrno::LinuxError;
+use spinbase::SpinNoIrq;
+
+use crate::cpu::{cpu_to_hartid, CpuMask};
+use crate::platform::dt;
+
+pub use self::jh7110::Jh7110Cpufreq;
+pub use self::scmi::{ScmiMailbox, ScmiPerf};
+
+/// The maximum number of registered [`CpufreqNotifier`]s.
+pub const MAX_CPUFREQ_NOTIFIERS: usize = 8;
+
+/// A driver that changes the frequency of the CPUs.
+pub trait CpufreqDriver: Sync {
+    /// Sets the frequency of the CPU `cpu_id` (and of the CPUs that share
+    /// its clock), in kHz.
+    fn set_frequency(&self, cpu_id: usize, khz: u32) -> Result<(), LinuxError>;
+    /// Returns the current frequency of the CPU `cpu_id`, in kHz.
+    fn frequency(&self, cpu_id: usize) -> Result<u32, LinuxError>;
+}
+
+/// An operating point of a CPU.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Opp {
+    /// The frequency in kHz.
+    pub khz: u32,
+    /// The voltage in microvolts, if given.
+    pub microvolt: Option<u32>,
+    /// Whether the OPP is only for short boosts.
+    pub turbo: bool,
+}
+
+/// Called after the frequency of the CPU `cpu_id` changed from `old_khz`
+/// to `new_khz`, with the transitions serialized.
+pub type CpufreqNotifier = fn(cpu_id: usize, old_khz: u32, new_khz: u32);
+
+static DRIVER: SpinNoIrq<Option<&'static dyn CpufreqDriver>> = SpinNoIrq::new(None);
+
+static NOTIFIERS: SpinNoIrq<[Option<CpufreqNotifier>; MAX_CPUFREQ_NOTIFIERS]> =
+    SpinNoIrq::new([None; MAX_CPUFREQ_NOTIFIERS]);
+
+/// Serializes the frequency transitions.
+static TRANSITION: SpinNoIrq<()> = SpinNoIrq::new(());
+
+/// Makes `driver` the cpufreq driver, in place of the previous one.
+pub fn register_cpufreq_driver(driver: &'static dyn CpufreqDriver) {
+    *DRIVER.lock() = Some(driver);
+}
+
+/// Registers a function called after each frequency change.
+///
+/// Returns `false` if there are already [`MAX_CPUFREQ_NOTIFIERS`] of them.
+pub fn register_cpufreq_notifier(notifier: CpufreqNotifier) -> bool {
+    let mut notifiers = NOTIFIERS.lock();
+    match notifiers.iter_mut().find(|slot| slot.is_none()) {
+        Some(slot) => {
+            *slot = Some(notifier);
+            true
+        }
+        None => false,
+    }
+}
+
+/// Returns an iterator over the OPPs of the CPU `cpu_id`, from its
+/// `operating-points-v2` table.
+pub fn opps(cpu_id: usize) -> impl Iterator<Item = Opp> {
+    cpu_to_hartid(cpu_id)
+        .and_then(dt::cpu_opp_table)
+        .into_iter()
+        .flat_map(|(table, _)| dt::opps(table))
+        .map(|opp| Opp {
+            khz: (opp.hz / 1000) as u32,
+            microvolt: opp.microvolt,
+            turbo: opp.turbo,
+        })
+}
+
+/// Returns the CPUs whose frequency changes with the one of `cpu_id`: those
+/// with the same `opp-shared` table, or only `cpu_id`.
+pub fn related_cpus(cpu_id: usize) -> CpuMask {
+    let mut cpus = CpuMask::new();
+    cpus.insert(cpu_id);
+    let Some((table, true)) = cpu_to_hartid(cpu_id).and_then(dt::cpu_opp_table) else {
+        return cpus;
+    };
+    for other in 0..crate::cpu::cpu_count() {
+        if cpu_to_hartid(other).and_then(dt::cpu_opp_table) == Some((table, true)) {
+            cpus.insert(other);
+        }
+    }
+    cpus
+}
+
+/// Returns the current frequency of the CPU `cpu_id`, in kHz.
+///
+/// Returns [`LinuxError::ENODEV`] if there is no cpufreq driver, and the
+/// error of the driver otherwise.
+pub fn frequency(cpu_id: usize) -> Result<u32, LinuxError> {
+    let driver = (*DRIVER.lock()).ok_or(LinuxError::ENODEV)?;
+    driver.frequency(cpu_id)
+}
+
+/// Sets the frequency of the CPU `cpu_id` (and of its [`related_cpus`]) to
+/// its OPP of `khz` kHz, and calls the notifiers for each of them.
+///
+/// Returns [`LinuxError::ENODEV`] if there is no cpufreq driver,
+/// [`LinuxError::EINVAL`] if `khz` is not an OPP of the CPU, and the error
+/// of the driver otherwise.
+pub fn set_frequency(cpu_id: usize, khz: u32) -> Result<(), LinuxError> {
+    let driver = (*DRIVER.lock()).ok_or(LinuxError::ENODEV)?;
+    if !opps(cpu_id).any(|opp| opp.khz == khz) {
+        return Err(LinuxError::EINVAL);
+    }
+    let _guard = TRANSITION.lock();
+    let old_khz = driver.frequency(cpu_id)?;
+    if old_khz == khz {
+        return Ok(());
+    }
+    driver.set_frequency(cpu_id, khz)?;
+    debug!("cpufreq: CPU {}: {} kHz -> {} kHz", cpu_id, old_khz, khz);
+    let notifiers = *NOTIFIERS.lock();
+    for cpu in related_cpus(cpu_id).iter() {
+        for notifier in notifiers.iter().flatten() {
+            notifier(cpu, old_khz, khz);
+        }
+    }
+    Ok(())
+}
+
+/// Finds a cpufreq driver in the device tree, and registers it.
+pub(crate) fn init() {
+    if let Some(driver) = jh7110::probe() {
+        register_cpufreq_driver(driver);
+    }
+}
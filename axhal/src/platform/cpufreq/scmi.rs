@@ -0,0 +1,224 @@
+//! SCMI performance domains (protocol 0x13), over a shared memory channel.
+//!
+//! A command is written to the `arm,scmi-shmem` area of the `arm,scmi` node,
+//! the channel is marked busy and the platform is signalled through the
+//! [`ScmiMailbox`] of the board; the response overwrites the command when
+//! the platform marks the channel free again, which is polled. The
+//! performance domain of a CPU is the cell of the `clocks` of its node that
+//! refers to the `protocol@13` node.
+
+use axerrno::LinuxError;
+use memory_addr::{PhysAddr, VirtAddr};
+use spinbase::SpinNoIrq;
+
+use super::CpufreqDriver;
+use crate::cpu::cpu_to_hartid;
+use crate::mem::phys_to_virt;
+use crate::platform::dt;
+
+/// The doorbell of an SCMI channel, e.g. a mailbox controller.
+pub trait ScmiMailbox: Sync {
+    /// Signals the platform that a command is in the shared memory.
+    fn ring_doorbell(&self);
+}
+
+const SHMEM_CHANNEL_STATUS: usize = 0x04;
+const SHMEM_FLAGS: usize = 0x10;
+const SHMEM_LENGTH: usize = 0x14;
+const SHMEM_MSG_HEADER: usize = 0x18;
+const SHMEM_PAYLOAD: usize = 0x1c;
+
+const CHANNEL_FREE: u32 = 1 << 0;
+const CHANNEL_ERROR: u32 = 1 << 1;
+
+const PROTOCOL_PERF: u32 = 0x13;
+const PERF_DOMAIN_ATTRIBUTES: u32 = 0x3;
+const PERF_LEVEL_SET: u32 = 0x7;
+const PERF_LEVEL_GET: u32 = 0x8;
+
+/// The words of the `PERFORMANCE_DOMAIN_ATTRIBUTES` response, after the
+/// status: attributes, rate limit, sustained frequency (kHz), sustained
+/// performance level, and the name.
+const DOMAIN_ATTRIBUTES_WORDS: usize = 8;
+
+/// How long a command may take.
+const TIMEOUT: core::time::Duration = core::time::Duration::from_millis(10);
+
+/// A performance domain of a CPU.
+#[derive(Clone, Copy)]
+struct PerfDomain {
+    id: u32,
+    /// The frequency of a performance level.
+    hz_per_level: u64,
+}
+
+/// The SCMI performance protocol, as a [`CpufreqDriver`].
+pub struct ScmiPerf {
+    shmem: VirtAddr,
+    mailbox: &'static dyn ScmiMailbox,
+    domains: [Option<PerfDomain>; axconfig::SMP],
+    /// Serializes the use of the channel.
+    channel: SpinNoIrq<u32>,
+}
+
+/// Converts an SCMI status to an error.
+fn scmi_error(status: i32) -> LinuxError {
+    match status {
+        -1 => LinuxError::ENOTSUP,
+        -2 => LinuxError::EINVAL,
+        -3 => LinuxError::EPERM,
+        -4 => LinuxError::ENOENT,
+        -5 => LinuxError::ERANGE,
+        -6 => LinuxError::EBUSY,
+        _ => LinuxError::EIO,
+    }
+}
+
+impl ScmiPerf {
+    /// Creates the driver of the `arm,scmi` node of the device tree, with
+    /// `mailbox` as its doorbell, and reads the performance domains of the
+    /// CPUs.
+    ///
+    /// Returns [`LinuxError::ENODEV`] if there is no SCMI node or shared
+    /// memory, or no CPU with a performance domain, and the errors of the
+    /// commands otherwise.
+    pub fn probe(mailbox: &'static dyn ScmiMailbox) -> Result<Self, LinuxError> {
+        let fdt = dt::fdt().ok_or(LinuxError::ENODEV)?;
+        let scmi = fdt
+            .find_compatible(&["arm,scmi"])
+            .filter(|&node| dt::node_enabled(node))
+            .ok_or(LinuxError::ENODEV)?;
+        let shmem = scmi
+            .property("shmem")
+            .and_then(|p| p.as_usize())
+            .and_then(|phandle| fdt.find_phandle(phandle as u32))
+            .and_then(|node| node.reg()?.next())
+            .ok_or(LinuxError::ENODEV)?;
+        let shmem = PhysAddr::from(shmem.starting_address as usize);
+        info!("SCMI performance domains: shmem @ {:#x}", shmem);
+
+        let mut perf = Self {
+            shmem: phys_to_virt(shmem),
+            mailbox,
+            domains: [None; axconfig::SMP],
+            channel: SpinNoIrq::new(0),
+        };
+        for cpu_id in 0..crate::cpu::cpu_count() {
+            let Some(id) = cpu_to_hartid(cpu_id).and_then(perf_domain_of) else {
+                continue;
+            };
+            let mut attrs = [0; DOMAIN_ATTRIBUTES_WORDS];
+            perf.command(PERF_DOMAIN_ATTRIBUTES, &[id], &mut attrs)?;
+            let (sustained_khz, sustained_level) = (attrs[2] as u64, attrs[3] as u64);
+            let hz_per_level = match sustained_level {
+                0 => 1000,
+                level => (sustained_khz * 1000 / level).max(1),
+            };
+            perf.domains[cpu_id] = Some(PerfDomain { id, hz_per_level });
+        }
+        if perf.domains.iter().all(Option::is_none) {
+            return Err(LinuxError::ENODEV);
+        }
+        Ok(perf)
+    }
+
+    fn shmem_reg(&self, offset: usize) -> *mut u32 {
+        (self.shmem.as_usize() + offset) as *mut u32
+    }
+
+    /// Sends the command `msg_id` of the performance protocol with
+    /// `payload`, and reads the words of the response after the status to
+    /// `response`.
+    fn command(
+        &self,
+        msg_id: u32,
+        payload: &[u32],
+        response: &mut [u32],
+    ) -> Result<(), LinuxError> {
+        let mut token = self.channel.lock();
+        let deadline = crate::time::current_time() + TIMEOUT;
+        let wait_free = || {
+            while unsafe { self.shmem_reg(SHMEM_CHANNEL_STATUS).read_volatile() } & CHANNEL_FREE
+                == 0
+            {
+                if crate::time::current_time() >= deadline {
+                    return Err(LinuxError::ETIMEDOUT);
+                }
+                core::hint::spin_loop();
+            }
+            Ok(())
+        };
+        wait_free()?;
+        *token = (*token + 1) & 0x3ff;
+        let header = msg_id | (PROTOCOL_PERF << 10) | (*token << 18);
+        unsafe {
+            self.shmem_reg(SHMEM_FLAGS).write_volatile(0); // polled
+            self.shmem_reg(SHMEM_LENGTH)
+                .write_volatile(4 * (1 + payload.len() as u32));
+            self.shmem_reg(SHMEM_MSG_HEADER).write_volatile(header);
+            for (i, &word) in payload.iter().enumerate() {
+                self.shmem_reg(SHMEM_PAYLOAD + 4 * i).write_volatile(word);
+            }
+            self.shmem_reg(SHMEM_CHANNEL_STATUS).write_volatile(0);
+        }
+        self.mailbox.ring_doorbell();
+        wait_free()?;
+        unsafe {
+            if self.shmem_reg(SHMEM_CHANNEL_STATUS).read_volatile() & CHANNEL_ERROR != 0 {
+                return Err(LinuxError::EIO);
+            }
+            let status = self.shmem_reg(SHMEM_PAYLOAD).read_volatile() as i32;
+            if status != 0 {
+                return Err(scmi_error(status));
+            }
+            for (i, word) in response.iter_mut().enumerate() {
+                *word = self.shmem_reg(SHMEM_PAYLOAD + 4 * (i + 1)).read_volatile();
+            }
+        }
+        Ok(())
+    }
+
+    fn domain(&self, cpu_id: usize) -> Result<PerfDomain, LinuxError> {
+        self.domains
+            .get(cpu_id)
+            .copied()
+            .flatten()
+            .ok_or(LinuxError::ENODEV)
+    }
+}
+
+/// Returns the performance domain of the CPU `hwid`: the cell after the
+/// phandle of the `protocol@13` node in its `clocks`.
+fn perf_domain_of(hwid: usize) -> Option<u32> {
+    let fdt = dt::fdt()?;
+    let clocks = dt::cpu_node(hwid)?.property("clocks")?.value;
+    let mut cells = clocks
+        .chunks_exact(4)
+        .map(|cell| u32::from_be_bytes(cell.try_into().unwrap()));
+    while let Some(phandle) = cells.next() {
+        let node = fdt.find_phandle(phandle)?;
+        let num_cells = node.property("#clock-cells").and_then(|p| p.as_usize())?;
+        if num_cells == 1
+            && node.property("reg").and_then(|p| p.as_usize()) == Some(PROTOCOL_PERF as usize)
+        {
+            return cells.next();
+        }
+        cells.by_ref().take(num_cells).for_each(drop);
+    }
+    None
+}
+
+impl CpufreqDriver for ScmiPerf {
+    fn set_frequency(&self, cpu_id: usize, khz: u32) -> Result<(), LinuxError> {
+        let domain = self.domain(cpu_id)?;
+        let level = (khz as u64 * 1000 / domain.hz_per_level) as u32;
+        self.command(PERF_LEVEL_SET, &[domain.id, level], &mut [])
+    }
+
+    fn frequency(&self, cpu_id: usize) -> Result<u32, LinuxError> {
+        let domain = self.domain(cpu_id)?;
+        let mut level = [0];
+        self.command(PERF_LEVEL_GET, &[domain.id], &mut level)?;
+        Ok((level[0] as u64 * domain.hz_per_level / 1000) as u32)
+    }
+}
@@ -0,0 +1,1138 @@
+//! Device tree (FDT) access.
+//!
+//! The device tree passed by the firmware (in `a1` on RISC-V) describes the
+//! platform: its memory, CPUs, interrupt controllers and devices. This
+//! module parses it once at boot, and gives typed iterators over the nodes
+//! the HAL and the upper layers need, so that nothing has to hard-code the
+//! addresses of a board.
+
+use core::sync::atomic::{AtomicUsize, Ordering};
+use fdt::Fdt;
+use lazy_init::LazyInit;
+
+use crate::mem::{phys_to_virt, PhysAddr};
+use crate::platform::acpi;
+
+static FDT: LazyInit<Fdt<'static>> = LazyInit::new();
+static DTB_PADDR: AtomicUsize = AtomicUsize::new(0);
+
+/// A `/cpus/cpu@*` node of the device tree.
+#[derive(Debug, Clone, Copy)]
+pub struct CpuNode {
+    /// The hardware ID of the CPU (the `reg` property, i.e. the hart ID on
+    /// RISC-V).
+    pub hwid: usize,
+    /// Whether the `status` property allows the CPU to be used.
+    pub enabled: bool,
+    /// The ISA string (`riscv,isa`), e.g. `"rv64imafdc_zicsr_sstc"`.
+    pub isa: Option<&'static str>,
+    /// The NUMA node (`numa-node-id`), if given.
+    pub numa_node: Option<usize>,
+}
+
+/// A range of physical memory of a `/memory` node.
+#[derive(Debug, Clone, Copy)]
+pub struct MemoryNode {
+    /// The start physical address.
+    pub paddr: usize,
+    /// The size in bytes.
+    pub size: usize,
+    /// The NUMA node (`numa-node-id`) of the `/memory` node, if given.
+    pub numa_node: Option<usize>,
+}
+
+/// A device node with registers, e.g. a UART or an interrupt controller.
+#[derive(Debug, Clone, Copy)]
+pub struct DeviceNode {
+    /// The node name, e.g. `"serial@10000000"`.
+    pub name: &'static str,
+    /// The first `compatible` string, or `""` if there is none.
+    pub compatible: &'static str,
+    /// The physical address of the first `reg` range.
+    pub paddr: usize,
+    /// The size of the first `reg` range, 0 if it is not given.
+    pub size: usize,
+    /// The first IRQ number of `interrupts`, if any.
+    pub irq: Option<usize>,
+}
+
+impl DeviceNode {
+    fn from_node(node: fdt::node::FdtNode<'static, 'static>) -> Option<Self> {
+        let reg = node.reg()?.next()?;
+        Some(Self {
+            name: node.name,
+            compatible: node.compatible().map_or("", |c| c.first()),
+            paddr: reg.starting_address as usize,
+            size: reg.size.unwrap_or(0),
+            irq: node.interrupts().and_then(|mut irqs| irqs.next()),
+        })
+    }
+}
+
+/// The `compatible` strings of the UARTs.
+const UART_COMPATIBLES: &[&str] = &[
+    "ns16550a",
+    "ns16550",
+    "ns8250",
+    "snps,dw-apb-uart",
+    "sifive,uart0",
+    "arm,pl011",
+];
+
+/// Parses the flattened device tree at the physical address `dtb_pa`.
+///
+/// It must be called before any other function in this module, otherwise
+/// they behave as if the device tree is empty.
+pub fn init(dtb_pa: usize) {
+    if dtb_pa == 0 {
+        warn!("No device tree is provided.");
+        return;
+    }
+    let dtb_ptr = phys_to_virt(PhysAddr::from(dtb_pa)).as_ptr();
+    match unsafe { Fdt::from_ptr(dtb_ptr) } {
+        Ok(fdt) => {
+            info!(
+                "Found device tree @ {:#x}, size {:#x}",
+                dtb_pa,
+                fdt.total_size()
+            );
+            DTB_PADDR.store(dtb_pa, Ordering::Relaxed);
+            FDT.init_by(fdt);
+        }
+        Err(err) => warn!("Invalid device tree @ {:#x}: {:?}", dtb_pa, err),
+    }
+}
+
+/// Returns the physical address of the device tree, or [`None`] if there is
+/// no valid one.
+pub fn dtb_paddr() -> Option<PhysAddr> {
+    match DTB_PADDR.load(Ordering::Relaxed) {
+        0 => None,
+        paddr => Some(PhysAddr::from(paddr)),
+    }
+}
+
+/// Returns the parsed device tree, or [`None`] if there is no valid one.
+pub fn fdt() -> Option<&'static Fdt<'static>> {
+    if FDT.is_init() {
+        Some(&FDT)
+    } else {
+        None
+    }
+}
+
+/// Returns whether the `status` property of a node allows it to be used.
+///
+/// A missing `status` means "okay".
+pub(crate) fn node_enabled(node: fdt::node::FdtNode) -> bool {
+    match node.property("status").and_then(|p| p.as_str()) {
+        Some(status) => status == "okay" || status == "ok",
+        None => true,
+    }
+}
+
+/// Returns whether a `/cpus/cpu@*` node lists the ISA extension `ext`, in
+/// `riscv,isa-extensions` or in the `riscv,isa` string.
+fn cpu_node_has_isa_ext(node: fdt::node::FdtNode, ext: &str) -> bool {
+    if let Some(exts) = node.property("riscv,isa-extensions") {
+        return exts
+            .value
+            .split(|&b| b == 0)
+            .any(|name| name.eq_ignore_ascii_case(ext.as_bytes()));
+    }
+    node.property("riscv,isa")
+        .and_then(|p| p.as_str())
+        .is_some_and(|isa| isa_string_has_ext(isa, ext))
+}
+
+/// Returns whether the ISA string `isa` lists the extension `ext`.
+fn isa_string_has_ext(isa: &str, ext: &str) -> bool {
+    // e.g. "rv64imafdcv_zicsr_sscofpmf", the single-letter extensions
+    // follow the base ISA in the first part.
+    if ext.len() == 1 {
+        isa.split('_')
+            .next()
+            .and_then(|base| base.get(4..))
+            .is_some_and(|letters| {
+                letters
+                    .bytes()
+                    .any(|b| b.eq_ignore_ascii_case(&ext.as_bytes()[0]))
+            })
+    } else {
+        isa.split('_')
+            .skip(1)
+            .any(|name| name.eq_ignore_ascii_case(ext))
+    }
+}
+
+/// Returns whether the CPU whose hardware ID is `hwid` supports the RISC-V
+/// ISA extension `ext`.
+///
+/// Without a device tree, the ISA string comes from the ACPI RHCT.
+pub fn cpu_isa_extension_supported(hwid: usize, ext: &str) -> bool {
+    match cpu_node(hwid) {
+        Some(node) => cpu_node_has_isa_ext(node, ext),
+        None => acpi::cpu_isa(hwid).is_some_and(|isa| isa_string_has_ext(isa, ext)),
+    }
+}
+
+/// Returns whether all enabled CPUs support the RISC-V ISA extension `ext`,
+/// e.g. `"v"` or `"sscofpmf"`.
+///
+/// Returns `false` if there is no CPU in the device tree.
+pub fn isa_extension_supported(ext: &str) -> bool {
+    if fdt().is_none() {
+        let mut cpus = acpi::cpus().filter(|cpu| cpu.enabled).peekable();
+        return cpus.peek().is_some()
+            && cpus.all(|cpu| cpu.isa.is_some_and(|isa| isa_string_has_ext(isa, ext)));
+    }
+    let mut found = false;
+    let all = fdt()
+        .and_then(|fdt| fdt.find_node("/cpus"))
+        .into_iter()
+        .flat_map(|cpus| cpus.children())
+        .filter(|node| node.name.starts_with("cpu@") && node_enabled(*node))
+        .all(|node| {
+            found = true;
+            cpu_node_has_isa_ext(node, ext)
+        });
+    found && all
+}
+
+/// Returns an iterator over all `/cpus/cpu@*` nodes, in node order, or
+/// the harts of the ACPI MADT without a device tree.
+///
+/// It does not skip the disabled CPUs, check [`CpuNode::enabled`] for that.
+pub fn cpus() -> impl Iterator<Item = CpuNode> {
+    fdt()
+        .and_then(|fdt| fdt.find_node("/cpus"))
+        .into_iter()
+        .flat_map(|cpus| cpus.children())
+        .filter(|node| node.name.starts_with("cpu@"))
+        .filter_map(|node| {
+            let hwid = node.property("reg").and_then(|p| p.as_usize())?;
+            Some(CpuNode {
+                hwid,
+                enabled: node_enabled(node),
+                isa: node.property("riscv,isa").and_then(|p| p.as_str()),
+                numa_node: node.property("numa-node-id").and_then(|p| p.as_usize()),
+            })
+        })
+        .chain(fdt().is_none().then(acpi::cpus).into_iter().flatten())
+}
+
+/// Returns an iterator over the physical memory ranges of all `/memory`
+/// nodes (those whose `device_type` is `"memory"`).
+pub fn memory() -> impl Iterator<Item = MemoryNode> {
+    fdt()
+        .into_iter()
+        .flat_map(|fdt| fdt.all_nodes())
+        .filter(|&node| {
+            node.property("device_type").and_then(|p| p.as_str()) == Some("memory")
+                && node_enabled(node)
+        })
+        .flat_map(|node| {
+            let numa_node = node.property("numa-node-id").and_then(|p| p.as_usize());
+            node.reg().into_iter().flatten().filter_map(move |reg| {
+                let size = reg.size.filter(|&size| size != 0)?;
+                Some(MemoryNode {
+                    paddr: reg.starting_address as usize,
+                    size,
+                    numa_node,
+                })
+            })
+        })
+}
+
+/// Returns an iterator over the entries of the `distance-matrix` of the
+/// `numa-distance-map-v1` node: the distance from a NUMA node to another.
+pub fn numa_distances() -> impl Iterator<Item = (usize, usize, u32)> {
+    let matrix = fdt()
+        .and_then(|fdt| fdt.find_compatible(&["numa-distance-map-v1"]))
+        .and_then(|node| node.property("distance-matrix"))
+        .map_or(&[][..], |p| p.value);
+    matrix.chunks_exact(12).map(|entry| {
+        let cell = |i: usize| u32::from_be_bytes(entry[i * 4..i * 4 + 4].try_into().unwrap());
+        (cell(0) as usize, cell(1) as usize, cell(2))
+    })
+}
+
+/// Returns an iterator over the enabled interrupt controllers with
+/// registers (e.g. the PLIC or the APLIC, not the per-hart local
+/// controllers), or the ones of the ACPI MADT without a device tree.
+pub fn interrupt_controllers() -> impl Iterator<Item = DeviceNode> {
+    fdt()
+        .into_iter()
+        .flat_map(|fdt| fdt.all_nodes())
+        .filter(|&node| node.property("interrupt-controller").is_some() && node_enabled(node))
+        .filter_map(DeviceNode::from_node)
+        .chain(
+            fdt()
+                .is_none()
+                .then(acpi::interrupt_controllers)
+                .into_iter()
+                .flatten(),
+        )
+}
+
+/// Returns an iterator over the enabled UARTs.
+pub fn uarts() -> impl Iterator<Item = DeviceNode> {
+    fdt()
+        .into_iter()
+        .flat_map(|fdt| fdt.all_nodes())
+        .filter(|&node| {
+            node.compatible()
+                .is_some_and(|c| c.all().any(|c| UART_COMPATIBLES.contains(&c)))
+                && node_enabled(node)
+        })
+        .filter_map(DeviceNode::from_node)
+}
+
+/// Returns an iterator over the enabled devices on the `simple-bus` buses
+/// (e.g. `/soc`), i.e. the MMIO ranges of the platform.
+pub fn mmio_devices() -> impl Iterator<Item = DeviceNode> {
+    fdt()
+        .into_iter()
+        .flat_map(|fdt| fdt.all_nodes())
+        .filter(|&node| {
+            node.compatible()
+                .is_some_and(|c| c.all().any(|c| c == "simple-bus"))
+        })
+        .flat_map(|bus| bus.children())
+        .filter(|&node| node_enabled(node))
+        .filter_map(DeviceNode::from_node)
+        .filter(|dev| dev.size != 0)
+}
+
+/// Returns the frequency of the `time` CSR (the `timebase-frequency` of
+/// `/cpus`, or of the first CPU node, or else of the ACPI RHCT), or
+/// [`None`] if it is not given.
+pub fn timebase_frequency() -> Option<usize> {
+    let Some(fdt) = fdt() else {
+        return acpi::timebase_frequency();
+    };
+    let cpus = fdt.find_node("/cpus")?;
+    cpus.property("timebase-frequency")
+        .and_then(|p| p.as_usize())
+        .or_else(|| {
+            cpus.children()
+                .filter(|node| node.name.starts_with("cpu@"))
+                .find_map(|node| node.property("timebase-frequency")?.as_usize())
+        })
+        .filter(|&freq| freq != 0)
+}
+
+/// A region of physical memory that the device tree marks as reserved.
+#[derive(Debug, Clone, Copy)]
+pub struct ReservedNode {
+    /// The start physical address of the region.
+    pub paddr: usize,
+    /// The size in bytes of the region.
+    pub size: usize,
+    /// The node name, or `"memreserve"` for an entry of the memory
+    /// reservation block (`"fdt"` and `"initrd"` for the blob and the
+    /// initial ramdisk).
+    pub name: &'static str,
+    /// Whether the region must not be mapped at all (the `no-map` property).
+    pub no_map: bool,
+}
+
+/// Returns an iterator over all reserved memory regions: the entries of the
+/// memory reservation block (`/memreserve/`), the `reg` of each enabled
+/// child of `/reserved-memory`, then the device tree blob itself (`"fdt"`)
+/// and the initial ramdisk (`"initrd"`, see
+/// [`initrd_region`](crate::mem::initrd_region)), that the kernel reads
+/// later.
+///
+/// On RISC-V, the latter includes the firmware regions that OpenSBI protects
+/// with PMP (named `mmode_resv*`). Nodes with only a `size` (to be allocated
+/// dynamically by the OS) are skipped.
+pub fn reserved_memory() -> impl Iterator<Item = ReservedNode> {
+    let memreserve = fdt()
+        .into_iter()
+        .flat_map(|fdt| fdt.memory_reservations())
+        .map(|resv| ReservedNode {
+            paddr: resv.address() as usize,
+            size: resv.size(),
+            name: "memreserve",
+            no_map: false,
+        });
+    let nodes = fdt()
+        .and_then(|fdt| fdt.find_node("/reserved-memory"))
+        .into_iter()
+        .flat_map(|resv| resv.children())
+        .filter(|&node| node_enabled(node))
+        .flat_map(|node| {
+            let no_map = node.property("no-map").is_some();
+            node.reg()
+                .into_iter()
+                .flatten()
+                .map(move |reg| ReservedNode {
+                    paddr: reg.starting_address as usize,
+                    size: reg.size.unwrap_or(0),
+                    name: node.name,
+                    no_map,
+                })
+        });
+    let blob = dtb_paddr().zip(fdt()).map(|(paddr, fdt)| ReservedNode {
+        paddr: paddr.as_usize(),
+        size: fdt.total_size(),
+        name: "fdt",
+        no_map: false,
+    });
+    let initrd = crate::mem::initrd_region().map(|(paddr, size)| ReservedNode {
+        paddr: paddr.as_usize(),
+        size,
+        name: "initrd",
+        no_map: false,
+    });
+    memreserve
+        .chain(nodes)
+        .chain(blob)
+        .chain(initrd)
+        .filter(|resv| resv.size != 0)
+}
+
+/// Returns the physical address and the size of the initial ramdisk, from
+/// the `linux,initrd-start` and `linux,initrd-end` of `/chosen`.
+pub fn initrd() -> Option<(usize, usize)> {
+    let chosen = fdt()?.find_node("/chosen")?;
+    let start = chosen.property("linux,initrd-start")?.as_usize()?;
+    let end = chosen.property("linux,initrd-end")?.as_usize()?;
+    (end > start).then(|| (start, end - start))
+}
+
+/// Returns the random bytes passed by the bootloader (the `rng-seed` of
+/// `/chosen`), if any.
+pub fn rng_seed() -> Option<&'static [u8]> {
+    let seed = fdt()?.find_node("/chosen")?.property("rng-seed")?.value;
+    (!seed.is_empty()).then_some(seed)
+}
+
+/// Returns the boot parameters (the `bootargs` of `/chosen`), if any.
+pub fn bootargs() -> Option<&'static str> {
+    fdt()?
+        .find_node("/chosen")?
+        .property("bootargs")?
+        .as_str()
+        .map(|args| args.trim_end_matches('\0'))
+}
+
+/// Returns the size and the physical address (if fixed) of the memory to
+/// reserve for a crash kernel: the `linux,crashkernel-size` and
+/// `linux,crashkernel-base` of `/chosen`, or else the
+/// `crashkernel=<size>[@<base>]` boot parameter.
+pub fn crashkernel() -> Option<(usize, Option<usize>)> {
+    let chosen = fdt()?.find_node("/chosen")?;
+    if let Some(size) = chosen
+        .property("linux,crashkernel-size")
+        .and_then(|p| p.as_usize())
+    {
+        let base = chosen
+            .property("linux,crashkernel-base")
+            .and_then(|p| p.as_usize());
+        return (size != 0).then_some((size, base));
+    }
+    crate::platform::cmdline::boot_params().crashkernel
+}
+
+/// A `syscon-poweroff` or `syscon-reboot` node: writing `value` to the bits
+/// `mask` of a 32-bit register powers off or resets the machine.
+#[derive(Debug, Clone, Copy)]
+pub struct SysconNode {
+    /// The physical address of the register (the `offset` in the `regmap`).
+    pub paddr: usize,
+    /// The value to write.
+    pub value: u32,
+    /// The bits of the register to write, all of them if not given.
+    pub mask: u32,
+}
+
+/// Returns the first enabled node compatible with `compatible`, e.g.
+/// `"syscon-poweroff"`, as a [`SysconNode`].
+///
+/// As in Linux, a node with a `mask` and no `value` writes the mask.
+pub fn syscon(compatible: &str) -> Option<SysconNode> {
+    let fdt = fdt()?;
+    let node = fdt
+        .find_compatible(&[compatible])
+        .filter(|&node| node_enabled(node))?;
+    let regmap = node.property("regmap")?.as_usize()?;
+    let base = fdt.find_phandle(regmap as u32)?.reg()?.next()?;
+    let offset = node.property("offset")?.as_usize()?;
+    let mask = node.property("mask").and_then(|p| p.as_usize());
+    let value = node.property("value").and_then(|p| p.as_usize()).or(mask)?;
+    Some(SysconNode {
+        paddr: base.starting_address as usize + offset,
+        value: value as u32,
+        mask: mask.map_or(u32::MAX, |mask| mask as u32),
+    })
+}
+
+/// Returns the physical address of the SiFive test device, the test
+/// finisher of QEMU `virt`, if any.
+pub fn test_finisher() -> Option<usize> {
+    let node = fdt()?
+        .find_compatible(&["sifive,test1", "sifive,test0"])
+        .filter(|&node| node_enabled(node))?;
+    Some(node.reg()?.next()?.starting_address as usize)
+}
+
+/// A `riscv,idle-state` node, a low-power state of the CPUs entered with
+/// `sbi_hart_suspend`.
+#[derive(Debug, Clone, Copy)]
+pub struct IdleStateNode {
+    /// The node name, e.g. `"cpu-retentive-0-0"`.
+    pub name: &'static str,
+    /// The suspend type of `sbi_hart_suspend` (`riscv,sbi-suspend-param`),
+    /// non-retentive if bit 31 is set.
+    pub suspend_param: u32,
+    /// The time to enter the state, in microseconds.
+    pub entry_latency_us: u32,
+    /// The time to leave the state, in microseconds.
+    pub exit_latency_us: u32,
+    /// The shortest time in the state worth entering it, in microseconds,
+    /// including the latencies.
+    pub min_residency_us: u32,
+    /// Whether the timer of the CPU stops in the state
+    /// (`local-timer-stop`).
+    pub local_timer_stop: bool,
+}
+
+/// Returns an iterator over the idle states of the CPUs: the nodes that the
+/// `cpu-idle-states` of the first enabled CPU refers to, in order, or else
+/// all the `riscv,idle-state` children of `/cpus/idle-states`.
+///
+/// The CPUs are assumed to have the same idle states. Disabled nodes, and
+/// those with no `riscv,sbi-suspend-param`, are skipped.
+pub fn idle_states() -> impl Iterator<Item = IdleStateNode> {
+    let fdt = fdt();
+    let cpu_states = fdt
+        .and_then(|fdt| fdt.find_node("/cpus"))
+        .into_iter()
+        .flat_map(|cpus| cpus.children())
+        .filter(|node| node.name.starts_with("cpu@") && node_enabled(*node))
+        .find_map(|node| node.property("cpu-idle-states"));
+    let referenced = cpu_states
+        .into_iter()
+        .flat_map(|prop| prop.value.chunks_exact(4))
+        .filter_map(move |phandle| {
+            let phandle = u32::from_be_bytes(phandle.try_into().unwrap());
+            fdt?.find_phandle(phandle)
+        });
+    let all = fdt
+        .filter(|_| cpu_states.is_none())
+        .and_then(|fdt| fdt.find_node("/cpus/idle-states"))
+        .into_iter()
+        .flat_map(|states| states.children())
+        .filter(|node| {
+            node.compatible()
+                .is_some_and(|c| c.all().any(|c| c == "riscv,idle-state"))
+        });
+    referenced
+        .chain(all)
+        .filter(|&node| node_enabled(node))
+        .filter_map(|node| {
+            let prop = |name| node.property(name).and_then(|p| p.as_usize());
+            Some(IdleStateNode {
+                name: node.name,
+                suspend_param: prop("riscv,sbi-suspend-param")? as u32,
+                entry_latency_us: prop("entry-latency-us").unwrap_or(0) as u32,
+                exit_latency_us: prop("exit-latency-us").unwrap_or(0) as u32,
+                min_residency_us: prop("min-residency-us").unwrap_or(0) as u32,
+                local_timer_stop: node.property("local-timer-stop").is_some(),
+            })
+        })
+}
+
+/// Returns the `/cpus/cpu@*` node of the CPU whose hardware ID is `hwid`.
+pub(crate) fn cpu_node(hwid: usize) -> Option<fdt::node::FdtNode<'static, 'static>> {
+    fdt()?
+        .find_node("/cpus")?
+        .children()
+        .filter(|node| node.name.starts_with("cpu@"))
+        .find(|node| node.property("reg").and_then(|p| p.as_usize()) == Some(hwid))
+}
+
+/// An operating point (`opp-*` node) of an `operating-points-v2` table.
+#[derive(Debug, Clone, Copy)]
+pub struct OppNode {
+    /// The frequency in Hz (`opp-hz`).
+    pub hz: u64,
+    /// The target voltage in microvolts (the first cell of
+    /// `opp-microvolt`), if given.
+    pub microvolt: Option<u32>,
+    /// Whether the OPP is only for short boosts (`turbo-mode`).
+    pub turbo: bool,
+}
+
+/// Returns the phandle of the `operating-points-v2` table of the CPU whose
+/// hardware ID is `hwid`, and whether the CPUs of the table share their
+/// clock (`opp-shared`).
+pub fn cpu_opp_table(hwid: usize) -> Option<(u32, bool)> {
+    let phandle = cpu_node(hwid)?
+        .property("operating-points-v2")?
+        .as_usize()? as u32;
+    let table = fdt()?.find_phandle(phandle)?;
+    Some((phandle, table.property("opp-shared").is_some()))
+}
+
+/// Returns an iterator over the enabled OPPs of the `operating-points-v2`
+/// table whose phandle is `phandle`, in node order (by convention, by
+/// increasing frequency).
+pub fn opps(phandle: u32) -> impl Iterator<Item = OppNode> {
+    fdt()
+        .and_then(|fdt| fdt.find_phandle(phandle))
+        .into_iter()
+        .flat_map(|table| table.children())
+        .filter(|&node| node.name.starts_with("opp") && node_enabled(node))
+        .filter_map(|node| {
+            // `/bits/ 64`, an array of them for multiple clocks.
+            let hz = node.property("opp-hz")?.value.get(..8)?;
+            let hz = u64::from_be_bytes(hz.try_into().unwrap());
+            let microvolt = node
+                .property("opp-microvolt")
+                .and_then(|p| p.value.get(..4))
+                .map(|cell| u32::from_be_bytes(cell.try_into().unwrap()));
+            Some(OppNode {
+                hz,
+                microvolt,
+                turbo: node.property("turbo-mode").is_some(),
+            })
+        })
+}
+
+/// The kind of a cache.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CacheType {
+    /// An instruction cache.
+    Instruction,
+    /// A data cache.
+    Data,
+    /// A unified instruction and data cache.
+    Unified,
+}
+
+/// A cache of a CPU: the `i-cache-*` or `d-cache-*` of its `/cpus/cpu@*`
+/// node, or a node of its `next-level-cache` chain.
+#[derive(Debug, Clone, Copy)]
+pub struct CacheNode {
+    /// The level, 1 for the caches of the CPU node.
+    pub level: u8,
+    /// The kind of the cache.
+    pub ty: CacheType,
+    /// The size in bytes, 0 if not given.
+    pub size: usize,
+    /// The size of a line (`*-cache-line-size`, or else
+    /// `*-cache-block-size`), 0 if not given.
+    pub line_size: usize,
+    /// The number of sets, 0 if not given.
+    pub sets: usize,
+    /// The phandle of the cache node, shared by the CPUs that share the
+    /// cache; `None` for the private caches of the CPU node.
+    pub phandle: Option<u32>,
+}
+
+/// The properties of a cache: its size, line size, block size and sets.
+type CacheProps = [&'static str; 4];
+
+const I_CACHE_PROPS: CacheProps = [
+    "i-cache-size",
+    "i-cache-line-size",
+    "i-cache-block-size",
+    "i-cache-sets",
+];
+const D_CACHE_PROPS: CacheProps = [
+    "d-cache-size",
+    "d-cache-line-size",
+    "d-cache-block-size",
+    "d-cache-sets",
+];
+const CACHE_PROPS: CacheProps = [
+    "cache-size",
+    "cache-line-size",
+    "cache-block-size",
+    "cache-sets",
+];
+
+impl CacheNode {
+    /// Reads the cache of `node` with the properties `props`, if it has a
+    /// size or a line size.
+    fn from_node(
+        node: fdt::node::FdtNode,
+        [size, line_size, block_size, sets]: CacheProps,
+        level: u8,
+        ty: CacheType,
+        phandle: Option<u32>,
+    ) -> Option<Self> {
+        let prop = |name| node.property(name).and_then(|p| p.as_usize());
+        let size = prop(size);
+        let line_size = prop(line_size).or_else(|| prop(block_size));
+        if size.is_none() && line_size.is_none() {
+            return None;
+        }
+        Some(Self {
+            level,
+            ty,
+            size: size.unwrap_or(0),
+            line_size: line_size.unwrap_or(0),
+            sets: prop(sets).unwrap_or(0),
+            phandle,
+        })
+    }
+}
+
+/// Returns an iterator over the caches of the CPU whose hardware ID is
+/// `hwid`, by increasing level: the level 1 caches of its node, then the
+/// caches of its `next-level-cache` chain.
+pub fn cpu_caches(hwid: usize) -> impl Iterator<Item = CacheNode> {
+    let node = cpu_node(hwid);
+    let l1 = node.into_iter().flat_map(|node| {
+        [
+            CacheNode::from_node(node, I_CACHE_PROPS, 1, CacheType::Instruction, None),
+            CacheNode::from_node(node, D_CACHE_PROPS, 1, CacheType::Data, None),
+            CacheNode::from_node(node, CACHE_PROPS, 1, CacheType::Unified, None),
+        ]
+        .into_iter()
+        .flatten()
+    });
+    let next = |node: fdt::node::FdtNode<'static, 'static>| {
+        let phandle = node.property("next-level-cache")?.as_usize()? as u32;
+        Some((fdt()?.find_phandle(phandle)?, phandle))
+    };
+    // A malformed chain may loop: stop at the deepest level.
+    let outer = core::iter::successors(node.and_then(next), move |&(node, _)| next(node))
+        .take(MAX_CACHE_LEVELS)
+        .enumerate()
+        .filter_map(|(i, (node, phandle))| {
+            let level = node
+                .property("cache-level")
+                .and_then(|p| p.as_usize())
+                .unwrap_or(i + 2) as u8;
+            CacheNode::from_node(node, CACHE_PROPS, level, CacheType::Unified, Some(phandle))
+        });
+    l1.chain(outer)
+}
+
+/// The maximum number of cache levels of a `next-level-cache` chain.
+const MAX_CACHE_LEVELS: usize = 4;
+
+/// A CPU of the `/cpus/cpu-map` node, with its place in the topology.
+#[derive(Debug, Clone, Copy)]
+pub struct CpuMapNode {
+    /// The hardware ID of the CPU.
+    pub hwid: usize,
+    /// The index of its `socketN`, 0 without sockets.
+    pub socket: usize,
+    /// The index of its innermost `clusterN` in the whole map.
+    pub cluster: usize,
+    /// The index of its `coreN` in the whole map.
+    pub core: usize,
+    /// The index of its `threadN` in the core, 0 without threads.
+    pub thread: usize,
+}
+
+/// The walk of `/cpus/cpu-map`.
+struct CpuMapWalk {
+    nodes: [Option<CpuMapNode>; axconfig::SMP],
+    len: usize,
+    cluster: usize,
+    core: usize,
+}
+
+impl CpuMapWalk {
+    fn push(&mut self, cpu: fdt::node::FdtNode, socket: usize, thread: usize) {
+        let hwid = cpu
+            .property("cpu")
+            .and_then(|p| p.as_usize())
+            .and_then(|phandle| fdt()?.find_phandle(phandle as u32))
+            .and_then(|node| node.property("reg")?.as_usize());
+        if let (Some(hwid), Some(slot)) = (hwid, self.nodes.get_mut(self.len)) {
+            *slot = Some(CpuMapNode {
+                hwid,
+                socket,
+                cluster: self.cluster,
+                core: self.core,
+                thread,
+            });
+            self.len += 1;
+        }
+    }
+
+    fn cluster(&mut self, node: fdt::node::FdtNode, socket: usize, depth: usize) {
+        if depth > MAX_CLUSTER_DEPTH {
+            return;
+        }
+        for child in node.children().filter(|n| n.name.starts_with("cluster")) {
+            self.cluster(child, socket, depth + 1);
+        }
+        let mut has_cores = false;
+        for core in node.children().filter(|n| n.name.starts_with("core")) {
+            has_cores = true;
+            let threads = core.children().filter(|n| n.name.starts_with("thread"));
+            let mut has_threads = false;
+            for (thread, node) in threads.enumerate() {
+                has_threads = true;
+                self.push(node, socket, thread);
+            }
+            if !has_threads {
+                self.push(core, socket, 0);
+            }
+            self.core += 1;
+        }
+        if has_cores {
+            self.cluster += 1;
+        }
+    }
+}
+
+/// The maximum nesting of the `clusterN` nodes.
+const MAX_CLUSTER_DEPTH: usize = 4;
+
+/// Returns an iterator over the CPUs of `/cpus/cpu-map`, with their
+/// sockets, clusters, cores and threads. It is empty if there is no
+/// `cpu-map`.
+pub fn cpu_map() -> impl Iterator<Item = CpuMapNode> {
+    let mut walk = CpuMapWalk {
+        nodes: [None; axconfig::SMP],
+        len: 0,
+        cluster: 0,
+        core: 0,
+    };
+    if let Some(map) = fdt().and_then(|fdt| fdt.find_node("/cpus/cpu-map")) {
+        let mut has_sockets = false;
+        for (socket, node) in map
+            .children()
+            .filter(|n| n.name.starts_with("socket"))
+            .enumerate()
+        {
+            has_sockets = true;
+            walk.cluster(node, socket, 0);
+        }
+        if !has_sockets {
+            walk.cluster(map, 0, 0);
+        }
+    }
+    walk.nodes.into_iter().flatten()
+}
+
+/// A translation of `dma-ranges`: the DMA of a device to the bus addresses
+/// `[bus_addr, bus_addr + size)` reaches the physical addresses from
+/// `paddr`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DmaRange {
+    /// The first bus address.
+    pub bus_addr: u64,
+    /// The first physical address.
+    pub paddr: u64,
+    /// The size in bytes.
+    pub size: u64,
+}
+
+/// The maximum number of `dma-ranges` entries of a bus.
+pub const MAX_DMA_RANGES: usize = 4;
+
+/// The DMA properties of a device node.
+#[derive(Debug, Clone, Copy)]
+pub struct DmaNode {
+    /// The node name.
+    pub name: &'static str,
+    /// Whether the device snoops the CPU caches: it does unless it or its
+    /// bus has `dma-noncoherent`.
+    pub coherent: bool,
+    /// The `dma-ranges` of its bus, only the first [`MAX_DMA_RANGES`]. With
+    /// none, the bus addresses are the physical addresses.
+    pub ranges: [Option<DmaRange>; MAX_DMA_RANGES],
+}
+
+/// Reads a number of `cells` big-endian cells from `value`.
+fn read_cells(value: &mut &[u8], cells: usize) -> Option<u64> {
+    let mut n: u64 = 0;
+    for _ in 0..cells {
+        let cell = value.get(..4)?;
+        n = (n << 32) | u32::from_be_bytes(cell.try_into().unwrap()) as u64;
+        *value = &value[4..];
+    }
+    Some(n)
+}
+
+/// Returns the DMA properties of the first enabled device compatible with
+/// one of `compatible`, at the root or on a bus that is a child of the root
+/// (e.g. `/soc`), whose `dma-ranges` translate to the physical addresses.
+pub fn dma_device(compatible: &[&str]) -> Option<DmaNode> {
+    let root = fdt()?.find_node("/")?;
+    let matches = |node: fdt::node::FdtNode| {
+        node_enabled(node)
+            && node
+                .compatible()
+                .is_some_and(|c| c.all().any(|s| compatible.contains(&s)))
+    };
+    let noncoherent = |node: fdt::node::FdtNode| node.property("dma-noncoherent").is_some();
+    if let Some(node) = root.children().find(|&node| matches(node)) {
+        return Some(DmaNode {
+            name: node.name,
+            coherent: !noncoherent(node),
+            ranges: [None; MAX_DMA_RANGES],
+        });
+    }
+    root.children().find_map(|bus| {
+        let node = bus.children().find(|&node| matches(node))?;
+        let mut ranges = [None; MAX_DMA_RANGES];
+        if let Some(prop) = bus.property("dma-ranges") {
+            let child = bus.cell_sizes();
+            let parent_cells = root.cell_sizes().address_cells;
+            let mut value = prop.value;
+            for range in ranges.iter_mut() {
+                let Some(bus_addr) = read_cells(&mut value, child.address_cells) else {
+                    break;
+                };
+                let (Some(paddr), Some(size)) = (
+                    read_cells(&mut value, parent_cells),
+                    read_cells(&mut value, child.size_cells),
+                ) else {
+                    break;
+                };
+                *range = Some(DmaRange {
+                    bus_addr,
+                    paddr,
+                    size,
+                });
+            }
+        }
+        Some(DmaNode {
+            name: node.name,
+            coherent: !noncoherent(node) && !noncoherent(bus),
+            ranges,
+        })
+    })
+}
+
+/// Returns the physical address and the size of the `shared-dma-pool` in
+/// `/reserved-memory`, the memory of the coherent DMA buffers.
+pub fn dma_pool() -> Option<(usize, usize)> {
+    let node = fdt()?
+        .find_node("/reserved-memory")?
+        .children()
+        .filter(|&node| node_enabled(node))
+        .find(|node| {
+            node.compatible()
+                .is_some_and(|c| c.all().any(|s| s == "shared-dma-pool"))
+        })?;
+    let reg = node.reg()?.next()?;
+    Some((reg.starting_address as usize, reg.size?))
+}
+
+/// Returns the physical address of the registers of the RISC-V IOMMU
+/// (`riscv,iommu`), if any.
+pub fn iommu() -> Option<usize> {
+    let node = fdt()?
+        .find_compatible(&["riscv,iommu"])
+        .filter(|&node| node_enabled(node))?;
+    Some(node.reg()?.next()?.starting_address as usize)
+}
+
+/// Returns the IOMMU device ID of the first enabled device compatible with
+/// one of `compatible`: the cell after the IOMMU phandle in its `iommus`.
+pub fn iommu_device_id(compatible: &[&str]) -> Option<u32> {
+    let node = fdt()?
+        .find_compatible(compatible)
+        .filter(|&node| node_enabled(node))?;
+    let mut value = node.property("iommus")?.value;
+    read_cells(&mut value, 1)?;
+    read_cells(&mut value, 1).map(|id| id as u32)
+}
+
+/// Returns the IOMMU device ID of the PCI requester ID `rid`, from the
+/// `iommu-map` of the first PCI host bridge that has one: the entries
+/// `(rid-base, iommu, iommu-base, length)` map `rid-base..rid-base + length`
+/// to the IDs from `iommu-base`.
+pub fn pci_iommu_device_id(rid: u32) -> Option<u32> {
+    let node = fdt()?
+        .all_nodes()
+        .filter(|&node| node_enabled(node))
+        .find(|node| {
+            node.property("device_type").and_then(|p| p.as_str()) == Some("pci")
+                && node.property("iommu-map").is_some()
+        })?;
+    let mut value = node.property("iommu-map")?.value;
+    while let (Some(rid_base), Some(_), Some(base), Some(len)) = (
+        read_cells(&mut value, 1),
+        read_cells(&mut value, 1),
+        read_cells(&mut value, 1),
+        read_cells(&mut value, 1),
+    ) {
+        let (rid_base, base, len) = (rid_base as u32, base as u32, len as u32);
+        if (rid_base..rid_base.wrapping_add(len)).contains(&rid) {
+            return Some(rid - rid_base + base);
+        }
+    }
+    None
+}
+
+/// The maximum number of `ranges` of a PCI host bridge.
+pub const MAX_PCI_RANGES: usize = 4;
+/// The maximum number of `interrupt-map` entries of a PCI host bridge.
+pub const MAX_PCI_INTX_MAP: usize = 32;
+
+/// The kind of a window of a PCI host bridge, from the space code of its
+/// `ranges`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PciSpace {
+    /// I/O ports.
+    Io,
+    /// 32-bit memory.
+    Mem32,
+    /// 64-bit memory.
+    Mem64,
+}
+
+/// A window of a PCI host bridge: the PCI addresses from `pci_addr` are the
+/// physical addresses from `paddr`.
+#[derive(Debug, Clone, Copy)]
+pub struct PciRangeNode {
+    /// The address space.
+    pub space: PciSpace,
+    /// Whether the window is prefetchable.
+    pub prefetchable: bool,
+    /// The first PCI address.
+    pub pci_addr: u64,
+    /// The first physical address.
+    pub paddr: u64,
+    /// The size in bytes.
+    pub size: u64,
+}
+
+/// An entry of the `interrupt-map` of a PCI host bridge: the INTx `pin`
+/// (1 to 4) of the function whose address (bus, device and function in
+/// bits 23:8) is `devfn_hi` is the interrupt `irq` of the controller.
+#[derive(Debug, Clone, Copy)]
+pub struct PciIntxMapNode {
+    /// The `phys.hi` cell of the child unit address.
+    pub devfn_hi: u32,
+    /// The INTx pin.
+    pub pin: u32,
+    /// The first interrupt cell of the parent.
+    pub irq: usize,
+}
+
+/// A `pci-host-ecam-generic` host bridge.
+#[derive(Debug, Clone, Copy)]
+pub struct PciHostNode {
+    /// The physical address of the ECAM window.
+    pub ecam_paddr: usize,
+    /// The size of the ECAM window.
+    pub ecam_size: usize,
+    /// The first and last bus numbers (`bus-range`).
+    pub bus_range: (u8, u8),
+    /// The windows (`ranges`), only the first [`MAX_PCI_RANGES`].
+    pub ranges: [Option<PciRangeNode>; MAX_PCI_RANGES],
+    /// The `phys.hi` and pin cells of `interrupt-map-mask`.
+    pub intx_mask: (u32, u32),
+    /// The INTx routing (`interrupt-map`), only the first
+    /// [`MAX_PCI_INTX_MAP`].
+    pub intx_map: [Option<PciIntxMapNode>; MAX_PCI_INTX_MAP],
+}
+
+/// Returns the first enabled `pci-host-ecam-generic` host bridge.
+///
+/// Without a device tree, it is the first ECAM window of the ACPI MCFG.
+pub fn pci_host() -> Option<PciHostNode> {
+    let Some(fdt) = fdt() else {
+        return acpi::pci_host();
+    };
+    let node = fdt
+        .find_compatible(&["pci-host-ecam-generic"])
+        .filter(|&node| node_enabled(node))?;
+    let ecam = node.reg()?.next()?;
+    let bus_range = node
+        .property("bus-range")
+        .and_then(|p| {
+            let mut value = p.value;
+            Some((
+                read_cells(&mut value, 1)? as u8,
+                read_cells(&mut value, 1)? as u8,
+            ))
+        })
+        .unwrap_or((0, 0xff));
+
+    let mut ranges = [None; MAX_PCI_RANGES];
+    let parent_cells = fdt.find_node("/")?.cell_sizes().address_cells;
+    if let Some(prop) = node.property("ranges") {
+        let mut value = prop.value;
+        for range in ranges.iter_mut() {
+            let (Some(hi), Some(pci_addr), Some(paddr), Some(size)) = (
+                read_cells(&mut value, 1),
+                read_cells(&mut value, 2),
+                read_cells(&mut value, parent_cells),
+                read_cells(&mut value, 2),
+            ) else {
+                break;
+            };
+            let space = match (hi >> 24) & 0x3 {
+                1 => PciSpace::Io,
+                2 => PciSpace::Mem32,
+                3 => PciSpace::Mem64,
+                _ => continue,
+            };
+            *range = Some(PciRangeNode {
+                space,
+                prefetchable: hi & (1 << 30) != 0,
+                pci_addr,
+                paddr,
+                size,
+            });
+        }
+    }
+
+    let intx_mask = node
+        .property("interrupt-map-mask")
+        .and_then(|p| {
+            let mut value = p.value;
+            let hi = read_cells(&mut value, 1)?;
+            read_cells(&mut value, 2)?;
+            Some((hi as u32, read_cells(&mut value, 1)? as u32))
+        })
+        .unwrap_or((0xffff_ff00, 0x7));
+    let mut intx_map = [None; MAX_PCI_INTX_MAP];
+    if let Some(prop) = node.property("interrupt-map") {
+        let mut value = prop.value;
+        for entry in intx_map.iter_mut() {
+            let (Some(hi), Some(_), Some(pin), Some(phandle)) = (
+                read_cells(&mut value, 1),
+                read_cells(&mut value, 2),
+                read_cells(&mut value, 1),
+                read_cells(&mut value, 1),
+            ) else {
+                break;
+            };
+            let Some(parent) = fdt.find_phandle(phandle as u32) else {
+                break;
+            };
+            let cells = |name| parent.property(name).and_then(|p| p.as_usize());
+            let address_cells = cells("#address-cells").unwrap_or(0);
+            let interrupt_cells = cells("#interrupt-cells").unwrap_or(1);
+            // The first interrupt cell, the others are flags.
+            let (Some(_), Some(irq), Some(_)) = (
+                read_cells(&mut value, address_cells),
+                read_cells(&mut value, 1),
+                read_cells(&mut value, interrupt_cells.saturating_sub(1)),
+            ) else {
+                break;
+            };
+            *entry = Some(PciIntxMapNode {
+                devfn_hi: hi as u32,
+                pin: pin as u32,
+                irq: irq as usize,
+            });
+        }
+    }
+
+    Some(PciHostNode {
+        ecam_paddr: ecam.starting_address as usize,
+        ecam_size: ecam.size.unwrap_or(0),
+        bus_range,
+        ranges,
+        intx_mask,
+        intx_map,
+    })
+}
@@ -1,5 +1,26 @@
 //! Platform-specific operations.
 
+#[cfg(not(target_arch = "x86_64"))]
+pub mod acpi;
+#[cfg(not(target_arch = "x86_64"))]
+pub mod cmdline;
+#[cfg(any(target_arch = "riscv32", target_arch = "riscv64"))]
+pub mod cpufreq;
+#[cfg(not(target_arch = "x86_64"))]
+pub mod dt;
+#[cfg(any(target_arch = "riscv32", target_arch = "riscv64"))]
+pub mod numa;
+#[cfg(any(target_arch = "riscv32", target_arch = "riscv64"))]
+pub mod pci;
+#[cfg(not(target_arch = "x86_64"))]
+pub mod rtc;
+#[cfg(any(target_arch = "riscv32", target_arch = "riscv64"))]
+pub mod topology;
+#[cfg(not(target_arch = "x86_64"))]
+pub mod uart;
+#[cfg(not(target_arch = "x86_64"))]
+pub mod watchdog;
+
 cfg_if::cfg_if! {
     if #[cfg(target_arch = "aarch64")]{
         mod aarch64_common;
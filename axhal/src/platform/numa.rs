@@ -0,0 +1,252 @@
+//! NUMA nodes: the CPUs and the memory ranges of each node, and the
+//! distances between them.
+//!
+//! They come from the `numa-node-id` of the CPU and `/memory` nodes of the
+//! device tree, and its `numa-distance-map-v1` node; or else, under ACPI,
+//! from the SRAT and the SLIT. Without either, there is a single node 0
+//! with all the CPUs and the memory.
+//!
+//! They are read once by [`init`].
+
+use lazy_init::LazyInit;
+
+use crate::cpu::{cpu_to_hartid, hartid_to_cpu, CpuMask};
+use crate::mem::PhysAddr;
+use crate::platform::{acpi, dt};
+
+/// The maximum number of NUMA nodes.
+pub const MAX_NUMA_NODES: usize = 8;
+
+/// The maximum number of memory ranges of all the nodes.
+pub const MAX_NUMA_MEM_RANGES: usize = 16;
+
+/// The distance from a node to itself.
+pub const LOCAL_DISTANCE: u32 = 10;
+
+/// The distance from a node to another, when not given.
+pub const REMOTE_DISTANCE: u32 = 20;
+
+/// A memory range of a node.
+#[derive(Debug, Clone, Copy)]
+pub struct NumaMemRange {
+    /// The node.
+    pub node: usize,
+    /// The start physical address.
+    pub paddr: PhysAddr,
+    /// The size in bytes.
+    pub size: usize,
+}
+
+struct Numa {
+    cpu_nodes: [usize; axconfig::SMP],
+    ranges: [Option<NumaMemRange>; MAX_NUMA_MEM_RANGES],
+    distances: [[u32; MAX_NUMA_NODES]; MAX_NUMA_NODES],
+    num_nodes: usize,
+}
+
+impl Numa {
+    fn new() -> Self {
+        let mut distances = [[REMOTE_DISTANCE; MAX_NUMA_NODES]; MAX_NUMA_NODES];
+        for (i, row) in distances.iter_mut().enumerate() {
+            row[i] = LOCAL_DISTANCE;
+        }
+        Self {
+            cpu_nodes: [0; axconfig::SMP],
+            ranges: [None; MAX_NUMA_MEM_RANGES],
+            distances,
+            num_nodes: 1,
+        }
+    }
+
+    /// Returns `node` if it is below [`MAX_NUMA_NODES`], and counts it.
+    fn node(&mut self, node: usize) -> Option<usize> {
+        if node >= MAX_NUMA_NODES {
+            warn!("NUMA node {} beyond the limit, ignored", node);
+            return None;
+        }
+        self.num_nodes = self.num_nodes.max(node + 1);
+        Some(node)
+    }
+
+    fn set_cpu(&mut self, hartid: usize, node: usize) {
+        if let (Some(cpu_id), Some(node)) = (hartid_to_cpu(hartid), self.node(node)) {
+            self.cpu_nodes[cpu_id] = node;
+        }
+    }
+
+    fn add_range(&mut self, node: usize, paddr: usize, size: usize) {
+        let Some(node) = self.node(node) else {
+            return;
+        };
+        match self.ranges.iter_mut().find(|slot| slot.is_none()) {
+            Some(slot) => {
+                *slot = Some(NumaMemRange {
+                    node,
+                    paddr: paddr.into(),
+                    size,
+                })
+            }
+            None => warn!("Too many NUMA memory ranges, ignore {:#x}", paddr),
+        }
+    }
+
+    fn set_distance(&mut self, from: usize, to: usize, distance: u32) {
+        if from < MAX_NUMA_NODES && to < MAX_NUMA_NODES {
+            self.distances[from][to] = distance;
+        }
+    }
+
+    /// Reads the nodes from the device tree, returns whether it has any.
+    fn read_dt(&mut self) -> bool {
+        let mut found = false;
+        for cpu in dt::cpus().filter(|cpu| cpu.enabled) {
+            if let Some(node) = cpu.numa_node {
+                found = true;
+                self.set_cpu(cpu.hwid, node);
+            }
+        }
+        for mem in dt::memory() {
+            if let Some(node) = mem.numa_node {
+                found = true;
+                self.add_range(node, mem.paddr, mem.size);
+            }
+        }
+        if found {
+            for (from, to, distance) in dt::numa_distances() {
+                self.set_distance(from, to, distance);
+            }
+        }
+        found
+    }
+
+    /// Reads the nodes from the SRAT and the SLIT, returns whether there is
+    /// a SRAT.
+    fn read_acpi(&mut self) -> bool {
+        let Some(srat) = acpi::table(b"SRAT") else {
+            return false;
+        };
+        for entry in acpi::srat_entries(srat) {
+            match entry {
+                acpi::SratEntry::Memory {
+                    domain,
+                    paddr,
+                    size,
+                    enabled: true,
+                } => self.add_range(domain as usize, paddr, size),
+                acpi::SratEntry::Rintc {
+                    domain,
+                    uid,
+                    enabled: true,
+                } => {
+                    let hartid = acpi::madt_entries().find_map(|entry| match entry {
+                        acpi::MadtEntry::Rintc {
+                            hartid,
+                            uid: u,
+                            enabled: true,
+                            ..
+                        } if u == uid => Some(hartid),
+                        _ => None,
+                    });
+                    if let Some(hartid) = hartid {
+                        self.set_cpu(hartid, domain as usize);
+                    }
+                }
+                _ => {}
+            }
+        }
+        if let Some(slit) = acpi::table(b"SLIT") {
+            for (from, to, distance) in acpi::slit_distances(slit) {
+                self.set_distance(from as usize, to as usize, distance);
+            }
+        }
+        true
+    }
+}
+
+static NUMA: LazyInit<Numa> = LazyInit::new();
+
+/// Reads the NUMA nodes from the device tree, or else from the ACPI tables.
+///
+/// It must be called after the hart IDs of the CPUs are known, i.e. after
+/// `arch_init_early`.
+pub(crate) fn init() {
+    let mut numa = Numa::new();
+    if numa.read_dt() || numa.read_acpi() {
+        info!("{} NUMA nodes", numa.num_nodes);
+        for range in numa.ranges.iter().flatten() {
+            info!(
+                "NUMA node {}: [{:#x}, {:#x})",
+                range.node,
+                range.paddr,
+                range.paddr.as_usize() + range.size
+            );
+        }
+    }
+    NUMA.init_by(numa);
+}
+
+/// Returns the number of NUMA nodes (the highest node ID plus one), 1
+/// without NUMA.
+pub fn num_nodes() -> usize {
+    if NUMA.is_init() {
+        NUMA.num_nodes
+    } else {
+        1
+    }
+}
+
+/// Returns the node of the CPU `cpu_id`, 0 without NUMA.
+pub fn cpu_to_node(cpu_id: usize) -> usize {
+    if NUMA.is_init() {
+        NUMA.cpu_nodes.get(cpu_id).copied().unwrap_or(0)
+    } else {
+        0
+    }
+}
+
+/// Returns the CPUs of the node `node`.
+pub fn node_cpus(node: usize) -> CpuMask {
+    let mut mask = CpuMask::new();
+    for cpu_id in (0..axconfig::SMP).filter(|&cpu_id| cpu_to_hartid(cpu_id).is_some()) {
+        if cpu_to_node(cpu_id) == node {
+            mask.insert(cpu_id);
+        }
+    }
+    mask
+}
+
+/// Returns an iterator over the memory ranges of all the nodes. It is empty
+/// without NUMA: all the memory is in the node 0.
+pub fn memory_ranges() -> impl Iterator<Item = NumaMemRange> {
+    NUMA.is_init()
+        .then(|| NUMA.ranges)
+        .into_iter()
+        .flatten()
+        .flatten()
+}
+
+/// Returns an iterator over the memory ranges of the node `node`.
+pub fn node_memory(node: usize) -> impl Iterator<Item = NumaMemRange> {
+    memory_ranges().filter(move |range| range.node == node)
+}
+
+/// Returns the node of the physical address `paddr`: the one of its
+/// memory range, or 0 if it is in none.
+pub fn paddr_to_node(paddr: PhysAddr) -> usize {
+    memory_ranges()
+        .find(|range| (range.paddr..range.paddr + range.size).contains(&paddr))
+        .map_or(0, |range| range.node)
+}
+
+/// Returns the distance from the node `from` to the node `to`
+/// ([`LOCAL_DISTANCE`] for the same node).
+pub fn node_distance(from: usize, to: usize) -> u32 {
+    if from == to {
+        return LOCAL_DISTANCE;
+    }
+    if NUMA.is_init() && from < MAX_NUMA_NODES && to < MAX_NUMA_NODES {
+        NUMA.distances[from][to]
+    } else {
+        REMOTE_DISTANCE
+    }
+}
@@ -0,0 +1,611 @@
+//! PCI Express, over the ECAM window of a `pci-host-ecam-generic` host
+//! bridge.
+//!
+//! [`init`] finds the host bridge in the device tree and enumerates the
+//! functions behind it, numbering the buses behind the PCI-to-PCI bridges.
+//! The BARs are assigned from the windows of its `ranges` (64-bit memory
+//! only on the root bus, as the prefetchable windows of the bridges are left
+//! closed), and the INTx pin of each function is routed to an interrupt of
+//! the controller through its `interrupt-map`, after the swizzling of the
+//! bridges. The decoding of the BARs is enabled, the bus mastering is left
+//! to the drivers.
+
+use core::fmt;
+
+use axerrno::LinuxError;
+use lazy_init::LazyInit;
+use memory_addr::VirtAddr;
+
+use crate::mem::{phys_to_virt, PhysAddr};
+use crate::platform::dt::{self, PciSpace, MAX_PCI_RANGES};
+
+/// The maximum number of enumerated functions.
+pub const MAX_PCI_DEVICES: usize = 32;
+
+const PCI_VENDOR_ID: usize = 0x00;
+const PCI_DEVICE_ID: usize = 0x02;
+const PCI_COMMAND: usize = 0x04;
+const PCI_STATUS: usize = 0x06;
+const PCI_CLASS_REVISION: usize = 0x08;
+const PCI_HEADER_TYPE: usize = 0x0e;
+const PCI_BAR0: usize = 0x10;
+const PCI_PRIMARY_BUS: usize = 0x18;
+const PCI_SECONDARY_BUS: usize = 0x19;
+const PCI_SUBORDINATE_BUS: usize = 0x1a;
+const PCI_IO_BASE: usize = 0x1c;
+const PCI_IO_LIMIT: usize = 0x1d;
+const PCI_MEMORY_BASE: usize = 0x20;
+const PCI_MEMORY_LIMIT: usize = 0x22;
+const PCI_PREF_MEMORY_BASE: usize = 0x24;
+const PCI_PREF_MEMORY_LIMIT: usize = 0x26;
+const PCI_IO_BASE_UPPER: usize = 0x30;
+const PCI_IO_LIMIT_UPPER: usize = 0x32;
+const PCI_CAPABILITY_LIST: usize = 0x34;
+const PCI_INTERRUPT_LINE: usize = 0x3c;
+const PCI_INTERRUPT_PIN: usize = 0x3d;
+
+const COMMAND_IO: u16 = 1 << 0;
+const COMMAND_MEMORY: u16 = 1 << 1;
+const COMMAND_BUS_MASTER: u16 = 1 << 2;
+
+const STATUS_CAP_LIST: u16 = 1 << 4;
+
+/// The capabilities follow the header, a loop in the list is cut after that
+/// many.
+const MAX_CAPABILITIES: usize = 48;
+
+const HEADER_TYPE_MASK: u8 = 0x7f;
+const HEADER_TYPE_BRIDGE: u8 = 1;
+const HEADER_MULTI_FUNCTION: u8 = 1 << 7;
+
+/// The alignments of the memory and I/O windows of a bridge.
+const BRIDGE_MEM_ALIGN: u64 = 1 << 20;
+const BRIDGE_IO_ALIGN: u64 = 1 << 12;
+
+/// The address of a PCI function.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct PciAddress {
+    /// The bus number.
+    pub bus: u8,
+    /// The device number, below 32.
+    pub device: u8,
+    /// The function number, below 8.
+    pub function: u8,
+}
+
+impl PciAddress {
+    /// Creates the address of `function` of `device` on `bus`.
+    pub const fn new(bus: u8, device: u8, function: u8) -> Self {
+        Self {
+            bus,
+            device,
+            function,
+        }
+    }
+
+    /// Returns the requester ID of the function, e.g. its IOMMU device ID
+    /// through [`dt::pci_iommu_device_id`].
+    pub const fn rid(self) -> u16 {
+        ((self.bus as u16) << 8) | ((self.device as u16) << 3) | self.function as u16
+    }
+}
+
+impl fmt::Display for PciAddress {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:02x}:{:02x}.{}", self.bus, self.device, self.function)
+    }
+}
+
+/// A value of the configuration space: `u8`, `u16` or `u32`.
+pub trait ConfigValue: Copy {
+    /// Reads the value at `vaddr`.
+    ///
+    /// # Safety
+    ///
+    /// `vaddr` must be a mapped configuration register.
+    unsafe fn read(vaddr: usize) -> Self;
+    /// Writes `value` at `vaddr`.
+    ///
+    /// # Safety
+    ///
+    /// `vaddr` must be a mapped configuration register.
+    unsafe fn write(vaddr: usize, value: Self);
+}
+
+macro_rules! impl_config_value {
+    ($($ty:ty),*) => {
+        $(impl ConfigValue for $ty {
+            unsafe fn read(vaddr: usize) -> Self {
+                (vaddr as *const $ty).read_volatile()
+            }
+
+            unsafe fn write(vaddr: usize, value: Self) {
+                (vaddr as *mut $ty).write_volatile(value)
+            }
+        })*
+    };
+}
+
+impl_config_value!(u8, u16, u32);
+
+/// The address space of a BAR.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PciBarKind {
+    /// I/O ports, accessed through the memory on RISC-V.
+    Io,
+    /// 32-bit memory.
+    Mem32,
+    /// 64-bit memory, in two BARs.
+    Mem64,
+}
+
+/// An assigned BAR of a function.
+#[derive(Debug, Clone, Copy)]
+pub struct PciBar {
+    /// The address space.
+    pub kind: PciBarKind,
+    /// Whether the memory is prefetchable.
+    pub prefetchable: bool,
+    /// The address on the PCI bus.
+    pub pci_addr: u64,
+    /// The physical address for the CPU.
+    pub paddr: PhysAddr,
+    /// The size in bytes.
+    pub size: u64,
+}
+
+/// An enumerated PCI function.
+#[derive(Debug, Clone, Copy)]
+pub struct PciDevice {
+    /// Its address.
+    pub addr: PciAddress,
+    /// The vendor ID.
+    pub vendor_id: u16,
+    /// The device ID.
+    pub device_id: u16,
+    /// The base class code.
+    pub class: u8,
+    /// The sub-class code.
+    pub subclass: u8,
+    /// The programming interface.
+    pub prog_if: u8,
+    /// The revision ID.
+    pub revision: u8,
+    /// Whether it is a PCI-to-PCI bridge.
+    pub bridge: bool,
+    /// The assigned BARs, by index (the upper half of a 64-bit BAR is
+    /// [`None`]).
+    pub bars: [Option<PciBar>; 6],
+    /// The interrupt of its INTx pin, if it has one and it is routed.
+    pub irq: Option<usize>,
+}
+
+impl PciDevice {
+    /// Lets the function do DMA.
+    pub fn enable_bus_master(&self) -> Result<(), LinuxError> {
+        let command: u16 = pci_config_read(self.addr, PCI_COMMAND)?;
+        pci_config_write(self.addr, PCI_COMMAND, command | COMMAND_BUS_MASTER)
+    }
+}
+
+/// A window of the host bridge, allocated upwards.
+#[derive(Debug, Clone, Copy)]
+struct Window {
+    space: PciSpace,
+    pci_start: u64,
+    pci_end: u64,
+    /// The offset of the physical addresses from the PCI addresses.
+    cpu_offset: u64,
+    /// The next free PCI address.
+    cursor: u64,
+}
+
+impl Window {
+    fn alloc(&mut self, size: u64, align: u64) -> Option<u64> {
+        let addr = self.cursor.checked_add(align - 1)? & !(align - 1);
+        let end = addr.checked_add(size)?;
+        (end <= self.pci_end).then(|| {
+            self.cursor = end;
+            addr
+        })
+    }
+
+    fn align_cursor(&mut self, align: u64) {
+        self.cursor = ((self.cursor + align - 1) & !(align - 1)).min(self.pci_end);
+    }
+}
+
+struct PciHost {
+    ecam: VirtAddr,
+    ecam_size: usize,
+    bus_range: (u8, u8),
+    windows: [Option<Window>; MAX_PCI_RANGES],
+    node: dt::PciHostNode,
+    devices: [Option<PciDevice>; MAX_PCI_DEVICES],
+    count: usize,
+    last_bus: u8,
+}
+
+static PCI_HOST: LazyInit<PciHost> = LazyInit::new();
+
+impl PciHost {
+    fn config_vaddr<T>(&self, addr: PciAddress, offset: usize) -> Result<usize, LinuxError> {
+        if addr.device >= 32
+            || addr.function >= 8
+            || offset >= 4096
+            || offset % core::mem::size_of::<T>() != 0
+        {
+            return Err(LinuxError::EINVAL);
+        }
+        if !(self.bus_range.0..=self.bus_range.1).contains(&addr.bus) {
+            return Err(LinuxError::ENODEV);
+        }
+        let ecam_offset = ((addr.bus - self.bus_range.0) as usize) << 20
+            | (addr.device as usize) << 15
+            | (addr.function as usize) << 12
+            | offset;
+        if self.ecam_size != 0 && ecam_offset >= self.ecam_size {
+            return Err(LinuxError::ENODEV);
+        }
+        Ok(self.ecam.as_usize() + ecam_offset)
+    }
+
+    fn read<T: ConfigValue>(&self, addr: PciAddress, offset: usize) -> T {
+        unsafe { T::read(self.config_vaddr::<T>(addr, offset).unwrap()) }
+    }
+
+    fn write<T: ConfigValue>(&self, addr: PciAddress, offset: usize, value: T) {
+        unsafe { T::write(self.config_vaddr::<T>(addr, offset).unwrap(), value) }
+    }
+
+    fn window(&mut self, space: PciSpace) -> Option<&mut Window> {
+        self.windows
+            .iter_mut()
+            .flatten()
+            .find(|window| window.space == space)
+    }
+
+    /// Sizes and assigns the `count` BARs of `addr`.
+    fn assign_bars(
+        &mut self,
+        addr: PciAddress,
+        count: usize,
+        on_root: bool,
+    ) -> [Option<PciBar>; 6] {
+        let mut bars = [None; 6];
+        let mut index = 0;
+        while index < count {
+            let offset = PCI_BAR0 + 4 * index;
+            let orig: u32 = self.read(addr, offset);
+            self.write(addr, offset, u32::MAX);
+            let mask: u32 = self.read(addr, offset);
+            let io = orig & 1 != 0;
+            let is_64 = !io && (orig >> 1) & 0x3 == 2;
+            let prefetchable = !io && orig & (1 << 3) != 0;
+            let size_mask = if io {
+                (mask & !0x3) as u64 | 0xffff_ffff_ffff_0000
+            } else if is_64 {
+                self.write(addr, offset + 4, u32::MAX);
+                let upper: u32 = self.read(addr, offset + 4);
+                (mask & !0xf) as u64 | (upper as u64) << 32
+            } else {
+                (mask & !0xf) as u64 | 0xffff_ffff_0000_0000
+            };
+            let this = index;
+            index += if is_64 { 2 } else { 1 };
+            let size = (!size_mask).wrapping_add(1);
+            if mask == 0 || size == 0 {
+                self.write(addr, offset, 0u32);
+                continue;
+            }
+
+            let kind = match (io, is_64) {
+                (true, _) => PciBarKind::Io,
+                (false, true) => PciBarKind::Mem64,
+                (false, false) => PciBarKind::Mem32,
+            };
+            let window = match kind {
+                PciBarKind::Io => self.window(PciSpace::Io),
+                PciBarKind::Mem64 if on_root && self.window(PciSpace::Mem64).is_some() => {
+                    self.window(PciSpace::Mem64)
+                }
+                _ => self.window(PciSpace::Mem32),
+            };
+            let Some((pci_addr, cpu_offset)) =
+                window.and_then(|window| Some((window.alloc(size, size)?, window.cpu_offset)))
+            else {
+                warn!(
+                    "PCI {}: no space for BAR {} ({:#x} bytes)",
+                    addr, this, size
+                );
+                continue;
+            };
+            self.write(
+                addr,
+                offset,
+                pci_addr as u32 | (orig & if io { 0x3 } else { 0xf }),
+            );
+            if is_64 {
+                self.write(addr, offset + 4, (pci_addr >> 32) as u32);
+            }
+            bars[this] = Some(PciBar {
+                kind,
+                prefetchable,
+                pci_addr,
+                paddr: PhysAddr::from(pci_addr.wrapping_add(cpu_offset) as usize),
+                size,
+            });
+        }
+        bars
+    }
+
+    /// Returns the interrupt of the INTx `pin` of the function `addr` of the
+    /// root bus, from the `interrupt-map`.
+    fn route_intx(&self, addr: PciAddress, pin: u8) -> Option<usize> {
+        let (hi_mask, pin_mask) = self.node.intx_mask;
+        let hi = ((addr.bus as u32) << 16)
+            | ((addr.device as u32) << 11)
+            | ((addr.function as u32) << 8);
+        self.node
+            .intx_map
+            .iter()
+            .flatten()
+            .find(|entry| entry.devfn_hi == hi & hi_mask && entry.pin == pin as u32 & pin_mask)
+            .map(|entry| entry.irq)
+    }
+
+    /// Enumerates the functions on `bus`. `upstream` is the bridge of the
+    /// root bus above it, and the sum of the device numbers of the bridges
+    /// below that one, for the INTx swizzling.
+    fn scan_bus(&mut self, bus: u8, upstream: Option<(PciAddress, usize)>) {
+        for device in 0..32 {
+            let header: u8 = self.read(PciAddress::new(bus, device, 0), PCI_HEADER_TYPE);
+            let functions = if header & HEADER_MULTI_FUNCTION != 0 {
+                8
+            } else {
+                1
+            };
+            for function in 0..functions {
+                let addr = PciAddress::new(bus, device, function);
+                if self.read::<u16>(addr, PCI_VENDOR_ID) != 0xffff {
+                    self.scan_function(addr, upstream);
+                }
+            }
+        }
+    }
+
+    fn scan_function(&mut self, addr: PciAddress, upstream: Option<(PciAddress, usize)>) {
+        let class_revision: u32 = self.read(addr, PCI_CLASS_REVISION);
+        let header: u8 = self.read(addr, PCI_HEADER_TYPE);
+        let bridge = header & HEADER_TYPE_MASK == HEADER_TYPE_BRIDGE;
+        let command: u16 = self.read(addr, PCI_COMMAND);
+        self.write(addr, PCI_COMMAND, command & !(COMMAND_IO | COMMAND_MEMORY));
+        let bars = self.assign_bars(addr, if bridge { 2 } else { 6 }, upstream.is_none());
+
+        let pin: u8 = self.read(addr, PCI_INTERRUPT_PIN);
+        let irq = (1..=4).contains(&pin).then(|| match upstream {
+            None => self.route_intx(addr, pin),
+            Some((root, sum)) => {
+                let pin = ((pin as usize - 1 + sum + addr.device as usize) % 4 + 1) as u8;
+                self.route_intx(root, pin)
+            }
+        });
+        let irq = irq.flatten();
+        if let Some(irq) = irq {
+            self.write(addr, PCI_INTERRUPT_LINE, irq as u8);
+        }
+
+        let mut command = command & !(COMMAND_IO | COMMAND_MEMORY);
+        for bar in bars.iter().flatten() {
+            command |= match bar.kind {
+                PciBarKind::Io => COMMAND_IO,
+                _ => COMMAND_MEMORY,
+            };
+        }
+        if bridge {
+            let child = match upstream {
+                None => (addr, 0),
+                Some((root, sum)) => (root, sum + addr.device as usize),
+            };
+            self.scan_bridge(addr, child);
+            command |= COMMAND_IO | COMMAND_MEMORY | COMMAND_BUS_MASTER;
+        }
+        self.write(addr, PCI_COMMAND, command);
+
+        let device = PciDevice {
+            addr,
+            vendor_id: self.read(addr, PCI_VENDOR_ID),
+            device_id: self.read(addr, PCI_DEVICE_ID),
+            class: (class_revision >> 24) as u8,
+            subclass: (class_revision >> 16) as u8,
+            prog_if: (class_revision >> 8) as u8,
+            revision: class_revision as u8,
+            bridge,
+            bars,
+            irq,
+        };
+        info!(
+            "PCI {}: {:04x}:{:04x} class {:02x}{:02x}{:02x}{}",
+            addr,
+            device.vendor_id,
+            device.device_id,
+            device.class,
+            device.subclass,
+            device.prog_if,
+            if bridge { " (bridge)" } else { "" }
+        );
+        if self.count == MAX_PCI_DEVICES {
+            warn!("PCI {}: too many functions, not recorded", addr);
+            return;
+        }
+        self.devices[self.count] = Some(device);
+        self.count += 1;
+    }
+
+    /// Numbers the bus behind the bridge `addr`, enumerates it, and opens
+    /// the memory and I/O windows of the bridge over what was assigned
+    /// behind it.
+    fn scan_bridge(&mut self, addr: PciAddress, upstream: (PciAddress, usize)) {
+        if self.last_bus == self.bus_range.1 {
+            warn!("PCI {}: no bus number left", addr);
+            return;
+        }
+        self.last_bus += 1;
+        let secondary = self.last_bus;
+        self.write(addr, PCI_PRIMARY_BUS, addr.bus);
+        self.write(addr, PCI_SECONDARY_BUS, secondary);
+        self.write(addr, PCI_SUBORDINATE_BUS, self.bus_range.1);
+
+        let start = |host: &mut Self, space, align| {
+            host.window(space).map(|window| {
+                window.align_cursor(align);
+                window.cursor
+            })
+        };
+        let mem_start = start(self, PciSpace::Mem32, BRIDGE_MEM_ALIGN);
+        let io_start = start(self, PciSpace::Io, BRIDGE_IO_ALIGN);
+        self.scan_bus(secondary, Some(upstream));
+        let mem_end = start(self, PciSpace::Mem32, BRIDGE_MEM_ALIGN);
+        let io_end = start(self, PciSpace::Io, BRIDGE_IO_ALIGN);
+        self.write(addr, PCI_SUBORDINATE_BUS, self.last_bus);
+
+        // An empty window has its base above its limit.
+        match mem_start.zip(mem_end).filter(|(start, end)| start < end) {
+            Some((start, end)) => {
+                self.write(addr, PCI_MEMORY_BASE, (start >> 16) as u16 & 0xfff0);
+                self.write(addr, PCI_MEMORY_LIMIT, ((end - 1) >> 16) as u16 & 0xfff0);
+            }
+            None => {
+                self.write(addr, PCI_MEMORY_BASE, 0xfff0u16);
+                self.write(addr, PCI_MEMORY_LIMIT, 0u16);
+            }
+        }
+        match io_start.zip(io_end).filter(|(start, end)| start < end) {
+            Some((start, end)) => {
+                self.write(addr, PCI_IO_BASE, (start >> 8) as u8 & 0xf0);
+                self.write(addr, PCI_IO_LIMIT, ((end - 1) >> 8) as u8 & 0xf0);
+                self.write(addr, PCI_IO_BASE_UPPER, (start >> 16) as u16);
+                self.write(addr, PCI_IO_LIMIT_UPPER, ((end - 1) >> 16) as u16);
+            }
+            None => {
+                self.write(addr, PCI_IO_BASE, 0xf0u8);
+                self.write(addr, PCI_IO_LIMIT, 0u8);
+            }
+        }
+        self.write(addr, PCI_PREF_MEMORY_BASE, 0xfff0u16);
+        self.write(addr, PCI_PREF_MEMORY_LIMIT, 0u16);
+    }
+}
+
+fn host() -> Result<&'static PciHost, LinuxError> {
+    if PCI_HOST.is_init() {
+        Ok(&PCI_HOST)
+    } else {
+        Err(LinuxError::ENODEV)
+    }
+}
+
+/// Reads the configuration register at `offset` of the function `addr`.
+///
+/// Returns [`LinuxError::EINVAL`] if `offset` is out of the 4 KiB space or
+/// not aligned to the size of `T`, and [`LinuxError::ENODEV`] if there is no
+/// host bridge or `addr` is not behind it. An absent function reads as all
+/// ones.
+pub fn pci_config_read<T: ConfigValue>(addr: PciAddress, offset: usize) -> Result<T, LinuxError> {
+    let vaddr = host()?.config_vaddr::<T>(addr, offset)?;
+    Ok(unsafe { T::read(vaddr) })
+}
+
+/// Writes `value` to the configuration register at `offset` of the function
+/// `addr`, with the errors of [`pci_config_read`].
+pub fn pci_config_write<T: ConfigValue>(
+    addr: PciAddress,
+    offset: usize,
+    value: T,
+) -> Result<(), LinuxError> {
+    let vaddr = host()?.config_vaddr::<T>(addr, offset)?;
+    unsafe { T::write(vaddr, value) };
+    Ok(())
+}
+
+/// Returns the offset in the configuration space of the first capability
+/// `id` of the function `addr` (e.g. 0x05 for MSI), if it has one.
+pub fn pci_find_capability(addr: PciAddress, id: u8) -> Option<usize> {
+    let status: u16 = pci_config_read(addr, PCI_STATUS).ok()?;
+    if status & STATUS_CAP_LIST == 0 {
+        return None;
+    }
+    let mut next: u8 = pci_config_read(addr, PCI_CAPABILITY_LIST).ok()?;
+    for _ in 0..MAX_CAPABILITIES {
+        let offset = (next & !0x3) as usize;
+        if offset < 0x40 {
+            return None;
+        }
+        if pci_config_read::<u8>(addr, offset).ok()? == id {
+            return Some(offset);
+        }
+        next = pci_config_read(addr, offset + 1).ok()?;
+    }
+    None
+}
+
+/// Returns an iterator over the enumerated functions, in bus order.
+pub fn pci_devices() -> impl Iterator<Item = PciDevice> {
+    host()
+        .into_iter()
+        .flat_map(|host| host.devices[..host.count].iter().flatten().copied())
+}
+
+/// Returns the first enumerated function with `vendor_id` and `device_id`.
+pub fn find_pci_device(vendor_id: u16, device_id: u16) -> Option<PciDevice> {
+    pci_devices().find(|dev| dev.vendor_id == vendor_id && dev.device_id == device_id)
+}
+
+/// Finds the PCI host bridge in the device tree, and enumerates it. The
+/// platform initialization calls it.
+pub(crate) fn init() {
+    let Some(node) = dt::pci_host() else {
+        return;
+    };
+    let ecam_paddr = PhysAddr::from(node.ecam_paddr);
+    info!(
+        "PCI host bridge: ECAM @ {:#x}, buses {:#x}-{:#x}",
+        ecam_paddr, node.bus_range.0, node.bus_range.1
+    );
+    let mut windows = [None; MAX_PCI_RANGES];
+    for (window, range) in windows.iter_mut().zip(node.ranges.iter().flatten()) {
+        // Bus address 0 would look like a BAR that is not assigned.
+        let pci_start = range.pci_addr.max(if range.space == PciSpace::Io {
+            0x1000
+        } else {
+            1
+        });
+        *window = Some(Window {
+            space: range.space,
+            pci_start,
+            pci_end: range.pci_addr + range.size,
+            cpu_offset: range.paddr.wrapping_sub(range.pci_addr),
+            cursor: pci_start,
+        });
+    }
+    let mut host = PciHost {
+        ecam: phys_to_virt(ecam_paddr),
+        ecam_size: node.ecam_size,
+        bus_range: node.bus_range,
+        windows,
+        node,
+        devices: [None; MAX_PCI_DEVICES],
+        count: 0,
+        last_bus: node.bus_range.0,
+    };
+    host.scan_bus(node.bus_range.0, None);
+    for window in host.windows.iter().flatten() {
+        debug!(
+            "PCI {:?} window: {:#x} of {:#x} bytes used",
+            window.space,
+            window.cursor - window.pci_start,
+            window.pci_end - window.pci_start
+        );
+    }
+    PCI_HOST.init_by(host);
+}
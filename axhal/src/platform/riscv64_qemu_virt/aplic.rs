@@ -0,0 +1,259 @@
+//! RISC-V AIA APLIC (Advanced Platform-Level Interrupt Controller), the
+//! S-level interrupt domain.
+//!
+//! In direct mode, the APLIC delivers the interrupts to the harts through
+//! its interrupt delivery controls (IDCs), like a PLIC. In MSI mode, it
+//! forwards each of them as an MSI to the [IMSIC](super::imsic) of its
+//! target hart, with the source number as the interrupt ID. The mode is the
+//! one given by the device tree: MSI if the domain has an `msi-parent`.
+//!
+//! The hart index of a hart is its hart ID, as on QEMU virt. All sources are
+//! configured as level-triggered, active high.
+
+use axerrno::LinuxError;
+use core::sync::atomic::{AtomicUsize, Ordering};
+use lazy_init::LazyInit;
+use memory_addr::{PhysAddr, VirtAddr};
+
+use crate::cpu::{_this_cpu_id, cpu_to_hartid};
+use crate::irq::IrqController;
+use crate::mem::phys_to_virt;
+
+use super::imsic::imsic;
+use super::irq::MAX_IRQ_COUNT;
+
+const DOMAINCFG: usize = 0x0000;
+const SOURCECFG_BASE: usize = 0x0004;
+const SETIENUM: usize = 0x1edc;
+const CLRIENUM: usize = 0x1fdc;
+const TARGET_BASE: usize = 0x3004;
+const IDC_BASE: usize = 0x4000;
+const IDC_STRIDE: usize = 32;
+const IDC_IDELIVERY: usize = 0x00;
+const IDC_ITHRESHOLD: usize = 0x08;
+const IDC_CLAIMI: usize = 0x1c;
+
+const DOMAINCFG_IE: u32 = 1 << 8;
+const DOMAINCFG_DM: u32 = 1 << 2;
+
+const SOURCECFG_SM_LEVEL_HIGH: u32 = 6;
+
+const TARGET_HART_SHIFT: u32 = 18;
+const TARGET_IPRIO_MASK: u32 = 0xff;
+const TARGET_EIID_MASK: u32 = 0x7ff;
+
+/// `claimi` (and `topi`) hold the source number in bits 25:16.
+const CLAIMI_ID_SHIFT: u32 = 16;
+
+/// How the APLIC delivers its interrupts.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AplicMode {
+    /// Through its IDCs, one per hart.
+    Direct,
+    /// As MSIs to the IMSIC.
+    Msi,
+}
+
+/// The S-level APLIC domain.
+pub struct Aplic {
+    base: VirtAddr,
+    mode: AplicMode,
+    /// The number of interrupt sources, `1..=num_sources` are valid.
+    num_sources: usize,
+    /// The target CPU of each source.
+    affinity: [AtomicUsize; MAX_IRQ_COUNT],
+}
+
+#[allow(clippy::declare_interior_mutable_const)]
+const AFFINITY_INIT: AtomicUsize = AtomicUsize::new(0);
+
+static APLIC: LazyInit<Aplic> = LazyInit::new();
+
+impl Aplic {
+    fn reg(&self, offset: usize) -> *mut u32 {
+        (self.base.as_usize() + offset) as *mut u32
+    }
+
+    fn read(&self, offset: usize) -> u32 {
+        unsafe { self.reg(offset).read_volatile() }
+    }
+
+    fn write(&self, offset: usize, value: u32) {
+        unsafe { self.reg(offset).write_volatile(value) }
+    }
+
+    fn valid(&self, irq: usize) -> bool {
+        irq != 0 && irq <= self.num_sources
+    }
+
+    fn idc(&self, hartid: usize, offset: usize) -> usize {
+        IDC_BASE + hartid * IDC_STRIDE + offset
+    }
+
+    /// Writes the target register of `irq`: its hart, and its priority in
+    /// direct mode or its interrupt ID in MSI mode.
+    fn set_target(&self, irq: usize, hartid: usize, priority: u32) {
+        let low = match self.mode {
+            AplicMode::Direct => priority.clamp(1, TARGET_IPRIO_MASK),
+            AplicMode::Msi => irq as u32 & TARGET_EIID_MASK,
+        };
+        let target = ((hartid as u32) << TARGET_HART_SHIFT) | low;
+        self.write(TARGET_BASE + (irq - 1) * 4, target);
+    }
+
+    fn target_priority(&self, irq: usize) -> u32 {
+        match self.mode {
+            AplicMode::Direct => self.read(TARGET_BASE + (irq - 1) * 4) & TARGET_IPRIO_MASK,
+            AplicMode::Msi => 0,
+        }
+    }
+
+    /// Sets up the domain on the primary CPU: all sources inactive, then
+    /// the domain enabled in its mode.
+    fn init(&self) {
+        self.write(DOMAINCFG, 0);
+        for irq in 1..=self.num_sources {
+            self.write(SOURCECFG_BASE + (irq - 1) * 4, 0);
+        }
+        let dm = match self.mode {
+            AplicMode::Direct => 0,
+            AplicMode::Msi => DOMAINCFG_DM,
+        };
+        self.write(DOMAINCFG, DOMAINCFG_IE | dm);
+    }
+
+    /// Enables the IDC of the current hart in direct mode, accepting all
+    /// priorities.
+    pub fn init_percpu(&self) {
+        if self.mode != AplicMode::Direct {
+            return;
+        }
+        let hartid = cpu_to_hartid(_this_cpu_id()).expect("current CPU has no hart ID");
+        self.write(self.idc(hartid, IDC_ITHRESHOLD), 0);
+        self.write(self.idc(hartid, IDC_IDELIVERY), 1);
+    }
+}
+
+impl IrqController for Aplic {
+    fn enable(&self, irq: usize) {
+        if !self.valid(irq) {
+            return;
+        }
+        // The target can only be written while the source is active.
+        self.write(SOURCECFG_BASE + (irq - 1) * 4, SOURCECFG_SM_LEVEL_HIGH);
+        let cpu_id = self.affinity[irq].load(Ordering::Relaxed);
+        if let Some(hartid) = cpu_to_hartid(cpu_id) {
+            self.set_target(irq, hartid, self.target_priority(irq));
+            self.write(SETIENUM, irq as u32);
+        }
+    }
+
+    fn disable(&self, irq: usize) {
+        if self.valid(irq) {
+            self.write(CLRIENUM, irq as u32);
+        }
+    }
+
+    fn claim(&self) -> Option<usize> {
+        match self.mode {
+            AplicMode::Direct => {
+                let hartid = cpu_to_hartid(_this_cpu_id())?;
+                match self.read(self.idc(hartid, IDC_CLAIMI)) >> CLAIMI_ID_SHIFT {
+                    0 => None,
+                    irq => Some(irq as usize),
+                }
+            }
+            AplicMode::Msi => imsic()?.claim(),
+        }
+    }
+
+    fn complete(&self, _irq: usize) {
+        // Claiming clears the pending bit, a level source still asserted
+        // becomes pending again.
+    }
+
+    fn set_priority(&self, irq: usize, priority: u32) {
+        // In MSI mode, the interrupt ID is the priority.
+        if self.valid(irq) && self.mode == AplicMode::Direct {
+            let target = self.read(TARGET_BASE + (irq - 1) * 4);
+            let target = (target & !TARGET_IPRIO_MASK) | priority.clamp(1, TARGET_IPRIO_MASK);
+            self.write(TARGET_BASE + (irq - 1) * 4, target);
+        }
+    }
+
+    fn set_affinity(&self, irq: usize, cpu_id: usize) -> Result<(), LinuxError> {
+        if !self.valid(irq) {
+            return Err(LinuxError::EINVAL);
+        }
+        let hartid = cpu_to_hartid(cpu_id).ok_or(LinuxError::EINVAL)?;
+        self.affinity[irq].store(cpu_id, Ordering::Relaxed);
+        if self.read(SOURCECFG_BASE + (irq - 1) * 4) != 0 {
+            self.set_target(irq, hartid, self.target_priority(irq));
+        }
+        Ok(())
+    }
+
+    fn affinity(&self, irq: usize) -> Option<usize> {
+        self.valid(irq)
+            .then(|| self.affinity[irq].load(Ordering::Relaxed))
+    }
+}
+
+/// Returns the APLIC, if it has been found in the device tree.
+pub fn aplic() -> Option<&'static Aplic> {
+    APLIC.is_init().then(|| &*APLIC)
+}
+
+/// Finds the S-level APLIC domain in the device tree (the one that does not
+/// delegate to child domains), and initializes its driver, with the IMSIC
+/// in MSI mode.
+///
+/// Returns whether it is found.
+pub(super) fn init() -> bool {
+    let Some(node) = crate::platform::dt::fdt().and_then(|fdt| {
+        fdt.all_nodes().find(|&node| {
+            node.compatible()
+                .is_some_and(|c| c.all().any(|c| c == "riscv,aplic"))
+                && node.property("riscv,children").is_none()
+                && crate::platform::dt::node_enabled(node)
+        })
+    }) else {
+        return false;
+    };
+    let Some(region) = node.reg().and_then(|mut reg| reg.next()) else {
+        warn!("APLIC without `reg`");
+        return false;
+    };
+    let mode = if node.property("msi-parent").is_some() {
+        if !super::imsic::init() {
+            warn!("APLIC in MSI mode without an IMSIC");
+            return false;
+        }
+        AplicMode::Msi
+    } else {
+        AplicMode::Direct
+    };
+    let mut num_sources = node
+        .property("riscv,num-sources")
+        .and_then(|p| p.as_usize())
+        .unwrap_or(0)
+        .min(MAX_IRQ_COUNT - 1);
+    if let Some(imsic) = imsic() {
+        // The source number is used as the interrupt ID.
+        num_sources = num_sources.min(imsic.num_ids());
+        imsic.reserve_ids(num_sources);
+    }
+    let paddr = PhysAddr::from(region.starting_address as usize);
+    info!(
+        "APLIC @ {:#x}, {} sources, {:?} mode",
+        paddr, num_sources, mode
+    );
+    APLIC.init_by(Aplic {
+        base: phys_to_virt(paddr),
+        mode,
+        num_sources,
+        affinity: [AFFINITY_INIT; MAX_IRQ_COUNT],
+    });
+    APLIC.init();
+    true
+}
@@ -1,14 +1,255 @@
+//! Console input and output, through the UART of the device tree
+//! `stdout-path` if it has a driver, or the SBI console otherwise.
+//!
+//! The SBI console is the debug console (DBCN) extension if the firmware
+//! has it, which writes whole buffers with [`write_bytes`], or the legacy
+//! one that takes an `ecall` per byte. These functions are the ones of
+//! `axhal::console`.
+//!
+//! With `irq`, the input of the UART is interrupt-driven: the received bytes
+//! are buffered in a ring buffer, or delivered to the handler registered by
+//! [`register_console_input_handler`].
+
+#[cfg(feature = "irq")]
+use core::sync::atomic::AtomicBool;
+use core::sync::atomic::{AtomicU8, Ordering};
+use memory_addr::VirtAddr;
+use spinbase::SpinNoIrq;
+
+use crate::arch::sbi;
+use crate::mem::virt_to_phys;
+use crate::platform::uart::console_uart;
+
+/// The size of the buffer of the DBCN writes.
+const DBCN_BUFFER_SIZE: usize = 256;
+
+/// The buffer of the DBCN writes, in the kernel image so that its physical
+/// address is known (the buffers of the callers may be anywhere).
+static DBCN_BUFFER: SpinNoIrq<[u8; DBCN_BUFFER_SIZE]> = SpinNoIrq::new([0; DBCN_BUFFER_SIZE]);
+
+/// Returns whether the firmware has the SBI debug console extension.
+fn has_dbcn() -> bool {
+    // 0: unknown, 1: not supported, 2: supported
+    static DBCN: AtomicU8 = AtomicU8::new(0);
+    match DBCN.load(Ordering::Relaxed) {
+        0 => {
+            let supported = sbi::probe_extension(sbi::EID_DBCN);
+            DBCN.store(if supported { 2 } else { 1 }, Ordering::Relaxed);
+            supported
+        }
+        state => state == 2,
+    }
+}
+
+/// Writes a byte to the SBI console, without the buffer.
+fn sbi_putchar(c: u8) {
+    if has_dbcn() {
+        sbi::console_write_byte(c).ok();
+    } else {
+        #[allow(deprecated)]
+        sbi_rt::legacy::console_putchar(c as usize);
+    }
+}
+
+/// Writes `bytes` to the SBI console.
+fn sbi_write_bytes(bytes: &[u8]) {
+    if !has_dbcn() {
+        #[allow(deprecated)]
+        for &c in bytes {
+            sbi_rt::legacy::console_putchar(c as usize);
+        }
+        return;
+    }
+    // A CPU that panics holding the buffer still gets its output out.
+    let Some(mut buffer) = DBCN_BUFFER.try_lock() else {
+        for &c in bytes {
+            sbi::console_write_byte(c).ok();
+        }
+        return;
+    };
+    let paddr = virt_to_phys(VirtAddr::from(buffer.as_ptr() as usize)).as_usize();
+    for chunk in bytes.chunks(DBCN_BUFFER_SIZE) {
+        buffer[..chunk.len()].copy_from_slice(chunk);
+        let mut written = 0;
+        while written < chunk.len() {
+            match sbi::console_write(paddr + written, chunk.len() - written) {
+                Ok(len) => written += len,
+                Err(_) => {
+                    for &c in &chunk[written..] {
+                        sbi::console_write_byte(c).ok();
+                    }
+                    break;
+                }
+            }
+        }
+    }
+}
+
 /// Writes a byte to the console.
+///
+/// Until the console UART is up, the byte is also captured in the
+/// [early log](crate::early_log).
 pub fn putchar(c: u8) {
-    #[allow(deprecated)]
-    sbi_rt::legacy::console_putchar(c as usize);
+    match console_uart() {
+        Some(uart) => uart.putchar(c),
+        None => {
+            crate::early_log::write_bytes(&[c]);
+            sbi_putchar(c);
+        }
+    }
+}
+
+/// Writes `bytes` to the console, with a single SBI call per
+/// `DBCN_BUFFER_SIZE` bytes on the SBI debug console.
+///
+/// Until the console UART is up, the bytes are also captured in the
+/// [early log](crate::early_log).
+pub fn write_bytes(bytes: &[u8]) {
+    match console_uart() {
+        Some(uart) => bytes.iter().for_each(|&c| uart.putchar(c)),
+        None => {
+            crate::early_log::write_bytes(bytes);
+            sbi_write_bytes(bytes);
+        }
+    }
 }
 
 /// Reads a byte from the console, or returns [`None`] if no input is available.
 pub fn getchar() -> Option<u8> {
+    #[cfg(feature = "irq")]
+    if RX_IRQ_ENABLED.load(Ordering::Acquire) {
+        // Polling the UART would race with the RX interrupt handler.
+        return RX_BUFFER.lock().pop();
+    }
+    if let Some(uart) = console_uart() {
+        return uart.getchar();
+    }
     #[allow(deprecated)]
     match sbi_rt::legacy::console_getchar() as isize {
         -1 => None,
         c => Some(c as u8),
     }
 }
+
+#[cfg(feature = "irq")]
+const RX_BUFFER_SIZE: usize = 256;
+
+/// The bytes received by the RX interrupt handler, not read yet.
+#[cfg(feature = "irq")]
+struct RxBuffer {
+    buf: [u8; RX_BUFFER_SIZE],
+    head: usize,
+    len: usize,
+}
+
+#[cfg(feature = "irq")]
+impl RxBuffer {
+    const fn new() -> Self {
+        Self {
+            buf: [0; RX_BUFFER_SIZE],
+            head: 0,
+            len: 0,
+        }
+    }
+
+    /// Appends `c`, dropping the oldest byte if the buffer is full.
+    fn push(&mut self, c: u8) {
+        self.buf[(self.head + self.len) % RX_BUFFER_SIZE] = c;
+        if self.len == RX_BUFFER_SIZE {
+            self.head = (self.head + 1) % RX_BUFFER_SIZE;
+        } else {
+            self.len += 1;
+        }
+    }
+
+    fn pop(&mut self) -> Option<u8> {
+        if self.len == 0 {
+            return None;
+        }
+        let c = self.buf[self.head];
+        self.head = (self.head + 1) % RX_BUFFER_SIZE;
+        self.len -= 1;
+        Some(c)
+    }
+}
+
+#[cfg(feature = "irq")]
+static RX_BUFFER: SpinNoIrq<RxBuffer> = SpinNoIrq::new(RxBuffer::new());
+
+/// The handler of the bytes received by the RX interrupt, see
+/// [`register_console_input_handler`].
+#[cfg(feature = "irq")]
+static INPUT_HANDLER: SpinNoIrq<Option<fn(u8)>> = SpinNoIrq::new(None);
+
+/// Whether the console input comes from the RX interrupt.
+#[cfg(feature = "irq")]
+static RX_IRQ_ENABLED: AtomicBool = AtomicBool::new(false);
+
+#[cfg(feature = "irq")]
+fn uart_irq_handler() {
+    let Some(uart) = console_uart() else {
+        return;
+    };
+    let handler = *INPUT_HANDLER.lock();
+    match handler {
+        Some(handler) => {
+            while let Some(c) = uart.getchar() {
+                handler(c);
+            }
+        }
+        None => {
+            let mut buffer = RX_BUFFER.lock();
+            while let Some(c) = uart.getchar() {
+                buffer.push(c);
+            }
+        }
+    }
+}
+
+/// Switches the console input to the RX interrupt of the UART, routed
+/// through the interrupt controller, if the UART has a driver and an IRQ.
+///
+/// The received bytes are buffered for [`getchar`], until a handler is
+/// registered with [`register_console_input_handler`].
+#[cfg(feature = "irq")]
+pub(super) fn init_rx_interrupt() {
+    let (Some(uart), Some(irq)) = (console_uart(), crate::platform::uart::console_uart_irq())
+    else {
+        return;
+    };
+    if super::irq::register_handler(irq, uart_irq_handler) {
+        RX_IRQ_ENABLED.store(true, Ordering::Release);
+        uart.set_rx_interrupt(true);
+    }
+}
+
+/// Sets up the console UART again on resume from suspend to RAM.
+pub(super) fn resume() {
+    if let Some(uart) = console_uart() {
+        uart.init();
+        #[cfg(feature = "irq")]
+        if RX_IRQ_ENABLED.load(Ordering::Acquire) {
+            uart.set_rx_interrupt(true);
+        }
+    }
+}
+
+/// Registers the handler of the console input, called with each received
+/// byte in the RX interrupt handler (so it must not block).
+///
+/// The bytes buffered before are passed to it first, and [`getchar`] gets
+/// nothing from now on. Returns `false` if a handler is already registered.
+#[cfg(feature = "irq")]
+pub fn register_console_input_handler(handler: fn(u8)) -> bool {
+    let _guard = kernel_guard_base::IrqSave::new();
+    let mut input_handler = INPUT_HANDLER.lock();
+    if input_handler.is_some() {
+        return false;
+    }
+    *input_handler = Some(handler);
+    drop(input_handler);
+    while let Some(c) = RX_BUFFER.lock().pop() {
+        handler(c);
+    }
+    true
+}
@@ -0,0 +1,193 @@
+//! RISC-V AIA IMSIC (Incoming MSI Controller), the S-level interrupt files.
+//!
+//! Each hart has its own interrupt file, a page of the IMSIC region (per
+//! hart index, i.e. the hart ID on QEMU virt), where devices write the ID of
+//! their MSI. The file of the current hart is accessed through the indirect
+//! CSRs `siselect`/`sireg`, and the pending IDs are claimed from `stopei`.
+//!
+//! All IDs are enabled in every file at boot: masking and routing are done
+//! by the sender (the APLIC, or the MSI capability of a PCIe device). The
+//! IDs that the APLIC sources do not use are allocated to the MSIs.
+
+use lazy_init::LazyInit;
+use memory_addr::PhysAddr;
+use spinbase::SpinNoIrq;
+
+use crate::cpu::cpu_to_hartid;
+
+use super::irq::MAX_IRQ_COUNT;
+
+const IMSIC_PAGE_SIZE: usize = 0x1000;
+
+/// The supervisor external interrupt of the hart local interrupt
+/// controllers, in the `interrupts-extended` of the S-level IMSIC (the
+/// M-level one has 11).
+const IRQ_S_EXT: u32 = 9;
+
+const CSR_SISELECT: usize = 0x150;
+const CSR_SIREG: usize = 0x151;
+const CSR_STOPEI: usize = 0x15c;
+
+const ISELECT_EIDELIVERY: usize = 0x70;
+const ISELECT_EITHRESHOLD: usize = 0x72;
+const ISELECT_EIE0: usize = 0xc0;
+
+/// `stopei` holds the ID of the top interrupt in bits 26:16.
+const TOPEI_ID_SHIFT: usize = 16;
+
+/// The S-level IMSIC of all the harts.
+pub struct Imsic {
+    base: PhysAddr,
+    /// The distance between the interrupt files of two harts.
+    hart_stride: usize,
+    /// The number of interrupt identities, `1..=num_ids` are valid.
+    num_ids: usize,
+}
+
+static IMSIC: LazyInit<Imsic> = LazyInit::new();
+
+/// The IDs in use, as bits: the APLIC sources and the allocated MSIs.
+static USED_IDS: SpinNoIrq<[u64; MAX_IRQ_COUNT / 64]> = SpinNoIrq::new([0; MAX_IRQ_COUNT / 64]);
+
+fn write_ireg(select: usize, value: usize) {
+    unsafe {
+        core::arch::asm!(
+            "csrw {siselect}, {select}",
+            "csrw {sireg}, {value}",
+            siselect = const CSR_SISELECT,
+            sireg = const CSR_SIREG,
+            select = in(reg) select,
+            value = in(reg) value,
+        )
+    }
+}
+
+impl Imsic {
+    /// Returns the number of interrupt identities.
+    pub fn num_ids(&self) -> usize {
+        self.num_ids
+    }
+
+    /// Returns the address of the interrupt file of the CPU `cpu_id`, where
+    /// an MSI to it must be written (the data is the interrupt ID).
+    pub fn msi_address(&self, cpu_id: usize) -> Option<PhysAddr> {
+        cpu_to_hartid(cpu_id).map(|hartid| self.base + hartid * self.hart_stride)
+    }
+
+    /// Reserves the IDs `1..=count`, which the APLIC uses for its sources.
+    pub(super) fn reserve_ids(&self, count: usize) {
+        let mut used = USED_IDS.lock();
+        for id in 1..=count.min(self.num_ids) {
+            used[id / 64] |= 1 << (id % 64);
+        }
+    }
+
+    /// Allocates `count` consecutive free IDs, the first one a multiple of
+    /// `align` (a power of 2), and returns the first one.
+    pub fn alloc_ids(&self, count: usize, align: usize) -> Option<usize> {
+        if count == 0 || !align.is_power_of_two() {
+            return None;
+        }
+        let mut used = USED_IDS.lock();
+        let is_free = |used: &[u64], id: usize| used[id / 64] & (1 << (id % 64)) == 0;
+        // ID 0 is never valid.
+        let first = (align..=self.num_ids)
+            .step_by(align)
+            .take_while(|&first| first + count - 1 <= self.num_ids)
+            .find(|&first| (first..first + count).all(|id| is_free(&*used, id)))?;
+        for id in first..first + count {
+            used[id / 64] |= 1 << (id % 64);
+        }
+        Some(first)
+    }
+
+    /// Frees the `count` IDs from `first`, from [`alloc_ids`](Self::alloc_ids).
+    pub fn free_ids(&self, first: usize, count: usize) {
+        let mut used = USED_IDS.lock();
+        for id in first..(first + count).min(self.num_ids + 1) {
+            used[id / 64] &= !(1 << (id % 64));
+        }
+    }
+
+    /// Enables the interrupt file of the current hart, with all IDs.
+    pub fn init_percpu(&self) {
+        let _guard = kernel_guard_base::IrqSave::new();
+        write_ireg(ISELECT_EIDELIVERY, 0);
+        write_ireg(ISELECT_EITHRESHOLD, 0);
+        // On RV64 only the even `eie` registers exist, with 64 IDs each.
+        for reg in 0..=self.num_ids / 64 {
+            write_ireg(ISELECT_EIE0 + reg * 2, usize::MAX);
+        }
+        write_ireg(ISELECT_EIDELIVERY, 1);
+    }
+
+    /// Claims the top pending ID of the current hart, which also clears it.
+    pub fn claim(&self) -> Option<usize> {
+        let topei: usize;
+        unsafe {
+            core::arch::asm!(
+                "csrrw {topei}, {stopei}, zero",
+                stopei = const CSR_STOPEI,
+                topei = out(reg) topei,
+            )
+        };
+        match topei >> TOPEI_ID_SHIFT {
+            0 => None,
+            id => Some(id),
+        }
+    }
+}
+
+/// Returns the IMSIC, if it has been found in the device tree.
+pub fn imsic() -> Option<&'static Imsic> {
+    IMSIC.is_init().then(|| &*IMSIC)
+}
+
+/// Returns whether the `interrupts-extended` of `node` target the
+/// supervisor external interrupts of the harts.
+fn targets_s_level(node: fdt::node::FdtNode) -> bool {
+    // Pairs of cells: the phandle of a hart controller, and the interrupt.
+    node.property("interrupts-extended").is_some_and(|p| {
+        p.value
+            .chunks_exact(8)
+            .any(|cells| u32::from_be_bytes(cells[4..].try_into().unwrap()) == IRQ_S_EXT)
+    })
+}
+
+/// Finds the S-level IMSIC in the device tree (the enabled one that targets
+/// the supervisor external interrupts), and initializes its driver.
+///
+/// Returns whether it is found.
+pub(super) fn init() -> bool {
+    let Some(node) = crate::platform::dt::fdt().and_then(|fdt| {
+        fdt.all_nodes().find(|&node| {
+            node.compatible()
+                .is_some_and(|c| c.all().any(|c| c == "riscv,imsics" || c == "qemu,imsics"))
+                && crate::platform::dt::node_enabled(node)
+                && targets_s_level(node)
+        })
+    }) else {
+        return false;
+    };
+    let Some(region) = node.reg().and_then(|mut reg| reg.next()) else {
+        warn!("IMSIC without `reg`");
+        return false;
+    };
+    let guest_index_bits = node
+        .property("riscv,guest-index-bits")
+        .and_then(|p| p.as_usize())
+        .unwrap_or(0);
+    let num_ids = node
+        .property("riscv,num-ids")
+        .and_then(|p| p.as_usize())
+        .unwrap_or(63)
+        .min(MAX_IRQ_COUNT - 1);
+    let base = PhysAddr::from(region.starting_address as usize);
+    info!("IMSIC @ {:#x}, {} IDs", base, num_ids);
+    IMSIC.init_by(Imsic {
+        base,
+        hart_stride: IMSIC_PAGE_SIZE << guest_index_bits,
+        num_ids,
+    });
+    true
+}
@@ -1,33 +1,295 @@
-//! TODO: PLIC
+//! Interrupts of the QEMU virt machine: the local ones of the hart (software,
+//! timer and counter-overflow), and the external ones routed by the interrupt
+//! controller, the AIA (APLIC and IMSIC) if the device tree has one, or else
+//! the PLIC.
+//!
+//! The handler of an external IRQ runs with the interrupts disabled, unless
+//! the IRQ is made preemptible with [`set_irq_preemptible`]: its source is
+//! then masked at the controller and the external interrupts in `sie`, and
+//! the interrupts are enabled while it runs, so that the timer and the IPIs
+//! are taken, nested on the same stack. They are not preemptible themselves,
+//! so the nesting is at most one level deeper.
 
-use riscv::register::sie;
+use axerrno::LinuxError;
+use core::sync::atomic::{AtomicUsize, Ordering};
+use memory_addr::PhysAddr;
+use spinbase::SpinNoIrq;
+
+use super::{aplic::aplic, imsic::imsic, plic::plic};
+use crate::arch::{disable_irq_sources, enable_irq_sources, IrqSources};
+use crate::cpu::CpuMask;
+use crate::irq::{IrqController, IrqHandler};
+use crate::trap::TrapStat;
 
 /// `Interrupt` bit in `scause`
 pub(super) const INTC_IRQ_BASE: usize = 1 << (usize::BITS - 1);
 
 /// Supervisor software interrupt in `scause`
-#[allow(unused)]
 pub(super) const S_SOFT: usize = INTC_IRQ_BASE + 1;
 
 /// Supervisor timer interrupt in `scause`
 pub(super) const S_TIMER: usize = INTC_IRQ_BASE + 5;
 
 /// Supervisor external interrupt in `scause`
-#[allow(unused)]
 pub(super) const S_EXT: usize = INTC_IRQ_BASE + 9;
 
-/// The maximum number of IRQs.
-#[allow(dead_code)]
+/// Local counter-overflow interrupt (Sscofpmf) in `scause`
+pub(super) const S_LCOFI: usize = INTC_IRQ_BASE + 13;
+
+/// The maximum number of IRQs (the PLIC interrupt sources).
 pub const MAX_IRQ_COUNT: usize = 1024;
 
+/// The number of local interrupt causes in `scause`.
+const LOCAL_IRQ_COUNT: usize = 16;
+
+/// The IPI number (supervisor software interrupt in `scause`).
+pub const IPI_IRQ_NUM: usize = S_SOFT;
+
 /// The timer IRQ number (supervisor timer interrupt in `scause`).
 pub const TIMER_IRQ_NUM: usize = S_TIMER;
 
+/// The PMU counter-overflow IRQ number (local counter-overflow interrupt in
+/// `scause`).
+pub const PMU_OVERFLOW_IRQ_NUM: usize = S_LCOFI;
+
+/// The handlers of the local interrupts, indexed by the cause in `scause`.
+static LOCAL_HANDLERS: SpinNoIrq<[Option<IrqHandler>; LOCAL_IRQ_COUNT]> =
+    SpinNoIrq::new([None; LOCAL_IRQ_COUNT]);
+
+const PREEMPTIBLE_WORDS: usize = MAX_IRQ_COUNT / usize::BITS as usize;
+
+#[allow(clippy::declare_interior_mutable_const)]
+const PREEMPTIBLE_INIT: AtomicUsize = AtomicUsize::new(0);
+
+/// The external IRQs made preemptible, as bits.
+static PREEMPTIBLE: [AtomicUsize; PREEMPTIBLE_WORDS] = [PREEMPTIBLE_INIT; PREEMPTIBLE_WORDS];
+
+fn is_preemptible(irq: usize) -> bool {
+    let bits = usize::BITS as usize;
+    PREEMPTIBLE
+        .get(irq / bits)
+        .is_some_and(|word| word.load(Ordering::Relaxed) & (1 << (irq % bits)) != 0)
+}
+
+fn local_irq(irq_num: usize) -> Option<usize> {
+    (irq_num & INTC_IRQ_BASE != 0)
+        .then_some(irq_num & !INTC_IRQ_BASE)
+        .filter(|&cause| cause < LOCAL_IRQ_COUNT)
+}
+
+/// Returns the interrupt controller of the external interrupts.
+fn controller() -> Option<&'static dyn IrqController> {
+    match aplic() {
+        Some(aplic) => Some(aplic),
+        None => plic().map(|plic| plic as _),
+    }
+}
+
+/// Returns the address where an MSI to the CPU `cpu_id` must be written, or
+/// [`None`] if there is no IMSIC.
+///
+/// The data of the MSI is its interrupt ID, which is dispatched like an
+/// external IRQ of that number. The IDs above the APLIC sources are free for
+/// the MSI-capable devices, e.g. PCIe (see [`msi`](super::msi)).
+pub fn msi_address(cpu_id: usize) -> Option<PhysAddr> {
+    imsic()?.msi_address(cpu_id)
+}
+
+/// Enables or disables the given IRQ.
+///
+/// Only the external IRQs can be changed, the local ones are enabled on
+/// every CPU by [`init_percpu`].
+pub fn set_enable(irq_num: usize, enabled: bool) {
+    if irq_num & INTC_IRQ_BASE != 0 {
+        return;
+    }
+    if let Some(controller) = controller() {
+        if enabled {
+            controller.enable(irq_num);
+        } else {
+            controller.disable(irq_num);
+        }
+    }
+}
+
+/// Routes the external IRQ `irq_num` to one CPU of `cpu_mask`: the first
+/// online one. The MSIs are routed by rewriting their target, the other IRQs
+/// through the interrupt controller.
+///
+/// Returns [`LinuxError::EINVAL`] if `irq_num` is not an external IRQ, or no
+/// CPU of `cpu_mask` is online.
+pub fn set_irq_affinity(irq_num: usize, cpu_mask: &CpuMask) -> Result<(), LinuxError> {
+    if irq_num & INTC_IRQ_BASE != 0 || irq_num >= MAX_IRQ_COUNT {
+        return Err(LinuxError::EINVAL);
+    }
+    let online = crate::cpu::online_cpus();
+    let cpu_id = cpu_mask
+        .iter()
+        .find(|&cpu_id| online.contains(cpu_id))
+        .ok_or(LinuxError::EINVAL)?;
+    if super::msi::is_msi_irq(irq_num) {
+        return super::msi::set_msi_irq_affinity(irq_num, cpu_id);
+    }
+    controller()
+        .ok_or(LinuxError::EINVAL)?
+        .set_affinity(irq_num, cpu_id)
+}
+
+/// Returns the CPU that the external IRQ `irq_num` is routed to, if it is
+/// known.
+pub fn irq_affinity(irq_num: usize) -> Option<usize> {
+    if irq_num & INTC_IRQ_BASE != 0 {
+        return None;
+    }
+    if super::msi::is_msi_irq(irq_num) {
+        return super::msi::msi_irq_affinity(irq_num);
+    }
+    controller()?.affinity(irq_num)
+}
+
+/// Makes the handler of the external IRQ `irq_num` preemptible by the timer
+/// and the IPIs, or not (the default), e.g. for a long-running device
+/// handler that must not delay them.
+///
+/// Returns [`LinuxError::EINVAL`] if `irq_num` is not an external IRQ.
+pub fn set_irq_preemptible(irq_num: usize, preemptible: bool) -> Result<(), LinuxError> {
+    if irq_num & INTC_IRQ_BASE != 0 || irq_num >= MAX_IRQ_COUNT {
+        return Err(LinuxError::EINVAL);
+    }
+    let bits = usize::BITS as usize;
+    let (word, bit) = (&PREEMPTIBLE[irq_num / bits], 1 << (irq_num % bits));
+    if preemptible {
+        word.fetch_or(bit, Ordering::Relaxed);
+    } else {
+        word.fetch_and(!bit, Ordering::Relaxed);
+    }
+    Ok(())
+}
+
+/// Runs the handler of the claimed `irq` with the local interrupts enabled.
+///
+/// It is completed after the handler returns: until then, the controller
+/// does not signal it again, to this CPU or another. The other external
+/// interrupts are masked in `sie` meanwhile, which keeps them pending (the
+/// MSIs cannot be masked at the controller).
+fn dispatch_preemptible(controller: &dyn IrqController, irq: usize) {
+    let external = disable_irq_sources(IrqSources::EXTERNAL);
+    crate::arch::enable_irqs();
+    crate::irq::dispatch_irq_common(irq);
+    crate::arch::disable_irqs();
+    controller.complete(irq);
+    enable_irq_sources(external);
+}
+
+/// Registers an IRQ handler for the given IRQ.
+///
+/// `irq_num` is either a local interrupt in `scause` (e.g.
+/// [`TIMER_IRQ_NUM`]), or an external one (a PLIC source ID), which is also
+/// enabled. Returns `false` if the registration failed.
+pub fn register_handler(irq_num: usize, handler: IrqHandler) -> bool {
+    if irq_num & INTC_IRQ_BASE == 0 {
+        return crate::irq::register_handler_common(irq_num, handler);
+    }
+    if let Some(cause) = local_irq(irq_num) {
+        let mut handlers = LOCAL_HANDLERS.lock();
+        if handlers[cause].is_none() {
+            handlers[cause] = Some(handler);
+            return true;
+        }
+    }
+    warn!("register handler for IRQ {:#x} failed", irq_num);
+    false
+}
+
+/// Dispatches the IRQ `irq_num` (the `scause` of the trap).
+///
+/// The external interrupts are claimed from the interrupt controller and
+/// dispatched to their handlers until none is pending.
+pub fn dispatch_irq(irq_num: usize) {
+    if irq_num == S_EXT {
+        let Some(controller) = controller() else {
+            warn!("External IRQ without an interrupt controller");
+            return;
+        };
+        while let Some(irq) = controller.claim() {
+            if is_preemptible(irq) {
+                dispatch_preemptible(controller, irq);
+                continue;
+            }
+            crate::irq::dispatch_irq_common(irq);
+            controller.complete(irq);
+        }
+        return;
+    }
+    let Some(cause) = local_irq(irq_num) else {
+        warn!("Unhandled IRQ {:#x}", irq_num);
+        return;
+    };
+    crate::trap::count_trap(TrapStat::LocalIrq(cause));
+    let handler = LOCAL_HANDLERS.lock()[cause];
+    match handler {
+        Some(handler) => handler(),
+        None => warn!("Unhandled IRQ {:#x}", irq_num),
+    }
+}
+
+/// Saves the state of the interrupt controller, for suspend to RAM.
+///
+/// Returns [`LinuxError::ENOTSUP`] with the AIA, whose state is not saved.
+pub(super) fn suspend() -> Result<(), LinuxError> {
+    if aplic().is_some() || imsic().is_some() {
+        warn!("Suspend to RAM is not supported with the AIA");
+        return Err(LinuxError::ENOTSUP);
+    }
+    if let Some(plic) = plic() {
+        plic.save();
+    }
+    Ok(())
+}
+
+/// Restores the state of the interrupt controller, on resume.
+pub(super) fn resume() {
+    if let Some(plic) = plic() {
+        plic.restore();
+    }
+}
+
+/// Routes the external IRQs and the MSIs of the CPU `cpu_id`, going down, to
+/// another online CPU.
+#[cfg(feature = "smp")]
+pub(super) fn migrate_irqs(cpu_id: usize) {
+    let Some(target) = crate::cpu::online_cpus().iter().find(|&cpu| cpu != cpu_id) else {
+        return;
+    };
+    super::msi::migrate_msis(cpu_id, target);
+    let Some(controller) = controller() else {
+        return;
+    };
+    for irq in 1..MAX_IRQ_COUNT {
+        if controller.affinity(irq) == Some(cpu_id) && controller.set_affinity(irq, target).is_ok()
+        {
+            debug!("IRQ {} moved from CPU {} to CPU {}", irq, cpu_id, target);
+        }
+    }
+}
+
+/// Initializes the interrupt controller, on the primary CPU.
+pub(super) fn init_primary() {
+    if !super::aplic::init() {
+        super::plic::init();
+    }
+}
+
 pub(super) fn init_percpu() {
-    // enable soft interrupts, timer interrupts, and external interrupts
-    unsafe {
-        sie::set_ssoft();
-        sie::set_stimer();
-        sie::set_sext();
+    if let Some(imsic) = imsic() {
+        imsic.init_percpu();
     }
+    if let Some(aplic) = aplic() {
+        aplic.init_percpu();
+    }
+    if let Some(plic) = plic() {
+        plic.init_percpu();
+    }
+    // enable soft interrupts, timer interrupts, and external interrupts
+    enable_irq_sources(IrqSources::SOFT | IrqSources::TIMER | IrqSources::EXTERNAL);
 }
@@ -1,6 +1,268 @@
-use crate::mem::MemRegion;
+use crate::mem::{virt_to_phys, MemRegion, MemRegionFlags, PhysAddr};
+use crate::platform::dt::{self, ReservedNode};
+use lazy_init::LazyInit;
+
+/// The maximum number of reserved regions taken from the device tree.
+const MAX_RESERVED_REGIONS: usize = 16;
+
+/// The name of the region reserved for a crash kernel.
+const CRASHKERNEL: &str = "crashkernel";
+
+/// The alignment of the crash kernel region, when its address is not given.
+const CRASHKERNEL_ALIGN: usize = 0x20_0000;
+
+/// Reserved regions from the device tree, sorted by the start address.
+struct ReservedRegions {
+    regions: [Option<ReservedNode>; MAX_RESERVED_REGIONS],
+    len: usize,
+}
+
+impl ReservedRegions {
+    fn iter(&self) -> impl Iterator<Item = &ReservedNode> {
+        self.regions[..self.len].iter().flatten()
+    }
+}
+
+static RESERVED_REGIONS: LazyInit<ReservedRegions> = LazyInit::new();
+
+/// Collects the reserved memory regions (including the firmware) from the
+/// device tree, and reserves the memory of the crash kernel
+/// ([`dt::crashkernel`]).
+///
+/// It must be called after [`dt::init`] and before [`platform_regions`].
+pub(crate) fn init_reserved_regions() {
+    let mut resv = ReservedRegions {
+        regions: [None; MAX_RESERVED_REGIONS],
+        len: 0,
+    };
+    for node in dt::reserved_memory() {
+        if resv.len == MAX_RESERVED_REGIONS {
+            warn!("Too many reserved memory regions, ignore {}", node.name);
+            continue;
+        }
+        info!(
+            "Reserved memory [{:#x}, {:#x}) {}",
+            node.paddr,
+            node.paddr + node.size,
+            node.name
+        );
+        resv.regions[resv.len] = Some(node);
+        resv.len += 1;
+    }
+    if let Some((size, base)) = dt::crashkernel() {
+        match place_crashkernel(&resv, size, base) {
+            Some(paddr) if resv.len < MAX_RESERVED_REGIONS => {
+                info!(
+                    "Reserved memory [{:#x}, {:#x}) {}",
+                    paddr,
+                    paddr + size,
+                    CRASHKERNEL
+                );
+                resv.regions[resv.len] = Some(ReservedNode {
+                    paddr,
+                    size,
+                    name: CRASHKERNEL,
+                    no_map: false,
+                });
+                resv.len += 1;
+            }
+            _ => warn!("Cannot reserve {:#x} bytes for the crash kernel", size),
+        }
+    }
+    resv.regions[..resv.len].sort_unstable_by_key(|node| node.map_or(usize::MAX, |n| n.paddr));
+    RESERVED_REGIONS.init_by(resv);
+}
+
+/// Returns the address of the crash kernel region of `size` bytes: `base` if
+/// it is in the free memory, or else the highest aligned free range, out of
+/// the regions in `resv`.
+fn place_crashkernel(resv: &ReservedRegions, size: usize, base: Option<usize>) -> Option<usize> {
+    let overlap = |paddr: usize| {
+        resv.iter()
+            .find(|node| node.paddr < paddr + size && paddr < node.paddr + node.size)
+    };
+    let mut highest = None;
+    for free in free_regions() {
+        let start = free.paddr.as_usize();
+        let mut end = start + free.size;
+        if let Some(base) = base {
+            if base >= start && base + size <= end && overlap(base).is_none() {
+                return Some(base);
+            }
+            continue;
+        }
+        // Top-down, below the reserved regions in the way.
+        while let Some(paddr) = end
+            .checked_sub(size)
+            .map(|paddr| paddr & !(CRASHKERNEL_ALIGN - 1))
+            .filter(|&paddr| paddr >= start)
+        {
+            match overlap(paddr) {
+                Some(node) => end = node.paddr,
+                None => {
+                    highest = highest.max(Some(paddr));
+                    break;
+                }
+            }
+        }
+    }
+    highest
+}
+
+fn reserved_regions() -> impl Iterator<Item = &'static ReservedNode> {
+    let resv: Option<&'static ReservedRegions> = if RESERVED_REGIONS.is_init() {
+        Some(&RESERVED_REGIONS)
+    } else {
+        None
+    };
+    resv.into_iter().flat_map(|resv| resv.iter())
+}
+
+/// Returns the name of the reserved region (e.g., the firmware) that
+/// contains `paddr`, if any.
+///
+/// It helps to explain an access fault on such an address.
+pub fn reserved_region_name(paddr: PhysAddr) -> Option<&'static str> {
+    let paddr = paddr.as_usize();
+    reserved_regions()
+        .find(|node| paddr >= node.paddr && paddr - node.paddr < node.size)
+        .map(|node| node.name)
+}
+
+/// Returns the physical address and the size of the memory reserved for the
+/// crash kernel at boot, if any.
+///
+/// It is mapped writable, for [`kexec_load_crash`](crate::arch::kexec_load_crash)
+/// to load the crash kernel in it.
+pub fn crash_kernel_region() -> Option<(PhysAddr, usize)> {
+    reserved_regions()
+        .find(|node| node.name == CRASHKERNEL)
+        .map(|node| (PhysAddr::from(node.paddr), node.size))
+}
+
+/// Splits `free` into the parts that do not overlap any reserved region.
+fn exclude_reserved(free: MemRegion) -> impl Iterator<Item = MemRegion> {
+    let end = free.paddr.as_usize() + free.size;
+    let mut start = free.paddr.as_usize();
+    let (flags, name) = (free.flags.bits(), free.name);
+    let mut resv = reserved_regions();
+    core::iter::from_fn(move || {
+        while start < end {
+            let piece_start = start;
+            let piece_end = match resv.next() {
+                Some(node) if node.paddr + node.size <= start => continue,
+                Some(node) if node.paddr < end => {
+                    start = node.paddr + node.size;
+                    node.paddr.max(piece_start)
+                }
+                _ => {
+                    start = end;
+                    end
+                }
+            };
+            let piece_start = PhysAddr::from(piece_start).align_up_4k();
+            let piece_end = PhysAddr::from(piece_end).align_down_4k();
+            if piece_end > piece_start {
+                return Some(MemRegion {
+                    paddr: piece_start,
+                    size: piece_end.as_usize() - piece_start.as_usize(),
+                    flags: MemRegionFlags::from_bits_truncate(flags),
+                    name,
+                });
+            }
+        }
+        None
+    })
+}
+
+extern "C" {
+    fn _stext();
+    fn _ekernel();
+}
+
+/// Returns the free memory: the `/memory` nodes of the device tree, or
+/// [`default_free_regions`](crate::mem::default_free_regions) if there is
+/// none.
+///
+/// As with the default, the memory below the kernel image in its own range
+/// is left out (it is the firmware).
+fn free_regions() -> impl Iterator<Item = MemRegion> {
+    let kernel_start = virt_to_phys((_stext as usize).into()).as_usize();
+    let kernel_end = virt_to_phys((_ekernel as usize).into()).as_usize();
+    let has_dt_memory = dt::memory().next().is_some();
+    let from_dt = dt::memory().filter_map(move |mem| {
+        let end = mem.paddr + mem.size;
+        let start = if (mem.paddr..end).contains(&kernel_start) {
+            kernel_end
+        } else {
+            mem.paddr
+        };
+        let start = PhysAddr::from(start).align_up_4k();
+        let end = PhysAddr::from(end).align_down_4k();
+        (end > start).then(|| MemRegion {
+            paddr: start,
+            size: end.as_usize() - start.as_usize(),
+            flags: MemRegionFlags::FREE | MemRegionFlags::READ | MemRegionFlags::WRITE,
+            name: "free memory",
+        })
+    });
+    let default = crate::mem::default_free_regions().filter(move |_| !has_dt_memory);
+    from_dt.chain(default)
+}
+
+/// Returns the MMIO regions: the devices of the device tree, and the ones of
+/// [`default_mmio_regions`](crate::mem::default_mmio_regions) that none of
+/// them overlaps (e.g. the PCI windows, which are not device `reg`s).
+fn mmio_regions() -> impl Iterator<Item = MemRegion> {
+    let from_dt = || {
+        dt::mmio_devices().map(|dev| {
+            let start = PhysAddr::from(dev.paddr).align_down_4k();
+            let end = PhysAddr::from(dev.paddr + dev.size).align_up_4k();
+            MemRegion {
+                paddr: start,
+                size: end.as_usize() - start.as_usize(),
+                flags: MemRegionFlags::RESERVED
+                    | MemRegionFlags::DEVICE
+                    | MemRegionFlags::READ
+                    | MemRegionFlags::WRITE,
+                name: dev.name,
+            }
+        })
+    };
+    let default = crate::mem::default_mmio_regions().filter(move |region| {
+        !from_dt().any(|dev| {
+            dev.paddr < region.paddr + region.size && region.paddr < dev.paddr + dev.size
+        })
+    });
+    from_dt().chain(default)
+}
 
 /// Returns platform-specific memory regions.
+///
+/// The memory and the MMIO ranges come from the device tree if it has them.
+/// The reserved regions of the device tree are excluded from the free
+/// memory, and are declared read-only (or not declared at all for `no-map`
+/// ones), so that the allocator never hands them out and the kernel direct
+/// map never makes them writable. The crash kernel region is the exception:
+/// it is writable.
 pub(crate) fn platform_regions() -> impl Iterator<Item = MemRegion> {
-    crate::mem::default_free_regions().chain(crate::mem::default_mmio_regions())
+    let reserved = reserved_regions()
+        .filter(|node| !node.no_map)
+        .map(|node| MemRegion {
+            paddr: PhysAddr::from(node.paddr).align_down_4k(),
+            size: PhysAddr::from(node.paddr + node.size)
+                .align_up_4k()
+                .as_usize()
+                - PhysAddr::from(node.paddr).align_down_4k().as_usize(),
+            flags: if node.name == CRASHKERNEL {
+                MemRegionFlags::RESERVED | MemRegionFlags::READ | MemRegionFlags::WRITE
+            } else {
+                MemRegionFlags::RESERVED | MemRegionFlags::READ
+            },
+            name: node.name,
+        });
+    free_regions()
+        .flat_map(exclude_reserved)
+        .chain(reserved)
+        .chain(mmio_regions())
 }
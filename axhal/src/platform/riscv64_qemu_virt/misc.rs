@@ -1,9 +1,80 @@
-/// Shutdown the whole system, including all CPUs.
-pub fn terminate() -> ! {
-    info!("Shutting down...");
-    sbi_rt::system_reset(sbi_rt::Shutdown, sbi_rt::NoReason);
-    warn!("It should shutdown!");
+//! Power off and reboot.
+//!
+//! The SBI System Reset (SRST) extension is used if the firmware has it,
+//! then the `syscon-poweroff` and `syscon-reboot` nodes of the device tree,
+//! then the test finisher of QEMU `virt`. Powering off also tries the legacy
+//! SBI `sbi_shutdown` last.
+
+use crate::arch::sbi::{self, ResetReason, ResetType};
+use crate::mem::{phys_to_virt, PhysAddr};
+use crate::platform::dt::{self, SysconNode};
+
+/// The value written to the test finisher to power off.
+const FINISHER_PASS: u32 = 0x5555;
+/// The value written to the test finisher to reset.
+const FINISHER_RESET: u32 = 0x7777;
+
+/// The extension ID of the legacy `sbi_shutdown`.
+const EID_LEGACY_SHUTDOWN: usize = 0x08;
+
+fn mmio_write(paddr: usize, value: u32, mask: u32) {
+    let reg = phys_to_virt(PhysAddr::from(paddr)).as_mut_ptr() as *mut u32;
+    unsafe {
+        let value = if mask == u32::MAX {
+            value
+        } else {
+            (reg.read_volatile() & !mask) | (value & mask)
+        };
+        reg.write_volatile(value);
+    }
+}
+
+fn syscon_write(node: SysconNode) {
+    mmio_write(node.paddr, node.value, node.mask);
+}
+
+fn legacy_shutdown() {
+    sbi::sbi_call(EID_LEGACY_SHUTDOWN, 0, 0, 0, 0);
+}
+
+fn halt_forever() -> ! {
     loop {
         crate::arch::halt();
     }
 }
+
+/// Powers off the machine.
+pub fn poweroff() -> ! {
+    info!("Shutting down...");
+    sbi::system_reset(ResetType::Shutdown, ResetReason::NoReason);
+    if let Some(node) = dt::syscon("syscon-poweroff") {
+        syscon_write(node);
+    }
+    if let Some(paddr) = dt::test_finisher() {
+        mmio_write(paddr, FINISHER_PASS, u32::MAX);
+    }
+    legacy_shutdown();
+    warn!("It should shutdown!");
+    halt_forever()
+}
+
+/// Resets the machine.
+pub fn reboot() -> ! {
+    info!("Rebooting...");
+    sbi::system_reset(ResetType::ColdReboot, ResetReason::NoReason);
+    if let Some(node) = dt::syscon("syscon-reboot") {
+        syscon_write(node);
+    }
+    if let Some(paddr) = dt::test_finisher() {
+        mmio_write(paddr, FINISHER_RESET, u32::MAX);
+    }
+    warn!("It should reboot!");
+    halt_forever()
+}
+
+/// Shutdown the whole system, including all CPUs.
+///
+/// It is the same as [`poweroff`].
+pub fn terminate() -> ! {
+    poweroff()
+}
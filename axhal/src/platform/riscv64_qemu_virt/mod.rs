@@ -3,8 +3,19 @@ pub mod mem;
 pub mod misc;
 pub mod time;
 
+#[cfg(feature = "irq")]
+mod aplic;
+#[cfg(feature = "irq")]
+mod imsic;
 #[cfg(feature = "irq")]
 pub mod irq;
+#[cfg(feature = "irq")]
+pub mod msi;
+#[cfg(feature = "irq")]
+mod plic;
+
+#[cfg(feature = "smp")]
+pub mod mp;
 
 /// Initializes the platform devices for the primary CPU.
 ///
@@ -12,9 +23,54 @@ pub mod irq;
 pub fn platform_init() {
     axconfig::init_once!();
 
+    crate::platform::uart::init();
+    crate::platform::topology::init();
+    crate::platform::numa::init();
+    crate::arch::init_cache_ops();
+    crate::arch::init_dma();
+    crate::arch::init_aslr();
+    crate::platform::pci::init();
+    #[cfg(feature = "irq")]
+    self::irq::init_primary();
     #[cfg(feature = "irq")]
     self::irq::init_percpu();
+    #[cfg(feature = "irq")]
+    self::console::init_rx_interrupt();
     self::time::init_percpu();
+    crate::platform::rtc::init();
+    crate::arch::init_vdso();
+    crate::platform::cpufreq::init();
+    crate::platform::watchdog::init();
+    register_suspend_ops();
+    crate::arch::init_cpuidle();
+    #[cfg(all(feature = "irq", feature = "smp"))]
+    crate::arch::register_cpu_hotplug(crate::arch::HotplugCallbacks {
+        startup: None,
+        teardown: Some(|cpu_id| {
+            self::irq::migrate_irqs(cpu_id);
+            self::time::stop_percpu();
+        }),
+    });
+}
+
+/// Registers the suspend-to-RAM callbacks of the console, the interrupt
+/// controller and the timer.
+fn register_suspend_ops() {
+    use crate::arch::{register_suspend_ops, SuspendOps};
+    register_suspend_ops(SuspendOps {
+        suspend: || Ok(()),
+        resume: self::console::resume,
+        resume_percpu: None,
+    });
+    #[cfg(feature = "irq")]
+    register_suspend_ops(SuspendOps {
+        suspend: self::irq::suspend,
+        resume: self::irq::resume,
+        resume_percpu: Some(|| {
+            self::irq::init_percpu();
+            self::time::resume_percpu();
+        }),
+    });
 }
 
 /// Initializes the platform devices for secondary CPUs.
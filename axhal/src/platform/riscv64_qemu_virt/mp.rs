@@ -0,0 +1,250 @@
+//! Secondary CPU bring-up through the SBI HSM (hart state management)
+//! extension.
+//!
+//! A secondary hart starts at [`secondary_trampoline`] with paging off. It
+//! switches to the page table of the primary CPU, moves to the high virtual
+//! alias of the kernel, sets up its boot stack and `stvec`, clears `gp` and
+//! `tp` until [`init_secondary`](crate::cpu::init_secondary), and calls
+//! the entry given by the kernel with its logical CPU ID.
+//!
+//! A started CPU can be taken down with [`cpu_down`], and started again on
+//! the same stack and entry with [`cpu_up`].
+
+use axerrno::LinuxError;
+use core::sync::atomic::{AtomicUsize, Ordering};
+use memory_addr::VirtAddr;
+
+use crate::arch::sbi;
+use crate::cpu::{cpu_online, cpu_to_hartid};
+use crate::mem::virt_to_phys;
+use crate::time::{current_time, Duration};
+
+/// The entry of a secondary CPU, called with its logical ID.
+pub type SecondaryEntry = fn(cpu_id: usize) -> !;
+
+/// The size of the boot stack of each secondary CPU.
+pub const SECONDARY_BOOT_STACK_SIZE: usize = 0x10000;
+
+/// How long to wait for a started CPU to come online.
+const START_TIMEOUT: Duration = Duration::from_secs(1);
+
+#[repr(C, align(16))]
+struct BootStack([u8; SECONDARY_BOOT_STACK_SIZE]);
+
+/// The boot stacks of the secondary CPUs, indexed by the logical CPU ID (the
+/// one of the primary CPU is unused).
+static mut SECONDARY_BOOT_STACKS: [BootStack; axconfig::SMP] = [BOOT_STACK_INIT; axconfig::SMP];
+
+const BOOT_STACK_INIT: BootStack = BootStack([0; SECONDARY_BOOT_STACK_SIZE]);
+
+/// What a secondary CPU needs before it can run Rust code, read by
+/// [`secondary_trampoline`] at its physical address.
+#[repr(C)]
+struct SecondaryBootInfo {
+    satp: AtomicUsize,
+    stack_top: AtomicUsize,
+    stvec: AtomicUsize,
+    cpu_id: AtomicUsize,
+    entry: AtomicUsize,
+    /// The virtual address of [`secondary_rust_entry`], jumped to as an
+    /// absolute address, as the PC may still be physical.
+    rust_entry: AtomicUsize,
+}
+
+#[allow(clippy::declare_interior_mutable_const)]
+const BOOT_INFO_INIT: SecondaryBootInfo = SecondaryBootInfo {
+    satp: AtomicUsize::new(0),
+    stack_top: AtomicUsize::new(0),
+    stvec: AtomicUsize::new(0),
+    cpu_id: AtomicUsize::new(0),
+    entry: AtomicUsize::new(0),
+    rust_entry: AtomicUsize::new(0),
+};
+
+/// The boot information of each secondary CPU, indexed by the logical CPU
+/// ID.
+static BOOT_INFO: [SecondaryBootInfo; axconfig::SMP] = [BOOT_INFO_INIT; axconfig::SMP];
+
+/// The entry of a secondary hart, with paging off: `a0` is the hart ID and
+/// `a1` the physical address of its [`SecondaryBootInfo`].
+///
+/// `stvec` is first pointed at the virtual address of the code right after
+/// the `satp` write: the next fetch at the physical PC faults (unless it is
+/// identity mapped), and the trap lands there, in the high alias.
+#[naked]
+unsafe extern "C" fn secondary_trampoline(_hartid: usize, _boot_info: usize) -> ! {
+    core::arch::asm!(
+        "
+        csrw    sie, zero
+        csrci   sstatus, 0x2
+        ld      t0, 0 * 8(a1)       // satp
+        li      t1, {offset}
+        lla     t2, 1f
+        add     t2, t2, t1
+        csrw    stvec, t2
+        add     a1, a1, t1          // the boot info in the linear mapping
+        csrw    satp, t0
+        sfence.vma
+    .align 2
+    1:
+        ld      sp, 1 * 8(a1)
+        ld      t0, 2 * 8(a1)
+        csrw    stvec, t0
+        mv      gp, zero            // no per-CPU area yet
+        mv      tp, zero
+        ld      a0, 3 * 8(a1)
+        ld      t0, 5 * 8(a1)
+        ld      a1, 4 * 8(a1)
+        jr      t0",
+        offset = const axconfig::PHYS_VIRT_OFFSET,
+        options(noreturn),
+    )
+}
+
+extern "C" fn secondary_rust_entry(cpu_id: usize, entry: usize) -> ! {
+    crate::cpu::init_secondary(cpu_id);
+    crate::arch::probe_cpu_features();
+    crate::arch::cpu_starting(cpu_id);
+    let entry: SecondaryEntry = unsafe { core::mem::transmute(entry) };
+    entry(cpu_id)
+}
+
+/// Starts the secondary CPU `cpu_id` on the boot stack whose top is
+/// `stack_top`, and waits until it is online.
+///
+/// The CPU runs with the current page table and trap vector of the caller,
+/// and calls `entry` after [`init_secondary`](crate::cpu::init_secondary)
+/// and the `startup` hotplug callbacks
+/// ([`register_cpu_hotplug`](crate::arch::register_cpu_hotplug)).
+///
+/// Returns [`LinuxError::EINVAL`] if the CPU does not exist,
+/// [`LinuxError::EBUSY`] if it is already started, [`LinuxError::EIO`] if
+/// the SBI call fails, and [`LinuxError::ETIMEDOUT`] if it does not come
+/// online in time.
+pub fn start_secondary_cpu(
+    cpu_id: usize,
+    stack_top: VirtAddr,
+    entry: SecondaryEntry,
+) -> Result<(), LinuxError> {
+    let hartid = cpu_to_hartid(cpu_id).ok_or(LinuxError::EINVAL)?;
+    if cpu_online(cpu_id) {
+        return Err(LinuxError::EBUSY);
+    }
+    let info = &BOOT_INFO[cpu_id];
+    let satp: usize;
+    let stvec: usize;
+    unsafe {
+        core::arch::asm!("csrr {}, satp", out(reg) satp);
+        core::arch::asm!("csrr {}, stvec", out(reg) stvec);
+    }
+    info.satp.store(satp, Ordering::Relaxed);
+    info.stack_top
+        .store(stack_top.as_usize(), Ordering::Relaxed);
+    info.stvec.store(stvec, Ordering::Relaxed);
+    info.cpu_id.store(cpu_id, Ordering::Relaxed);
+    info.entry.store(entry as usize, Ordering::Relaxed);
+    info.rust_entry
+        .store(secondary_rust_entry as usize, Ordering::Relaxed);
+    // The hart reads its boot info with paging off.
+    core::sync::atomic::fence(Ordering::SeqCst);
+
+    let start_paddr = virt_to_phys(VirtAddr::from(secondary_trampoline as usize));
+    let info_paddr = virt_to_phys(VirtAddr::from(info as *const _ as usize));
+    match sbi::hart_start(hartid, start_paddr.as_usize(), info_paddr.as_usize()) {
+        Ok(()) => {}
+        Err(LinuxError::EALREADY) => return Err(LinuxError::EBUSY),
+        Err(err) => {
+            warn!("sbi_hart_start of hart {} failed: {:?}", hartid, err);
+            return Err(LinuxError::EIO);
+        }
+    }
+    let deadline = current_time() + START_TIMEOUT;
+    while !cpu_online(cpu_id) {
+        if current_time() > deadline {
+            warn!("CPU {} (hart {}) did not come online", cpu_id, hartid);
+            return Err(LinuxError::ETIMEDOUT);
+        }
+        core::hint::spin_loop();
+    }
+    Ok(())
+}
+
+/// How long [`cpu_down`] waits for the CPU to stop.
+const STOP_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Takes the online CPU `cpu_id` down: it runs the `teardown` hotplug
+/// callbacks (the IRQs routed to it are moved to another CPU), goes offline
+/// and stops with the SBI HSM extension. The task it was running is lost,
+/// so the scheduler must have moved the tasks away first.
+///
+/// Returns [`LinuxError::EINVAL`] if the CPU does not exist or is the
+/// current one, [`LinuxError::EBUSY`] if it is offline or the last online
+/// CPU, and [`LinuxError::ETIMEDOUT`] if it does not stop in time.
+pub fn cpu_down(cpu_id: usize) -> Result<(), LinuxError> {
+    let hartid = cpu_to_hartid(cpu_id).ok_or(LinuxError::EINVAL)?;
+    if cpu_id == crate::cpu::_this_cpu_id() {
+        return Err(LinuxError::EINVAL);
+    }
+    if !cpu_online(cpu_id) || crate::cpu::num_online_cpus() == 1 {
+        return Err(LinuxError::EBUSY);
+    }
+    crate::arch::send_ipi(cpu_id, crate::arch::IpiKind::CPU_DOWN);
+    let deadline = current_time() + STOP_TIMEOUT;
+    loop {
+        if sbi::hart_status(hartid) == Ok(sbi::HSM_STATUS_STOPPED) {
+            break;
+        }
+        if current_time() > deadline {
+            warn!("CPU {} (hart {}) did not stop", cpu_id, hartid);
+            return Err(LinuxError::ETIMEDOUT);
+        }
+        core::hint::spin_loop();
+    }
+    crate::arch::ipi_cpu_dead(cpu_id);
+    info!("CPU {} is down", cpu_id);
+    Ok(())
+}
+
+/// Starts the CPU `cpu_id` taken down by [`cpu_down`] again, on the stack
+/// and with the entry it was first started with by
+/// [`start_secondary_cpu`], and waits until it is online.
+///
+/// Returns [`LinuxError::EINVAL`] if the CPU was never started, and the
+/// errors of [`start_secondary_cpu`] otherwise.
+pub fn cpu_up(cpu_id: usize) -> Result<(), LinuxError> {
+    let info = BOOT_INFO.get(cpu_id).ok_or(LinuxError::EINVAL)?;
+    let entry = info.entry.load(Ordering::Relaxed);
+    if entry == 0 {
+        return Err(LinuxError::EINVAL);
+    }
+    let stack_top = VirtAddr::from(info.stack_top.load(Ordering::Relaxed));
+    let entry: SecondaryEntry = unsafe { core::mem::transmute(entry) };
+    start_secondary_cpu(cpu_id, stack_top, entry)?;
+    info!("CPU {} is up", cpu_id);
+    Ok(())
+}
+
+/// Starts all the other CPUs of the device tree, on their own boot stacks
+/// of [`SECONDARY_BOOT_STACK_SIZE`] bytes, up to the limit of the `nosmp`
+/// or `maxcpus=` boot parameters.
+///
+/// Returns the number of CPUs started.
+pub fn start_secondary_cpus(entry: SecondaryEntry) -> usize {
+    let this_cpu = crate::cpu::_this_cpu_id();
+    let max_secondary = crate::platform::cmdline::boot_params()
+        .max_cpus()
+        .map_or(usize::MAX, |n| n - 1);
+    let mut started = 0;
+    for cpu_id in (0..crate::cpu::cpu_count())
+        .filter(|&cpu_id| cpu_id != this_cpu)
+        .take(max_secondary)
+    {
+        let stack = unsafe { core::ptr::addr_of!(SECONDARY_BOOT_STACKS[cpu_id]) };
+        let stack_top = VirtAddr::from(stack as usize + SECONDARY_BOOT_STACK_SIZE);
+        match start_secondary_cpu(cpu_id, stack_top, entry) {
+            Ok(()) => started += 1,
+            Err(err) => warn!("Failed to start CPU {}: {:?}", cpu_id, err),
+        }
+    }
+    started
+}
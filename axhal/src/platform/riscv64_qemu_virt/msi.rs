@@ -0,0 +1,494 @@
+//! PCI MSI and MSI-X, delivered to the interrupt files of the
+//! [IMSIC](super::imsic).
+//!
+//! [`alloc_msi_vectors`] takes free interrupt IDs of the IMSIC for a
+//! function, and programs its MSI-X table (or else its MSI capability) with
+//! the address of the interrupt file of the target CPU, and the ID as the
+//! data. The ID of a vector is an external IRQ number like the APLIC
+//! sources: [`register_msi_handler`] registers its handler and unmasks it.
+//! Changing the affinity of a vector rewrites its address.
+//!
+//! The vectors of a multiple MSI are consecutive data values of a single
+//! address, so they all target the same CPU.
+
+use axerrno::LinuxError;
+use spinbase::SpinNoIrq;
+
+use super::imsic::imsic;
+use crate::arch::{ioremap, iounmap, MemAttr, MmioRegion};
+use crate::irq::IrqHandler;
+use crate::platform::pci::{
+    pci_config_read, pci_config_write, pci_find_capability, PciAddress, PciBarKind, PciDevice,
+};
+
+/// The maximum number of vectors of a function.
+pub const MAX_MSI_VECTORS: usize = 32;
+
+/// The maximum number of functions with vectors at the same time.
+const MAX_MSI_FUNCTIONS: usize = 16;
+
+const PCI_COMMAND: usize = 0x04;
+const COMMAND_INTX_DISABLE: u16 = 1 << 10;
+
+const CAP_ID_MSI: u8 = 0x05;
+const CAP_ID_MSIX: u8 = 0x11;
+
+const MSI_FLAGS: usize = 0x02;
+const MSI_ADDRESS_LO: usize = 0x04;
+const MSI_ADDRESS_HI: usize = 0x08;
+const MSI_DATA_32: usize = 0x08;
+const MSI_DATA_64: usize = 0x0c;
+const MSI_MASK_32: usize = 0x0c;
+const MSI_MASK_64: usize = 0x10;
+
+const MSI_FLAGS_ENABLE: u16 = 1 << 0;
+const MSI_FLAGS_QMASK_SHIFT: u16 = 1;
+const MSI_FLAGS_QSIZE_SHIFT: u16 = 4;
+const MSI_FLAGS_64BIT: u16 = 1 << 7;
+const MSI_FLAGS_MASKBIT: u16 = 1 << 8;
+
+const MSIX_FLAGS: usize = 0x02;
+const MSIX_TABLE: usize = 0x04;
+
+const MSIX_FLAGS_QSIZE: u16 = 0x7ff;
+const MSIX_FLAGS_MASKALL: u16 = 1 << 14;
+const MSIX_FLAGS_ENABLE: u16 = 1 << 15;
+const MSIX_TABLE_BIR: u32 = 0x7;
+
+const MSIX_ENTRY_SIZE: usize = 16;
+const MSIX_ENTRY_LOWER_ADDR: usize = 0x0;
+const MSIX_ENTRY_UPPER_ADDR: usize = 0x4;
+const MSIX_ENTRY_DATA: usize = 0x8;
+const MSIX_ENTRY_VECTOR_CTRL: usize = 0xc;
+const MSIX_ENTRY_CTRL_MASKBIT: u32 = 1 << 0;
+
+/// The kind of the vectors of a function.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MsiKind {
+    /// The MSI capability: consecutive data values to a single address.
+    Msi,
+    /// The MSI-X table: an address and a data value per vector.
+    MsiX,
+}
+
+/// The MSI vectors of a function, from [`alloc_msi_vectors`].
+#[derive(Debug, Clone, Copy)]
+pub struct MsiVectors {
+    addr: PciAddress,
+    kind: MsiKind,
+    first_irq: usize,
+    count: usize,
+}
+
+impl MsiVectors {
+    /// Returns the function of the vectors.
+    pub const fn addr(&self) -> PciAddress {
+        self.addr
+    }
+
+    /// Returns whether they are MSI or MSI-X vectors.
+    pub const fn kind(&self) -> MsiKind {
+        self.kind
+    }
+
+    /// Returns the number of vectors.
+    pub const fn count(&self) -> usize {
+        self.count
+    }
+
+    /// Returns the IRQ number of the vector `index`.
+    pub fn irq(&self, index: usize) -> Option<usize> {
+        (index < self.count).then_some(self.first_irq + index)
+    }
+}
+
+struct MsiFunction {
+    vectors: MsiVectors,
+    /// The offset of the capability in the configuration space.
+    cap: usize,
+    /// The MSI-X table.
+    table: Option<MmioRegion>,
+    /// The target CPU of each vector.
+    cpus: [usize; MAX_MSI_VECTORS],
+}
+
+impl MsiFunction {
+    fn msi_flags(&self) -> u16 {
+        pci_config_read(self.vectors.addr, self.cap + MSI_FLAGS).unwrap_or(0)
+    }
+
+    /// Writes the address of the interrupt file of `cpu_id` to the vector
+    /// `index` (all of them for MSI), masked while it changes.
+    fn set_target(&mut self, index: usize, cpu_id: usize) -> Result<(), LinuxError> {
+        let msi_addr = imsic()
+            .and_then(|imsic| imsic.msi_address(cpu_id))
+            .ok_or(LinuxError::EINVAL)?
+            .as_usize() as u64;
+        let addr = self.vectors.addr;
+        match &self.table {
+            Some(table) => {
+                let entry = index * MSIX_ENTRY_SIZE;
+                let ctrl = table.read_u32(entry + MSIX_ENTRY_VECTOR_CTRL);
+                table.write_u32(
+                    entry + MSIX_ENTRY_VECTOR_CTRL,
+                    ctrl | MSIX_ENTRY_CTRL_MASKBIT,
+                );
+                table.write_u32(entry + MSIX_ENTRY_LOWER_ADDR, msi_addr as u32);
+                table.write_u32(entry + MSIX_ENTRY_UPPER_ADDR, (msi_addr >> 32) as u32);
+                table.write_u32(
+                    entry + MSIX_ENTRY_DATA,
+                    (self.vectors.first_irq + index) as u32,
+                );
+                table.write_u32(entry + MSIX_ENTRY_VECTOR_CTRL, ctrl);
+                self.cpus[index] = cpu_id;
+            }
+            None => {
+                pci_config_write(addr, self.cap + MSI_ADDRESS_LO, msi_addr as u32)?;
+                if self.msi_flags() & MSI_FLAGS_64BIT != 0 {
+                    pci_config_write(addr, self.cap + MSI_ADDRESS_HI, (msi_addr >> 32) as u32)?;
+                } else if msi_addr >> 32 != 0 {
+                    return Err(LinuxError::ERANGE);
+                }
+                self.cpus[..self.vectors.count].fill(cpu_id);
+            }
+        }
+        Ok(())
+    }
+
+    /// Targets the boot CPU and masks all the vectors, enables the
+    /// capability, and disables the INTx pin of the function.
+    fn enable(&mut self) -> Result<(), LinuxError> {
+        let (addr, cap) = (self.vectors.addr, self.cap);
+        for index in 0..self.vectors.count {
+            self.set_target(index, 0)?;
+            self.set_masked(index, true);
+        }
+        match self.vectors.kind {
+            MsiKind::MsiX => {
+                let flags: u16 = pci_config_read(addr, cap + MSIX_FLAGS)?;
+                pci_config_write(addr, cap + MSIX_FLAGS, flags & !MSIX_FLAGS_MASKALL)?;
+            }
+            MsiKind::Msi => {
+                let flags = self.msi_flags();
+                pci_config_write(addr, cap + MSI_FLAGS, flags | MSI_FLAGS_ENABLE)?;
+            }
+        }
+        let command: u16 = pci_config_read(addr, PCI_COMMAND)?;
+        pci_config_write(addr, PCI_COMMAND, command | COMMAND_INTX_DISABLE)
+    }
+
+    /// Disables the capability and unmaps the MSI-X table, returns the
+    /// vectors, whose IDs are still allocated.
+    fn disable(self) -> MsiVectors {
+        let (addr, cap) = (self.vectors.addr, self.cap);
+        let flags = match self.vectors.kind {
+            MsiKind::MsiX => (MSIX_FLAGS, MSIX_FLAGS_ENABLE),
+            MsiKind::Msi => (MSI_FLAGS, MSI_FLAGS_ENABLE),
+        };
+        if let Ok(value) = pci_config_read::<u16>(addr, cap + flags.0) {
+            pci_config_write(addr, cap + flags.0, value & !flags.1).ok();
+        }
+        if let Some(table) = self.table {
+            iounmap(table).ok();
+        }
+        self.vectors
+    }
+
+    /// Masks or unmasks the vector `index`, if the function can mask it.
+    fn set_masked(&self, index: usize, masked: bool) {
+        let addr = self.vectors.addr;
+        if let Some(table) = &self.table {
+            let offset = index * MSIX_ENTRY_SIZE + MSIX_ENTRY_VECTOR_CTRL;
+            let ctrl = table.read_u32(offset) & !MSIX_ENTRY_CTRL_MASKBIT;
+            table.write_u32(
+                offset,
+                ctrl | if masked { MSIX_ENTRY_CTRL_MASKBIT } else { 0 },
+            );
+            return;
+        }
+        let flags = self.msi_flags();
+        if flags & MSI_FLAGS_MASKBIT == 0 {
+            return;
+        }
+        let offset = self.cap
+            + if flags & MSI_FLAGS_64BIT != 0 {
+                MSI_MASK_64
+            } else {
+                MSI_MASK_32
+            };
+        if let Ok(bits) = pci_config_read::<u32>(addr, offset) {
+            let bits = (bits & !(1 << index)) | ((masked as u32) << index);
+            pci_config_write(addr, offset, bits).ok();
+        }
+    }
+}
+
+const NO_FUNCTION: Option<MsiFunction> = None;
+
+static MSI_FUNCTIONS: SpinNoIrq<[Option<MsiFunction>; MAX_MSI_FUNCTIONS]> =
+    SpinNoIrq::new([NO_FUNCTION; MAX_MSI_FUNCTIONS]);
+
+/// Sets up the MSI-X table of `dev` with up to `count` IDs, all masked, and
+/// returns it with the first ID and the number of IDs.
+fn setup_msix(
+    dev: &PciDevice,
+    cap: usize,
+    count: usize,
+) -> Result<(MmioRegion, usize, usize), LinuxError> {
+    let addr = dev.addr;
+    let flags: u16 = pci_config_read(addr, cap + MSIX_FLAGS)?;
+    let table_size = (flags & MSIX_FLAGS_QSIZE) as usize + 1;
+    let table_reg: u32 = pci_config_read(addr, cap + MSIX_TABLE)?;
+    let bar = dev
+        .bars
+        .get((table_reg & MSIX_TABLE_BIR) as usize)
+        .copied()
+        .flatten()
+        .filter(|bar| bar.kind != PciBarKind::Io)
+        .ok_or(LinuxError::ENODEV)?;
+    let count = count.min(table_size);
+    let imsic = imsic().ok_or(LinuxError::ENODEV)?;
+    let first_irq = imsic.alloc_ids(count, 1).ok_or(LinuxError::ENOSPC)?;
+    let paddr = bar.paddr + (table_reg & !MSIX_TABLE_BIR) as usize;
+    let table = match ioremap(paddr, table_size * MSIX_ENTRY_SIZE, MemAttr::Io) {
+        Ok(table) => table,
+        Err(err) => {
+            imsic.free_ids(first_irq, count);
+            return Err(err);
+        }
+    };
+    // The table is written with the function masked.
+    let enable = flags | MSIX_FLAGS_MASKALL | MSIX_FLAGS_ENABLE;
+    if let Err(err) = pci_config_write(addr, cap + MSIX_FLAGS, enable) {
+        iounmap(table).ok();
+        imsic.free_ids(first_irq, count);
+        return Err(err);
+    }
+    for entry in 0..table_size {
+        table.write_u32(
+            entry * MSIX_ENTRY_SIZE + MSIX_ENTRY_VECTOR_CTRL,
+            MSIX_ENTRY_CTRL_MASKBIT,
+        );
+    }
+    Ok((table, first_irq, count))
+}
+
+/// Sets up the MSI capability of `dev` with up to `count` IDs (a power of 2,
+/// aligned), and returns the first ID and the number of IDs.
+fn setup_msi(dev: &PciDevice, cap: usize, count: usize) -> Result<(usize, usize), LinuxError> {
+    let addr = dev.addr;
+    let flags: u16 = pci_config_read(addr, cap + MSI_FLAGS)?;
+    let capable = 1 << ((flags >> MSI_FLAGS_QMASK_SHIFT) & 0x7).min(5);
+    let count = 1 << count.min(capable).ilog2();
+    let imsic = imsic().ok_or(LinuxError::ENODEV)?;
+    let first_irq = imsic.alloc_ids(count, count).ok_or(LinuxError::ENOSPC)?;
+    let data = if flags & MSI_FLAGS_64BIT != 0 {
+        MSI_DATA_64
+    } else {
+        MSI_DATA_32
+    };
+    let flags = (flags & !(0x7 << MSI_FLAGS_QSIZE_SHIFT))
+        | ((count.ilog2() as u16) << MSI_FLAGS_QSIZE_SHIFT);
+    let result = pci_config_write(addr, cap + data, first_irq as u16)
+        .and_then(|()| pci_config_write(addr, cap + MSI_FLAGS, flags));
+    if let Err(err) = result {
+        imsic.free_ids(first_irq, count);
+        return Err(err);
+    }
+    Ok((first_irq, count))
+}
+
+/// Allocates up to `count` MSI vectors for `dev`: as many as it has in its
+/// MSI-X table, or else the largest power of 2 of its MSI capability. They
+/// target the boot CPU, and are masked until their handler is registered
+/// with [`register_msi_handler`]. The INTx pin of `dev` is disabled.
+///
+/// Returns [`LinuxError::EINVAL`] if `count` is 0 or above
+/// [`MAX_MSI_VECTORS`], [`LinuxError::EBUSY`] if `dev` already has vectors,
+/// [`LinuxError::ENODEV`] if there is no IMSIC or `dev` has no capability,
+/// and [`LinuxError::ENOSPC`] if the IDs are exhausted, or the error of a
+/// configuration access. On an error, the capability is left disabled and
+/// nothing stays allocated.
+pub fn alloc_msi_vectors(dev: &PciDevice, count: usize) -> Result<MsiVectors, LinuxError> {
+    if count == 0 || count > MAX_MSI_VECTORS {
+        return Err(LinuxError::EINVAL);
+    }
+    let mut functions = MSI_FUNCTIONS.lock();
+    if functions
+        .iter()
+        .flatten()
+        .any(|function| function.vectors.addr == dev.addr)
+    {
+        return Err(LinuxError::EBUSY);
+    }
+    let slot = functions
+        .iter()
+        .position(Option::is_none)
+        .ok_or(LinuxError::ENOSPC)?;
+
+    let (kind, cap, table, first_irq, count) =
+        if let Some(cap) = pci_find_capability(dev.addr, CAP_ID_MSIX) {
+            let (table, first_irq, count) = setup_msix(dev, cap, count)?;
+            (MsiKind::MsiX, cap, Some(table), first_irq, count)
+        } else if let Some(cap) = pci_find_capability(dev.addr, CAP_ID_MSI) {
+            let (first_irq, count) = setup_msi(dev, cap, count)?;
+            (MsiKind::Msi, cap, None, first_irq, count)
+        } else {
+            return Err(LinuxError::ENODEV);
+        };
+    let mut function = MsiFunction {
+        vectors: MsiVectors {
+            addr: dev.addr,
+            kind,
+            first_irq,
+            count,
+        },
+        cap,
+        table,
+        cpus: [0; MAX_MSI_VECTORS],
+    };
+    if let Err(err) = function.enable() {
+        free_ids(&function.disable());
+        return Err(err);
+    }
+    debug!(
+        "PCI {}: {} {:?} vectors, IRQs {}..{}",
+        dev.addr,
+        count,
+        kind,
+        first_irq,
+        first_irq + count
+    );
+    let vectors = function.vectors;
+    functions[slot] = Some(function);
+    Ok(vectors)
+}
+
+/// Disables the vectors of a function and frees them, with their handlers.
+pub fn free_msi_vectors(vectors: MsiVectors) {
+    let mut functions = MSI_FUNCTIONS.lock();
+    let Some(slot) = functions.iter_mut().find(|function| {
+        function
+            .as_ref()
+            .is_some_and(|function| function.vectors.addr == vectors.addr)
+    }) else {
+        return;
+    };
+    let Some(function) = slot.take() else {
+        return;
+    };
+    drop(functions);
+
+    let vectors = function.disable();
+    for irq in vectors.first_irq..vectors.first_irq + vectors.count {
+        crate::irq::unregister_handler_common(irq);
+    }
+    free_ids(&vectors);
+}
+
+/// Frees the IDs of the IMSIC of `vectors`.
+fn free_ids(vectors: &MsiVectors) {
+    if let Some(imsic) = imsic() {
+        imsic.free_ids(vectors.first_irq, vectors.count);
+    }
+}
+
+fn with_function<T>(
+    vectors: &MsiVectors,
+    index: usize,
+    f: impl FnOnce(&mut MsiFunction) -> Result<T, LinuxError>,
+) -> Result<T, LinuxError> {
+    if index >= vectors.count {
+        return Err(LinuxError::EINVAL);
+    }
+    let mut functions = MSI_FUNCTIONS.lock();
+    let function = functions
+        .iter_mut()
+        .flatten()
+        .find(|function| function.vectors.addr == vectors.addr)
+        .ok_or(LinuxError::EINVAL)?;
+    f(function)
+}
+
+/// Registers `handler` for the vector `index`, and unmasks it.
+///
+/// Returns `false` if the registration failed.
+pub fn register_msi_handler(vectors: &MsiVectors, index: usize, handler: IrqHandler) -> bool {
+    let Some(irq) = vectors.irq(index) else {
+        return false;
+    };
+    if !super::irq::register_handler(irq, handler) {
+        return false;
+    }
+    with_function(vectors, index, |function| {
+        function.set_masked(index, false);
+        Ok(())
+    })
+    .is_ok()
+}
+
+/// Routes the vector `index` to the CPU `cpu_id`. For MSI, all the vectors
+/// of the function follow.
+///
+/// Returns [`LinuxError::EINVAL`] if `index` or `cpu_id` is out of range.
+pub fn set_msi_affinity(
+    vectors: &MsiVectors,
+    index: usize,
+    cpu_id: usize,
+) -> Result<(), LinuxError> {
+    with_function(vectors, index, |function| {
+        function.set_target(index, cpu_id)
+    })
+}
+
+/// Returns the CPU that the vector `index` is routed to.
+pub fn msi_affinity(vectors: &MsiVectors, index: usize) -> Option<usize> {
+    with_function(vectors, index, |function| Ok(function.cpus[index])).ok()
+}
+
+/// Returns the function and the index of the vector of `irq`.
+fn find_irq(
+    functions: &mut [Option<MsiFunction>],
+    irq: usize,
+) -> Option<(&mut MsiFunction, usize)> {
+    functions.iter_mut().flatten().find_map(|function| {
+        let index = irq.checked_sub(function.vectors.first_irq)?;
+        (index < function.vectors.count).then_some((function, index))
+    })
+}
+
+/// Returns whether `irq` is an allocated MSI vector.
+pub(super) fn is_msi_irq(irq: usize) -> bool {
+    find_irq(&mut *MSI_FUNCTIONS.lock(), irq).is_some()
+}
+
+/// Routes the MSI vector `irq` to the CPU `cpu_id`, see
+/// [`set_msi_affinity`].
+pub(super) fn set_msi_irq_affinity(irq: usize, cpu_id: usize) -> Result<(), LinuxError> {
+    let mut functions = MSI_FUNCTIONS.lock();
+    let (function, index) = find_irq(&mut *functions, irq).ok_or(LinuxError::EINVAL)?;
+    function.set_target(index, cpu_id)
+}
+
+/// Returns the CPU that the MSI vector `irq` is routed to.
+pub(super) fn msi_irq_affinity(irq: usize) -> Option<usize> {
+    let mut functions = MSI_FUNCTIONS.lock();
+    find_irq(&mut *functions, irq).map(|(function, index)| function.cpus[index])
+}
+
+/// Routes the vectors of the CPU `cpu_id`, going down, to `target`.
+#[cfg(feature = "smp")]
+pub(super) fn migrate_msis(cpu_id: usize, target: usize) {
+    for function in MSI_FUNCTIONS.lock().iter_mut().flatten() {
+        for index in 0..function.vectors.count {
+            if function.cpus[index] == cpu_id && function.set_target(index, target).is_ok() {
+                debug!(
+                    "MSI {} moved from CPU {} to CPU {}",
+                    function.vectors.first_irq + index,
+                    cpu_id,
+                    target
+                );
+            }
+        }
+    }
+}
@@ -0,0 +1,259 @@
+//! SiFive/QEMU-virt PLIC (Platform-Level Interrupt Controller).
+//!
+//! The PLIC is found in the device tree. Its contexts follow the QEMU virt
+//! layout: context `2 * hartid` is the M-mode one of the hart, and
+//! `2 * hartid + 1` the S-mode one, which is used here.
+
+use axerrno::LinuxError;
+use core::sync::atomic::{AtomicUsize, Ordering};
+use lazy_init::LazyInit;
+use memory_addr::{PhysAddr, VirtAddr};
+use spinbase::SpinNoIrq;
+
+use crate::cpu::{_this_cpu_id, cpu_to_hartid};
+use crate::irq::IrqController;
+use crate::mem::phys_to_virt;
+
+use super::irq::MAX_IRQ_COUNT;
+
+const PRIORITY_BASE: usize = 0;
+const ENABLE_BASE: usize = 0x2000;
+const ENABLE_STRIDE: usize = 0x80;
+const CONTEXT_BASE: usize = 0x20_0000;
+const CONTEXT_STRIDE: usize = 0x1000;
+const CONTEXT_THRESHOLD: usize = 0;
+const CONTEXT_CLAIM: usize = 4;
+
+/// A PLIC.
+pub struct Plic {
+    base: VirtAddr,
+    /// The number of interrupt sources, `1..=num_sources` are valid.
+    num_sources: usize,
+    /// The target CPU of each source.
+    affinity: [AtomicUsize; MAX_IRQ_COUNT],
+    /// Serializes the read-modify-write of the enable bits.
+    enable_lock: SpinNoIrq<()>,
+}
+
+/// The state of the PLIC saved for suspend to RAM: the priorities of the
+/// sources and the enable bits of the S-mode contexts.
+struct PlicState {
+    priorities: [u32; MAX_IRQ_COUNT],
+    enables: [[u32; MAX_IRQ_COUNT / 32]; axconfig::SMP],
+}
+
+static SAVED_STATE: SpinNoIrq<PlicState> = SpinNoIrq::new(PlicState {
+    priorities: [0; MAX_IRQ_COUNT],
+    enables: [[0; MAX_IRQ_COUNT / 32]; axconfig::SMP],
+});
+
+#[allow(clippy::declare_interior_mutable_const)]
+const AFFINITY_INIT: AtomicUsize = AtomicUsize::new(0);
+
+static PLIC: LazyInit<Plic> = LazyInit::new();
+
+impl Plic {
+    /// Creates the driver of the PLIC mapped at `base`.
+    pub const fn new(base: VirtAddr, num_sources: usize) -> Self {
+        Self {
+            base,
+            num_sources,
+            affinity: [AFFINITY_INIT; MAX_IRQ_COUNT],
+            enable_lock: SpinNoIrq::new(()),
+        }
+    }
+
+    fn reg(&self, offset: usize) -> *mut u32 {
+        (self.base.as_usize() + offset) as *mut u32
+    }
+
+    fn valid(&self, irq: usize) -> bool {
+        irq != 0 && irq <= self.num_sources
+    }
+
+    /// Returns the S-mode context of the CPU `cpu_id`.
+    fn context(cpu_id: usize) -> Option<usize> {
+        cpu_to_hartid(cpu_id).map(|hartid| 2 * hartid + 1)
+    }
+
+    fn set_enable_bit(&self, context: usize, irq: usize, enabled: bool) {
+        let reg = self.reg(ENABLE_BASE + context * ENABLE_STRIDE + (irq / 32) * 4);
+        let bit = 1 << (irq % 32);
+        let _guard = self.enable_lock.lock();
+        unsafe {
+            let value = reg.read_volatile();
+            reg.write_volatile(if enabled { value | bit } else { value & !bit });
+        }
+    }
+
+    fn enable_bit(&self, context: usize, irq: usize) -> bool {
+        let reg = self.reg(ENABLE_BASE + context * ENABLE_STRIDE + (irq / 32) * 4);
+        unsafe { reg.read_volatile() & (1 << (irq % 32)) != 0 }
+    }
+
+    fn this_context() -> usize {
+        Self::context(_this_cpu_id()).expect("current CPU has no hart ID")
+    }
+
+    fn enable_reg(context: usize, word: usize) -> usize {
+        ENABLE_BASE + context * ENABLE_STRIDE + word * 4
+    }
+
+    /// Saves the priorities and the enable bits, which are lost in suspend
+    /// to RAM.
+    pub(super) fn save(&self) {
+        let mut state = SAVED_STATE.lock();
+        for irq in 1..=self.num_sources {
+            state.priorities[irq] = unsafe { self.reg(PRIORITY_BASE + irq * 4).read_volatile() };
+        }
+        for (cpu_id, enables) in state.enables.iter_mut().enumerate() {
+            let Some(context) = Self::context(cpu_id) else {
+                continue;
+            };
+            for (word, enable) in enables
+                .iter_mut()
+                .enumerate()
+                .take(self.num_sources / 32 + 1)
+            {
+                *enable = unsafe { self.reg(Self::enable_reg(context, word)).read_volatile() };
+            }
+        }
+    }
+
+    /// Restores the state saved by [`save`](Self::save), on resume.
+    pub(super) fn restore(&self) {
+        let state = SAVED_STATE.lock();
+        for irq in 1..=self.num_sources {
+            unsafe {
+                self.reg(PRIORITY_BASE + irq * 4)
+                    .write_volatile(state.priorities[irq])
+            };
+        }
+        for (cpu_id, enables) in state.enables.iter().enumerate() {
+            let Some(context) = Self::context(cpu_id) else {
+                continue;
+            };
+            for (word, &enable) in enables.iter().enumerate().take(self.num_sources / 32 + 1) {
+                unsafe {
+                    self.reg(Self::enable_reg(context, word))
+                        .write_volatile(enable)
+                };
+            }
+        }
+    }
+
+    /// Accepts all priorities on the current CPU (threshold 0).
+    pub fn init_percpu(&self) {
+        let context = Self::this_context();
+        unsafe {
+            self.reg(CONTEXT_BASE + context * CONTEXT_STRIDE + CONTEXT_THRESHOLD)
+                .write_volatile(0)
+        };
+    }
+}
+
+impl IrqController for Plic {
+    fn enable(&self, irq: usize) {
+        if !self.valid(irq) {
+            return;
+        }
+        // Priority 0 never interrupts.
+        if unsafe { self.reg(PRIORITY_BASE + irq * 4).read_volatile() } == 0 {
+            self.set_priority(irq, 1);
+        }
+        let cpu_id = self.affinity[irq].load(Ordering::Relaxed);
+        if let Some(context) = Self::context(cpu_id) {
+            self.set_enable_bit(context, irq, true);
+        }
+    }
+
+    fn disable(&self, irq: usize) {
+        if !self.valid(irq) {
+            return;
+        }
+        let cpu_id = self.affinity[irq].load(Ordering::Relaxed);
+        if let Some(context) = Self::context(cpu_id) {
+            self.set_enable_bit(context, irq, false);
+        }
+    }
+
+    fn claim(&self) -> Option<usize> {
+        let context = Self::this_context();
+        let irq = unsafe {
+            self.reg(CONTEXT_BASE + context * CONTEXT_STRIDE + CONTEXT_CLAIM)
+                .read_volatile()
+        };
+        (irq != 0).then_some(irq as usize)
+    }
+
+    fn complete(&self, irq: usize) {
+        let context = Self::this_context();
+        unsafe {
+            self.reg(CONTEXT_BASE + context * CONTEXT_STRIDE + CONTEXT_CLAIM)
+                .write_volatile(irq as u32)
+        };
+    }
+
+    fn set_priority(&self, irq: usize, priority: u32) {
+        if self.valid(irq) {
+            unsafe { self.reg(PRIORITY_BASE + irq * 4).write_volatile(priority) };
+        }
+    }
+
+    fn set_affinity(&self, irq: usize, cpu_id: usize) -> Result<(), LinuxError> {
+        if !self.valid(irq) {
+            return Err(LinuxError::EINVAL);
+        }
+        let new_context = Self::context(cpu_id).ok_or(LinuxError::EINVAL)?;
+        let old_cpu = self.affinity[irq].swap(cpu_id, Ordering::Relaxed);
+        if let Some(old_context) = Self::context(old_cpu) {
+            if old_context != new_context && self.enable_bit(old_context, irq) {
+                self.set_enable_bit(old_context, irq, false);
+                self.set_enable_bit(new_context, irq, true);
+            }
+        }
+        Ok(())
+    }
+
+    fn affinity(&self, irq: usize) -> Option<usize> {
+        self.valid(irq)
+            .then(|| self.affinity[irq].load(Ordering::Relaxed))
+    }
+}
+
+/// Returns the PLIC, if it has been found in the device tree.
+pub fn plic() -> Option<&'static Plic> {
+    PLIC.is_init().then(|| &*PLIC)
+}
+
+/// Finds the PLIC in the device tree, or else in the ACPI MADT, and
+/// initializes its driver.
+pub(super) fn init() {
+    let Some(node) = crate::platform::dt::fdt()
+        .and_then(|fdt| fdt.find_compatible(&["sifive,plic-1.0.0", "riscv,plic0"]))
+    else {
+        match crate::platform::acpi::plic() {
+            Some((paddr, num_sources)) => init_plic(paddr.into(), num_sources),
+            None => warn!("No PLIC in the device tree"),
+        }
+        return;
+    };
+    let Some(region) = node.reg().and_then(|mut reg| reg.next()) else {
+        warn!("PLIC without `reg`");
+        return;
+    };
+    let num_sources = node
+        .property("riscv,ndev")
+        .and_then(|p| p.as_usize())
+        .unwrap_or(MAX_IRQ_COUNT - 1);
+    init_plic(
+        PhysAddr::from(region.starting_address as usize),
+        num_sources,
+    );
+}
+
+fn init_plic(paddr: PhysAddr, num_sources: usize) {
+    let num_sources = num_sources.min(MAX_IRQ_COUNT - 1);
+    info!("PLIC @ {:#x}, {} sources", paddr, num_sources);
+    PLIC.init_by(Plic::new(phys_to_virt(paddr), num_sources));
+}
@@ -1,6 +1,27 @@
+use core::sync::atomic::AtomicU64;
+use ratio::Ratio;
 use riscv::register::time;
 
-const NANOS_PER_TICK: u64 = crate::time::NANOS_PER_SEC / axconfig::TIMER_FREQUENCY as u64;
+use core::sync::atomic::Ordering;
+#[cfg(feature = "irq")]
+use spinbase::SpinNoIrq;
+
+/// The frequency of the `time` CSR, from the device tree.
+static TIMER_FREQUENCY: AtomicU64 = AtomicU64::new(axconfig::TIMER_FREQUENCY as u64);
+
+static mut TICKS_TO_NANOS_RATIO: Ratio = Ratio::new(
+    crate::time::NANOS_PER_SEC as u32,
+    axconfig::TIMER_FREQUENCY as u32,
+);
+static mut NANOS_TO_TICKS_RATIO: Ratio = Ratio::new(
+    axconfig::TIMER_FREQUENCY as u32,
+    crate::time::NANOS_PER_SEC as u32,
+);
+
+/// The last deadline set by [`set_oneshot_timer`] (in nanoseconds).
+#[cfg(feature = "irq")]
+#[percpu2::def_percpu]
+static TIMER_DEADLINE: u64 = u64::MAX;
 
 /// Returns the current clock time in hardware ticks.
 #[inline]
@@ -8,16 +29,69 @@ pub fn current_ticks() -> u64 {
     time::read() as u64
 }
 
+/// Returns the frequency of the hardware ticks, in Hz.
+#[inline]
+pub fn timer_frequency() -> u64 {
+    TIMER_FREQUENCY.load(Ordering::Relaxed)
+}
+
 /// Converts hardware ticks to nanoseconds.
 #[inline]
-pub const fn ticks_to_nanos(ticks: u64) -> u64 {
-    ticks * NANOS_PER_TICK
+pub fn ticks_to_nanos(ticks: u64) -> u64 {
+    unsafe { TICKS_TO_NANOS_RATIO.mul_trunc(ticks) }
 }
 
 /// Converts nanoseconds to hardware ticks.
 #[inline]
-pub const fn nanos_to_ticks(nanos: u64) -> u64 {
-    nanos / NANOS_PER_TICK
+pub fn nanos_to_ticks(nanos: u64) -> u64 {
+    unsafe { NANOS_TO_TICKS_RATIO.mul_trunc(nanos) }
+}
+
+/// Early stage initialization: takes the timer frequency from the
+/// `timebase-frequency` of the device tree, or keeps the configured one
+/// (`axconfig::TIMER_FREQUENCY`).
+pub(crate) fn init_early() {
+    let Some(freq) = crate::platform::dt::timebase_frequency() else {
+        warn!(
+            "No timebase-frequency in the device tree, use {} Hz",
+            axconfig::TIMER_FREQUENCY
+        );
+        return;
+    };
+    if freq > u32::MAX as usize {
+        warn!("Timebase frequency {} Hz is out of range, ignored", freq);
+        return;
+    }
+    info!("Timebase frequency: {} Hz", freq);
+    unsafe {
+        TICKS_TO_NANOS_RATIO = Ratio::new(crate::time::NANOS_PER_SEC as u32, freq as u32);
+        NANOS_TO_TICKS_RATIO = TICKS_TO_NANOS_RATIO.inverse();
+    }
+    TIMER_FREQUENCY.store(freq as u64, Ordering::Relaxed);
+}
+
+/// The handler of the timer interrupts, see [`register_tick_handler`].
+#[cfg(feature = "irq")]
+static TICK_HANDLER: SpinNoIrq<Option<fn()>> = SpinNoIrq::new(None);
+
+/// Returns whether all CPUs support the Sstc extension, i.e. S-mode can
+/// program its timer in `stimecmp` without calling the SBI.
+#[cfg(feature = "irq")]
+pub fn has_sstc() -> bool {
+    crate::arch::cpu_has(crate::arch::Feature::Sstc)
+}
+
+/// Programs the timer of the current CPU to fire at `ticks`, with
+/// `stimecmp` if there is Sstc, or `sbi_set_timer` otherwise.
+///
+/// Either way, a new value also clears the pending timer interrupt.
+#[cfg(feature = "irq")]
+fn set_timer_ticks(ticks: u64) {
+    if has_sstc() {
+        unsafe { core::arch::asm!("csrw stimecmp, {}", in(reg) ticks) };
+    } else {
+        crate::arch::sbi::set_timer(ticks).ok();
+    }
 }
 
 /// Set a one-shot timer.
@@ -25,10 +99,85 @@ pub const fn nanos_to_ticks(nanos: u64) -> u64 {
 /// A timer interrupt will be triggered at the given deadline (in nanoseconds).
 #[cfg(feature = "irq")]
 pub fn set_oneshot_timer(deadline_ns: u64) {
-    sbi_rt::set_timer(nanos_to_ticks(deadline_ns));
+    unsafe { TIMER_DEADLINE.write_current_raw(deadline_ns) };
+    set_timer_ticks(nanos_to_ticks(deadline_ns));
+}
+
+/// Arms the timer of the current CPU again with the last deadline, on
+/// resume from suspend to RAM.
+#[cfg(feature = "irq")]
+pub(super) fn resume_percpu() {
+    enable_user_time();
+    let deadline = unsafe { TIMER_DEADLINE.read_current_raw() };
+    if deadline != u64::MAX {
+        set_timer_ticks(nanos_to_ticks(deadline));
+    }
+}
+
+/// Disarms the timer of the current CPU, going down.
+#[cfg(all(feature = "irq", feature = "smp"))]
+pub(super) fn stop_percpu() {
+    unsafe { TIMER_DEADLINE.write_current_raw(u64::MAX) };
+    set_timer_ticks(u64::MAX);
+}
+
+/// Handles the timer interrupt: disarms the timer, and calls the tick
+/// handler, which may arm it again with [`set_oneshot_timer`].
+#[cfg(feature = "irq")]
+fn timer_irq_handler() {
+    unsafe { TIMER_DEADLINE.write_current_raw(u64::MAX) };
+    set_timer_ticks(u64::MAX);
+    crate::arch::lockup_timer_tick();
+    crate::arch::vdso_timer_tick();
+    let handler = *TICK_HANDLER.lock();
+    if let Some(handler) = handler {
+        handler();
+    }
+}
+
+/// Registers the handler of the timer interrupts (the tick handler of the
+/// scheduler, or the expiry of the hrtimers).
+///
+/// The timer is disarmed before `handler` is called, so a periodic tick
+/// must re-arm it each time. Returns `false` if a handler is already
+/// registered.
+#[cfg(feature = "irq")]
+pub fn register_tick_handler(handler: fn()) -> bool {
+    let mut tick_handler = TICK_HANDLER.lock();
+    if tick_handler.is_some() {
+        return false;
+    }
+    *tick_handler = Some(handler);
+    drop(tick_handler);
+    super::irq::register_handler(super::irq::TIMER_IRQ_NUM, timer_irq_handler)
+}
+
+/// Runs `wait`, which waits for an interrupt on the current CPU, with the
+/// timer armed to fire no later than `wakeup_ns`.
+///
+/// The deadline of [`set_oneshot_timer`] is restored afterwards, unless the
+/// timer has been re-armed in the meantime (by an interrupt handler).
+#[cfg(feature = "virt-yield")]
+pub(crate) fn wait_with_wakeup(wakeup_ns: u64, wait: impl FnOnce()) {
+    let deadline = unsafe { TIMER_DEADLINE.read_current_raw() };
+    if wakeup_ns >= deadline {
+        return wait();
+    }
+    set_timer_ticks(nanos_to_ticks(wakeup_ns));
+    wait();
+    if unsafe { TIMER_DEADLINE.read_current_raw() } == deadline {
+        set_timer_ticks(nanos_to_ticks(deadline));
+    }
+}
+
+/// Lets U-mode read `time` directly (e.g. the vDSO), without trapping.
+fn enable_user_time() {
+    const SCOUNTEREN_TM: usize = 1 << 1;
+    unsafe { core::arch::asm!("csrs scounteren, {}", in(reg) SCOUNTEREN_TM) };
 }
 
 pub(super) fn init_percpu() {
+    enable_user_time();
     #[cfg(feature = "irq")]
-    sbi_rt::set_timer(0);
+    set_timer_ticks(0);
 }
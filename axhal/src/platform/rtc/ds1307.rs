@@ -0,0 +1,101 @@
+//! Maxim DS1307, an RTC on an I2C bus.
+//!
+//! It keeps the date and time in BCD, with a two-digit year (2000 to 2099),
+//! at a one-second resolution.
+
+use axerrno::LinuxError;
+
+use super::{DateTime, Rtc, NANOS_PER_SEC};
+
+/// The I2C address of the DS1307.
+pub const DS1307_I2C_ADDR: u8 = 0x68;
+
+const REG_SECONDS: u8 = 0x00;
+
+/// Clock halt, in the seconds register.
+const SECONDS_CH: u8 = 1 << 7;
+/// 12-hour mode, in the hours register.
+const HOURS_12H: u8 = 1 << 6;
+/// PM, in the hours register in 12-hour mode.
+const HOURS_PM: u8 = 1 << 5;
+
+/// An I2C bus controller, provided by the board code.
+pub trait I2cBus: Sync {
+    /// Writes `buf` to the device at `addr`.
+    fn write(&self, addr: u8, buf: &[u8]) -> Result<(), LinuxError>;
+    /// Writes `wbuf` to the device at `addr`, then reads `rbuf` from it in
+    /// the same transfer (with a repeated start).
+    fn write_read(&self, addr: u8, wbuf: &[u8], rbuf: &mut [u8]) -> Result<(), LinuxError>;
+}
+
+/// A DS1307 on an I2C bus.
+pub struct Ds1307 {
+    bus: &'static dyn I2cBus,
+    addr: u8,
+}
+
+const fn from_bcd(value: u8) -> u32 {
+    ((value >> 4) * 10 + (value & 0xf)) as u32
+}
+
+const fn to_bcd(value: u32) -> u8 {
+    (((value / 10) << 4) | (value % 10)) as u8
+}
+
+impl Ds1307 {
+    /// Creates the driver of the DS1307 at `addr` (usually
+    /// [`DS1307_I2C_ADDR`]) on `bus`.
+    pub const fn new(bus: &'static dyn I2cBus, addr: u8) -> Self {
+        Self { bus, addr }
+    }
+}
+
+impl Rtc for Ds1307 {
+    /// Returns [`LinuxError::EIO`] if the clock is halted, i.e. it has never
+    /// been set.
+    fn read_epoch_ns(&self) -> Result<u64, LinuxError> {
+        let mut regs = [0u8; 7];
+        self.bus.write_read(self.addr, &[REG_SECONDS], &mut regs)?;
+        if regs[0] & SECONDS_CH != 0 {
+            return Err(LinuxError::EIO);
+        }
+        let hour = if regs[2] & HOURS_12H != 0 {
+            // 1 to 12, 12 AM is midnight.
+            from_bcd(regs[2] & 0x1f) % 12 + if regs[2] & HOURS_PM != 0 { 12 } else { 0 }
+        } else {
+            from_bcd(regs[2] & 0x3f)
+        };
+        let datetime = DateTime {
+            year: 2000 + from_bcd(regs[6]),
+            month: from_bcd(regs[5] & 0x1f),
+            day: from_bcd(regs[4] & 0x3f),
+            hour,
+            minute: from_bcd(regs[1] & 0x7f),
+            second: from_bcd(regs[0] & 0x7f),
+        };
+        let secs = datetime.to_epoch_secs().ok_or(LinuxError::EIO)?;
+        Ok(secs * NANOS_PER_SEC)
+    }
+
+    /// Returns [`LinuxError::EINVAL`] if the time is not in 2000 to 2099.
+    fn set_epoch_ns(&self, epoch_ns: u64) -> Result<(), LinuxError> {
+        let datetime = DateTime::from_epoch_secs(epoch_ns / NANOS_PER_SEC);
+        if !(2000..2100).contains(&datetime.year) {
+            return Err(LinuxError::EINVAL);
+        }
+        // 1970-01-01 is a Thursday, the day of week is 1 (Sunday) to 7.
+        let weekday = ((epoch_ns / NANOS_PER_SEC / 86400 + 4) % 7 + 1) as u32;
+        // Clears the clock halt bit, and selects the 24-hour mode.
+        let buf = [
+            REG_SECONDS,
+            to_bcd(datetime.second),
+            to_bcd(datetime.minute),
+            to_bcd(datetime.hour),
+            to_bcd(weekday),
+            to_bcd(datetime.day),
+            to_bcd(datetime.month),
+            to_bcd(datetime.year - 2000),
+        ];
+        self.bus.write(self.addr, &buf)
+    }
+}
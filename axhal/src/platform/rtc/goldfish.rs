@@ -0,0 +1,59 @@
+//! Goldfish RTC, the RTC of the QEMU virt machines.
+
+use axerrno::LinuxError;
+use lazy_init::LazyInit;
+use memory_addr::{PhysAddr, VirtAddr};
+
+use super::Rtc;
+use crate::mem::phys_to_virt;
+
+const TIME_LOW: usize = 0x00;
+const TIME_HIGH: usize = 0x04;
+
+/// A Goldfish RTC, which counts nanoseconds since the Unix epoch.
+pub struct GoldfishRtc {
+    base: VirtAddr,
+}
+
+impl GoldfishRtc {
+    /// Creates the driver of the RTC mapped at `base`.
+    pub const fn new(base: VirtAddr) -> Self {
+        Self { base }
+    }
+
+    fn reg(&self, offset: usize) -> *mut u32 {
+        (self.base.as_usize() + offset) as *mut u32
+    }
+}
+
+impl Rtc for GoldfishRtc {
+    fn read_epoch_ns(&self) -> Result<u64, LinuxError> {
+        // Reading the low half latches the high half.
+        let low = unsafe { self.reg(TIME_LOW).read_volatile() } as u64;
+        let high = unsafe { self.reg(TIME_HIGH).read_volatile() } as u64;
+        Ok((high << 32) | low)
+    }
+
+    fn set_epoch_ns(&self, epoch_ns: u64) -> Result<(), LinuxError> {
+        // Writing the low half commits the new time.
+        unsafe {
+            self.reg(TIME_HIGH).write_volatile((epoch_ns >> 32) as u32);
+            self.reg(TIME_LOW).write_volatile(epoch_ns as u32);
+        }
+        Ok(())
+    }
+}
+
+static GOLDFISH_RTC: LazyInit<GoldfishRtc> = LazyInit::new();
+
+/// Finds a Goldfish RTC in the device tree, and initializes its driver.
+pub(super) fn probe() -> Option<&'static GoldfishRtc> {
+    let node = crate::platform::dt::fdt()?
+        .find_compatible(&["google,goldfish-rtc"])
+        .filter(|&node| crate::platform::dt::node_enabled(node))?;
+    let region = node.reg()?.next()?;
+    let paddr = PhysAddr::from(region.starting_address as usize);
+    info!("Goldfish RTC @ {:#x}", paddr);
+    GOLDFISH_RTC.init_by(GoldfishRtc::new(phys_to_virt(paddr)));
+    Some(&GOLDFISH_RTC)
+}
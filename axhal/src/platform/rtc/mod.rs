@@ -0,0 +1,115 @@
+//! Real-time clocks, for the wall-clock time.
+//!
+//! The RTC of the system is either found in the device tree by [`init`]
+//! (the Goldfish RTC of QEMU), or registered by the board code with
+//! [`register_rtc`] (e.g. a [`Ds1307`] on an I2C bus the board provides).
+
+mod ds1307;
+mod goldfish;
+
+use axerrno::LinuxError;
+use spinbase::SpinNoIrq;
+
+pub use self::ds1307::{Ds1307, I2cBus, DS1307_I2C_ADDR};
+pub use self::goldfish::GoldfishRtc;
+
+/// Number of nanoseconds in a second.
+const NANOS_PER_SEC: u64 = crate::time::NANOS_PER_SEC;
+
+/// A real-time clock.
+pub trait Rtc: Sync {
+    /// Reads the time, in nanoseconds since the Unix epoch.
+    fn read_epoch_ns(&self) -> Result<u64, LinuxError>;
+    /// Sets the time, in nanoseconds since the Unix epoch.
+    fn set_epoch_ns(&self, epoch_ns: u64) -> Result<(), LinuxError>;
+}
+
+static RTC: SpinNoIrq<Option<&'static dyn Rtc>> = SpinNoIrq::new(None);
+
+/// Makes `rtc` the RTC of the system, in place of the previous one.
+pub fn register_rtc(rtc: &'static dyn Rtc) {
+    *RTC.lock() = Some(rtc);
+}
+
+/// Reads the wall-clock time from the RTC, in nanoseconds since the Unix
+/// epoch.
+///
+/// Returns [`LinuxError::ENODEV`] if there is no RTC.
+pub fn read_epoch_ns() -> Result<u64, LinuxError> {
+    let rtc = (*RTC.lock()).ok_or(LinuxError::ENODEV)?;
+    rtc.read_epoch_ns()
+}
+
+/// Sets the wall-clock time of the RTC, in nanoseconds since the Unix epoch,
+/// and of the vDSO.
+///
+/// Returns [`LinuxError::ENODEV`] if there is no RTC.
+pub fn set_epoch_ns(epoch_ns: u64) -> Result<(), LinuxError> {
+    let rtc = (*RTC.lock()).ok_or(LinuxError::ENODEV)?;
+    rtc.set_epoch_ns(epoch_ns)?;
+    #[cfg(platform_family = "riscv64-qemu-virt")]
+    crate::arch::update_vdso_data(epoch_ns.saturating_sub(crate::time::current_time_nanos()));
+    Ok(())
+}
+
+/// Finds an RTC in the device tree, and registers it.
+#[cfg_attr(not(platform_family = "riscv64-qemu-virt"), allow(dead_code))]
+pub(crate) fn init() {
+    if let Some(rtc) = goldfish::probe() {
+        register_rtc(rtc);
+    }
+}
+
+/// A broken-down UTC date and time.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct DateTime {
+    pub year: u32,
+    /// 1 to 12.
+    pub month: u32,
+    /// 1 to 31.
+    pub day: u32,
+    pub hour: u32,
+    pub minute: u32,
+    pub second: u32,
+}
+
+impl DateTime {
+    /// Returns the date and time of `secs` seconds since the Unix epoch.
+    pub fn from_epoch_secs(secs: u64) -> Self {
+        let days = (secs / 86400) as i64;
+        let rem = secs % 86400;
+        // Howard Hinnant's `civil_from_days`.
+        let z = days + 719468;
+        let era = z.div_euclid(146097);
+        let doe = z.rem_euclid(146097);
+        let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+        let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+        let mp = (5 * doy + 2) / 153;
+        let day = (doy - (153 * mp + 2) / 5 + 1) as u32;
+        let month = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+        let year = (yoe + era * 400 + (month <= 2) as i64) as u32;
+        Self {
+            year,
+            month,
+            day,
+            hour: (rem / 3600) as u32,
+            minute: (rem / 60 % 60) as u32,
+            second: (rem % 60) as u32,
+        }
+    }
+
+    /// Returns the seconds since the Unix epoch, [`None`] if it is before.
+    pub fn to_epoch_secs(self) -> Option<u64> {
+        // Howard Hinnant's `days_from_civil`.
+        let year = self.year as i64 - (self.month <= 2) as i64;
+        let era = year.div_euclid(400);
+        let yoe = year.rem_euclid(400);
+        let mp = (self.month as i64 + 9) % 12;
+        let doy = (153 * mp + 2) / 5 + self.day as i64 - 1;
+        let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+        let days = era * 146097 + doe - 719468;
+        let secs =
+            days * 86400 + self.hour as i64 * 3600 + self.minute as i64 * 60 + self.second as i64;
+        u64::try_from(secs).ok()
+    }
+}
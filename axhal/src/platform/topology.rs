@@ -0,0 +1,178 @@
+//! CPU topology and cache hierarchy.
+//!
+//! The sockets, clusters, cores and threads of the CPUs come from the
+//! `/cpus/cpu-map` node of the device tree ([`dt::cpu_map`]); without it,
+//! each CPU is a core of its own in a single cluster. The caches of a CPU
+//! come from its node and its `next-level-cache` chain
+//! ([`dt::cpu_caches`]): the CPUs whose chains reach the same cache node
+//! share that cache.
+//!
+//! Both are read once by [`init`], for the enabled CPUs.
+
+use lazy_init::LazyInit;
+
+use crate::cpu::{cpu_to_hartid, hartid_to_cpu, CpuMask};
+use crate::platform::dt::{self, CacheNode};
+
+pub use crate::platform::dt::CacheType;
+
+/// The maximum number of caches of a CPU.
+pub const MAX_CPU_CACHES: usize = 8;
+
+/// The place of a CPU in the topology.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct CpuTopology {
+    /// The index of its socket.
+    pub socket: usize,
+    /// The index of its cluster, over all the sockets.
+    pub cluster: usize,
+    /// The index of its core, over all the clusters.
+    pub core: usize,
+    /// The index of its hardware thread in the core.
+    pub thread: usize,
+}
+
+/// A cache of a CPU.
+#[derive(Debug, Clone, Copy)]
+pub struct CacheInfo {
+    /// The level, 1 for the caches closest to the core.
+    pub level: u8,
+    /// The kind of the cache.
+    pub ty: CacheType,
+    /// The size in bytes, 0 if unknown.
+    pub size: usize,
+    /// The size of a line in bytes, 0 if unknown.
+    pub line_size: usize,
+    /// The number of sets, 0 if unknown.
+    pub sets: usize,
+    /// The CPUs that share the cache, including this one.
+    pub shared_cpus: CpuMask,
+}
+
+struct Topology {
+    cpus: [CpuTopology; axconfig::SMP],
+    caches: [[Option<CacheInfo>; MAX_CPU_CACHES]; axconfig::SMP],
+}
+
+static TOPOLOGY: LazyInit<Topology> = LazyInit::new();
+
+fn cache_info(cache: CacheNode, shared_cpus: CpuMask) -> CacheInfo {
+    CacheInfo {
+        level: cache.level,
+        ty: cache.ty,
+        size: cache.size,
+        line_size: cache.line_size,
+        sets: cache.sets,
+        shared_cpus,
+    }
+}
+
+/// Reads the topology and the caches of the CPUs from the device tree.
+///
+/// It must be called after the hart IDs of the CPUs are known, i.e. after
+/// `arch_init_early`.
+pub(crate) fn init() {
+    let mut topo = Topology {
+        cpus: [CpuTopology::default(); axconfig::SMP],
+        caches: [[None; MAX_CPU_CACHES]; axconfig::SMP],
+    };
+    for (cpu_id, cpu) in topo.cpus.iter_mut().enumerate() {
+        cpu.core = cpu_id;
+    }
+    let mut mapped = false;
+    for node in dt::cpu_map() {
+        if let Some(cpu_id) = hartid_to_cpu(node.hwid) {
+            mapped = true;
+            topo.cpus[cpu_id] = CpuTopology {
+                socket: node.socket,
+                cluster: node.cluster,
+                core: node.core,
+                thread: node.thread,
+            };
+        }
+    }
+    if !mapped {
+        debug!("No CPU map in the device tree, one core per CPU");
+    }
+
+    for cpu_id in 0..axconfig::SMP {
+        let Some(hwid) = cpu_to_hartid(cpu_id) else {
+            continue;
+        };
+        for (slot, cache) in topo.caches[cpu_id].iter_mut().zip(dt::cpu_caches(hwid)) {
+            let mut shared_cpus = CpuMask::new();
+            shared_cpus.insert(cpu_id);
+            if let Some(phandle) = cache.phandle {
+                for other in (0..axconfig::SMP).filter(|&other| other != cpu_id) {
+                    let shares = cpu_to_hartid(other).is_some_and(|hwid| {
+                        dt::cpu_caches(hwid).any(|c| c.phandle == Some(phandle))
+                    });
+                    if shares {
+                        shared_cpus.insert(other);
+                    }
+                }
+            }
+            *slot = Some(cache_info(cache, shared_cpus));
+        }
+    }
+    TOPOLOGY.init_by(topo);
+}
+
+/// Returns the place of the CPU `cpu_id` in the topology, or [`None`]
+/// before [`init`] or if there is no such CPU.
+pub fn cpu_topology(cpu_id: usize) -> Option<CpuTopology> {
+    if !TOPOLOGY.is_init() || cpu_to_hartid(cpu_id).is_none() {
+        return None;
+    }
+    TOPOLOGY.cpus.get(cpu_id).copied()
+}
+
+/// Returns the CPUs whose topology matches the one of `cpu_id` by `same`,
+/// `cpu_id` included.
+fn cpus_matching(cpu_id: usize, same: impl Fn(&CpuTopology, &CpuTopology) -> bool) -> CpuMask {
+    let mut mask = CpuMask::new();
+    if let Some(this) = cpu_topology(cpu_id) {
+        for other in 0..axconfig::SMP {
+            if cpu_topology(other).is_some_and(|topo| same(&this, &topo)) {
+                mask.insert(other);
+            }
+        }
+    }
+    mask
+}
+
+/// Returns the CPUs of the same socket as `cpu_id`.
+pub fn socket_cpus(cpu_id: usize) -> CpuMask {
+    cpus_matching(cpu_id, |a, b| a.socket == b.socket)
+}
+
+/// Returns the CPUs of the same cluster as `cpu_id`.
+pub fn cluster_cpus(cpu_id: usize) -> CpuMask {
+    cpus_matching(cpu_id, |a, b| a.cluster == b.cluster)
+}
+
+/// Returns the hardware threads of the same core as `cpu_id`.
+pub fn thread_siblings(cpu_id: usize) -> CpuMask {
+    cpus_matching(cpu_id, |a, b| a.core == b.core)
+}
+
+/// Returns an iterator over the caches of the CPU `cpu_id`, by increasing
+/// level. It is empty before [`init`].
+pub fn cpu_caches(cpu_id: usize) -> impl Iterator<Item = CacheInfo> {
+    TOPOLOGY
+        .is_init()
+        .then(|| TOPOLOGY.caches.get(cpu_id).copied())
+        .flatten()
+        .into_iter()
+        .flatten()
+        .flatten()
+}
+
+/// Returns the smallest line size of the data (or unified) caches of the
+/// CPU `cpu_id`, if any is known: the granule of the cache maintenance.
+pub fn dcache_line_size(cpu_id: usize) -> Option<usize> {
+    cpu_caches(cpu_id)
+        .filter(|cache| cache.ty != CacheType::Instruction && cache.line_size != 0)
+        .map(|cache| cache.line_size)
+        .min()
+}
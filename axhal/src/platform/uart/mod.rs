@@ -0,0 +1,206 @@
+//! UART drivers for the console.
+//!
+//! The console UART is the `console=` boot parameter, or else the
+//! `stdout-path` of the device tree `/chosen` node.
+//! Until it is found by [`init`] (or [`init_earlycon`]), and if it has no driver here, the console
+//! falls back to the firmware (e.g. the SBI console on RISC-V).
+//!
+//! A second UART can be probed with [`probe_debug_uart`], for a debugger
+//! that polls it (e.g. the GDB stub).
+
+mod ns16550;
+mod sifive;
+
+use fdt::node::FdtNode;
+use lazy_init::LazyInit;
+use memory_addr::PhysAddr;
+
+pub use self::ns16550::Ns16550;
+pub use self::sifive::SifiveUart;
+
+/// A console device.
+pub trait ConsoleDevice: Sync {
+    /// Initializes the device: enables the transmitter and the receiver,
+    /// with the RX interrupt off.
+    fn init(&self);
+    /// Writes a byte, waiting until there is room in the TX FIFO.
+    fn putchar(&self, c: u8);
+    /// Reads a byte, or returns [`None`] if the RX FIFO is empty.
+    fn getchar(&self) -> Option<u8>;
+    /// Enables or disables the interrupt raised when a byte is received.
+    fn set_rx_interrupt(&self, enabled: bool);
+}
+
+/// The console UART, and its IRQ number if it has one.
+static CONSOLE_UART: LazyInit<(&'static dyn ConsoleDevice, Option<usize>)> = LazyInit::new();
+
+/// The debug UART, see [`probe_debug_uart`].
+static DEBUG_UART: LazyInit<&'static dyn ConsoleDevice> = LazyInit::new();
+
+/// The driver instances of one UART.
+struct UartDrivers {
+    ns16550: LazyInit<Ns16550>,
+    sifive: LazyInit<SifiveUart>,
+}
+
+impl UartDrivers {
+    const fn new() -> Self {
+        Self {
+            ns16550: LazyInit::new(),
+            sifive: LazyInit::new(),
+        }
+    }
+}
+
+static CONSOLE_DRIVERS: UartDrivers = UartDrivers::new();
+static DEBUG_DRIVERS: UartDrivers = UartDrivers::new();
+
+/// Returns the console UART, if it has been found.
+#[inline]
+pub fn console_uart() -> Option<&'static dyn ConsoleDevice> {
+    CONSOLE_UART.is_init().then(|| CONSOLE_UART.0)
+}
+
+/// Returns the IRQ number of the console UART (the first one of its
+/// `interrupts`), if it has one.
+pub fn console_uart_irq() -> Option<usize> {
+    CONSOLE_UART.is_init().then(|| CONSOLE_UART.1).flatten()
+}
+
+/// Initializes the driver of the UART `node` in `drivers`, and returns it
+/// with the first `compatible` and the physical address of the UART.
+fn probe(
+    node: &FdtNode<'static, 'static>,
+    drivers: &'static UartDrivers,
+    #[cfg(any(target_arch = "riscv32", target_arch = "riscv64"))] slot: crate::arch::FixmapSlot,
+) -> Option<(&'static dyn ConsoleDevice, &'static str, PhysAddr)> {
+    let compatible = node.compatible()?;
+    let region = node.reg().and_then(|mut reg| reg.next())?;
+    let paddr = PhysAddr::from(region.starting_address as usize);
+    // The boot page table may not map the UART.
+    #[cfg(any(target_arch = "riscv32", target_arch = "riscv64"))]
+    let base = crate::arch::fixmap_if_unmapped(slot, paddr, region.size.unwrap_or(0));
+    #[cfg(not(any(target_arch = "riscv32", target_arch = "riscv64")))]
+    let base = crate::mem::phys_to_virt(paddr);
+    let prop = |name| node.property(name).and_then(|p| p.as_usize());
+
+    let device: &'static dyn ConsoleDevice = if compatible.all().any(|c| c == "sifive,uart0") {
+        drivers.sifive.init_by(SifiveUart::new(base));
+        &*drivers.sifive
+    } else if compatible.all().any(|c| {
+        matches!(
+            c,
+            "ns16550a" | "ns16550" | "ns8250" | "snps,dw-apb-uart" | "nvidia,tegra20-uart"
+        )
+    }) {
+        let reg_shift = prop("reg-shift").unwrap_or(0);
+        let reg_io_width = prop("reg-io-width").unwrap_or(1);
+        drivers
+            .ns16550
+            .init_by(Ns16550::new(base, reg_shift, reg_io_width));
+        &*drivers.ns16550
+    } else {
+        warn!("No driver for the UART {}", compatible.first());
+        return None;
+    };
+    device.init();
+    Some((device, compatible.first(), paddr))
+}
+
+/// Returns the node of the console UART: the one of `console=` if given,
+/// otherwise the `stdout-path` of `/chosen`. Returns [`None`] to keep the
+/// firmware console.
+fn console_node() -> Option<FdtNode<'static, 'static>> {
+    let fdt = crate::platform::dt::fdt()?;
+    let path = match crate::platform::cmdline::boot_params().console {
+        Some("sbi" | "hvc0") => return None,
+        // e.g. "serial0,115200n8"
+        Some(console) => console.split(',').next().unwrap_or(console),
+        // e.g. "/soc/serial@10000000:115200"
+        None => {
+            let path = fdt
+                .find_node("/chosen")?
+                .property("stdout-path")?
+                .as_str()?;
+            path.split(':').next().unwrap_or(path)
+        }
+    };
+    fdt.find_node(path.trim_end_matches('\0'))
+}
+
+/// Returns the node of the UART of `earlycon=`, if any.
+fn earlycon_node() -> Option<FdtNode<'static, 'static>> {
+    use crate::platform::cmdline::Earlycon;
+    match crate::platform::cmdline::boot_params().earlycon? {
+        Earlycon::Sbi => None,
+        Earlycon::StdoutPath => console_node(),
+        Earlycon::Uart { paddr, .. } => crate::platform::dt::fdt()?.all_nodes().find(|node| {
+            node.reg()
+                .and_then(|mut reg| reg.next())
+                .is_some_and(|reg| reg.starting_address as usize == paddr)
+        }),
+    }
+}
+
+/// Initializes the driver of the console UART `node`, and sends the
+/// console output to it from now on.
+fn init_console(node: FdtNode<'static, 'static>) {
+    let Some((device, compatible, paddr)) = probe(
+        &node,
+        &CONSOLE_DRIVERS,
+        #[cfg(any(target_arch = "riscv32", target_arch = "riscv64"))]
+        crate::arch::FixmapSlot::EarlyConsole,
+    ) else {
+        return;
+    };
+    let irq = node.interrupts().and_then(|mut irqs| irqs.next());
+    info!("Console UART {} @ {:#x}, IRQ {:?}", compatible, paddr, irq);
+    CONSOLE_UART.init_by((device, irq));
+    // The output goes to the real console from now on.
+    crate::early_log::stop_capture();
+}
+
+/// Initializes the console UART of `earlycon=` at the earliest boot, if
+/// any, so that it gets the output before [`init`].
+pub(crate) fn init_earlycon() {
+    if let Some(node) = earlycon_node() {
+        init_console(node);
+    }
+}
+
+/// Finds the console UART from `console=` or the `stdout-path` of the
+/// device tree, and initializes its driver, unless `earlycon=` already
+/// did.
+///
+/// The UART must be mapped at `phys_to_virt` of its address, or on RISC-V,
+/// it is mapped in the [fixmap](crate::arch::FixmapSlot) if it is not.
+#[cfg_attr(not(platform_family = "riscv64-qemu-virt"), allow(dead_code))]
+pub(crate) fn init() {
+    if CONSOLE_UART.is_init() {
+        return;
+    }
+    if let Some(node) = console_node() {
+        init_console(node);
+    }
+}
+
+/// Finds the UART at the device tree `path` (e.g. `/soc/serial@10001000`),
+/// and initializes its driver as the debug UART, with the RX interrupt off.
+///
+/// Only one debug UART can be probed: the next calls return it, whatever
+/// `path`. Returns [`None`] if there is no such node, or no driver for it.
+pub fn probe_debug_uart(path: &str) -> Option<&'static dyn ConsoleDevice> {
+    if DEBUG_UART.is_init() {
+        return Some(*DEBUG_UART);
+    }
+    let node = crate::platform::dt::fdt()?.find_node(path)?;
+    let (device, compatible, paddr) = probe(
+        &node,
+        &DEBUG_DRIVERS,
+        #[cfg(any(target_arch = "riscv32", target_arch = "riscv64"))]
+        crate::arch::FixmapSlot::DebugUart,
+    )?;
+    info!("Debug UART {} @ {:#x}", compatible, paddr);
+    DEBUG_UART.init_by(device);
+    Some(device)
+}
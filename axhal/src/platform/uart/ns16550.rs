@@ -0,0 +1,89 @@
+//! 8250/16550-compatible UARTs.
+//!
+//! The baud rate is left as the firmware set it.
+
+use memory_addr::VirtAddr;
+
+use super::ConsoleDevice;
+
+const RBR_THR: usize = 0;
+const IER: usize = 1;
+const FCR: usize = 2;
+const LCR: usize = 3;
+const MCR: usize = 4;
+const LSR: usize = 5;
+
+const IER_RX_AVAILABLE: u32 = 1 << 0;
+/// Enables and clears the FIFOs.
+const FCR_FIFO_ENABLE_CLEAR: u32 = 0x07;
+/// 8 data bits, no parity, 1 stop bit.
+const LCR_8N1: u32 = 0x03;
+/// DTR, RTS, and OUT2 (which gates the interrupt on some boards).
+const MCR_DTR_RTS_OUT2: u32 = 0x0b;
+const LSR_DATA_READY: u32 = 1 << 0;
+const LSR_THR_EMPTY: u32 = 1 << 5;
+
+/// A 16550-compatible UART.
+pub struct Ns16550 {
+    base: VirtAddr,
+    /// The registers are `1 << reg_shift` bytes apart.
+    reg_shift: usize,
+    /// The width of the register accesses, 1 or 4 bytes.
+    reg_io_width: usize,
+}
+
+impl Ns16550 {
+    /// Creates the driver of the UART mapped at `base`, with the
+    /// `reg-shift` and `reg-io-width` of its device tree node.
+    pub const fn new(base: VirtAddr, reg_shift: usize, reg_io_width: usize) -> Self {
+        Self {
+            base,
+            reg_shift,
+            reg_io_width,
+        }
+    }
+
+    fn read(&self, reg: usize) -> u32 {
+        let addr = self.base.as_usize() + (reg << self.reg_shift);
+        unsafe {
+            match self.reg_io_width {
+                4 => (addr as *const u32).read_volatile(),
+                _ => (addr as *const u8).read_volatile() as u32,
+            }
+        }
+    }
+
+    fn write(&self, reg: usize, value: u32) {
+        let addr = self.base.as_usize() + (reg << self.reg_shift);
+        unsafe {
+            match self.reg_io_width {
+                4 => (addr as *mut u32).write_volatile(value),
+                _ => (addr as *mut u8).write_volatile(value as u8),
+            }
+        }
+    }
+}
+
+impl ConsoleDevice for Ns16550 {
+    fn init(&self) {
+        self.write(IER, 0);
+        self.write(FCR, FCR_FIFO_ENABLE_CLEAR);
+        self.write(LCR, LCR_8N1);
+        self.write(MCR, MCR_DTR_RTS_OUT2);
+    }
+
+    fn putchar(&self, c: u8) {
+        while self.read(LSR) & LSR_THR_EMPTY == 0 {
+            core::hint::spin_loop();
+        }
+        self.write(RBR_THR, c as u32);
+    }
+
+    fn getchar(&self) -> Option<u8> {
+        (self.read(LSR) & LSR_DATA_READY != 0).then(|| self.read(RBR_THR) as u8)
+    }
+
+    fn set_rx_interrupt(&self, enabled: bool) {
+        self.write(IER, if enabled { IER_RX_AVAILABLE } else { 0 });
+    }
+}
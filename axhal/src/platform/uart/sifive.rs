@@ -0,0 +1,70 @@
+//! SiFive UART (`sifive,uart0`).
+//!
+//! The baud rate divisor is left as the firmware set it.
+
+use memory_addr::VirtAddr;
+
+use super::ConsoleDevice;
+
+const TXDATA: usize = 0x00;
+const RXDATA: usize = 0x04;
+const TXCTRL: usize = 0x08;
+const RXCTRL: usize = 0x0c;
+const IE: usize = 0x10;
+
+const TXDATA_FULL: u32 = 1 << 31;
+const RXDATA_EMPTY: u32 = 1 << 31;
+const CTRL_ENABLE: u32 = 1 << 0;
+/// The RX watermark interrupt, raised when the RX FIFO holds more bytes
+/// than the watermark (0).
+const IE_RXWM: u32 = 1 << 1;
+/// The RX watermark, in bits 18:16 of `rxctrl`.
+const RXCTRL_RXCNT_MASK: u32 = 0x7 << 16;
+
+/// A SiFive UART.
+pub struct SifiveUart {
+    base: VirtAddr,
+}
+
+impl SifiveUart {
+    /// Creates the driver of the UART mapped at `base`.
+    pub const fn new(base: VirtAddr) -> Self {
+        Self { base }
+    }
+
+    fn read(&self, reg: usize) -> u32 {
+        unsafe { ((self.base.as_usize() + reg) as *const u32).read_volatile() }
+    }
+
+    fn write(&self, reg: usize, value: u32) {
+        unsafe { ((self.base.as_usize() + reg) as *mut u32).write_volatile(value) }
+    }
+}
+
+impl ConsoleDevice for SifiveUart {
+    fn init(&self) {
+        self.write(IE, 0);
+        self.write(TXCTRL, self.read(TXCTRL) | CTRL_ENABLE);
+        self.write(
+            RXCTRL,
+            (self.read(RXCTRL) & !RXCTRL_RXCNT_MASK) | CTRL_ENABLE,
+        );
+    }
+
+    fn putchar(&self, c: u8) {
+        while self.read(TXDATA) & TXDATA_FULL != 0 {
+            core::hint::spin_loop();
+        }
+        self.write(TXDATA, c as u32);
+    }
+
+    fn getchar(&self) -> Option<u8> {
+        // Reading `rxdata` pops the byte, if the FIFO is not empty.
+        let rxdata = self.read(RXDATA);
+        (rxdata & RXDATA_EMPTY == 0).then_some(rxdata as u8)
+    }
+
+    fn set_rx_interrupt(&self, enabled: bool) {
+        self.write(IE, if enabled { IE_RXWM } else { 0 });
+    }
+}
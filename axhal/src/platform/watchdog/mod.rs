@@ -0,0 +1,84 @@
+//! Watchdog timers, which reset the machine if they are not petted in time.
+//!
+//! The watchdog of the system is either found in the device tree by
+//! [`init`] (the SBSA generic watchdog, emulated by QEMU, or the watchdog
+//! of the SiFive always-on block), or registered by the board code with
+//! [`register_watchdog`]. The OS layer builds a softdog or a lockup
+//! detector on [`start`], [`pet`] and [`stop`].
+
+mod sbsa;
+mod sifive;
+
+use axerrno::LinuxError;
+use core::time::Duration;
+use spinbase::SpinNoIrq;
+
+pub use self::sbsa::SbsaWatchdog;
+pub use self::sifive::SifiveWatchdog;
+
+/// A watchdog timer.
+pub trait Watchdog: Sync {
+    /// Starts the watchdog, or restarts it with a new timeout: the machine
+    /// is reset if it is not petted for `timeout`, rounded up to what the
+    /// hardware supports (at most [`max_timeout`](Self::max_timeout)).
+    fn start(&self, timeout: Duration) -> Result<(), LinuxError>;
+    /// Pets the watchdog, restarting its countdown.
+    fn pet(&self);
+    /// Stops the watchdog.
+    fn stop(&self) -> Result<(), LinuxError>;
+    /// Returns the longest supported timeout.
+    fn max_timeout(&self) -> Duration;
+}
+
+static WATCHDOG: SpinNoIrq<Option<&'static dyn Watchdog>> = SpinNoIrq::new(None);
+
+/// Makes `watchdog` the watchdog of the system, in place of the previous
+/// one.
+pub fn register_watchdog(watchdog: &'static dyn Watchdog) {
+    *WATCHDOG.lock() = Some(watchdog);
+}
+
+/// Returns the longest timeout of the watchdog, or [`None`] if there is no
+/// watchdog.
+pub fn max_timeout() -> Option<Duration> {
+    (*WATCHDOG.lock()).map(|watchdog| watchdog.max_timeout())
+}
+
+/// Starts the watchdog with `timeout`, or changes its timeout.
+///
+/// Returns [`LinuxError::ENODEV`] if there is no watchdog, and
+/// [`LinuxError::EINVAL`] if `timeout` is zero or longer than
+/// [`max_timeout`].
+pub fn start(timeout: Duration) -> Result<(), LinuxError> {
+    let watchdog = (*WATCHDOG.lock()).ok_or(LinuxError::ENODEV)?;
+    if timeout.is_zero() || timeout > watchdog.max_timeout() {
+        return Err(LinuxError::EINVAL);
+    }
+    watchdog.start(timeout)
+}
+
+/// Pets the watchdog, if any.
+pub fn pet() {
+    if let Some(watchdog) = *WATCHDOG.lock() {
+        watchdog.pet();
+    }
+}
+
+/// Stops the watchdog.
+///
+/// Returns [`LinuxError::ENODEV`] if there is no watchdog, and the error of
+/// the driver if it cannot be stopped.
+pub fn stop() -> Result<(), LinuxError> {
+    let watchdog = (*WATCHDOG.lock()).ok_or(LinuxError::ENODEV)?;
+    watchdog.stop()
+}
+
+/// Finds a watchdog in the device tree, and registers it.
+#[cfg_attr(not(platform_family = "riscv64-qemu-virt"), allow(dead_code))]
+pub(crate) fn init() {
+    if let Some(watchdog) = sbsa::probe() {
+        register_watchdog(watchdog);
+    } else if let Some(watchdog) = sifive::probe() {
+        register_watchdog(watchdog);
+    }
+}
@@ -0,0 +1,89 @@
+//! SBSA generic watchdog (`arm,sbsa-gwdt`), emulated by QEMU.
+//!
+//! It counts at the frequency of the system counter. When the offset
+//! expires, the first signal (WS0) is raised, and the reset (WS1) follows
+//! when it expires again with no refresh in between; the interrupt of WS0
+//! is not used, so the offset is half the timeout.
+
+use axerrno::LinuxError;
+use core::time::Duration;
+use lazy_init::LazyInit;
+use memory_addr::{PhysAddr, VirtAddr};
+
+use super::Watchdog;
+use crate::mem::phys_to_virt;
+use crate::time::{nanos_to_ticks, ticks_to_nanos};
+
+/// The control and status register, in the control frame.
+const WCS: usize = 0x000;
+/// The offset register, in the control frame (32 bits in revision 0).
+const WOR: usize = 0x008;
+/// The refresh register, in the refresh frame.
+const WRR: usize = 0x000;
+
+const WCS_EN: u32 = 1 << 0;
+
+/// An SBSA generic watchdog.
+pub struct SbsaWatchdog {
+    control: VirtAddr,
+    refresh: VirtAddr,
+}
+
+impl SbsaWatchdog {
+    /// Creates the driver of the watchdog whose control frame is mapped at
+    /// `control` and refresh frame at `refresh`.
+    pub const fn new(control: VirtAddr, refresh: VirtAddr) -> Self {
+        Self { control, refresh }
+    }
+
+    fn write(&self, frame: VirtAddr, reg: usize, value: u32) {
+        unsafe { ((frame.as_usize() + reg) as *mut u32).write_volatile(value) }
+    }
+}
+
+impl Watchdog for SbsaWatchdog {
+    fn start(&self, timeout: Duration) -> Result<(), LinuxError> {
+        let offset = nanos_to_ticks(timeout.as_nanos() as u64).div_ceil(2);
+        let offset = u32::try_from(offset).map_err(|_| LinuxError::EINVAL)?;
+        // Writing the offset also refreshes the watchdog.
+        self.write(self.control, WOR, offset);
+        self.write(self.control, WCS, WCS_EN);
+        Ok(())
+    }
+
+    fn pet(&self) {
+        self.write(self.refresh, WRR, 0);
+    }
+
+    fn stop(&self) -> Result<(), LinuxError> {
+        self.write(self.control, WCS, 0);
+        Ok(())
+    }
+
+    fn max_timeout(&self) -> Duration {
+        Duration::from_nanos(ticks_to_nanos(2 * u32::MAX as u64))
+    }
+}
+
+static SBSA_WATCHDOG: LazyInit<SbsaWatchdog> = LazyInit::new();
+
+/// Finds an SBSA generic watchdog in the device tree, and initializes its
+/// driver. Its first `reg` is the control frame, the second the refresh
+/// frame.
+pub(super) fn probe() -> Option<&'static SbsaWatchdog> {
+    let node = crate::platform::dt::fdt()?
+        .find_compatible(&["arm,sbsa-gwdt"])
+        .filter(|&node| crate::platform::dt::node_enabled(node))?;
+    let mut regs = node.reg()?;
+    let control = PhysAddr::from(regs.next()?.starting_address as usize);
+    let refresh = PhysAddr::from(regs.next()?.starting_address as usize);
+    info!(
+        "SBSA watchdog: control @ {:#x}, refresh @ {:#x}",
+        control, refresh
+    );
+    SBSA_WATCHDOG.init_by(SbsaWatchdog::new(
+        phys_to_virt(control),
+        phys_to_virt(refresh),
+    ));
+    Some(&SBSA_WATCHDOG)
+}
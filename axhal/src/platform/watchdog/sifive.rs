@@ -0,0 +1,106 @@
+//! The watchdog of the SiFive always-on block (`sifive,aon0`), e.g. of the
+//! FE310, also emulated by the QEMU `sifive_e` machine.
+//!
+//! It counts at the low-frequency clock of the block (32768 Hz unless a
+//! fixed clock says otherwise), scaled down by a power of two, and resets
+//! the machine when the scaled count reaches `wdogcmp0`. Each write to a
+//! register must be preceded by the unlock key.
+
+use axerrno::LinuxError;
+use core::time::Duration;
+use lazy_init::LazyInit;
+use memory_addr::{PhysAddr, VirtAddr};
+
+use super::Watchdog;
+use crate::mem::phys_to_virt;
+use crate::time::NANOS_PER_SEC;
+
+const WDOGCFG: usize = 0x00;
+const WDOGCOUNT: usize = 0x08;
+const WDOGFEED: usize = 0x18;
+const WDOGKEY: usize = 0x1c;
+const WDOGCMP0: usize = 0x20;
+
+const WDOGCFG_RSTEN: u32 = 1 << 8;
+const WDOGCFG_ENALWAYS: u32 = 1 << 12;
+/// The largest scale, in bits 3:0 of `wdogcfg`.
+const WDOGCFG_SCALE_MAX: u32 = 15;
+
+const WDOG_KEY: u32 = 0x0051_f15e;
+const WDOG_FEED: u32 = 0x0d09_f00d;
+/// `wdogcmp0` is 16 bits.
+const WDOGCMP0_MAX: u64 = 0xffff;
+
+const LFCLK_DEFAULT_HZ: u64 = 32768;
+
+/// The watchdog of a SiFive always-on block.
+pub struct SifiveWatchdog {
+    base: VirtAddr,
+    clock_hz: u64,
+}
+
+impl SifiveWatchdog {
+    /// Creates the driver of the always-on block mapped at `base`, whose
+    /// low-frequency clock runs at `clock_hz`.
+    pub const fn new(base: VirtAddr, clock_hz: u64) -> Self {
+        Self { base, clock_hz }
+    }
+
+    fn write(&self, reg: usize, value: u32) {
+        unsafe {
+            ((self.base.as_usize() + WDOGKEY) as *mut u32).write_volatile(WDOG_KEY);
+            ((self.base.as_usize() + reg) as *mut u32).write_volatile(value);
+        }
+    }
+}
+
+impl Watchdog for SifiveWatchdog {
+    fn start(&self, timeout: Duration) -> Result<(), LinuxError> {
+        let ticks = (timeout.as_nanos() as u64)
+            .saturating_mul(self.clock_hz)
+            .div_ceil(NANOS_PER_SEC);
+        let scale = (0..=WDOGCFG_SCALE_MAX)
+            .find(|&scale| ticks.div_ceil(1 << scale) <= WDOGCMP0_MAX)
+            .ok_or(LinuxError::EINVAL)?;
+        self.write(WDOGCFG, 0);
+        self.write(WDOGCMP0, ticks.div_ceil(1 << scale) as u32);
+        self.write(WDOGCOUNT, 0);
+        self.write(WDOGCFG, scale | WDOGCFG_RSTEN | WDOGCFG_ENALWAYS);
+        Ok(())
+    }
+
+    fn pet(&self) {
+        self.write(WDOGFEED, WDOG_FEED);
+    }
+
+    fn stop(&self) -> Result<(), LinuxError> {
+        self.write(WDOGCFG, 0);
+        Ok(())
+    }
+
+    fn max_timeout(&self) -> Duration {
+        let ticks = WDOGCMP0_MAX << WDOGCFG_SCALE_MAX;
+        Duration::from_nanos(ticks * NANOS_PER_SEC / self.clock_hz)
+    }
+}
+
+static SIFIVE_WATCHDOG: LazyInit<SifiveWatchdog> = LazyInit::new();
+
+/// Finds a SiFive always-on block in the device tree, and initializes the
+/// driver of its watchdog.
+pub(super) fn probe() -> Option<&'static SifiveWatchdog> {
+    let fdt = crate::platform::dt::fdt()?;
+    let node = fdt
+        .find_compatible(&["sifive,aon0"])
+        .filter(|&node| crate::platform::dt::node_enabled(node))?;
+    let paddr = PhysAddr::from(node.reg()?.next()?.starting_address as usize);
+    let clock_hz = node
+        .property("clocks")
+        .and_then(|p| p.as_usize())
+        .and_then(|phandle| fdt.find_phandle(phandle as u32))
+        .and_then(|clock| clock.property("clock-frequency")?.as_usize())
+        .map_or(LFCLK_DEFAULT_HZ, |hz| hz as u64);
+    info!("SiFive watchdog @ {:#x}, {} Hz", paddr, clock_hz);
+    SIFIVE_WATCHDOG.init_by(SifiveWatchdog::new(phys_to_virt(paddr), clock_hz));
+    Some(&SIFIVE_WATCHDOG)
+}
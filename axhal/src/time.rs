@@ -10,9 +10,15 @@ pub type TimeValue = Duration;
 
 #[cfg(feature = "irq")]
 pub use crate::platform::irq::TIMER_IRQ_NUM;
+#[cfg(not(target_arch = "x86_64"))]
+pub use crate::platform::rtc::{read_epoch_ns, set_epoch_ns};
 #[cfg(feature = "irq")]
 pub use crate::platform::time::set_oneshot_timer;
+#[cfg(platform_family = "riscv64-qemu-virt")]
+pub use crate::platform::time::timer_frequency;
 pub use crate::platform::time::{current_ticks, nanos_to_ticks, ticks_to_nanos};
+#[cfg(all(feature = "irq", platform_family = "riscv64-qemu-virt"))]
+pub use crate::platform::time::{has_sstc, register_tick_handler};
 
 /// Number of milliseconds in a second.
 pub const MILLIS_PER_SEC: u64 = 1_000;
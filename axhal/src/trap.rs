@@ -1,6 +1,267 @@
 //! Trap handling.
 
+use axerrno::LinuxError;
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use memory_addr::VirtAddr;
+use spinbase::SpinNoIrq;
+
 use crate::arch::TrapFrame;
 
+pub use crate::arch::USER_REDZONE;
+
 pub const TRAPFRAME_SIZE: usize = core::mem::size_of::<TrapFrame>();
 pub const STACK_ALIGN: usize = 16;
+
+/// The largest red zone accepted by [`set_user_redzone`].
+pub const MAX_USER_REDZONE: usize = 4096;
+
+/// The current size of the user red zone, [`USER_REDZONE`] by default.
+static USER_REDZONE_SIZE: AtomicUsize = AtomicUsize::new(USER_REDZONE);
+
+/// Sets the size of the area below the user stack pointer that the kernel
+/// skips before placing data on a user stack, for a user space built by a
+/// toolchain that assumes a red zone.
+///
+/// Returns [`LinuxError::EINVAL`] if `size` is larger than
+/// [`MAX_USER_REDZONE`].
+pub fn set_user_redzone(size: usize) -> Result<(), LinuxError> {
+    if size > MAX_USER_REDZONE {
+        return Err(LinuxError::EINVAL);
+    }
+    USER_REDZONE_SIZE.store(size, Ordering::Relaxed);
+    Ok(())
+}
+
+/// Returns the size of the user red zone, see [`set_user_redzone`].
+#[inline]
+pub fn user_redzone() -> usize {
+    USER_REDZONE_SIZE.load(Ordering::Relaxed)
+}
+
+/// Reserves `size` bytes below the user stack pointer `sp`.
+///
+/// It skips the red zone first ([`user_redzone`]), so that data placed by
+/// the kernel (signal frames, the initial argv/envp/auxv block) never
+/// overlaps the area that the interrupted code may still be using, and then
+/// rounds the result down to [`STACK_ALIGN`].
+///
+/// Returns the new stack pointer, i.e. the lowest address of the reserved
+/// area, or [`None`] if it would be below 0 (`sp` comes from the user).
+#[inline]
+pub fn user_stack_reserve(sp: usize, size: usize) -> Option<usize> {
+    let sp = sp.checked_sub(user_redzone())?.checked_sub(size)?;
+    Some(sp & !(STACK_ALIGN - 1))
+}
+
+/// The kind of access that caused a page fault.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PageFaultAccess {
+    /// A load.
+    Read,
+    /// A store or an atomic memory operation.
+    Write,
+    /// An instruction fetch.
+    Execute,
+}
+
+/// A page fault, decoded from the trap by the architecture.
+#[derive(Debug, Clone, Copy)]
+pub struct PageFaultInfo {
+    /// The faulting virtual address.
+    pub vaddr: VirtAddr,
+    /// The address of the faulting instruction.
+    pub ip: usize,
+    /// The kind of the access.
+    pub access: PageFaultAccess,
+    /// Whether the fault came from user mode.
+    pub user: bool,
+}
+
+/// The type of a page fault handler. It returns whether it has resolved the
+/// fault, so that the faulting instruction can be retried.
+///
+/// A kernel access to user memory (`user` false) may fault with the
+/// interrupts disabled, e.g. under the lock of the emulated atomic
+/// instructions on RISC-V, which fault the word in beforehand. The handler
+/// must not sleep then: it fails the fault if it cannot resolve it at once.
+pub type PageFaultHandler = fn(&PageFaultInfo, &mut TrapFrame) -> bool;
+
+static PAGE_FAULT_HANDLER: SpinNoIrq<Option<PageFaultHandler>> = SpinNoIrq::new(None);
+
+/// Registers the handler of the page faults, which the trap handler calls
+/// through [`handle_page_fault`].
+pub fn register_page_fault_handler(f: PageFaultHandler) {
+    *PAGE_FAULT_HANDLER.lock() = Some(f);
+}
+
+/// Passes a page fault to the handler registered with
+/// [`register_page_fault_handler`]. Returns `false` if it has not resolved
+/// it, or if no handler is registered.
+pub fn handle_page_fault(info: &PageFaultInfo, tf: &mut TrapFrame) -> bool {
+    let handler = *PAGE_FAULT_HANDLER.lock();
+    match handler {
+        Some(f) => f(info, tf),
+        None => {
+            warn!("No page fault handler for {:#x?}", info);
+            false
+        }
+    }
+}
+
+/// The synchronous exceptions that the OS can hook with
+/// [`register_trap_handler`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TrapCause {
+    /// A misaligned instruction fetch.
+    InstructionMisaligned,
+    /// An illegal (or disabled, e.g. FP with `FS` off) instruction.
+    IllegalInstruction,
+    /// A breakpoint instruction.
+    Breakpoint,
+    /// A misaligned load.
+    LoadMisaligned,
+    /// A misaligned store or atomic memory operation.
+    StoreMisaligned,
+    /// A system call from user mode.
+    UserEcall,
+    /// An environment call from the kernel.
+    KernelEcall,
+    /// A software-check exception, e.g. a CFI violation (see
+    /// [`cfi_violation`](crate::arch::cfi_violation)).
+    SoftwareCheck,
+}
+
+impl TrapCause {
+    const COUNT: usize = 8;
+}
+
+/// The type of a trap handler. It returns whether it has handled the trap;
+/// otherwise the default handling of the cause applies.
+pub type TrapHandler = fn(&mut TrapFrame) -> bool;
+
+static TRAP_HANDLERS: SpinNoIrq<[Option<TrapHandler>; TrapCause::COUNT]> =
+    SpinNoIrq::new([None; TrapCause::COUNT]);
+
+/// Registers the handler of the exceptions of `cause`, replacing the
+/// previous one. Returns the previous handler.
+///
+/// The handler runs before the default handling of the HAL (e.g.
+/// [`handle_breakpoint`](crate::arch::handle_breakpoint)), so that it can
+/// implement e.g. an FP emulator or kprobes.
+pub fn register_trap_handler(cause: TrapCause, handler: TrapHandler) -> Option<TrapHandler> {
+    TRAP_HANDLERS.lock()[cause as usize].replace(handler)
+}
+
+/// Unregisters the handler of `cause`, and returns it.
+pub fn unregister_trap_handler(cause: TrapCause) -> Option<TrapHandler> {
+    TRAP_HANDLERS.lock()[cause as usize].take()
+}
+
+/// Calls the handler registered for `cause`. Returns `false` if it has not
+/// handled the trap, or if none is registered.
+pub fn dispatch_trap(cause: TrapCause, tf: &mut TrapFrame) -> bool {
+    let handler = TRAP_HANDLERS.lock()[cause as usize];
+    handler.is_some_and(|f| f(tf))
+}
+
+/// The number of local interrupt causes counted.
+const MAX_LOCAL_IRQS: usize = 16;
+/// The number of IPI kinds counted, by bit.
+const MAX_IPI_KINDS: usize = 8;
+/// The number of exception causes counted.
+const MAX_EXCEPTIONS: usize = 32;
+
+/// A row of the interrupt statistics, e.g. of `/proc/interrupts`, counted
+/// per CPU.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TrapStat {
+    /// An external IRQ, by number.
+    #[cfg(feature = "irq")]
+    Irq(usize),
+    /// A local interrupt, by cause (e.g. the timer, or the software
+    /// interrupt of the IPIs).
+    LocalIrq(usize),
+    /// The IPIs of a kind, by the index of its bit (e.g. of
+    /// [`IpiKind`](crate::arch::IpiKind) on RISC-V).
+    Ipi(usize),
+    /// A synchronous exception, by cause (e.g. in `scause`), the page faults
+    /// and the system calls included.
+    Exception(usize),
+}
+
+struct TrapCounts {
+    local: [AtomicUsize; MAX_LOCAL_IRQS],
+    ipi: [AtomicUsize; MAX_IPI_KINDS],
+    exception: [AtomicUsize; MAX_EXCEPTIONS],
+}
+
+#[allow(clippy::declare_interior_mutable_const)]
+const COUNT_INIT: AtomicUsize = AtomicUsize::new(0);
+#[allow(clippy::declare_interior_mutable_const)]
+const TRAP_COUNTS_INIT: TrapCounts = TrapCounts {
+    local: [COUNT_INIT; MAX_LOCAL_IRQS],
+    ipi: [COUNT_INIT; MAX_IPI_KINDS],
+    exception: [COUNT_INIT; MAX_EXCEPTIONS],
+};
+
+/// The counts of each CPU, indexed by the logical CPU ID.
+static TRAP_COUNTS: [TrapCounts; axconfig::SMP] = [TRAP_COUNTS_INIT; axconfig::SMP];
+
+impl TrapStat {
+    fn counter(self, cpu_id: usize) -> Option<&'static AtomicUsize> {
+        let counts = TRAP_COUNTS.get(cpu_id)?;
+        match self {
+            #[cfg(feature = "irq")]
+            Self::Irq(_) => None,
+            Self::LocalIrq(cause) => counts.local.get(cause),
+            Self::Ipi(bit) => counts.ipi.get(bit),
+            Self::Exception(cause) => counts.exception.get(cause),
+        }
+    }
+
+    /// Returns the count on the CPU `cpu_id`.
+    pub fn count(self, cpu_id: usize) -> usize {
+        match self {
+            #[cfg(feature = "irq")]
+            Self::Irq(irq_num) => crate::irq::irq_count(irq_num, cpu_id),
+            _ => self
+                .counter(cpu_id)
+                .map_or(0, |count| count.load(Ordering::Relaxed)),
+        }
+    }
+
+    /// Returns the count on all CPUs.
+    pub fn total(self) -> usize {
+        (0..axconfig::SMP).map(|cpu_id| self.count(cpu_id)).sum()
+    }
+}
+
+/// Counts an occurrence of `stat` on the current CPU. The external IRQs are
+/// counted by their dispatching.
+#[cfg_attr(
+    not(any(target_arch = "riscv32", target_arch = "riscv64")),
+    allow(dead_code)
+)]
+pub(crate) fn count_trap(stat: TrapStat) {
+    if let Some(count) = stat.counter(crate::cpu::_this_cpu_id()) {
+        count.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+/// Returns an iterator over the rows that occurred at least once: the
+/// external IRQs, the local interrupts, the IPIs and then the exceptions,
+/// each by number.
+///
+/// The counters are read without stopping the other CPUs, each row is a
+/// snapshot of its own.
+pub fn trap_stats() -> impl Iterator<Item = TrapStat> {
+    #[cfg(feature = "irq")]
+    let irqs = (0..crate::platform::irq::MAX_IRQ_COUNT).map(TrapStat::Irq);
+    #[cfg(not(feature = "irq"))]
+    let irqs = core::iter::empty();
+    irqs.chain((0..MAX_LOCAL_IRQS).map(TrapStat::LocalIrq))
+        .chain((0..MAX_IPI_KINDS).map(TrapStat::Ipi))
+        .chain((0..MAX_EXCEPTIONS).map(TrapStat::Exception))
+        .filter(|stat| stat.total() != 0)
+}
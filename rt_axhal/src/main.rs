@@ -8,8 +8,8 @@ use core::panic::PanicInfo;
 use axhal::mem::memory_regions;
 
 #[no_mangle]
-pub extern "Rust" fn runtime_main(cpu_id: usize, _dtb_pa: usize) {
-    axhal::arch_init_early(cpu_id);
+pub extern "Rust" fn runtime_main(cpu_id: usize, dtb_pa: usize) {
+    axhal::arch_init_early(cpu_id, dtb_pa);
 
     axlog2::init("debug");
     info!("[rt_axhal]: ...");